@@ -1,22 +1,26 @@
 use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use std::ops::Bound;
 use std::time::Duration;
 
 use chrono::Utc;
 use rand::{Rng, SeedableRng};
 
+use criterion::measurement::WallTime;
 use criterion::{
-    criterion_group, criterion_main, AxisScale, BenchmarkId, Criterion, PlotConfiguration,
-    SamplingMode, Throughput,
+    criterion_group, criterion_main, AxisScale, BenchmarkGroup, BenchmarkId, Criterion,
+    PlotConfiguration, SamplingMode, Throughput,
 };
 
-use reconcile::{DatedMaybeTombstone, HRTree, HashRangeQueryable, Service};
+use reconcile::dated::DatedTree;
+use reconcile::diff::Diffable;
+use reconcile::map::Map;
+use reconcile::{DatedMaybeTombstone, DefaultFingerprint, HRTree, HashRangeQueryable, Service};
 
 fn hrtree_new(c: &mut Criterion) {
     let mut group = c.benchmark_group("HRTree::new");
-    group.bench_function("BTreeMap::new()", |b| {
-        b.iter(|| BTreeMap::<u32, u32>::new())
-    });
-    group.bench_function("HRTree::new()", |b| b.iter(|| HRTree::<u32, u32>::new()));
+    group.bench_function("BTreeMap::new()", |b| b.iter(BTreeMap::<u32, u32>::new));
+    group.bench_function("HRTree::new()", |b| b.iter(HRTree::<u32, u32>::new));
 }
 
 /// Measure the time to insert N elements in the tree
@@ -63,6 +67,59 @@ fn hrtree_fill(c: &mut Criterion) {
     }
 }
 
+/// Measure the time to build a tree of N elements at once
+fn hrtree_from_iter(c: &mut Criterion) {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+    let mut key_values = Vec::new();
+    for _ in 0..1_000_000 {
+        let key: u32 = rng.gen();
+        let value: u32 = rng.gen();
+        key_values.push((key, value));
+    }
+    let key_values = &key_values;
+    let mut sorted_key_values = key_values.clone();
+    sorted_key_values.sort();
+    let sorted_key_values = &sorted_key_values;
+
+    let plot_config = PlotConfiguration::default().summary_scale(AxisScale::Logarithmic);
+    let mut group = c.benchmark_group("HRTree::from_iter");
+    group.plot_config(plot_config);
+    let mut size = 10;
+    while size <= key_values.len() {
+        group.throughput(Throughput::Elements(size as u64));
+        group.sample_size(10.max(1_000_000 / size).min(100));
+        group.sampling_mode(SamplingMode::Linear);
+        group.bench_with_input(
+            BenchmarkId::new("HRTree::insert", size),
+            &size,
+            |b, &size| {
+                b.iter(|| {
+                    let mut tree = HRTree::<u32, u32>::new();
+                    for (k, v) in sorted_key_values[..size].iter().copied() {
+                        tree.insert(k, v);
+                    }
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("HRTree::from_iter", size),
+            &size,
+            |b, &size| b.iter(|| HRTree::from_iter(key_values[..size].iter().copied())),
+        );
+        group.bench_with_input(
+            BenchmarkId::new("HRTree::from_sorted_iter", size),
+            &size,
+            |b, &size| {
+                b.iter(|| {
+                    HRTree::<u32, u32>::from_sorted_iter(sorted_key_values[..size].iter().copied())
+                })
+            },
+        );
+        size *= 10;
+    }
+}
+
 /// Measure the time to insert (and remove) 1 element in a tree of size N
 fn hrtree_insert(c: &mut Criterion) {
     let mut rng = rand::rngs::StdRng::seed_from_u64(42);
@@ -125,6 +182,50 @@ fn hrtree_insert(c: &mut Criterion) {
     }
 }
 
+/// Measure the time to insert (and remove) 1 element in a tree of size N while a clone shares its
+/// nodes, so that the path to the element is copied every time
+fn hrtree_clone(c: &mut Criterion) {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+    let mut key_values = Vec::new();
+    for _ in 0..1_000_000 {
+        let key: u32 = rng.gen();
+        let value: u32 = rng.gen();
+        key_values.push((key, value));
+    }
+    let key_values = &key_values;
+
+    let plot_config = PlotConfiguration::default().summary_scale(AxisScale::Logarithmic);
+    let mut group = c.benchmark_group("HRTree::clone");
+    group.plot_config(plot_config);
+    let mut size = 10;
+    while size <= key_values.len() {
+        group.throughput(Throughput::Elements(size as u64));
+        group.sample_size(10.max(1_000_000 / size).min(100));
+        group.sampling_mode(SamplingMode::Linear);
+        let tree = HRTree::<u32, u32>::from_iter(key_values[..size].iter().copied());
+        group.bench_with_input(BenchmarkId::new("HRTree::clone", size), &size, |b, _| {
+            b.iter(|| tree.clone())
+        });
+        group.bench_with_input(
+            BenchmarkId::new("HRTree::insert_shared", size),
+            &size,
+            |b, _| {
+                let mut tree = tree.clone();
+                b.iter(|| {
+                    let snapshot = tree.clone();
+                    let k = rng.gen();
+                    let v = rng.gen();
+                    tree.insert(k, v);
+                    tree.remove(&k);
+                    snapshot
+                })
+            },
+        );
+        size *= 10;
+    }
+}
+
 /// Measure the time to remove (and restore) 1 element in a tree of size N
 fn hrtree_remove(c: &mut Criterion) {
     let mut rng = rand::rngs::StdRng::seed_from_u64(42);
@@ -222,12 +323,187 @@ fn hrtree_hash(c: &mut Criterion) {
     }
 }
 
+/// Measure the time to insert (and remove) 1 element in a shared tree, and to compute the hash over
+/// a range, in trees of 100k elements with N children per node
+fn hrtree_node_size(c: &mut Criterion) {
+    fn bench<const N: usize>(group: &mut BenchmarkGroup<'_, WallTime>, key_values: &[(u32, u32)]) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let tree =
+            HRTree::<u32, u32, DefaultFingerprint, N>::from_sorted_iter(key_values.iter().copied());
+        group.bench_with_input(BenchmarkId::new("insert_shared", N), &N, |b, _| {
+            let mut tree = tree.clone();
+            b.iter(|| {
+                let snapshot = tree.clone();
+                let k = rng.gen();
+                let v = rng.gen();
+                tree.insert(k, v);
+                tree.remove(&k);
+                snapshot
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("get", N), &N, |b, _| {
+            b.iter(|| tree.get(&key_values[rng.gen_range(0..key_values.len())].0))
+        });
+        group.bench_with_input(BenchmarkId::new("hash", N), &N, |b, _| {
+            b.iter(|| {
+                let k1: u32 = rng.gen();
+                let k2: u32 = rng.gen();
+                let range = if k1 < k2 { k1..k2 } else { k2..k1 };
+                tree.hash(&range)
+            })
+        });
+    }
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    let mut key_values: Vec<(u32, u32)> = (0..100_000).map(|_| (rng.gen(), rng.gen())).collect();
+    key_values.sort();
+
+    let mut group = c.benchmark_group("HRTree::node_size");
+    bench::<4>(&mut group, &key_values);
+    bench::<12>(&mut group, &key_values);
+    bench::<32>(&mut group, &key_values);
+    bench::<64>(&mut group, &key_values);
+}
+
+/// Measure the memory per entry of 100k dated u64 values, in an HRTree and in a DatedTree, and
+/// the time to insert and get them
+fn dated_tree(c: &mut Criterion) {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    let timestamp = Utc::now();
+    let key_values: Vec<(u32, DatedMaybeTombstone<u64>)> = (0..100_000)
+        .map(|_| (rng.gen(), (timestamp, Some(rng.gen()))))
+        .collect();
+    let tree: HRTree<u32, DatedMaybeTombstone<u64>> = key_values.iter().cloned().collect();
+    let map: DatedTree<u32, u64> = key_values.iter().cloned().collect();
+
+    let mut group = c.benchmark_group("DatedTree");
+    group.bench_function("HRTree::insert", |b| {
+        let mut tree = tree.clone();
+        b.iter(|| {
+            let k = rng.gen();
+            tree.insert(k, (timestamp, Some(rng.gen())));
+            tree.remove(&k);
+        })
+    });
+    group.bench_function("DatedTree::insert", |b| {
+        let mut map: DatedTree<u32, u64> = key_values.iter().cloned().collect();
+        b.iter(|| {
+            let k = rng.gen();
+            Map::insert(&mut map, k, (timestamp, Some(rng.gen())));
+            Map::remove(&mut map, &k);
+        })
+    });
+    group.bench_function("HRTree::get", |b| {
+        b.iter(|| {
+            tree.get(&key_values[rng.gen_range(0..key_values.len())].0)
+                .cloned()
+        })
+    });
+    group.bench_function("DatedTree::get", |b| {
+        b.iter(|| map.get(&key_values[rng.gen_range(0..key_values.len())].0))
+    });
+}
+
+/// Measure the time to run the diff rounds between 2 trees of 100k String keys, with N differing
+/// items
+fn diff_round(c: &mut Criterion) {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+    let mut key_values = Vec::new();
+    for _ in 0..100_000 {
+        let key = format!("{:032x}", rng.gen::<u128>());
+        let value: u32 = rng.gen();
+        key_values.push((key, value));
+    }
+    let tree1 = HRTree::from_iter(key_values.iter().cloned());
+
+    let mut group = c.benchmark_group("Diffable::diff_round");
+    let mut count = 1;
+    while count <= 1000 {
+        let mut tree2 = tree1.clone();
+        for (key, value) in key_values.iter().step_by(key_values.len() / count) {
+            tree2.insert(key.clone(), value + 1);
+        }
+        group.bench_with_input(BenchmarkId::from_parameter(count), &count, |b, _| {
+            b.iter(|| {
+                let trees = [&tree1, &tree2];
+                let mut segments = tree1.start_diff();
+                let mut turn = 1;
+                while !segments.is_empty() {
+                    let mut out_comparison = Vec::new();
+                    let mut differences = Vec::new();
+                    trees[turn].diff_round(segments, &mut out_comparison, &mut differences);
+                    segments = out_comparison;
+                    turn = 1 - turn;
+                }
+            })
+        });
+        count *= 10;
+    }
+}
+
+/// Measure the time to answer 32 divergent segments over a tree of 1M keys of 200 bytes, one by
+/// one or all in the same round
+///
+/// Run with `--features parallel` to answer large rounds over several threads.
+fn diff_round_segments(c: &mut Criterion) {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+
+    let mut key_values = Vec::new();
+    for _ in 0..1_000_000 {
+        let key = format!("{:0200x}", rng.gen::<u128>());
+        let value: u32 = rng.gen();
+        key_values.push((key, value));
+    }
+    let mut tree = HRTree::from_iter(key_values);
+
+    // the segments of a remote tree differing by one item in each
+    let count = 32;
+    let step = tree.len() / count;
+    let bound = |i: usize| tree.key_at(i * step).clone();
+    let mut segments = Vec::new();
+    for i in 0..count {
+        let end = if i + 1 < count {
+            Bound::Excluded(bound(i + 1))
+        } else {
+            Bound::Unbounded
+        };
+        segments.extend(tree.start_diff_range(&(Bound::Included(bound(i)), end)));
+    }
+    for i in 0..count {
+        let key = tree.key_at(i * step + step / 2).clone();
+        tree.insert(key, 0);
+    }
+
+    let mut group = c.benchmark_group("Diffable::diff_round segments");
+    group.throughput(Throughput::Elements(count as u64));
+    group.bench_function("one by one", |b| {
+        b.iter(|| {
+            let mut out_comparison = Vec::new();
+            let mut differences = Vec::new();
+            for segment in &segments {
+                tree.diff_round(vec![segment.clone()], &mut out_comparison, &mut differences);
+            }
+            out_comparison
+        })
+    });
+    group.bench_function("same round", |b| {
+        b.iter(|| {
+            let mut out_comparison = Vec::new();
+            let mut differences = Vec::new();
+            tree.diff_round(segments.clone(), &mut out_comparison, &mut differences);
+            out_comparison
+        })
+    });
+    group.finish();
+}
+
 /// Measure the time to send 1 insertion, and 1 removal between 2 Service instances containing N items
 fn service_send(c: &mut Criterion) {
-    let port = 8080;
     let peer_net = "127.0.0.1/8".parse().unwrap();
-    let addr1 = "127.0.0.44".parse().unwrap();
-    let addr2 = "127.0.0.45".parse().unwrap();
+    // both instances on the loopback address, which is the only one available on some systems
+    let addr1: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "127.0.0.1:8081".parse().unwrap();
 
     let mut rng = rand::rngs::StdRng::seed_from_u64(42);
 
@@ -255,12 +531,14 @@ fn service_send(c: &mut Criterion) {
                 let tree2 = HRTree::from_iter(key_values[..size].iter().copied());
 
                 // start reconciliation services
-                let service1 = Service::new(tree1, port, addr1, peer_net)
+                let service1 = Service::new(tree1, addr1.port(), addr1.ip(), peer_net)
                     .await
-                    .with_seed(addr2);
-                let service2 = Service::new(tree2, port, addr2, peer_net)
+                    .unwrap()
+                    .with_seed_addr(addr2);
+                let service2 = Service::new(tree2, addr2.port(), addr2.ip(), peer_net)
                     .await
-                    .with_seed(addr1);
+                    .unwrap()
+                    .with_seed_addr(addr1);
                 let task1 = tokio::spawn(service1.clone().run());
                 let task2 = tokio::spawn(service2.clone().run());
 
@@ -288,10 +566,10 @@ fn service_send(c: &mut Criterion) {
 
 /// Measure the time to reconcile 1 insertion/removal between Service instances containing N items
 fn service_reconcile(c: &mut Criterion) {
-    let port = 8080;
     let peer_net = "127.0.0.1/8".parse().unwrap();
-    let addr1 = "127.0.0.44".parse().unwrap();
-    let addr2 = "127.0.0.45".parse().unwrap();
+    // both instances on the loopback address, which is the only one available on some systems
+    let addr1: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "127.0.0.1:8081".parse().unwrap();
 
     let mut rng = rand::rngs::StdRng::seed_from_u64(42);
 
@@ -319,12 +597,14 @@ fn service_reconcile(c: &mut Criterion) {
                 let tree2 = HRTree::from_iter(key_values[..size].iter().copied());
 
                 // start reconciliation services
-                let service1 = Service::new(tree1, port, addr1, peer_net)
+                let service1 = Service::new(tree1, addr1.port(), addr1.ip(), peer_net)
                     .await
-                    .with_seed(addr2);
-                let service2 = Service::new(tree2, port, addr2, peer_net)
+                    .unwrap()
+                    .with_seed_addr(addr2);
+                let service2 = Service::new(tree2, addr2.port(), addr2.ip(), peer_net)
                     .await
-                    .with_seed(addr1);
+                    .unwrap()
+                    .with_seed_addr(addr1);
                 let task1 = tokio::spawn(service1.clone().run());
                 let task2 = tokio::spawn(service2.clone().run());
 
@@ -333,14 +613,14 @@ fn service_reconcile(c: &mut Criterion) {
                     let v: u32 = rng.gen();
                     service1.just_insert(k, v, Utc::now());
                     let clone = service1.clone();
-                    let task = tokio::spawn(async move { clone.initiate_reconciliation().await });
+                    let task = tokio::spawn(async move { clone.start_reconciliation().await });
                     while service2.get(&k).is_none() {
                         std::thread::sleep(Duration::from_micros(1));
                     }
                     service1.just_remove(&k, Utc::now());
                     task.abort();
                     let clone = service1.clone();
-                    let task = tokio::spawn(async move { clone.initiate_reconciliation().await });
+                    let task = tokio::spawn(async move { clone.start_reconciliation().await });
                     while service2.get(&k).is_some() {
                         std::thread::sleep(Duration::from_micros(1));
                     }
@@ -356,14 +636,62 @@ fn service_reconcile(c: &mut Criterion) {
     }
 }
 
+/// Measure the time to insert 100k items in bulk, until they are sent to 5 peers
+fn service_insert_bulk(c: &mut Criterion) {
+    let peer_net = "127.0.0.1/8".parse().unwrap();
+    let addr: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    let keys: Vec<u32> = (0..100_000).map(|_| rng.gen()).collect();
+
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    let (service, task, _peers) = rt.block_on(async {
+        // the peers only receive the updates
+        let mut peers = Vec::new();
+        for port in 8081..8086 {
+            let peer = SocketAddr::new(addr.ip(), port);
+            peers.push(tokio::net::UdpSocket::bind(peer).await.unwrap());
+        }
+        let mut service = Service::new(HRTree::new(), addr.port(), addr.ip(), peer_net)
+            .await
+            .unwrap();
+        for peer in &peers {
+            service = service.with_seed_addr(peer.local_addr().unwrap());
+        }
+        let task = tokio::spawn(service.clone().run());
+        (service, task, peers)
+    });
+
+    let mut group = c.benchmark_group("Service::insert_bulk");
+    group.sample_size(10);
+    group.bench_function("100k", |b| {
+        b.iter(|| {
+            let now = Utc::now();
+            let key_values: Vec<_> = keys.iter().map(|&key| (key, key, now)).collect();
+            rt.block_on(service.insert_bulk(&key_values).sent());
+        })
+    });
+    group.finish();
+
+    task.abort();
+    let _ = rt.block_on(task);
+}
+
 criterion_group!(
     benches,
     hrtree_new,
     hrtree_fill,
+    hrtree_from_iter,
     hrtree_insert,
+    hrtree_clone,
     hrtree_remove,
     hrtree_hash,
+    hrtree_node_size,
+    dated_tree,
+    diff_round,
+    diff_round_segments,
     service_send,
     service_reconcile,
+    service_insert_bulk,
 );
 criterion_main!(benches);
@@ -1,4 +1,4 @@
-use std::net::IpAddr;
+use std::net::{IpAddr, SocketAddr};
 
 use chrono::Utc;
 use clap::Parser;
@@ -19,6 +19,9 @@ struct Args {
     elements: usize,
     #[arg(short, long)]
     seed: Vec<IpAddr>,
+    /// Seed listening on another port than this instance
+    #[arg(long)]
+    seed_addr: Vec<SocketAddr>,
     #[arg(short, long, default_value_t = tracing::Level::INFO)]
     log_level: tracing::Level,
 }
@@ -30,6 +33,7 @@ async fn main() {
         listen_addr,
         peer_net,
         seed,
+        seed_addr,
         elements,
         log_level,
     } = Args::parse();
@@ -48,10 +52,15 @@ async fn main() {
     let tree = HRTree::from_iter(key_values.into_iter());
     info!("Global hash is {}", tree.hash(&..));
 
-    let mut service = Service::new(tree, port, listen_addr, peer_net).await;
+    let mut service = Service::new(tree, port, listen_addr, peer_net)
+        .await
+        .unwrap();
 
     for seed in seed {
         service = service.with_seed(seed);
     }
+    for seed in seed_addr {
+        service = service.with_seed_addr(seed);
+    }
     service.run().await;
 }
@@ -0,0 +1,174 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`AuthTransport`], which authenticates the datagrams of a service with a key shared by
+//! the cluster.
+//!
+//! An authenticated datagram starts with [`AUTH_MAGIC`] instead of the usual magic bytes, and ends
+//! with the first [`AUTH_TAG_SIZE`] bytes of the HMAC-SHA256 of the rest of the datagram. The
+//! datagrams are sent with the first key, and accepted with any of the keys, so that the key can
+//! be rotated without interrupting the cluster.
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tracing::{debug, warn};
+
+use crate::internal_service::MAGIC;
+use crate::metrics::ServiceMetrics;
+use crate::transport::{Transport, TransportFuture};
+
+/// Replaces the magic bytes of the protocol at the start of authenticated datagrams
+pub(crate) const AUTH_MAGIC: [u8; 2] = *b"RA";
+/// Size of the truncated HMAC appended to authenticated datagrams
+pub(crate) const AUTH_TAG_SIZE: usize = 16;
+
+/// Shared key of the cluster
+pub(crate) type AuthKey = [u8; 32];
+
+/// Socket of a service, authenticating the datagrams sent and received through another socket.
+pub(crate) struct AuthTransport {
+    sockets: Arc<Vec<Box<dyn Transport>>>,
+    /// Index of the wrapped socket in `sockets`
+    index: usize,
+    keys: Arc<Vec<AuthKey>>,
+    metrics: Arc<ServiceMetrics>,
+}
+
+impl AuthTransport {
+    /// Wrap each of the sockets, sending with the first key and accepting any of them.
+    pub fn wrap_all(
+        sockets: Arc<Vec<Box<dyn Transport>>>,
+        keys: Vec<AuthKey>,
+        metrics: Arc<ServiceMetrics>,
+    ) -> Vec<Box<dyn Transport>> {
+        assert!(!keys.is_empty(), "at least one key is needed");
+        let keys = Arc::new(keys);
+        (0..sockets.len())
+            .map(|index| {
+                Box::new(AuthTransport {
+                    sockets: sockets.clone(),
+                    index,
+                    keys: keys.clone(),
+                    metrics: metrics.clone(),
+                }) as Box<dyn Transport>
+            })
+            .collect()
+    }
+
+    fn socket(&self) -> &dyn Transport {
+        &*self.sockets[self.index]
+    }
+
+    /// Check the tag of an authenticated datagram, in place; return the size of the datagram
+    /// once its magic bytes are restored and its tag removed, or `None` if it must be dropped.
+    fn verify(&self, datagram: &mut [u8], peer: SocketAddr) -> Option<usize> {
+        if !datagram.starts_with(&AUTH_MAGIC) {
+            warn!(
+                "unauthenticated datagram from {peer}; is the auth key set on all the instances?"
+            );
+            return None;
+        }
+        let Some(size) = datagram.len().checked_sub(AUTH_TAG_SIZE) else {
+            debug!("authenticated datagram from {peer} too short, discarded");
+            return None;
+        };
+        let (payload, tag) = datagram.split_at(size);
+        let valid = self.keys.iter().any(|key| {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+            mac.update(payload);
+            mac.verify_truncated_left(tag).is_ok()
+        });
+        if !valid {
+            debug!("datagram from {peer} with an invalid auth tag, discarded");
+            return None;
+        }
+        datagram[..MAGIC.len()].copy_from_slice(&MAGIC);
+        Some(size)
+    }
+}
+
+impl Transport for AuthTransport {
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> TransportFuture<'a, usize> {
+        let mut datagram = Vec::with_capacity(buf.len() + AUTH_TAG_SIZE);
+        datagram.extend_from_slice(&AUTH_MAGIC);
+        datagram.extend_from_slice(buf.get(AUTH_MAGIC.len()..).unwrap_or_default());
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.keys[0]).unwrap();
+        mac.update(&datagram);
+        datagram.extend_from_slice(&mac.finalize().into_bytes()[..AUTH_TAG_SIZE]);
+        Box::pin(async move { self.socket().send_to(&datagram, target).await })
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, SocketAddr)> {
+        Box::pin(async move {
+            loop {
+                let (size, peer) = self.socket().recv_from(buf).await?;
+                if size == buf.len() {
+                    // truncated; let the service report it
+                    return Ok((size, peer));
+                }
+                match self.verify(&mut buf[..size], peer) {
+                    Some(size) => return Ok((size, peer)),
+                    None => ServiceMetrics::add(&self.metrics.auth_failures, 1),
+                }
+            }
+        })
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket().local_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+
+    use super::{AuthKey, AuthTransport};
+    use crate::metrics::ServiceMetrics;
+    use crate::sim::SimNetwork;
+    use crate::transport::Transport;
+
+    #[tokio::test]
+    async fn key_rotation() {
+        let network = SimNetwork::new(42);
+        let metrics = Arc::new(ServiceMetrics::default());
+        let addr = |i| -> SocketAddr { format!("10.0.0.{i}:8080").parse().unwrap() };
+        let wrap = |i, keys: Vec<AuthKey>| {
+            let socket: Box<dyn Transport> = Box::new(network.bind(addr(i)).unwrap());
+            AuthTransport::wrap_all(Arc::new(vec![socket]), keys, metrics.clone()).remove(0)
+        };
+        // the receiver sends with the new key, and still accepts the old one
+        let receiver = wrap(1, vec![[2; 32], [1; 32]]);
+        let old_sender = wrap(2, vec![[1; 32]]);
+        let other_sender = wrap(3, vec![[3; 32]]);
+        let raw = network.bind(addr(4)).unwrap();
+        let new_sender = wrap(5, vec![[2; 32]]);
+        let mut buf = vec![0; 100];
+
+        old_sender.send_to(b"RC\x02hello", addr(1)).await.unwrap();
+        let (size, _) = receiver.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"RC\x02hello");
+
+        // other keys, unauthenticated datagrams and truncated tags are dropped
+        other_sender
+            .send_to(b"RC\x02forged", addr(1))
+            .await
+            .unwrap();
+        raw.send_to(b"RC\x02forged", addr(1)).await.unwrap();
+        raw.send_to(b"RA\x02", addr(1)).await.unwrap();
+        new_sender.send_to(b"RC\x02again", addr(1)).await.unwrap();
+        let (size, peer) = receiver.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"RC\x02again");
+        assert_eq!(peer, addr(5));
+        assert_eq!(metrics.snapshot().auth_failures, 3);
+    }
+}
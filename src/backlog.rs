@@ -0,0 +1,149 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`Backlog`], which receives the datagrams of a service until it runs.
+//!
+//! A service is often filled before it is run, while its peers already send it updates and
+//! comparison segments. Instead of leaving them in the buffer of the socket, which silently drops
+//! them once full, a task receives them as soon as the service is created, and the service
+//! handles them first when it starts running.
+
+use std::collections::VecDeque;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use parking_lot::Mutex;
+use tokio::task::JoinHandle;
+use tracing::{debug, trace, warn};
+
+use crate::internal_service::BUFFER_SIZE;
+use crate::transport::Transport;
+
+/// Maximum number of datagrams kept until the service runs; the next ones are dropped
+pub(crate) const BACKLOG_CAPACITY: usize = 1024;
+
+/// Datagrams received, along with the index of the socket and the sender
+type Datagrams = VecDeque<(usize, Vec<u8>, SocketAddr)>;
+
+pub(crate) struct Backlog {
+    datagrams: Arc<Mutex<Datagrams>>,
+    pump: Mutex<Option<JoinHandle<()>>>,
+}
+
+impl Backlog {
+    /// Start receiving from the sockets, if a runtime is available to run the task.
+    pub fn start(sockets: Arc<Vec<Box<dyn Transport>>>) -> Self {
+        let datagrams = Arc::new(Mutex::new(VecDeque::new()));
+        let pump = match tokio::runtime::Handle::try_current() {
+            Ok(runtime) => Some(runtime.spawn(pump(sockets, datagrams.clone()))),
+            Err(_) => {
+                debug!("no runtime; datagrams are only received once the service runs");
+                None
+            }
+        };
+        Backlog {
+            datagrams,
+            pump: Mutex::new(pump),
+        }
+    }
+
+    /// Stop receiving, and return the datagrams received so far, in order.
+    pub async fn stop(&self) -> Datagrams {
+        let pump = self.pump.lock().take();
+        if let Some(pump) = pump {
+            pump.abort();
+            // wait for the task, so that it does not receive concurrently with the service
+            let _ = pump.await;
+        }
+        std::mem::take(&mut *self.datagrams.lock())
+    }
+}
+
+/// The task stops when the service is dropped without running, releasing the sockets.
+impl Drop for Backlog {
+    fn drop(&mut self) {
+        if let Some(pump) = self.pump.get_mut().take() {
+            pump.abort();
+        }
+    }
+}
+
+async fn pump(sockets: Arc<Vec<Box<dyn Transport>>>, datagrams: Arc<Mutex<Datagrams>>) {
+    let recv = |index: usize| {
+        let sockets = sockets.clone();
+        let datagrams = datagrams.clone();
+        async move {
+            // extra byte that easily detect when the buffer is too small
+            let mut recv_buf = vec![0; BUFFER_SIZE + 1];
+            loop {
+                match sockets[index].recv_from(&mut recv_buf).await {
+                    Ok((size, _)) if size == recv_buf.len() => {
+                        warn!("Buffer too small for message, discarded");
+                    }
+                    Ok((size, peer)) => {
+                        let mut datagrams = datagrams.lock();
+                        if datagrams.len() < BACKLOG_CAPACITY {
+                            trace!("keeping {size} bytes from {peer} until the service runs");
+                            datagrams.push_back((index, recv_buf[..size].to_vec(), peer));
+                        } else {
+                            trace!("backlog full, dropping {size} bytes from {peer}");
+                        }
+                    }
+                    Err(err) => warn!("network error in recv_from: {err}"),
+                }
+            }
+        }
+    };
+    match sockets.len() {
+        1 => recv(0).await,
+        _ => tokio::join!(recv(0), recv(1)).0,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use tokio::net::UdpSocket;
+
+    use super::{Backlog, BACKLOG_CAPACITY};
+    use crate::transport::Transport;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn backlog() {
+        let socket = UdpSocket::bind("127.0.1.5:8080").await.unwrap();
+        let sockets: Arc<Vec<Box<dyn Transport>>> = Arc::new(vec![Box::new(socket)]);
+        let backlog = Backlog::start(sockets.clone());
+
+        // more than the buffer of the socket holds, at a pace the task keeps up with
+        let sender = UdpSocket::bind("127.0.1.6:8080").await.unwrap();
+        for i in 0..BACKLOG_CAPACITY + 10 {
+            let datagram = [i as u8; 1000];
+            sender.send_to(&datagram, "127.0.1.5:8080").await.unwrap();
+            if i % 10 == 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let datagrams = backlog.stop().await;
+        assert_eq!(datagrams.len(), BACKLOG_CAPACITY);
+        for (i, (index, datagram, peer)) in datagrams.into_iter().enumerate() {
+            assert_eq!(index, 0);
+            assert_eq!(datagram, [i as u8; 1000]);
+            assert_eq!(peer, "127.0.1.6:8080".parse().unwrap());
+        }
+
+        // the socket is left to the service
+        sender.send_to(b"hello", "127.0.1.5:8080").await.unwrap();
+        let mut buf = [0; 16];
+        let (size, _) = sockets[0].recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..size], b"hello");
+        assert!(backlog.stop().await.is_empty());
+    }
+}
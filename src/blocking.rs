@@ -0,0 +1,167 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides a blocking [`Service`], for applications without a tokio runtime.
+//!
+//! The service owns a single-threaded runtime, which runs the reconciliation on a dedicated
+//! thread once [`spawn_background`](Service::spawn_background) is called. The service can be
+//! cloned and used from any number of threads: the reads and writes of the map only take its
+//! lock, and never wait for the network.
+
+use std::borrow::Borrow;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use parking_lot::RwLockReadGuard;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::runtime::Runtime;
+use tokio::sync::watch;
+
+use crate::diff::{Diffable, HashRangeQueryable};
+use crate::error::Error;
+use crate::map::Map;
+use crate::service::{self, DatedMaybeTombstone, ValueRef};
+
+/// Blocking wrapper of a [`Service`](service::Service), running it on its own runtime.
+pub struct Service<M: Map + HashRangeQueryable>
+where
+    <M as Map>::Key: Clone + Hash + std::cmp::Eq + Send + Sync,
+{
+    service: service::Service<M>,
+    runtime: Arc<Runtime>,
+}
+
+impl<M: Map + HashRangeQueryable> Clone for Service<M>
+where
+    <M as Map>::Key: Clone + Hash + std::cmp::Eq + Send + Sync,
+{
+    fn clone(&self) -> Self {
+        Service {
+            service: self.service.clone(),
+            runtime: self.runtime.clone(),
+        }
+    }
+}
+
+impl<
+        K: Clone + Debug + DeserializeOwned + Hash + Ord + Send + Serialize + Sync + 'static,
+        V: Clone + DeserializeOwned + Hash + Send + Serialize + Sync + 'static,
+        C: Clone + Debug + DeserializeOwned + Send + Serialize + Sync + 'static,
+        D: Clone + Debug + Send + Sync + 'static,
+        M: Map<Key = K, Value = DatedMaybeTombstone<V>, DifferenceItem = D>
+            + Diffable<ComparisonItem = C, DifferenceItem = D>
+            + HashRangeQueryable<Key = K>
+            + Send
+            + Sync
+            + 'static,
+    > Service<M>
+{
+    /// Create the service and its runtime, listening on the given address.
+    pub fn new(map: M, port: u16, listen_addr: IpAddr, peer_net: IpNet) -> Result<Self, Error> {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()?;
+        let service = runtime.block_on(service::Service::new(map, port, listen_addr, peer_net))?;
+        Ok(Service {
+            service,
+            runtime: Arc::new(runtime),
+        })
+    }
+
+    /// Apply the builders of the asynchronous service, such as
+    /// [`with_seed`](service::Service::with_seed), within the runtime of the service.
+    pub fn configure<F: FnOnce(service::Service<M>) -> service::Service<M>>(self, f: F) -> Self {
+        let _guard = self.runtime.enter();
+        Service {
+            service: f(self.service),
+            runtime: self.runtime.clone(),
+        }
+    }
+
+    /// Underlying asynchronous service, for the methods without a blocking equivalent.
+    pub fn inner(&self) -> &service::Service<M> {
+        &self.service
+    }
+
+    /// Run the service on a dedicated thread, until the returned handle shuts it down.
+    pub fn spawn_background(self) -> BackgroundHandle {
+        let (sender, mut receiver) = watch::channel(false);
+        let thread = std::thread::spawn(move || {
+            self.runtime
+                .block_on(self.service.run_with_shutdown(async move {
+                    if receiver.wait_for(|&stop| stop).await.is_err() {
+                        // the handle was dropped
+                        std::future::pending::<()>().await;
+                    }
+                }))
+        });
+        BackgroundHandle { sender, thread }
+    }
+
+    pub fn read(&self) -> RwLockReadGuard<'_, M> {
+        self.service.read()
+    }
+
+    pub fn get<Q: Ord + ?Sized>(&self, k: &Q) -> Option<ValueRef<'_, V>>
+    where
+        K: Borrow<Q>,
+    {
+        self.service.get(k)
+    }
+
+    pub fn get_cloned<Q: Ord + ?Sized>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        self.service.get_cloned(k)
+    }
+
+    pub fn get_dated<Q: Ord + ?Sized>(&self, k: &Q) -> Option<DatedMaybeTombstone<V>>
+    where
+        K: Borrow<Q>,
+    {
+        self.service.get_dated(k)
+    }
+
+    pub fn multi_get(&self, keys: &[K]) -> Vec<Option<V>> {
+        self.service.multi_get(keys)
+    }
+
+    pub fn insert(&self, key: K, value: V, timestamp: DateTime<Utc>) -> Option<V> {
+        self.service.insert(key, value, timestamp)
+    }
+
+    pub fn insert_bulk(&self, key_values: &[(K, V, DateTime<Utc>)]) {
+        self.service.insert_bulk(key_values);
+    }
+
+    pub fn remove(&self, key: &K, timestamp: DateTime<Utc>) -> Option<V> {
+        self.service.remove(key, timestamp)
+    }
+}
+
+/// Handle to a blocking [`Service`] running on its own thread, returned by
+/// [`spawn_background`](Service::spawn_background).
+pub struct BackgroundHandle {
+    sender: watch::Sender<bool>,
+    thread: JoinHandle<()>,
+}
+
+impl BackgroundHandle {
+    /// Shut the service down gracefully, and wait for its thread to return; see
+    /// [`run_with_shutdown`](service::Service::run_with_shutdown).
+    pub fn shutdown(self) -> std::thread::Result<()> {
+        self.sender.send_replace(true);
+        self.thread.join()
+    }
+}
@@ -0,0 +1,227 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`BroadcastQueue`], which holds the local writes until they are sent to the peers.
+//!
+//! Writes are pushed without waiting, and sent in batches by a single broadcaster running along
+//! the service. When writes come faster than they can be sent, the queue drops some of them, as
+//! chosen by the [`BroadcastOverflow`] policy; the peers still get them at the next
+//! reconciliation sessions.
+//!
+//! The queue can be held, to keep the writes while the synchronization is paused, and send them
+//! as soon as it resumes.
+//!
+//! Each write pushed gets a sequence number, so that a [`BroadcastTicket`] can wait for the
+//! writes pushed with it to be sent.
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use parking_lot::Mutex;
+use tokio::sync::{watch, Notify};
+
+/// Which writes to drop when the broadcast queue is full.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum BroadcastOverflow {
+    /// Drop the oldest write in the queue, so that the most recent changes are sent first
+    #[default]
+    DropOldest,
+    /// Drop the new write
+    DropNewest,
+}
+
+/// Completes once the writes of a bulk operation were sent to the peers, see
+/// [`Service::insert_bulk`](crate::Service::insert_bulk).
+///
+/// The writes dropped because the broadcast queue was full count as sent. The ticket only
+/// completes while the service runs, and the synchronization is not paused.
+pub struct BroadcastTicket {
+    sent: watch::Receiver<u64>,
+    /// Sequence number of the last write to wait for
+    seq: u64,
+}
+
+impl BroadcastTicket {
+    /// Wait until the writes were sent.
+    pub async fn sent(mut self) {
+        let seq = self.seq;
+        // fails only when the queue was dropped, with the service
+        let _ = self.sent.wait_for(|&sent| sent >= seq).await;
+    }
+
+    /// Whether the writes were already sent.
+    pub fn is_sent(&self) -> bool {
+        *self.sent.borrow() >= self.seq
+    }
+}
+
+struct Items<T> {
+    /// Items with their sequence number
+    queue: VecDeque<(u64, T)>,
+    /// Sequence number of the last item pushed
+    last_seq: u64,
+    /// Sequence number of the last item popped, being sent
+    in_flight: u64,
+}
+
+pub(crate) struct BroadcastQueue<T> {
+    items: Mutex<Items<T>>,
+    capacity: usize,
+    overflow: BroadcastOverflow,
+    notify: Notify,
+    /// Whether the items are kept in the queue instead of being sent
+    held: AtomicBool,
+    /// Sequence number of the last item sent, or dropped before it
+    sent: watch::Sender<u64>,
+}
+
+impl<T> BroadcastQueue<T> {
+    pub fn new(capacity: usize, overflow: BroadcastOverflow) -> Self {
+        BroadcastQueue {
+            items: Mutex::new(Items {
+                queue: VecDeque::new(),
+                last_seq: 0,
+                in_flight: 0,
+            }),
+            capacity: capacity.max(1),
+            overflow,
+            notify: Notify::new(),
+            held: AtomicBool::new(false),
+            sent: watch::channel(0).0,
+        }
+    }
+
+    /// Keep the items in the queue until released; the queue still drops the oldest or newest
+    /// ones when it is full.
+    pub fn hold(&self, held: bool) {
+        self.held.store(held, Ordering::Relaxed);
+        if !held {
+            self.notify.notify_one();
+        }
+    }
+
+    /// Add the items at the end of the queue, without waiting.
+    ///
+    /// Return the number of items dropped because the queue was full, and a ticket completing
+    /// once the items are sent.
+    pub fn push<I: IntoIterator<Item = T>>(&self, new_items: I) -> (usize, BroadcastTicket) {
+        let mut dropped = 0;
+        let seq = {
+            let mut items = self.items.lock();
+            for item in new_items {
+                if items.queue.len() == self.capacity {
+                    dropped += 1;
+                    match self.overflow {
+                        BroadcastOverflow::DropOldest => items.queue.pop_front(),
+                        BroadcastOverflow::DropNewest => continue,
+                    };
+                }
+                items.last_seq += 1;
+                let seq = items.last_seq;
+                items.queue.push_back((seq, item));
+            }
+            items.last_seq
+        };
+        self.notify.notify_one();
+        let ticket = BroadcastTicket {
+            sent: self.sent.subscribe(),
+            seq,
+        };
+        (dropped, ticket)
+    }
+
+    /// Wait for items while the queue is not held, and remove up to `max` of them from the front
+    /// of the queue.
+    ///
+    /// The items count as sent at the next call, or at the call to [`sent`](Self::sent).
+    pub async fn pop_batch(&self, max: usize) -> Vec<T> {
+        self.sent();
+        loop {
+            {
+                let mut items = self.items.lock();
+                if !items.queue.is_empty() && !self.held.load(Ordering::Relaxed) {
+                    let count = max.min(items.queue.len());
+                    let batch: Vec<_> = items.queue.drain(..count).collect();
+                    // the items dropped before are sent along
+                    items.in_flight = batch.last().map_or(items.in_flight, |(seq, _)| *seq);
+                    return batch.into_iter().map(|(_, item)| item).collect();
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// Record that the last batch popped was sent.
+    pub fn sent(&self) {
+        let in_flight = self.items.lock().in_flight;
+        self.sent.send_if_modified(|sent| {
+            let modified = in_flight > *sent;
+            *sent = in_flight.max(*sent);
+            modified
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BroadcastOverflow, BroadcastQueue};
+
+    #[tokio::test]
+    async fn overflow() {
+        let queue = BroadcastQueue::new(3, BroadcastOverflow::DropOldest);
+        assert_eq!(queue.push(0..5).0, 2);
+        assert_eq!(queue.pop_batch(2).await, vec![2, 3]);
+        assert_eq!(queue.pop_batch(2).await, vec![4]);
+
+        let queue = BroadcastQueue::new(3, BroadcastOverflow::DropNewest);
+        assert_eq!(queue.push(0..5).0, 2);
+        assert_eq!(queue.push([5]).0, 1);
+        assert_eq!(queue.pop_batch(10).await, vec![0, 1, 2]);
+
+        // waiting for new items
+        let pop = queue.pop_batch(10);
+        queue.push([6]);
+        assert_eq!(pop.await, vec![6]);
+
+        // held items are kept until released
+        queue.hold(true);
+        queue.push([7, 8]);
+        let pop = queue.pop_batch(10);
+        tokio::pin!(pop);
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(50), &mut pop)
+                .await
+                .is_err()
+        );
+        queue.hold(false);
+        assert_eq!(pop.await, vec![7, 8]);
+    }
+
+    #[tokio::test]
+    async fn tickets() {
+        let queue = BroadcastQueue::new(3, BroadcastOverflow::DropOldest);
+        let (_, first) = queue.push([0, 1]);
+        let (_, second) = queue.push([2, 3]);
+        let (_, empty) = queue.push([]);
+        assert!(!first.is_sent());
+
+        // the first item was dropped, and counts as sent along the next batch
+        assert_eq!(queue.pop_batch(2).await, vec![1, 2]);
+        assert!(!first.is_sent());
+        queue.sent();
+        assert!(first.is_sent());
+        assert!(!second.is_sent());
+        assert!(!empty.is_sent());
+
+        let sent = tokio::spawn(second.sent());
+        assert_eq!(queue.pop_batch(2).await, vec![3]);
+        queue.sent();
+        sent.await.unwrap();
+        assert!(empty.is_sent());
+    }
+}
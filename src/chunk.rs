@@ -0,0 +1,190 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`Chunked`], a value whose content is split into chunks stored outside of the map.
+//!
+//! The map only holds the manifest of each value: its metadata, and the hashes of its chunks.
+//! Reconciliation thus only exchanges the manifests; once a manifest is received from a peer, the
+//! chunks missing locally are requested from it, and the value becomes readable once all of them
+//! arrived. The chunks are addressed by the SHA-256 of their content, so identical chunks are
+//! stored and transferred only once.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Maximum number of bytes of content in each chunk, so that a chunk fits in a datagram
+pub const CHUNK_SIZE: usize = 32 * 1024;
+
+/// SHA-256 of the content of a chunk
+pub type ChunkHash = [u8; 32];
+
+/// Manifest of a value stored in chunks.
+#[derive(Clone, Debug, Deserialize, Eq, Hash, PartialEq, Serialize)]
+pub struct Chunked<M> {
+    /// Small data stored in the map along with the chunk hashes
+    pub metadata: M,
+    /// Hashes of the chunks of the content, in order
+    pub chunks: Vec<ChunkHash>,
+}
+
+/// Value stored in chunks, as returned by [`Service::get_chunked`](crate::Service::get_chunked).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum ChunkedValue<M> {
+    /// Some chunks of the content have not been received yet
+    Pending,
+    /// The metadata, and the whole content
+    Complete(M, Vec<u8>),
+}
+
+/// Hash of the content of a chunk.
+pub(crate) fn chunk_hash(bytes: &[u8]) -> ChunkHash {
+    Sha256::digest(bytes).into()
+}
+
+/// Chunks of the values of a service, with the number of values referencing each chunk.
+///
+/// A chunk is dropped once no value references it anymore.
+#[derive(Default)]
+pub(crate) struct ChunkStore {
+    chunks: HashMap<ChunkHash, Vec<u8>>,
+    refs: HashMap<ChunkHash, usize>,
+    /// Peers to request the referenced chunks that are missing from
+    sources: HashMap<ChunkHash, SocketAddr>,
+}
+
+impl ChunkStore {
+    /// Split the content into chunks, and store them until they are referenced.
+    ///
+    /// Return the hashes of the chunks.
+    pub fn put(&mut self, data: &[u8]) -> Vec<ChunkHash> {
+        data.chunks(CHUNK_SIZE)
+            .map(|bytes| {
+                let hash = chunk_hash(bytes);
+                self.chunks.entry(hash).or_insert_with(|| bytes.to_vec());
+                hash
+            })
+            .collect()
+    }
+
+    /// Count a new reference to each of the chunks.
+    pub fn add_refs(&mut self, hashes: &[ChunkHash]) {
+        for &hash in hashes {
+            *self.refs.entry(hash).or_default() += 1;
+        }
+    }
+
+    /// Remove a reference to each of the chunks, and drop the ones no longer referenced.
+    pub fn release(&mut self, hashes: &[ChunkHash]) {
+        for hash in hashes {
+            let Some(count) = self.refs.get_mut(hash) else {
+                continue;
+            };
+            *count -= 1;
+            if *count == 0 {
+                self.refs.remove(hash);
+                self.chunks.remove(hash);
+                self.sources.remove(hash);
+            }
+        }
+    }
+
+    /// Store a chunk received from a peer, if it is referenced and its content matches its hash.
+    ///
+    /// Return whether the chunk was stored.
+    pub fn insert(&mut self, hash: ChunkHash, bytes: Vec<u8>) -> bool {
+        if !self.refs.contains_key(&hash)
+            || self.chunks.contains_key(&hash)
+            || chunk_hash(&bytes) != hash
+        {
+            return false;
+        }
+        self.chunks.insert(hash, bytes);
+        self.sources.remove(&hash);
+        true
+    }
+
+    pub fn get(&self, hash: &ChunkHash) -> Option<&[u8]> {
+        self.chunks.get(hash).map(Vec::as_slice)
+    }
+
+    /// Record the peer to request the missing chunks among the given ones from.
+    ///
+    /// Return the missing chunks.
+    pub fn want(&mut self, hashes: &[ChunkHash], source: SocketAddr) -> Vec<ChunkHash> {
+        let missing: Vec<_> = hashes
+            .iter()
+            .filter(|hash| !self.chunks.contains_key(*hash))
+            .copied()
+            .collect();
+        for &hash in &missing {
+            self.sources.insert(hash, source);
+        }
+        missing
+    }
+
+    /// Referenced chunks that are still missing, with the peer to request them from.
+    pub fn missing(&self) -> Vec<(ChunkHash, SocketAddr)> {
+        self.sources
+            .iter()
+            .map(|(&hash, &source)| (hash, source))
+            .collect()
+    }
+
+    /// Concatenate the content of the chunks, or return `None` if some of them are missing.
+    pub fn assemble(&self, hashes: &[ChunkHash]) -> Option<Vec<u8>> {
+        let mut data = Vec::new();
+        for hash in hashes {
+            data.extend_from_slice(self.chunks.get(hash)?);
+        }
+        Some(data)
+    }
+
+    /// Hashes of the chunks held.
+    pub fn hashes(&self) -> Vec<ChunkHash> {
+        self.chunks.keys().copied().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{chunk_hash, ChunkStore, CHUNK_SIZE};
+
+    #[test]
+    fn references() {
+        let peer: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let data: Vec<u8> = (0..CHUNK_SIZE * 2 + 1).map(|i| (i % 251) as u8).collect();
+        let mut store = ChunkStore::default();
+        let hashes = store.put(&data);
+        assert_eq!(hashes.len(), 3);
+        store.add_refs(&hashes);
+        store.add_refs(&hashes[..1]);
+        assert_eq!(store.assemble(&hashes), Some(data.clone()));
+
+        // chunks are dropped with their last reference
+        store.release(&hashes);
+        assert_eq!(store.hashes(), vec![hashes[0]]);
+        store.release(&hashes[..1]);
+        assert!(store.hashes().is_empty());
+
+        // only the referenced chunks with a matching content are accepted
+        let mut other = ChunkStore::default();
+        let bytes = data[..CHUNK_SIZE].to_vec();
+        assert!(!other.insert(hashes[0], bytes.clone()));
+        other.add_refs(&hashes);
+        assert_eq!(other.want(&hashes, peer).len(), 3);
+        assert!(!other.insert(hashes[1], bytes.clone()));
+        assert!(other.insert(chunk_hash(&bytes), bytes));
+        assert_eq!(other.missing().len(), 2);
+        assert_eq!(other.assemble(&hashes), None);
+    }
+}
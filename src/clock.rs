@@ -0,0 +1,91 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides the [`Clock`] trait, the source of the current time for a [`Service`](crate::Service).
+
+use std::sync::atomic::{AtomicI64, Ordering};
+
+use chrono::{DateTime, TimeZone, Utc};
+
+/// Source of the current time, used to expire tombstones and to date the local modifications.
+///
+/// Replace the [`MonotonicClock`] with [`with_clock`](crate::Service::with_clock), for instance to
+/// control the passing of time in tests.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The wall clock of the system.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// Last time returned by a [`MonotonicClock`] in the process, in microseconds
+static LAST_MONOTONIC: AtomicI64 = AtomicI64::new(i64::MIN);
+
+/// The wall clock of the system, made strictly increasing across the process.
+///
+/// Calls within the same microsecond, or after the wall clock went back, return the previous time
+/// plus 1 microsecond, as hybrid logical clocks do, so that the modifications dated with it in a
+/// tight loop never share a timestamp, which would leave their conflicts to the tie breaker.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MonotonicClock;
+
+impl Clock for MonotonicClock {
+    fn now(&self) -> DateTime<Utc> {
+        let wall = Utc::now().timestamp_micros();
+        let mut last = LAST_MONOTONIC.load(Ordering::Relaxed);
+        loop {
+            let next = wall.max(last + 1);
+            match LAST_MONOTONIC.compare_exchange_weak(
+                last,
+                next,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Utc.timestamp_nanos(next * 1000),
+                Err(current) => last = current,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::{Clock, MonotonicClock};
+
+    #[test]
+    fn monotonic() {
+        let threads: Vec<_> = (0..2)
+            .map(|_| {
+                thread::spawn(|| {
+                    (0..100_000)
+                        .map(|_| MonotonicClock.now())
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+        let mut all = Vec::new();
+        for thread in threads {
+            let times = thread.join().unwrap();
+            assert!(times.windows(2).all(|pair| pair[0] < pair[1]));
+            all.extend(times);
+        }
+        // no two calls return the same time, even from different threads
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), 200_000);
+    }
+}
@@ -0,0 +1,266 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides the [`CodecMap`], a [`Map`] whose values are stored and sent to the peers encoded by
+//! a [`ValueCodec`], for instance to encrypt them at rest and on the wire.
+//!
+//! The map holds the encoded bytes: the hashes and the conflicts between values are computed on
+//! them, so the peers agree on the state of the map whether or not they can decode its values,
+//! and the protocol is unchanged. A [`Service`](crate::Service) over a [`CodecMap`] encodes and
+//! decodes the values with [`insert_encoded`](crate::Service::insert_encoded) and
+//! [`try_get`](crate::Service::try_get).
+//!
+//! ```
+//! # use reconcile::codec::CodecMap;
+//! # use reconcile::{DatedMaybeTombstone, HRTree};
+//! let map = CodecMap::new(
+//!     HRTree::<u8, DatedMaybeTombstone<Vec<u8>>>::new(),
+//!     |value: &String| value.bytes().rev().collect(),
+//!     |bytes: &[u8]| String::from_utf8(bytes.iter().rev().copied().collect()),
+//! );
+//! let encoded = map.codec().encode(&"Hello".to_string());
+//! assert_eq!(encoded, b"olleH");
+//! assert_eq!(map.codec().decode(&encoded).unwrap(), "Hello");
+//! ```
+
+use std::borrow::{Borrow, Cow};
+use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
+
+use crate::diff::{FingerprintOf, HashRangeQueryable};
+use crate::hrtree::MergeStats;
+use crate::map::{Entries, Map, MutMap};
+use crate::service::DatedMaybeTombstone;
+
+type Encode<V> = Box<dyn Send + Sync + Fn(&V) -> Vec<u8>>;
+type Decode<V, E> = Box<dyn Send + Sync + Fn(&[u8]) -> Result<V, E>>;
+
+/// Converts the values of a [`CodecMap`] from and to the bytes it stores.
+pub struct ValueCodec<V, E> {
+    encode: Encode<V>,
+    decode: Decode<V, E>,
+}
+
+impl<V, E> ValueCodec<V, E> {
+    pub fn new<
+        EncodeFn: Send + Sync + Fn(&V) -> Vec<u8> + 'static,
+        DecodeFn: Send + Sync + Fn(&[u8]) -> Result<V, E> + 'static,
+    >(
+        encode: EncodeFn,
+        decode: DecodeFn,
+    ) -> Self {
+        ValueCodec {
+            encode: Box::new(encode),
+            decode: Box::new(decode),
+        }
+    }
+
+    pub fn encode(&self, value: &V) -> Vec<u8> {
+        (self.encode)(value)
+    }
+
+    pub fn decode(&self, bytes: &[u8]) -> Result<V, E> {
+        (self.decode)(bytes)
+    }
+}
+
+/// [`Map`] storing the values encoded by a [`ValueCodec`] in another map.
+///
+/// It behaves exactly as the inner map, whose values are the encoded bytes; the codec is only
+/// used by the methods dealing with decoded values, such as [`try_get`](CodecMap::try_get).
+pub struct CodecMap<M, V, E> {
+    map: M,
+    codec: Arc<ValueCodec<V, E>>,
+}
+
+impl<M, V, E> CodecMap<M, V, E> {
+    /// Wrap a map holding the encoded values, such as an
+    /// [`HRTree<K, DatedMaybeTombstone<Vec<u8>>>`](crate::HRTree).
+    pub fn new<
+        EncodeFn: Send + Sync + Fn(&V) -> Vec<u8> + 'static,
+        DecodeFn: Send + Sync + Fn(&[u8]) -> Result<V, E> + 'static,
+    >(
+        map: M,
+        encode: EncodeFn,
+        decode: DecodeFn,
+    ) -> Self {
+        CodecMap {
+            map,
+            codec: Arc::new(ValueCodec::new(encode, decode)),
+        }
+    }
+
+    pub fn codec(&self) -> &Arc<ValueCodec<V, E>> {
+        &self.codec
+    }
+
+    /// Map holding the encoded values.
+    pub fn inner(&self) -> &M {
+        &self.map
+    }
+}
+
+impl<K, V, E, M: Map<Key = K, Value = DatedMaybeTombstone<Vec<u8>>>> CodecMap<M, V, E> {
+    /// Get and decode the value at the given key.
+    ///
+    /// Return `None` if the key is absent or removed, and the error of the codec if the value
+    /// cannot be decoded, for instance when it was encrypted with another key.
+    pub fn try_get<Q: Ord + ?Sized>(&self, key: &Q) -> Option<Result<V, E>>
+    where
+        K: Borrow<Q>,
+    {
+        let value = self.map.get(key)?;
+        let bytes = value.1.as_ref()?;
+        Some(self.codec.decode(bytes))
+    }
+
+    /// Get the timestamp of the value at the given key, and the value decoded, unless removed.
+    pub fn try_get_dated<Q: Ord + ?Sized>(
+        &self,
+        key: &Q,
+    ) -> Option<DatedMaybeTombstone<Result<V, E>>>
+    where
+        K: Borrow<Q>,
+    {
+        let value = self.map.get(key)?;
+        let decoded = value.1.as_ref().map(|bytes| self.codec.decode(bytes));
+        Some((value.0, decoded))
+    }
+}
+
+impl<M: Map, V, E> Map for CodecMap<M, V, E> {
+    type Key = M::Key;
+    type Value = M::Value;
+    type DifferenceItem = M::DifferenceItem;
+
+    fn enumerate_diff_ranges(
+        &self,
+        diff_ranges: Vec<Self::DifferenceItem>,
+    ) -> Vec<(Self::Key, Self::Value)> {
+        self.map.enumerate_diff_ranges(diff_ranges)
+    }
+
+    fn enumerate_diff_ranges_iter<'a>(
+        &'a self,
+        diff_ranges: Vec<Self::DifferenceItem>,
+    ) -> Box<dyn Iterator<Item = (Self::Key, Self::Value)> + 'a>
+    where
+        Self::Key: 'a,
+        Self::Value: 'a,
+    {
+        self.map.enumerate_diff_ranges_iter(diff_ranges)
+    }
+
+    fn enumerate_diff_range_chunk(
+        &self,
+        diff_range: Self::DifferenceItem,
+        limit: usize,
+    ) -> (Vec<(Self::Key, Self::Value)>, Option<Self::DifferenceItem>) {
+        self.map.enumerate_diff_range_chunk(diff_range, limit)
+    }
+
+    fn diff_range_contains(diff_range: &Self::DifferenceItem, key: &Self::Key) -> bool {
+        M::diff_range_contains(diff_range, key)
+    }
+
+    fn enumerate_range(
+        &self,
+        range: &(Bound<Self::Key>, Bound<Self::Key>),
+        limit: usize,
+    ) -> Vec<(Self::Key, Self::Value)> {
+        self.map.enumerate_range(range, limit)
+    }
+
+    fn enumerate_by_rank(&self, start: usize, count: usize) -> Vec<(Self::Key, Self::Value)> {
+        self.map.enumerate_by_rank(start, count)
+    }
+
+    fn get<'a, Q: Ord + ?Sized>(&'a self, key: &Q) -> Option<Cow<'a, Self::Value>>
+    where
+        Self::Key: Borrow<Q>,
+    {
+        self.map.get(key)
+    }
+
+    fn contains_key<Q: Ord + ?Sized>(&self, key: &Q) -> bool
+    where
+        Self::Key: Borrow<Q>,
+    {
+        self.map.contains_key(key)
+    }
+
+    fn iter_entries(&self) -> Entries<'_, Self::Key, Self::Value> {
+        self.map.iter_entries()
+    }
+
+    fn insert(&mut self, key: Self::Key, value: Self::Value) -> Option<Self::Value> {
+        self.map.insert(key, value)
+    }
+
+    fn remove(&mut self, key: &Self::Key) -> Option<Self::Value> {
+        self.map.remove(key)
+    }
+
+    fn retain<P: FnMut(&Self::Key, &Self::Value) -> bool>(
+        &mut self,
+        predicate: P,
+    ) -> Vec<(Self::Key, Self::Value)> {
+        self.map.retain(predicate)
+    }
+
+    fn merge_from_sorted_with<I, D, C>(&mut self, iter: I, decide: D, changed: C) -> MergeStats
+    where
+        Self::Key: Ord,
+        I: IntoIterator<Item = (Self::Key, Self::Value)>,
+        D: FnMut(&Self::Key, &Self::Value, &Self::Value) -> bool,
+        C: FnMut(&Self::Key, &Self::Value, Option<&Self::Value>),
+    {
+        self.map.merge_from_sorted_with(iter, decide, changed)
+    }
+}
+
+impl<M: MutMap, V, E> MutMap for CodecMap<M, V, E> {
+    fn get_mut<C: FnOnce(Option<&mut Self::Value>)>(&mut self, key: &Self::Key, callback: C) {
+        self.map.get_mut(key, callback)
+    }
+}
+
+impl<M: HashRangeQueryable, V, E> HashRangeQueryable for CodecMap<M, V, E> {
+    type Key = M::Key;
+    type Fingerprint = M::Fingerprint;
+
+    fn hash<R: RangeBounds<Self::Key>>(&self, range: &R) -> FingerprintOf<M> {
+        self.map.hash(range)
+    }
+
+    fn insertion_position<Q: Ord + ?Sized>(&self, key: &Q) -> usize
+    where
+        Self::Key: Borrow<Q>,
+    {
+        self.map.insertion_position(key)
+    }
+
+    fn key_at(&self, index: usize) -> &Self::Key {
+        self.map.key_at(index)
+    }
+
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    fn root_hash(&self) -> FingerprintOf<M> {
+        self.map.root_hash()
+    }
+
+    fn hash_of<Q: Ord + ?Sized>(&self, key: &Q) -> Option<FingerprintOf<M>>
+    where
+        Self::Key: Borrow<Q>,
+    {
+        self.map.hash_of(key)
+    }
+}
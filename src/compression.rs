@@ -0,0 +1,86 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`Compression`], which compresses the large datagrams of a service with zstd.
+//!
+//! A compressed datagram has the [`COMPRESSED`] flag set in the protocol version of its header,
+//! and the messages after the header are compressed as a whole. Compressed datagrams are always
+//! accepted, but only sent by the services with a compression level, so that compression can be
+//! enabled on the instances of a cluster one by one.
+
+/// Flag of the protocol version for compressed datagrams
+pub(crate) const COMPRESSED: u8 = 0x80;
+/// Maximum size of the messages of a compressed datagram, once decompressed
+pub(crate) const MAX_DECOMPRESSED_SIZE: usize = 1 << 18;
+/// Datagrams up to this size are not worth compressing
+const DEFAULT_THRESHOLD: usize = 1024;
+
+/// Settings of the compression of the datagrams sent.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Compression {
+    /// zstd level, or `None` to send the datagrams uncompressed
+    pub level: Option<i32>,
+    pub threshold: usize,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Compression {
+            level: None,
+            threshold: DEFAULT_THRESHOLD,
+        }
+    }
+}
+
+impl Compression {
+    pub fn is_enabled(&self) -> bool {
+        self.level.is_some()
+    }
+
+    /// Compressed messages of a datagram, if they are larger than the threshold and compression
+    /// makes them smaller.
+    pub fn compress(&self, messages: &[u8]) -> Option<Vec<u8>> {
+        let level = self.level.filter(|_| messages.len() > self.threshold)?;
+        zstd::bulk::compress(messages, level)
+            .ok()
+            .filter(|compressed| compressed.len() < messages.len())
+    }
+}
+
+/// Decompress the messages of a compressed datagram, failing beyond [`MAX_DECOMPRESSED_SIZE`].
+pub(crate) fn decompress(compressed: &[u8]) -> std::io::Result<Vec<u8>> {
+    zstd::bulk::decompress(compressed, MAX_DECOMPRESSED_SIZE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decompress, Compression, MAX_DECOMPRESSED_SIZE};
+
+    #[test]
+    fn round_trip() {
+        let compression = Compression {
+            level: Some(3),
+            ..Compression::default()
+        };
+        // small or incompressible messages are sent as is
+        assert_eq!(compression.compress(b"hello"), None);
+        let noise: Vec<u8> = (0..10_000).map(|_| rand::random()).collect();
+        assert_eq!(compression.compress(&noise), None);
+        assert_eq!(Compression::default().compress(&[0; 10_000]), None);
+
+        let messages = br#"{"name": "value"}"#.repeat(1000);
+        let compressed = compression.compress(&messages).unwrap();
+        assert!(compressed.len() < messages.len() / 5);
+        assert_eq!(decompress(&compressed).unwrap(), messages);
+
+        // bombs are rejected
+        let bomb = zstd::bulk::compress(&vec![0; MAX_DECOMPRESSED_SIZE + 1], 3).unwrap();
+        assert!(decompress(&bomb).is_err());
+        assert!(decompress(b"garbage").is_err());
+    }
+}
@@ -0,0 +1,427 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`explain_diff`], which reports the differences between two maps, to investigate
+//! instances that do not converge.
+//!
+//! The maps may be compared in-process, or loaded from the snapshots of the instances with
+//! [`read_snapshot`]:
+//!
+//! ```no_run
+//! # use reconcile::{debug, HRTree, DatedMaybeTombstone};
+//! type Map = HRTree<String, DatedMaybeTombstone<String>>;
+//! let a: Map = debug::read_snapshot("a.snapshot").unwrap();
+//! let b: Map = debug::read_snapshot("b.snapshot").unwrap();
+//! println!("{}", debug::explain_diff(&a, &b, 20));
+//! ```
+
+use std::cmp::Ordering;
+use std::collections::BTreeSet;
+use std::fmt::{Debug, Display};
+use std::ops::Bound;
+use std::path::Path;
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde::de::DeserializeOwned;
+
+use crate::diff::{
+    range_indices, DiffRange, Diffable, FingerprintOf, HashRangeQueryable, MaybeParallel,
+};
+use crate::map::Map;
+use crate::service::DatedMaybeTombstone;
+use crate::snapshot;
+
+/// State of a key in one of the maps.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EntryReport<H> {
+    pub timestamp: DateTime<Utc>,
+    pub tombstone: bool,
+    /// Hash of the element, as cumulated in the hashes of the map
+    pub hash: H,
+}
+
+/// Key that differs between the maps, with its state in each of them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ItemReport<K, H> {
+    pub key: K,
+    pub a: Option<EntryReport<H>>,
+    pub b: Option<EntryReport<H>>,
+}
+
+/// Differing range found by the diff protocol.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RangeReport<K, H> {
+    pub range: DiffRange<K>,
+    /// The first differing keys of the range
+    pub items: Vec<ItemReport<K, H>>,
+    /// Number of differing keys of the range not listed in `items`
+    pub omitted: usize,
+}
+
+/// Differences between two maps, as returned by [`explain_diff`].
+///
+/// The [`Display`] implementation renders the report as a table per range.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DiffReport<K, H> {
+    pub ranges: Vec<RangeReport<K, H>>,
+    /// Number of keys only present in the first map (tombstones included)
+    pub only_a: usize,
+    /// Number of keys only present in the second map (tombstones included)
+    pub only_b: usize,
+    /// Number of keys present in both maps with different values
+    pub conflicting: usize,
+}
+
+impl<K, H> DiffReport<K, H> {
+    /// Whether the maps hold the same entries.
+    pub fn is_empty(&self) -> bool {
+        self.only_a == 0 && self.only_b == 0 && self.conflicting == 0
+    }
+}
+
+/// Read a map from a snapshot file saved by
+/// [`Service::save_snapshot`](crate::service::Service::save_snapshot).
+pub fn read_snapshot<M: DeserializeOwned, P: AsRef<Path>>(path: P) -> std::io::Result<M> {
+    snapshot::load(path)
+}
+
+/// Report the differences between two maps.
+///
+/// The ranges are the ones found by running the diff protocol between the maps, in both
+/// directions, in the order they are found. Within each range, the keys whose elements differ are listed, up to `max_items`
+/// per range; the totals count all of them. A key found in several ranges is only reported in the
+/// first one.
+///
+/// The keys of the ranges are walked without reading the values, which are only read for the
+/// listed keys, so that large ranges are cheap to count.
+pub fn explain_diff<K, V, M>(a: &M, b: &M, max_items: usize) -> DiffReport<K, FingerprintOf<M>>
+where
+    K: Clone + MaybeParallel + Ord,
+    V: Clone,
+    M: Map<Key = K, Value = DatedMaybeTombstone<V>> + HashRangeQueryable<Key = K> + MaybeParallel,
+{
+    let mut report = DiffReport {
+        ranges: Vec::new(),
+        only_a: 0,
+        only_b: 0,
+        conflicting: 0,
+    };
+    let mut seen = BTreeSet::new();
+    for range in diff_ranges(a, b) {
+        let mut range_report = RangeReport {
+            range,
+            items: Vec::new(),
+            omitted: 0,
+        };
+        for (key, hash_a, hash_b) in differing_keys(a, b, &range_report.range) {
+            if !seen.insert(key.clone()) {
+                continue;
+            }
+            match (hash_a, hash_b) {
+                (Some(_), Some(_)) => report.conflicting += 1,
+                (Some(_), None) => report.only_a += 1,
+                _ => report.only_b += 1,
+            }
+            if range_report.items.len() < max_items {
+                range_report.items.push(ItemReport {
+                    a: hash_a.and_then(|hash| entry_report(a, &key, hash)),
+                    b: hash_b.and_then(|hash| entry_report(b, &key, hash)),
+                    key,
+                });
+            } else {
+                range_report.omitted += 1;
+            }
+        }
+        if !range_report.items.is_empty() || range_report.omitted > 0 {
+            report.ranges.push(range_report);
+        }
+    }
+    report
+}
+
+/// Run the diff protocol between the maps until it completes, and return the differing ranges
+/// found by both sides.
+fn diff_ranges<K: Clone + MaybeParallel + Ord, M: HashRangeQueryable<Key = K> + MaybeParallel>(
+    a: &M,
+    b: &M,
+) -> Vec<DiffRange<K>> {
+    let mut differences = Vec::new();
+    let mut segments = a.start_diff();
+    let sides = [a, b];
+    let mut turn = 1;
+    while !segments.is_empty() {
+        let mut out_comparison = Vec::new();
+        sides[turn].diff_round(segments, &mut out_comparison, &mut differences);
+        segments = out_comparison;
+        turn = 1 - turn;
+    }
+    differences
+}
+
+/// Keys of the range whose elements differ between the maps, with the hashes of their elements.
+fn differing_keys<'a, K: Clone + Ord, M: HashRangeQueryable<Key = K>>(
+    a: &'a M,
+    b: &'a M,
+    range: &DiffRange<K>,
+) -> impl Iterator<Item = (K, Option<FingerprintOf<M>>, Option<FingerprintOf<M>>)> + 'a {
+    let (mut index_a, end_a) = range_indices(a, range);
+    let (mut index_b, end_b) = range_indices(b, range);
+    std::iter::from_fn(move || loop {
+        let key_a = (index_a < end_a).then(|| a.key_at(index_a));
+        let key_b = (index_b < end_b).then(|| b.key_at(index_b));
+        // a missing key sorts after the present one
+        let ordering = match (key_a, key_b) {
+            (None, None) => return None,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some(key_a), Some(key_b)) => key_a.cmp(key_b),
+        };
+        let (key, hash_a, hash_b) = match (ordering, key_a, key_b) {
+            (Ordering::Less, Some(key_a), _) => {
+                index_a += 1;
+                (key_a, a.hash_of(key_a), None)
+            }
+            (Ordering::Greater, _, Some(key_b)) => {
+                index_b += 1;
+                (key_b, None, b.hash_of(key_b))
+            }
+            (_, Some(key), _) => {
+                index_a += 1;
+                index_b += 1;
+                (key, a.hash_of(key), b.hash_of(key))
+            }
+            (_, None, _) => unreachable!("the key of the first map is only missing when greater"),
+        };
+        if hash_a != hash_b {
+            return Some((key.clone(), hash_a, hash_b));
+        }
+    })
+}
+
+fn entry_report<K, V, M>(
+    map: &M,
+    key: &K,
+    hash: FingerprintOf<M>,
+) -> Option<EntryReport<FingerprintOf<M>>>
+where
+    K: Ord,
+    V: Clone,
+    M: Map<Key = K, Value = DatedMaybeTombstone<V>> + HashRangeQueryable<Key = K>,
+{
+    let value = map.get(key)?;
+    Some(EntryReport {
+        timestamp: value.0,
+        tombstone: value.1.is_none(),
+        hash,
+    })
+}
+
+fn format_range<K: Debug>((start, end): &DiffRange<K>) -> String {
+    let start = match start {
+        Bound::Unbounded => "(..".to_string(),
+        Bound::Included(key) => format!("[{key:?}"),
+        Bound::Excluded(key) => format!("({key:?}"),
+    };
+    let end = match end {
+        Bound::Unbounded => "..)".to_string(),
+        Bound::Included(key) => format!("{key:?}]"),
+        Bound::Excluded(key) => format!("{key:?})"),
+    };
+    format!("{start}, {end}")
+}
+
+fn format_entry<H: Display>(entry: &Option<EntryReport<H>>) -> String {
+    match entry {
+        None => "missing".to_string(),
+        Some(entry) => format!(
+            "{} {} {}",
+            entry.timestamp.to_rfc3339_opts(SecondsFormat::Millis, true),
+            if entry.tombstone {
+                "tombstone"
+            } else {
+                "value"
+            },
+            entry.hash
+        ),
+    }
+}
+
+impl<K: Debug, H: Display> Display for DiffReport<K, H> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for range in &self.ranges {
+            writeln!(
+                f,
+                "range {}: {} differing keys",
+                format_range(&range.range),
+                range.items.len() + range.omitted
+            )?;
+            let rows: Vec<[String; 3]> = range
+                .items
+                .iter()
+                .map(|item| {
+                    [
+                        format!("{:?}", item.key),
+                        format_entry(&item.a),
+                        format_entry(&item.b),
+                    ]
+                })
+                .collect();
+            let header = ["key".to_string(), "A".to_string(), "B".to_string()];
+            let width = |column: usize| {
+                rows.iter()
+                    .chain([&header])
+                    .map(|row| row[column].chars().count())
+                    .max()
+                    .unwrap_or(0)
+            };
+            let (width0, width1) = (width(0), width(1));
+            for row in std::iter::once(&header).chain(&rows) {
+                writeln!(f, "  {:width0$} | {:width1$} | {}", row[0], row[1], row[2])?;
+            }
+            if range.omitted > 0 {
+                writeln!(f, "  ({} more keys)", range.omitted)?;
+            }
+        }
+        write!(
+            f,
+            "only in A: {}, only in B: {}, conflicting: {}",
+            self.only_a, self.only_b, self.conflicting
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use chrono::{DateTime, Utc};
+    use rand::{Rng, SeedableRng};
+
+    use super::{explain_diff, EntryReport};
+    use crate::hrtree::HRTree;
+    use crate::service::DatedMaybeTombstone;
+
+    type Tree = HRTree<u16, DatedMaybeTombstone<u8>>;
+
+    fn random_value(rng: &mut impl Rng) -> DatedMaybeTombstone<u8> {
+        let timestamp = DateTime::from_timestamp(rng.gen_range(0..4), 0).unwrap();
+        (timestamp, rng.gen_bool(0.8).then(|| rng.gen_range(0..4)))
+    }
+
+    /// Two trees sharing most of their entries
+    fn random_trees(seed: u64, len: usize) -> (Tree, Tree) {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+        let mut a = Tree::new();
+        let mut b = Tree::new();
+        for _ in 0..len {
+            let key = rng.gen_range(0..4 * len as u16);
+            let value = random_value(&mut rng);
+            match rng.gen_range(0..10) {
+                0 => {
+                    a.insert(key, value);
+                }
+                1 => {
+                    b.insert(key, value);
+                }
+                2 => {
+                    a.insert(key, value);
+                    b.insert(key, random_value(&mut rng));
+                }
+                _ => {
+                    a.insert(key, value);
+                    b.insert(key, value);
+                }
+            }
+        }
+        (a, b)
+    }
+
+    #[test]
+    fn matches_brute_force() {
+        for seed in 0..20 {
+            let (a, b) = random_trees(seed, 1 + 100 * seed as usize);
+            // brute-force comparison
+            let mut expected = BTreeMap::new();
+            for (key, value) in a.iter() {
+                if b.get(key) != Some(value) {
+                    expected.insert(*key, (Some(*value), b.get(key).copied()));
+                }
+            }
+            for (key, value) in b.iter() {
+                if a.get(key).is_none() {
+                    expected.insert(*key, (None, Some(*value)));
+                }
+            }
+
+            let report = explain_diff(&a, &b, usize::MAX);
+            let count =
+                |f: fn(&(Option<_>, Option<_>)) -> bool| expected.values().filter(|v| f(v)).count();
+            assert_eq!(report.only_a, count(|v| v.1.is_none()));
+            assert_eq!(report.only_b, count(|v| v.0.is_none()));
+            assert_eq!(
+                report.conflicting,
+                count(|v| v.0.is_some() && v.1.is_some())
+            );
+            assert_eq!(report.is_empty(), expected.is_empty());
+            let entry = |tree: &Tree, key, value: Option<DatedMaybeTombstone<u8>>| {
+                value.map(|(timestamp, value)| EntryReport {
+                    timestamp,
+                    tombstone: value.is_none(),
+                    hash: tree.hash_of(&key).unwrap(),
+                })
+            };
+            let mut listed = BTreeMap::new();
+            for range in &report.ranges {
+                assert_eq!(range.omitted, 0);
+                for item in &range.items {
+                    assert!(listed
+                        .insert(item.key, (item.a.clone(), item.b.clone()))
+                        .is_none());
+                }
+            }
+            let expected: BTreeMap<_, _> = expected
+                .into_iter()
+                .map(|(key, (value_a, value_b))| {
+                    (key, (entry(&a, key, value_a), entry(&b, key, value_b)))
+                })
+                .collect();
+            assert_eq!(listed, expected);
+
+            // the totals do not depend on the number of listed keys
+            let limited = explain_diff(&a, &b, 1);
+            assert_eq!(
+                (limited.only_a, limited.only_b, limited.conflicting),
+                (report.only_a, report.only_b, report.conflicting)
+            );
+            for range in &limited.ranges {
+                assert!(range.items.len() <= 1);
+            }
+        }
+    }
+
+    #[test]
+    fn display() {
+        let timestamp = DateTime::from_timestamp(0, 0).unwrap();
+        let a: Tree = [(1, (timestamp, Some(1))), (2, (timestamp, Some(2)))]
+            .into_iter()
+            .collect();
+        let b: Tree = [(2, (timestamp, None)), (3, (Utc::now(), Some(3)))]
+            .into_iter()
+            .collect();
+        let report = explain_diff(&a, &b, 2);
+        let rendered = report.to_string();
+        assert!(rendered.contains("1970-01-01T00:00:00.000Z tombstone"));
+        assert!(rendered.contains("missing"));
+        assert!(rendered.ends_with("only in A: 1, only in B: 1, conflicting: 1"));
+        assert!(explain_diff(&a, &a, 2).is_empty());
+        assert_eq!(
+            explain_diff(&a, &a, 2).to_string(),
+            "only in A: 0, only in B: 0, conflicting: 0"
+        );
+    }
+}
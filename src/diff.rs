@@ -8,10 +8,17 @@
 
 //! Provides two traits:
 //! [`HashRangeQueryable`] and [`Diffable`].
+//!
+//! The way differing segments are answered is set by [`DiffTuning`].
 
+use std::borrow::Borrow;
+use std::cmp::Ordering;
 use std::ops::{Bound, RangeBounds};
 
 use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use crate::fingerprint::FingerprintStrategy;
 
 /// Provides the necessary methods to be able
 /// to efficiently determine and compare
@@ -20,33 +27,460 @@ use serde::{Deserialize, Serialize};
 /// * [`insertion_position`](HashRangeQueryable::insertion_position),
 /// * [`key_at`](HashRangeQueryable::key_at),
 /// * [`len`](HashRangeQueryable::len)
-/// (with [`is_empty`](HashRangeQueryable::is_empty) as a default implementation).
+///   (with [`is_empty`](HashRangeQueryable::is_empty) as a default implementation).
+///
 /// This is a low-level trait.
+///
+/// The keys must be ordered consistently on both peers: their [`Ord`] must be a total order
+/// agreeing with their [`Eq`], and equal keys must have equal hashes. Otherwise, the peers compare
+/// hashes of ranges holding different keys, and never converge.
 pub trait HashRangeQueryable {
     type Key;
+    /// Defines how the hashes of the elements are computed and cumulated.
+    type Fingerprint: FingerprintStrategy;
+    /// Whether the elements are hashed in their serialized form, see
+    /// [`HRTree::with_canonical_hashing`](crate::HRTree::with_canonical_hashing).
+    ///
+    /// The default implementation returns `false`.
+    fn canonical_hashing(&self) -> bool {
+        false
+    }
     /// Cumulated hash over a given range of keys. For instance, it could be the XOR of all the hashes of the elements in the range.
-    fn hash<R: RangeBounds<Self::Key>>(&self, range: &R) -> u64;
-    /// Position of the given key in the collection, if it exists, or position where it would be after insertion otherwise
-    fn insertion_position(&self, key: &Self::Key) -> usize;
+    fn hash<R: RangeBounds<Self::Key>>(
+        &self,
+        range: &R,
+    ) -> <Self::Fingerprint as FingerprintStrategy>::Output;
+    /// Position of the given key in the collection, if it exists, or position where it would be after insertion otherwise.
+    /// The key may be any borrowed form of [`Key`](HashRangeQueryable::Key).
+    fn insertion_position<Q: Ord + ?Sized>(&self, key: &Q) -> usize
+    where
+        Self::Key: Borrow<Q>;
     /// Reference to the [`Key`](HashRangeQueryable::Key) at a given position. Panics if the key is not in the collection.
     fn key_at(&self, index: usize) -> &Self::Key;
     /// Number of elements in the collection.
     fn len(&self) -> usize;
+    /// Cumulated hash of the whole collection.
+    ///
+    /// The default implementation hashes the unbounded range.
+    fn root_hash(&self) -> <Self::Fingerprint as FingerprintStrategy>::Output {
+        self.hash(&..)
+    }
     fn is_empty(&self) -> bool {
         self.len() == 0
     }
+    /// Hash of the element with the given key, if it exists.
+    ///
+    /// The default implementation hashes the range made of the key alone.
+    fn hash_of<Q: Ord + ?Sized>(
+        &self,
+        key: &Q,
+    ) -> Option<<Self::Fingerprint as FingerprintStrategy>::Output>
+    where
+        Self::Key: Borrow<Q>,
+    {
+        let index = self.insertion_position(key);
+        if index >= self.len() {
+            return None;
+        }
+        let local_key = self.key_at(index);
+        (local_key.borrow() == key)
+            .then(|| self.hash(&(Bound::Included(local_key), Bound::Included(local_key))))
+    }
 }
 
+/// Type of the cumulated hashes of a [`HashRangeQueryable`].
+pub type FingerprintOf<T> = <<T as HashRangeQueryable>::Fingerprint as FingerprintStrategy>::Output;
+
 /// Represents the elements of the collections in the given key range. The `hash` and `size` fields allow testing whether the two segments represent the same elements.
+///
+/// Small segments may also list their elements individually, as keys with their hashes, so that
+/// the peer can tell which ones differ without further rounds.
 #[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
-pub struct HashSegment<K> {
+pub struct HashSegment<K, H = u64> {
     range: (Bound<K>, Bound<K>),
-    hash: u64,
+    hash: H,
+    size: usize,
+    items: Option<Vec<(K, H)>>,
+}
+
+impl<K, H> HashSegment<K, H> {
+    /// Range of keys covered by the segment.
+    pub(crate) fn range(&self) -> &DiffRange<K> {
+        &self.range
+    }
+
+    /// Borrow the keys of the segment, to serialize it without cloning them.
+    pub fn as_ref(&self) -> HashSegmentRef<'_, K, H>
+    where
+        H: Copy,
+    {
+        HashSegmentRef {
+            range: (self.range.0.as_ref(), self.range.1.as_ref()),
+            hash: self.hash,
+            size: self.size,
+            items: self
+                .items
+                .as_ref()
+                .map(|items| items.iter().map(|(key, hash)| (key, *hash)).collect()),
+        }
+    }
+
+    /// Apply the function to the hash of the segment and to the ones of its items.
+    fn map_hashes(mut self, f: impl Fn(H) -> H) -> Self
+    where
+        H: Copy,
+    {
+        self.hash = f(self.hash);
+        if let Some(items) = &mut self.items {
+            for (_, hash) in items {
+                *hash = f(*hash);
+            }
+        }
+        self
+    }
+}
+
+/// Mirror of [`HashSegment`] borrowing its keys, which serializes identically.
+///
+/// Segments are refined as borrowed ones while the collection is locked, so that the keys are only
+/// cloned for the segments actually sent.
+#[derive(Clone, Debug, Eq, PartialEq, Serialize)]
+pub struct HashSegmentRef<'a, K, H = u64> {
+    range: (Bound<&'a K>, Bound<&'a K>),
+    hash: H,
     size: usize,
+    items: Option<Vec<(&'a K, H)>>,
 }
 
+impl<K: Clone, H: Copy> HashSegmentRef<'_, K, H> {
+    /// Clone the keys of the segment.
+    pub fn to_owned(&self) -> HashSegment<K, H> {
+        HashSegment {
+            range: (self.range.0.cloned(), self.range.1.cloned()),
+            hash: self.hash,
+            size: self.size,
+            items: self.items.as_ref().map(|items| {
+                items
+                    .iter()
+                    .map(|&(key, hash)| (key.clone(), hash))
+                    .collect()
+            }),
+        }
+    }
+}
+
+/// Differing segments with at most this number of elements are listed item by item, instead of
+/// being split further
+const ITEMS_THRESHOLD: usize = 8;
+
+/// Diff rounds whose estimated work, the number of segments times the depth of the tree, reaches
+/// this value process the segments in parallel
+#[cfg(feature = "parallel")]
+const PARALLEL_WORK_THRESHOLD: usize = 256;
+
+/// Bound of the maps and of their keys in [`Diffable::diff_round`].
+///
+/// With the `parallel` feature, the segments of a round are processed by several threads, which
+/// share the map and send the keys back, so this requires [`Send`] and [`Sync`]. Otherwise, it
+/// holds for any type.
+#[cfg(feature = "parallel")]
+pub trait MaybeParallel: Send + Sync {}
+#[cfg(feature = "parallel")]
+impl<T: Send + Sync> MaybeParallel for T {}
+
+/// Bound of the maps and of their keys in [`Diffable::diff_round`].
+///
+/// With the `parallel` feature, the segments of a round are processed by several threads, which
+/// share the map and send the keys back, so this requires [`Send`] and [`Sync`]. Otherwise, it
+/// holds for any type.
+#[cfg(not(feature = "parallel"))]
+pub trait MaybeParallel {}
+#[cfg(not(feature = "parallel"))]
+impl<T> MaybeParallel for T {}
+
+/// Number of segments received in a datagram whose local hashes are compared to choose the
+/// [`SplitStrategy`], when the density of the previous round is unknown
+const DENSITY_SAMPLE: usize = 64;
+
 pub type DiffRange<K> = (Bound<K>, Bound<K>);
 
+/// How [`Diffable::diff_round_split`] answers the differing segments.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SplitStrategy {
+    /// Number of segments a differing segment is split into
+    pub fan_out: usize,
+    /// Differing segments with at most this number of elements are sent directly, instead of
+    /// being split or listed item by item; 0 to never send them directly
+    pub direct_items: usize,
+}
+
+impl Default for SplitStrategy {
+    fn default() -> Self {
+        SplitStrategy {
+            fan_out: 16,
+            direct_items: 0,
+        }
+    }
+}
+
+/// Settings of the diff rounds of a [`Service`](crate::Service), see
+/// [`with_diff_tuning`](crate::Service::with_diff_tuning).
+///
+/// By default, the differing segments are split into 16 segments, which finds a few differences
+/// among many elements in few rounds. When most elements differ, as when an instance catches up
+/// after a long downtime, this only adds rounds: with [`adaptive`](DiffTuning::adaptive), the
+/// segments received in a session that mostly differ are answered in the dense mode instead,
+/// which splits them wider and sends the smaller ones directly. A session between instances of
+/// very different sizes, as when a fresh instance joins, is answered directly from the start.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DiffTuning {
+    /// Number of segments a differing segment is split into
+    pub fan_out: usize,
+    /// Fraction of the segments received in a datagram that differ locally above which they are
+    /// answered in the dense mode, or `None` to never use it
+    pub dense_threshold: Option<f64>,
+    /// In the dense mode, differing segments with at most this number of elements are sent
+    /// directly, and the peer is asked to send its own
+    pub dense_items: usize,
+    /// In the dense mode, number of segments the larger differing segments are split into, at
+    /// most; the reply must still fit in a datagram
+    pub dense_fan_out: usize,
+    /// When the dense mode is enabled, a session is answered directly when one of the instances
+    /// holds at most this many times fewer elements than the other
+    pub bootstrap_ratio: usize,
+}
+
+impl Default for DiffTuning {
+    fn default() -> Self {
+        DiffTuning {
+            fan_out: SplitStrategy::default().fan_out,
+            dense_threshold: None,
+            dense_items: 256,
+            dense_fan_out: 128,
+            bootstrap_ratio: 8,
+        }
+    }
+}
+
+impl DiffTuning {
+    /// Use the dense mode when more than 70% of the segments received in a datagram differ.
+    pub fn adaptive() -> Self {
+        DiffTuning {
+            dense_threshold: Some(0.7),
+            ..DiffTuning::default()
+        }
+    }
+
+    /// Strategy to answer the segments received from a peer in a session, splitting them into
+    /// at most `max_fan_out` segments.
+    ///
+    /// `density` is the fraction of the segments that differed in the previous round of the
+    /// session, when it had several; otherwise, it is sampled from the given segments. The
+    /// segment opening a session tells nothing about the density of the differences: it is
+    /// answered directly when one of the instances holds much fewer elements than the other, and
+    /// in the default mode otherwise.
+    pub fn strategy<'a, T: Diffable + HashRangeQueryable>(
+        &self,
+        map: &T,
+        segments: impl IntoIterator<Item = &'a <T as Diffable>::ComparisonItem>,
+        density: Option<f64>,
+        max_fan_out: usize,
+    ) -> SplitStrategy
+    where
+        <T as Diffable>::ComparisonItem: 'a,
+    {
+        let default = SplitStrategy {
+            fan_out: self.fan_out,
+            direct_items: 0,
+        };
+        let Some(threshold) = self.dense_threshold else {
+            return default;
+        };
+        let sample: Vec<_> = segments.into_iter().take(DENSITY_SAMPLE).collect();
+        if let Some(remote) = sample.iter().find_map(|segment| T::whole_size(segment)) {
+            let local = map.len();
+            if local.min(remote).saturating_mul(self.bootstrap_ratio) > local.max(remote) {
+                return default;
+            }
+            // send all the local elements, and ask the peer to send all its own
+            return SplitStrategy {
+                fan_out: self.fan_out,
+                direct_items: local,
+            };
+        }
+        let Some(density) = density.or_else(|| differing_fraction(map, sample)) else {
+            return default;
+        };
+        if density <= threshold {
+            return default;
+        }
+        SplitStrategy {
+            fan_out: self.dense_fan_out.min(max_fan_out).max(self.fan_out),
+            direct_items: self.dense_items,
+        }
+    }
+}
+
+/// Fraction of the segments received from a peer that differ locally, unless there are less than
+/// 2 of them.
+fn differing_fraction<'a, T: Diffable>(
+    map: &T,
+    segments: impl IntoIterator<Item = &'a T::ComparisonItem>,
+) -> Option<f64>
+where
+    T::ComparisonItem: 'a,
+{
+    let mut count = 0;
+    let mut differing = 0;
+    for segment in segments {
+        count += 1;
+        if map.comparison_differs(segment) {
+            differing += 1;
+        }
+    }
+    (count >= 2).then(|| differing as f64 / count as f64)
+}
+
+/// Intersection of two ranges, or `None` if it is obviously empty.
+///
+/// The intersection of `(Excluded(a), Excluded(b))` might still be empty for discrete keys.
+pub fn intersect_ranges<K: Clone + Ord>(
+    a: &DiffRange<K>,
+    b: &DiffRange<K>,
+) -> Option<DiffRange<K>> {
+    let start = match (&a.0, &b.0) {
+        (Bound::Unbounded, bound) | (bound, Bound::Unbounded) => bound.clone(),
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y))
+            if x != y =>
+        {
+            if x > y {
+                a.0.clone()
+            } else {
+                b.0.clone()
+            }
+        }
+        (Bound::Excluded(x), _) | (_, Bound::Excluded(x)) => Bound::Excluded(x.clone()),
+        (bound, _) => bound.clone(),
+    };
+    let end = match (&a.1, &b.1) {
+        (Bound::Unbounded, bound) | (bound, Bound::Unbounded) => bound.clone(),
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y))
+            if x != y =>
+        {
+            if x < y {
+                a.1.clone()
+            } else {
+                b.1.clone()
+            }
+        }
+        (Bound::Excluded(x), _) | (_, Bound::Excluded(x)) => Bound::Excluded(x.clone()),
+        (bound, _) => bound.clone(),
+    };
+    let is_empty = match (&start, &end) {
+        (Bound::Included(x), Bound::Included(y)) => x > y,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            x >= y
+        }
+        _ => false,
+    };
+    (!is_empty).then_some((start, end))
+}
+
+/// Order of two start bounds, the lesser one admitting more keys.
+fn cmp_start_bounds<K: Ord>(a: &Bound<K>, b: &Bound<K>) -> Ordering {
+    match (a, b) {
+        (Bound::Unbounded, Bound::Unbounded) => Ordering::Equal,
+        (Bound::Unbounded, _) => Ordering::Less,
+        (_, Bound::Unbounded) => Ordering::Greater,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            // on the same key, the included bound admits the key itself
+            x.cmp(y).then(match (a, b) {
+                (Bound::Included(_), Bound::Excluded(_)) => Ordering::Less,
+                (Bound::Excluded(_), Bound::Included(_)) => Ordering::Greater,
+                _ => Ordering::Equal,
+            })
+        }
+    }
+}
+
+/// Order of two end bounds, the greater one admitting more keys.
+fn cmp_end_bounds<K: Ord>(a: &Bound<K>, b: &Bound<K>) -> Ordering {
+    match (a, b) {
+        (Bound::Unbounded, Bound::Unbounded) => Ordering::Equal,
+        (Bound::Unbounded, _) => Ordering::Greater,
+        (_, Bound::Unbounded) => Ordering::Less,
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => {
+            x.cmp(y).then(match (a, b) {
+                (Bound::Included(_), Bound::Excluded(_)) => Ordering::Greater,
+                (Bound::Excluded(_), Bound::Included(_)) => Ordering::Less,
+                _ => Ordering::Equal,
+            })
+        }
+    }
+}
+
+/// Whether the range starts after it ends, which only a faulty peer, or keys whose [`Ord`] is
+/// inconsistent, can produce.
+pub(crate) fn is_reversed<K: Ord>((start, end): &DiffRange<K>) -> bool {
+    match (start, end) {
+        (Bound::Included(x) | Bound::Excluded(x), Bound::Included(y) | Bound::Excluded(y)) => x > y,
+        _ => false,
+    }
+}
+
+/// Whether the first range holds all the keys of the second one.
+pub fn range_covers<K: Ord>(outer: &DiffRange<K>, inner: &DiffRange<K>) -> bool {
+    cmp_start_bounds(&outer.0, &inner.0) != Ordering::Greater
+        && cmp_end_bounds(&outer.1, &inner.1) != Ordering::Less
+}
+
+/// Union of the ranges, as disjoint ranges sorted by start bound: the ranges that overlap are
+/// merged.
+///
+/// Ranges that are only adjacent, such as `..Excluded(3)` and `Included(3)..`, are kept apart.
+pub fn merge_ranges<K: Clone + Ord>(mut ranges: Vec<DiffRange<K>>) -> Vec<DiffRange<K>> {
+    ranges.sort_by(|a, b| cmp_start_bounds(&a.0, &b.0));
+    let mut merged: Vec<DiffRange<K>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if intersect_ranges(last, &range).is_some() => {
+                if cmp_end_bounds(&range.1, &last.1) == Ordering::Greater {
+                    last.1 = range.1;
+                }
+            }
+            _ => merged.push(range),
+        }
+    }
+    merged
+}
+
+/// Positions of the ranges covered by another one of the list, keeping the first of identical
+/// ranges.
+fn covered_ranges<K: Ord>(ranges: &[&DiffRange<K>]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..ranges.len()).collect();
+    // by start bound, then from the widest, then in the original order
+    order.sort_by(|&i, &j| {
+        cmp_start_bounds(&ranges[i].0, &ranges[j].0)
+            .then(cmp_end_bounds(&ranges[j].1, &ranges[i].1))
+            .then(i.cmp(&j))
+    });
+    // the ranges before one in this order start before it, so it is covered when one of them
+    // ends after it
+    let mut covered = Vec::new();
+    let mut max_end: Option<&Bound<K>> = None;
+    for index in order {
+        let end = &ranges[index].1;
+        match max_end {
+            Some(max_end) if cmp_end_bounds(end, max_end) != Ordering::Greater => {
+                covered.push(index)
+            }
+            _ => max_end = Some(end),
+        }
+    }
+    covered.sort_unstable();
+    covered
+}
+
 /// Exposes two methods that can be used to implement a reconciliation protocol over a network.
 pub trait Diffable {
     type ComparisonItem;
@@ -54,6 +488,21 @@ pub trait Diffable {
     /// Returns a representation of all the elements in the collection
     /// that can be sent to `diff_round`; for instance, an accumulated hash of the elements
     fn start_diff(&self) -> Vec<Self::ComparisonItem>;
+    /// Same as [`start_diff`](Diffable::start_diff), but only represents the elements within the
+    /// given range.
+    fn start_diff_range(&self, range: &Self::DifferenceItem) -> Vec<Self::ComparisonItem>;
+    /// Restricts comparison items received from a peer to the given range, before
+    /// [`diff_round`](Diffable::diff_round).
+    ///
+    /// The items within the range are returned. The items that overlap the range are replaced by
+    /// local items over the overlap, added to `out_comparison` so that the peer compares them
+    /// instead. The other items are dropped.
+    fn clip_comparison(
+        &self,
+        in_comparison: Vec<Self::ComparisonItem>,
+        range: &Self::DifferenceItem,
+        out_comparison: &mut Vec<Self::ComparisonItem>,
+    ) -> Vec<Self::ComparisonItem>;
     /// Refines set differences (typically, a range of keys along with the accumulated hash) into smaller sets.
     ///
     /// When sets are determined to contains the same elements, they can be remove from the output.
@@ -66,10 +515,117 @@ pub trait Diffable {
         out_comparison: &mut Vec<Self::ComparisonItem>,
         differences: &mut Vec<Self::DifferenceItem>,
     );
+    /// Same as [`diff_round`](Diffable::diff_round), answering the differing sets as set by the
+    /// strategy.
+    ///
+    /// The default implementation ignores the strategy.
+    fn diff_round_split(
+        &self,
+        in_comparison: Vec<Self::ComparisonItem>,
+        out_comparison: &mut Vec<Self::ComparisonItem>,
+        differences: &mut Vec<Self::DifferenceItem>,
+        _strategy: &SplitStrategy,
+    ) {
+        self.diff_round(in_comparison, out_comparison, differences)
+    }
+    /// Whether the comparison item received from a peer differs from the local elements it
+    /// represents, to estimate the density of the differences.
+    ///
+    /// The default implementation returns `true`.
+    fn comparison_differs(&self, _item: &Self::ComparisonItem) -> bool {
+        true
+    }
+    /// Number of elements of the peer's collection, if the comparison item received from it
+    /// covers the whole collection, as the ones from [`start_diff`](Diffable::start_diff).
+    ///
+    /// The default implementation returns `None`.
+    fn whole_size(_item: &Self::ComparisonItem) -> Option<usize> {
+        None
+    }
+    /// Number of local elements within the difference item, if it is cheap to count, to estimate
+    /// the size of the updates before listing them.
+    ///
+    /// The default implementation returns `None`.
+    fn difference_size(&self, _item: &Self::DifferenceItem) -> Option<usize> {
+        None
+    }
+    /// Restricts a local comparison item to the fingerprint with the given id, before sending it
+    /// to a peer comparing with this fingerprint.
+    ///
+    /// The default implementation returns the item unchanged.
+    fn project_comparison(item: Self::ComparisonItem, _fingerprint: u8) -> Self::ComparisonItem {
+        item
+    }
+    /// Interprets a comparison item received from a peer comparing with the fingerprint with the
+    /// given id.
+    ///
+    /// The default implementation returns the item unchanged.
+    fn resolve_comparison(item: Self::ComparisonItem, _fingerprint: u8) -> Self::ComparisonItem {
+        item
+    }
+    /// Whether two difference items overlap, so that a divergence found over the first one is
+    /// still ongoing when the second one is found.
+    ///
+    /// The default implementation returns `false`.
+    fn differences_overlap(_a: &Self::DifferenceItem, _b: &Self::DifferenceItem) -> bool {
+        false
+    }
+    /// Whether the comparison item covers the whole difference item, so that the difference is
+    /// resolved once the item matches.
+    ///
+    /// The default implementation returns `false`.
+    fn comparison_covers(_item: &Self::ComparisonItem, _difference: &Self::DifferenceItem) -> bool {
+        false
+    }
+    /// Drops the comparison items received from a peer that are redundant with the other ones,
+    /// so that the same elements are not compared and answered several times.
+    ///
+    /// The default implementation returns the items unchanged.
+    fn normalize_comparison(in_comparison: Vec<Self::ComparisonItem>) -> Vec<Self::ComparisonItem> {
+        in_comparison
+    }
+    /// Merges the difference items that overlap, so that the same elements are not sent several
+    /// times.
+    ///
+    /// The default implementation returns the items unchanged.
+    fn normalize_differences(differences: Vec<Self::DifferenceItem>) -> Vec<Self::DifferenceItem> {
+        differences
+    }
+}
+
+/// Positions of the first element in the range, and after the last element in the range.
+pub(crate) fn range_indices<K: Ord, T: HashRangeQueryable<Key = K>>(
+    tree: &T,
+    (start_bound, end_bound): &DiffRange<K>,
+) -> (usize, usize) {
+    // position of the key, and whether it is present
+    let position = |key| {
+        let index = tree.insertion_position(key);
+        (index, index < tree.len() && tree.key_at(index) == key)
+    };
+    let start_index = match start_bound.as_ref() {
+        Bound::Unbounded => 0,
+        Bound::Included(key) => position(key).0,
+        Bound::Excluded(key) => match position(key) {
+            (index, true) => index + 1,
+            (index, false) => index,
+        },
+    };
+    let end_index = match end_bound.as_ref() {
+        Bound::Unbounded => tree.len(),
+        Bound::Included(key) => match position(key) {
+            (index, true) => index + 1,
+            (index, false) => index,
+        },
+        Bound::Excluded(key) => position(key).0,
+    };
+    (start_index, end_index)
 }
 
-impl<K: Clone, T: HashRangeQueryable<Key = K>> Diffable for T {
-    type ComparisonItem = HashSegment<K>;
+impl<K: Clone + MaybeParallel + Ord, T: HashRangeQueryable<Key = K> + MaybeParallel> Diffable
+    for T
+{
+    type ComparisonItem = HashSegment<K, <T::Fingerprint as FingerprintStrategy>::Output>;
     type DifferenceItem = DiffRange<K>;
 
     fn start_diff(&self) -> Vec<Self::ComparisonItem> {
@@ -77,91 +633,948 @@ impl<K: Clone, T: HashRangeQueryable<Key = K>> Diffable for T {
             range: (Bound::Unbounded, Bound::Unbounded),
             hash: self.hash(&..),
             size: self.len(),
+            items: None,
+        }]
+    }
+
+    fn start_diff_range(&self, range: &Self::DifferenceItem) -> Vec<Self::ComparisonItem> {
+        let (start_index, end_index) = range_indices(self, range);
+        vec![HashSegment {
+            range: range.clone(),
+            hash: self.hash(range),
+            size: end_index.saturating_sub(start_index),
+            items: None,
         }]
     }
 
+    fn clip_comparison(
+        &self,
+        in_comparison: Vec<Self::ComparisonItem>,
+        range: &Self::DifferenceItem,
+        out_comparison: &mut Vec<Self::ComparisonItem>,
+    ) -> Vec<Self::ComparisonItem> {
+        let mut clipped = Vec::new();
+        for segment in in_comparison {
+            match intersect_ranges(&segment.range, range) {
+                None => (),
+                Some(overlap) if overlap == segment.range => clipped.push(segment),
+                Some(overlap) => out_comparison.extend(self.start_diff_range(&overlap)),
+            }
+        }
+        clipped
+    }
+
     fn diff_round(
         &self,
         in_comparison: Vec<Self::ComparisonItem>,
         out_comparison: &mut Vec<Self::ComparisonItem>,
         differences: &mut Vec<Self::DifferenceItem>,
     ) {
-        for segment in in_comparison {
-            let HashSegment { range, hash, size } = segment.clone();
-            let local_hash = self.hash(&range);
-            if hash == local_hash {
-                continue;
-            } else if hash == 0 {
-                differences.push(range);
-                continue;
-            } else if local_hash == 0 {
-                // present on remote; bounce back to the remote
-                out_comparison.push(HashSegment {
-                    range,
-                    hash: 0,
-                    size: 0,
-                });
-                continue;
+        self.diff_round_split(
+            in_comparison,
+            out_comparison,
+            differences,
+            &SplitStrategy::default(),
+        );
+    }
+
+    fn diff_round_split(
+        &self,
+        in_comparison: Vec<Self::ComparisonItem>,
+        out_comparison: &mut Vec<Self::ComparisonItem>,
+        differences: &mut Vec<Self::DifferenceItem>,
+        strategy: &SplitStrategy,
+    ) {
+        let first_difference = differences.len();
+        let segments = Self::normalize_comparison(in_comparison);
+        diff_segments(self, segments, strategy, out_comparison, differences);
+        // segments that overlap without covering each other may give overlapping differences
+        let new_differences = differences.split_off(first_difference);
+        differences.extend(Self::normalize_differences(new_differences));
+    }
+
+    fn comparison_differs(&self, item: &Self::ComparisonItem) -> bool {
+        self.hash(&item.range) != item.hash
+    }
+
+    fn whole_size(item: &Self::ComparisonItem) -> Option<usize> {
+        (item.range == (Bound::Unbounded, Bound::Unbounded)).then_some(item.size)
+    }
+
+    fn difference_size(&self, item: &Self::DifferenceItem) -> Option<usize> {
+        let (start_index, end_index) = range_indices(self, item);
+        Some(end_index.saturating_sub(start_index))
+    }
+
+    fn project_comparison(item: Self::ComparisonItem, fingerprint: u8) -> Self::ComparisonItem {
+        item.map_hashes(|hash| T::Fingerprint::project(hash, fingerprint))
+    }
+
+    fn resolve_comparison(item: Self::ComparisonItem, fingerprint: u8) -> Self::ComparisonItem {
+        item.map_hashes(|hash| T::Fingerprint::resolve(hash, fingerprint))
+    }
+
+    fn differences_overlap(a: &Self::DifferenceItem, b: &Self::DifferenceItem) -> bool {
+        intersect_ranges(a, b).is_some()
+    }
+
+    fn comparison_covers(item: &Self::ComparisonItem, difference: &Self::DifferenceItem) -> bool {
+        // the intersection is the difference itself exactly when the range of the item covers it
+        intersect_ranges(&item.range, difference).as_ref() == Some(difference)
+    }
+
+    fn normalize_comparison(in_comparison: Vec<Self::ComparisonItem>) -> Vec<Self::ComparisonItem> {
+        if in_comparison.len() < 2 {
+            return in_comparison;
+        }
+        let ranges: Vec<_> = in_comparison.iter().map(|segment| &segment.range).collect();
+        let covered = covered_ranges(&ranges);
+        if covered.is_empty() {
+            return in_comparison;
+        }
+        debug!(
+            "dropping {} segments covered by other segments",
+            covered.len()
+        );
+        in_comparison
+            .into_iter()
+            .enumerate()
+            .filter(|(index, _)| covered.binary_search(index).is_err())
+            .map(|(_, segment)| segment)
+            .collect()
+    }
+
+    fn normalize_differences(differences: Vec<Self::DifferenceItem>) -> Vec<Self::DifferenceItem> {
+        if differences.len() < 2 {
+            return differences;
+        }
+        let count = differences.len();
+        let merged = merge_ranges(differences);
+        if merged.len() < count {
+            debug!("merged {} overlapping diff ranges", count - merged.len());
+        }
+        merged
+    }
+}
+
+/// Answer the segments received from a peer, in order.
+///
+/// With the `parallel` feature, large rounds are processed over several threads; the outputs are
+/// the same as when processing the segments one by one.
+fn diff_segments<K: Clone + MaybeParallel + Ord, T: HashRangeQueryable<Key = K> + MaybeParallel>(
+    tree: &T,
+    segments: Vec<HashSegment<K, FingerprintOf<T>>>,
+    strategy: &SplitStrategy,
+    out_comparison: &mut Vec<HashSegment<K, FingerprintOf<T>>>,
+    differences: &mut Vec<DiffRange<K>>,
+) {
+    #[cfg(feature = "parallel")]
+    {
+        // each segment costs a few range hashes, logarithmic in the size of the tree
+        let depth = (usize::BITS - tree.len().leading_zeros()) as usize;
+        if segments.len() * depth >= PARALLEL_WORK_THRESHOLD {
+            return diff_segments_parallel(tree, segments, strategy, out_comparison, differences);
+        }
+    }
+    for segment in segments {
+        diff_segment(tree, segment, strategy, out_comparison, differences);
+    }
+}
+
+/// Answer the segments over the threads of the rayon pool, then gather the outputs in the order
+/// of the segments.
+#[cfg(feature = "parallel")]
+fn diff_segments_parallel<
+    K: Clone + MaybeParallel + Ord,
+    T: HashRangeQueryable<Key = K> + MaybeParallel,
+>(
+    tree: &T,
+    segments: Vec<HashSegment<K, FingerprintOf<T>>>,
+    strategy: &SplitStrategy,
+    out_comparison: &mut Vec<HashSegment<K, FingerprintOf<T>>>,
+    differences: &mut Vec<DiffRange<K>>,
+) {
+    use rayon::prelude::*;
+
+    let outputs: Vec<_> = segments
+        .into_par_iter()
+        .map(|segment| {
+            let mut segment_out_comparison = Vec::new();
+            let mut segment_differences = Vec::new();
+            diff_segment(
+                tree,
+                segment,
+                strategy,
+                &mut segment_out_comparison,
+                &mut segment_differences,
+            );
+            (segment_out_comparison, segment_differences)
+        })
+        .collect();
+    for (segment_out_comparison, segment_differences) in outputs {
+        out_comparison.extend(segment_out_comparison);
+        differences.extend(segment_differences);
+    }
+}
+
+/// Answer a segment received from a peer: drop it if it matches, list the differences, or send
+/// back smaller segments.
+fn diff_segment<K: Clone + Ord, T: HashRangeQueryable<Key = K>>(
+    tree: &T,
+    segment: HashSegment<K, FingerprintOf<T>>,
+    strategy: &SplitStrategy,
+    out_comparison: &mut Vec<HashSegment<K, FingerprintOf<T>>>,
+    differences: &mut Vec<DiffRange<K>>,
+) {
+    let empty_hash = T::Fingerprint::identity();
+    let HashSegment {
+        range,
+        hash,
+        size,
+        items,
+    } = segment;
+    if is_reversed(&range) {
+        warn!("reversed segment of size {size}, skipped");
+        return;
+    }
+    let local_hash = tree.hash(&range);
+    if hash == local_hash {
+        return;
+    } else if let Some(items) = items {
+        compare_items(tree, range, hash, size, items, out_comparison, differences);
+        return;
+    } else if hash == empty_hash {
+        differences.push(range);
+        return;
+    } else if local_hash == empty_hash {
+        // present on remote; bounce back to the remote
+        out_comparison.push(HashSegment {
+            range,
+            hash: empty_hash,
+            size: 0,
+            items: None,
+        });
+        return;
+    }
+    let (start_index, end_index) = range_indices(tree, &range);
+    // NOTE: a reversed range gives an empty local segment
+    let local_size = end_index.saturating_sub(start_index);
+    if size == 0 || local_size == 0 {
+        // the sizes do not match the hashes; this can only come from a faulty peer
+        warn!("inconsistent segment of size {size} (local size {local_size}), skipped");
+    } else if local_size <= strategy.direct_items {
+        // most elements differ: send ours, and ask the remote to send its own
+        out_comparison.push(HashSegment {
+            range: range.clone(),
+            hash: empty_hash,
+            size: 0,
+            items: None,
+        });
+        differences.push(range);
+    } else if size == 1 && local_size == 1 {
+        // list the local item, so that the remote only sends its item if it differs, and
+        // requests ours if it is missing or differs
+        let key = tree.key_at(start_index);
+        out_comparison.push(HashSegment {
+            range,
+            hash: local_hash,
+            size: 1,
+            items: Some(vec![(key.clone(), local_hash)]),
+        });
+    } else if local_size <= ITEMS_THRESHOLD {
+        // list the local items, so that the remote finds the conflicting ones directly
+        let items = (start_index..end_index)
+            .map(|index| {
+                let key = tree.key_at(index);
+                (key.clone(), tree.hash_of(key).unwrap_or(empty_hash))
+            })
+            .collect();
+        out_comparison.push(HashSegment {
+            range,
+            hash: local_hash,
+            size: local_size,
+            items: Some(items),
+        });
+    } else {
+        // NOTE: end_index - start_index > ITEMS_THRESHOLD
+        let step = 1.max((end_index - start_index) / strategy.fan_out.max(2));
+        split_segment(tree, &range, start_index, end_index, step, out_comparison);
+    }
+}
+
+/// Split the elements between the given positions into segments of `step` elements, except for
+/// the last one.
+///
+/// The segments are built over borrowed keys, so that each key is only cloned for the bounds sent.
+fn split_segment<K: Clone + Ord, T: HashRangeQueryable<Key = K>>(
+    tree: &T,
+    (start_bound, end_bound): &DiffRange<K>,
+    start_index: usize,
+    end_index: usize,
+    step: usize,
+    out_comparison: &mut Vec<HashSegment<K, FingerprintOf<T>>>,
+) {
+    let mut cur_bound = start_bound.as_ref();
+    let mut cur_index = start_index;
+    loop {
+        let next_index = cur_index + step;
+        let (end, size) = if next_index >= end_index {
+            (end_bound.as_ref(), end_index - cur_index)
+        } else {
+            (Bound::Excluded(tree.key_at(next_index)), step)
+        };
+        let range = (cur_bound, end);
+        let segment = HashSegmentRef {
+            hash: tree.hash(&range),
+            range,
+            size,
+            items: None,
+        };
+        out_comparison.push(segment.to_owned());
+        match end {
+            Bound::Excluded(next_key) if next_index < end_index => {
+                cur_bound = Bound::Included(next_key);
+                cur_index = next_index;
+            }
+            _ => return,
+        }
+    }
+}
+
+/// Split the whole key space into segments of at most `max_leaf` elements each.
+pub(crate) fn export_segments<K: Clone, T: HashRangeQueryable<Key = K>>(
+    tree: &T,
+    max_leaf: usize,
+) -> Vec<HashSegment<K, FingerprintOf<T>>> {
+    let max_leaf = max_leaf.max(1);
+    let mut segments = Vec::new();
+    let mut cur_bound = Bound::Unbounded;
+    let mut cur_index = 0;
+    while cur_index + max_leaf < tree.len() {
+        let next_key = tree.key_at(cur_index + max_leaf);
+        let range = (cur_bound, Bound::Excluded(next_key.clone()));
+        segments.push(HashSegment {
+            hash: tree.hash(&range),
+            range,
+            size: max_leaf,
+            items: None,
+        });
+        cur_bound = Bound::Included(next_key.clone());
+        cur_index += max_leaf;
+    }
+    let range = (cur_bound, Bound::Unbounded);
+    segments.push(HashSegment {
+        hash: tree.hash(&range),
+        range,
+        size: tree.len() - cur_index,
+        items: None,
+    });
+    segments
+}
+
+/// Ranges of the segments whose elements differ from the local ones.
+pub(crate) fn compare_segments<K: Clone, T: HashRangeQueryable<Key = K>>(
+    tree: &T,
+    segments: &[HashSegment<K, FingerprintOf<T>>],
+) -> Vec<DiffRange<K>> {
+    segments
+        .iter()
+        .filter(|segment| tree.hash(&segment.range) != segment.hash)
+        .map(|segment| segment.range.clone())
+        .collect()
+}
+
+/// Compare the items listed by the remote with the local ones over the range.
+///
+/// The local items missing on the remote are sent over the gaps between the remote keys. The
+/// remote items that are missing or differ locally are requested one by one.
+fn compare_items<K: Clone + Ord, T: HashRangeQueryable<Key = K>>(
+    tree: &T,
+    range: DiffRange<K>,
+    hash: FingerprintOf<T>,
+    size: usize,
+    items: Vec<(K, FingerprintOf<T>)>,
+    out_comparison: &mut Vec<HashSegment<K, FingerprintOf<T>>>,
+    differences: &mut Vec<DiffRange<K>>,
+) {
+    let consistent = size == items.len()
+        && size <= ITEMS_THRESHOLD
+        && items.windows(2).all(|pair| pair[0].0 < pair[1].0)
+        && items.iter().all(|(key, _)| range.contains(key))
+        && items
+            .iter()
+            .fold(T::Fingerprint::identity(), |acc, &(_, item_hash)| {
+                T::Fingerprint::combine(acc, item_hash)
+            })
+            == hash;
+    if !consistent {
+        // this can only come from a faulty peer
+        warn!(
+            "inconsistent list of {} items (size {size}), skipped",
+            items.len()
+        );
+        return;
+    }
+    let empty_hash = T::Fingerprint::identity();
+    let (start_bound, end_bound) = range;
+    let mut gap_start = start_bound;
+    for (key, item_hash) in items {
+        let gap = (gap_start, Bound::Excluded(key.clone()));
+        let (start_index, end_index) = range_indices(tree, &gap);
+        if end_index > start_index {
+            // missing on the remote
+            differences.push(gap);
+        }
+        let local_item_hash = tree.hash_of(&key).unwrap_or(empty_hash);
+        if local_item_hash != item_hash {
+            let item_range = (Bound::Included(key.clone()), Bound::Included(key.clone()));
+            // ask the remote to send us the item
+            out_comparison.push(HashSegment {
+                range: item_range.clone(),
+                hash: empty_hash,
+                size: 0,
+                items: None,
+            });
+            if local_item_hash != empty_hash {
+                // and send ours, in case it wins
+                differences.push(item_range);
             }
-            let (start_bound, end_bound) = range;
-            let start_index = match start_bound.as_ref() {
-                Bound::Unbounded => 0,
-                Bound::Included(key) => self.insertion_position(key),
-                Bound::Excluded(_) => unimplemented!(),
+        }
+        gap_start = Bound::Excluded(key);
+    }
+    let gap = (gap_start, end_bound);
+    let (start_index, end_index) = range_indices(tree, &gap);
+    if end_index > start_index {
+        differences.push(gap);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use bincode::{DefaultOptions, Options};
+
+    use super::{
+        covered_ranges, differing_fraction, intersect_ranges, merge_ranges, range_covers,
+        range_indices, DiffTuning, Diffable, HashRangeQueryable, HashSegment, HashSegmentRef,
+    };
+    use crate::HRTree;
+
+    /// Run the protocol between two trees until it completes.
+    ///
+    /// Return the number of messages exchanged, and the keys sent by each tree.
+    fn exchange(a: &HRTree<u32, u32>, b: &HRTree<u32, u32>) -> (usize, Vec<u32>, Vec<u32>) {
+        exchange_tuned(a, b, &DiffTuning::default())
+    }
+
+    /// Same as [`exchange`], answering the segments as set by `tuning`.
+    fn exchange_tuned(
+        a: &HRTree<u32, u32>,
+        b: &HRTree<u32, u32>,
+        tuning: &DiffTuning,
+    ) -> (usize, Vec<u32>, Vec<u32>) {
+        let trees = [a, b];
+        let mut sent = [Vec::new(), Vec::new()];
+        let mut segments = a.start_diff();
+        let mut messages = 0;
+        let mut turn = 1;
+        // density of the differences in the previous round of each tree, as tracked by sessions
+        let mut densities = [None, None];
+        while !segments.is_empty() {
+            messages += 1;
+            let mut out_comparison = Vec::new();
+            let mut differences = Vec::new();
+            let strategy = tuning.strategy(trees[turn], &segments, densities[turn], 128);
+            densities[turn] = differing_fraction(trees[turn], &segments);
+            trees[turn].diff_round_split(
+                segments,
+                &mut out_comparison,
+                &mut differences,
+                &strategy,
+            );
+            for range in differences {
+                let (start_index, end_index) = range_indices(trees[turn], &range);
+                sent[turn].extend((start_index..end_index).map(|i| *trees[turn].key_at(i)));
+            }
+            segments = out_comparison;
+            turn = 1 - turn;
+        }
+        let [sent_a, sent_b] = sent;
+        (messages, sent_a, sent_b)
+    }
+
+    /// Run a diff round on a segment with a hash that does not match the local one.
+    fn split(
+        tree: &HRTree<u32, u32>,
+        range: (Bound<u32>, Bound<u32>),
+        size: usize,
+    ) -> Vec<HashSegment<u32>> {
+        let mut out_comparison = Vec::new();
+        let mut differences = Vec::new();
+        let segment = HashSegment {
+            range,
+            hash: 42,
+            size,
+            items: None,
+        };
+        tree.diff_round(vec![segment], &mut out_comparison, &mut differences);
+        out_comparison
+    }
+
+    #[test]
+    fn borrowed_segments() {
+        let options = DefaultOptions::new();
+        let segments: [HashSegment<String>; 3] = [
+            HashSegment {
+                range: (Bound::Unbounded, Bound::Unbounded),
+                hash: 42,
+                size: 1000,
+                items: None,
+            },
+            HashSegment {
+                range: (Bound::Included("a".into()), Bound::Excluded("c".into())),
+                hash: 3,
+                size: 2,
+                items: Some(vec![("a".into(), 1), ("b".into(), 2)]),
+            },
+            HashSegment {
+                range: (Bound::Excluded("c".into()), Bound::Included("d".into())),
+                hash: 0,
+                size: 0,
+                items: Some(Vec::new()),
+            },
+        ];
+        for segment in segments {
+            // the borrowed segment has the same wire format as the owned one
+            let bytes = options.serialize(&segment.as_ref()).unwrap();
+            assert_eq!(bytes, options.serialize(&segment).unwrap());
+            let decoded: HashSegment<String> = options.deserialize(&bytes).unwrap();
+            assert_eq!(decoded, segment);
+            assert_eq!(segment.as_ref().to_owned(), segment);
+        }
+        let segment: HashSegmentRef<'_, String> = HashSegmentRef {
+            range: (Bound::Unbounded, Bound::Unbounded),
+            hash: 0,
+            size: 0,
+            items: None,
+        };
+        assert_eq!(segment.to_owned().as_ref(), segment);
+    }
+
+    #[test]
+    fn intersection() {
+        use Bound::{Excluded, Included, Unbounded};
+        let cases = [
+            (
+                (Unbounded, Unbounded),
+                (Included(2), Excluded(5)),
+                Some((Included(2), Excluded(5))),
+            ),
+            (
+                (Included(2), Included(5)),
+                (Excluded(2), Excluded(5)),
+                Some((Excluded(2), Excluded(5))),
+            ),
+            (
+                (Included(1), Included(5)),
+                (Included(3), Unbounded),
+                Some((Included(3), Included(5))),
+            ),
+            (
+                (Unbounded, Excluded(3)),
+                (Excluded(1), Included(7)),
+                Some((Excluded(1), Excluded(3))),
+            ),
+            (
+                (Included(3), Included(3)),
+                (Included(3), Unbounded),
+                Some((Included(3), Included(3))),
+            ),
+            ((Unbounded, Excluded(3)), (Included(3), Unbounded), None),
+            ((Unbounded, Included(3)), (Excluded(3), Unbounded), None),
+            ((Included(1), Included(2)), (Included(5), Included(7)), None),
+        ];
+        for (a, b, expected) in cases {
+            assert_eq!(intersect_ranges(&a, &b), expected);
+            assert_eq!(intersect_ranges(&b, &a), expected);
+        }
+    }
+
+    #[test]
+    fn covering() {
+        use Bound::{Excluded, Included, Unbounded};
+        let covering = [
+            ((Unbounded, Unbounded), (Unbounded, Unbounded)),
+            ((Unbounded, Unbounded), (Included(2), Excluded(5))),
+            ((Unbounded, Excluded(5)), (Included(2), Excluded(5))),
+            ((Included(2), Unbounded), (Included(2), Included(9))),
+            ((Included(2), Included(5)), (Excluded(2), Excluded(5))),
+            ((Included(2), Included(5)), (Included(2), Included(5))),
+            ((Excluded(1), Included(5)), (Included(2), Included(4))),
+        ];
+        for (outer, inner) in covering {
+            assert!(range_covers(&outer, &inner), "{outer:?} {inner:?}");
+        }
+        let not_covering = [
+            ((Included(2), Excluded(5)), (Unbounded, Unbounded)),
+            ((Included(2), Excluded(5)), (Included(2), Unbounded)),
+            ((Included(2), Excluded(5)), (Unbounded, Excluded(5))),
+            ((Excluded(2), Included(5)), (Included(2), Included(5))),
+            ((Included(2), Excluded(5)), (Included(2), Included(5))),
+            ((Included(2), Included(5)), (Included(6), Included(7))),
+        ];
+        for (outer, inner) in not_covering {
+            assert!(!range_covers(&outer, &inner), "{outer:?} {inner:?}");
+        }
+    }
+
+    #[test]
+    fn covered() {
+        use Bound::{Excluded, Included, Unbounded};
+        let ranges = [
+            (Included(2), Excluded(5)),
+            (Unbounded, Unbounded),
+            (Included(2), Excluded(5)),
+            (Included(7), Unbounded),
+        ];
+        assert_eq!(
+            covered_ranges(&ranges.iter().collect::<Vec<_>>()),
+            [0, 2, 3]
+        );
+        // the first of identical ranges is kept
+        let ranges = [
+            (Included(2), Excluded(5)),
+            (Included(3), Included(4)),
+            (Included(2), Excluded(5)),
+            (Excluded(4), Included(8)),
+        ];
+        assert_eq!(covered_ranges(&ranges.iter().collect::<Vec<_>>()), [1, 2]);
+        // overlapping ranges are not covered
+        let ranges = [
+            (Unbounded, Included(5)),
+            (Included(5), Included(9)),
+            (Excluded(5), Unbounded),
+        ];
+        assert!(covered_ranges(&ranges.iter().collect::<Vec<_>>()).is_empty());
+    }
+
+    #[test]
+    fn merging() {
+        use Bound::{Excluded, Included, Unbounded};
+        let cases = [
+            (vec![], vec![]),
+            (
+                vec![(Included(5), Included(9)), (Unbounded, Included(5))],
+                vec![(Unbounded, Included(9))],
+            ),
+            // adjacent ranges are kept apart
+            (
+                vec![(Included(5), Included(9)), (Unbounded, Excluded(5))],
+                vec![(Unbounded, Excluded(5)), (Included(5), Included(9))],
+            ),
+            (
+                vec![(Excluded(5), Unbounded), (Unbounded, Included(5))],
+                vec![(Unbounded, Included(5)), (Excluded(5), Unbounded)],
+            ),
+            // duplicates, and ranges within other ones
+            (
+                vec![
+                    (Included(1), Included(3)),
+                    (Included(1), Included(3)),
+                    (Included(0), Excluded(10)),
+                    (Included(4), Included(6)),
+                    (Included(20), Included(20)),
+                ],
+                vec![(Included(0), Excluded(10)), (Included(20), Included(20))],
+            ),
+            // chains of overlapping ranges
+            (
+                vec![
+                    (Included(1), Included(4)),
+                    (Excluded(3), Included(6)),
+                    (Included(6), Excluded(8)),
+                ],
+                vec![(Included(1), Excluded(8))],
+            ),
+        ];
+        for (ranges, expected) in cases {
+            assert_eq!(merge_ranges(ranges), expected);
+        }
+    }
+
+    #[test]
+    fn overlapping_segments() {
+        let a: HRTree<u32, u32> = HRTree::from_iter((0..1000).map(|i| (i, i)));
+        let mut b = a.clone();
+        for key in 500..510 {
+            b.insert(key, 0);
+        }
+        let round = |segments: Vec<HashSegment<u32>>| {
+            let mut out_comparison = Vec::new();
+            let mut differences = Vec::new();
+            b.diff_round(segments, &mut out_comparison, &mut differences);
+            (out_comparison, differences)
+        };
+
+        // the whole range along with its sub-ranges is answered like the whole range alone
+        let whole = a.start_diff();
+        let (expected, _) = round(whole.clone());
+        let sub_ranges: Vec<_> = expected
+            .iter()
+            .map(|segment| a.start_diff_range(&segment.range).remove(0))
+            .collect();
+        let (out_comparison, _) = round([sub_ranges.clone(), whole.clone(), sub_ranges].concat());
+        assert_eq!(out_comparison, expected);
+
+        // duplicated segments are answered once
+        let (out_comparison, _) = round([whole.clone(), whole].concat());
+        assert_eq!(out_comparison, expected);
+
+        // the differences of overlapping segments are sent once
+        let empty = |range| HashSegment {
+            range,
+            hash: 0,
+            size: 0,
+            items: None,
+        };
+        let (_, differences) = round(vec![
+            empty((Bound::Included(100), Bound::Excluded(200))),
+            empty((Bound::Included(150), Bound::Excluded(250))),
+        ]);
+        assert_eq!(differences, [(Bound::Included(100), Bound::Excluded(250))]);
+    }
+
+    #[test]
+    fn reversed_range() {
+        let tree: HRTree<u32, u32> = HRTree::from_iter((0..100).map(|i| (i, i)));
+        // only a faulty peer sends a reversed range, which is skipped
+        for (hash, size, items) in [(5, 40, None), (0, 0, None), (5, 1, Some(vec![(30, 5)]))] {
+            let segment = HashSegment {
+                range: (Bound::Included(50), Bound::Excluded(10)),
+                hash,
+                size,
+                items,
             };
-            let end_index = match end_bound.as_ref() {
-                Bound::Unbounded => self.len(),
-                Bound::Included(_) => unimplemented!(),
-                Bound::Excluded(key) => self.insertion_position(key),
+            let mut out_comparison = Vec::new();
+            let mut differences = Vec::new();
+            tree.diff_round(vec![segment], &mut out_comparison, &mut differences);
+            assert!(out_comparison.is_empty());
+            assert!(differences.is_empty());
+        }
+    }
+
+    #[test]
+    fn clip_comparison() {
+        let tree: HRTree<u32, u32> = HRTree::from_iter((0..100).map(|i| (i, i)));
+        let range = (Bound::Included(10), Bound::Excluded(20));
+        let inside = tree.start_diff_range(&(Bound::Included(12), Bound::Included(15)));
+        let overlapping = tree.start_diff();
+        let outside = tree.start_diff_range(&(Bound::Included(20), Bound::Unbounded));
+        let mut out_comparison = Vec::new();
+        let clipped = tree.clip_comparison(
+            [inside.clone(), overlapping, outside].concat(),
+            &range,
+            &mut out_comparison,
+        );
+        assert_eq!(clipped, inside);
+        assert_eq!(out_comparison, tree.start_diff_range(&range));
+        assert_eq!(out_comparison[0].size, 10);
+    }
+
+    #[test]
+    fn adversarial_segments() {
+        // even keys only, so that bounds can be on present or absent keys
+        let tree: HRTree<u32, u32> = HRTree::from_iter((0..100).map(|i| (2 * i, i)));
+        let total_size = |segments: &[HashSegment<u32>]| -> usize {
+            segments.iter().map(|segment| segment.size).sum()
+        };
+
+        // excluded start bound, on a present key and an absent key
+        let segments = split(&tree, (Bound::Excluded(20), Bound::Excluded(100)), 39);
+        assert_eq!(segments[0].range.0, Bound::Excluded(20));
+        assert_eq!(total_size(&segments), 39);
+        let segments = split(&tree, (Bound::Excluded(21), Bound::Excluded(100)), 39);
+        assert_eq!(total_size(&segments), 39);
+
+        // included end bound, on a present key and an absent key
+        let segments = split(&tree, (Bound::Included(20), Bound::Included(100)), 41);
+        assert_eq!(segments.last().unwrap().range.1, Bound::Included(100));
+        assert_eq!(total_size(&segments), 41);
+        let segments = split(&tree, (Bound::Included(20), Bound::Included(101)), 41);
+        assert_eq!(total_size(&segments), 41);
+
+        // single element between exclusive and inclusive bounds, listed for the remote
+        let segments = split(&tree, (Bound::Excluded(20), Bound::Included(22)), 1);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].size, 1);
+        assert_eq!(
+            segments[0].items,
+            Some(vec![(22, tree.hash_of(&22).unwrap())])
+        );
+
+        // the sub-segments match the local hashes
+        for segment in split(&tree, (Bound::Excluded(0), Bound::Included(198)), 99) {
+            assert_eq!(segment.hash, tree.hash(&segment.range));
+        }
+
+        // bogus sizes are skipped
+        assert!(split(&tree, (Bound::Included(20), Bound::Excluded(100)), 0).is_empty());
+
+        // item lists that do not match the size, the range or the hash are skipped
+        let range = (Bound::Included(20), Bound::Excluded(30));
+        let items = vec![(20, 1), (22, 2)];
+        for (size, items) in [
+            (3, items.clone()),
+            (2, vec![(20, 1), (40, 2)]),
+            (2, vec![(22, 2), (20, 1)]),
+            (2, vec![(20, 1), (22, 4)]),
+        ] {
+            let segment = HashSegment {
+                range,
+                hash: 3,
+                size,
+                items: Some(items),
             };
-            let local_size = end_index - start_index;
-            if size == 0 || local_size == 0 {
-                // handled by the hash checks above
-                unreachable!();
-            } else if size == 1 && local_size == 1 {
-                // ask the remote to send us the conflicting item
-                out_comparison.push(HashSegment {
-                    range: (start_bound.clone(), end_bound.clone()),
-                    hash: 0,
-                    size: 0,
-                });
-                // send the conflicting item to the remote
-                differences.push((start_bound, end_bound));
-            } else if local_size == 1 {
-                // not enough information; bounce back to the remote
-                out_comparison.push(HashSegment {
-                    range: (start_bound, end_bound),
-                    hash: local_hash,
-                    size: local_size,
-                });
-            } else {
-                // NOTE: end_index - start_index ≥ 2
-                let step = 1.max((end_index - start_index) / 16);
-                let mut cur_bound = start_bound;
-                let mut cur_index = start_index;
-                loop {
-                    let next_index = cur_index + step;
-                    if next_index >= end_index {
-                        let range = (cur_bound, end_bound);
-                        out_comparison.push(HashSegment {
-                            hash: self.hash(&range),
-                            range,
-                            size: end_index - cur_index,
-                        });
-                        break;
-                    } else {
-                        let next_key = self.key_at(next_index);
-                        let range = (cur_bound, Bound::Excluded(next_key.clone()));
-                        out_comparison.push(HashSegment {
-                            hash: self.hash(&range),
-                            range,
-                            size: next_index - cur_index,
-                        });
-                        cur_bound = Bound::Included(next_key.clone());
-                        cur_index = next_index;
+            let mut out_comparison = Vec::new();
+            let mut differences = Vec::new();
+            tree.diff_round(vec![segment], &mut out_comparison, &mut differences);
+            assert!(out_comparison.is_empty());
+            assert!(differences.is_empty());
+        }
+
+        // reversed ranges are skipped, while empty ones are bounced back
+        assert!(split(&tree, (Bound::Excluded(100), Bound::Excluded(20)), 40).is_empty());
+        let segments = split(&tree, (Bound::Excluded(20), Bound::Excluded(20)), 40);
+        assert_eq!(segments.len(), 1);
+        assert_eq!(segments[0].size, 0);
+    }
+
+    #[test]
+    fn small_divergences() {
+        let a: HRTree<u32, u32> = HRTree::from_iter((0..1000).map(|i| (i, i)));
+        for count in [1, 2, 8] {
+            let mut b: HRTree<u32, u32> = HRTree::from_iter((0..1000).map(|i| (i, i)));
+            let keys: Vec<u32> = (500..500 + count).collect();
+            for &key in &keys {
+                b.insert(key, 0);
+            }
+            let (messages, sent_a, sent_b) = exchange(&a, &b);
+            // two splits, the list of items, then the requests for the conflicting ones
+            assert_eq!(messages, 5);
+            // only the conflicting items are exchanged
+            assert_eq!(sent_a, keys);
+            assert_eq!(sent_b, keys);
+        }
+
+        // only one of two elements differs
+        let a: HRTree<u32, u32> = HRTree::from_iter([(1, 1), (2, 2)]);
+        let b: HRTree<u32, u32> = HRTree::from_iter([(1, 1), (2, 0)]);
+        let (messages, sent_a, sent_b) = exchange(&a, &b);
+        assert_eq!(messages, 3);
+        assert_eq!(sent_a, [2]);
+        assert_eq!(sent_b, [2]);
+
+        // missing items are sent over the gaps between the listed items
+        let a: HRTree<u32, u32> = HRTree::from_iter((0..1000).map(|i| (i, i)));
+        let mut b: HRTree<u32, u32> = HRTree::from_iter((0..1000).map(|i| (i, i)));
+        b.remove(&500);
+        b.remove(&501);
+        let (messages, sent_a, sent_b) = exchange(&a, &b);
+        assert_eq!(messages, 4);
+        assert_eq!(sent_a, [500, 501]);
+        assert!(sent_b.is_empty());
+    }
+
+    #[test]
+    fn adaptive_tuning() {
+        let tuning = DiffTuning::adaptive();
+        let a: HRTree<u32, u32> = HRTree::from_iter((0..10000).map(|i| (i, i)));
+
+        // an empty instance receives everything at once in both modes
+        let b: HRTree<u32, u32> = HRTree::new();
+        let (messages, sent_a, _) = exchange(&a, &b);
+        let (adaptive_messages, adaptive_sent_a, _) = exchange_tuned(&a, &b, &tuning);
+        assert_eq!(adaptive_messages, messages);
+        assert_eq!(adaptive_sent_a, sent_a);
+
+        // a fresh instance that received a few writes is bootstrapped at once, whichever side
+        // opens the session
+        let b: HRTree<u32, u32> = HRTree::from_iter((0..10000).step_by(200).map(|i| (i, i + 1)));
+        let (messages, sent_a, sent_b) = exchange(&a, &b);
+        let (adaptive_messages, adaptive_sent_a, adaptive_sent_b) = exchange_tuned(&a, &b, &tuning);
+        assert_eq!(sent_a.len(), 10000);
+        assert_eq!(sent_b.len(), 50);
+        assert_eq!(adaptive_sent_a.len(), 10000);
+        assert_eq!(adaptive_sent_b.len(), 50);
+        assert_eq!(messages, 5);
+        assert_eq!(adaptive_messages, 2);
+        let (messages, _, _) = exchange(&b, &a);
+        let (adaptive_messages, adaptive_sent_b, adaptive_sent_a) = exchange_tuned(&b, &a, &tuning);
+        assert_eq!(adaptive_sent_a.len(), 10000);
+        assert_eq!(adaptive_sent_b.len(), 50);
+        assert_eq!(messages, 4);
+        assert_eq!(adaptive_messages, 2);
+
+        // all the values differ, as after a long downtime
+        let b: HRTree<u32, u32> = HRTree::from_iter((0..10000).map(|i| (i, i + 1)));
+        let (messages, sent_a, sent_b) = exchange(&a, &b);
+        let (adaptive_messages, adaptive_sent_a, adaptive_sent_b) = exchange_tuned(&a, &b, &tuning);
+        assert_eq!(sent_a.len(), 10000);
+        assert_eq!(sent_b.len(), 10000);
+        assert_eq!(adaptive_sent_a.len(), 10000);
+        assert_eq!(adaptive_sent_b.len(), 10000);
+        // three splits, the lists of items, then the requests for the conflicting ones
+        assert_eq!(messages, 6);
+        // the differing segments are sent directly once they are small enough
+        assert_eq!(adaptive_messages, 4);
+
+        // a single difference is found as without the adaptive tuning
+        let mut b: HRTree<u32, u32> = HRTree::from_iter((0..10000).map(|i| (i, i)));
+        b.insert(5000, 0);
+        let (messages, sent_a, sent_b) = exchange(&a, &b);
+        let (adaptive_messages, adaptive_sent_a, adaptive_sent_b) = exchange_tuned(&a, &b, &tuning);
+        assert!(adaptive_messages <= messages);
+        assert_eq!(adaptive_sent_a, sent_a);
+        assert_eq!(adaptive_sent_b, sent_b);
+    }
+
+    #[cfg(feature = "parallel")]
+    #[test]
+    fn parallel_segments() {
+        use rand::{Rng, SeedableRng};
+
+        use super::{diff_segment, diff_segments_parallel, SplitStrategy};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let tree: HRTree<u32, u32> =
+            HRTree::from_iter((0..10_000).map(|_| (rng.gen_range(0..100_000), rng.gen())));
+        let mut other = tree.clone();
+        for _ in 0..200 {
+            other.insert(rng.gen_range(0..100_000), rng.gen());
+        }
+        for _ in 0..20 {
+            // random ranges, compared against the other tree or with random hashes
+            let segments: Vec<_> = (0..rng.gen_range(1..64))
+                .map(|_| {
+                    let start = rng.gen_range(0..100_000);
+                    let end = rng.gen_range(start..=100_000);
+                    let range = (Bound::Included(start), Bound::Excluded(end));
+                    let mut segment = other.start_diff_range(&range).remove(0);
+                    if rng.gen_bool(0.2) {
+                        segment.hash = rng.gen();
                     }
-                }
+                    segment
+                })
+                .collect();
+            let mut expected = (Vec::new(), Vec::new());
+            let strategy = SplitStrategy::default();
+            for segment in segments.clone() {
+                diff_segment(&tree, segment, &strategy, &mut expected.0, &mut expected.1);
             }
+            let mut actual = (Vec::new(), Vec::new());
+            diff_segments_parallel(&tree, segments, &strategy, &mut actual.0, &mut actual.1);
+            assert_eq!(actual, expected);
         }
     }
 }
@@ -0,0 +1,198 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides the [`Discovery`] trait, which finds the addresses of the other instances, along with
+//! its implementations.
+//!
+//! At each reconciliation round, a [`Service`](crate::Service) starts a session with each
+//! candidate that is not a known peer yet. Candidates are not added to the known peers: if an
+//! instance listens at the address, it replies and is then learned as usual.
+
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::time::{Duration, Instant};
+
+use ipnet::IpNet;
+use rand::rngs::StdRng;
+use rand::SeedableRng;
+use tracing::warn;
+
+use crate::gen_ip::gen_ip;
+
+/// Future returned by the methods of [`Discovery`] and [`Resolver`]
+pub type DiscoveryFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Strategy to find the addresses of other instances.
+pub trait Discovery: Send {
+    /// Addresses to probe at the current reconciliation round.
+    fn candidates(&mut self) -> DiscoveryFuture<'_, Vec<IpAddr>>;
+}
+
+/// Probe a random address of the peer network at each round.
+///
+/// This is the default strategy, which only suits small networks: on a large one, most datagrams
+/// are sent to hosts that do not exist.
+pub struct RandomSubnet {
+    network: IpNet,
+    rng: StdRng,
+}
+
+impl RandomSubnet {
+    pub fn new(network: IpNet) -> Self {
+        RandomSubnet {
+            network,
+            rng: StdRng::from_entropy(),
+        }
+    }
+}
+
+impl Discovery for RandomSubnet {
+    fn candidates(&mut self) -> DiscoveryFuture<'_, Vec<IpAddr>> {
+        let addr = gen_ip(&mut self.rng, self.network);
+        Box::pin(async move { vec![addr] })
+    }
+}
+
+/// Probe a fixed list of addresses.
+pub struct StaticList {
+    addrs: Vec<IpAddr>,
+}
+
+impl StaticList {
+    pub fn new<I: IntoIterator<Item = IpAddr>>(addrs: I) -> Self {
+        StaticList {
+            addrs: addrs.into_iter().collect(),
+        }
+    }
+}
+
+impl Discovery for StaticList {
+    fn candidates(&mut self) -> DiscoveryFuture<'_, Vec<IpAddr>> {
+        let addrs = self.addrs.clone();
+        Box::pin(async move { addrs })
+    }
+}
+
+/// Resolve a hostname to IP addresses.
+pub trait Resolver: Send + Sync {
+    fn resolve<'a>(&'a self, host: &'a str) -> DiscoveryFuture<'a, std::io::Result<Vec<IpAddr>>>;
+}
+
+/// Resolve hostnames with the resolver of the system, through [`tokio::net::lookup_host`].
+pub struct SystemResolver;
+
+impl Resolver for SystemResolver {
+    fn resolve<'a>(&'a self, host: &'a str) -> DiscoveryFuture<'a, std::io::Result<Vec<IpAddr>>> {
+        Box::pin(async move {
+            let addrs = tokio::net::lookup_host((host, 0)).await?;
+            Ok(addrs.map(|addr| addr.ip()).collect())
+        })
+    }
+}
+
+/// Probe the addresses a hostname resolves to, such as the headless service of a Kubernetes
+/// stateful set.
+///
+/// The hostname is resolved again once the addresses are older than the given time to live. When
+/// the resolution fails, the previous addresses are kept.
+pub struct DnsName<R: Resolver = SystemResolver> {
+    host: String,
+    ttl: Duration,
+    resolver: R,
+    addrs: Vec<IpAddr>,
+    resolved_at: Option<Instant>,
+}
+
+impl DnsName {
+    pub fn new<S: Into<String>>(host: S, ttl: Duration) -> Self {
+        DnsName::with_resolver(host, ttl, SystemResolver)
+    }
+}
+
+impl<R: Resolver> DnsName<R> {
+    /// Resolve the hostname with another resolver than the one of the system.
+    pub fn with_resolver<S: Into<String>>(host: S, ttl: Duration, resolver: R) -> Self {
+        DnsName {
+            host: host.into(),
+            ttl,
+            resolver,
+            addrs: Vec::new(),
+            resolved_at: None,
+        }
+    }
+}
+
+impl<R: Resolver> Discovery for DnsName<R> {
+    fn candidates(&mut self) -> DiscoveryFuture<'_, Vec<IpAddr>> {
+        Box::pin(async move {
+            if self
+                .resolved_at
+                .is_none_or(|instant| instant.elapsed() >= self.ttl)
+            {
+                match self.resolver.resolve(&self.host).await {
+                    Ok(addrs) => self.addrs = addrs,
+                    Err(err) => warn!("failed to resolve {}: {err}", self.host),
+                }
+                self.resolved_at = Some(Instant::now());
+            }
+            self.addrs.clone()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::{Discovery, DiscoveryFuture, DnsName, RandomSubnet, Resolver};
+
+    struct CountingResolver(Arc<AtomicUsize>);
+
+    impl Resolver for CountingResolver {
+        fn resolve<'a>(&'a self, _: &'a str) -> DiscoveryFuture<'a, std::io::Result<Vec<IpAddr>>> {
+            let count = self.0.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async move {
+                match count {
+                    0 => Ok(vec!["10.0.0.1".parse().unwrap()]),
+                    _ => Err(std::io::ErrorKind::NotFound.into()),
+                }
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn dns_name() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let resolver = CountingResolver(count.clone());
+        let mut discovery = DnsName::with_resolver("peers", Duration::from_millis(50), resolver);
+        let expected: Vec<IpAddr> = vec!["10.0.0.1".parse().unwrap()];
+        assert_eq!(discovery.candidates().await, expected);
+        // cached until the time to live expires
+        assert_eq!(discovery.candidates().await, expected);
+        assert_eq!(count.load(Ordering::Relaxed), 1);
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        // the previous addresses are kept when the resolution fails
+        assert_eq!(discovery.candidates().await, expected);
+        assert_eq!(count.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn random_subnet() {
+        let network = "192.168.1.0/24".parse().unwrap();
+        let mut discovery = RandomSubnet::new(network);
+        for _ in 0..10 {
+            let candidates = discovery.candidates().await;
+            assert_eq!(candidates.len(), 1);
+            assert!(network.contains(&candidates[0]));
+        }
+    }
+}
@@ -0,0 +1,175 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`Divergences`], which remembers since when ranges of keys differ with each peer.
+//!
+//! A range normally differs for a single diff round: the items are exchanged, and the next round
+//! finds it equal. A range that keeps differing reveals that the instances cannot converge, for
+//! instance because one of them rejects the values of the other. Each difference found is merged
+//! with the ones it overlaps, keeping the time of the first detection, and is cleared when a
+//! comparison covering it matches.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Maximum number of ranges tracked per peer; the ones not found again for the longest time are
+/// forgotten first
+const MAX_DIVERGENCES: usize = 256;
+
+struct Divergence<D> {
+    range: D,
+    first_seen: Instant,
+    last_seen: Instant,
+}
+
+pub(crate) struct Divergences<D> {
+    peers: HashMap<SocketAddr, Vec<Divergence<D>>>,
+}
+
+impl<D: Clone> Divergences<D> {
+    pub fn new() -> Self {
+        Divergences {
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Record the ranges found to differ with the peer, given how to tell whether two ranges
+    /// overlap.
+    pub fn record(
+        &mut self,
+        peer: SocketAddr,
+        differences: &[D],
+        overlap: impl Fn(&D, &D) -> bool,
+    ) {
+        if differences.is_empty() {
+            return;
+        }
+        let now = Instant::now();
+        let divergences = self.peers.entry(peer).or_default();
+        for range in differences {
+            let mut first_seen = now;
+            divergences.retain(|divergence| {
+                if overlap(&divergence.range, range) {
+                    first_seen = first_seen.min(divergence.first_seen);
+                    false
+                } else {
+                    true
+                }
+            });
+            divergences.push(Divergence {
+                range: range.clone(),
+                first_seen,
+                last_seen: now,
+            });
+        }
+        if divergences.len() > MAX_DIVERGENCES {
+            divergences.sort_by_key(|divergence| std::cmp::Reverse(divergence.last_seen));
+            divergences.truncate(MAX_DIVERGENCES);
+        }
+    }
+
+    /// Whether some ranges are known to differ with the peer.
+    pub fn has(&self, peer: SocketAddr) -> bool {
+        self.peers.contains_key(&peer)
+    }
+
+    /// Forget the ranges that matched with the peer.
+    pub fn clear(&mut self, peer: SocketAddr, matched: impl Fn(&D) -> bool) {
+        if let Some(divergences) = self.peers.get_mut(&peer) {
+            divergences.retain(|divergence| !matched(&divergence.range));
+            if divergences.is_empty() {
+                self.peers.remove(&peer);
+            }
+        }
+    }
+
+    /// Forget all the ranges of the peer, once all the keys matched.
+    pub fn clear_peer(&mut self, peer: SocketAddr) {
+        self.peers.remove(&peer);
+    }
+
+    /// Forget the ranges of the peers not in the list.
+    pub fn retain_peers(&mut self, peers: &[SocketAddr]) {
+        self.peers.retain(|peer, _| peers.contains(peer));
+    }
+
+    /// Time since the oldest range still differing was first found, if any.
+    pub fn max_age(&self) -> Option<Duration> {
+        self.peers
+            .values()
+            .flatten()
+            .map(|divergence| divergence.first_seen.elapsed())
+            .max()
+    }
+
+    /// List the ranges still differing with the given peers, with the time since they were first
+    /// and last found to differ.
+    pub fn list(&self, peers: &[SocketAddr]) -> Vec<(SocketAddr, D, Duration, Duration)> {
+        peers
+            .iter()
+            .filter_map(|peer| Some((peer, self.peers.get(peer)?)))
+            .flat_map(|(&peer, divergences)| {
+                divergences.iter().map(move |divergence| {
+                    (
+                        peer,
+                        divergence.range.clone(),
+                        divergence.first_seen.elapsed(),
+                        divergence.last_seen.elapsed(),
+                    )
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use super::Divergences;
+
+    #[test]
+    fn divergences() {
+        let overlap = |a: &(u8, u8), b: &(u8, u8)| a.0 < b.1 && b.0 < a.1;
+        let peer: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let other: SocketAddr = "127.0.0.2:8080".parse().unwrap();
+        let mut divergences = Divergences::new();
+        assert_eq!(divergences.max_age(), None);
+
+        divergences.record(peer, &[(0, 10), (20, 30)], overlap);
+        std::thread::sleep(Duration::from_millis(50));
+        // a narrower range found again keeps the time of the first detection
+        divergences.record(peer, &[(5, 8), (40, 50)], overlap);
+        divergences.record(other, &[(0, 10)], overlap);
+        let mut list = divergences.list(&[peer]);
+        list.sort_by_key(|(_, range, _, _)| *range);
+        let ranges: Vec<_> = list.iter().map(|(_, range, _, _)| *range).collect();
+        assert_eq!(ranges, vec![(5, 8), (20, 30), (40, 50)]);
+        assert!(list[0].2 >= Duration::from_millis(50));
+        assert!(list[0].3 < Duration::from_millis(50));
+        assert!(list[2].2 < Duration::from_millis(50));
+        assert!(divergences.max_age().unwrap() >= Duration::from_millis(50));
+
+        // the ranges within a matching comparison are cleared
+        divergences.clear(peer, |range| range.1 <= 35);
+        let ranges: Vec<_> = divergences
+            .list(&[peer, other])
+            .into_iter()
+            .map(|(peer, range, _, _)| (peer, range))
+            .collect();
+        assert_eq!(ranges.len(), 2);
+        assert!(ranges.contains(&(peer, (40, 50))));
+        assert!(ranges.contains(&(other, (0, 10))));
+        divergences.clear_peer(peer);
+        divergences.clear_peer(other);
+        assert!(!divergences.has(peer));
+        assert_eq!(divergences.max_age(), None);
+    }
+}
@@ -0,0 +1,445 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`ProtocolEngine`], the reconciliation protocol over arbitrary byte streams, for
+//! applications that own their connections instead of letting the [`Service`](crate::Service)
+//! bind a socket.
+//!
+//! The engine does not perform any I/O: the bytes received from a peer are passed to
+//! [`handle_bytes`](ProtocolEngine::handle_bytes), which applies the updates they hold and
+//! returns the bytes to send back. The bytes may be split in any way, for instance into packets
+//! of a fixed size, as long as the bytes of each peer are handled in order. A reconciliation
+//! starts by sending the [`initial_message`](ProtocolEngine::initial_message) to the peer, and
+//! ends once a side has nothing left to reply.
+//!
+//! ```
+//! # use reconcile::{engine::ProtocolEngine, HRTree, HashRangeQueryable};
+//! let now = chrono::Utc::now();
+//! let alice = ProtocolEngine::<_, u8>::new(HRTree::from_iter([(1u8, (now, "one".to_string()))]));
+//! let bob = ProtocolEngine::<_, u8>::new(HRTree::from_iter([(2u8, (now, "two".to_string()))]));
+//! let mut bytes = alice.initial_message();
+//! let mut peers = [(&bob, 0), (&alice, 1)];
+//! loop {
+//!     let (engine, peer) = peers[0];
+//!     let output = engine.handle_bytes(peer, &bytes);
+//!     if output.converged {
+//!         break;
+//!     }
+//!     bytes = output.reply;
+//!     peers.swap(0, 1);
+//! }
+//! assert_eq!(alice.read().len(), 2);
+//! assert_eq!(bob.read().len(), 2);
+//! ```
+//!
+//! The messages are those of the datagrams of the [`Service`](crate::Service), grouped into
+//! frames prefixed by their length, so an engine cannot talk to a service.
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use parking_lot::{Mutex, RwLock, RwLockReadGuard};
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::{debug, trace, warn};
+
+use crate::diff::{Diffable, HashRangeQueryable};
+use crate::internal_service::{
+    decode_message, element_hash, hashable, message_size, read_framed, version_hash, write_message,
+    Message, BUFFER_SIZE, HEADER, MAX_DATAGRAM_SIZE, MAX_MESSAGE_SIZE,
+};
+use crate::map::Map;
+use crate::reconcilable::{ConflictPolicy, LwwPolicy, Reconcilable, Resolution};
+
+/// Size of the length prefixing each frame
+const FRAME_PREFIX: usize = 4;
+
+/// Result of handling the bytes received from a peer.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct EngineOutput {
+    /// Bytes to send back to the peer, empty when there is nothing to reply
+    pub reply: Vec<u8>,
+    /// Number of updates of the peer stored in the map
+    pub applied: usize,
+    /// Whether the reconciliation with the peer is over: the segments received all matched, and
+    /// nothing is left to send
+    pub converged: bool,
+}
+
+/// Reconciliation protocol over a map, without owning any socket.
+///
+/// The map is only locked while handling bytes, so the engine may be shared between the tasks
+/// serving several peers, identified by `P`.
+pub struct ProtocolEngine<M: Map, P = SocketAddr> {
+    map: RwLock<M>,
+    policy: Arc<dyn ConflictPolicy<M::Value>>,
+    /// Bytes received from each peer that do not form a whole frame yet
+    partial: Mutex<HashMap<P, Vec<u8>>>,
+}
+
+impl<
+        K: Clone + Debug + DeserializeOwned + Hash + Ord + Serialize,
+        V: Clone + DeserializeOwned + Hash + Serialize,
+        C: Clone + Debug + DeserializeOwned + Serialize,
+        D: Debug,
+        M: Map<Key = K, Value = V, DifferenceItem = D>
+            + Diffable<ComparisonItem = C, DifferenceItem = D>
+            + HashRangeQueryable<Key = K>,
+        P: Clone + Debug + Eq + Hash,
+    > ProtocolEngine<M, P>
+{
+    /// Create an engine over the map, resolving the conflicts with the [`LwwPolicy`].
+    pub fn new(map: M) -> Self
+    where
+        V: Reconcilable,
+    {
+        ProtocolEngine::new_with_policy(map, Arc::new(LwwPolicy))
+    }
+
+    /// Create an engine over the map, resolving the conflicts with the given policy.
+    pub fn new_with_policy(map: M, policy: Arc<dyn ConflictPolicy<V>>) -> Self {
+        ProtocolEngine {
+            map: RwLock::new(map),
+            policy,
+            partial: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Lock the map for reading.
+    pub fn read(&self) -> RwLockReadGuard<'_, M> {
+        self.map.read()
+    }
+
+    /// Insert a key-value pair in the map, returning the previous value.
+    ///
+    /// The peers learn it at the next reconciliation. In the canonical hashing mode, an element
+    /// that cannot be serialized is dropped with a warning.
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        let mut guard = self.map.write();
+        if !hashable(guard.canonical_hashing(), &key, &value) {
+            return None;
+        }
+        guard.insert(key, value)
+    }
+
+    /// Bytes starting a reconciliation, to send to a peer.
+    pub fn initial_message(&self) -> Vec<u8> {
+        let segments = self.map.read().start_diff();
+        let messages: Vec<_> = segments
+            .into_iter()
+            .map(|segment| Message::ComparisonItem::<K, V, C>(0, segment))
+            .collect();
+        frames(&messages)
+    }
+
+    /// Handle bytes received from a peer, and return the bytes to send back.
+    ///
+    /// The bytes that do not form a whole frame are kept until the next call for the same peer.
+    /// Malformed frames are dropped with a warning.
+    pub fn handle_bytes(&self, peer: P, bytes: &[u8]) -> EngineOutput {
+        let mut segments = Vec::new();
+        let mut updates = Vec::new();
+        let mut received = false;
+        {
+            let mut partial = self.partial.lock();
+            let buffer = partial.entry(peer.clone()).or_default();
+            buffer.extend_from_slice(bytes);
+            let mut reader = &buffer[..];
+            while let Some((size, rest)) = reader.split_first_chunk::<FRAME_PREFIX>() {
+                let size = u32::from_le_bytes(*size) as usize;
+                if size > BUFFER_SIZE {
+                    // the stream cannot be resynchronized
+                    warn!("dropping the stream of {peer:?}: frame of {size} bytes");
+                    reader = &[];
+                    break;
+                }
+                if rest.len() < size {
+                    break;
+                }
+                let (frame, rest) = rest.split_at(size);
+                reader = rest;
+                received = true;
+                read_frame(&peer, frame, &mut segments, &mut updates);
+            }
+            let consumed = buffer.len() - reader.len();
+            buffer.drain(..consumed);
+            if buffer.is_empty() {
+                partial.remove(&peer);
+            }
+        }
+        if !received {
+            return EngineOutput::default();
+        }
+
+        let (applied, mut messages) = self.apply_updates(&peer, updates);
+        let guard = self.map.read();
+        let mut out_comparison = Vec::new();
+        let mut differences = Vec::new();
+        guard.diff_round(segments, &mut out_comparison, &mut differences);
+        debug!(
+            "returning {} segments and {} diff_ranges to {peer:?}",
+            out_comparison.len(),
+            differences.len()
+        );
+        messages.extend(
+            out_comparison
+                .into_iter()
+                .map(|segment| Message::ComparisonItem(0, segment)),
+        );
+        if !differences.is_empty() {
+            messages.extend(
+                guard
+                    .enumerate_diff_ranges_iter(differences)
+                    .map(Message::Update),
+            );
+        }
+        drop(guard);
+        EngineOutput {
+            converged: messages.is_empty(),
+            reply: frames(&messages),
+            applied,
+        }
+    }
+
+    /// Forget the bytes received from the peer that do not form a whole frame, once its stream
+    /// is closed.
+    pub fn forget(&self, peer: &P) {
+        self.partial.lock().remove(peer);
+    }
+
+    /// Store the updates of the peer that win over the local values.
+    ///
+    /// Return the number of updates stored, and the merged values to send back.
+    fn apply_updates(&self, peer: &P, updates: Vec<(K, V)>) -> (usize, Vec<Message<K, V, C>>) {
+        let mut applied = 0;
+        let mut merged_updates = Vec::new();
+        let mut guard = self.map.write();
+        let canonical = guard.canonical_hashing();
+        for (k, v) in updates {
+            if !hashable(canonical, &k, &v) {
+                continue;
+            }
+            if guard.hash_of(&k) == Some(element_hash(&*guard, &k, &v)) {
+                trace!("skipping update from {peer:?} identical to the local value");
+                continue;
+            }
+            let (change, merged) = resolve_update(&*self.policy, &k, guard.get(&k).as_deref(), v);
+            if let Some(merged) = merged {
+                merged_updates.push(Message::Update((k.clone(), merged)));
+            }
+            if let Some(v) = change {
+                guard.insert(k, v);
+                applied += 1;
+            }
+        }
+        (applied, merged_updates)
+    }
+}
+
+/// Decide what to store for an update received from a peer, given the local value of the key.
+///
+/// Return the value to store, unless the local value is kept, and the merged value to send back,
+/// when the peer does not hold it.
+pub(crate) fn resolve_update<K: Hash, V: Clone + Hash>(
+    policy: &dyn ConflictPolicy<V>,
+    key: &K,
+    local: Option<&V>,
+    incoming: V,
+) -> (Option<V>, Option<V>) {
+    let Some(local) = local else {
+        return (Some(incoming), None);
+    };
+    match policy.resolve(local, &incoming) {
+        Resolution::Merge(merged) => {
+            let hash = version_hash(key, &merged);
+            let send_back = (hash != version_hash(key, &incoming)).then(|| merged.clone());
+            (
+                (hash != version_hash(key, local)).then_some(merged),
+                send_back,
+            )
+        }
+        Resolution::KeepIncoming => (Some(incoming), None),
+        Resolution::KeepLocal => (None, None),
+    }
+}
+
+/// Read the messages of a frame, keeping the segments and updates.
+fn read_frame<
+    K: DeserializeOwned + Serialize,
+    V: DeserializeOwned + Serialize,
+    C: DeserializeOwned + Serialize,
+    P: Debug,
+>(
+    peer: &P,
+    frame: &[u8],
+    segments: &mut Vec<C>,
+    updates: &mut Vec<(K, V)>,
+) {
+    let Some(mut reader) = frame.strip_prefix(&HEADER[..]) else {
+        warn!("dropping frame from {peer:?} with unknown header");
+        return;
+    };
+    while !reader.is_empty() {
+        let message = match read_framed(&mut reader) {
+            Ok(message) => message,
+            Err(err) => {
+                warn!("dropping the rest of a frame from {peer:?}: {err}");
+                return;
+            }
+        };
+        // a message that cannot be deserialized is skipped, the following ones are read anyway
+        match decode_message::<Message<K, V, C>>(message, BUFFER_SIZE) {
+            Ok(Some(Message::ComparisonItem(_, segment))) => segments.push(segment),
+            Ok(Some(Message::Update(update))) => updates.push(update),
+            Ok(_) => trace!("skipping message from {peer:?}"),
+            Err(err) => warn!("skipping undecodable message from {peer:?}: {err}"),
+        }
+    }
+}
+
+/// Group the messages into frames of at most the size of a datagram, each prefixed by its size.
+///
+/// The messages too large for a frame are dropped with a warning.
+fn frames<M: Serialize>(messages: &[M]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    let mut frame = HEADER.to_vec();
+    for message in messages {
+        let size = message_size(message);
+        if size > MAX_MESSAGE_SIZE {
+            warn!("dropping message of {size} bytes, too large for a frame");
+            continue;
+        }
+        if frame.len() + size > MAX_DATAGRAM_SIZE {
+            push_frame(&mut bytes, &frame);
+            frame.truncate(HEADER.len());
+        }
+        if let Err(err) = write_message(&mut frame, message) {
+            warn!("dropping message: {err}");
+        }
+    }
+    if frame.len() > HEADER.len() {
+        push_frame(&mut bytes, &frame);
+    }
+    bytes
+}
+
+fn push_frame(bytes: &mut Vec<u8>, frame: &[u8]) {
+    // NOTE: a frame holds at most MAX_DATAGRAM_SIZE bytes
+    bytes.extend_from_slice(&(frame.len() as u32).to_le_bytes());
+    bytes.extend_from_slice(frame);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use super::{frames, ProtocolEngine};
+    use crate::internal_service::Message;
+    use crate::reconcilable::Resolution;
+    use crate::{HRTree, HashRangeQueryable};
+
+    type Engine = ProtocolEngine<HRTree<u32, u32>, u8>;
+
+    /// Engine over the given entries, keeping the largest value on conflicts.
+    fn engine(entries: impl IntoIterator<Item = (u32, u32)>) -> Engine {
+        let policy = |local: &u32, incoming: &u32| {
+            if incoming > local {
+                Resolution::KeepIncoming
+            } else {
+                Resolution::KeepLocal
+            }
+        };
+        Engine::new_with_policy(HRTree::from_iter(entries), Arc::new(policy))
+    }
+
+    /// Exchange the bytes between the engines in packets of `packet` bytes until one of them
+    /// converges, and return the number of round-trips.
+    fn run(a: &Engine, b: &Engine, packet: usize) -> usize {
+        let mut bytes = a.initial_message();
+        let engines = [(b, 0), (a, 1)];
+        for round in 0.. {
+            let (engine, peer) = engines[round % 2];
+            let mut reply = Vec::new();
+            let mut converged = false;
+            for chunk in bytes.chunks(packet) {
+                let output = engine.handle_bytes(peer, chunk);
+                reply.extend(output.reply);
+                converged |= output.converged;
+            }
+            if converged {
+                assert!(reply.is_empty());
+                return round / 2;
+            }
+            assert!(!reply.is_empty(), "stalled without converging");
+            bytes = reply;
+        }
+        unreachable!()
+    }
+
+    fn entries(engine: &Engine) -> Vec<(u32, u32)> {
+        engine
+            .read()
+            .iter()
+            .map(|(&k, &v)| (k, v))
+            .collect::<Vec<_>>()
+    }
+
+    #[test]
+    fn in_memory() {
+        // the maps differ in both directions, on values and keys
+        let a = engine((0..10_000).map(|i| (i, i)));
+        let b = engine((5_000..12_000).map(|i| (i, i)));
+        a.insert(7_000, 70_000);
+        b.insert(8_000, 80_000);
+        for packet in [1, 1200, usize::MAX] {
+            let rounds = run(&a, &b, packet);
+            assert!(rounds < 10, "{rounds} rounds");
+            assert_eq!(entries(&a), entries(&b));
+            assert_eq!(a.read().len(), 12_000);
+        }
+        // nothing left to exchange
+        let initial = a.initial_message();
+        let output = b.handle_bytes(0, &initial);
+        assert!(output.converged);
+        assert_eq!(output.applied, 0);
+    }
+
+    #[test]
+    fn merged_values() {
+        // the values are merged by keeping the largest, which the peer holding the lowest must
+        // learn back
+        let policy =
+            Arc::new(|local: &u32, incoming: &u32| Resolution::Merge(*local.max(incoming)));
+        let a = Engine::new_with_policy(HRTree::from_iter([(1, 5), (2, 1)]), policy.clone());
+        let b = Engine::new_with_policy(HRTree::from_iter([(1, 1), (2, 5)]), policy);
+        run(&a, &b, 1200);
+        assert_eq!(entries(&a), [(1, 5), (2, 5)]);
+        assert_eq!(entries(&b), entries(&a));
+    }
+
+    #[test]
+    fn partial_frames() {
+        let a = engine([(1, 1)]);
+        let b = engine([]);
+        let bytes = frames(&[Message::<u32, u32, ()>::Update((2, 2))]);
+        let (start, end) = bytes.split_at(bytes.len() - 1);
+        // the frame is only handled once complete
+        assert_eq!(a.handle_bytes(0, start), Default::default());
+        assert_eq!(a.handle_bytes(1, end), Default::default());
+        let output = a.handle_bytes(0, end);
+        assert_eq!(output.applied, 1);
+        assert!(output.converged);
+        a.forget(&1);
+        assert!(a.partial.lock().is_empty());
+        // garbage is dropped
+        assert_eq!(b.handle_bytes(0, &[4, 0, 0, 0, 1, 2, 3, 4]).applied, 0);
+        assert!(b.read().is_empty());
+    }
+}
@@ -0,0 +1,53 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`Error`], returned by the constructors of the services, and reported by
+//! [`Service::last_error`](crate::Service::last_error) while they run.
+
+use std::fmt::{self, Display};
+use std::net::SocketAddr;
+
+/// Failure of a service to set up, or to communicate with its peers.
+#[derive(Debug)]
+pub enum Error {
+    /// The socket could not be bound to the address
+    Bind(SocketAddr, std::io::Error),
+    /// A datagram could not be sent to the peer, even after retrying
+    Send(SocketAddr, std::io::Error),
+    /// A message could not be serialized, because of the implementation of
+    /// [`Serialize`](serde::Serialize) of the keys or values
+    Serialize(bincode::Error),
+    /// The write-ahead log or the snapshot could not be read or written
+    Io(std::io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Bind(addr, err) => write!(f, "failed to bind to {addr}: {err}"),
+            Error::Send(addr, err) => write!(f, "failed to send to {addr}: {err}"),
+            Error::Serialize(err) => write!(f, "failed to serialize a message: {err}"),
+            Error::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Bind(_, err) | Error::Send(_, err) | Error::Io(err) => Some(err),
+            Error::Serialize(err) => Some(err),
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        Error::Io(err)
+    }
+}
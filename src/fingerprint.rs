@@ -0,0 +1,415 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides the [`FingerprintStrategy`] trait, which defines how the elements of an
+//! [`HRTree`](crate::HRTree) are hashed and how the hashes are cumulated over a range.
+//!
+//! Two strategies are available:
+//! * [`DefaultFingerprint`], which XORs 64-bit hashes (this is the historic behavior),
+//! * [`Sum128Fingerprint`], which sums 128-bit hashes, making collisions much less likely.
+//!
+//! To migrate a cluster from one strategy to another without restarting all the instances at
+//! once, the instances can be restarted one by one with a [`DualFingerprint`], which maintains
+//! both. Each session then compares with the strongest fingerprint supported by both instances.
+//! Once all the instances support the new strategy, they can be restarted one by one again with
+//! only the new one.
+//!
+//! The hashes of the elements rely on the [`Hash`] implementations of the keys and values, unless
+//! the tree hashes their serialized form with [`canonical_hash`], which is specified
+//! independently of Rust.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt::{Debug, Display};
+use std::hash::{Hash, Hasher};
+use std::marker::PhantomData;
+
+use bincode::{DefaultOptions, Options};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+
+/// Defines the hash of an element, and how hashes are cumulated.
+///
+/// The cumulated hash of a range does not depend on how the elements are grouped in the tree,
+/// so [`combine`](FingerprintStrategy::combine) must be associative and commutative, with
+/// [`identity`](FingerprintStrategy::identity) as the neutral element, and every hash must have
+/// an [`invert`](FingerprintStrategy::invert)ed element. In other words, the hashes must form an
+/// abelian group.
+pub trait FingerprintStrategy {
+    type Output: Copy + Debug + DeserializeOwned + Display + Eq + Send + Serialize + Sync + 'static;
+    /// Identifies the strategy among the ones peers may use; a higher id is a stronger strategy.
+    const ID: u8 = 0;
+    /// Hash of a single key-value pair.
+    ///
+    /// The hash follows the [`Hash`] implementations of the key and the value: a `String` and a
+    /// `&str` hash the same, since the former delegates to the latter, but a newtype with its own
+    /// [`Hash`] may not hash as the type it wraps. Peers using such types need
+    /// [`canonical_hash`], and [`assert_key_hash_compat`](crate::testing::assert_key_hash_compat)
+    /// checks two types in tests.
+    fn hash<K: Hash, V: Hash>(key: &K, value: &V) -> Self::Output;
+    /// Cumulated hash of the empty set.
+    fn identity() -> Self::Output;
+    /// Cumulated hash of the union of two disjoint sets.
+    fn combine(a: Self::Output, b: Self::Output) -> Self::Output;
+    /// Value whose combination with `a` yields the identity.
+    fn invert(a: Self::Output) -> Self::Output;
+    /// Cumulated hash of a set after removing the subset whose cumulated hash is `b`.
+    fn remove(a: Self::Output, b: Self::Output) -> Self::Output {
+        Self::combine(a, Self::invert(b))
+    }
+    /// Ids of the strategies the hashes can be compared with, from the weakest.
+    fn supported() -> Vec<u8> {
+        vec![Self::ID]
+    }
+    /// Restrict a cumulated hash to the strategy with the given id, among the supported ones.
+    fn project(hash: Self::Output, _id: u8) -> Self::Output {
+        hash
+    }
+    /// Interpret a hash received from a peer comparing with the strategy with the given id.
+    fn resolve(hash: Self::Output, _id: u8) -> Self::Output {
+        hash
+    }
+    /// Hash of a key-value pair from the SHA-256 digest of its serialized form, see
+    /// [`canonical_hash`].
+    ///
+    /// The default implementation hashes the digest with [`hash`](FingerprintStrategy::hash),
+    /// which may not be specified.
+    fn hash_digest(digest: &[u8; 32]) -> Self::Output {
+        Self::hash(digest, &())
+    }
+}
+
+/// Hash of a key-value pair in its serialized form, derived by the strategy `F` from a SHA-256
+/// digest of the bytes sent to the peers.
+///
+/// The hash only depends on these bytes, and is fully specified, so that it can be computed from
+/// them whatever the types the peers deserialize them into, and in other languages. The key and
+/// the value are serialized as in the messages, by `bincode` with its `DefaultOptions`
+/// (little-endian, variable-length integers). The digest is the SHA-256 of:
+/// 1. the length of the serialized key in bytes, as a little-endian `u64`,
+/// 2. the serialized key,
+/// 3. the length of the serialized value in bytes, as a little-endian `u64`,
+/// 4. the serialized value.
+///
+/// The hash is then given by [`FingerprintStrategy::hash_digest`]: for instance, the first 8
+/// bytes of the digest as a little-endian `u64` for the [`DefaultFingerprint`].
+///
+/// # Panics
+///
+/// Panics if the key or the value cannot be serialized; the [`Service`](crate::Service) drops
+/// such elements in the canonical mode instead of storing them.
+pub fn canonical_hash<F: FingerprintStrategy, K: Serialize, V: Serialize>(
+    key: &K,
+    value: &V,
+) -> F::Output {
+    let options = DefaultOptions::new();
+    let key = options.serialize(key).expect("cannot serialize the key");
+    let value = options
+        .serialize(value)
+        .expect("cannot serialize the value");
+    let mut hasher = Sha256::new();
+    for bytes in [key, value] {
+        hasher.update((bytes.len() as u64).to_le_bytes());
+        hasher.update(bytes);
+    }
+    F::hash_digest(&hasher.finalize().into())
+}
+
+/// 64-bit hashes from the [`DefaultHasher`], cumulated with XOR.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DefaultFingerprint;
+
+impl FingerprintStrategy for DefaultFingerprint {
+    type Output = u64;
+
+    fn hash<K: Hash, V: Hash>(key: &K, value: &V) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        key.hash(&mut hasher);
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+
+    fn hash_digest(digest: &[u8; 32]) -> u64 {
+        u64::from_le_bytes(digest[..8].try_into().unwrap())
+    }
+
+    fn identity() -> u64 {
+        0
+    }
+
+    fn combine(a: u64, b: u64) -> u64 {
+        a ^ b
+    }
+
+    fn invert(a: u64) -> u64 {
+        a
+    }
+}
+
+/// 128-bit hashes, cumulated with a wrapping sum.
+///
+/// Unlike XOR, a sum does not cancel out an element that appears twice,
+/// and the wider hash makes accidental collisions negligible.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct Sum128Fingerprint;
+
+impl FingerprintStrategy for Sum128Fingerprint {
+    type Output = u128;
+    const ID: u8 = 1;
+
+    fn hash<K: Hash, V: Hash>(key: &K, value: &V) -> u128 {
+        let low = DefaultFingerprint::hash(key, value);
+        let mut hasher = DefaultHasher::new();
+        low.hash(&mut hasher);
+        key.hash(&mut hasher);
+        value.hash(&mut hasher);
+        let high = hasher.finish();
+        ((high as u128) << 64) | low as u128
+    }
+
+    fn hash_digest(digest: &[u8; 32]) -> u128 {
+        u128::from_le_bytes(digest[..16].try_into().unwrap())
+    }
+
+    fn identity() -> u128 {
+        0
+    }
+
+    fn combine(a: u128, b: u128) -> u128 {
+        a.wrapping_add(b)
+    }
+
+    fn invert(a: u128) -> u128 {
+        a.wrapping_neg()
+    }
+}
+
+/// Maintains the hashes of two strategies: the primary one `P`, used by the older instances of a
+/// cluster, and the secondary one `S`, to migrate to.
+///
+/// The hashes of both strategies must fit in 128 bits. On the wire, a hash is sent as a single
+/// integer, so that the hashes of the primary strategy are understood by the instances that only
+/// use it.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DualFingerprint<P, S>(PhantomData<(P, S)>);
+
+/// Hash of a [`DualFingerprint`].
+///
+/// The hashes of both strategies are equal to the hashes of either strategy when they match.
+#[derive(Clone, Copy, Debug)]
+pub enum DualHash<A, B> {
+    /// Hashes of both strategies, as stored in the tree
+    Both(A, B),
+    /// Hash of the primary strategy, in a session using it
+    Primary(A),
+    /// Hash of the secondary strategy, in a session using it
+    Secondary(B),
+    /// Hash received from a peer, until the strategy of the session is known
+    Received(u128),
+}
+
+impl<A: PartialEq, B: PartialEq> PartialEq for DualHash<A, B> {
+    fn eq(&self, other: &Self) -> bool {
+        use DualHash::*;
+        match (self, other) {
+            (Both(a1, b1), Both(a2, b2)) => a1 == a2 && b1 == b2,
+            (Both(a1, _) | Primary(a1), Both(a2, _) | Primary(a2)) => a1 == a2,
+            (Both(_, b1) | Secondary(b1), Both(_, b2) | Secondary(b2)) => b1 == b2,
+            (Received(x1), Received(x2)) => x1 == x2,
+            _ => false,
+        }
+    }
+}
+
+impl<A: Eq, B: Eq> Eq for DualHash<A, B> {}
+
+impl<A: Display, B: Display> Display for DualHash<A, B> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DualHash::Both(a, b) => write!(f, "{a}/{b}"),
+            DualHash::Primary(a) => write!(f, "{a}"),
+            DualHash::Secondary(b) => write!(f, "{b}"),
+            DualHash::Received(x) => write!(f, "{x}"),
+        }
+    }
+}
+
+impl<A: Copy + Into<u128>, B: Copy + Into<u128>> Serialize for DualHash<A, B> {
+    fn serialize<Ser: Serializer>(&self, serializer: Ser) -> Result<Ser::Ok, Ser::Error> {
+        let value = match *self {
+            // NOTE: only projected hashes are sent
+            DualHash::Both(a, _) | DualHash::Primary(a) => a.into(),
+            DualHash::Secondary(b) => b.into(),
+            DualHash::Received(x) => x,
+        };
+        value.serialize(serializer)
+    }
+}
+
+impl<'de, A, B> Deserialize<'de> for DualHash<A, B> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u128::deserialize(deserializer).map(DualHash::Received)
+    }
+}
+
+impl<P, S> FingerprintStrategy for DualFingerprint<P, S>
+where
+    P: FingerprintStrategy,
+    S: FingerprintStrategy,
+    P::Output: Into<u128> + TryFrom<u128>,
+    S::Output: Into<u128> + TryFrom<u128>,
+{
+    type Output = DualHash<P::Output, S::Output>;
+    const ID: u8 = S::ID;
+
+    fn hash<K: Hash, V: Hash>(key: &K, value: &V) -> Self::Output {
+        DualHash::Both(P::hash(key, value), S::hash(key, value))
+    }
+
+    fn hash_digest(digest: &[u8; 32]) -> Self::Output {
+        DualHash::Both(P::hash_digest(digest), S::hash_digest(digest))
+    }
+
+    fn identity() -> Self::Output {
+        DualHash::Both(P::identity(), S::identity())
+    }
+
+    fn combine(a: Self::Output, b: Self::Output) -> Self::Output {
+        use DualHash::*;
+        match (a, b) {
+            (Both(a1, b1), Both(a2, b2)) => Both(P::combine(a1, a2), S::combine(b1, b2)),
+            (Both(a1, _) | Primary(a1), Both(a2, _) | Primary(a2)) => Primary(P::combine(a1, a2)),
+            (Both(_, b1) | Secondary(b1), Both(_, b2) | Secondary(b2)) => {
+                Secondary(S::combine(b1, b2))
+            }
+            // the hashes of different strategies never match anything
+            _ => Received(u128::MAX),
+        }
+    }
+
+    fn invert(a: Self::Output) -> Self::Output {
+        match a {
+            DualHash::Both(a, b) => DualHash::Both(P::invert(a), S::invert(b)),
+            DualHash::Primary(a) => DualHash::Primary(P::invert(a)),
+            DualHash::Secondary(b) => DualHash::Secondary(S::invert(b)),
+            DualHash::Received(x) => DualHash::Received(x),
+        }
+    }
+
+    fn supported() -> Vec<u8> {
+        vec![P::ID, S::ID]
+    }
+
+    fn project(hash: Self::Output, id: u8) -> Self::Output {
+        match hash {
+            DualHash::Both(a, _) if id == P::ID => DualHash::Primary(a),
+            DualHash::Both(_, b) if id == S::ID => DualHash::Secondary(b),
+            hash => hash,
+        }
+    }
+
+    fn resolve(hash: Self::Output, id: u8) -> Self::Output {
+        let DualHash::Received(x) = hash else {
+            return hash;
+        };
+        let resolved = if id == P::ID {
+            P::Output::try_from(x).ok().map(DualHash::Primary)
+        } else if id == S::ID {
+            S::Output::try_from(x).ok().map(DualHash::Secondary)
+        } else {
+            None
+        };
+        resolved.unwrap_or(hash)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bincode::{DefaultOptions, Options};
+
+    use serde::{Serialize, Serializer};
+
+    use super::{
+        canonical_hash, DefaultFingerprint, DualFingerprint, DualHash, FingerprintStrategy,
+        Sum128Fingerprint,
+    };
+
+    type Dual = DualFingerprint<DefaultFingerprint, Sum128Fingerprint>;
+
+    #[test]
+    fn dual_projections() {
+        let a = Dual::hash(&1, &"a");
+        let b = Dual::hash(&2, &"b");
+        let both = Dual::combine(a, b);
+        let primary = DefaultFingerprint::combine(
+            DefaultFingerprint::hash(&1, &"a"),
+            DefaultFingerprint::hash(&2, &"b"),
+        );
+        assert_eq!(
+            Dual::project(both, DefaultFingerprint::ID),
+            DualHash::Primary(primary)
+        );
+        assert_eq!(
+            Dual::remove(Dual::project(both, Sum128Fingerprint::ID), b),
+            Dual::project(a, Sum128Fingerprint::ID)
+        );
+
+        // the primary hashes are sent as the ones of the primary strategy
+        let options = DefaultOptions::new();
+        let bytes = options
+            .serialize(&Dual::project(both, DefaultFingerprint::ID))
+            .unwrap();
+        assert_eq!(bytes, options.serialize(&primary).unwrap());
+        let received: DualHash<u64, u128> = options.deserialize(&bytes).unwrap();
+        assert_eq!(
+            Dual::resolve(received, DefaultFingerprint::ID),
+            DualHash::Primary(primary)
+        );
+    }
+
+    /// Value that cannot be serialized
+    struct Unserializable;
+
+    impl Serialize for Unserializable {
+        fn serialize<S: Serializer>(&self, _serializer: S) -> Result<S::Ok, S::Error> {
+            Err(serde::ser::Error::custom("unserializable"))
+        }
+    }
+
+    #[test]
+    fn canonical_hashes() {
+        // only the serialized form matters
+        assert_eq!(
+            canonical_hash::<DefaultFingerprint, _, _>(&"a", &1u32),
+            canonical_hash::<DefaultFingerprint, _, _>(&String::from("a"), &1u64)
+        );
+        assert_eq!(
+            canonical_hash::<Sum128Fingerprint, _, _>(&"a", &()),
+            canonical_hash::<Sum128Fingerprint, _, _>(&b"a".to_vec(), &())
+        );
+        // the SHA-256 of the lengths and bytes of the key, [1, b'a'], and of the value, [1]
+        assert_eq!(
+            canonical_hash::<DefaultFingerprint, _, _>(&"a", &1u32),
+            0x27ad45119e58e852
+        );
+        assert_eq!(
+            canonical_hash::<Sum128Fingerprint, _, _>(&"a", &1u32),
+            0x7063af2eee4a756727ad45119e58e852
+        );
+        // the bytes are not shifted from the key to the value
+        assert_ne!(
+            canonical_hash::<DefaultFingerprint, _, _>(&(1u8, 2u8), &3u8),
+            canonical_hash::<DefaultFingerprint, _, _>(&1u8, &(2u8, 3u8))
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot serialize the value")]
+    fn canonical_hash_unserializable() {
+        canonical_hash::<DefaultFingerprint, _, _>(&1u32, &Unserializable);
+    }
+}
@@ -0,0 +1,143 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`Reassembly`], which puts back together the messages too large for a datagram.
+//!
+//! Such a message is serialized, then split into fragments sent in separate datagrams. Each
+//! fragment carries the id of the message, which is the hash of its bytes, its index, and the
+//! total number of fragments. When one of the fragments is lost, the partial message is dropped
+//! after a while, and the difference is found again by the next reconciliation sessions.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Number of bytes of a serialized message carried by each fragment
+pub(crate) const FRAGMENT_SIZE: usize = 60000;
+/// Maximum number of bytes taken by a fragment in a datagram besides the bytes of the message
+pub(crate) const FRAGMENT_OVERHEAD: usize = 32;
+/// Maximum number of fragments of a message, which bounds the size of the messages
+pub(crate) const MAX_FRAGMENTS: usize = 256;
+/// Maximum number of partial messages held at the same time
+const MAX_PENDING: usize = 64;
+/// A partial message without any new fragment for this long is dropped
+const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Id of the message with the given serialized bytes.
+pub(crate) fn message_id(bytes: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    bytes.hash(&mut hasher);
+    hasher.finish()
+}
+
+struct Partial {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    last_activity: Instant,
+}
+
+pub(crate) struct Reassembly {
+    partials: HashMap<(SocketAddr, u64), Partial>,
+}
+
+impl Reassembly {
+    pub fn new() -> Self {
+        Reassembly {
+            partials: HashMap::new(),
+        }
+    }
+
+    /// Add a fragment received from the peer.
+    ///
+    /// Return the bytes of the message once all of its fragments were received.
+    pub fn insert(
+        &mut self,
+        peer: SocketAddr,
+        id: u64,
+        index: usize,
+        total: usize,
+        bytes: Vec<u8>,
+    ) -> Option<Vec<u8>> {
+        if index >= total || total > MAX_FRAGMENTS || bytes.len() > FRAGMENT_SIZE {
+            return None;
+        }
+        self.partials
+            .retain(|_, partial| partial.last_activity.elapsed() < REASSEMBLY_TIMEOUT);
+        if self.partials.len() >= MAX_PENDING && !self.partials.contains_key(&(peer, id)) {
+            // make room by dropping the partial message that made progress the longest ago
+            let oldest = self
+                .partials
+                .iter()
+                .min_by_key(|(_, partial)| partial.last_activity)
+                .map(|(&key, _)| key);
+            if let Some(oldest) = oldest {
+                self.partials.remove(&oldest);
+            }
+        }
+        let partial = self.partials.entry((peer, id)).or_insert_with(|| Partial {
+            fragments: vec![None; total],
+            received: 0,
+            last_activity: Instant::now(),
+        });
+        if partial.fragments.len() != total {
+            // the id is the hash of the message, so this peer is misbehaving
+            self.partials.remove(&(peer, id));
+            return None;
+        }
+        partial.last_activity = Instant::now();
+        let fragment = &mut partial.fragments[index];
+        if fragment.is_none() {
+            *fragment = Some(bytes);
+            partial.received += 1;
+        }
+        if partial.received < total {
+            return None;
+        }
+        let partial = self.partials.remove(&(peer, id))?;
+        let message: Vec<u8> = partial.fragments.into_iter().flatten().flatten().collect();
+        (message_id(&message) == id).then_some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{message_id, Reassembly, FRAGMENT_SIZE};
+
+    #[test]
+    fn reassembly() {
+        let peer1: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let peer2: SocketAddr = "127.0.0.2:8080".parse().unwrap();
+        let message: Vec<u8> = (0..2 * FRAGMENT_SIZE + 10).map(|i| i as u8).collect();
+        let id = message_id(&message);
+        let fragments: Vec<_> = message.chunks(FRAGMENT_SIZE).map(<[u8]>::to_vec).collect();
+        let mut reassembly = Reassembly::new();
+        let mut insert = |peer, index, total, fragment: &Vec<u8>| {
+            reassembly.insert(peer, id, index, total, fragment.clone())
+        };
+
+        // out of order, duplicated, and interleaved with the fragments from another peer
+        assert_eq!(insert(peer1, 2, 3, &fragments[2]), None);
+        assert_eq!(insert(peer1, 2, 3, &fragments[2]), None);
+        assert_eq!(insert(peer2, 0, 3, &fragments[0]), None);
+        assert_eq!(insert(peer1, 0, 3, &fragments[0]), None);
+        assert_eq!(insert(peer1, 1, 3, &fragments[1]), Some(message));
+        // the message is only returned once
+        assert_eq!(insert(peer1, 1, 3, &fragments[1]), None);
+
+        // inconsistent fragments
+        assert_eq!(insert(peer2, 1, 2, &fragments[1]), None);
+        assert_eq!(insert(peer2, 3, 3, &fragments[1]), None);
+        assert_eq!(insert(peer2, 1, 3, &fragments[0]), None);
+        assert_eq!(insert(peer2, 0, 3, &fragments[0]), None);
+        assert_eq!(insert(peer2, 2, 3, &fragments[2]), None);
+    }
+}
@@ -0,0 +1,46 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Entry points of the fuzz targets under `fuzz/`, which are also run for a few seconds by the
+//! smoke tests of the `fuzz-smoke` feature.
+//!
+//! Both consume bytes controlled by a peer, and must not panic, whatever the bytes.
+
+use bincode::{DefaultOptions, Options};
+use chrono::{DateTime, Utc};
+
+use crate::diff::{is_reversed, Diffable, HashSegment};
+use crate::hrtree::HRTree;
+use crate::internal_service::parse_datagram;
+
+/// Read the segments and the updates of a datagram, as the service does.
+pub fn datagram(data: &[u8]) {
+    type Value = (DateTime<Utc>, Option<Vec<u8>>);
+    let _ = parse_datagram::<Vec<u8>, Value, HashSegment<Vec<u8>>>(data);
+}
+
+/// Compare the segments decoded from the data with a small fixed tree, and check that the
+/// segments sent back and the differences found are well-formed.
+pub fn diff_round(data: &[u8]) {
+    let Ok(segments) = DefaultOptions::new()
+        .with_limit(data.len() as u64)
+        .deserialize::<Vec<HashSegment<u64>>>(data)
+    else {
+        return;
+    };
+    let tree: HRTree<u64, u64> = HRTree::from_sorted_iter((0..1000).map(|i| (2 * i, i)));
+    let mut out_comparison = Vec::new();
+    let mut differences = Vec::new();
+    tree.diff_round(segments, &mut out_comparison, &mut differences);
+    for segment in &out_comparison {
+        assert!(!is_reversed(segment.range()), "reversed segment sent back");
+    }
+    for range in &differences {
+        assert!(!is_reversed(range), "reversed difference");
+    }
+}
@@ -0,0 +1,187 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides the [`Hello`] an instance sends to the peers it contacts for the first time, and
+//! [`Handshakes`], which tracks whether each peer is compatible.
+//!
+//! Instances that do not share the protocol version, any fingerprint, or the schema of the
+//! values cannot reconcile: their segments never match, or their updates cannot be deserialized.
+//! Once a peer is found incompatible, no session is started with it, and what it sends is dropped.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use serde::{Deserialize, Serialize};
+
+/// Describes an instance to a peer, on first contact.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) struct Hello {
+    /// Version of the wire format
+    pub proto_version: u8,
+    /// Ids of the fingerprints supported, from the weakest
+    pub fingerprints: Vec<u8>,
+    /// Identifies the types of the keys and values, as set by
+    /// [`with_schema_id`](crate::Service::with_schema_id)
+    pub value_schema_hash: u64,
+    /// Number of elements of the map
+    pub len: u64,
+    /// Global hash of the map, as displayed
+    pub root_hash: String,
+}
+
+impl Hello {
+    /// Why the instance that sent `remote` cannot reconcile with this one, if it cannot.
+    ///
+    /// Instances with another schema id are compatible when the updates are tagged with it, see
+    /// [`with_schema_tags`](crate::Service::with_schema_tags).
+    pub fn incompatibility(&self, remote: &Hello, schema_tags: bool) -> Option<String> {
+        if remote.proto_version != self.proto_version {
+            Some(format!(
+                "protocol version {} instead of {}",
+                remote.proto_version, self.proto_version
+            ))
+        } else if !remote
+            .fingerprints
+            .iter()
+            .any(|id| self.fingerprints.contains(id))
+        {
+            Some(format!(
+                "fingerprints {:?} instead of {:?}",
+                remote.fingerprints, self.fingerprints
+            ))
+        } else if remote.value_schema_hash != self.value_schema_hash && !schema_tags {
+            Some(format!(
+                "schema {} instead of {}",
+                remote.value_schema_hash, self.value_schema_hash
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    /// Sent segments without a hello, as the instances of earlier versions
+    Legacy,
+    Compatible,
+    Incompatible,
+}
+
+pub(crate) struct Handshakes {
+    peers: HashMap<SocketAddr, State>,
+}
+
+impl Handshakes {
+    pub fn new() -> Self {
+        Handshakes {
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Record the outcome of the hello received from the peer.
+    ///
+    /// Return whether it changed, in which case the peer is sent a hello in return, even if it
+    /// did not open a session.
+    pub fn record(&mut self, peer: SocketAddr, compatible: bool) -> bool {
+        let state = if compatible {
+            State::Compatible
+        } else {
+            State::Incompatible
+        };
+        self.peers.insert(peer, state) != Some(state)
+    }
+
+    /// Whether the peer sent a hello.
+    pub fn greeted(&self, peer: SocketAddr) -> bool {
+        matches!(
+            self.peers.get(&peer),
+            Some(State::Compatible | State::Incompatible)
+        )
+    }
+
+    pub fn is_incompatible(&self, peer: SocketAddr) -> bool {
+        self.peers.get(&peer) == Some(&State::Incompatible)
+    }
+
+    /// Record segments received from the peer.
+    ///
+    /// Return whether it is the first time the peer sends segments without a hello.
+    pub fn legacy(&mut self, peer: SocketAddr) -> bool {
+        if self.peers.contains_key(&peer) {
+            return false;
+        }
+        self.peers.insert(peer, State::Legacy);
+        true
+    }
+
+    /// Forget the peers not in the list.
+    pub fn retain_peers(&mut self, peers: &[SocketAddr]) {
+        self.peers.retain(|peer, _| peers.contains(peer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Handshakes, Hello};
+
+    #[test]
+    fn handshakes() {
+        let hello = Hello {
+            proto_version: 2,
+            fingerprints: vec![0, 1],
+            value_schema_hash: 42,
+            len: 10,
+            root_hash: "123".to_string(),
+        };
+        // the size and hash of the maps do not matter, nor the strongest fingerprint
+        let remote = Hello {
+            fingerprints: vec![0],
+            len: 0,
+            root_hash: "0".to_string(),
+            ..hello.clone()
+        };
+        assert_eq!(hello.incompatibility(&remote, false), None);
+        let others = [
+            Hello {
+                proto_version: 3,
+                ..hello.clone()
+            },
+            Hello {
+                fingerprints: vec![2],
+                ..hello.clone()
+            },
+            Hello {
+                value_schema_hash: 0,
+                ..hello.clone()
+            },
+        ];
+        for remote in &others {
+            assert!(hello.incompatibility(remote, false).is_some());
+        }
+        // only the schema may differ when the updates are tagged with it
+        assert!(hello.incompatibility(&others[0], true).is_some());
+        assert_eq!(hello.incompatibility(&others[2], true), None);
+
+        let peer = "10.0.0.1:8080".parse().unwrap();
+        let mut handshakes = Handshakes::new();
+        assert!(handshakes.legacy(peer));
+        assert!(!handshakes.legacy(peer));
+        assert!(!handshakes.greeted(peer));
+        // a peer is answered once, unless it changes
+        assert!(handshakes.record(peer, true));
+        assert!(!handshakes.record(peer, true));
+        assert!(handshakes.greeted(peer));
+        assert!(!handshakes.is_incompatible(peer));
+        assert!(handshakes.record(peer, false));
+        assert!(handshakes.is_incompatible(peer));
+        assert!(!handshakes.legacy(peer));
+        handshakes.retain_peers(&[]);
+        assert!(!handshakes.greeted(peer));
+    }
+}
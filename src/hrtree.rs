@@ -21,83 +21,141 @@
 //! [`HRTree`] implements the [`Diffable`](crate::diff::Diffable)
 //! and [`HashRangeQueryable`] traits.
 
+use std::borrow::Borrow;
 use std::cmp::Ordering;
-use std::collections::hash_map::DefaultHasher;
-use std::hash::{Hash, Hasher};
+use std::hash::Hash;
+use std::iter::FusedIterator;
+use std::marker::PhantomData;
 use std::ops::{Bound, RangeBounds};
+use std::sync::Arc;
 
 use arrayvec::ArrayVec;
 use range_cmp::{RangeComparable, RangeOrdering};
+use serde::de::{Error, SeqAccess, Visitor};
+use serde::ser::SerializeSeq;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use tracing::trace;
 
-use crate::diff::HashRangeQueryable;
+use crate::diff::{self, DiffRange, HashRangeQueryable, HashSegment};
+use crate::fingerprint::{
+    canonical_hash, DefaultFingerprint, DualFingerprint, FingerprintStrategy,
+};
 
+/// Hash of a key-value pair with the [`DefaultFingerprint`].
 pub fn hash<K: Hash, V: Hash>(key: &K, value: &V) -> u64 {
-    let mut hasher = DefaultHasher::new();
-    key.hash(&mut hasher);
-    value.hash(&mut hasher);
-    hasher.finish()
+    DefaultFingerprint::hash(key, value)
 }
 
-const B: usize = 6;
-const MIN_CAPACITY: usize = B - 1;
-const MAX_CAPACITY: usize = 2 * B - 1;
-
-type InsertionTuple<K, V> = Option<(K, V, u64, Box<Node<K, V>>)>;
+/// Default branching parameter of the tree: the nodes other than the root hold between `B - 1`
+/// and `2 * B - 1` key-value pairs, and the internal nodes have one more child than key-value
+/// pairs.
+///
+/// The last parameter of [`HRTree`] is the maximum number of children of a node, `2 * B`, and
+/// can be changed to tune the tree (see [`HRTree`]).
+pub const B: usize = 6;
+
+type InsertionTuple<K, V, F, const N: usize> = Option<(
+    K,
+    V,
+    <F as FingerprintStrategy>::Output,
+    Arc<Node<K, V, F, N>>,
+)>;
+
+/// Hash of a key-value pair replacing the one of the fingerprint strategy, see
+/// [`HRTree::with_canonical_hashing`]
+type ElementHasher<K, V, F> = fn(&K, &V) -> <F as FingerprintStrategy>::Output;
+
+/// Hash of a key-value pair, with the given hasher if any, or the fingerprint strategy otherwise.
+fn element_hash<F: FingerprintStrategy, K: Hash, V: Hash>(
+    hasher: Option<ElementHasher<K, V, F>>,
+    key: &K,
+    value: &V,
+) -> F::Output {
+    match hasher {
+        Some(hasher) => hasher(key, value),
+        None => F::hash(key, value),
+    }
+}
 
-#[derive(Debug, Default)]
-struct Node<K, V> {
-    keys: ArrayVec<K, MAX_CAPACITY>,
-    values: ArrayVec<V, MAX_CAPACITY>,
-    hashes: ArrayVec<u64, MAX_CAPACITY>,
-    children: Option<ArrayVec<Box<Node<K, V>>, { MAX_CAPACITY + 1 }>>,
-    tree_hash: u64,
+/// Node of the tree; the children are shared between the clones of a tree, and copied on write.
+///
+/// The arrays have room for `N` items, but only the children use the last one.
+struct Node<K, V, F: FingerprintStrategy, const N: usize> {
+    keys: ArrayVec<K, N>,
+    values: ArrayVec<V, N>,
+    hashes: ArrayVec<F::Output, N>,
+    children: Option<ArrayVec<Arc<Node<K, V, F, N>>, N>>,
+    tree_hash: F::Output,
     tree_size: usize,
 }
 
-impl<K, V> Node<K, V> {
+/// Only copies the node itself: the children are shared.
+impl<K: Clone, V: Clone, F: FingerprintStrategy, const N: usize> Clone for Node<K, V, F, N> {
+    fn clone(&self) -> Self {
+        Node {
+            keys: self.keys.clone(),
+            values: self.values.clone(),
+            hashes: self.hashes.clone(),
+            children: self.children.clone(),
+            tree_hash: self.tree_hash,
+            tree_size: self.tree_size,
+        }
+    }
+}
+
+impl<K, V, F: FingerprintStrategy, const N: usize> Node<K, V, F, N> {
+    const MIN_CAPACITY: usize = N / 2 - 1;
+    const MAX_CAPACITY: usize = N - 1;
+    const VALID_ORDER: () = assert!(
+        N >= 4 && N.is_multiple_of(2),
+        "the nodes must have an even number of children, at least 4"
+    );
+
     fn new() -> Self {
+        let () = Self::VALID_ORDER;
         Node {
             keys: ArrayVec::new(),
             values: ArrayVec::new(),
             hashes: ArrayVec::new(),
             children: None,
-            tree_hash: 0,
+            tree_hash: F::identity(),
             tree_size: 0,
         }
     }
 
     fn refresh_hash_size(&mut self) {
-        let mut cum_hash = 0;
-        for hash in self.hashes.iter() {
-            cum_hash ^= hash;
+        let mut cum_hash = F::identity();
+        for &hash in self.hashes.iter() {
+            cum_hash = F::combine(cum_hash, hash);
         }
         let mut tot_size = self.keys.len();
         if let Some(children) = self.children.as_ref() {
             for child in children {
-                cum_hash ^= child.tree_hash;
+                cum_hash = F::combine(cum_hash, child.tree_hash);
                 tot_size += child.tree_size;
             }
         }
         self.tree_hash = cum_hash;
         self.tree_size = tot_size;
     }
+}
 
+impl<K: Clone, V: Clone, F: FingerprintStrategy, const N: usize> Node<K, V, F, N> {
     fn insert(
         &mut self,
         index: usize,
         key: K,
         value: V,
-        hash: u64,
-        right_child: Option<Box<Node<K, V>>>,
-        diff_hash: u64,
-    ) -> InsertionTuple<K, V> {
+        hash: F::Output,
+        right_child: Option<Arc<Node<K, V, F, N>>>,
+        diff_hash: F::Output,
+    ) -> InsertionTuple<K, V, F, N> {
         assert_eq!(self.children.is_none(), right_child.is_none());
-        if self.keys.is_full() {
+        if self.keys.len() == Self::MAX_CAPACITY {
             // TODO: handle case where self.keys.len() == 2 without leaving empty node
             let mid = self.keys.len() / 2;
             // split
-            let mut right_sibling = Box::new(Node {
+            let mut right_sibling = Node {
                 keys: ArrayVec::from_iter(self.keys.drain(mid + 1..)),
                 values: ArrayVec::from_iter(self.values.drain(mid + 1..)),
                 hashes: ArrayVec::from_iter(self.hashes.drain(mid + 1..)),
@@ -105,9 +163,9 @@ impl<K, V> Node<K, V> {
                     .children
                     .as_mut()
                     .map(|children| ArrayVec::from_iter(children.drain(mid + 1..))),
-                tree_hash: 0,
+                tree_hash: F::identity(),
                 tree_size: 0,
-            });
+            };
             let mid_key = self.keys.pop().unwrap();
             let mid_value = self.values.pop().unwrap();
             let mid_hash = self.hashes.pop().unwrap();
@@ -123,14 +181,14 @@ impl<K, V> Node<K, V> {
             // update invariants
             self.refresh_hash_size();
             right_sibling.refresh_hash_size();
-            Some((mid_key, mid_value, mid_hash, right_sibling))
+            Some((mid_key, mid_value, mid_hash, Arc::new(right_sibling)))
         } else {
             // just insert
             self.keys.insert(index, key);
             self.values.insert(index, value);
             self.hashes.insert(index, hash);
             self.tree_size += 1;
-            self.tree_hash ^= diff_hash;
+            self.tree_hash = F::combine(self.tree_hash, diff_hash);
             if let Some(right_child) = right_child {
                 assert!(self.children.is_some());
                 self.children
@@ -142,200 +200,655 @@ impl<K, V> Node<K, V> {
         }
     }
 
+    /// Remove the rightmost element of the sub-tree, restoring the invariants on the way up.
+    fn pop_last(&mut self) -> (K, V, F::Output) {
+        if let Some(children) = self.children.as_mut() {
+            let (k, v, h) = Arc::make_mut(children.last_mut().unwrap()).pop_last();
+            self.tree_size -= 1;
+            self.tree_hash = F::remove(self.tree_hash, h);
+            self.rebalance_after_deletion(self.keys.len());
+            (k, v, h)
+        } else {
+            let k = self.keys.pop().unwrap();
+            let v = self.values.pop().unwrap();
+            let h = self.hashes.pop().unwrap();
+            self.tree_size -= 1;
+            self.tree_hash = F::remove(self.tree_hash, h);
+            (k, v, h)
+        }
+    }
+
+    fn pop_first(&mut self) -> (K, V, F::Output) {
+        if let Some(children) = self.children.as_mut() {
+            let (k, v, h) = Arc::make_mut(&mut children[0]).pop_first();
+            self.tree_size -= 1;
+            self.tree_hash = F::remove(self.tree_hash, h);
+            self.rebalance_after_deletion(0);
+            (k, v, h)
+        } else {
+            let k = self.keys.remove(0);
+            let v = self.values.remove(0);
+            let h = self.hashes.remove(0);
+            self.tree_size -= 1;
+            self.tree_hash = F::remove(self.tree_hash, h);
+            (k, v, h)
+        }
+    }
+
     fn rebalance_after_deletion(&mut self, index: usize) {
-        let children = self.children.as_mut().unwrap();
-        if children[index].keys.len() >= MIN_CAPACITY {
-            // nothing to do
-            return;
-        }
-        // need to restore minimum node size invariant
-        if index > 0 && children[index - 1].keys.len() > MIN_CAPACITY {
-            // steal left, rotate right
-            // take last separator (k, v, h) from left sibling
-            let left_sibling = children[index - 1].as_mut();
-            let k = left_sibling.keys.pop().unwrap();
-            let v = left_sibling.values.pop().unwrap();
-            let h = left_sibling.hashes.pop().unwrap();
-            left_sibling.tree_size -= 1;
-            left_sibling.tree_hash ^= h;
-            // take last child from left sibling if any
-            let c = left_sibling.children.as_mut().map(|children| {
-                let c = children.pop().unwrap();
-                left_sibling.tree_size -= c.tree_size;
-                left_sibling.tree_hash ^= c.tree_hash;
-                c
-            });
-            // NOTE: separator (k, v, h) is left of child c
-            // exchange sibling's separator with parent's separator
-            let k = std::mem::replace(&mut self.keys[index - 1], k);
-            let v = std::mem::replace(&mut self.values[index - 1], v);
-            let h = std::mem::replace(&mut self.hashes[index - 1], h);
-            // NOTE: separator (k, v, h) is now right of child c
-            // move separator (k, v, h) in current node
-            let current = children[index].as_mut();
-            current.keys.insert(0, k);
-            current.values.insert(0, v);
-            current.hashes.insert(0, h);
-            current.tree_size += 1;
-            current.tree_hash ^= h;
-            // move child c in current node if any
-            if let Some(c) = c {
-                current.tree_size += c.tree_size;
-                current.tree_hash ^= c.tree_hash;
-                current.children.as_mut().unwrap().insert(0, c);
-            }
-        } else if index + 1 < children.len() && children[index + 1].keys.len() > MIN_CAPACITY {
-            // steal right, rotate left
-            // take first separator (k, v, h) from right sibling
-            let right_sibling = children[index + 1].as_mut();
-            let k = right_sibling.keys.remove(0);
-            let v = right_sibling.values.remove(0);
-            let h = right_sibling.hashes.remove(0);
-            right_sibling.tree_size -= 1;
-            right_sibling.tree_hash ^= h;
-            // take first child from right sibling if any
-            let c = right_sibling.children.as_mut().map(|children| {
-                let c = children.remove(0);
-                right_sibling.tree_size -= c.tree_size;
-                right_sibling.tree_hash ^= c.tree_hash;
-                c
-            });
-            // NOTE: separator (k, v, h) is right of child c
-            // exchange (k, v, h) with separator
-            let k = std::mem::replace(&mut self.keys[index], k);
-            let v = std::mem::replace(&mut self.values[index], v);
-            let h = std::mem::replace(&mut self.hashes[index], h);
-            // NOTE: separator (k, v, h) is now left of child c
-            // move separator (k, v, h) in current node
-            let current = children[index].as_mut();
-            current.keys.push(k);
-            current.values.push(v);
-            current.hashes.push(h);
-            current.tree_size += 1;
-            current.tree_hash ^= h;
-            // move child c in current node if any
-            if let Some(c) = c {
-                current.tree_size += c.tree_size;
-                current.tree_hash ^= c.tree_hash;
-                current.children.as_mut().unwrap().push(c);
+        // NOTE: a single iteration is enough after removing a single element from the child, but
+        // several are needed when the child lost many elements at once (see `remove_range`)
+        loop {
+            let children = self.children.as_mut().unwrap();
+            if children[index].keys.len() >= Self::MIN_CAPACITY {
+                // nothing to do
+                return;
             }
-        } else {
-            let merge_into = if index > 0 {
-                index - 1
-            } else if index + 1 < children.len() {
-                index
+            // need to restore minimum node size invariant
+            if index > 0 && children[index - 1].keys.len() > Self::MIN_CAPACITY {
+                // steal left, rotate right
+                // take last separator (k, v, h) from left sibling
+                let left_sibling = Arc::make_mut(&mut children[index - 1]);
+                let k = left_sibling.keys.pop().unwrap();
+                let v = left_sibling.values.pop().unwrap();
+                let h = left_sibling.hashes.pop().unwrap();
+                left_sibling.tree_size -= 1;
+                left_sibling.tree_hash = F::remove(left_sibling.tree_hash, h);
+                // take last child from left sibling if any
+                let c = left_sibling.children.as_mut().map(|children| {
+                    let c = children.pop().unwrap();
+                    left_sibling.tree_size -= c.tree_size;
+                    left_sibling.tree_hash = F::remove(left_sibling.tree_hash, c.tree_hash);
+                    c
+                });
+                // NOTE: separator (k, v, h) is left of child c
+                // exchange sibling's separator with parent's separator
+                let k = std::mem::replace(&mut self.keys[index - 1], k);
+                let v = std::mem::replace(&mut self.values[index - 1], v);
+                let h = std::mem::replace(&mut self.hashes[index - 1], h);
+                // NOTE: separator (k, v, h) is now right of child c
+                // move separator (k, v, h) in current node
+                let current = Arc::make_mut(&mut children[index]);
+                current.keys.insert(0, k);
+                current.values.insert(0, v);
+                current.hashes.insert(0, h);
+                current.tree_size += 1;
+                current.tree_hash = F::combine(current.tree_hash, h);
+                // move child c in current node if any
+                if let Some(c) = c {
+                    current.tree_size += c.tree_size;
+                    current.tree_hash = F::combine(current.tree_hash, c.tree_hash);
+                    current.children.as_mut().unwrap().insert(0, c);
+                }
+            } else if index + 1 < children.len()
+                && children[index + 1].keys.len() > Self::MIN_CAPACITY
+            {
+                // steal right, rotate left
+                // take first separator (k, v, h) from right sibling
+                let right_sibling = Arc::make_mut(&mut children[index + 1]);
+                let k = right_sibling.keys.remove(0);
+                let v = right_sibling.values.remove(0);
+                let h = right_sibling.hashes.remove(0);
+                right_sibling.tree_size -= 1;
+                right_sibling.tree_hash = F::remove(right_sibling.tree_hash, h);
+                // take first child from right sibling if any
+                let c = right_sibling.children.as_mut().map(|children| {
+                    let c = children.remove(0);
+                    right_sibling.tree_size -= c.tree_size;
+                    right_sibling.tree_hash = F::remove(right_sibling.tree_hash, c.tree_hash);
+                    c
+                });
+                // NOTE: separator (k, v, h) is right of child c
+                // exchange (k, v, h) with separator
+                let k = std::mem::replace(&mut self.keys[index], k);
+                let v = std::mem::replace(&mut self.values[index], v);
+                let h = std::mem::replace(&mut self.hashes[index], h);
+                // NOTE: separator (k, v, h) is now left of child c
+                // move separator (k, v, h) in current node
+                let current = Arc::make_mut(&mut children[index]);
+                current.keys.push(k);
+                current.values.push(v);
+                current.hashes.push(h);
+                current.tree_size += 1;
+                current.tree_hash = F::combine(current.tree_hash, h);
+                // move child c in current node if any
+                if let Some(c) = c {
+                    current.tree_size += c.tree_size;
+                    current.tree_hash = F::combine(current.tree_hash, c.tree_hash);
+                    current.children.as_mut().unwrap().push(c);
+                }
             } else {
-                // root node, nothing to do
-                return;
-            };
-
-            // merge right sibling in the current node
-            let right_sibling = children.remove(merge_into + 1);
-            let current = children[merge_into].as_mut();
-            // move separator in current node
-            let k = self.keys.remove(merge_into);
-            let v = self.values.remove(merge_into);
-            let h = self.hashes.remove(merge_into);
-            current.keys.push(k);
-            current.values.push(v);
-            current.hashes.push(h);
-            current.tree_size += 1;
-            current.tree_hash ^= h;
-            // move values of right_sibling in current node
-            for k in right_sibling.keys {
+                let merge_into = if index > 0 {
+                    index - 1
+                } else if index + 1 < children.len() {
+                    index
+                } else {
+                    // root node, nothing to do
+                    return;
+                };
+
+                // merge right sibling in the current node
+                let right_sibling = Arc::unwrap_or_clone(children.remove(merge_into + 1));
+                let current = Arc::make_mut(&mut children[merge_into]);
+                // move separator in current node
+                let k = self.keys.remove(merge_into);
+                let v = self.values.remove(merge_into);
+                let h = self.hashes.remove(merge_into);
                 current.keys.push(k);
-            }
-            for v in right_sibling.values {
                 current.values.push(v);
-            }
-            for h in right_sibling.hashes {
                 current.hashes.push(h);
+                current.tree_size += 1;
+                current.tree_hash = F::combine(current.tree_hash, h);
+                // move values of right_sibling in current node
+                for k in right_sibling.keys {
+                    current.keys.push(k);
+                }
+                for v in right_sibling.values {
+                    current.values.push(v);
+                }
+                for h in right_sibling.hashes {
+                    current.hashes.push(h);
+                }
+                if let Some(child_children) = current.children.as_mut() {
+                    for c in right_sibling.children.unwrap() {
+                        child_children.push(c);
+                    }
+                }
+                current.tree_size += right_sibling.tree_size;
+                current.tree_hash = F::combine(current.tree_hash, right_sibling.tree_hash);
+                return;
+            }
+        }
+    }
+}
+
+/// A sub-tree along with its height, used when splitting and joining trees.
+///
+/// Only the root of the sub-tree is allowed to break the minimum node size invariant.
+type SubTree<K, V, F, const N: usize> = (Arc<Node<K, V, F, N>>, usize);
+
+fn height<K, V, F: FingerprintStrategy, const N: usize>(node: &Node<K, V, F, N>) -> usize {
+    match node.children.as_ref() {
+        Some(children) => 1 + height(&children[0]),
+        None => 1,
+    }
+}
+
+/// Remove the internal roots without any key, which have a single child.
+fn collapse<K, V, F: FingerprintStrategy, const N: usize>(
+    (mut node, mut height): SubTree<K, V, F, N>,
+) -> SubTree<K, V, F, N> {
+    while node.keys.is_empty() && node.children.is_some() {
+        node = node.children.as_ref().unwrap()[0].clone();
+        height -= 1;
+    }
+    (node, height)
+}
+
+/// Insert the separator and the sub-tree `right` at the end of `node`, where `right` is lower
+/// than `node` by at least one level.
+fn join_right<K: Clone, V: Clone, F: FingerprintStrategy, const N: usize>(
+    node: &mut Node<K, V, F, N>,
+    height: usize,
+    (k, v, h): (K, V, F::Output),
+    right: SubTree<K, V, F, N>,
+) -> InsertionTuple<K, V, F, N> {
+    let mut ret = if height == right.1 + 1 {
+        let mut ret = node.insert(node.keys.len(), k, v, h, Some(right.0), F::identity());
+        // the new child might be under-sized
+        let last = ret
+            .as_mut()
+            .map(|(_, _, _, sibling)| Arc::make_mut(sibling));
+        let last = last.unwrap_or(&mut *node);
+        last.rebalance_after_deletion(last.keys.len());
+        last.refresh_hash_size();
+        ret
+    } else {
+        let last = Arc::make_mut(node.children.as_mut().unwrap().last_mut().unwrap());
+        match join_right(last, height - 1, (k, v, h), right) {
+            Some((k, v, h, sibling)) => {
+                node.insert(node.keys.len(), k, v, h, Some(sibling), F::identity())
             }
-            if let Some(child_children) = current.children.as_mut() {
-                for c in right_sibling.children.unwrap() {
-                    child_children.push(c);
+            None => None,
+        }
+    };
+    node.refresh_hash_size();
+    if let Some((_, _, _, sibling)) = ret.as_mut() {
+        Arc::make_mut(sibling).refresh_hash_size();
+    }
+    ret
+}
+
+/// Insert the sub-tree `left` and the separator at the beginning of `node`, where `left` is
+/// lower than `node` by at least one level.
+fn join_left<K: Clone, V: Clone, F: FingerprintStrategy, const N: usize>(
+    node: &mut Node<K, V, F, N>,
+    height: usize,
+    left: SubTree<K, V, F, N>,
+    (k, v, h): (K, V, F::Output),
+) -> InsertionTuple<K, V, F, N> {
+    let ret = if height == left.1 + 1 {
+        // NOTE: the new element is inserted in `node`, even if `node` is split
+        let ret = node.insert(0, k, v, h, Some(left.0), F::identity());
+        node.children.as_mut().unwrap().swap(0, 1);
+        // the new child might be under-sized
+        node.rebalance_after_deletion(0);
+        ret
+    } else {
+        let first = Arc::make_mut(node.children.as_mut().unwrap().first_mut().unwrap());
+        match join_left(first, height - 1, left, (k, v, h)) {
+            Some((k, v, h, sibling)) => node.insert(0, k, v, h, Some(sibling), F::identity()),
+            None => None,
+        }
+    };
+    node.refresh_hash_size();
+    ret
+}
+
+/// Build the sub-tree containing the elements of `left`, then the separator, then the elements
+/// of `right`.
+fn join<K: Clone, V: Clone, F: FingerprintStrategy, const N: usize>(
+    left: SubTree<K, V, F, N>,
+    separator: (K, V, F::Output),
+    right: SubTree<K, V, F, N>,
+) -> SubTree<K, V, F, N> {
+    let (mut left, left_height) = collapse(left);
+    let (mut right, right_height) = collapse(right);
+    let (mut root, height, to_insert) = match left_height.cmp(&right_height) {
+        Ordering::Equal => {
+            if left.keys.len() + 1 + right.keys.len() <= Node::<K, V, F, N>::MAX_CAPACITY {
+                // merge everything in a single node
+                let node = Arc::make_mut(&mut left);
+                let (k, v, h) = separator;
+                node.keys.push(k);
+                node.values.push(v);
+                node.hashes.push(h);
+                let Node {
+                    keys,
+                    values,
+                    hashes,
+                    children,
+                    ..
+                } = Arc::unwrap_or_clone(right);
+                node.keys.extend(keys);
+                node.values.extend(values);
+                node.hashes.extend(hashes);
+                if let (Some(left_children), Some(children)) = (node.children.as_mut(), children) {
+                    left_children.extend(children);
                 }
+                node.refresh_hash_size();
+                return (left, left_height);
+            }
+            let (k, v, h) = separator;
+            (left, left_height, Some((k, v, h, right)))
+        }
+        Ordering::Greater => {
+            let node = Arc::make_mut(&mut left);
+            let to_insert = join_right(node, left_height, separator, (right, right_height));
+            (left, left_height, to_insert)
+        }
+        Ordering::Less => {
+            let node = Arc::make_mut(&mut right);
+            let to_insert = join_left(node, right_height, (left, left_height), separator);
+            (right, right_height, to_insert)
+        }
+    };
+    // if we still have things to insert at the root, we need to create a new root
+    if let Some((k, v, h, sibling)) = to_insert {
+        let mut children = ArrayVec::new();
+        children.push(root);
+        children.push(sibling);
+        let mut new_root = Node::new();
+        new_root.keys.push(k);
+        new_root.values.push(v);
+        new_root.hashes.push(h);
+        new_root.children = Some(children);
+        // the former roots might be under-sized
+        new_root.rebalance_after_deletion(0);
+        new_root.rebalance_after_deletion(1);
+        new_root.refresh_hash_size();
+        root = Arc::new(new_root);
+        return (root, height + 1);
+    }
+    (root, height)
+}
+
+/// Build the sub-tree containing the elements of `left`, then the elements of `right`.
+fn concat<K: Clone, V: Clone, F: FingerprintStrategy, const N: usize>(
+    left: SubTree<K, V, F, N>,
+    right: SubTree<K, V, F, N>,
+) -> SubTree<K, V, F, N> {
+    let (mut left, left_height) = collapse(left);
+    if left.keys.is_empty() {
+        return right;
+    }
+    let separator = Arc::make_mut(&mut left).pop_last();
+    join((left, left_height), separator, right)
+}
+
+/// Split the sub-tree in two: the elements whose keys satisfy the predicate, and the others.
+///
+/// The predicate must be monotonic: if it is true for a key, it must be true for all the keys
+/// before it.
+fn split<K: Clone, V: Clone, F: FingerprintStrategy, const N: usize, P: Fn(&K) -> bool>(
+    (mut node, height): SubTree<K, V, F, N>,
+    goes_left: &P,
+) -> (SubTree<K, V, F, N>, SubTree<K, V, F, N>) {
+    let left = Arc::make_mut(&mut node);
+    let index = left.keys.partition_point(goes_left);
+    let mut right = Node {
+        keys: left.keys.drain(index..).collect(),
+        values: left.values.drain(index..).collect(),
+        hashes: left.hashes.drain(index..).collect(),
+        children: None,
+        tree_hash: F::identity(),
+        tree_size: 0,
+    };
+    let Some(children) = left.children.as_mut() else {
+        // leaf
+        left.refresh_hash_size();
+        right.refresh_hash_size();
+        return ((node, height), (Arc::new(right), height));
+    };
+    // internal node: the child at `index` contains keys on both sides
+    right.children = Some(children.drain(index + 1..).collect());
+    let middle = children.pop().unwrap();
+    let (middle_left, middle_right) = split((middle, height - 1), goes_left);
+    let left = if let (Some(k), Some(v), Some(h)) =
+        (left.keys.pop(), left.values.pop(), left.hashes.pop())
+    {
+        left.refresh_hash_size();
+        join((node, height), (k, v, h), middle_left)
+    } else {
+        middle_left
+    };
+    let right = if right.keys.is_empty() {
+        middle_right
+    } else {
+        let k = right.keys.remove(0);
+        let v = right.values.remove(0);
+        let h = right.hashes.remove(0);
+        right.refresh_hash_size();
+        join(middle_right, (k, v, h), (Arc::new(right), height))
+    };
+    (left, right)
+}
+
+/// Maximum number of elements in a sub-tree of the given height.
+fn max_tree_size<const N: usize>(height: usize) -> usize {
+    let mut size = N - 1;
+    for _ in 1..height {
+        size = (N - 1).saturating_add(N.saturating_mul(size));
+    }
+    size
+}
+
+/// Build a sub-tree of the given height from the next `size` items, which must be sorted.
+///
+/// The elements are spread evenly over the smallest number of children that can hold them, so
+/// that leaves are nearly full. Non-root nodes always get enough children to satisfy the minimum
+/// node size invariant.
+fn bulk_load<
+    K: Hash,
+    V: Hash,
+    F: FingerprintStrategy,
+    const N: usize,
+    I: Iterator<Item = (K, V)>,
+>(
+    items: &mut I,
+    size: usize,
+    height: usize,
+    is_root: bool,
+    hasher: Option<ElementHasher<K, V, F>>,
+) -> Arc<Node<K, V, F, N>> {
+    let mut node = Node::new();
+    if height == 1 {
+        for (key, value) in items.by_ref().take(size) {
+            node.hashes
+                .push(element_hash::<F, _, _>(hasher, &key, &value));
+            node.keys.push(key);
+            node.values.push(value);
+        }
+    } else {
+        let child_capacity = max_tree_size::<N>(height - 1);
+        let mut children_count = (size + 1).div_ceil(child_capacity + 1);
+        if !is_root {
+            children_count = children_count.max(N / 2);
+        }
+        let children_items = size - (children_count - 1);
+        let mut children = ArrayVec::new();
+        for i in 0..children_count {
+            let child_size =
+                children_items / children_count + usize::from(i < children_items % children_count);
+            children.push(bulk_load(items, child_size, height - 1, false, hasher));
+            if i + 1 < children_count {
+                let (key, value) = items.next().unwrap();
+                node.hashes
+                    .push(element_hash::<F, _, _>(hasher, &key, &value));
+                node.keys.push(key);
+                node.values.push(value);
             }
-            current.tree_size += right_sibling.tree_size;
-            current.tree_hash ^= right_sibling.tree_hash;
         }
+        node.children = Some(children);
+    }
+    node.refresh_hash_size();
+    Arc::new(node)
+}
+
+/// Build the root of a tree from key-value pairs sorted by key, without duplicates.
+fn load_sorted<K: Hash, V: Hash, F: FingerprintStrategy, const N: usize>(
+    items: Vec<(K, V)>,
+    hasher: Option<ElementHasher<K, V, F>>,
+) -> Arc<Node<K, V, F, N>> {
+    let mut height = 1;
+    while max_tree_size::<N>(height) < items.len() {
+        height += 1;
     }
+    let size = items.len();
+    bulk_load(&mut items.into_iter(), size, height, true, hasher)
 }
 
-pub struct HRTree<K, V> {
-    root: Box<Node<K, V>>,
+/// Key-value map sorted by key, which also maintains the cumulated hash of its sub-trees.
+///
+/// Cloning a tree is cheap: the nodes are shared between the clones, and only copied when one of
+/// them modifies them, along the path from the root to the modification.
+///
+/// `N` is the maximum number of children of a node, `2 * B` by default (see [`B`]); it must be
+/// even and at least 4. Smaller nodes copy less on write, which makes the updates of a shared
+/// tree cheaper, while larger ones make the tree shallower and the lookups and range hashes
+/// faster. Peers using different node sizes still reconcile with each other.
+///
+/// The ordering of `K` must be a total order consistent with its equality (`a == b` exactly when
+/// `a.cmp(b)` is [`Equal`](std::cmp::Ordering::Equal)), and equal keys must have the same hash.
+/// A key type breaking this is not detected: lookups miss existing keys, and peers disagree on
+/// the hashes of their ranges forever. [`with_validation`](HRTree::with_validation) checks the
+/// order around each insertion and removal, to find such a key type in tests.
+///
+/// The hash of each element is the one of the fingerprint strategy `F`, which relies on the
+/// [`Hash`] implementations of `K` and `V`. Types that compare equal across peers but hash
+/// differently, such as a `String` and a newtype around it with its own [`Hash`], never converge;
+/// [`with_canonical_hashing`](HRTree::with_canonical_hashing) hashes their serialized form instead.
+pub struct HRTree<K, V, F: FingerprintStrategy = DefaultFingerprint, const N: usize = { 2 * B }> {
+    root: Arc<Node<K, V, F, N>>,
+    /// Whether the order of the keys is checked along the path of each update
+    validate: bool,
+    /// Hash of the elements, when it is not the one of `F`
+    hasher: Option<ElementHasher<K, V, F>>,
 }
 
-impl<K, V> Default for HRTree<K, V> {
+impl<K, V, F: FingerprintStrategy, const N: usize> Default for HRTree<K, V, F, N> {
     fn default() -> Self {
         HRTree {
-            root: Box::new(Node::new()),
+            root: Arc::new(Node::new()),
+            validate: false,
+            hasher: None,
+        }
+    }
+}
+
+impl<K: Clone, V: Clone, F: FingerprintStrategy, const N: usize> Clone for HRTree<K, V, F, N> {
+    fn clone(&self) -> Self {
+        HRTree {
+            root: self.root.clone(),
+            validate: self.validate,
+            hasher: self.hasher,
         }
     }
 }
 
 impl<K: Hash + Ord, V: Hash> HRTree<K, V> {
+    /// Create an empty tree using the [`DefaultFingerprint`].
+    ///
+    /// Use [`HRTree::default`] to create a tree with another fingerprint strategy.
     pub fn new() -> Self {
         Default::default()
     }
+}
 
-    pub fn get<'a>(&'a self, key: &K) -> Option<&'a V> {
-        fn aux<'a, K: Ord, V>(node: &'a Node<K, V>, key: &K) -> Option<&'a V> {
-            match node.keys.binary_search(key) {
-                Ok(index) => Some(&node.values[index]),
-                Err(index) => {
-                    if let Some(children) = node.children.as_ref() {
-                        aux(children[index].as_ref(), key)
-                    } else {
-                        None
-                    }
-                }
+impl<K: Hash + Ord, V: Hash, F: FingerprintStrategy, const N: usize> HRTree<K, V, F, N> {
+    /// Build a tree from key-value pairs sorted by key, without inserting them one by one.
+    ///
+    /// When a key appears several times, the last value is kept. The order is not checked:
+    /// unsorted items result in an invalid tree.
+    pub fn from_sorted_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        let mut items: Vec<(K, V)> = Vec::new();
+        for (key, value) in iter {
+            match items.last_mut() {
+                Some(last) if last.0 == key => last.1 = value,
+                _ => items.push((key, value)),
             }
         }
-        aux(self.root.as_ref(), key)
+        HRTree {
+            root: load_sorted(items, None),
+            validate: false,
+            hasher: None,
+        }
     }
 
-    pub fn get_mut<F: FnOnce(Option<&mut V>)>(&mut self, key: &K, callback: F) {
-        fn aux<K: Hash + Ord, V: Hash, F: FnOnce(Option<&mut V>)>(
-            node: &mut Node<K, V>,
+    /// Get the value associated with the given key, if it exists.
+    ///
+    /// As with [`BTreeMap::get`](std::collections::BTreeMap::get), the key may be any borrowed
+    /// form of `K`, as long as its ordering matches the one of `K`. Note that tuples cannot be
+    /// borrowed by parts: a `(String, u128)` key is not looked up with a `(&str, u128)`, and the
+    /// keys sharing a prefix are the range `(prefix.clone(), 0)..=(prefix, u128::MAX)`.
+    pub fn get<'a, Q: Ord + ?Sized>(&'a self, key: &Q) -> Option<&'a V>
+    where
+        K: Borrow<Q>,
+    {
+        self.find(key).map(|(node, index)| &node.values[index])
+    }
+
+    /// Whether the tree contains an element with the given key.
+    pub fn contains_key<Q: Ord + ?Sized>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        self.find(key).is_some()
+    }
+
+    /// Hash of the element with the given key, if it exists, as combined in the hash of the tree.
+    ///
+    /// This is the hash stored along with the element, so that it is cheaper to compare it to the
+    /// hash of another value than the values themselves.
+    pub fn hash_of<Q: Ord + ?Sized>(&self, key: &Q) -> Option<F::Output>
+    where
+        K: Borrow<Q>,
+    {
+        self.find(key).map(|(node, index)| node.hashes[index])
+    }
+
+    /// Hash of the whole tree, as returned by `hash(&..)`, but read from the root without any
+    /// traversal.
+    pub fn root_hash(&self) -> F::Output {
+        self.root.tree_hash
+    }
+
+    /// Node holding the given key, and its position in the node.
+    fn find<Q: Ord + ?Sized>(&self, key: &Q) -> Option<(&Node<K, V, F, N>, usize)>
+    where
+        K: Borrow<Q>,
+    {
+        let mut node = self.root.as_ref();
+        loop {
+            match node.keys.binary_search_by(|k| k.borrow().cmp(key)) {
+                Ok(index) => return Some((node, index)),
+                Err(index) => node = node.children.as_ref()?[index].as_ref(),
+            }
+        }
+    }
+
+    /// Get the element with the smallest key, if any.
+    pub fn first_key_value(&self) -> Option<(&K, &V)> {
+        let mut node = self.root.as_ref();
+        while let Some(children) = node.children.as_ref() {
+            node = children.first().unwrap();
+        }
+        Some((node.keys.first()?, node.values.first()?))
+    }
+
+    /// Get the element with the largest key, if any.
+    pub fn last_key_value(&self) -> Option<(&K, &V)> {
+        let mut node = self.root.as_ref();
+        while let Some(children) = node.children.as_ref() {
+            node = children.last().unwrap();
+        }
+        Some((node.keys.last()?, node.values.last()?))
+    }
+
+    /// Number of elements whose keys are in the given range.
+    ///
+    /// This only descends the tree along the two bounds of the range.
+    pub fn range_len<R: RangeBounds<K>>(&self, range: &R) -> usize {
+        // number of keys before the given key, including it if `inclusive`
+        fn rank<K: Ord, V, F: FingerprintStrategy, const N: usize>(
+            node: &Node<K, V, F, N>,
             key: &K,
-            callback: F,
-        ) -> u64 {
-            match node.keys.binary_search(key) {
-                Ok(index) => {
-                    let v = Some(&mut node.values[index]);
-                    callback(v);
-                    // callback likely modified v, so we need to restore the hash invariants
-                    let old_hash = node.hashes[index];
-                    let new_hash = hash(key, &node.values[index]);
-                    node.hashes[index] = new_hash;
-                    let diff_hash = old_hash ^ new_hash;
-                    node.tree_hash ^= diff_hash;
-                    println!("{diff_hash}");
-                    diff_hash
+            inclusive: bool,
+        ) -> usize {
+            let index = match node.keys.binary_search(key) {
+                Ok(index) if inclusive => {
+                    return index
+                        + 1
+                        + node.children.as_ref().map_or(0, |children| {
+                            children[..=index].iter().map(|c| c.tree_size).sum()
+                        });
                 }
-                Err(index) => {
-                    if let Some(children) = node.children.as_mut() {
-                        let diff_hash = aux(children[index].as_mut(), key, callback);
-                        node.tree_hash ^= diff_hash;
-                        diff_hash
-                    } else {
-                        callback(None);
-                        // callback cannot change the content of the tree, no invariant to restore
-                        0
-                    }
+                Ok(index) | Err(index) => index,
+            };
+            match node.children.as_ref() {
+                Some(children) => {
+                    let before: usize = children[..index].iter().map(|c| c.tree_size).sum();
+                    index + before + rank(&children[index], key, inclusive)
                 }
+                None => index,
             }
         }
-        aux(self.root.as_mut(), key, callback);
+        let start = match range.start_bound() {
+            Bound::Unbounded => 0,
+            Bound::Included(key) => rank(&self.root, key, false),
+            Bound::Excluded(key) => rank(&self.root, key, true),
+        };
+        let end = match range.end_bound() {
+            Bound::Unbounded => self.root.tree_size,
+            Bound::Included(key) => rank(&self.root, key, true),
+            Bound::Excluded(key) => rank(&self.root, key, false),
+        };
+        end.saturating_sub(start)
     }
 
-    pub fn position(&self, key: &K) -> Option<usize> {
-        fn aux<K: Ord, V>(node: &Node<K, V>, key: &K) -> Option<usize> {
+    /// Position of the given key, if it exists; the key may be any borrowed form of `K`.
+    pub fn position<Q: Ord + ?Sized>(&self, key: &Q) -> Option<usize>
+    where
+        K: Borrow<Q>,
+    {
+        fn aux<K: Borrow<Q>, V, F: FingerprintStrategy, const N: usize, Q: Ord + ?Sized>(
+            node: &Node<K, V, F, N>,
+            key: &Q,
+        ) -> Option<usize> {
             if let Some(children) = node.children.as_ref() {
                 let mut index = 0;
                 for i in 0..node.keys.len() {
-                    let cmp = key.cmp(&node.keys[i]);
+                    let cmp = key.cmp(node.keys[i].borrow());
                     if cmp == Ordering::Less {
                         // recurse left to key
                         return aux(&children[i], key).map(|offset| index + offset);
@@ -351,144 +864,74 @@ impl<K: Hash + Ord, V: Hash> HRTree<K, V> {
                 }
                 aux(children.last().unwrap().as_ref(), key).map(|offset| index + offset)
             } else {
-                node.keys.binary_search(key).ok()
+                node.keys.binary_search_by(|k| k.borrow().cmp(key)).ok()
             }
         }
         aux(self.root.as_ref(), key)
     }
 
-    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
-        // return:
-        // - a key and node to be inserted after the current node
-        // - the hash difference
-        // - the value that was at key, if any
-        fn aux<K: Hash + Ord, V: Hash>(
-            node: &mut Node<K, V>,
-            key: K,
-            value: V,
-        ) -> (InsertionTuple<K, V>, u64, Option<V>) {
-            match node.keys.binary_search(&key) {
-                Ok(index) => {
-                    let old_hash = node.hashes[index];
-                    let new_hash = hash(&key, &value);
-                    let diff_hash = old_hash ^ new_hash;
-                    node.hashes[index] = new_hash;
-                    node.tree_hash ^= diff_hash;
-                    let ret = std::mem::replace(&mut node.values[index], value);
-                    (None, diff_hash, Some(ret))
-                }
-                Err(index) => {
-                    if let Some(children) = node.children.as_mut() {
-                        // internal node
-                        let (mut to_insert, diff_hash, ret) = aux(&mut children[index], key, value);
-                        if let Some((key, value, hash, right_child)) = to_insert {
-                            to_insert =
-                                node.insert(index, key, value, hash, Some(right_child), diff_hash)
-                        } else {
-                            if ret.is_none() {
-                                node.tree_size += 1;
-                            }
-                            node.tree_hash ^= diff_hash;
-                        }
-                        (to_insert, diff_hash, ret)
-                    } else {
-                        // leaf
-                        let hash = hash(&key, &value);
-                        let to_insert = node.insert(index, key, value, hash, None, hash);
-                        (to_insert, hash, None)
-                    }
-                }
-            }
-        }
-        let (to_insert, _, ret) = aux(&mut self.root, key, value);
-        // if we still have things to insert at the root, we need to create a new root
-        if let Some((key, value, hash, right_child)) = to_insert {
-            let new_root = Box::new(Node::new());
-            let old_root = std::mem::replace(&mut self.root, new_root);
-            let mut children = ArrayVec::new();
-            children.push(old_root);
-            children.push(right_child);
-            self.root.keys.push(key);
-            self.root.values.push(value);
-            self.root.hashes.push(hash);
-            self.root.children = Some(children);
-            self.root.refresh_hash_size();
-        }
-        trace!(
-            "Updated state after insertion; global hash is now {}",
-            self.root.tree_hash
-        );
-        ret
+    /// Check the order of the keys along the path to every inserted or removed key, and panic
+    /// when it is broken.
+    ///
+    /// This catches key types whose [`Ord`] is inconsistent with their [`Eq`], or not a total
+    /// order, at the update that exposes it rather than by a failed reconciliation much later.
+    /// The checks cost a walk from the root for each update, so this is meant for tests and debug
+    /// builds.
+    pub fn with_validation(mut self) -> Self {
+        self.validate = true;
+        self
     }
 
-    pub fn remove(&mut self, key: &K) -> Option<V> {
-        fn rightmost_child<K, V>(node: &mut Node<K, V>) -> (K, V, u64) {
-            if let Some(children) = node.children.as_mut() {
-                let (k, v, h) = rightmost_child(children.last_mut().unwrap());
-                node.tree_size -= 1;
-                node.tree_hash ^= h;
-                node.rebalance_after_deletion(node.keys.len());
-                (k, v, h)
-            } else {
-                let k = node.keys.pop().unwrap();
-                let v = node.values.pop().unwrap();
-                let h = node.hashes.pop().unwrap();
-                node.tree_size -= 1;
-                node.tree_hash ^= h;
-                (k, v, h)
+    /// Check the order of the keys in the nodes from the root to the given key.
+    fn validate_path(&self, key: &K) {
+        assert!(
+            key.cmp(key) == Ordering::Equal,
+            "key ordering is not reflexive"
+        );
+        let mut node = self.root.as_ref();
+        let mut min: Option<&K> = None;
+        let mut max: Option<&K> = None;
+        loop {
+            for (i, k) in node.keys.iter().enumerate() {
+                let previous = if i == 0 { min } else { Some(&node.keys[i - 1]) };
+                if let Some(previous) = previous {
+                    assert!(
+                        (previous == k) == (previous.cmp(k) == Ordering::Equal),
+                        "key ordering is inconsistent with key equality"
+                    );
+                    assert!(
+                        previous.cmp(k) == Ordering::Less && k.cmp(previous) == Ordering::Greater,
+                        "order invariant violated"
+                    );
+                }
             }
-        }
-        // return:
-        // - the hash diff
-        // - the value at the key that was removed, if there was one
-        fn aux<K: Ord, V>(node: &mut Node<K, V>, key: &K) -> (u64, Option<V>) {
-            match node.keys.binary_search(key) {
+            if let (Some(last), Some(max)) = (node.keys.last(), max) {
+                assert!(
+                    last.cmp(max) == Ordering::Less && max.cmp(last) == Ordering::Greater,
+                    "order invariant violated"
+                );
+            }
+            let index = match node.keys.binary_search(key) {
                 Ok(index) => {
-                    if let Some(children) = node.children.as_mut() {
-                        // internal node
-                        // we need to replace key, value hash with a new separator; we can find it
-                        // in the left or right sub-tree
-                        let (prev_k, prev_v, prev_h) = rightmost_child(&mut children[index]);
-                        node.keys[index] = prev_k;
-                        let v = std::mem::replace(&mut node.values[index], prev_v);
-                        let h = std::mem::replace(&mut node.hashes[index], prev_h);
-                        node.tree_size -= 1;
-                        node.tree_hash ^= h;
-                        node.rebalance_after_deletion(index);
-                        (h, Some(v))
-                    } else {
-                        // leaf node
-                        node.keys.remove(index);
-                        let v = node.values.remove(index);
-                        let h = node.hashes.remove(index);
-                        node.tree_size -= 1;
-                        node.tree_hash ^= h;
-                        (h, Some(v))
-                    }
-                }
-                Err(index) => {
-                    if let Some(children) = node.children.as_mut() {
-                        // internal node
-                        let (diff_hash, ret) = aux(&mut children[index], key);
-                        if ret.is_some() {
-                            node.tree_size -= 1;
-                        }
-                        node.tree_hash ^= diff_hash;
-                        node.rebalance_after_deletion(index);
-                        (diff_hash, ret)
-                    } else {
-                        // leaf node
-                        (0, None)
-                    }
+                    assert!(
+                        node.keys[index] == *key,
+                        "key ordering is inconsistent with key equality"
+                    );
+                    return;
                 }
+                Err(index) => index,
+            };
+            let Some(children) = node.children.as_ref() else {
+                return;
+            };
+            if index > 0 {
+                min = Some(&node.keys[index - 1]);
             }
+            if index < node.keys.len() {
+                max = Some(&node.keys[index]);
+            }
+            node = children[index].as_ref();
         }
-        let ret = aux(&mut self.root, key).1;
-        trace!(
-            "Updated state after removal; global hash is now {}",
-            self.root.tree_hash
-        );
-        ret
     }
 
     pub fn check_invariants(&self) {
@@ -496,22 +939,32 @@ impl<K: Hash + Ord, V: Hash> HRTree<K, V> {
         // - the cumulated hash of the sub-tree
         // - the number of nodes of the sub-tree
         // - the height of the sub-tree
-        fn aux<'a, K: Hash + Ord, V: Hash>(
-            node: &'a Node<K, V>,
+        fn aux<'a, K: Hash + Ord, V: Hash, F: FingerprintStrategy, const N: usize>(
+            node: &'a Node<K, V, F, N>,
             mut min: Option<&'a K>,
             max: Option<&K>,
-        ) -> (u64, usize, usize) {
-            let mut cum_hash = 0;
+            hasher: Option<ElementHasher<K, V, F>>,
+        ) -> (F::Output, usize, usize) {
+            let mut cum_hash = F::identity();
             let mut tot_size = 0;
             let mut max_height = 1;
             // check node size
             if min.is_some() || max.is_some() {
                 // this is not the root
                 assert!(
-                    node.keys.len() >= MIN_CAPACITY,
+                    node.keys.len() >= Node::<K, V, F, N>::MIN_CAPACITY,
                     "minimum node size invariant violated"
                 );
             }
+            assert!(
+                node.keys.len() <= Node::<K, V, F, N>::MAX_CAPACITY,
+                "maximum node size invariant violated"
+            );
+            // only the root of an empty tree has no key, and it has no children
+            assert!(
+                !node.keys.is_empty() || node.children.is_none(),
+                "empty internal node"
+            );
             // check order
             if let Some(min) = min {
                 assert!(min <= &node.keys[0], "order invariant violated");
@@ -526,8 +979,9 @@ impl<K: Hash + Ord, V: Hash> HRTree<K, V> {
                 // child before key
                 if let Some(children) = node.children.as_ref() {
                     let next_max = Some(&node.keys[i]);
-                    let (child_hash, child_size, child_height) = aux(&children[i], min, next_max);
-                    cum_hash ^= child_hash;
+                    let (child_hash, child_size, child_height) =
+                        aux(&children[i], min, next_max, hasher);
+                    cum_hash = F::combine(cum_hash, child_hash);
                     tot_size += child_size;
                     if max_height != 1 {
                         assert_eq!(child_height, max_height, "height invariant violated");
@@ -536,16 +990,16 @@ impl<K: Hash + Ord, V: Hash> HRTree<K, V> {
                     min = next_max;
                 }
                 // key
-                let hash = hash(&node.keys[i], &node.values[i]);
+                let hash = element_hash::<F, _, _>(hasher, &node.keys[i], &node.values[i]);
                 assert_eq!(hash, node.hashes[i], "hash cache invalid");
-                cum_hash ^= hash;
+                cum_hash = F::combine(cum_hash, hash);
                 tot_size += 1;
             }
             // child after last key
             if let Some(children) = node.children.as_ref() {
                 let (child_hash, child_size, child_height) =
-                    aux(children.last().unwrap(), min, max);
-                cum_hash ^= child_hash;
+                    aux(children.last().unwrap(), min, max, hasher);
+                cum_hash = F::combine(cum_hash, child_hash);
                 tot_size += child_size;
                 if max_height != 1 {
                     assert_eq!(child_height, max_height, "height invariant violated");
@@ -555,47 +1009,612 @@ impl<K: Hash + Ord, V: Hash> HRTree<K, V> {
             assert_eq!(tot_size, node.tree_size, "size invariant violated");
             (cum_hash, tot_size, max_height + 1)
         }
-        aux(&self.root, None, None);
+        aux(&self.root, None, None, self.hasher);
     }
 }
 
-impl<K, V> PartialEq for HRTree<K, V> {
-    fn eq(&self, other: &Self) -> bool {
-        self.root.tree_hash == other.root.tree_hash
+impl<K: Clone + Hash + Ord, V: Clone + Hash, F: FingerprintStrategy, const N: usize>
+    HRTree<K, V, F, N>
+{
+    /// Hash the elements in their serialized form, as they are sent to the peers, rather than
+    /// with their [`Hash`] implementations; see [`canonical_hash`].
+    ///
+    /// The hashes then only depend on the bytes on the wire, so that peers using different types
+    /// with the same serialization, such as `String` and a newtype around it, reconcile with each
+    /// other. All the peers must use this mode, or none: the hashes of the two modes never match.
+    /// The existing elements are hashed again. A tree loaded from a snapshot uses the default mode,
+    /// and must be switched again.
+    ///
+    /// Inserting an element that cannot be serialized then panics, see [`canonical_hash`].
+    pub fn with_canonical_hashing(mut self) -> Self
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        self.set_canonical_hashing();
+        self
     }
-}
-
-impl<K, V> Eq for HRTree<K, V> {}
 
-impl<K: Hash + Ord, V: Hash> FromIterator<(K, V)> for HRTree<K, V> {
-    fn from_iter<T>(iter: T) -> Self
+    /// Switch to the canonical hashing in place, see
+    /// [`with_canonical_hashing`](HRTree::with_canonical_hashing).
+    pub fn set_canonical_hashing(&mut self)
     where
-        T: IntoIterator<Item = (K, V)>,
+        K: Serialize,
+        V: Serialize,
     {
-        let mut tree = HRTree::new();
-        let mut items: Vec<_> = iter.into_iter().collect();
-        items.sort_by(|a, b| a.0.cmp(&b.0));
-        for (k, v) in items {
-            tree.insert(k, v);
-        }
-        tree
+        self.hasher = Some(canonical_hash::<F, K, V>);
+        // the hashes are recomputed when the guard is dropped
+        drop(self.values_mut());
     }
-}
-
-enum IntoIterItem<K, V> {
-    Node(Box<Node<K, V>>),
-    Element(K, V),
-}
 
-pub struct IntoIter<K, V> {
-    stack: Vec<IntoIterItem<K, V>>,
-}
-
-impl<K, V> Iterator for IntoIter<K, V> {
-    type Item = (K, V);
-    fn next(&mut self) -> Option<Self::Item> {
+    /// Get a mutable access to the value associated with the given key, if it exists.
+    ///
+    /// The hashes of the tree are updated when the returned [`ValueGuard`] is dropped.
+    pub fn get_mut(&mut self, key: &K) -> Option<ValueGuard<'_, K, V, F, N>> {
+        let mut path = Vec::new();
+        let mut node = self.root.as_ref();
+        loop {
+            match node.keys.binary_search(key) {
+                Ok(index) => {
+                    // copy the shared nodes on the path, so that the guard can modify them
+                    let mut node = Arc::make_mut(&mut self.root);
+                    for &i in &path {
+                        node = Arc::make_mut(&mut node.children.as_mut().unwrap()[i]);
+                    }
+                    return Some(ValueGuard {
+                        root: Arc::get_mut(&mut self.root).unwrap(),
+                        path,
+                        index,
+                        hasher: self.hasher,
+                    });
+                }
+                Err(index) => {
+                    node = node.children.as_ref()?[index].as_ref();
+                    path.push(index);
+                }
+            }
+        }
+    }
+
+    /// Get a mutable access to all the values, through the returned [`ValuesMut`].
+    ///
+    /// The nodes shared with clones of the tree are copied first, and the hashes of the tree are
+    /// updated when the guard is dropped.
+    pub fn values_mut(&mut self) -> ValuesMut<'_, K, V, F, N> {
+        fn aux<K: Clone, V: Clone, F: FingerprintStrategy, const N: usize>(
+            node: &mut Arc<Node<K, V, F, N>>,
+        ) {
+            let node = Arc::make_mut(node);
+            for child in node.children.iter_mut().flatten() {
+                aux(child);
+            }
+        }
+        aux(&mut self.root);
+        ValuesMut {
+            root: Arc::get_mut(&mut self.root).unwrap(),
+            hasher: self.hasher,
+        }
+    }
+
+    /// Remove and return the element with the smallest key, if any.
+    pub fn pop_first(&mut self) -> Option<(K, V)> {
+        if self.root.tree_size == 0 {
+            return None;
+        }
+        let (k, v, _) = Arc::make_mut(&mut self.root).pop_first();
+        self.collapse_root();
+        trace!(
+            "Updated state after removal; global hash is now {}",
+            self.root.tree_hash
+        );
+        Some((k, v))
+    }
+
+    /// Remove and return the element with the largest key, if any.
+    pub fn pop_last(&mut self) -> Option<(K, V)> {
+        if self.root.tree_size == 0 {
+            return None;
+        }
+        let (k, v, _) = Arc::make_mut(&mut self.root).pop_last();
+        self.collapse_root();
+        trace!(
+            "Updated state after removal; global hash is now {}",
+            self.root.tree_hash
+        );
+        Some((k, v))
+    }
+
+    pub fn insert(&mut self, key: K, value: V) -> Option<V> {
+        // return:
+        // - a key and node to be inserted after the current node
+        // - the hash difference
+        // - the value that was at key, if any
+        fn aux<K: Clone + Hash + Ord, V: Clone + Hash, F: FingerprintStrategy, const N: usize>(
+            node: &mut Node<K, V, F, N>,
+            key: K,
+            value: V,
+            hasher: Option<ElementHasher<K, V, F>>,
+        ) -> (InsertionTuple<K, V, F, N>, F::Output, Option<V>) {
+            match node.keys.binary_search(&key) {
+                Ok(index) => {
+                    let old_hash = node.hashes[index];
+                    let new_hash = element_hash::<F, _, _>(hasher, &key, &value);
+                    let diff_hash = F::remove(new_hash, old_hash);
+                    node.hashes[index] = new_hash;
+                    node.tree_hash = F::combine(node.tree_hash, diff_hash);
+                    let ret = std::mem::replace(&mut node.values[index], value);
+                    (None, diff_hash, Some(ret))
+                }
+                Err(index) => {
+                    if let Some(children) = node.children.as_mut() {
+                        // internal node
+                        let child = Arc::make_mut(&mut children[index]);
+                        let (mut to_insert, diff_hash, ret) = aux(child, key, value, hasher);
+                        if let Some((key, value, hash, right_child)) = to_insert {
+                            to_insert =
+                                node.insert(index, key, value, hash, Some(right_child), diff_hash)
+                        } else {
+                            if ret.is_none() {
+                                node.tree_size += 1;
+                            }
+                            node.tree_hash = F::combine(node.tree_hash, diff_hash);
+                        }
+                        (to_insert, diff_hash, ret)
+                    } else {
+                        // leaf
+                        let hash = element_hash::<F, _, _>(hasher, &key, &value);
+                        let to_insert = node.insert(index, key, value, hash, None, hash);
+                        (to_insert, hash, None)
+                    }
+                }
+            }
+        }
+        let validated_key = self.validate.then(|| key.clone());
+        let (to_insert, _, ret) = aux(Arc::make_mut(&mut self.root), key, value, self.hasher);
+        // if we still have things to insert at the root, we need to create a new root
+        if let Some((key, value, hash, right_child)) = to_insert {
+            let mut children = ArrayVec::new();
+            children.push(self.root.clone());
+            children.push(right_child);
+            let mut new_root = Node::new();
+            new_root.keys.push(key);
+            new_root.values.push(value);
+            new_root.hashes.push(hash);
+            new_root.children = Some(children);
+            new_root.refresh_hash_size();
+            self.root = Arc::new(new_root);
+        }
+        if let Some(key) = validated_key {
+            self.validate_path(&key);
+        }
+        trace!(
+            "Updated state after insertion; global hash is now {}",
+            self.root.tree_hash
+        );
+        ret
+    }
+
+    pub fn remove(&mut self, key: &K) -> Option<V> {
+        // return:
+        // - the hash diff
+        // - the value at the key that was removed, if there was one
+        fn aux<K: Clone + Ord, V: Clone, F: FingerprintStrategy, const N: usize>(
+            node: &mut Node<K, V, F, N>,
+            key: &K,
+        ) -> (F::Output, Option<V>) {
+            match node.keys.binary_search(key) {
+                Ok(index) => {
+                    if let Some(children) = node.children.as_mut() {
+                        // internal node
+                        // we need to replace key, value hash with a new separator; we can find it
+                        // in the left or right sub-tree
+                        let child = Arc::make_mut(&mut children[index]);
+                        let (prev_k, prev_v, prev_h) = child.pop_last();
+                        node.keys[index] = prev_k;
+                        let v = std::mem::replace(&mut node.values[index], prev_v);
+                        let h = std::mem::replace(&mut node.hashes[index], prev_h);
+                        node.tree_size -= 1;
+                        node.tree_hash = F::remove(node.tree_hash, h);
+                        node.rebalance_after_deletion(index);
+                        (h, Some(v))
+                    } else {
+                        // leaf node
+                        node.keys.remove(index);
+                        let v = node.values.remove(index);
+                        let h = node.hashes.remove(index);
+                        node.tree_size -= 1;
+                        node.tree_hash = F::remove(node.tree_hash, h);
+                        (h, Some(v))
+                    }
+                }
+                Err(index) => {
+                    if let Some(children) = node.children.as_mut() {
+                        // internal node
+                        let (diff_hash, ret) = aux(Arc::make_mut(&mut children[index]), key);
+                        if ret.is_some() {
+                            node.tree_size -= 1;
+                        }
+                        node.tree_hash = F::remove(node.tree_hash, diff_hash);
+                        node.rebalance_after_deletion(index);
+                        (diff_hash, ret)
+                    } else {
+                        // leaf node
+                        (F::identity(), None)
+                    }
+                }
+            }
+        }
+        let ret = aux(Arc::make_mut(&mut self.root), key).1;
+        self.collapse_root();
+        if self.validate {
+            self.validate_path(key);
+        }
+        trace!(
+            "Updated state after removal; global hash is now {}",
+            self.root.tree_hash
+        );
+        ret
+    }
+
+    /// Replace the root by its only child while it has no key, after a merge of its last two
+    /// children, so that the tree does not stay taller than needed.
+    fn collapse_root(&mut self) {
+        while self.root.keys.is_empty() && self.root.children.is_some() {
+            self.root = self.root.children.as_ref().unwrap()[0].clone();
+        }
+    }
+
+    /// Remove all the elements whose keys are in the given range, and return them in order.
+    ///
+    /// The tree is split around the range, and the two remaining parts are joined back,
+    /// so this only rebalances the nodes along the boundaries of the range.
+    pub fn remove_range<R: RangeBounds<K>>(&mut self, range: &R) -> Vec<(K, V)> {
+        let root = std::mem::replace(&mut self.root, Arc::new(Node::new()));
+        let height = height(&root);
+        let (left, rest) = split((root, height), &|key: &K| {
+            key.range_cmp(range) == RangeOrdering::Below
+        });
+        let (removed, right) = split(rest, &|key: &K| {
+            key.range_cmp(range) != RangeOrdering::Above
+        });
+        self.root = concat(left, right).0;
+        self.collapse_root();
+        trace!(
+            "Updated state after range removal; global hash is now {}",
+            self.root.tree_hash
+        );
+        HRTree {
+            root: removed.0,
+            validate: false,
+            hasher: None,
+        }
+        .into_iter()
+        .collect()
+    }
+
+    /// Remove all the elements for which the predicate returns `false`, and return them in order.
+    ///
+    /// The elements are visited once, in order, and the remaining ones are bulk-loaded into a new
+    /// tree, as with [`from_sorted_iter`](HRTree::from_sorted_iter).
+    pub fn retain<P: FnMut(&K, &V) -> bool>(&mut self, mut predicate: P) -> Vec<(K, V)> {
+        let root = std::mem::replace(&mut self.root, Arc::new(Node::new()));
+        let (kept, removed): (Vec<_>, Vec<_>) = HRTree::<K, V, F, N> {
+            root,
+            validate: false,
+            hasher: None,
+        }
+        .into_iter()
+        .partition(|(key, value)| predicate(key, value));
+        self.root = load_sorted(kept, self.hasher);
+        trace!(
+            "Updated state after retain; global hash is now {}",
+            self.root.tree_hash
+        );
+        removed
+    }
+
+    /// Merge key-value pairs sorted by key into the tree: the new keys are inserted, and the
+    /// existing values are replaced when `decide` returns `true`, given the key, the existing
+    /// value and the new one.
+    ///
+    /// This is equivalent to looking up and inserting each pair in turn, including when a key
+    /// appears several times, but a large input is merged with the tree in a single pass, and
+    /// the result bulk-loaded, as with [`from_sorted_iter`](HRTree::from_sorted_iter). The order
+    /// is not checked: unsorted items result in an invalid tree.
+    pub fn merge_from_sorted<I, D>(&mut self, iter: I, decide: D) -> MergeStats
+    where
+        I: IntoIterator<Item = (K, V)>,
+        D: FnMut(&K, &V, &V) -> bool,
+    {
+        self.merge_from_sorted_with(iter, decide, |_, _, _| {})
+    }
+
+    /// Same as [`merge_from_sorted`](HRTree::merge_from_sorted), calling `changed` before each
+    /// insertion, with the key, the new value and the value it replaces, if any.
+    pub fn merge_from_sorted_with<I, D, C>(
+        &mut self,
+        iter: I,
+        mut decide: D,
+        mut changed: C,
+    ) -> MergeStats
+    where
+        I: IntoIterator<Item = (K, V)>,
+        D: FnMut(&K, &V, &V) -> bool,
+        C: FnMut(&K, &V, Option<&V>),
+    {
+        let items: Vec<(K, V)> = iter.into_iter().collect();
+        let mut stats = MergeStats::default();
+        // a lookup costs a descent in the tree, while the single pass visits all its elements
+        if items.len().saturating_mul(self.depth()) < self.len() {
+            for (key, value) in items {
+                match self.get(&key) {
+                    None => stats.inserted += 1,
+                    Some(existing) if decide(&key, existing, &value) => {
+                        stats.overwritten += 1;
+                    }
+                    Some(_) => {
+                        stats.skipped += 1;
+                        continue;
+                    }
+                }
+                changed(&key, &value, self.get(&key));
+                self.insert(key, value);
+            }
+            return stats;
+        }
+        let mut merged: Vec<(K, V)> = Vec::with_capacity(self.len() + items.len());
+        let root = std::mem::replace(&mut self.root, Arc::new(Node::new()));
+        let mut existing = HRTree::<K, V, F, N> {
+            root,
+            validate: false,
+            hasher: None,
+        }
+        .into_iter()
+        .peekable();
+        for (key, value) in items {
+            while let Some((existing_key, _)) = existing.peek() {
+                if *existing_key >= key {
+                    break;
+                }
+                merged.extend(existing.next());
+            }
+            // the key is either the last one merged, when it appears several times in the input,
+            // or the next existing one
+            let merged_last = merged.last().is_some_and(|(last, _)| *last == key);
+            if !merged_last && existing.peek().is_some_and(|(next, _)| *next == key) {
+                merged.extend(existing.next());
+            }
+            match merged.last_mut().filter(|(last, _)| *last == key) {
+                None => {
+                    changed(&key, &value, None);
+                    merged.push((key, value));
+                    stats.inserted += 1;
+                }
+                Some(last) if decide(&key, &last.1, &value) => {
+                    changed(&key, &value, Some(&last.1));
+                    last.1 = value;
+                    stats.overwritten += 1;
+                }
+                Some(_) => stats.skipped += 1,
+            }
+        }
+        merged.extend(existing);
+        self.root = load_sorted(merged, self.hasher);
+        trace!(
+            "Updated state after merge; global hash is now {}",
+            self.root.tree_hash
+        );
+        stats
+    }
+}
+
+/// Outcome of [`HRTree::merge_from_sorted`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MergeStats {
+    /// Number of keys that were not in the tree
+    pub inserted: usize,
+    /// Number of existing values replaced
+    pub overwritten: usize,
+    /// Number of key-value pairs discarded in favor of the existing value
+    pub skipped: usize,
+}
+
+/// Child of a node on the path of a [`ValueGuard`], which [`HRTree::get_mut`] made unique.
+fn child_mut<K, V, F: FingerprintStrategy, const N: usize>(
+    node: &mut Node<K, V, F, N>,
+    i: usize,
+) -> &mut Node<K, V, F, N> {
+    Arc::get_mut(&mut node.children.as_mut().unwrap()[i]).unwrap()
+}
+
+/// Mutable access to a value of an [`HRTree`], returned by [`HRTree::get_mut`].
+///
+/// Dropping the guard recomputes the hash of the key-value pair, and updates the cumulated hashes
+/// of the nodes on the path from the root.
+pub struct ValueGuard<
+    'a,
+    K: Hash,
+    V: Hash,
+    F: FingerprintStrategy = DefaultFingerprint,
+    const N: usize = { 2 * B },
+> {
+    root: &'a mut Node<K, V, F, N>,
+    /// Index of the child to follow at each level, from the root to the node holding the value
+    path: Vec<usize>,
+    index: usize,
+    hasher: Option<ElementHasher<K, V, F>>,
+}
+
+impl<'a, K: Hash, V: Hash, F: FingerprintStrategy, const N: usize> ValueGuard<'a, K, V, F, N> {
+    fn node(&self) -> &Node<K, V, F, N> {
+        let mut node = &*self.root;
+        for &i in &self.path {
+            node = &node.children.as_ref().unwrap()[i];
+        }
+        node
+    }
+
+    fn node_mut(&mut self) -> &mut Node<K, V, F, N> {
+        let mut node = &mut *self.root;
+        for &i in &self.path {
+            node = child_mut(node, i);
+        }
+        node
+    }
+
+    pub fn key(&self) -> &K {
+        &self.node().keys[self.index]
+    }
+}
+
+impl<'a, K: Hash, V: Hash, F: FingerprintStrategy, const N: usize> std::ops::Deref
+    for ValueGuard<'a, K, V, F, N>
+{
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.node().values[self.index]
+    }
+}
+
+impl<'a, K: Hash, V: Hash, F: FingerprintStrategy, const N: usize> std::ops::DerefMut
+    for ValueGuard<'a, K, V, F, N>
+{
+    fn deref_mut(&mut self) -> &mut V {
+        let index = self.index;
+        &mut self.node_mut().values[index]
+    }
+}
+
+impl<'a, K: Hash, V: Hash, F: FingerprintStrategy, const N: usize> Drop
+    for ValueGuard<'a, K, V, F, N>
+{
+    fn drop(&mut self) {
+        // the value was likely modified, so we need to restore the hash invariants
+        let index = self.index;
+        let hasher = self.hasher;
+        let node = self.node_mut();
+        let old_hash = node.hashes[index];
+        let new_hash = element_hash::<F, _, _>(hasher, &node.keys[index], &node.values[index]);
+        node.hashes[index] = new_hash;
+        let diff_hash = F::remove(new_hash, old_hash);
+        let mut node = &mut *self.root;
+        node.tree_hash = F::combine(node.tree_hash, diff_hash);
+        for &i in &self.path {
+            node = child_mut(node, i);
+            node.tree_hash = F::combine(node.tree_hash, diff_hash);
+        }
+    }
+}
+
+impl<K, V, F: FingerprintStrategy, const N: usize> PartialEq for HRTree<K, V, F, N> {
+    fn eq(&self, other: &Self) -> bool {
+        self.root.tree_hash == other.root.tree_hash
+    }
+}
+
+impl<K, V, F: FingerprintStrategy, const N: usize> Eq for HRTree<K, V, F, N> {}
+
+impl<K: Hash + Ord, V: Hash> FromIterator<(K, V)> for HRTree<K, V> {
+    fn from_iter<T>(iter: T) -> Self
+    where
+        T: IntoIterator<Item = (K, V)>,
+    {
+        let mut items: Vec<_> = iter.into_iter().collect();
+        // the sort is stable, so the last value of a key is kept, as with successive insertions
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+        HRTree::from_sorted_iter(items)
+    }
+}
+
+impl<K: Clone + Hash + Ord, V: Clone + Hash, F: FingerprintStrategy, const N: usize> Extend<(K, V)>
+    for HRTree<K, V, F, N>
+{
+    fn extend<T>(&mut self, iter: T)
+    where
+        T: IntoIterator<Item = (K, V)>,
+    {
+        let mut items: Vec<_> = iter.into_iter().collect();
+        items.sort_by(|a, b| a.0.cmp(&b.0));
+        for (k, v) in items {
+            self.insert(k, v);
+        }
+    }
+}
+
+/// Serialized as the sequence of its entries, in key order, preceded by their number.
+///
+/// The format does not depend on the structure of the tree.
+impl<K: Serialize, V: Serialize, F: FingerprintStrategy, const N: usize> Serialize
+    for HRTree<K, V, F, N>
+{
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.root.tree_size))?;
+        for entry in self {
+            seq.serialize_element(&entry)?;
+        }
+        seq.end()
+    }
+}
+
+/// Built in linear time from the sorted entries; unsorted or duplicate keys are rejected.
+impl<'de, K, V, F, const N: usize> Deserialize<'de> for HRTree<K, V, F, N>
+where
+    K: Deserialize<'de> + Hash + Ord,
+    V: Deserialize<'de> + Hash,
+    F: FingerprintStrategy,
+{
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct EntriesVisitor<K, V, F, const N: usize>(PhantomData<(K, V, F)>);
+
+        impl<'de, K, V, F, const N: usize> Visitor<'de> for EntriesVisitor<K, V, F, N>
+        where
+            K: Deserialize<'de> + Hash + Ord,
+            V: Deserialize<'de> + Hash,
+            F: FingerprintStrategy,
+        {
+            type Value = HRTree<K, V, F, N>;
+
+            fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+                formatter.write_str("a sequence of key-value pairs sorted by key")
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
+                // do not trust the announced size for the allocation
+                let mut items: Vec<(K, V)> =
+                    Vec::with_capacity(seq.size_hint().unwrap_or(0).min(4096));
+                while let Some((key, value)) = seq.next_element::<(K, V)>()? {
+                    if items.last().is_some_and(|(last, _)| *last >= key) {
+                        return Err(A::Error::custom("keys are not strictly increasing"));
+                    }
+                    items.push((key, value));
+                }
+                Ok(HRTree::from_sorted_iter(items))
+            }
+        }
+
+        deserializer.deserialize_seq(EntriesVisitor(PhantomData))
+    }
+}
+
+enum IntoIterItem<K, V, F: FingerprintStrategy, const N: usize> {
+    Node(Arc<Node<K, V, F, N>>),
+    Element(K, V),
+}
+
+pub struct IntoIter<K, V, F: FingerprintStrategy = DefaultFingerprint, const N: usize = { 2 * B }> {
+    stack: Vec<IntoIterItem<K, V, F, N>>,
+    /// Number of elements not yielded yet
+    remaining: usize,
+}
+
+impl<K: Clone, V: Clone, F: FingerprintStrategy, const N: usize> Iterator for IntoIter<K, V, F, N> {
+    type Item = (K, V);
+    fn next(&mut self) -> Option<Self::Item> {
         match self.stack.pop() {
-            Some(IntoIterItem::Node(mut node)) => {
+            Some(IntoIterItem::Node(node)) => {
+                // the nodes shared with other trees are copied
+                let mut node = Arc::unwrap_or_clone(node);
                 if let Some(mut children) = node.children {
                     self.stack.push(IntoIterItem::Node(children.pop().unwrap()));
                     while !node.keys.is_empty() {
@@ -614,83 +1633,521 @@ impl<K, V> Iterator for IntoIter<K, V> {
                 }
                 self.next()
             }
-            Some(IntoIterItem::Element(k, v)) => Some((k, v)),
+            Some(IntoIterItem::Element(k, v)) => {
+                self.remaining -= 1;
+                Some((k, v))
+            }
             None => None,
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K: Clone, V: Clone, F: FingerprintStrategy, const N: usize> ExactSizeIterator
+    for IntoIter<K, V, F, N>
+{
+}
+
+impl<K: Clone, V: Clone, F: FingerprintStrategy, const N: usize> FusedIterator
+    for IntoIter<K, V, F, N>
+{
 }
 
-impl<K, V> IntoIterator for HRTree<K, V> {
+impl<K: Clone, V: Clone, F: FingerprintStrategy, const N: usize> IntoIterator
+    for HRTree<K, V, F, N>
+{
     type Item = (K, V);
-    type IntoIter = IntoIter<K, V>;
+    type IntoIter = IntoIter<K, V, F, N>;
     fn into_iter(self) -> Self::IntoIter {
         IntoIter {
+            remaining: self.root.tree_size,
             stack: vec![IntoIterItem::Node(self.root)],
         }
     }
 }
 
-pub struct Iter<'a, K, V> {
-    stack: Vec<(&'a Node<K, V>, usize)>,
+/// Iterator over the elements of an [`HRTree`], in order of the keys, or in reverse order with
+/// [`Iterator::rev`].
+pub struct Iter<'a, K, V, F: FingerprintStrategy = DefaultFingerprint, const N: usize = { 2 * B }> {
+    /// Nodes of the front, with the number of their children passed
+    stack: Vec<(&'a Node<K, V, F, N>, usize)>,
+    /// Nodes of the back, with the number of their children passed from the right
+    back: Vec<(&'a Node<K, V, F, N>, usize)>,
+    /// Number of elements between the front and the back; the traversals stop when they meet
+    remaining: usize,
 }
 
-impl<'a, K, V> Iterator for Iter<'a, K, V> {
-    type Item = (&'a K, &'a V);
-    fn next(&mut self) -> Option<Self::Item> {
-        if let Some((node, children_passed)) = self.stack.pop() {
-            if children_passed < node.keys.len() {
-                self.stack.push((node, children_passed + 1));
-            }
-            if children_passed <= node.keys.len() {
-                if let Some(children) = node.children.as_ref() {
-                    self.stack.push((&children[children_passed], 0));
-                }
+impl<'a, K, V, F: FingerprintStrategy, const N: usize> Iter<'a, K, V, F, N> {
+    /// Iterate over the elements of the tree from the given front, which holds the last
+    /// `remaining` elements.
+    fn new(
+        root: &'a Node<K, V, F, N>,
+        stack: Vec<(&'a Node<K, V, F, N>, usize)>,
+        remaining: usize,
+    ) -> Self {
+        Iter {
+            stack,
+            back: vec![(root, 0)],
+            remaining,
+        }
+    }
+
+    fn advance_front(&mut self) -> Option<(&'a K, &'a V)> {
+        let (node, children_passed) = self.stack.pop()?;
+        if children_passed < node.keys.len() {
+            self.stack.push((node, children_passed + 1));
+        }
+        if children_passed <= node.keys.len() {
+            if let Some(children) = node.children.as_ref() {
+                self.stack.push((&children[children_passed], 0));
             }
-            if 0 < children_passed && children_passed <= node.keys.len() {
-                Some((
-                    &node.keys[children_passed - 1],
-                    &node.values[children_passed - 1],
-                ))
-            } else {
-                self.next()
+        }
+        if 0 < children_passed && children_passed <= node.keys.len() {
+            Some((
+                &node.keys[children_passed - 1],
+                &node.values[children_passed - 1],
+            ))
+        } else {
+            self.advance_front()
+        }
+    }
+
+    fn advance_back(&mut self) -> Option<(&'a K, &'a V)> {
+        let (node, children_passed) = self.back.pop()?;
+        let len = node.keys.len();
+        if children_passed < len {
+            self.back.push((node, children_passed + 1));
+        }
+        if children_passed <= len {
+            if let Some(children) = node.children.as_ref() {
+                self.back.push((&children[len - children_passed], 0));
             }
+        }
+        if 0 < children_passed && children_passed <= len {
+            Some((
+                &node.keys[len - children_passed],
+                &node.values[len - children_passed],
+            ))
         } else {
-            None
+            self.advance_back()
         }
     }
 }
 
-impl<'a, K, V> IntoIterator for &'a HRTree<K, V> {
+impl<'a, K, V, F: FingerprintStrategy, const N: usize> Iterator for Iter<'a, K, V, F, N> {
     type Item = (&'a K, &'a V);
-    type IntoIter = Iter<'a, K, V>;
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        self.advance_front()
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K, V, F: FingerprintStrategy, const N: usize> DoubleEndedIterator for Iter<'_, K, V, F, N> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        self.advance_back()
+    }
+}
+
+impl<K, V, F: FingerprintStrategy, const N: usize> ExactSizeIterator for Iter<'_, K, V, F, N> {}
+
+impl<K, V, F: FingerprintStrategy, const N: usize> FusedIterator for Iter<'_, K, V, F, N> {}
+
+impl<'a, K, V, F: FingerprintStrategy, const N: usize> IntoIterator for &'a HRTree<K, V, F, N> {
+    type Item = (&'a K, &'a V);
+    type IntoIter = Iter<'a, K, V, F, N>;
     fn into_iter(self) -> Self::IntoIter {
-        Iter {
-            stack: vec![(&self.root, 0)],
+        Iter::new(&self.root, vec![(&self.root, 0)], self.root.tree_size)
+    }
+}
+
+/// Iterator over the keys of an [`HRTree`], returned by [`HRTree::keys`].
+pub struct Keys<'a, K, V, F: FingerprintStrategy = DefaultFingerprint, const N: usize = { 2 * B }> {
+    inner: Iter<'a, K, V, F, N>,
+}
+
+impl<'a, K, V, F: FingerprintStrategy, const N: usize> Iterator for Keys<'a, K, V, F, N> {
+    type Item = &'a K;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(key, _)| key)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V, F: FingerprintStrategy, const N: usize> DoubleEndedIterator for Keys<'_, K, V, F, N> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|(key, _)| key)
+    }
+}
+
+impl<K, V, F: FingerprintStrategy, const N: usize> ExactSizeIterator for Keys<'_, K, V, F, N> {}
+
+impl<K, V, F: FingerprintStrategy, const N: usize> FusedIterator for Keys<'_, K, V, F, N> {}
+
+/// Iterator over the values of an [`HRTree`], in order of their keys, returned by
+/// [`HRTree::values`].
+pub struct Values<'a, K, V, F: FingerprintStrategy = DefaultFingerprint, const N: usize = { 2 * B }>
+{
+    inner: Iter<'a, K, V, F, N>,
+}
+
+impl<'a, K, V, F: FingerprintStrategy, const N: usize> Iterator for Values<'a, K, V, F, N> {
+    type Item = &'a V;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(_, value)| value)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<K, V, F: FingerprintStrategy, const N: usize> DoubleEndedIterator for Values<'_, K, V, F, N> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.inner.next_back().map(|(_, value)| value)
+    }
+}
+
+impl<K, V, F: FingerprintStrategy, const N: usize> ExactSizeIterator for Values<'_, K, V, F, N> {}
+
+impl<K, V, F: FingerprintStrategy, const N: usize> FusedIterator for Values<'_, K, V, F, N> {}
+
+/// Mutable access to all the values of an [`HRTree`], returned by [`HRTree::values_mut`].
+///
+/// The values are iterated over with `&mut`, in order of their keys. As with [`ValueGuard`],
+/// dropping the guard recomputes the hashes of the tree, which takes `O(n)`.
+pub struct ValuesMut<
+    'a,
+    K: Hash,
+    V: Hash,
+    F: FingerprintStrategy = DefaultFingerprint,
+    const N: usize = { 2 * B },
+> {
+    root: &'a mut Node<K, V, F, N>,
+    hasher: Option<ElementHasher<K, V, F>>,
+}
+
+impl<K: Hash, V: Hash, F: FingerprintStrategy, const N: usize> ValuesMut<'_, K, V, F, N> {
+    pub fn iter(&mut self) -> IterValuesMut<'_, K, V, F, N> {
+        IterValuesMut {
+            remaining: self.root.tree_size,
+            stack: vec![ValuesMutFrame::new(self.root)],
+        }
+    }
+}
+
+impl<'b, K: Hash, V: Hash, F: FingerprintStrategy, const N: usize> IntoIterator
+    for &'b mut ValuesMut<'_, K, V, F, N>
+{
+    type Item = &'b mut V;
+    type IntoIter = IterValuesMut<'b, K, V, F, N>;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<K: Hash, V: Hash, F: FingerprintStrategy, const N: usize> Drop for ValuesMut<'_, K, V, F, N> {
+    fn drop(&mut self) {
+        // the values were likely modified, so we need to restore the hash invariants
+        fn aux<K: Hash, V: Hash, F: FingerprintStrategy, const N: usize>(
+            node: &mut Node<K, V, F, N>,
+            hasher: Option<ElementHasher<K, V, F>>,
+        ) {
+            for i in 0..node.children.as_ref().map_or(0, |c| c.len()) {
+                aux(child_mut(node, i), hasher);
+            }
+            for i in 0..node.keys.len() {
+                node.hashes[i] = element_hash::<F, _, _>(hasher, &node.keys[i], &node.values[i]);
+            }
+            node.refresh_hash_size();
         }
+        aux(self.root, self.hasher);
     }
 }
 
-impl<K, V> HRTree<K, V> {
-    pub fn iter(&self) -> Iter<K, V> {
+/// Node on the path of an [`IterValuesMut`]
+struct ValuesMutFrame<'a, K, V, F: FingerprintStrategy, const N: usize> {
+    values: std::slice::IterMut<'a, V>,
+    children: Option<std::slice::IterMut<'a, Arc<Node<K, V, F, N>>>>,
+    /// Whether the next child comes before the next value
+    descend: bool,
+}
+
+impl<'a, K, V, F: FingerprintStrategy, const N: usize> ValuesMutFrame<'a, K, V, F, N> {
+    fn new(node: &'a mut Node<K, V, F, N>) -> Self {
+        ValuesMutFrame {
+            values: node.values.iter_mut(),
+            children: node.children.as_mut().map(|children| children.iter_mut()),
+            descend: true,
+        }
+    }
+}
+
+/// Iterator over the values of an [`HRTree`] through a [`ValuesMut`].
+pub struct IterValuesMut<
+    'a,
+    K,
+    V,
+    F: FingerprintStrategy = DefaultFingerprint,
+    const N: usize = { 2 * B },
+> {
+    stack: Vec<ValuesMutFrame<'a, K, V, F, N>>,
+    remaining: usize,
+}
+
+impl<'a, K, V, F: FingerprintStrategy, const N: usize> Iterator for IterValuesMut<'a, K, V, F, N> {
+    type Item = &'a mut V;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let frame = self.stack.last_mut()?;
+            if std::mem::take(&mut frame.descend) {
+                if let Some(child) = frame.children.as_mut().and_then(|c| c.next()) {
+                    // HRTree::values_mut made all the nodes unique
+                    let child = Arc::get_mut(child).unwrap();
+                    self.stack.push(ValuesMutFrame::new(child));
+                    continue;
+                }
+            }
+            if let Some(value) = frame.values.next() {
+                frame.descend = true;
+                self.remaining -= 1;
+                return Some(value);
+            }
+            self.stack.pop();
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<K, V, F: FingerprintStrategy, const N: usize> ExactSizeIterator
+    for IterValuesMut<'_, K, V, F, N>
+{
+}
+
+impl<K, V, F: FingerprintStrategy, const N: usize> FusedIterator for IterValuesMut<'_, K, V, F, N> {}
+
+impl<K: Clone + Hash + Ord, V: Hash, F: FingerprintStrategy, const N: usize> HRTree<K, V, F, N> {
+    /// Describe the content of the tree offline, as segments of at most `max_leaf` elements
+    /// covering the whole key space.
+    ///
+    /// The segments can be saved, and compared later with another tree using
+    /// [`compare_segments`](HRTree::compare_segments), without running the reconciliation
+    /// protocol.
+    pub fn export_segments(&self, max_leaf: usize) -> Vec<HashSegment<K, F::Output>> {
+        diff::export_segments(self, max_leaf)
+    }
+
+    /// Return the ranges of the segments exported from another tree with
+    /// [`export_segments`](HRTree::export_segments) whose elements differ from the local ones.
+    pub fn compare_segments(&self, segments: &[HashSegment<K, F::Output>]) -> Vec<DiffRange<K>> {
+        diff::compare_segments(self, segments)
+    }
+}
+
+impl<K: Clone + Hash + Ord, V: Clone + Hash, F: FingerprintStrategy, const N: usize>
+    HRTree<K, V, F, N>
+{
+    /// Rebuild the tree to also maintain the hashes of another fingerprint strategy, so that the
+    /// instance can compare with the peers using either.
+    ///
+    /// All the hashes are computed again, in a single pass over the elements.
+    pub fn enable_secondary_fingerprint<S: FingerprintStrategy>(
+        self,
+    ) -> HRTree<K, V, DualFingerprint<F, S>, N>
+    where
+        DualFingerprint<F, S>: FingerprintStrategy,
+    {
+        HRTree::from_sorted_iter(self)
+    }
+}
+
+impl<
+        K: Clone + Hash + Ord,
+        V: Clone + Hash,
+        P: FingerprintStrategy,
+        S: FingerprintStrategy,
+        const N: usize,
+    > HRTree<K, V, DualFingerprint<P, S>, N>
+where
+    DualFingerprint<P, S>: FingerprintStrategy,
+{
+    /// Rebuild the tree to only maintain the hashes of the secondary fingerprint strategy, once
+    /// no peer uses the primary one anymore.
+    pub fn disable_primary_fingerprint(self) -> HRTree<K, V, S, N> {
+        HRTree::from_sorted_iter(self)
+    }
+}
+
+impl<K, V, F: FingerprintStrategy, const N: usize> HRTree<K, V, F, N> {
+    pub fn iter(&self) -> Iter<'_, K, V, F, N> {
         self.into_iter()
     }
+
+    /// Iterate over the keys, in order.
+    pub fn keys(&self) -> Keys<'_, K, V, F, N> {
+        Keys { inner: self.iter() }
+    }
+
+    /// Iterate over the values, in order of their keys.
+    pub fn values(&self) -> Values<'_, K, V, F, N> {
+        Values { inner: self.iter() }
+    }
+
+    /// Iterate over the elements in order, starting at the one at the given position.
+    ///
+    /// The iterator is positioned in `O(log n)`; it is empty if `index` is beyond the last element.
+    pub fn iter_at(&self, index: usize) -> Iter<'_, K, V, F, N> {
+        let remaining = self.root.tree_size.saturating_sub(index);
+        let mut stack = Vec::new();
+        let mut node = &*self.root;
+        let mut index = index;
+        // NOTE: the element at the top of the stack is the next one
+        'descend: while let Some(children) = node.children.as_ref() {
+            for i in 0..node.keys.len() {
+                if index < children[i].tree_size {
+                    // continue with the key after the sub-tree
+                    stack.push((node, i + 1));
+                    node = &children[i];
+                    continue 'descend;
+                }
+                // pass sub-tree
+                index -= children[i].tree_size;
+                if index == 0 {
+                    stack.push((node, i + 1));
+                    return Iter::new(&self.root, stack, remaining);
+                }
+                // pass key
+                index -= 1;
+            }
+            node = children.last().unwrap();
+        }
+        if index < node.keys.len() {
+            stack.push((node, index + 1));
+        }
+        Iter::new(&self.root, stack, remaining)
+    }
+
+    /// Iterate over the elements whose positions are within `start..end`, in order.
+    pub fn range_by_rank(&self, start: usize, end: usize) -> impl Iterator<Item = (&K, &V)> {
+        let end = end.min(self.root.tree_size);
+        self.iter_at(start).take(end.saturating_sub(start))
+    }
+
+    /// Number of levels of nodes, which is 1 when the root is the only node.
+    pub fn depth(&self) -> usize {
+        height(&self.root)
+    }
+
+    /// Describe the shape of the tree and estimate its memory usage, in a single traversal.
+    pub fn stats(&self) -> TreeStats {
+        fn aux<K, V, F: FingerprintStrategy, const N: usize>(
+            node: &Node<K, V, F, N>,
+            level: usize,
+            levels: &mut Vec<LevelStats>,
+        ) {
+            if levels.len() == level {
+                levels.push(LevelStats::default());
+            }
+            levels[level].nodes += 1;
+            levels[level].slots += N - 1;
+            levels[level].used_slots += node.keys.len();
+            for child in node.children.iter().flatten() {
+                aux(child, level + 1, levels);
+            }
+        }
+        let mut levels = Vec::new();
+        aux(&self.root, 0, &mut levels);
+        let nodes = levels.iter().map(|level| level.nodes).sum();
+        TreeStats {
+            nodes,
+            height: levels.len(),
+            slots: levels.iter().map(|level| level.slots).sum(),
+            used_slots: levels.iter().map(|level| level.used_slots).sum(),
+            bytes: nodes * std::mem::size_of::<Node<K, V, F, N>>(),
+            levels,
+        }
+    }
 }
 
-impl<K: std::fmt::Debug, V: std::fmt::Debug> std::fmt::Debug for HRTree<K, V> {
+/// Shape and memory usage of an [`HRTree`], returned by [`HRTree::stats`].
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct TreeStats {
+    /// Number of nodes
+    pub nodes: usize,
+    /// Number of levels of nodes, as returned by [`HRTree::depth`]
+    pub height: usize,
+    /// Number of key-value pairs the nodes can hold, `N - 1` each for an `HRTree<K, V, F, N>`
+    pub slots: usize,
+    /// Number of key-value pairs held
+    pub used_slots: usize,
+    /// Statistics of each level, from the root to the leaves
+    pub levels: Vec<LevelStats>,
+    /// Estimate of the memory used by the nodes, in bytes; the memory the keys and values own
+    /// outside of the tree (such as the content of a `String`) is not counted
+    pub bytes: usize,
+}
+
+impl TreeStats {
+    /// Proportion of the slots that hold a key-value pair; the nodes other than the root are at
+    /// least half full.
+    pub fn occupancy(&self) -> f64 {
+        self.used_slots as f64 / self.slots as f64
+    }
+}
+
+/// Statistics of the nodes at a given depth of an [`HRTree`].
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct LevelStats {
+    /// Number of nodes
+    pub nodes: usize,
+    /// Number of key-value pairs the nodes can hold
+    pub slots: usize,
+    /// Number of key-value pairs held
+    pub used_slots: usize,
+}
+
+impl<K: std::fmt::Debug, V: std::fmt::Debug, F: FingerprintStrategy, const N: usize> std::fmt::Debug
+    for HRTree<K, V, F, N>
+{
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
         f.debug_map().entries(self.iter()).finish()
     }
 }
 
-impl<K: Hash + Ord, V: Hash> HashRangeQueryable for HRTree<K, V> {
+impl<K: Hash + Ord, V: Hash, F: FingerprintStrategy, const N: usize> HashRangeQueryable
+    for HRTree<K, V, F, N>
+{
     type Key = K;
-    fn hash<R: RangeBounds<K>>(&self, range: &R) -> u64 {
-        fn aux<'a, K: Ord, V, R: RangeBounds<K>>(
-            node: &'a Node<K, V>,
+    type Fingerprint = F;
+    fn canonical_hashing(&self) -> bool {
+        self.hasher.is_some()
+    }
+    fn hash<R: RangeBounds<K>>(&self, range: &R) -> F::Output {
+        fn aux<'a, K: Ord, V, F: FingerprintStrategy, const N: usize, R: RangeBounds<K>>(
+            node: &'a Node<K, V, F, N>,
             range: &R,
             mut lower_bound: Option<&'a K>,
             upper_bound: Option<&K>,
-        ) -> u64 {
+        ) -> F::Output {
             // check if the lower-bound is included in the range
             let lower_bound_included = match range.start_bound() {
                 Bound::Unbounded => true,
@@ -719,7 +2176,7 @@ impl<K: Hash + Ord, V: Hash> HashRangeQueryable for HRTree<K, V> {
             }
             // otherwise, recurse in the relevant sub-trees
 
-            let mut cum_hash = 0;
+            let mut cum_hash = F::identity();
             let mut i = 0;
             while i < node.keys.len() && node.keys[i].range_cmp(range) == RangeOrdering::Below {
                 i += 1;
@@ -727,26 +2184,34 @@ impl<K: Hash + Ord, V: Hash> HashRangeQueryable for HRTree<K, V> {
             while i < node.keys.len() && node.keys[i].range_cmp(range) == RangeOrdering::Inside {
                 let cur_bound = Some(&node.keys[i]);
                 if let Some(children) = node.children.as_ref() {
-                    cum_hash ^= aux(&children[i], range, lower_bound, cur_bound);
+                    let child_hash = aux(&children[i], range, lower_bound, cur_bound);
+                    cum_hash = F::combine(cum_hash, child_hash);
                 }
-                cum_hash ^= node.hashes[i];
+                cum_hash = F::combine(cum_hash, node.hashes[i]);
                 lower_bound = cur_bound;
                 i += 1;
             }
             if let Some(children) = node.children.as_ref() {
-                cum_hash ^= aux(&children[i], range, lower_bound, upper_bound);
+                let child_hash = aux(&children[i], range, lower_bound, upper_bound);
+                cum_hash = F::combine(cum_hash, child_hash);
             }
             cum_hash
         }
         aux(&self.root, range, None, None)
     }
 
-    fn insertion_position(&self, key: &K) -> usize {
-        fn aux<K: Ord, V>(node: &Node<K, V>, key: &K) -> usize {
+    fn insertion_position<Q: Ord + ?Sized>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+    {
+        fn aux<K: Borrow<Q>, V, F: FingerprintStrategy, const N: usize, Q: Ord + ?Sized>(
+            node: &Node<K, V, F, N>,
+            key: &Q,
+        ) -> usize {
             if let Some(children) = node.children.as_ref() {
                 let mut index = 0;
                 for i in 0..node.keys.len() {
-                    let cmp = key.cmp(&node.keys[i]);
+                    let cmp = key.cmp(node.keys[i].borrow());
                     if cmp == Ordering::Less {
                         // recurse left to key
                         return index + aux(&children[i], key);
@@ -762,7 +2227,7 @@ impl<K: Hash + Ord, V: Hash> HashRangeQueryable for HRTree<K, V> {
                 }
                 index + aux(children.last().unwrap(), key)
             } else {
-                match node.keys.binary_search(key) {
+                match node.keys.binary_search_by(|k| k.borrow().cmp(key)) {
                     Ok(index) => index,
                     Err(index) => index,
                 }
@@ -772,7 +2237,10 @@ impl<K: Hash + Ord, V: Hash> HashRangeQueryable for HRTree<K, V> {
     }
 
     fn key_at(&self, index: usize) -> &K {
-        fn aux<K: Ord, V>(node: &Node<K, V>, mut index: usize) -> &K {
+        fn aux<K: Ord, V, F: FingerprintStrategy, const N: usize>(
+            node: &Node<K, V, F, N>,
+            mut index: usize,
+        ) -> &K {
             if let Some(children) = node.children.as_ref() {
                 for i in 0..node.keys.len() {
                     if index < children[i].tree_size {
@@ -793,26 +2261,95 @@ impl<K: Hash + Ord, V: Hash> HashRangeQueryable for HRTree<K, V> {
                 &node.keys[index]
             }
         }
-        aux(&self.root, index)
+        aux(&self.root, index)
+    }
+
+    fn len(&self) -> usize {
+        self.root.tree_size
+    }
+
+    fn root_hash(&self) -> F::Output {
+        HRTree::root_hash(self)
+    }
+
+    fn hash_of<Q: Ord + ?Sized>(&self, key: &Q) -> Option<F::Output>
+    where
+        K: Borrow<Q>,
+    {
+        HRTree::hash_of(self, key)
+    }
+}
+
+enum RangeRef<'a, R> {
+    Borrowed(&'a R),
+    Owned(R),
+}
+
+impl<R> RangeRef<'_, R> {
+    fn get(&self) -> &R {
+        match self {
+            RangeRef::Borrowed(range) => range,
+            RangeRef::Owned(range) => range,
+        }
     }
+}
 
-    fn len(&self) -> usize {
-        self.root.tree_size
+/// Where the key is relative to the range; unlike [`RangeComparable`], this accepts unsized keys.
+fn cmp_range<Q: Ord + ?Sized, R: RangeBounds<Q>>(key: &Q, range: &R) -> RangeOrdering {
+    let below = match range.start_bound() {
+        Bound::Included(start) => key < start,
+        Bound::Excluded(start) => key <= start,
+        Bound::Unbounded => false,
+    };
+    let above = match range.end_bound() {
+        Bound::Included(end) => key > end,
+        Bound::Excluded(end) => key >= end,
+        Bound::Unbounded => false,
+    };
+    if below {
+        RangeOrdering::Below
+    } else if above {
+        RangeOrdering::Above
+    } else {
+        RangeOrdering::Inside
     }
 }
 
-pub struct ItemRange<'a, K, V, R: RangeBounds<K>> {
-    range: &'a R,
-    stack: Vec<(&'a Node<K, V>, usize)>,
+/// Iterator over the elements whose keys are in a range of `Q`, a borrowed form of `K`.
+pub struct ItemRange<
+    'a,
+    K,
+    V,
+    R: RangeBounds<Q>,
+    F: FingerprintStrategy = DefaultFingerprint,
+    const N: usize = { 2 * B },
+    Q: ?Sized = K,
+> {
+    range: RangeRef<'a, R>,
+    stack: Vec<(&'a Node<K, V, F, N>, usize)>,
+    key: PhantomData<fn(&Q)>,
 }
 
-impl<'a, K: Ord, V, R: RangeBounds<K>> Iterator for ItemRange<'a, K, V, R> {
+impl<
+        'a,
+        K: Borrow<Q>,
+        V,
+        R: RangeBounds<Q>,
+        F: FingerprintStrategy,
+        const N: usize,
+        Q: Ord + ?Sized,
+    > Iterator for ItemRange<'a, K, V, R, F, N, Q>
+{
     type Item = (&'a K, &'a V);
     fn next(&mut self) -> Option<Self::Item> {
         if let Some((node, children_passed)) = self.stack.pop() {
             #[allow(clippy::collapsible_if)]
             if 0 < children_passed && children_passed <= node.keys.len() {
-                if !self.range.contains(&node.keys[children_passed - 1]) {
+                if !self
+                    .range
+                    .get()
+                    .contains(node.keys[children_passed - 1].borrow())
+                {
                     self.stack.clear();
                     return None;
                 }
@@ -837,14 +2374,61 @@ impl<'a, K: Ord, V, R: RangeBounds<K>> Iterator for ItemRange<'a, K, V, R> {
     }
 }
 
-impl<K: Ord, V> HRTree<K, V> {
-    pub fn get_range<'a, R: RangeBounds<K>>(&'a self, range: &'a R) -> ItemRange<'a, K, V, R> {
+impl<K: Ord, V, F: FingerprintStrategy, const N: usize> HRTree<K, V, F, N> {
+    pub fn get_range<'a, R: RangeBounds<K>>(
+        &'a self,
+        range: &'a R,
+    ) -> ItemRange<'a, K, V, R, F, N> {
+        ItemRange {
+            range: RangeRef::Borrowed(range),
+            stack: self.range_stack(range),
+            key: PhantomData,
+        }
+    }
+
+    /// Same as [`get_range`](HRTree::get_range), but takes ownership of the range, so that the
+    /// iterator only borrows the tree.
+    pub fn get_range_owned<R: RangeBounds<K>>(&self, range: R) -> ItemRange<'_, K, V, R, F, N> {
+        ItemRange {
+            stack: self.range_stack(&range),
+            range: RangeRef::Owned(range),
+            key: PhantomData,
+        }
+    }
+
+    /// Same as [`get_range_owned`](HRTree::get_range_owned), but the bounds may be any borrowed
+    /// form of `K`, as with [`BTreeMap::range`](std::collections::BTreeMap::range).
+    ///
+    /// Since [`RangeFull`](std::ops::RangeFull) is a range of any type, `Q` must be given
+    /// explicitly to iterate over the whole tree, which [`get_range`](HRTree::get_range) does not
+    /// require.
+    pub fn range<Q: Ord + ?Sized, R: RangeBounds<Q>>(
+        &self,
+        range: R,
+    ) -> ItemRange<'_, K, V, R, F, N, Q>
+    where
+        K: Borrow<Q>,
+    {
+        ItemRange {
+            stack: self.range_stack(&range),
+            range: RangeRef::Owned(range),
+            key: PhantomData,
+        }
+    }
+
+    fn range_stack<Q: Ord + ?Sized, R: RangeBounds<Q>>(
+        &self,
+        range: &R,
+    ) -> Vec<(&Node<K, V, F, N>, usize)>
+    where
+        K: Borrow<Q>,
+    {
         let mut stack = Vec::new();
         let mut node = self.root.as_ref();
         // traverse interior nodes
         'main_loop: while let Some(children) = node.children.as_ref() {
             for i in 0..node.keys.len() {
-                match node.keys[i].range_cmp(range) {
+                match cmp_range(node.keys[i].borrow(), range) {
                     RangeOrdering::Below => (),
                     RangeOrdering::Above => {
                         node = &children[i];
@@ -861,7 +2445,7 @@ impl<K: Ord, V> HRTree<K, V> {
         }
         // traverse leaf node
         for i in 0..node.keys.len() {
-            match node.keys[i].range_cmp(range) {
+            match cmp_range(node.keys[i].borrow(), range) {
                 RangeOrdering::Below => (),
                 RangeOrdering::Above => {
                     break;
@@ -872,19 +2456,21 @@ impl<K: Ord, V> HRTree<K, V> {
                 }
             }
         }
-        ItemRange { range, stack }
+        stack
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use std::ops::RangeBounds;
+    use std::cmp::Ordering;
+    use std::ops::{Bound, RangeBounds};
 
     use rand::{seq::SliceRandom, Rng, SeedableRng};
 
     use crate::diff::{Diffable, HashRangeQueryable};
+    use crate::fingerprint::{DefaultFingerprint, FingerprintStrategy, Sum128Fingerprint};
 
-    use super::HRTree;
+    use super::{height, HRTree, MergeStats, B};
 
     #[test]
     fn test_simple() {
@@ -929,6 +2515,61 @@ mod tests {
         tree.check_invariants();
         let hash4 = tree.hash(&..);
         assert_eq!(hash4, hash2);
+
+        // hashes of the elements
+        assert_eq!(tree.hash_of(&50), Some(hash1));
+        assert_eq!(tree.hash_of(&75), None);
+        let mut big_tree: HRTree<u64, u64> = (0..1000).map(|i| (i, i)).collect();
+        for key in [0, 1, 500, 999] {
+            assert_eq!(big_tree.hash_of(&key), Some(big_tree.hash(&(key..=key))));
+        }
+        big_tree.insert(500, 0);
+        assert_eq!(big_tree.hash_of(&500), Some(big_tree.hash(&(500..=500))));
+        assert_eq!(big_tree.hash_of(&1000), None);
+    }
+
+    #[test]
+    fn rank_iteration() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let mut tree = HRTree::new();
+        // churn, so that nodes are split and merged
+        for _ in 0..10_000 {
+            let key: u16 = rng.gen_range(0..4000);
+            if rng.gen_bool(0.3) {
+                tree.remove(&key);
+            } else {
+                tree.insert(key, key);
+            }
+        }
+        tree.check_invariants();
+        let items: Vec<_> = tree.iter().map(|(&k, &v)| (k, v)).collect();
+        assert_eq!(items.len(), tree.len());
+        for index in 0..tree.len() {
+            let (key, _) = tree.iter_at(index).next().unwrap();
+            assert_eq!(key, tree.key_at(index));
+            let page: Vec<_> = tree
+                .iter_at(index)
+                .take(20)
+                .map(|(&k, &v)| (k, v))
+                .collect();
+            assert_eq!(page, items[index..(index + 20).min(items.len())]);
+        }
+        for index in (0..tree.len()).step_by(97) {
+            assert!(tree
+                .iter_at(index)
+                .map(|(&k, &v)| (k, v))
+                .eq(items[index..].to_vec()));
+        }
+        assert_eq!(tree.iter_at(tree.len()).next(), None);
+        assert_eq!(tree.iter_at(tree.len() + 1).next(), None);
+
+        let len = tree.len();
+        let end: Vec<_> = tree.range_by_rank(len - 5, len + 10).collect();
+        assert_eq!(end.len(), 5);
+        assert_eq!(*end[0].0, items[len - 5].0);
+        assert_eq!(tree.range_by_rank(len + 1, len + 5).count(), 0);
+        assert_eq!(tree.range_by_rank(10, 5).count(), 0);
+        assert_eq!(HRTree::<u8, u8>::new().range_by_rank(0, 5).count(), 0);
     }
 
     #[test]
@@ -948,6 +2589,7 @@ mod tests {
             tree1.check_invariants();
             expected_hash ^= super::hash(&key, &value);
             assert_eq!(tree1.hash(&..), expected_hash);
+            assert_eq!(tree1.root_hash(), expected_hash);
             key_values.push((key, value));
         }
 
@@ -955,13 +2597,19 @@ mod tests {
         assert_eq!(tree1.get(&key_values[0].0), Some(&key_values[0].1));
 
         // test get_mut
-        tree1.get_mut(&rng.gen(), |v| assert_eq!(v, None));
+        assert!(tree1.get_mut(&rng.gen()).is_none());
         let key: u64 = rng.gen::<u64>();
         let value1: u64 = rng.gen();
         let value2: u64 = rng.gen();
         tree1.insert(key, value1);
-        tree1.get_mut(&key, |v| *v.unwrap() = value2);
+        {
+            let mut guard = tree1.get_mut(&key).unwrap();
+            assert_eq!(*guard.key(), key);
+            assert_eq!(*guard, value1);
+            *guard = value2;
+        }
         tree1.check_invariants();
+        assert_eq!(tree1.get(&key), Some(&value2));
         expected_hash ^= super::hash(&key, &value2);
         key_values.push((key, value2));
 
@@ -996,7 +2644,7 @@ mod tests {
             R: RangeBounds<u64>,
             SI: std::slice::SliceIndex<[(u64, u64)], Output = [(u64, u64)]>,
         >(
-            key_values: &Vec<(u64, u64)>,
+            key_values: &[(u64, u64)],
             tree: &HRTree<u64, u64>,
             range: R,
             slice_index: SI,
@@ -1031,12 +2679,12 @@ mod tests {
         let mut segments2 = Vec::new();
         while !segments1.is_empty() {
             tree2.diff_round(
-                segments1.drain(..).collect(),
+                std::mem::take(&mut segments1),
                 &mut segments2,
                 &mut diff_ranges2,
             );
             tree1.diff_round(
-                segments2.drain(..).collect(),
+                std::mem::take(&mut segments2),
                 &mut segments1,
                 &mut diff_ranges1,
             );
@@ -1057,6 +2705,530 @@ mod tests {
         }
     }
 
+    #[test]
+    fn range_queries() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let random_bound = |rng: &mut rand::rngs::StdRng| match rng.gen_range(0..3) {
+            0 => Bound::Unbounded,
+            1 => Bound::Included(rng.gen_range(0..1000u16)),
+            _ => Bound::Excluded(rng.gen_range(0..1000u16)),
+        };
+        for _ in 0..2000 {
+            // a dense key space, so that the bounds often fall on keys and node boundaries
+            let mut tree = HRTree::new();
+            for _ in 0..rng.gen_range(0..800) {
+                tree.insert(rng.gen_range(0..1000u16), rng.gen::<u32>());
+            }
+            for _ in 0..rng.gen_range(0..200) {
+                tree.remove(&rng.gen_range(0..1000u16));
+            }
+            let items: Vec<_> = tree.iter().map(|(k, v)| (*k, *v)).collect();
+            if rng.gen() {
+                // nodes are fuller when bulk-loaded
+                tree = HRTree::from_sorted_iter(items.iter().copied());
+            }
+            for _ in 0..20 {
+                // including empty and reversed ranges, and ranges without keys
+                let range = (random_bound(&mut rng), random_bound(&mut rng));
+                let expected: Vec<_> = items
+                    .iter()
+                    .copied()
+                    .filter(|(key, _)| range.contains(key))
+                    .collect();
+                let found: Vec<_> = tree.get_range(&range).map(|(k, v)| (*k, *v)).collect();
+                assert_eq!(found, expected, "{range:?}");
+                let found: Vec<_> = tree.get_range_owned(range).map(|(k, v)| (*k, *v)).collect();
+                assert_eq!(found, expected, "{range:?}");
+                let expected_hash = expected
+                    .iter()
+                    .fold(0, |acc, (key, value)| acc ^ super::hash(key, value));
+                assert_eq!(tree.hash(&range), expected_hash, "{range:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_remove_range() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        for size in [0, 1, 10, 100, 1000] {
+            for _ in 0..20 {
+                let mut key_values = Vec::new();
+                for _ in 0..size {
+                    key_values.push((rng.gen_range(0..10_000u64), rng.gen::<u64>()));
+                }
+                let mut tree = HRTree::new();
+                for (k, v) in key_values.iter().copied() {
+                    tree.insert(k, v);
+                }
+                let mut expected: std::collections::BTreeMap<_, _> =
+                    key_values.into_iter().collect();
+                let a = rng.gen_range(0..10_000u64);
+                let b = rng.gen_range(a..10_000u64);
+                let range = match rng.gen_range(0..5) {
+                    0 => (Bound::Included(a), Bound::Excluded(b)),
+                    1 => (Bound::Included(a), Bound::Included(b)),
+                    2 => (Bound::Unbounded, Bound::Excluded(b)),
+                    3 => (Bound::Excluded(a), Bound::Unbounded),
+                    _ => (Bound::Unbounded, Bound::Unbounded),
+                };
+                let removed = tree.remove_range(&range);
+                tree.check_invariants();
+                let expected_removed: Vec<_> =
+                    expected.range(range).map(|(&k, &v)| (k, v)).collect();
+                expected.retain(|k, _| !range.contains(k));
+                assert_eq!(removed, expected_removed);
+                assert_eq!(
+                    tree.iter().map(|(&k, &v)| (k, v)).collect::<Vec<_>>(),
+                    expected.into_iter().collect::<Vec<_>>()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_contains_key() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let mut tree: HRTree<u64, u64> = HRTree::new();
+        for key in 0..1000 {
+            tree.insert(key, rng.gen());
+        }
+        for key in 0..1000 {
+            assert!(tree.contains_key(&key));
+            let hash = tree.hash_of(&key).unwrap();
+            // the same value keeps the same hash
+            let value = *tree.get(&key).unwrap();
+            tree.insert(key, value);
+            assert_eq!(tree.hash_of(&key), Some(hash));
+            // another value changes it
+            tree.insert(key, value.wrapping_add(1));
+            assert_ne!(tree.hash_of(&key), Some(hash));
+        }
+        for key in (0..1000).step_by(2) {
+            tree.remove(&key);
+            assert!(!tree.contains_key(&key));
+            assert_eq!(tree.hash_of(&key), None);
+            assert!(tree.contains_key(&(key + 1)));
+        }
+        tree.check_invariants();
+        assert!(!tree.contains_key(&1000));
+    }
+
+    #[test]
+    fn test_height() {
+        // bounds of the height of a B-tree with the given number of elements
+        fn height_bounds(size: usize) -> (usize, usize) {
+            let mut min = 1;
+            while (2 * B).pow(min as u32) - 1 < size {
+                min += 1;
+            }
+            let mut max = 1;
+            while 2 * B.pow(max as u32) - 1 <= size {
+                max += 1;
+            }
+            (min, max)
+        }
+        let check = |tree: &HRTree<u64, u64>| {
+            tree.check_invariants();
+            let (min, max) = height_bounds(tree.len());
+            let height = height(&tree.root);
+            assert!(
+                (min..=max).contains(&height),
+                "height {height} of a tree of {} elements",
+                tree.len()
+            );
+        };
+
+        // sequential insertions and removals, in both orders
+        for reverse in [false, true] {
+            let mut tree = HRTree::new();
+            for key in 0..2000 {
+                tree.insert(key, key);
+                check(&tree);
+            }
+            for key in 0..2000 {
+                let key = if reverse { 1999 - key } else { key };
+                assert_eq!(tree.remove(&key), Some(key));
+                check(&tree);
+            }
+            assert_eq!(height(&tree.root), 1);
+        }
+
+        // random cycles of insertions and removals
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let mut tree = HRTree::new();
+        for _ in 0..10 {
+            for _ in 0..1000 {
+                let key = rng.gen_range(0..2000);
+                tree.insert(key, key);
+            }
+            check(&tree);
+            for _ in 0..rng.gen_range(0..tree.len() + 1) {
+                match rng.gen_range(0..4) {
+                    0 => {
+                        tree.pop_first();
+                    }
+                    1 => {
+                        tree.pop_last();
+                    }
+                    _ => {
+                        tree.remove(&rng.gen_range(0..2000));
+                    }
+                }
+                check(&tree);
+            }
+            let a = rng.gen_range(0..2000);
+            tree.remove_range(&(a..rng.gen_range(a..2000)));
+            check(&tree);
+        }
+    }
+
+    #[test]
+    fn test_first_last() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        for size in [0, 1, 10, 100, 1000] {
+            let mut tree = HRTree::new();
+            for _ in 0..size {
+                tree.insert(rng.gen_range(0..10_000u64), rng.gen::<u64>());
+            }
+            let mut expected: std::collections::BTreeMap<_, _> =
+                tree.iter().map(|(&k, &v)| (k, v)).collect();
+            while !expected.is_empty() {
+                assert_eq!(tree.first_key_value(), expected.first_key_value());
+                assert_eq!(tree.last_key_value(), expected.last_key_value());
+                let popped = if rng.gen() {
+                    (tree.pop_first(), expected.pop_first())
+                } else {
+                    (tree.pop_last(), expected.pop_last())
+                };
+                assert_eq!(popped.0, popped.1);
+                tree.check_invariants();
+                assert_eq!(tree.len(), expected.len());
+            }
+            assert_eq!(tree.first_key_value(), None);
+            assert_eq!(tree.last_key_value(), None);
+            assert_eq!(tree.pop_first(), None);
+            assert_eq!(tree.pop_last(), None);
+        }
+    }
+
+    #[test]
+    fn test_range_len() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        for size in [0, 1, 10, 100, 1000] {
+            let mut tree = HRTree::new();
+            for _ in 0..size {
+                tree.insert(rng.gen_range(0..1000u64), ());
+            }
+            let expected: std::collections::BTreeSet<_> = tree.iter().map(|(&k, _)| k).collect();
+            for _ in 0..100 {
+                let a = rng.gen_range(0..1000u64);
+                let b = rng.gen_range(a..1000u64);
+                let range = match rng.gen_range(0..6) {
+                    0 => (Bound::Included(a), Bound::Excluded(b)),
+                    1 => (Bound::Included(a), Bound::Included(b)),
+                    2 => (Bound::Excluded(a), Bound::Included(b)),
+                    3 => (Bound::Unbounded, Bound::Excluded(b)),
+                    4 => (Bound::Excluded(a), Bound::Unbounded),
+                    _ => (Bound::Unbounded, Bound::Unbounded),
+                };
+                assert_eq!(tree.range_len(&range), expected.range(range).count());
+            }
+        }
+    }
+
+    #[test]
+    fn test_borrowed_keys() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let tree: HRTree<String, u64> = (0..1000)
+            .map(|_| (format!("{:x}", rng.gen::<u32>()), rng.gen()))
+            .collect();
+        let expected: std::collections::BTreeMap<_, _> =
+            tree.iter().map(|(k, &v)| (k.clone(), v)).collect();
+        for (index, (key, value)) in expected.iter().enumerate() {
+            assert_eq!(tree.get(key.as_str()), Some(value));
+            assert_eq!(tree.position(key.as_str()), Some(index));
+            assert_eq!(tree.insertion_position(key.as_str()), index);
+        }
+        assert_eq!(tree.get("not hexadecimal"), None);
+        assert_eq!(tree.position("not hexadecimal"), None);
+        assert_eq!(tree.insertion_position(""), 0);
+        for (a, b) in [("1", "2"), ("8", "8a"), ("a", "b"), ("", "0")] {
+            assert!(tree
+                .range::<str, _>((Bound::Included(a), Bound::Excluded(b)))
+                .eq(expected.range::<str, _>((Bound::Included(a), Bound::Excluded(b)))));
+            let from = (Bound::Included(a), Bound::Unbounded);
+            assert!(tree
+                .range::<str, _>(from)
+                .eq(expected.range::<str, _>(from)));
+        }
+        assert_eq!(tree.range::<str, _>(..).count(), tree.len());
+
+        // composite keys: a prefix is a range of the full keys
+        let tree: HRTree<(String, u128), ()> = (0..1000)
+            .map(|_| ((format!("tenant{}", rng.gen_range(0..10)), rng.gen()), ()))
+            .collect();
+        tree.check_invariants();
+        let tenant = "tenant3".to_string();
+        let prefix = (tenant.clone(), 0)..=(tenant.clone(), u128::MAX);
+        let keys: Vec<_> = tree.get_range(&prefix).map(|(k, _)| k).collect();
+        assert!(!keys.is_empty());
+        assert!(keys.iter().all(|(t, _)| *t == tenant));
+        assert_eq!(
+            keys.len(),
+            tree.iter().filter(|((t, _), _)| *t == tenant).count()
+        );
+        assert_eq!(tree.range_len(&prefix), keys.len());
+        assert_eq!(tree.get(keys[0]), Some(&()));
+    }
+
+    #[test]
+    fn test_retain() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        for size in [0, 1, 10, 100, 1000] {
+            for threshold in [0, 1, 50, 99, 100] {
+                let mut tree = HRTree::new();
+                for _ in 0..size {
+                    tree.insert(rng.gen_range(0..10_000u64), rng.gen_range(0..100u64));
+                }
+                let mut expected: std::collections::BTreeMap<_, _> =
+                    tree.iter().map(|(&k, &v)| (k, v)).collect();
+                let removed = tree.retain(|_, &v| v < threshold);
+                tree.check_invariants();
+                let expected_removed: Vec<_> = expected
+                    .iter()
+                    .filter(|(_, &v)| v >= threshold)
+                    .map(|(&k, &v)| (k, v))
+                    .collect();
+                expected.retain(|_, v| *v < threshold);
+                assert_eq!(removed, expected_removed);
+                assert_eq!(
+                    tree.iter().map(|(&k, &v)| (k, v)).collect::<Vec<_>>(),
+                    expected.into_iter().collect::<Vec<_>>()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_serde() {
+        use bincode::{DefaultOptions, Options};
+
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        for size in [0, 1, 11, 12, 100, 1000, 10_000] {
+            let mut tree = HRTree::new();
+            for _ in 0..size {
+                tree.insert(rng.gen_range(0..100_000u64), rng.gen::<u32>());
+            }
+            let bytes = DefaultOptions::new().serialize(&tree).unwrap();
+            let copy: HRTree<u64, u32> = DefaultOptions::new().deserialize(&bytes).unwrap();
+            copy.check_invariants();
+            assert_eq!(copy.hash(&..), tree.hash(&..));
+            assert_eq!(copy.len(), tree.len());
+            assert!(copy.iter().eq(tree.iter()));
+        }
+
+        // the entries must be sorted, without duplicates
+        for entries in [vec![(2u64, 0u32), (1, 0)], vec![(1, 0), (1, 1)]] {
+            let bytes = DefaultOptions::new().serialize(&entries).unwrap();
+            let result: bincode::Result<HRTree<u64, u32>> =
+                DefaultOptions::new().deserialize(&bytes);
+            assert!(result.is_err());
+        }
+    }
+
+    #[test]
+    fn test_from_sorted_iter() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        for size in [0, 1, 5, 11, 12, 100, 143, 144, 1000, 1727, 1728, 10_000] {
+            let mut key_values: Vec<(u64, u64)> =
+                (0..size).map(|_| (rng.gen(), rng.gen())).collect();
+            key_values.sort();
+            let mut incremental = HRTree::new();
+            for (k, v) in key_values.iter().copied() {
+                incremental.insert(k, v);
+            }
+            let tree: HRTree<u64, u64> = HRTree::from_sorted_iter(key_values.iter().copied());
+            tree.check_invariants();
+            assert_eq!(tree.len(), key_values.len());
+            assert_eq!(tree.hash(&..), incremental.hash(&..));
+            if let Some(&(mid, _)) = key_values.get(size / 2) {
+                assert_eq!(tree.hash(&..mid), incremental.hash(&..mid));
+                assert_eq!(tree.hash(&(mid..)), incremental.hash(&(mid..)));
+            }
+            assert!(tree.iter().eq(incremental.iter()));
+        }
+
+        // the last value of a key wins
+        let tree = HRTree::from_iter([(2, 'a'), (1, 'b'), (2, 'c'), (1, 'd'), (3, 'e')]);
+        tree.check_invariants();
+        assert_eq!(
+            tree.iter().map(|(&k, &v)| (k, v)).collect::<Vec<_>>(),
+            vec![(1, 'd'), (2, 'c'), (3, 'e')]
+        );
+    }
+
+    #[test]
+    fn test_merge_from_sorted() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        // keep the largest value
+        let decide = |_: &u64, existing: &u64, new: &u64| new > existing;
+        for (tree_size, input_size) in [(0, 0), (0, 100), (100, 0), (10_000, 10), (1000, 1000)] {
+            for _ in 0..10 {
+                let tree: HRTree<u64, u64> = (0..tree_size)
+                    .map(|_| (rng.gen_range(0..2000), rng.gen()))
+                    .collect();
+                // with duplicate keys
+                let mut input: Vec<(u64, u64)> = (0..input_size)
+                    .map(|_| (rng.gen_range(0..2000), rng.gen()))
+                    .collect();
+                input.sort_by_key(|&(key, _)| key);
+
+                let mut naive = tree.clone();
+                let mut expected = MergeStats::default();
+                for &(key, value) in &input {
+                    match naive.get(&key) {
+                        None => expected.inserted += 1,
+                        Some(existing) if decide(&key, existing, &value) => {
+                            expected.overwritten += 1
+                        }
+                        Some(_) => {
+                            expected.skipped += 1;
+                            continue;
+                        }
+                    }
+                    naive.insert(key, value);
+                }
+
+                let mut merged = tree.clone();
+                let mut changes = Vec::new();
+                let stats = merged.merge_from_sorted_with(input, decide, |&k, &v, old| {
+                    changes.push((k, v, old.copied()))
+                });
+                merged.check_invariants();
+                assert_eq!(stats, expected);
+                assert_eq!(merged, naive);
+                assert!(merged.iter().eq(naive.iter()));
+                assert_eq!(changes.len(), stats.inserted + stats.overwritten);
+                // replaying the changes on the original tree gives the same result
+                let mut replayed = tree.clone();
+                for (k, v, old) in changes {
+                    assert_eq!(replayed.insert(k, v), old);
+                }
+                assert_eq!(replayed, naive);
+            }
+        }
+    }
+
+    #[test]
+    fn test_sum128_fingerprint() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let mut tree: HRTree<u64, u64, Sum128Fingerprint> = HRTree::default();
+        let mut key_values = Vec::new();
+        let mut expected_hash = 0u128;
+        for _ in 0..1000 {
+            let key: u64 = rng.gen();
+            let value: u64 = rng.gen();
+            tree.insert(key, value);
+            tree.check_invariants();
+            expected_hash = expected_hash.wrapping_add(Sum128Fingerprint::hash(&key, &value));
+            assert_eq!(tree.hash(&..), expected_hash);
+            key_values.push((key, value));
+        }
+        let mid = key_values[key_values.len() / 2].0;
+        assert_eq!(
+            tree.hash(&..mid).wrapping_add(tree.hash(&(mid..))),
+            tree.hash(&..)
+        );
+        key_values.shuffle(&mut rng);
+        for (key, value) in key_values {
+            assert_eq!(tree.remove(&key), Some(value));
+            tree.check_invariants();
+            expected_hash = expected_hash.wrapping_sub(Sum128Fingerprint::hash(&key, &value));
+            assert_eq!(tree.hash(&..), expected_hash);
+        }
+        assert_eq!(expected_hash, 0);
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let mut tree: HRTree<u64, u64> = HRTree::new();
+        let stats = tree.stats();
+        assert_eq!((stats.nodes, stats.height, stats.used_slots), (1, 1, 0));
+
+        // random workload
+        let mut keys: Vec<u64> = (0..10000).map(|_| rng.gen()).collect();
+        for &key in &keys {
+            tree.insert(key, key);
+        }
+        keys.shuffle(&mut rng);
+        for key in &keys[..5000] {
+            tree.remove(key);
+        }
+        for _ in 0..5000 {
+            let key = rng.gen();
+            tree.insert(key, key);
+        }
+        tree.check_invariants();
+
+        let stats = tree.stats();
+        assert_eq!(stats.used_slots, tree.len());
+        assert_eq!(stats.height, tree.depth());
+        assert_eq!(stats.levels.len(), tree.depth());
+        assert_eq!(stats.levels[0].nodes, 1);
+        assert_eq!(
+            stats.levels.iter().map(|level| level.nodes).sum::<usize>(),
+            stats.nodes
+        );
+        assert!(stats.occupancy() > 0.5, "{stats:?}");
+        assert!(stats.bytes > stats.used_slots * 3 * std::mem::size_of::<u64>());
+
+        // bulk loading fills the leaves
+        let tree: HRTree<u64, u64> = HRTree::from_sorted_iter((0..10000).map(|i| (i, i)));
+        assert!(tree.stats().occupancy() > 0.9);
+    }
+
+    #[test]
+    fn test_clone_on_write() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let original: HRTree<u64, u64> = (0..10000).map(|_| (rng.gen(), rng.gen())).collect();
+        let items: Vec<(u64, u64)> = original.iter().map(|(&k, &v)| (k, v)).collect();
+        let hash = original.hash(&..);
+
+        // the clone diverges through all the mutation paths
+        let mut clone = original.clone();
+        for _ in 0..1000 {
+            clone.insert(rng.gen(), rng.gen());
+        }
+        for (key, _) in items.choose_multiple(&mut rng, 1000) {
+            assert!(clone.remove(key).is_some());
+        }
+        *clone.get_mut(&items[5000].0).unwrap() += 1;
+        clone.pop_first();
+        clone.pop_last();
+        clone.remove_range(&(items[100].0..items[200].0));
+        clone.retain(|key, _| key % 7 != 0);
+        clone.check_invariants();
+        assert_ne!(clone.hash(&..), hash);
+
+        // the original is unaffected
+        original.check_invariants();
+        assert_eq!(original.hash(&..), hash);
+        assert_eq!(
+            original.iter().map(|(&k, &v)| (k, v)).collect::<Vec<_>>(),
+            items
+        );
+
+        // and conversely
+        let snapshot = clone.clone();
+        let clone_items: Vec<(u64, u64)> = clone.iter().map(|(&k, &v)| (k, v)).collect();
+        let mut original = original;
+        original.remove_range(&..);
+        assert!(original.is_empty());
+        snapshot.check_invariants();
+        assert_eq!(snapshot.into_iter().collect::<Vec<_>>(), clone_items);
+    }
+
     #[test]
     fn test_iter() {
         let mut rng = rand::rngs::StdRng::seed_from_u64(42);
@@ -1076,4 +3248,238 @@ mod tests {
         );
         assert_eq!(tree.into_iter().collect::<Vec<_>>(), key_values);
     }
+
+    #[test]
+    fn test_iter_sizes_and_reverse() {
+        fn check<const N: usize>(size: u64) {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(size);
+            let reference: std::collections::BTreeMap<u64, u64> =
+                (0..size).map(|_| (rng.gen(), rng.gen())).collect();
+            let mut tree: HRTree<u64, u64, DefaultFingerprint, N> =
+                HRTree::from_sorted_iter(reference.clone());
+            let len = reference.len();
+
+            // exact sizes
+            assert_eq!(tree.iter().len(), len);
+            assert_eq!(tree.keys().len(), len);
+            assert_eq!(tree.values().len(), len);
+            assert_eq!(tree.clone().into_iter().len(), len);
+            let mut iter = tree.iter();
+            for i in 0..len {
+                assert_eq!(iter.size_hint(), (len - i, Some(len - i)));
+                iter.next();
+            }
+            assert_eq!(iter.len(), 0);
+            assert_eq!(iter.next(), None);
+            let mut into_iter = tree.clone().into_iter();
+            into_iter.nth(len / 2);
+            assert_eq!(into_iter.len(), len - (len / 2 + 1).min(len));
+            assert_eq!(tree.iter_at(len / 3).len(), len - len / 3);
+            assert_eq!(tree.iter_at(len + 1).len(), 0);
+
+            // keys and values
+            assert!(tree.keys().eq(reference.keys()));
+            assert!(tree.values().eq(reference.values()));
+            assert!(tree.keys().rev().eq(reference.keys().rev()));
+            assert!(tree.values().rev().eq(reference.values().rev()));
+
+            // reverse order, and both ends meeting in the middle
+            assert!(tree.iter().rev().eq(reference.iter().rev()));
+            assert!(tree
+                .iter_at(len / 3)
+                .rev()
+                .eq(reference.iter().skip(len / 3).rev()));
+            let mut iter = tree.iter();
+            let mut expected = reference.iter();
+            loop {
+                let (item, expected_item) = if rng.gen() {
+                    (iter.next(), expected.next())
+                } else {
+                    (iter.next_back(), expected.next_back())
+                };
+                assert_eq!(item, expected_item);
+                assert_eq!(iter.len(), expected.len());
+                if item.is_none() {
+                    break;
+                }
+            }
+
+            // mutable values, with the tree shared with a clone
+            let snapshot = tree.clone();
+            for value in &mut tree.values_mut() {
+                *value = value.wrapping_add(1);
+            }
+            assert_eq!(tree.values_mut().iter().len(), len);
+            tree.check_invariants();
+            snapshot.check_invariants();
+            assert!(snapshot.iter().eq(reference.iter()));
+            let expected: std::collections::BTreeMap<u64, u64> = reference
+                .iter()
+                .map(|(&k, &v)| (k, v.wrapping_add(1)))
+                .collect();
+            assert!(tree.iter().eq(expected.iter()));
+            let rebuilt: HRTree<u64, u64, DefaultFingerprint, N> =
+                HRTree::from_sorted_iter(expected);
+            assert_eq!(tree.hash(&..), rebuilt.hash(&..));
+        }
+        for size in [0, 1, 3, 100, 5000] {
+            check::<4>(size);
+            check::<{ 2 * B }>(size);
+        }
+    }
+
+    #[test]
+    fn test_node_sizes() {
+        // same workload with the smallest nodes, the default ones, and large ones
+        fn check<const N: usize>() {
+            let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+            let mut reference = std::collections::BTreeMap::new();
+            let mut tree: HRTree<u64, u64, DefaultFingerprint, N> = HRTree::default();
+            // splits and rotations
+            for _ in 0..2000 {
+                let (key, value) = (rng.gen_range(0..4000), rng.gen());
+                assert_eq!(tree.insert(key, value), reference.insert(key, value));
+            }
+            tree.check_invariants();
+            // merges
+            for _ in 0..1000 {
+                let key = rng.gen_range(0..4000);
+                assert_eq!(tree.remove(&key), reference.remove(&key));
+            }
+            tree.check_invariants();
+            assert_eq!(tree.pop_first(), reference.pop_first());
+            assert_eq!(tree.pop_last(), reference.pop_last());
+            // splits and joins of whole sub-trees
+            let removed = tree.remove_range(&(1000..3000));
+            assert!(removed.iter().all(|(key, _)| (1000..3000).contains(key)));
+            reference.retain(|key, _| !(1000..3000).contains(key));
+            tree.check_invariants();
+            assert!(tree.iter().map(|(&k, &v)| (k, v)).eq(reference.clone()));
+            // the hashes do not depend on the shape of the tree
+            let default: HRTree<u64, u64> = reference.clone().into_iter().collect();
+            assert_eq!(tree.hash(&..), default.hash(&..));
+            assert_eq!(tree.hash(&(500..3500)), default.hash(&(500..3500)));
+            // bulk loading
+            for size in [0, 1, N - 1, N, N * N, 10_000] {
+                let tree: HRTree<u64, u64, DefaultFingerprint, N> =
+                    HRTree::from_sorted_iter((0..size as u64).map(|i| (i, i)));
+                tree.check_invariants();
+                assert_eq!(tree.len(), size);
+            }
+        }
+        check::<4>();
+        check::<{ 2 * B }>();
+        check::<64>();
+    }
+
+    #[test]
+    fn validation() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let mut tree: HRTree<u64, u64, DefaultFingerprint, 4> = HRTree::default().with_validation();
+        for _ in 0..1000 {
+            tree.insert(rng.gen_range(0..500), rng.gen());
+            tree.remove(&rng.gen_range(0..500));
+        }
+        tree.check_invariants();
+    }
+
+    /// Key whose ordering ignores its second field, while its equality does not
+    #[derive(Clone, Debug, Eq, Hash, PartialEq)]
+    struct Inconsistent(u64, u64);
+
+    impl PartialOrd for Inconsistent {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Inconsistent {
+        fn cmp(&self, other: &Self) -> Ordering {
+            self.0.cmp(&other.0)
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "key ordering is inconsistent with key equality")]
+    fn inconsistent_ordering() {
+        let mut tree: HRTree<Inconsistent, u64> = HRTree::default().with_validation();
+        tree.insert(Inconsistent(1, 0), 0);
+        tree.insert(Inconsistent(2, 0), 0);
+        tree.insert(Inconsistent(1, 1), 0);
+    }
+
+    /// Key whose ordering is not reflexive
+    #[derive(Clone, Debug, Eq, Hash, PartialEq)]
+    struct Irreflexive(u64);
+
+    impl PartialOrd for Irreflexive {
+        fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+            Some(self.cmp(other))
+        }
+    }
+
+    impl Ord for Irreflexive {
+        fn cmp(&self, _other: &Self) -> Ordering {
+            Ordering::Less
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "key ordering is not reflexive")]
+    fn irreflexive_ordering() {
+        let mut tree: HRTree<Irreflexive, u64> = HRTree::default().with_validation();
+        tree.insert(Irreflexive(1), 0);
+    }
+
+    #[test]
+    fn canonical_hashing() {
+        use serde::Serialize;
+        use std::hash::{Hash, Hasher};
+
+        /// Serialized as a `String`, but not hashed as one
+        #[derive(Clone, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+        #[serde(transparent)]
+        struct Name(String);
+
+        impl Hash for Name {
+            fn hash<H: Hasher>(&self, state: &mut H) {
+                state.write(self.0.as_bytes());
+            }
+        }
+
+        let names = |range: std::ops::Range<u32>| range.map(|i| format!("{i:05}"));
+        let strings: HRTree<String, u32> = names(0..1000).map(|name| (name, 1)).collect();
+        let wrapped: HRTree<Name, u32> =
+            HRTree::from_sorted_iter(names(0..1000).map(|name| (Name(name), 1)));
+        assert_ne!(strings.root_hash(), wrapped.root_hash());
+        let mut strings = strings.with_canonical_hashing();
+        let mut wrapped = wrapped.with_canonical_hashing();
+        assert!(strings.canonical_hashing());
+        assert_eq!(strings.root_hash(), wrapped.root_hash());
+        strings.check_invariants();
+
+        // the updates keep hashing the serialized elements
+        strings.insert(format!("{:05}", 10), 2);
+        wrapped.insert(Name(format!("{:05}", 10)), 2);
+        *strings.get_mut(&format!("{:05}", 20)).unwrap() = 3;
+        *wrapped.get_mut(&Name(format!("{:05}", 20))).unwrap() = 3;
+        for value in &mut strings.values_mut() {
+            *value += 1;
+        }
+        for value in &mut wrapped.values_mut() {
+            *value += 1;
+        }
+        strings.merge_from_sorted(names(500..2000).map(|name| (name, 5)), |_, _, _| true);
+        wrapped.merge_from_sorted(names(500..2000).map(|name| (Name(name), 5)), |_, _, _| true);
+        strings.retain(|key, _| !key.ends_with('7'));
+        wrapped.retain(|key, _| !key.0.ends_with('7'));
+        strings.check_invariants();
+        wrapped.check_invariants();
+        assert_eq!(strings.root_hash(), wrapped.root_hash());
+
+        // a clone keeps the mode
+        let mut clone = strings.clone();
+        clone.insert(format!("{:05}", 3000), 0);
+        clone.check_invariants();
+    }
 }
@@ -9,333 +9,3846 @@
 //! Provides the [`InternalService`], the inner layer of the [`Service`](crate::service::Service)
 //! that handles communication between instances at the network level.
 
-use std::collections::HashMap;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
+use std::future::Future;
 use std::hash::Hash;
 use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicBool, AtomicI64, Ordering};
 use std::sync::Arc;
 use std::time::{Duration, Instant};
 
-use bincode::{DefaultOptions, Deserializer, Serializer};
+use bincode::{DefaultOptions, Options};
+use chrono::{DateTime, TimeZone, Utc};
 use ipnet::IpNet;
 use parking_lot::RwLock;
 use rand::rngs::StdRng;
-use rand::SeedableRng;
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
-use tokio::net::{ToSocketAddrs, UdpSocket};
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use serde::{de::DeserializeOwned, ser::SerializeTupleVariant, Deserialize, Serialize};
+use tokio::net::UdpSocket;
+use tokio::sync::{mpsc, watch};
 use tokio::time::timeout;
 use tracing::{debug, trace, warn};
 
-use crate::diff::Diffable;
-use crate::gen_ip::gen_ip;
+use crate::auth::{AuthKey, AuthTransport, AUTH_MAGIC, AUTH_TAG_SIZE};
+use crate::backlog::Backlog;
+use crate::broadcast::{BroadcastOverflow, BroadcastQueue, BroadcastTicket};
+use crate::chunk::{ChunkHash, ChunkStore};
+use crate::clock::{Clock, MonotonicClock};
+use crate::compression::{self, Compression, COMPRESSED, MAX_DECOMPRESSED_SIZE};
+use crate::diff::{DiffTuning, Diffable, FingerprintOf, HashRangeQueryable, SplitStrategy};
+use crate::discovery::{Discovery, RandomSubnet};
+use crate::divergence::Divergences;
+use crate::engine::resolve_update;
+use crate::error::Error;
+use crate::fingerprint::{canonical_hash, DefaultFingerprint, FingerprintStrategy};
+use crate::fragment::{message_id, Reassembly, FRAGMENT_OVERHEAD, FRAGMENT_SIZE, MAX_FRAGMENTS};
+use crate::handshake::{Handshakes, Hello};
+use crate::hrtree::MergeStats;
+use crate::journal::DeletionJournal;
 use crate::map::Map;
-use crate::reconcilable::{Reconcilable, ReconciliationResult};
+use crate::metrics::ServiceMetrics;
+use crate::oversize::{DatagramBudgets, NackReason, Oversize, MIN_DATAGRAM_SIZE};
+use crate::rate_limit::{RateLimiter, UpdateBudget};
+use crate::recent_writes::RecentWrites;
+use crate::reconcilable::{ConflictPolicy, LwwPolicy, Reconcilable, Resolution};
+use crate::replay::{Duplicates, DEFAULT_DEDUP_WINDOW};
+use crate::root_watch::RootWatch;
+use crate::session::Sessions;
+use crate::skew::ClockOffsets;
+use crate::transport::Transport;
 
-const BUFFER_SIZE: usize = 65507;
-const ACTIVITY_TIMEOUT: Duration = Duration::from_secs(1);
-const PEER_EXPIRATION: Duration = Duration::from_secs(60);
+pub(crate) const BUFFER_SIZE: usize = 65507;
+/// Start of all the datagrams of the protocol
+pub(crate) const MAGIC: [u8; 2] = *b"RC";
+/// Version of the wire format, after the magic number in each datagram
+const PROTOCOL_VERSION: u8 = 2;
+pub(crate) const HEADER: [u8; 3] = [MAGIC[0], MAGIC[1], PROTOCOL_VERSION];
+/// Header of the datagrams whose messages are compressed
+const COMPRESSED_HEADER: [u8; 3] = [MAGIC[0], MAGIC[1], PROTOCOL_VERSION | COMPRESSED];
+/// Number of variants of [`Message`]; messages with another tag are skipped, so that new variants
+/// can be added without breaking older instances
+const MESSAGE_TAGS: u8 = 16;
+/// Tag of [`Message::Namespace`]
+const NAMESPACE_TAG: u8 = 5;
+/// Tag of [`Message::SchemaUpdate`]
+const SCHEMA_UPDATE_TAG: u32 = 15;
+/// Maximum size of the datagrams built by the service, leaving room for the authentication tag
+pub(crate) const MAX_DATAGRAM_SIZE: usize = BUFFER_SIZE - AUTH_TAG_SIZE;
+/// Maximum size of a message, with its length, in a datagram along with the header
+pub(crate) const MAX_MESSAGE_SIZE: usize = MAX_DATAGRAM_SIZE - HEADER.len();
+const DEFAULT_ACTIVITY_TIMEOUT: Duration = Duration::from_secs(1);
+const DEFAULT_PEER_EXPIRATION: Duration = Duration::from_secs(60);
+const PEER_GOSSIP_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_ADVERTISED_PEERS: usize = 128;
+const DEFAULT_MAX_CONCURRENT_SESSIONS: usize = 8;
+const DEFAULT_PARANOIA_INTERVAL: Duration = Duration::from_secs(60);
+const DEFAULT_SESSION_DEADLINE: Duration = Duration::from_secs(30);
 
 const MAX_SENDTO_RETRIES: u32 = 4;
+/// Maximum number of updates enumerated while holding the read lock on the map
+const ENUMERATION_CHUNK: usize = 1000;
+const DEFAULT_BROADCAST_CAPACITY: usize = 10000;
+/// Number of updates received applied under a single write lock
+const DEFAULT_UPDATE_CHUNK: usize = 1024;
+/// Maximum number of bytes of updates sent to a peer in reply to a diff round; the remaining
+/// differences are found again by the next reconciliation sessions
+const MAX_ROUND_BYTES: usize = 1 << 20;
+/// Maximum number of segments deferred in a session; the remaining differences are found again
+/// by the next reconciliation sessions
+const MAX_DEFERRED_SEGMENTS: usize = 4096;
 
-type PreInsertCallback<K, V> = Box<dyn Send + Sync + Fn(&K, &V)>;
+/// Called with the key, the new value, the previous value and the origin of the change, while
+/// holding the write lock
+type PreInsertCallback<K, V> = Box<dyn Send + Sync + Fn(&K, &V, Option<&V>, ChangeOrigin)>;
+/// Called with the key, the new value and the previous value, after releasing the write lock
+type PostInsertCallback<K, V> = Option<Box<dyn Send + Sync + Fn(&K, &V, Option<&V>)>>;
+/// Called with a batch of changes, their origin and the global hash of the map right after the
+/// batch, after releasing the write lock
+type ChangesCallback<M> = Option<
+    Box<
+        dyn Send
+            + Sync
+            + Fn(
+                &[(
+                    <M as Map>::Key,
+                    <M as Map>::Value,
+                    Option<<M as Map>::Value>,
+                )],
+                ChangeOrigin,
+                FingerprintOf<M>,
+            ),
+    >,
+>;
+/// Called after a batch of changes, to write what was staged while the map was locked
+type FlushCallback = Option<Box<dyn Send + Sync + Fn()>>;
+/// Called with the service, a batch of changes, their origin and the global hash of the map right
+/// after the batch, after the change feed
+type PostBatchCallback<M> = Option<
+    Box<
+        dyn Send
+            + Sync
+            + Fn(
+                &InternalService<M>,
+                &[(
+                    <M as Map>::Key,
+                    <M as Map>::Value,
+                    Option<<M as Map>::Value>,
+                )],
+                ChangeOrigin,
+                FingerprintOf<M>,
+            ),
+    >,
+>;
+/// Origin of the value of each key, shared between the clones of the service
+type Origins<K> = Option<Arc<RwLock<HashMap<K, Origin>>>>;
+/// For each pending request of the latest version of a key, when it expires, and where to pass
+/// the responses
+type KeyRequests<K, V> = HashMap<u64, (Instant, mpsc::UnboundedSender<(SocketAddr, K, V)>)>;
+/// Called with each key-value pair received from a peer, which is discarded if it returns false
+type UpdateFilter<K, V> = Option<Box<dyn Send + Sync + Fn(&K, &V) -> bool>>;
+/// Called with the sender, the schema id and the bytes of each message received that could not
+/// be deserialized, or was tagged with another schema id
+type UndecodableHook = Option<Box<dyn Send + Sync + Fn(SocketAddr, Option<u64>, &[u8])>>;
+/// Lists the chunks referenced by a value, for the values stored in chunks
+type ChunkRefs<V> = Option<Box<dyn Send + Sync + Fn(&V) -> Vec<ChunkHash>>>;
+/// New values, along with the previous ones, to pass to the post-insertion callback
+type Inserted<K, V> = Vec<(K, V, Option<V>)>;
+/// For each peer, the versions of the key-value pairs it acknowledged
+type PeerAcks<K> = HashMap<SocketAddr, HashMap<K, u64>>;
+/// For each peer, the last global hash found equal to its own, and when it was last checked
+type Confirmed<H> = HashMap<SocketAddr, (H, Instant)>;
+/// Versions of the key-value pairs removed after being acknowledged, and when they were removed
+type Collected<K> = HashMap<K, (u64, Instant)>;
+/// For each peer and reply session id, the segments received but not compared yet, because the
+/// reply would not have fit in a single datagram, along with the fingerprint of the session
+type Deferred<C> = HashMap<(SocketAddr, u64), (u8, VecDeque<C>)>;
+/// Peers, along with the updates to send to each of them
+type PeerGroup<'a, K, V> = (Vec<SocketAddr>, Vec<UpdateRef<'a, K, V>>);
+
+/// Ranges of keys synchronized with the peers.
+struct SyncRanges<D> {
+    /// Range specific to a peer
+    peers: HashMap<SocketAddr, D>,
+    /// Range for the other peers; all the keys if `None`
+    default: Option<D>,
+}
+
+impl<D> SyncRanges<D> {
+    fn get(&self, peer: SocketAddr) -> Option<&D> {
+        self.peers.get(&peer).or(self.default.as_ref())
+    }
+}
+
+/// Range of keys reconciled with each peer before the other keys.
+struct PriorityRange<D> {
+    /// Compared alone until it matches the peer; all the keys are compared at once if `None`
+    range: Option<D>,
+    /// Peers the range matched
+    synced: HashSet<SocketAddr>,
+    /// Id of the last session over the range with each peer
+    sessions: HashMap<SocketAddr, u64>,
+}
+
+/// Notification that a diff round with a peer found no difference.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Convergence<H = u64> {
+    /// Address of the peer whose comparison items all matched the local map
+    pub peer: SocketAddr,
+    /// Global hash of the local map at the moment of convergence
+    pub hash: H,
+}
+
+/// Hash and size of the map, read at the same instant, as returned by
+/// [`Service::summary`](crate::Service::summary).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MapSummary<H> {
+    /// Global hash of the map
+    pub root_hash: H,
+    /// Number of elements in the map, tombstones included
+    pub len: usize,
+    /// Time of the last change to the map, local or received from a peer, or of the creation of
+    /// the service
+    pub last_change: DateTime<Utc>,
+}
+
+/// Progress of the reconciliation with the peers, as returned by
+/// [`Service::sync_progress`](crate::Service::sync_progress).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct SyncProgress {
+    /// Largest number of elements in the map of a peer, as advertised at the start of the last
+    /// session it opened; `None` until a peer opens a session over all the keys
+    pub known_remote_size: Option<usize>,
+    /// Number of elements in the local map, tombstones included
+    pub local_size: usize,
+    /// Number of ranges found to differ in the last diff round with each known peer, and still
+    /// being reconciled
+    pub differing_ranges: usize,
+    /// Number of key-value pairs received from peers and inserted in the local map
+    pub updates_applied: u64,
+}
+
+/// State of the reconciliation with each peer, from which the [`SyncProgress`] is computed.
+#[derive(Default)]
+struct PeerProgress {
+    /// Number of elements in the map of the peer, from its last opening segments
+    remote_sizes: HashMap<SocketAddr, usize>,
+    /// Number of ranges found to differ in the last diff round with the peer
+    differing_ranges: HashMap<SocketAddr, usize>,
+}
+
+/// Where a change to the map comes from.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChangeOrigin {
+    /// Written through the local service
+    Local,
+    /// Received in a datagram from the peer with the given address
+    Peer(SocketAddr),
+}
+
+/// Where the current value of a key comes from, as returned by
+/// [`Service::origin_of`](crate::Service::origin_of).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Origin {
+    /// Whether the value was written locally or received from a peer
+    pub source: ChangeOrigin,
+    /// When the value was stored in the map
+    pub applied_at: DateTime<Utc>,
+}
+
+/// State of a known peer.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct PeerInfo {
+    /// Address of the peer
+    pub addr: SocketAddr,
+    /// Time since the last datagram received from the peer; peers learned from other peers count
+    /// as seen half the expiration delay ago
+    pub last_seen: Duration,
+    /// Number of key-value pairs received from the peer and inserted in the local map, since it
+    /// became known
+    pub updates_applied: u64,
+    /// Number of datagrams received from the peer and dropped because they did not fit in the
+    /// receive buffer, since it became known
+    pub oversize_datagrams: u64,
+    /// Whether the peer uses another protocol version, fingerprint or schema, in which case it is
+    /// not reconciled with
+    pub incompatible: bool,
+}
+
+/// Conditions that keep the service from converging with its peers, as returned by
+/// [`Service::health`](crate::Service::health).
+#[derive(Clone, Debug, Default)]
+pub struct Health {
+    /// Error of the last datagram that could not be sent, unless one was sent successfully since
+    pub last_error: Option<Arc<Error>>,
+    /// Peers whose last datagrams did not fit in the receive buffer, with whether they were asked
+    /// for smaller datagrams
+    pub oversize_peers: Vec<(SocketAddr, bool)>,
+    /// Peers that asked for smaller datagrams, with the size of the datagrams sent to them
+    pub narrowed_peers: Vec<(SocketAddr, usize)>,
+}
+
+impl Health {
+    /// Whether nothing keeps the service from converging; the peers sent smaller datagrams still
+    /// converge.
+    pub fn is_healthy(&self) -> bool {
+        self.last_error.is_none() && self.oversize_peers.is_empty()
+    }
+}
+
+/// Range of keys that kept differing with a peer, as returned by
+/// [`Service::divergences`](crate::Service::divergences).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DivergenceInfo<D> {
+    /// Address of the peer
+    pub peer: SocketAddr,
+    /// Range found to differ in the last diff rounds with the peer
+    pub range: D,
+    /// Time since a range overlapping this one was first found to differ
+    pub first_seen: Duration,
+    /// Time since the range was last found to differ
+    pub last_seen: Duration,
+}
 
 /// The internal service at the network level.
 /// This struct does not handle removals, which are managed by the external layer.
 /// For more information, see [`Service`](crate::service::Service).
-pub(crate) struct InternalService<M: Map> {
+pub(crate) struct InternalService<M: Map + HashRangeQueryable> {
     pub(crate) map: Arc<RwLock<M>>,
-    port: u16,
-    socket: Arc<UdpSocket>,
-    peer_net: IpNet,
+    /// One socket, or two sockets of different address families
+    sockets: Arc<Vec<Box<dyn Transport>>>,
+    /// Datagrams received before the service runs
+    backlog: Arc<Backlog>,
+    /// Finds the addresses to probe, besides the known peers
+    discovery: Arc<tokio::sync::Mutex<Box<dyn Discovery>>>,
     rng: Arc<RwLock<StdRng>>,
-    pub(crate) peers: Arc<RwLock<HashMap<IpAddr, Instant>>>,
-    pub(crate) pre_insert: Arc<RwLock<PreInsertCallback<M::Key, M::Value>>>,
+    pub(crate) peers: Arc<RwLock<HashMap<SocketAddr, Instant>>>,
+    /// Hosts whose datagrams are ignored, until the given instant
+    bans: Arc<RwLock<HashMap<IpAddr, Instant>>>,
+    pub(crate) pre_insert: Arc<RwLock<PreInsertCallback<<M as Map>::Key, M::Value>>>,
+    /// Origin of the value of each key, when tracked
+    origins: Origins<<M as Map>::Key>,
+    pub(crate) post_insert: Arc<RwLock<PostInsertCallback<<M as Map>::Key, M::Value>>>,
+    pub(crate) on_changes: Arc<RwLock<ChangesCallback<M>>>,
+    pub(crate) post_batch: Arc<RwLock<PostBatchCallback<M>>>,
+    /// Called after each batch of changes once the write lock is released, before the other
+    /// callbacks, to write what was staged while it was held
+    pub(crate) flush_batch: Arc<RwLock<FlushCallback>>,
+    pub(crate) update_filter: Arc<RwLock<UpdateFilter<<M as Map>::Key, M::Value>>>,
+    pub(crate) undecodable_hook: Arc<RwLock<UndecodableHook>>,
+    /// Settles the conflicts between the local values and the ones received from the peers
+    pub(crate) policy: Arc<dyn ConflictPolicy<M::Value>>,
+    /// Source of the current time, sent to the peers to estimate the skew between the clocks
+    pub(crate) clock: Arc<dyn Clock>,
+    /// Time of the last change to the map, in milliseconds since the epoch; only updated while
+    /// holding the write lock on the map
+    last_change: Arc<AtomicI64>,
+    clock_offsets: Arc<RwLock<ClockOffsets>>,
+    convergence: Arc<watch::Sender<Option<Convergence<FingerprintOf<M>>>>>,
+    confirmed: Arc<RwLock<Confirmed<FingerprintOf<M>>>>,
+    pub(crate) metrics: Arc<ServiceMetrics>,
+    limiter: Arc<RateLimiter>,
+    update_budget: Arc<UpdateBudget>,
+    pub(crate) compression: Compression,
+    acks: Arc<RwLock<PeerAcks<<M as Map>::Key>>>,
+    /// Peers heard from, which must all acknowledge a tombstone before it is removed; unlike the
+    /// known peers they do not expire, and are only forgotten when removed or banned
+    ack_peers: Arc<RwLock<HashSet<SocketAddr>>>,
+    collected: Arc<RwLock<Collected<<M as Map>::Key>>>,
+    sessions: Arc<RwLock<Sessions>>,
+    recent_writes: Arc<RwLock<RecentWrites<<M as Map>::Key>>>,
+    /// Recent deletions, kept after their tombstones are removed from the map
+    pub(crate) deletions: Arc<RwLock<DeletionJournal<<M as Map>::Key, M::Value>>>,
+    broadcast_queue: Arc<BroadcastQueue<(<M as Map>::Key, M::Value)>>,
+    /// Whether the synchronization with the peers is paused
+    paused: Arc<AtomicBool>,
+    reassembly: Arc<RwLock<Reassembly>>,
+    /// Digests of the last datagrams received from each peer
+    duplicates: Arc<RwLock<Duplicates>>,
+    /// Size of the largest datagram received
+    recv_buffer_size: usize,
+    /// Peers whose last datagrams were too large for the receive buffer
+    oversize: Arc<RwLock<Oversize>>,
+    /// Maximum size of the datagrams sent to the peers
+    budgets: Arc<DatagramBudgets>,
+    sync_ranges: Arc<RwLock<SyncRanges<<M as Map>::DifferenceItem>>>,
+    /// Ranges found to differ with each peer, and since when
+    divergences: Arc<RwLock<Divergences<<M as Map>::DifferenceItem>>>,
+    /// When the global hashes last matched with each peer
+    pub(crate) root_watch: Arc<RwLock<RootWatch>>,
+    progress: Arc<RwLock<PeerProgress>>,
+    priority: Arc<RwLock<PriorityRange<<M as Map>::DifferenceItem>>>,
+    key_requests: Arc<RwLock<KeyRequests<<M as Map>::Key, M::Value>>>,
+    /// Fingerprints advertised by each peer
+    peer_fingerprints: Arc<RwLock<HashMap<SocketAddr, Vec<u8>>>>,
+    /// Identifies the types of the keys and values, which must match the one of the peers
+    pub(crate) schema_id: u64,
+    /// Whether the updates sent are tagged with the schema id
+    pub(crate) schema_tags: bool,
+    /// Whether each peer that sent a hello is compatible
+    handshakes: Arc<RwLock<Handshakes>>,
+    /// Chunks of the values, when they are stored in chunks
+    pub(crate) chunks: Arc<RwLock<ChunkStore>>,
+    pub(crate) chunk_refs: Arc<RwLock<ChunkRefs<M::Value>>>,
+    pub(crate) max_concurrent_sessions: usize,
+    pub(crate) update_chunk: usize,
+    pub(crate) activity_timeout: Duration,
+    pub(crate) peer_expiration: Duration,
+    pub(crate) paranoia_interval: Duration,
+    pub(crate) session_deadline: Duration,
+    pub(crate) diff_tuning: DiffTuning,
 }
 
-impl<M: Map> Clone for InternalService<M> {
+impl<M: Map + HashRangeQueryable> Clone for InternalService<M> {
     fn clone(&self) -> Self {
         InternalService {
             map: self.map.clone(),
-            port: self.port,
-            socket: self.socket.clone(),
-            peer_net: self.peer_net,
+            sockets: self.sockets.clone(),
+            backlog: self.backlog.clone(),
+            discovery: self.discovery.clone(),
             rng: self.rng.clone(),
             peers: self.peers.clone(),
+            bans: self.bans.clone(),
             pre_insert: self.pre_insert.clone(),
+            origins: self.origins.clone(),
+            post_insert: self.post_insert.clone(),
+            on_changes: self.on_changes.clone(),
+            post_batch: self.post_batch.clone(),
+            flush_batch: self.flush_batch.clone(),
+            update_filter: self.update_filter.clone(),
+            undecodable_hook: self.undecodable_hook.clone(),
+            policy: self.policy.clone(),
+            clock: self.clock.clone(),
+            last_change: self.last_change.clone(),
+            clock_offsets: self.clock_offsets.clone(),
+            convergence: self.convergence.clone(),
+            confirmed: self.confirmed.clone(),
+            metrics: self.metrics.clone(),
+            limiter: self.limiter.clone(),
+            update_budget: self.update_budget.clone(),
+            compression: self.compression,
+            acks: self.acks.clone(),
+            ack_peers: self.ack_peers.clone(),
+            collected: self.collected.clone(),
+            sessions: self.sessions.clone(),
+            recent_writes: self.recent_writes.clone(),
+            deletions: self.deletions.clone(),
+            broadcast_queue: self.broadcast_queue.clone(),
+            paused: self.paused.clone(),
+            reassembly: self.reassembly.clone(),
+            duplicates: self.duplicates.clone(),
+            recv_buffer_size: self.recv_buffer_size,
+            oversize: self.oversize.clone(),
+            budgets: self.budgets.clone(),
+            sync_ranges: self.sync_ranges.clone(),
+            divergences: self.divergences.clone(),
+            root_watch: self.root_watch.clone(),
+            progress: self.progress.clone(),
+            priority: self.priority.clone(),
+            key_requests: self.key_requests.clone(),
+            peer_fingerprints: self.peer_fingerprints.clone(),
+            schema_id: self.schema_id,
+            schema_tags: self.schema_tags,
+            handshakes: self.handshakes.clone(),
+            chunks: self.chunks.clone(),
+            chunk_refs: self.chunk_refs.clone(),
+            max_concurrent_sessions: self.max_concurrent_sessions,
+            update_chunk: self.update_chunk,
+            activity_timeout: self.activity_timeout,
+            peer_expiration: self.peer_expiration,
+            paranoia_interval: self.paranoia_interval,
+            session_deadline: self.session_deadline,
+            diff_tuning: self.diff_tuning,
         }
     }
 }
 
 /// Represent an atomic message for the reconciliation protocol.
+///
+/// In a datagram, each message is prefixed by its length, so that unknown ones can be skipped.
+/// New variants must be added at the end, and [`MESSAGE_TAGS`] updated.
 #[derive(Clone, Debug, Deserialize, Serialize)]
-enum Message<K: Serialize, V: Serialize, C: Serialize> {
+pub(crate) enum Message<K: Serialize, V: Serialize, C: Serialize> {
     /// Provides information about a set of keys that allows checking
-    /// whether there are differences between the two instances over this set
-    ComparisonItem(C),
+    /// whether there are differences between the two instances over this set,
+    /// along with the id of the reconciliation session
+    ComparisonItem(u64, C),
     /// Provides an individual key-value pair when the protocol
     /// has identified that it differs on the two instances
     Update((K, V)),
+    /// Provides addresses of other instances, listening on the same port as the receiver; only
+    /// sent by older instances, see [`PeerAddrs`](Message::PeerAddrs)
+    Peers(Vec<IpAddr>),
+    /// Signals that the sender holds the version of the key-value pair
+    /// with the given [`version_hash`]
+    Ack(K, u64),
+    /// Provides a part of a serialized message too large for a datagram, with the id of the
+    /// message, the index of the part and the number of parts; only updates are fragmented
+    Fragment(u64, u16, u16, Vec<u8>),
+    /// Signals that the following messages of the datagram belong to the namespace with the
+    /// given id; the messages before it belong to the namespace 0
+    Namespace(u16),
+    /// Asks for the content of the chunk with the given hash
+    ChunkRequest(ChunkHash),
+    /// Provides the content of the chunk with the given hash
+    ChunkData(ChunkHash, Vec<u8>),
+    /// Asks for the version of the key-value pair held by the peer, with the id of the request
+    KeyRequest(K, u64),
+    /// Provides the version of a key-value pair, in response to the request with the given id
+    KeyResponse(u64, K, V),
+    /// Signals that the comparison items of the datagram use the fingerprint with the given id,
+    /// along with the ids of all the fingerprints the sender supports; without it, the items use
+    /// the [`DefaultFingerprint`]
+    Fingerprints(u8, Vec<u8>),
+    /// Provides the current time of the sender, to estimate the skew between the clocks
+    Clock(DateTime<Utc>),
+    /// Provides addresses of other instances, so that a whole cluster can be discovered from a
+    /// single seed
+    PeerAddrs(Vec<SocketAddr>),
+    /// Describes the sender, on first contact and in response to the first hello received
+    Hello(Hello),
+    /// Signals that the last datagrams of the receiver were refused, with the size of the largest
+    /// datagram the sender accepts
+    Nack { reason: NackReason, max: u32 },
+    /// Provides a key-value pair like [`Update`](Message::Update), along with the schema id of
+    /// the sender, so that instances with another schema skip it; see
+    /// [`with_schema_tags`](crate::Service::with_schema_tags)
+    SchemaUpdate(u64, (K, V)),
+}
+
+impl<K: Serialize, V: Serialize, C: Serialize> Message<K, V, C> {
+    /// Key-value pair of an update, tagged or not.
+    fn update(&self) -> Option<&(K, V)> {
+        match self {
+            Message::Update(update) | Message::SchemaUpdate(_, update) => Some(update),
+            _ => None,
+        }
+    }
+}
+
+/// Key-value pair serialized as a [`Message::Update`], or a [`Message::SchemaUpdate`] with the
+/// given schema id, without cloning it.
+struct UpdateRef<'a, K, V>(&'a (K, V), Option<u64>);
+
+impl<K: Serialize, V: Serialize> Serialize for UpdateRef<'_, K, V> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        // NOTE: the indices of the variants must match the declaration order of `Message`
+        let Some(schema) = self.1 else {
+            return serializer.serialize_newtype_variant("Message", 1, "Update", self.0);
+        };
+        let mut variant =
+            serializer.serialize_tuple_variant("Message", SCHEMA_UPDATE_TAG, "SchemaUpdate", 2)?;
+        variant.serialize_field(&schema)?;
+        variant.serialize_field(self.0)?;
+        variant.end()
+    }
 }
 
 impl<
         K: Clone + Debug + DeserializeOwned + Hash + Ord + Send + Serialize + Sync + 'static,
         V: Clone + DeserializeOwned + Hash + Reconcilable + Send + Serialize + Sync + 'static,
-        C: Debug + DeserializeOwned + Send + Serialize + Sync + 'static,
-        D: Debug,
+        C: Clone + Debug + DeserializeOwned + Send + Serialize + Sync + 'static,
+        D: Clone + Debug,
+        M: Map<Key = K, Value = V, DifferenceItem = D>
+            + Diffable<ComparisonItem = C, DifferenceItem = D>
+            + HashRangeQueryable<Key = K>,
+    > InternalService<M>
+{
+    pub async fn new(
+        map: M,
+        port: u16,
+        listen_addr: IpAddr,
+        peer_net: IpNet,
+    ) -> Result<Self, Error> {
+        InternalService::new_with_policy(map, port, listen_addr, peer_net, Arc::new(LwwPolicy))
+            .await
+    }
+
+    /// Create the service over already-bound sockets, settling the conflicts with the
+    /// [`LwwPolicy`].
+    pub fn with_sockets(map: M, sockets: Vec<Box<dyn Transport>>, peer_net: IpNet) -> Self {
+        InternalService::with_sockets_and_policy(map, sockets, peer_net, Arc::new(LwwPolicy))
+    }
+}
+
+impl<
+        K: Clone + Debug + DeserializeOwned + Hash + Ord + Send + Serialize + Sync + 'static,
+        V: Clone + DeserializeOwned + Hash + Send + Serialize + Sync + 'static,
+        C: Clone + Debug + DeserializeOwned + Send + Serialize + Sync + 'static,
+        D: Clone + Debug,
         M: Map<Key = K, Value = V, DifferenceItem = D>
-            + Diffable<ComparisonItem = C, DifferenceItem = D>,
+            + Diffable<ComparisonItem = C, DifferenceItem = D>
+            + HashRangeQueryable<Key = K>,
     > InternalService<M>
 {
-    pub async fn new(map: M, port: u16, listen_addr: IpAddr, peer_net: IpNet) -> Self {
-        let socket = UdpSocket::bind(SocketAddr::new(listen_addr, port))
+    pub async fn new_with_policy(
+        map: M,
+        port: u16,
+        listen_addr: IpAddr,
+        peer_net: IpNet,
+        policy: Arc<dyn ConflictPolicy<V>>,
+    ) -> Result<Self, Error> {
+        let addr = SocketAddr::new(listen_addr, port);
+        let socket = UdpSocket::bind(addr)
             .await
-            .unwrap();
-        debug!("Listening on: {}", socket.local_addr().unwrap());
+            .map_err(|err| Error::Bind(addr, err))?;
+        Ok(InternalService::with_sockets_and_policy(
+            map,
+            vec![Box::new(socket)],
+            peer_net,
+            policy,
+        ))
+    }
+
+    /// Create the service over already-bound sockets.
+    ///
+    /// Messages to a peer are sent from the socket with the same address family, to the port the
+    /// socket is bound to.
+    pub fn with_sockets_and_policy(
+        map: M,
+        sockets: Vec<Box<dyn Transport>>,
+        peer_net: IpNet,
+        policy: Arc<dyn ConflictPolicy<V>>,
+    ) -> Self {
+        assert!(
+            matches!(sockets.len(), 1 | 2),
+            "the service needs one or two sockets"
+        );
+        for socket in &sockets {
+            if let Ok(addr) = socket.local_addr() {
+                debug!("Listening on: {addr}");
+            }
+        }
+        let sockets = Arc::new(sockets);
         InternalService {
             map: Arc::new(RwLock::new(map)),
-            port,
-            socket: Arc::new(socket),
-            peer_net,
+            backlog: Arc::new(Backlog::start(sockets.clone())),
+            sockets,
+            discovery: Arc::new(tokio::sync::Mutex::new(Box::new(RandomSubnet::new(
+                peer_net,
+            )))),
             rng: Arc::new(RwLock::new(StdRng::from_entropy())),
             peers: Arc::new(RwLock::new(HashMap::new())),
-            pre_insert: Arc::new(RwLock::new(Box::new(|_, _| {}))),
+            bans: Arc::new(RwLock::new(HashMap::new())),
+            pre_insert: Arc::new(RwLock::new(Box::new(|_, _, _, _| {}))),
+            origins: None,
+            post_insert: Arc::new(RwLock::new(None)),
+            on_changes: Arc::new(RwLock::new(None)),
+            post_batch: Arc::new(RwLock::new(None)),
+            flush_batch: Arc::new(RwLock::new(None)),
+            update_filter: Arc::new(RwLock::new(None)),
+            undecodable_hook: Arc::new(RwLock::new(None)),
+            policy,
+            clock: Arc::new(MonotonicClock),
+            last_change: Arc::new(AtomicI64::new(Utc::now().timestamp_millis())),
+            clock_offsets: Arc::new(RwLock::new(ClockOffsets::new())),
+            convergence: Arc::new(watch::channel(None).0),
+            confirmed: Arc::new(RwLock::new(HashMap::new())),
+            metrics: Arc::new(ServiceMetrics::default()),
+            limiter: Arc::new(RateLimiter::default()),
+            update_budget: Arc::new(UpdateBudget::default()),
+            compression: Compression::default(),
+            acks: Arc::new(RwLock::new(HashMap::new())),
+            ack_peers: Arc::new(RwLock::new(HashSet::new())),
+            collected: Arc::new(RwLock::new(HashMap::new())),
+            sessions: Arc::new(RwLock::new(Sessions::new())),
+            recent_writes: Arc::new(RwLock::new(RecentWrites::new())),
+            deletions: Arc::new(RwLock::new(DeletionJournal::new())),
+            broadcast_queue: Arc::new(BroadcastQueue::new(
+                DEFAULT_BROADCAST_CAPACITY,
+                BroadcastOverflow::default(),
+            )),
+            paused: Arc::new(AtomicBool::new(false)),
+            reassembly: Arc::new(RwLock::new(Reassembly::new())),
+            duplicates: Arc::new(RwLock::new(Duplicates::new(DEFAULT_DEDUP_WINDOW))),
+            recv_buffer_size: BUFFER_SIZE,
+            oversize: Arc::new(RwLock::new(Oversize::new())),
+            budgets: Arc::new(DatagramBudgets::new(MAX_DATAGRAM_SIZE)),
+            divergences: Arc::new(RwLock::new(Divergences::new())),
+            root_watch: Arc::new(RwLock::new(RootWatch::new())),
+            sync_ranges: Arc::new(RwLock::new(SyncRanges {
+                peers: HashMap::new(),
+                default: None,
+            })),
+            progress: Arc::new(RwLock::new(PeerProgress::default())),
+            priority: Arc::new(RwLock::new(PriorityRange {
+                range: None,
+                synced: HashSet::new(),
+                sessions: HashMap::new(),
+            })),
+            key_requests: Arc::new(RwLock::new(HashMap::new())),
+            peer_fingerprints: Arc::new(RwLock::new(HashMap::new())),
+            schema_id: 0,
+            schema_tags: false,
+            handshakes: Arc::new(RwLock::new(Handshakes::new())),
+            chunks: Arc::new(RwLock::new(ChunkStore::default())),
+            chunk_refs: Arc::new(RwLock::new(None)),
+            max_concurrent_sessions: DEFAULT_MAX_CONCURRENT_SESSIONS,
+            update_chunk: DEFAULT_UPDATE_CHUNK,
+            activity_timeout: DEFAULT_ACTIVITY_TIMEOUT,
+            peer_expiration: DEFAULT_PEER_EXPIRATION,
+            paranoia_interval: DEFAULT_PARANOIA_INTERVAL,
+            session_deadline: DEFAULT_SESSION_DEADLINE,
+            diff_tuning: DiffTuning::default(),
         }
     }
 
-    fn get_peers(&self) -> Vec<IpAddr> {
-        let mut guard = self.peers.write();
-        guard.retain(|_, instant| instant.elapsed() < PEER_EXPIRATION);
-        guard.keys().cloned().collect()
+    /// Authenticate the datagrams sent with the first key, and drop the ones received that are
+    /// not authenticated with any of the keys.
+    pub fn with_auth_keys(mut self, keys: Vec<AuthKey>) -> Self {
+        let sockets = AuthTransport::wrap_all(self.sockets.clone(), keys, self.metrics.clone());
+        self.sockets = Arc::new(sockets);
+        // NOTE: the few datagrams received meanwhile would not be authenticated anyway
+        self.backlog = Arc::new(Backlog::start(self.sockets.clone()));
+        self
     }
 
-    pub fn just_insert(&self, key: K, value: V) -> Option<V> {
-        let mut guard = self.map.write();
-        (self.pre_insert.read())(&key, &value);
-        guard.insert(key.clone(), value.clone())
+    /// Compress with zstd at the given level the datagrams sent that are larger than the
+    /// threshold, when it makes them smaller.
+    pub fn with_compression(mut self, level: i32) -> Self {
+        self.compression.level = Some(level);
+        self
     }
 
-    pub fn insert(&self, key: K, value: V) -> Option<V> {
-        let ret = self.just_insert(key.clone(), value.clone());
-        let peers = self.get_peers();
-        let port = self.port;
-        let socket = Arc::clone(&self.socket);
-        tokio::spawn(async move {
-            let message = Message::Update::<K, V, C>((key, value));
-            let messages = vec![message];
-            let mut send_buf = Vec::new();
-            for addr in peers {
-                let peer = SocketAddr::new(addr, port);
-                send_messages_to(&messages, Arc::clone(&socket), &peer, &mut send_buf).await;
+    /// Only compress the datagrams larger than the given number of bytes.
+    pub fn with_compression_threshold(mut self, threshold: usize) -> Self {
+        self.compression.threshold = threshold;
+        self
+    }
+
+    /// Log a warning when the estimated skew between the clock of a peer and the local one goes
+    /// beyond the threshold.
+    pub fn with_clock_skew_warning(self, threshold: Duration) -> Self {
+        self.clock_offsets.write().warning_threshold = threshold;
+        self
+    }
+
+    /// Limit the outbound bandwidth to the given number of bytes per second.
+    pub fn with_max_bandwidth(mut self, bytes_per_sec: u64) -> Self {
+        self.limiter = Arc::new(RateLimiter::new(bytes_per_sec));
+        self
+    }
+
+    /// Limit the updates accepted from each peer to the given number per second.
+    pub fn with_peer_update_rate(mut self, updates_per_sec: u64) -> Self {
+        self.update_budget = Arc::new(UpdateBudget::new(updates_per_sec));
+        self
+    }
+
+    /// Drop the datagrams identical to one of the last `window` ones received from the same peer.
+    pub fn with_dedup_window(mut self, window: usize) -> Self {
+        self.duplicates = Arc::new(RwLock::new(Duplicates::new(window)));
+        self
+    }
+
+    /// Track the origin of the value of each key.
+    pub fn with_origin_tracking(mut self) -> Self {
+        self.origins = Some(Arc::new(RwLock::new(HashMap::new())));
+        self
+    }
+
+    /// Receive datagrams of up to `size` bytes.
+    pub fn with_recv_buffer_size(mut self, size: usize) -> Self {
+        assert!(
+            size >= MIN_DATAGRAM_SIZE,
+            "the receive buffer must hold at least {MIN_DATAGRAM_SIZE} bytes"
+        );
+        self.recv_buffer_size = size;
+        self
+    }
+
+    /// Find the addresses to probe with the given strategy instead of random addresses of the peer
+    /// network.
+    pub fn with_discovery<T: Discovery + 'static>(mut self, discovery: T) -> Self {
+        self.discovery = Arc::new(tokio::sync::Mutex::new(Box::new(discovery)));
+        self
+    }
+
+    /// Hold at most `capacity` local writes waiting to be sent to the peers, and drop some of
+    /// them as chosen by `overflow` beyond that.
+    pub fn with_broadcast_queue(mut self, capacity: usize, overflow: BroadcastOverflow) -> Self {
+        self.broadcast_queue = Arc::new(BroadcastQueue::new(capacity, overflow));
+        self
+    }
+
+    /// Only synchronize the keys within the given range with the peer.
+    pub fn with_sync_range(self, peer: SocketAddr, range: D) -> Self {
+        self.sync_ranges.write().peers.insert(peer, range);
+        self
+    }
+
+    /// Only synchronize the keys within the given range with the peers that have no specific
+    /// range.
+    pub fn with_default_sync_range(self, range: D) -> Self {
+        self.sync_ranges.write().default = Some(range);
+        self
+    }
+
+    /// Compare the keys within the given range with each peer before the other keys.
+    pub fn with_priority_range(self, range: D) -> Self {
+        self.priority.write().range = Some(range);
+        self
+    }
+
+    /// Group the peers by the key-value pairs within the range synchronized with them.
+    fn split_by_sync_range<'a>(
+        &self,
+        peers: Vec<SocketAddr>,
+        key_values: &'a [(K, V)],
+    ) -> Vec<PeerGroup<'a, K, V>> {
+        let sync_ranges = self.sync_ranges.read();
+        let schema = self.update_schema();
+        let mut unrestricted = Vec::new();
+        let mut groups = Vec::new();
+        for peer in peers {
+            if let Some(range) = sync_ranges.get(peer) {
+                let messages: Vec<_> = key_values
+                    .iter()
+                    .filter(|(key, _)| M::diff_range_contains(range, key))
+                    .map(|update| UpdateRef(update, schema))
+                    .collect();
+                if !messages.is_empty() {
+                    groups.push((vec![peer], messages));
+                }
+            } else {
+                unrestricted.push(peer);
             }
-        });
-        ret
+        }
+        if !unrestricted.is_empty() {
+            let messages = key_values
+                .iter()
+                .map(|update| UpdateRef(update, schema))
+                .collect();
+            groups.push((unrestricted, messages));
+        }
+        groups
     }
 
-    pub fn just_insert_bulk(&self, key_values: &[(K, V)]) {
-        let mut guard = self.map.write();
-        for (key, value) in key_values {
-            (self.pre_insert.read())(key, value);
-            guard.insert(key.clone(), value.clone());
+    pub fn subscribe_convergence(&self) -> watch::Receiver<Option<Convergence<FingerprintOf<M>>>> {
+        self.convergence.subscribe()
+    }
+
+    /// List the known peers, except the incompatible ones.
+    pub(crate) fn get_peers(&self) -> Vec<SocketAddr> {
+        let mut guard = self.peers.write();
+        guard.retain(|_, instant| instant.elapsed() < self.peer_expiration);
+        let handshakes = self.handshakes.read();
+        guard
+            .keys()
+            .filter(|&&addr| !handshakes.is_incompatible(addr))
+            .cloned()
+            .collect()
+    }
+
+    /// List the known peers, with the time since they were last heard from.
+    pub fn peer_infos(&self) -> Vec<PeerInfo> {
+        let mut guard = self.peers.write();
+        guard.retain(|_, instant| instant.elapsed() < self.peer_expiration);
+        let peers: Vec<_> = guard.keys().cloned().collect();
+        let updates_applied = self.metrics.peer_updates_applied(&peers);
+        let oversize_datagrams = self.metrics.peer_oversize_datagrams(&peers);
+        let handshakes = self.handshakes.read();
+        guard
+            .iter()
+            .map(|(&addr, instant)| PeerInfo {
+                addr,
+                last_seen: instant.elapsed(),
+                updates_applied: updates_applied.get(&addr).copied().unwrap_or(0),
+                oversize_datagrams: oversize_datagrams.get(&addr).copied().unwrap_or(0),
+                incompatible: handshakes.is_incompatible(addr),
+            })
+            .collect()
+    }
+
+    pub fn health(&self) -> Health {
+        Health {
+            last_error: self.metrics.last_error(),
+            oversize_peers: self.oversize.read().peers(),
+            narrowed_peers: self.budgets.lowered(),
         }
     }
 
-    pub fn insert_bulk(&self, key_values: &[(K, V)]) {
-        self.just_insert_bulk(key_values);
+    /// Estimated offset between the local clock and the clock of each peer that opened a session.
+    pub fn peer_clock_offsets(&self) -> HashMap<SocketAddr, chrono::Duration> {
+        self.clock_offsets.read().get()
+    }
+
+    /// Ranges that kept differing with the known peers.
+    pub fn divergences(&self) -> Vec<DivergenceInfo<D>> {
+        let peers = self.get_peers();
+        self.divergences
+            .read()
+            .list(&peers)
+            .into_iter()
+            .map(|(peer, range, first_seen, last_seen)| DivergenceInfo {
+                peer,
+                range,
+                first_seen,
+                last_seen,
+            })
+            .collect()
+    }
+
+    /// Progress of the reconciliation with the known peers.
+    pub fn sync_progress(&self) -> SyncProgress {
         let peers = self.get_peers();
-        let messages: Vec<_> = key_values
+        let progress = self.progress.read();
+        let known_peers = |map: &HashMap<SocketAddr, usize>| {
+            map.iter()
+                .filter(|(addr, _)| peers.contains(addr))
+                .map(|(_, &value)| value)
+                .collect::<Vec<_>>()
+        };
+        SyncProgress {
+            known_remote_size: known_peers(&progress.remote_sizes).into_iter().max(),
+            local_size: self.map.read().len(),
+            differing_ranges: known_peers(&progress.differing_ranges).into_iter().sum(),
+            updates_applied: self.metrics.snapshot().updates_applied,
+        }
+    }
+
+    /// Address of the peer at the given IP address that listens on the same port as the local
+    /// socket of the same address family, as the peers of a cluster usually do.
+    pub fn default_peer_addr(&self, ip: IpAddr) -> SocketAddr {
+        let local_addrs = self.local_addrs();
+        let port = local_addrs
+            .iter()
+            .find(|addr| addr.is_ipv4() == ip.is_ipv4())
+            .or(local_addrs.first())
+            .map_or(0, |addr| addr.port());
+        SocketAddr::new(ip, port)
+    }
+
+    /// Add a known peer, unless it is banned.
+    pub fn add_peer(&self, addr: SocketAddr) {
+        if !self.is_banned(addr.ip()) {
+            self.peers.write().insert(addr, Instant::now());
+            self.ack_peers.write().insert(addr);
+        }
+    }
+
+    /// Forget a known peer; return whether it was known.
+    pub fn remove_peer(&self, addr: SocketAddr) -> bool {
+        self.ack_peers.write().remove(&addr);
+        self.acks.write().remove(&addr);
+        self.peers.write().remove(&addr).is_some()
+    }
+
+    /// Forget the known peers at the given IP address, and ignore their datagrams for the given
+    /// duration.
+    pub fn ban_peer(&self, ip: IpAddr, duration: Duration) {
+        let now = Instant::now();
+        let mut bans = self.bans.write();
+        bans.retain(|_, until| *until > now);
+        bans.insert(ip, now + duration);
+        self.peers.write().retain(|addr, _| addr.ip() != ip);
+        self.ack_peers.write().retain(|addr| addr.ip() != ip);
+        self.acks.write().retain(|addr, _| addr.ip() != ip);
+    }
+
+    /// Stop exchanging the comparison items and updates with the peers, and hold the local
+    /// writes in the broadcast queue.
+    pub fn pause_sync(&self) {
+        self.paused.store(true, Ordering::Relaxed);
+        self.broadcast_queue.hold(true);
+    }
+
+    /// Resume the synchronization, and send the writes held in the broadcast queue.
+    pub fn resume_sync(&self) {
+        self.paused.store(false, Ordering::Relaxed);
+        self.broadcast_queue.hold(false);
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    fn is_banned(&self, addr: IpAddr) -> bool {
+        self.bans
+            .read()
+            .get(&addr)
+            .is_some_and(|until| *until > Instant::now())
+    }
+
+    /// Select a random sample of the peers that were heard from recently.
+    ///
+    /// Peers learned from gossip are not advertised until they contact us directly. Otherwise,
+    /// instances would keep telling each other about dead peers, which would never expire.
+    fn get_advertised_peers(&self) -> Vec<SocketAddr> {
+        let peers: Vec<_> = self
+            .peers
+            .read()
             .iter()
-            .map(|kv| Message::Update::<K, V, C>(kv.clone()))
+            .filter(|(_, instant)| instant.elapsed() < self.peer_expiration / 2)
+            .map(|(addr, _)| *addr)
             .collect();
-        let port = self.port;
-        let socket = Arc::clone(&self.socket);
-        tokio::spawn(async move {
-            let mut send_buf = Vec::new();
-            for addr in peers {
-                let peer = SocketAddr::new(addr, port);
-                send_messages_to(&messages, Arc::clone(&socket), &peer, &mut send_buf).await;
+        let mut rng = self.rng.write();
+        peers
+            .choose_multiple(&mut *rng, MAX_ADVERTISED_PEERS)
+            .copied()
+            .collect()
+    }
+
+    /// Addresses the sockets are bound to.
+    fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.sockets
+            .iter()
+            .filter_map(|socket| socket.local_addr().ok())
+            .collect()
+    }
+
+    /// Merge the addresses received from a peer in the known peers.
+    fn add_gossiped_peers(&self, addrs: Vec<SocketAddr>) {
+        let local_addrs = self.local_addrs();
+        // gossiped peers will expire unless they contact us directly
+        let now = Instant::now();
+        let instant = now.checked_sub(self.peer_expiration / 2).unwrap_or(now);
+        let mut guard = self.peers.write();
+        for addr in addrs {
+            if !local_addrs.contains(&addr) && !self.is_banned(addr.ip()) {
+                guard.entry(addr).or_insert(instant);
             }
-        });
+        }
     }
 
-    pub async fn run(self) {
-        // extra byte that easily detect when the buffer is too small
-        let mut recv_buf = [0; BUFFER_SIZE + 1];
-        let mut send_buf = Vec::new();
-        let recv_timeout = ACTIVITY_TIMEOUT;
-        // start the protocol at the beginning
-        self.start_reconciliation(&mut send_buf).await;
-        // infinite loop
-        loop {
-            match timeout(recv_timeout, self.socket.recv_from(&mut recv_buf)).await {
-                Err(_) => {
-                    // timeout
-                    debug!("no recent activity; initiating diff protocol");
-                    self.start_reconciliation(&mut send_buf).await;
-                }
-                Ok(Err(err)) => {
-                    // network error
-                    warn!("network error in recv_from: {err}");
-                }
-                Ok(Ok((size, peer))) => {
-                    // received datagram
-                    if peer.port() != self.port {
-                        warn!(
-                            "received message from {peer}, but protocol port is {}",
-                            self.port
-                        );
-                    }
-                    self.handle_messages(&recv_buf, (size, peer), &mut send_buf)
-                        .await;
-                    let now = Instant::now();
-                    let addr = peer.ip();
-                    self.peers.write().insert(addr, now);
-                }
+    async fn send_peers(&self, targets: &[SocketAddr], send_buf: &mut Vec<u8>) {
+        let advertised = self.get_advertised_peers();
+        for &addr in targets {
+            let addrs: Vec<_> = advertised.iter().copied().filter(|&a| a != addr).collect();
+            if addrs.is_empty() {
+                continue;
             }
+            trace!("sending {} peers to {addr}", addrs.len());
+            let messages = [Message::PeerAddrs::<K, V, C>(addrs)];
+            broadcast_messages(
+                &messages,
+                &self.sockets,
+                &[addr],
+                send_buf,
+                &self.metrics,
+                &self.limiter,
+                &self.budgets,
+                self.compression,
+            )
+            .await;
         }
     }
 
-    pub async fn start_reconciliation(&self, send_buf: &mut Vec<u8>) {
-        let segments = {
-            let guard = self.map.read();
-            guard.start_diff()
-        };
-        send_buf.clear();
-        for segment in segments {
-            Message::ComparisonItem::<K, V, C>(segment)
-                .serialize(&mut Serializer::new(&mut *send_buf, DefaultOptions::new()))
-                .unwrap();
+    /// Keep the acknowledgements of the peer that match the local versions.
+    fn record_acks(&self, peer: SocketAddr, acks: Vec<(K, u64)>) {
+        let guard = self.map.read();
+        let mut peer_acks = self.acks.write();
+        let peer_acks = peer_acks.entry(peer).or_default();
+        for (key, hash) in acks {
+            if guard.get(&key).map(|value| version_hash(&key, &value)) == Some(hash) {
+                peer_acks.insert(key, hash);
+            }
         }
-        let mut peers = self.get_peers();
-        // select a random address out of the peer network
-        // NOTE: the random address might not correspond to a real peer, so we do not add it to the
-        // list of known peers, just to our local copies of the addresses; if a peer exists at this
-        // address, they will eventually send us a message in return, and we will add them to the
-        // list of known peer
-        let addr = gen_ip(&mut *self.rng.write(), self.peer_net);
-        peers.push(addr);
-        // initiate the reconciliation protocol with all the known peers, and a random one
-        for peer in peers {
-            trace!("start_diff {} bytes to {peer}", send_buf.len());
-            send_to_retry(&self.socket, send_buf, (peer, self.port))
-                .await
-                .unwrap();
+    }
+
+    /// Tell all the known peers that the given versions are held locally.
+    pub async fn send_acks(&self, acks: &[(K, u64)]) {
+        let peers = self.get_peers();
+        self.collected
+            .write()
+            .retain(|_, (_, instant)| instant.elapsed() < self.peer_expiration);
+        let messages: Vec<_> = acks
+            .iter()
+            .map(|(key, hash)| Message::Ack::<K, V, C>(key.clone(), *hash))
+            .collect();
+        let mut send_buf = Vec::new();
+        broadcast_messages(
+            &messages,
+            &self.sockets,
+            &peers,
+            &mut send_buf,
+            &self.metrics,
+            &self.limiter,
+            &self.budgets,
+            self.compression,
+        )
+        .await;
+    }
+
+    /// Whether all the peers heard from acknowledged the given version of the key.
+    ///
+    /// This includes the expired peers, for instance partitioned for longer than the peer
+    /// expiration: they would send back their older value once the tombstone is gone. Peers that
+    /// left for good must be removed with [`remove_peer`](Self::remove_peer).
+    pub fn is_acknowledged(&self, key: &K, hash: u64) -> bool {
+        let handshakes = self.handshakes.read();
+        let acks = self.acks.read();
+        self.ack_peers
+            .read()
+            .iter()
+            .filter(|&&peer| !handshakes.is_incompatible(peer))
+            .all(|peer| acks.get(peer).and_then(|acks| acks.get(key)) == Some(&hash))
+    }
+
+    /// Forget the acknowledgements of a key-value pair that was removed from the map.
+    ///
+    /// For a while, the same version received from peers is discarded instead of being inserted
+    /// back: peers that have not removed it yet would otherwise keep sending it to each other.
+    pub fn collect(&self, key: &K, hash: u64) {
+        for acks in self.acks.write().values_mut() {
+            acks.remove(key);
         }
+        self.collected
+            .write()
+            .insert(key.clone(), (hash, Instant::now()));
     }
 
-    async fn handle_messages(
-        &self,
-        recv_buf: &[u8],
-        (size, peer): (usize, SocketAddr),
-        send_buf: &mut Vec<u8>,
-    ) {
-        if size == recv_buf.len() {
-            warn!("Buffer too small for message, discarded");
-            return;
+    /// Record that the map changed now; the write lock must be held, so that
+    /// [`summary`](Self::summary) reads the time of the change along with the map.
+    pub(crate) fn record_change(&self) {
+        let now = self.clock.now().timestamp_millis();
+        self.last_change.store(now, Ordering::Relaxed);
+    }
+
+    /// Hash and size of the map, and time of its last change, under a single read lock.
+    pub fn summary(&self) -> MapSummary<FingerprintOf<M>> {
+        let guard = self.map.read();
+        let last_change = self.last_change.load(Ordering::Relaxed);
+        MapSummary {
+            root_hash: guard.root_hash(),
+            len: guard.len(),
+            last_change: Utc.timestamp_millis_opt(last_change).unwrap(),
         }
-        trace!("received {} bytes from {peer}", size);
-        let mut in_comparison = Vec::new();
-        let mut updates = Vec::new();
-        let mut deserializer = Deserializer::from_slice(&recv_buf[..size], DefaultOptions::new());
-        // read messages in buffer
-        loop {
-            match Message::deserialize(&mut deserializer) {
-                Err(ref kind) => {
-                    if let bincode::ErrorKind::Io(err) = kind.as_ref() {
-                        if err.kind() == std::io::ErrorKind::UnexpectedEof {
-                            break;
-                        }
-                    }
-                    panic!("failed to deserialize message: {:?}", kind);
-                }
-                Ok(Message::ComparisonItem(segment)) => in_comparison.push(segment),
-                Ok(Message::Update(update)) => updates.push(update),
-            }
+    }
+
+    /// Record the origin of the new value of the key, if tracked; the write lock must be held.
+    pub(crate) fn record_origin(&self, key: &K, source: ChangeOrigin) {
+        if let Some(origins) = &self.origins {
+            let origin = Origin {
+                source,
+                applied_at: self.clock.now(),
+            };
+            origins.write().insert(key.clone(), origin);
         }
-        // handle messages
-        if !in_comparison.is_empty() {
-            debug!("received {} segments", in_comparison.len());
-            let mut differences = Vec::new();
-            let mut out_comparison = Vec::new();
-            {
-                let guard = self.map.read();
-                guard.diff_round(in_comparison, &mut out_comparison, &mut differences);
+    }
+
+    /// Forget the origin of a key removed from the map.
+    pub(crate) fn forget_origin(&self, key: &K) {
+        if let Some(origins) = &self.origins {
+            origins.write().remove(key);
+        }
+    }
+
+    /// Origin of the current value of the key, if the origins are tracked and the key is in the
+    /// map.
+    pub fn origin_of(&self, key: &K) -> Option<Origin> {
+        self.origins.as_ref()?.read().get(key).copied()
+    }
+
+    /// Insert the key-value pair in the locked map, calling the pre-insertion callback with the
+    /// previous value, and moving the chunk references from the previous value to the new one.
+    /// Whether the element can be stored in the map, see [`hashable`]; count it otherwise.
+    fn check_hashable(&self, canonical: bool, key: &K, value: &V) -> bool {
+        let hashable = hashable(canonical, key, value);
+        if !hashable {
+            ServiceMetrics::add(&self.metrics.serialize_errors, 1);
+        }
+        hashable
+    }
+
+    fn insert_locked(&self, guard: &mut M, key: K, value: V, origin: ChangeOrigin) -> Option<V> {
+        self.before_insert(&key, &value, guard.get(&key).as_deref(), origin);
+        guard.insert(key, value)
+    }
+
+    /// Call the pre-insertion callback, record the origin of the value, and move the chunk
+    /// references from the previous value to the new one, while holding the write lock.
+    fn before_insert(&self, key: &K, value: &V, previous: Option<&V>, origin: ChangeOrigin) {
+        self.record_change();
+        self.record_origin(key, origin);
+        (self.pre_insert.read())(key, value, previous, origin);
+        if let Some(chunk_refs) = &*self.chunk_refs.read() {
+            let mut chunks = self.chunks.write();
+            chunks.add_refs(&chunk_refs(value));
+            if let Some(previous) = previous {
+                chunks.release(&chunk_refs(previous));
             }
-            let mut messages = Vec::new();
-            if !out_comparison.is_empty() {
-                debug!("returning {} segments", out_comparison.len());
-                trace!("segments: {out_comparison:?}");
-                for segment in out_comparison {
-                    messages.push(Message::ComparisonItem::<K, V, C>(segment))
-                }
+        }
+    }
+
+    /// Whether the insertions must be collected for the post-insertion callback, the change feed
+    /// or the post-batch callback.
+    fn has_post_insert(&self) -> bool {
+        self.post_insert.read().is_some()
+            || self.on_changes.read().is_some()
+            || self.post_batch.read().is_some()
+    }
+
+    /// Global hash of the locked map, if needed for the change feed or the post-batch callback.
+    pub(crate) fn batch_hash(&self, guard: &M) -> Option<FingerprintOf<M>> {
+        (self.on_changes.read().is_some() || self.post_batch.read().is_some())
+            .then(|| guard.hash(&..))
+    }
+
+    /// Call the post-insertion callback, if any, then the change feed and the post-batch callback
+    /// with the whole batch; the write lock must have been released.
+    pub(crate) fn post_insert(
+        &self,
+        inserted: &[(K, V, Option<V>)],
+        origin: ChangeOrigin,
+        hash: Option<FingerprintOf<M>>,
+    ) {
+        self.flush_batch();
+        if let Some(post_insert) = self.post_insert.read().as_ref() {
+            for (key, value, old_value) in inserted {
+                post_insert(key, value, old_value.as_ref());
             }
-            if !differences.is_empty() {
-                debug!("returning {} diff_ranges", differences.len());
-                trace!("diff_ranges: {differences:?}");
-                let guard = self.map.read();
-                for update in guard.enumerate_diff_ranges(differences) {
-                    messages.push(Message::Update(update));
-                }
+        }
+        if let (Some(on_changes), Some(hash)) = (self.on_changes.read().as_ref(), hash) {
+            if !inserted.is_empty() {
+                on_changes(inserted, origin, hash);
             }
-            if !messages.is_empty() {
-                send_messages_to(&messages, Arc::clone(&self.socket), &peer, send_buf).await;
+        }
+        if let (Some(post_batch), Some(hash)) = (self.post_batch.read().as_ref(), hash) {
+            if !inserted.is_empty() {
+                post_batch(self, inserted, origin, hash);
             }
         }
-        if !updates.is_empty() {
-            debug!("received {} updates", updates.len());
+    }
+
+    /// Call the flush callback, if any; the write lock must have been released.
+    pub(crate) fn flush_batch(&self) {
+        if let Some(flush_batch) = self.flush_batch.read().as_ref() {
+            flush_batch();
+        }
+    }
+
+    pub fn just_insert(&self, key: K, value: V) -> Option<V> {
+        let (old_value, hash) = {
             let mut guard = self.map.write();
-            for (k, v) in updates {
-                let local_v = guard.get(&k);
-                let do_change = local_v
-                    .map(|local_v| local_v.reconcile(&v) == ReconciliationResult::KeepOther)
-                    .unwrap_or(true);
-                if do_change {
-                    (self.pre_insert.read())(&k, &v);
-                    guard.insert(k, v);
-                }
+            if !self.check_hashable(guard.canonical_hashing(), &key, &value) {
+                return None;
             }
+            let old_value =
+                self.insert_locked(&mut guard, key.clone(), value.clone(), ChangeOrigin::Local);
+            (old_value, self.batch_hash(&guard))
+        };
+        if self.has_post_insert() {
+            self.post_insert(
+                &[(key, value, old_value.clone())],
+                ChangeOrigin::Local,
+                hash,
+            );
+        } else {
+            self.flush_batch();
         }
+        old_value
     }
-}
 
-async fn send_to_retry<A: ToSocketAddrs>(
-    socket: &UdpSocket,
-    buf: &[u8],
-    target: A,
-) -> std::io::Result<usize> {
-    let mut res = Ok(0);
-    for _ in 0..MAX_SENDTO_RETRIES {
-        res = socket.send_to(buf, &target).await;
-        if res.is_ok() {
-            break;
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        let ret = self.just_insert(key.clone(), value.clone());
+        self.recent_writes.write().record(key.clone());
+        self.broadcast_updates(&[(key, value)]);
+        ret
+    }
+
+    /// Replace the value at the given key with the result of the closure, if any, while holding
+    /// the write lock on the map, and send it to the peers.
+    pub fn update<F: FnOnce(Option<&V>) -> Option<V>>(&self, key: K, f: F) {
+        let (value, old_value, hash) = {
+            let mut guard = self.map.write();
+            let Some(value) = f(guard.get(&key).as_deref()) else {
+                return;
+            };
+            if !self.check_hashable(guard.canonical_hashing(), &key, &value) {
+                return;
+            }
+            let old_value =
+                self.insert_locked(&mut guard, key.clone(), value.clone(), ChangeOrigin::Local);
+            (value, old_value, self.batch_hash(&guard))
+        };
+        if self.has_post_insert() {
+            self.post_insert(
+                &[(key.clone(), value.clone(), old_value)],
+                ChangeOrigin::Local,
+                hash,
+            );
+        } else {
+            self.flush_batch();
         }
-        tokio::time::sleep(Duration::from_millis(1)).await;
+        self.recent_writes.write().record(key.clone());
+        self.broadcast_updates(&[(key, value)]);
     }
-    res
-}
 
-async fn send_messages_to<K: Serialize, V: Serialize, C: Serialize>(
-    messages: &[Message<K, V, C>],
-    socket: Arc<UdpSocket>,
-    peer: &SocketAddr,
-    send_buf: &mut Vec<u8>,
-) {
-    debug!("sending {} messages to {peer}", messages.len());
-    send_buf.clear();
+    pub fn just_insert_bulk(&self, key_values: &[(K, V)]) {
+        let collect = self.has_post_insert();
+        let mut inserted: Inserted<K, V> = Vec::new();
+        let hash = {
+            let mut guard = self.map.write();
+            let canonical = guard.canonical_hashing();
+            for (key, value) in key_values {
+                if !self.check_hashable(canonical, key, value) {
+                    continue;
+                }
+                let old_value =
+                    self.insert_locked(&mut guard, key.clone(), value.clone(), ChangeOrigin::Local);
+                if collect {
+                    inserted.push((key.clone(), value.clone(), old_value));
+                }
+            }
+            self.batch_hash(&guard)
+        };
+        self.post_insert(&inserted, ChangeOrigin::Local, hash);
+    }
+
+    /// Insert the key-value pairs, and queue them to be sent to the known peers.
+    ///
+    /// Return a ticket completing once they were sent.
+    pub fn insert_bulk(&self, key_values: &[(K, V)]) -> BroadcastTicket {
+        self.just_insert_bulk(key_values);
+        {
+            let mut recent_writes = self.recent_writes.write();
+            for (key, _) in key_values {
+                recent_writes.record(key.clone());
+            }
+        }
+        self.broadcast_updates(key_values)
+    }
+
+    /// Merge key-value pairs sorted by key into the map under a single write lock, keeping the
+    /// existing values unless the policy resolves the conflict with the new one.
+    ///
+    /// Return the statistics of the merge, and the key-value pairs inserted.
+    pub fn just_merge_bulk<I: IntoIterator<Item = (K, V)>>(
+        &self,
+        key_values: I,
+    ) -> (MergeStats, Vec<(K, V)>) {
+        let collect = self.has_post_insert();
+        let mut inserted: Inserted<K, V> = Vec::new();
+        let mut changes = Vec::new();
+        let (stats, hash) = {
+            let mut guard = self.map.write();
+            let canonical = guard.canonical_hashing();
+            let stats = guard.merge_from_sorted_with(
+                key_values
+                    .into_iter()
+                    .filter(|(key, value)| self.check_hashable(canonical, key, value)),
+                |_, existing, new| {
+                    matches!(self.policy.resolve(existing, new), Resolution::KeepIncoming)
+                },
+                |key, value, previous| {
+                    self.before_insert(key, value, previous, ChangeOrigin::Local);
+                    changes.push((key.clone(), value.clone()));
+                    if collect {
+                        inserted.push((key.clone(), value.clone(), previous.cloned()));
+                    }
+                },
+            );
+            (stats, self.batch_hash(&guard))
+        };
+        self.post_insert(&inserted, ChangeOrigin::Local, hash);
+        (stats, changes)
+    }
+
+    /// Merge key-value pairs sorted by key into the map like
+    /// [`just_merge_bulk`](Self::just_merge_bulk), and send the changes to the peers.
+    pub fn merge_bulk<I: IntoIterator<Item = (K, V)>>(&self, key_values: I) -> MergeStats {
+        let (stats, changes) = self.just_merge_bulk(key_values);
+        {
+            let mut recent_writes = self.recent_writes.write();
+            for (key, _) in &changes {
+                recent_writes.record(key.clone());
+            }
+        }
+        self.broadcast_updates(&changes);
+        stats
+    }
+
+    /// Queue the key-value pairs to be sent to the known peers, without waiting.
+    ///
+    /// Return a ticket completing once they were sent.
+    fn broadcast_updates(&self, key_values: &[(K, V)]) -> BroadcastTicket {
+        let (dropped, ticket) = self.broadcast_queue.push(key_values.iter().cloned());
+        if dropped > 0 {
+            trace!("broadcast queue full, {dropped} updates dropped");
+            ServiceMetrics::add(&self.metrics.broadcasts_dropped, dropped as u64);
+        }
+        ticket
+    }
+
+    /// Send the queued key-value pairs to the known peers, packing them in shared datagrams.
+    async fn broadcast_queued(&self) {
+        let mut send_buf = Vec::new();
+        loop {
+            let key_values = self.broadcast_queue.pop_batch(ENUMERATION_CHUNK).await;
+            let groups = self.split_by_sync_range(self.get_peers(), &key_values);
+            for (peers, messages) in groups {
+                let updates = messages.len() * peers.len();
+                ServiceMetrics::add(&self.metrics.updates_sent, updates as u64);
+                broadcast_messages(
+                    &messages,
+                    &self.sockets,
+                    &peers,
+                    &mut send_buf,
+                    &self.metrics,
+                    &self.limiter,
+                    &self.budgets,
+                    self.compression,
+                )
+                .await;
+            }
+            self.broadcast_queue.sent();
+        }
+    }
+
+    /// Run the service until `shutdown` completes.
+    ///
+    /// The datagram being handled is processed completely, then the writes made since the last
+    /// push are sent again to all the peers, in case the original updates were lost.
+    pub async fn run_until<F: Future<Output = ()>>(self, shutdown: F) {
+        // the local writes are sent along the handling of the datagrams, in the same task
+        tokio::select! {
+            () = self.serve(shutdown) => (),
+            () = self.broadcast_queued() => (),
+        }
+    }
+
+    async fn serve<F: Future<Output = ()>>(&self, shutdown: F) {
+        tokio::pin!(shutdown);
+        // extra byte that easily detect when the buffer is too small
+        let mut recv_bufs = vec![vec![0; self.recv_buffer_size + 1]; self.sockets.len()];
+        let mut send_buf = Vec::new();
+        let mut deferred = Deferred::new();
+        let recv_timeout = self.activity_timeout;
+        // handle the datagrams received since the service was created
+        let backlog = self.backlog.stop().await;
+        if !backlog.is_empty() {
+            debug!(
+                "handling {} datagrams received before running",
+                backlog.len()
+            );
+        }
+        for (index, datagram, peer) in backlog {
+            // a datagram larger than the receive buffer is truncated, as by the socket
+            let size = datagram.len().min(recv_bufs[index].len());
+            recv_bufs[index][..size].copy_from_slice(&datagram[..size]);
+            let received = (size, peer);
+            self.receive(
+                index,
+                &recv_bufs[index],
+                received,
+                &mut deferred,
+                &mut send_buf,
+            )
+            .await;
+        }
+        // start the protocol at the beginning
+        self.start_reconciliation(&mut send_buf).await;
+        let mut last_reconciliation = Instant::now();
+        let mut last_gossip = Instant::now();
+        let mut last_push = Instant::now();
+        loop {
+            if last_gossip.elapsed() >= PEER_GOSSIP_INTERVAL {
+                last_gossip = Instant::now();
+                self.send_peers(&self.get_peers(), &mut send_buf).await;
+                let known: Vec<_> = self.peers.read().keys().copied().collect();
+                self.handshakes.write().retain_peers(&known);
+                self.duplicates.write().retain_peers(&known);
+                self.oversize.write().retain_peers(&known);
+                self.budgets.retain_peers(&known);
+            }
+            if last_push.elapsed() >= self.activity_timeout {
+                last_push = Instant::now();
+                self.push_recent_writes(&mut send_buf).await;
+                self.request_missing_chunks(&mut send_buf).await;
+            }
+            if last_reconciliation.elapsed() >= self.activity_timeout {
+                // start sessions with the next peers, even if others keep us busy
+                last_reconciliation = Instant::now();
+                self.resume_deferred(&mut deferred, &mut send_buf).await;
+                self.start_reconciliation(&mut send_buf).await;
+            }
+            let recv = timeout(recv_timeout, recv_from_any(&self.sockets, &mut recv_bufs));
+            let received = tokio::select! {
+                () = &mut shutdown => break,
+                received = recv => received,
+            };
+            match received {
+                Err(_) => {
+                    // timeout
+                    debug!("no recent activity; initiating diff protocol");
+                    ServiceMetrics::add(&self.metrics.timeout_reconciliations, 1);
+                    last_reconciliation = Instant::now();
+                    self.resume_deferred(&mut deferred, &mut send_buf).await;
+                    self.start_reconciliation(&mut send_buf).await;
+                }
+                Ok((_, Err(err))) => {
+                    // network error
+                    warn!("network error in recv_from: {err}");
+                }
+                Ok((index, Ok(received))) => {
+                    self.receive(
+                        index,
+                        &recv_bufs[index],
+                        received,
+                        &mut deferred,
+                        &mut send_buf,
+                    )
+                    .await;
+                }
+            }
+        }
+        debug!("shutting down");
+        self.flush_recent_writes(last_push, &mut send_buf).await;
+    }
+
+    /// Handle a datagram received on the socket with the given index, and remember its sender as
+    /// a peer.
+    async fn receive(
+        &self,
+        index: usize,
+        recv_buf: &[u8],
+        (size, peer): (usize, SocketAddr),
+        deferred: &mut Deferred<C>,
+        send_buf: &mut Vec<u8>,
+    ) {
+        let socket = &*self.sockets[index];
+        let accepted = self
+            .handle_messages(socket, recv_buf, (size, peer), deferred, send_buf)
+            .await;
+        if !accepted {
+            // do not take stray datagrams, or banned peers, for a peer
+            return;
+        }
+        // the peer is reached at the address it sends from, whatever its port
+        let first_contact = self.peers.write().insert(peer, Instant::now()).is_none();
+        if first_contact {
+            debug!("new peer {peer}");
+            self.ack_peers.write().insert(peer);
+            self.deletions.write().reset_peer(peer);
+            self.send_peers(&[peer], send_buf).await;
+        }
+        if self.is_paused() {
+            // the tombstones are sent once the synchronization resumes
+            return;
+        }
+        if self.handshakes.read().is_incompatible(peer) {
+            return;
+        }
+        let tombstones = self.deletions.write().inform(peer);
+        if !tombstones.is_empty() {
+            self.send_deletions(peer, tombstones, send_buf).await;
+        }
+    }
+
+    /// Send the tombstones of the recent deletions to a peer heard from for the first time,
+    /// within its sync range, so that it does not push back the values it still holds.
+    async fn send_deletions(
+        &self,
+        peer: SocketAddr,
+        tombstones: Vec<(K, V)>,
+        send_buf: &mut Vec<u8>,
+    ) {
+        let Some((socket, target)) = route(&self.sockets, peer) else {
+            trace!("no socket to reach {peer}");
+            return;
+        };
+        let range = self.sync_ranges.read().get(peer).cloned();
+        let messages: Vec<_> = tombstones
+            .into_iter()
+            .filter(|(key, _)| {
+                range
+                    .as_ref()
+                    .is_none_or(|range| M::diff_range_contains(range, key))
+            })
+            .map(|update| self.update_message(update))
+            .collect();
+        if messages.is_empty() {
+            return;
+        }
+        debug!("sending {} recent deletions to {peer}", messages.len());
+        send_messages_to(
+            &messages,
+            socket,
+            &target,
+            send_buf,
+            &self.metrics,
+            &self.limiter,
+            &self.budgets,
+            self.compression,
+        )
+        .await;
+    }
+
+    /// Start reconciliation sessions with the next known peers, within the limit of concurrent
+    /// sessions, and with the candidates of the discovery strategy that are not known yet.
+    pub async fn start_reconciliation(&self, send_buf: &mut Vec<u8>) {
+        if self.is_paused() {
+            return;
+        }
+        let candidates = self.discovery.lock().await.candidates().await;
+        let (segments, hash) = {
+            let guard = self.map.read();
+            (guard.start_diff(), guard.hash(&..))
+        };
+        let peers = self.get_peers();
+        let local_addrs = self.local_addrs();
+        let targets = {
+            let mut sessions = self.sessions.write();
+            self.watch_roots(hash, sessions.take_unanswered(), &peers);
+            for session in sessions.take_overdue(self.session_deadline) {
+                warn!(
+                    "session with {} still running after {:?}, with {} rounds completed and {} ranges outstanding; starting over",
+                    session.peer, session.elapsed, session.rounds, session.outstanding
+                );
+                ServiceMetrics::add(&self.metrics.sessions_restarted, 1);
+                ServiceMetrics::add(&self.metrics.restarted_session_rounds, session.rounds);
+                ServiceMetrics::add(
+                    &self.metrics.restarted_session_ranges,
+                    session.outstanding as u64,
+                );
+            }
+            let mut confirmed = self.confirmed.write();
+            // skip the peers that confirmed the same global hash, unless it was a while ago
+            let is_idle = |addr| {
+                confirmed
+                    .get(&addr)
+                    .is_some_and(|&(confirmed_hash, instant)| {
+                        confirmed_hash == hash && instant.elapsed() < self.paranoia_interval
+                    })
+            };
+            let mut targets = sessions.schedule(&peers, self.max_concurrent_sessions, is_idle);
+            for (addr, _) in &targets {
+                if let Some((_, instant)) = confirmed.get_mut(addr) {
+                    *instant = Instant::now();
+                }
+            }
+            // NOTE: a candidate might not correspond to a real peer, so we do not add it to the
+            // list of known peers; if a peer exists at this address, they will eventually send us
+            // a message in return, and we will add them to the list of known peer
+            let handshakes = self.handshakes.read();
+            for ip in candidates {
+                let addr = self.default_peer_addr(ip);
+                if !peers.contains(&addr)
+                    && !local_addrs.contains(&addr)
+                    && !self.is_banned(ip)
+                    && !handshakes.is_incompatible(addr)
+                    && !targets.iter().any(|&(target, _)| target == addr)
+                {
+                    targets.push((addr, sessions.start(addr)));
+                }
+            }
+            self.metrics.set_longest_session(sessions.longest_active());
+            targets
+        };
+        // initiate the reconciliation protocol with the selected peers
+        for (peer, session_id) in targets {
+            self.send_opening(peer, session_id, &segments, send_buf)
+                .await;
+        }
+    }
+
+    /// Resume the comparison of the segments deferred in each session.
+    async fn resume_deferred(&self, deferred: &mut Deferred<C>, send_buf: &mut Vec<u8>) {
+        // drop the segments of the sessions abandoned or replaced, and of the expired peers
+        {
+            let sessions = self.sessions.read();
+            deferred
+                .retain(|&(addr, reply_session_id), _| !sessions.is_stale(addr, reply_session_id));
+        }
+        let sessions: Vec<_> = deferred.keys().copied().collect();
+        for (addr, reply_session_id) in sessions {
+            let route = route(&self.sockets, addr);
+            let Some((socket, peer)) = route.filter(|_| !self.is_banned(addr.ip())) else {
+                deferred.remove(&(addr, reply_session_id));
+                continue;
+            };
+            let range = self.sync_ranges.read().get(addr).cloned();
+            let fingerprint = deferred[&(addr, reply_session_id)].0;
+            self.reply_comparison(
+                socket,
+                peer,
+                (reply_session_id, false, fingerprint),
+                Vec::new(),
+                range.as_ref(),
+                deferred,
+                send_buf,
+            )
+            .await;
+        }
+    }
+
+    /// Segments opening a session with the peer instead of the ones over all the keys, if any: the
+    /// ones over the priority range until it matched the peer, and only over the keys
+    /// synchronized with the peer.
+    fn opening_segments(&self, peer: SocketAddr, session_id: u64) -> Option<Vec<C>> {
+        let range = self.sync_ranges.read().get(peer).cloned();
+        let priority_range = {
+            let priority = self.priority.read();
+            priority
+                .range
+                .clone()
+                .filter(|_| !priority.synced.contains(&peer))
+        };
+        let guard = self.map.read();
+        if let Some(priority_range) = priority_range {
+            let mut segments = guard.start_diff_range(&priority_range);
+            if let Some(range) = &range {
+                let mut overlaps = Vec::new();
+                segments = guard.clip_comparison(segments, range, &mut overlaps);
+                segments.extend(overlaps);
+            }
+            drop(guard);
+            let mut priority = self.priority.write();
+            if !segments.is_empty() {
+                priority.sessions.insert(peer, session_id);
+                return Some(segments);
+            }
+            // no key of the priority range is synchronized with the peer
+            priority.synced.insert(peer);
+            drop(priority);
+            return range.map(|range| self.map.read().start_diff_range(&range));
+        }
+        range.map(|range| guard.start_diff_range(&range))
+    }
+
+    /// Ids of the fingerprints supported locally, from the weakest.
+    fn fingerprints() -> Vec<u8> {
+        <M as HashRangeQueryable>::Fingerprint::supported()
+    }
+
+    /// Fingerprint to open a session with the peer: the strongest one supported by both
+    /// instances, or the weakest local one until the peer advertised its own.
+    fn session_fingerprint(&self, peer: SocketAddr) -> u8 {
+        let local = Self::fingerprints();
+        let weakest = local[0];
+        match self.peer_fingerprints.read().get(&peer) {
+            Some(remote) => local
+                .into_iter()
+                .rev()
+                .find(|id| remote.contains(id))
+                .unwrap_or(weakest),
+            None => weakest,
+        }
+    }
+
+    /// Message to put before the comparison items using the given fingerprint, unless only the
+    /// [`DefaultFingerprint`] is supported, which the peers assume otherwise.
+    fn fingerprints_message(fingerprint: u8) -> Option<Message<K, V, C>> {
+        let local = Self::fingerprints();
+        (local != [DefaultFingerprint::ID]).then_some(Message::Fingerprints(fingerprint, local))
+    }
+
+    /// Schema id to tag the updates sent with, if they are tagged.
+    fn update_schema(&self) -> Option<u64> {
+        self.schema_tags.then_some(self.schema_id)
+    }
+
+    /// Message sending the key-value pair to the peers, tagged with the schema id if enabled.
+    fn update_message(&self, update: (K, V)) -> Message<K, V, C> {
+        match self.update_schema() {
+            Some(schema) => Message::SchemaUpdate(schema, update),
+            None => Message::Update(update),
+        }
+    }
+
+    /// Skip a message received from the peer, that could not be deserialized or was tagged with
+    /// another schema id, and pass it to the hook.
+    fn skip_undecodable(&self, peer: SocketAddr, schema: Option<u64>, payload: &[u8]) {
+        match schema {
+            Some(schema) if schema != self.schema_id => {
+                debug!("skipping update from {peer} with schema {schema}");
+                ServiceMetrics::add(&self.metrics.foreign_schema_updates, 1);
+            }
+            _ => ServiceMetrics::add(&self.metrics.undecodable_messages, 1),
+        }
+        if let Some(hook) = &*self.undecodable_hook.read() {
+            hook(peer, schema, payload);
+        }
+    }
+
+    /// Keep the update received from the peer if it has the local schema, skip it otherwise.
+    fn check_schema(&self, peer: SocketAddr, schema: u64, update: (K, V)) -> Option<(K, V)> {
+        if schema == self.schema_id {
+            return Some(update);
+        }
+        let payload = if self.undecodable_hook.read().is_some() {
+            let message = Message::<K, V, C>::SchemaUpdate(schema, update);
+            DefaultOptions::new()
+                .serialize(&message)
+                .unwrap_or_default()
+        } else {
+            Vec::new()
+        };
+        self.skip_undecodable(peer, Some(schema), &payload);
+        None
+    }
+
+    /// Hello describing the local instance to the peers.
+    fn hello(&self) -> Hello {
+        let (len, root_hash) = {
+            let guard = self.map.read();
+            (guard.len(), guard.hash(&..))
+        };
+        Hello {
+            proto_version: PROTOCOL_VERSION,
+            fingerprints: Self::fingerprints(),
+            value_schema_hash: self.schema_id,
+            len: len as u64,
+            root_hash: root_hash.to_string(),
+        }
+    }
+
+    /// Record the hello received from the peer, and send it ours in return if the peer changed, or
+    /// if it opened a session, in case it restarted and forgot about us.
+    ///
+    /// Return whether the peer is compatible.
+    async fn greet(
+        &self,
+        socket: &dyn Transport,
+        peer: SocketAddr,
+        (remote, opening): (Hello, bool),
+        send_buf: &mut Vec<u8>,
+    ) -> bool {
+        let hello = self.hello();
+        let incompatibility = hello.incompatibility(&remote, self.schema_tags);
+        if incompatibility.is_none() {
+            self.peer_fingerprints
+                .write()
+                .insert(peer, remote.fingerprints);
+            let len = usize::try_from(remote.len).unwrap_or(usize::MAX);
+            self.progress.write().remote_sizes.insert(peer, len);
+        }
+        let changed = self
+            .handshakes
+            .write()
+            .record(peer, incompatibility.is_none());
+        if changed {
+            match &incompatibility {
+                Some(reason) => warn!("{peer} is incompatible, not reconciling with it: {reason}"),
+                None => debug!(
+                    "hello from {peer}: {} elements, root hash {}",
+                    remote.len, remote.root_hash
+                ),
+            }
+        } else if !opening {
+            return incompatibility.is_none();
+        }
+        send_messages_to(
+            &[Message::<K, V, C>::Hello(hello)],
+            socket,
+            &peer,
+            send_buf,
+            &self.metrics,
+            &self.limiter,
+            &self.budgets,
+            self.compression,
+        )
+        .await;
+        incompatibility.is_none()
+    }
+
+    /// Send the segments opening a session with the peer.
+    async fn send_opening(
+        &self,
+        peer: SocketAddr,
+        session_id: u64,
+        segments: &[C],
+        send_buf: &mut Vec<u8>,
+    ) {
+        let Some((socket, target)) = route(&self.sockets, peer) else {
+            trace!("no socket to reach {peer}");
+            return;
+        };
+        let restricted = self.opening_segments(peer, session_id);
+        let opening = restricted.as_deref().unwrap_or(segments);
+        self.sessions.write().sent(peer, session_id, opening.len());
+        let fingerprint = self.session_fingerprint(peer);
+        if fingerprint != Self::fingerprints()[0] {
+            ServiceMetrics::add(&self.metrics.upgraded_fingerprint_sessions, 1);
+        }
+        send_buf.clear();
+        send_buf.extend_from_slice(&HEADER);
+        let clock = Message::<K, V, C>::Clock(self.clock.now());
+        write_or_drop(send_buf, &clock, &self.metrics);
+        if !self.handshakes.read().greeted(peer) {
+            let hello = Message::<K, V, C>::Hello(self.hello());
+            write_or_drop(send_buf, &hello, &self.metrics);
+        }
+        if let Some(message) = Self::fingerprints_message(fingerprint) {
+            write_or_drop(send_buf, &message, &self.metrics);
+        }
+        for segment in opening {
+            let segment = M::project_comparison(segment.clone(), fingerprint);
+            write_or_drop(
+                send_buf,
+                &Message::ComparisonItem::<K, V, C>(session_id, segment),
+                &self.metrics,
+            );
+        }
+        trace!(
+            "start_diff {} bytes to {target} in session {session_id}",
+            send_buf.len()
+        );
+        send_datagram(
+            socket,
+            send_buf,
+            target,
+            &self.metrics,
+            &self.limiter,
+            &self.budgets,
+            self.compression,
+        )
+        .await;
+    }
+
+    /// Push again to each peer the local writes made since the last convergence with it, in case
+    /// the original updates were lost.
+    pub async fn push_recent_writes(&self, send_buf: &mut Vec<u8>) {
+        if self.is_paused() {
+            return;
+        }
+        let peers = self.get_peers();
+        let pending = self.recent_writes.write().pending(&peers);
+        for (peer, keys) in pending {
+            self.push_writes(peer, keys, send_buf).await;
+        }
+    }
+
+    /// Push again to all the peers the local writes made since the given instant, regardless of
+    /// their convergence.
+    pub async fn flush_recent_writes(&self, since: Instant, send_buf: &mut Vec<u8>) {
+        let keys = self.recent_writes.read().since(since);
+        if keys.is_empty() || self.is_paused() {
+            return;
+        }
+        for peer in self.get_peers() {
+            self.push_writes(peer, keys.clone(), send_buf).await;
+        }
+    }
+
+    /// Send the current values of the given keys to the peer, within its sync range.
+    async fn push_writes(&self, peer: SocketAddr, keys: Vec<K>, send_buf: &mut Vec<u8>) {
+        let Some((socket, target)) = route(&self.sockets, peer) else {
+            trace!("no socket to reach {peer}");
+            return;
+        };
+        let range = self.sync_ranges.read().get(peer).cloned();
+        let messages: Vec<_> = {
+            let guard = self.map.read();
+            keys.into_iter()
+                .filter(|key| {
+                    range
+                        .as_ref()
+                        .is_none_or(|range| M::diff_range_contains(range, key))
+                })
+                .filter_map(|key| {
+                    let value = guard.get(&key)?.into_owned();
+                    Some(self.update_message((key, value)))
+                })
+                .collect()
+        };
+        if messages.is_empty() {
+            return;
+        }
+        debug!("pushing {} recent writes to {peer}", messages.len());
+        send_messages_to(
+            &messages,
+            socket,
+            &target,
+            send_buf,
+            &self.metrics,
+            &self.limiter,
+            &self.budgets,
+            self.compression,
+        )
+        .await;
+    }
+
+    /// Request again the referenced chunks that are still missing, from the peers that sent the
+    /// values referencing them.
+    pub async fn request_missing_chunks(&self, send_buf: &mut Vec<u8>) {
+        let mut by_source: HashMap<SocketAddr, Vec<ChunkHash>> = HashMap::new();
+        for (hash, source) in self.chunks.read().missing() {
+            by_source.entry(source).or_default().push(hash);
+        }
+        for (source, hashes) in by_source {
+            self.request_chunks(source, hashes, send_buf).await;
+        }
+    }
+
+    /// Ask the peer for the content of the given chunks.
+    async fn request_chunks(
+        &self,
+        peer: SocketAddr,
+        hashes: Vec<ChunkHash>,
+        send_buf: &mut Vec<u8>,
+    ) {
+        let Some((socket, target)) = route(&self.sockets, peer) else {
+            trace!("no socket to reach {peer}");
+            return;
+        };
+        debug!("requesting {} chunks from {peer}", hashes.len());
+        let messages: Vec<_> = hashes
+            .into_iter()
+            .map(Message::<K, V, C>::ChunkRequest)
+            .collect();
+        send_messages_to(
+            &messages,
+            socket,
+            &target,
+            send_buf,
+            &self.metrics,
+            &self.limiter,
+            &self.budgets,
+            self.compression,
+        )
+        .await;
+    }
+
+    /// Ask the known peers for their version of the key-value pair, and apply the ones received
+    /// like updates, until `quorum` peers responded or the delay elapsed.
+    ///
+    /// Only the peers holding the key respond. Return the local value afterwards.
+    pub async fn fetch_latest(&self, key: K, quorum: usize, delay: Duration) -> Option<V> {
+        let deadline = Instant::now() + delay;
+        let request_id = self.rng.write().gen();
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        {
+            let mut key_requests = self.key_requests.write();
+            // forget the requests whose future was dropped
+            key_requests.retain(|_, (expiration, _)| *expiration > Instant::now());
+            key_requests.insert(request_id, (deadline, sender));
+        }
+        let mut send_buf = Vec::new();
+        for peer in self.get_peers() {
+            let range = self.sync_ranges.read().get(peer).cloned();
+            if range.is_some_and(|range| !M::diff_range_contains(&range, &key)) {
+                continue;
+            }
+            let Some((socket, target)) = route(&self.sockets, peer) else {
+                trace!("no socket to reach {peer}");
+                continue;
+            };
+            let messages = [Message::<K, V, C>::KeyRequest(key.clone(), request_id)];
+            send_messages_to(
+                &messages,
+                socket,
+                &target,
+                &mut send_buf,
+                &self.metrics,
+                &self.limiter,
+                &self.budgets,
+                self.compression,
+            )
+            .await;
+        }
+        let mut responded = HashSet::new();
+        while responded.len() < quorum {
+            let response = tokio::time::timeout_at(deadline.into(), receiver.recv()).await;
+            let Ok(Some((peer, response_key, value))) = response else {
+                debug!("{} peers out of {quorum} responded", responded.len());
+                break;
+            };
+            if response_key != key || !responded.insert(peer) {
+                continue;
+            }
+            let range = self.sync_ranges.read().get(peer).cloned();
+            let missing_chunks =
+                self.apply_updates(peer, vec![(key.clone(), value)], range.as_ref());
+            if !missing_chunks.is_empty() {
+                self.request_chunks(peer, missing_chunks, &mut send_buf)
+                    .await;
+            }
+        }
+        self.key_requests.write().remove(&request_id);
+        self.map.read().get(&key).map(Cow::into_owned)
+    }
+
+    /// Handle the messages of a datagram received from a peer.
+    ///
+    /// Return whether the whole datagram was well-formed, and sent by a peer that is not banned.
+    async fn handle_messages(
+        &self,
+        socket: &dyn Transport,
+        recv_buf: &[u8],
+        (size, peer): (usize, SocketAddr),
+        deferred: &mut Deferred<C>,
+        send_buf: &mut Vec<u8>,
+    ) -> bool {
+        if self.is_banned(peer.ip()) {
+            trace!("ignoring datagram from banned peer {peer}");
+            return false;
+        }
+        if size == recv_buf.len() {
+            warn!("Buffer too small for message from {peer}, discarded");
+            ServiceMetrics::add(&self.metrics.oversize_datagrams, 1);
+            self.metrics.add_peer_oversize_datagrams(peer, 1);
+            if self.oversize.write().record(peer) {
+                // the peer would keep sending the same data, ask it for smaller datagrams
+                let max = u32::try_from(recv_buf.len() - 1).unwrap_or(u32::MAX);
+                debug!("asking {peer} for datagrams of at most {max} bytes");
+                let nack = Message::<K, V, C>::Nack {
+                    reason: NackReason::TooLarge,
+                    max,
+                };
+                send_messages_to(
+                    &[nack],
+                    socket,
+                    &peer,
+                    send_buf,
+                    &self.metrics,
+                    &self.limiter,
+                    &self.budgets,
+                    self.compression,
+                )
+                .await;
+            }
+            return false;
+        }
+        self.oversize.write().clear(peer);
+        trace!("received {} bytes from {peer}", size);
+        ServiceMetrics::add(&self.metrics.datagrams_received, 1);
+        ServiceMetrics::add(&self.metrics.bytes_received, size as u64);
+        if self
+            .duplicates
+            .write()
+            .is_duplicate(peer, &recv_buf[..size])
+        {
+            trace!("dropping duplicate datagram from {peer}");
+            ServiceMetrics::add(&self.metrics.duplicate_datagrams, 1);
+            return true;
+        }
+        let mut in_comparison = Vec::new();
+        let mut session_id = None;
+        let mut updates = Vec::new();
+        let mut acks = Vec::new();
+        let mut chunk_requests = Vec::new();
+        let mut key_requests = Vec::new();
+        let mut hello = None;
+        let datagram = &recv_buf[..size];
+        if datagram.starts_with(&AUTH_MAGIC) {
+            warn!("authenticated datagram from {peer}, but no auth key is set; discarded");
+            ServiceMetrics::add(&self.metrics.auth_failures, 1);
+            return false;
+        }
+        let parsed = match parse_messages::<Message<K, V, C>>(datagram) {
+            Ok(parsed) => parsed,
+            Err(DatagramError::Foreign) => {
+                warn!("datagram from {peer} does not belong to the protocol, discarded");
+                ServiceMetrics::add(&self.metrics.malformed_datagrams, 1);
+                return false;
+            }
+            Err(DatagramError::Compression(err)) => {
+                warn!("malformed compressed datagram from {peer}, discarded: {err}");
+                ServiceMetrics::add(&self.metrics.malformed_datagrams, 1);
+                return false;
+            }
+            Err(DatagramError::Version(version)) => {
+                warn!("unsupported protocol version {version} from {peer}, datagram discarded");
+                return false;
+            }
+        };
+        let malformed = parsed.malformed.is_some();
+        if let Some((err, dropped)) = parsed.malformed {
+            // the messages read so far are kept, the rest of the datagram is dropped
+            warn!("malformed message from {peer}, {dropped} bytes dropped: {err}");
+        }
+        for undecodable in &parsed.undecodable {
+            // only this message is dropped, for instance an update of another version of the
+            // values
+            match undecodable.schema {
+                Some(schema) if schema != self.schema_id => {}
+                _ => warn!(
+                    "undecodable message of {} bytes from {peer}, skipped: {}",
+                    undecodable.payload.len(),
+                    undecodable.error
+                ),
+            }
+            self.skip_undecodable(peer, undecodable.schema, &undecodable.payload);
+        }
+        if malformed || !parsed.undecodable.is_empty() {
+            ServiceMetrics::add(&self.metrics.malformed_datagrams, 1);
+        }
+        let mut namespace = 0;
+        let mut fingerprint = DefaultFingerprint::ID;
+        for message in parsed.messages {
+            match message {
+                Message::Namespace(id) => namespace = id,
+                _ if namespace != 0 => {
+                    trace!("skipping message of namespace {namespace} from {peer}")
+                }
+                Message::ComparisonItem(id, segment) => {
+                    if *session_id.get_or_insert(id) == id {
+                        in_comparison.push(segment);
+                    } else {
+                        debug!(
+                            "dropping segment of session {id} sent along session {session_id:?}"
+                        );
+                    }
+                }
+                Message::Update(update) => updates.push(update),
+                Message::SchemaUpdate(schema, update) => {
+                    updates.extend(self.check_schema(peer, schema, update));
+                }
+                Message::Peers(addrs) => self.add_gossiped_peers(
+                    addrs
+                        .into_iter()
+                        .map(|ip| self.default_peer_addr(ip))
+                        .collect(),
+                ),
+                Message::PeerAddrs(addrs) => self.add_gossiped_peers(addrs),
+                Message::Hello(remote) => hello = Some(remote),
+                Message::Nack {
+                    reason: NackReason::TooLarge,
+                    max,
+                } => {
+                    warn!("{peer} refused datagrams larger than {max} bytes, sending smaller ones");
+                    // NOTE: the peer counts the authentication tag in its receive buffer
+                    let max = (max as usize).saturating_sub(AUTH_TAG_SIZE);
+                    self.budgets.lower(peer, max);
+                }
+                Message::Ack(key, hash) => acks.push((key, hash)),
+                Message::ChunkRequest(hash) => chunk_requests.push(hash),
+                Message::KeyRequest(key, request_id) => key_requests.push((key, request_id)),
+                Message::KeyResponse(request_id, key, value) => {
+                    match self.key_requests.read().get(&request_id) {
+                        Some((_, sender)) => {
+                            let _ = sender.send((peer, key, value));
+                        }
+                        None => trace!("dropping response to unknown request {request_id}"),
+                    }
+                }
+                Message::Fingerprints(id, supported) => {
+                    fingerprint = id;
+                    self.peer_fingerprints.write().insert(peer, supported);
+                }
+                Message::Clock(sent) => {
+                    let received = self.clock.now();
+                    if let Some(offset) = self.clock_offsets.write().record(peer, sent, received) {
+                        warn!(
+                            "clock of {peer} is {}ms {} the local one; conflicts between dated \
+                            values are settled in favor of the most advanced clock",
+                            offset.num_milliseconds().abs(),
+                            if offset < chrono::Duration::zero() {
+                                "ahead of"
+                            } else {
+                                "behind"
+                            },
+                        );
+                    }
+                }
+                Message::ChunkData(hash, bytes) => {
+                    if !self.chunks.write().insert(hash, bytes) {
+                        trace!("dropping unexpected chunk from {peer}");
+                    }
+                }
+                Message::Fragment(id, index, total, bytes) => {
+                    let message =
+                        self.reassembly
+                            .write()
+                            .insert(peer, id, index.into(), total.into(), bytes);
+                    let Some(message) = message else {
+                        continue;
+                    };
+                    match decode_message::<Message<K, V, C>>(
+                        &message,
+                        FRAGMENT_SIZE * MAX_FRAGMENTS,
+                    ) {
+                        Ok(Some(Message::Update(update))) => updates.push(update),
+                        Ok(Some(Message::SchemaUpdate(schema, update))) => {
+                            updates.extend(self.check_schema(peer, schema, update));
+                        }
+                        Ok(_) => debug!("dropping fragmented message other than an update"),
+                        Err(err) => {
+                            warn!("malformed fragmented message from {peer}: {err}");
+                            ServiceMetrics::add(&self.metrics.malformed_datagrams, 1);
+                            self.skip_undecodable(peer, message_schema(&message), &message);
+                        }
+                    }
+                }
+            }
+        }
+        let compatible = match hello {
+            Some(remote) => {
+                let opening = !in_comparison.is_empty();
+                self.greet(socket, peer, (remote, opening), send_buf).await
+            }
+            None => {
+                if !in_comparison.is_empty() && self.handshakes.write().legacy(peer) {
+                    warn!(
+                        "{peer} sent segments without a hello, it runs a deprecated version; \
+                        its compatibility cannot be checked"
+                    );
+                }
+                !self.handshakes.read().is_incompatible(peer)
+            }
+        };
+        if !compatible {
+            trace!("dropping datagram from incompatible peer {peer}");
+            return !malformed;
+        }
+        if !acks.is_empty() {
+            trace!("received {} acks from {peer}", acks.len());
+            self.record_acks(peer, acks);
+        }
+        if !chunk_requests.is_empty() {
+            let messages: Vec<_> = {
+                let chunks = self.chunks.read();
+                chunk_requests
+                    .into_iter()
+                    .filter_map(|hash| {
+                        let bytes = chunks.get(&hash)?.to_vec();
+                        Some(Message::<K, V, C>::ChunkData(hash, bytes))
+                    })
+                    .collect()
+            };
+            if !messages.is_empty() {
+                debug!("sending {} chunks to {peer}", messages.len());
+                send_messages_to(
+                    &messages,
+                    socket,
+                    &peer,
+                    send_buf,
+                    &self.metrics,
+                    &self.limiter,
+                    &self.budgets,
+                    self.compression,
+                )
+                .await;
+            }
+        }
+        let opening =
+            session_id.is_some_and(|session_id| self.sessions.read().is_opening(peer, session_id));
+        // drop the segments of stale sessions
+        let reply_session_id = session_id.and_then(|session_id| {
+            let reply_session_id = self.sessions.write().accept(peer, session_id);
+            if reply_session_id.is_none() {
+                debug!(
+                    "dropping {} segments of stale session {session_id} from {peer}",
+                    in_comparison.len()
+                );
+            }
+            reply_session_id
+        });
+        // handle messages
+        let range = self.sync_ranges.read().get(peer).cloned();
+        let paused = self.is_paused();
+        if paused && (!in_comparison.is_empty() || !updates.is_empty()) {
+            // the differences are found again by the sessions started after resuming
+            trace!("synchronization paused, dropping segments and updates from {peer}");
+            ServiceMetrics::add(&self.metrics.datagrams_paused, 1);
+        }
+        if !key_requests.is_empty() {
+            let messages: Vec<_> = {
+                let guard = self.map.read();
+                key_requests
+                    .into_iter()
+                    .filter(|(key, _)| {
+                        range
+                            .as_ref()
+                            .is_none_or(|range| M::diff_range_contains(range, key))
+                    })
+                    .filter_map(|(key, request_id)| {
+                        let value = guard.get(&key)?.into_owned();
+                        Some(Message::<K, V, C>::KeyResponse(request_id, key, value))
+                    })
+                    .collect()
+            };
+            if !messages.is_empty() {
+                debug!("responding to {} key requests from {peer}", messages.len());
+                send_messages_to(
+                    &messages,
+                    socket,
+                    &peer,
+                    send_buf,
+                    &self.metrics,
+                    &self.limiter,
+                    &self.budgets,
+                    self.compression,
+                )
+                .await;
+            }
+        }
+        if !in_comparison.is_empty() {
+            if Self::fingerprints().contains(&fingerprint) {
+                in_comparison = M::normalize_comparison(
+                    in_comparison
+                        .into_iter()
+                        .map(|segment| M::resolve_comparison(segment, fingerprint))
+                        .collect(),
+                );
+            } else {
+                debug!("dropping segments from {peer} with unsupported fingerprint {fingerprint}");
+                in_comparison.clear();
+            }
+        }
+        if let Some(reply_session_id) =
+            reply_session_id.filter(|_| !paused && !in_comparison.is_empty())
+        {
+            debug!("received {} segments", in_comparison.len());
+            ServiceMetrics::add(&self.metrics.segments_processed, in_comparison.len() as u64);
+            if let Some(size) = in_comparison.iter().find_map(M::whole_size) {
+                self.progress.write().remote_sizes.insert(peer, size);
+            }
+            self.reply_comparison(
+                socket,
+                peer,
+                (reply_session_id, opening, fingerprint),
+                in_comparison,
+                range.as_ref(),
+                deferred,
+                send_buf,
+            )
+            .await;
+        }
+        if !paused && !updates.is_empty() {
+            debug!("received {} updates", updates.len());
+            let allowed = self.update_budget.take(peer, updates.len());
+            if allowed < updates.len() {
+                // the differences are found again by the next reconciliation sessions
+                debug!(
+                    "dropping {} updates from {peer} beyond its budget",
+                    updates.len() - allowed
+                );
+                ServiceMetrics::add(
+                    &self.metrics.updates_throttled,
+                    (updates.len() - allowed) as u64,
+                );
+                updates.truncate(allowed);
+            }
+            // the write lock is released between the chunks, so that local reads are not stalled
+            let mut missing_chunks = Vec::new();
+            let mut updates = updates.into_iter().peekable();
+            while updates.peek().is_some() {
+                let chunk = updates.by_ref().take(self.update_chunk).collect();
+                missing_chunks.extend(self.apply_updates(peer, chunk, range.as_ref()));
+                if updates.peek().is_some() {
+                    tokio::task::yield_now().await;
+                }
+            }
+            if !missing_chunks.is_empty() {
+                self.request_chunks(peer, missing_chunks, send_buf).await;
+            }
+        }
+        !malformed
+    }
+
+    /// Apply the updates received from the peer, merging them with the local values, and send
+    /// the merged values to the peers.
+    ///
+    /// Return the chunks referenced by the new values that are missing locally.
+    fn apply_updates(
+        &self,
+        peer: SocketAddr,
+        updates: Vec<(K, V)>,
+        range: Option<&D>,
+    ) -> Vec<ChunkHash> {
+        let collect = self.has_post_insert();
+        let mut inserted: Inserted<K, V> = Vec::new();
+        let mut applied = 0;
+        // merged values, which the peer does not hold
+        let mut merged_updates = Vec::new();
+        // chunks referenced by the values received, to request from the peer
+        let mut wanted = Vec::new();
+        let mut guard = self.map.write();
+        let collected = self.collected.read();
+        let update_filter = self.update_filter.read();
+        let canonical = guard.canonical_hashing();
+        for (k, v) in updates {
+            if !self.check_hashable(canonical, &k, &v) {
+                continue;
+            }
+            if range.is_some_and(|range| !M::diff_range_contains(range, &k)) {
+                trace!("rejecting update from {peer} outside of the synchronized range");
+                ServiceMetrics::add(&self.metrics.updates_rejected, 1);
+                continue;
+            }
+            if collected
+                .get(&k)
+                .is_some_and(|&(hash, _)| hash == version_hash(&k, &v))
+            {
+                ServiceMetrics::add(&self.metrics.updates_rejected, 1);
+                continue;
+            }
+            // NOTE: the journal is locked again by the pre-insertion callback
+            if self.deletions.read().rejects(&k, &v, &*self.policy) {
+                trace!("rejecting update from {peer} older than a recent deletion");
+                ServiceMetrics::add(&self.metrics.updates_rejected, 1);
+                continue;
+            }
+            // the element hashes are compared first, since the local value may be costly to get
+            if guard.hash_of(&k) == Some(element_hash(&*guard, &k, &v)) {
+                trace!("skipping update from {peer} identical to the local value");
+                ServiceMetrics::add(&self.metrics.updates_rejected, 1);
+                continue;
+            }
+            if let Some(filter) = &*update_filter {
+                if !filter(&k, &v) {
+                    trace!("rejecting update from {peer} discarded by the filter");
+                    ServiceMetrics::add(&self.metrics.updates_rejected, 1);
+                    continue;
+                }
+            }
+            let (change, merged) = resolve_update(&*self.policy, &k, guard.get(&k).as_deref(), v);
+            if let Some(merged) = merged {
+                merged_updates.push((k.clone(), merged));
+            }
+            if let Some(v) = change {
+                if let Some(chunk_refs) = &*self.chunk_refs.read() {
+                    wanted.extend(chunk_refs(&v));
+                }
+                let new_value = collect.then(|| (k.clone(), v.clone()));
+                let old_value = self.insert_locked(&mut guard, k, v, ChangeOrigin::Peer(peer));
+                if let Some((k, v)) = new_value {
+                    inserted.push((k, v, old_value));
+                }
+                applied += 1;
+            } else {
+                ServiceMetrics::add(&self.metrics.updates_rejected, 1);
+            }
+        }
+        drop(collected);
+        drop(update_filter);
+        ServiceMetrics::add(&self.metrics.updates_applied, applied);
+        if applied > 0 {
+            self.metrics.add_peer_updates_applied(peer, applied);
+        }
+        let hash = self.batch_hash(&guard);
+        drop(guard);
+        self.post_insert(&inserted, ChangeOrigin::Peer(peer), hash);
+        let missing_chunks = self.chunks.write().want(&wanted, peer);
+        if !merged_updates.is_empty() {
+            debug!("sending {} merged values", merged_updates.len());
+            {
+                let mut recent_writes = self.recent_writes.write();
+                for (key, _) in &merged_updates {
+                    recent_writes.record(key.clone());
+                }
+            }
+            self.broadcast_updates(&merged_updates);
+        }
+        missing_chunks
+    }
+
+    /// Record the peers whose global hash matched the local one, either confirmed by the sessions
+    /// they opened, or because they did not reply to the ones opened locally, and alert about the
+    /// peers that did not match for too long.
+    fn watch_roots(
+        &self,
+        hash: FingerprintOf<M>,
+        unanswered: Vec<SocketAddr>,
+        peers: &[SocketAddr],
+    ) {
+        let checked = {
+            let mut root_watch = self.root_watch.write();
+            for peer in unanswered {
+                root_watch.matched(peer);
+            }
+            for (&peer, &(confirmed_hash, _)) in self.confirmed.read().iter() {
+                if confirmed_hash == hash {
+                    root_watch.matched(peer);
+                }
+            }
+            root_watch.check(peers)
+        };
+        if let Some((alert, diverging)) = checked {
+            for (peer, elapsed) in diverging {
+                warn!("global hash not matched with {peer} for {elapsed:?} despite the contact");
+                alert(peer, elapsed);
+            }
+        }
+    }
+
+    /// Forget the divergences with the peer covered by the matching segments, and record the
+    /// differences found.
+    fn track_divergences(&self, peer: SocketAddr, matched: &[C], differences: &[D]) {
+        {
+            let mut divergences = self.divergences.write();
+            divergences.clear(peer, |range| {
+                matched
+                    .iter()
+                    .any(|segment| M::comparison_covers(segment, range))
+            });
+            divergences.record(peer, differences, M::differences_overlap);
+        }
+        self.update_divergence_age();
+    }
+
+    /// Update the age of the oldest divergence in the metrics, forgetting the expired peers.
+    fn update_divergence_age(&self) {
+        let peers = self.get_peers();
+        let mut divergences = self.divergences.write();
+        divergences.retain_peers(&peers);
+        self.metrics.set_max_divergence_age(divergences.max_age());
+    }
+
+    /// Compare the segments received from the peer in a session, after the ones deferred from
+    /// the previous datagrams of the session, and send the reply.
+    ///
+    /// The segments are compared in order until the returned segments would not fit in a single
+    /// datagram, since a reply split over several datagrams is lost if any of them is. The
+    /// remaining segments are deferred to the next datagram of the session, or to the next
+    /// timeout.
+    #[allow(clippy::too_many_arguments)]
+    async fn reply_comparison(
+        &self,
+        socket: &dyn Transport,
+        peer: SocketAddr,
+        (reply_session_id, opening, fingerprint): (u64, bool, u8),
+        in_comparison: Vec<C>,
+        range: Option<&D>,
+        deferred: &mut Deferred<C>,
+        send_buf: &mut Vec<u8>,
+    ) {
+        let mut pending = deferred
+            .remove(&(peer, reply_session_id))
+            .map(|(_, pending)| pending)
+            .unwrap_or_default();
+        let resumed = !pending.is_empty();
+        // an opening that does not cover all the keys is sent back once it matched, so that the
+        // peer learns it
+        let covers_all = in_comparison
+            .iter()
+            .any(|segment| M::whole_size(segment).is_some());
+        let echo = (opening && !covers_all).then(|| in_comparison.clone());
+        // without a sync range, such an opening is over the priority range of the peer
+        let partial_opening = echo.is_some() && range.is_none();
+        let priority_session =
+            !opening && self.priority.read().sessions.get(&peer) == Some(&reply_session_id);
+        pending.extend(in_comparison);
+        if pending.len() > MAX_DEFERRED_SEGMENTS {
+            debug!(
+                "dropping {} segments from {peer}; the differences are found again by the next sessions",
+                pending.len() - MAX_DEFERRED_SEGMENTS
+            );
+            pending.truncate(MAX_DEFERRED_SEGMENTS);
+        }
+        let mut differences = Vec::new();
+        let mut out_comparison = Vec::new();
+        let mut reply_opening = None;
+        // the segments that matched, which resolve the divergences they cover
+        let track_divergences = self.divergences.read().has(peer);
+        let mut matched = Vec::new();
+        let fingerprints_message = Self::fingerprints_message(fingerprint);
+        let max_reply_size = self.budgets.get(peer) - HEADER.len();
+        {
+            let guard = self.map.read();
+            let mut reply_size = fingerprints_message.as_ref().map_or(0, message_size);
+            // the segments split from any of them must fit in the rest of the reply
+            let largest_segment = pending
+                .iter()
+                .map(|segment| {
+                    message_size(&Message::ComparisonItem::<K, V, &C>(
+                        reply_session_id,
+                        segment,
+                    ))
+                })
+                .max()
+                .unwrap_or(0);
+            let max_fan_out = max_reply_size.saturating_sub(reply_size) / largest_segment.max(1);
+            let density = self.sessions.read().density(peer, reply_session_id);
+            let strategy = self
+                .diff_tuning
+                .strategy(&*guard, pending.iter(), density, max_fan_out);
+            // the updates sent directly must fit in the bytes sent in a round
+            let mut direct_size = 0;
+            let mut compared = 0;
+            let mut differing = 0;
+            while let Some(segment) = pending.pop_front() {
+                let mut segment_out = Vec::new();
+                let mut segment_differences = Vec::new();
+                // the segment is only deferred once a reply is pending, keep it for that case
+                let kept = (!out_comparison.is_empty()).then(|| segment.clone());
+                let mut segments = vec![segment];
+                if let Some(range) = range {
+                    // only compare the keys synchronized with the peer
+                    segments = guard.clip_comparison(segments, range, &mut segment_out);
+                }
+                let compared_segments = track_divergences.then(|| segments.clone());
+                let split = (strategy.direct_items > 0).then(|| segments.clone());
+                let mut segment_direct_size = 0;
+                guard.diff_round_split(
+                    segments,
+                    &mut segment_out,
+                    &mut segment_differences,
+                    &strategy,
+                );
+                if let Some(segments) = split.filter(|_| !segment_differences.is_empty()) {
+                    let budget = MAX_ROUND_BYTES.saturating_sub(direct_size);
+                    let items: Option<usize> = segment_differences
+                        .iter()
+                        .map(|difference| guard.difference_size(difference))
+                        .sum();
+                    let mut sizes = guard
+                        .enumerate_diff_ranges_iter(segment_differences.clone())
+                        .map(|update| message_size(&self.update_message(update)));
+                    // estimate the size of the updates from the first one before listing them,
+                    // and stop listing them once they exceed the budget
+                    let size = match sizes.next() {
+                        Some(first)
+                            if items.is_some_and(|items| first.saturating_mul(items) > budget) =>
+                        {
+                            None
+                        }
+                        Some(first) => std::iter::once(first)
+                            .chain(sizes)
+                            .try_fold(0, |size, next| Some(size + next).filter(|&s| s <= budget)),
+                        None => Some(0),
+                    };
+                    if let Some(size) = size {
+                        segment_direct_size = size;
+                    } else {
+                        // split the segment instead
+                        segment_out.clear();
+                        segment_differences.clear();
+                        guard.diff_round_split(
+                            segments,
+                            &mut segment_out,
+                            &mut segment_differences,
+                            &SplitStrategy {
+                                direct_items: 0,
+                                ..strategy
+                            },
+                        );
+                    }
+                }
+                let matches = segment_out.is_empty() && segment_differences.is_empty();
+                if matches {
+                    matched.extend(compared_segments.into_iter().flatten());
+                }
+                let segment_out: Vec<_> = segment_out
+                    .into_iter()
+                    .map(|segment| M::project_comparison(segment, fingerprint))
+                    .collect();
+                let size: usize = segment_out
+                    .iter()
+                    .map(|segment| {
+                        message_size(&Message::ComparisonItem::<K, V, &C>(
+                            reply_session_id,
+                            segment,
+                        ))
+                    })
+                    .sum();
+                if let Some(segment) = kept.filter(|_| reply_size + size > max_reply_size) {
+                    pending.push_front(segment);
+                    break;
+                }
+                reply_size += size;
+                direct_size += segment_direct_size;
+                compared += 1;
+                if !matches {
+                    differing += 1;
+                }
+                out_comparison.extend(segment_out);
+                differences.extend(segment_differences);
+            }
+            if compared >= 2 {
+                self.sessions.write().record_density(
+                    peer,
+                    reply_session_id,
+                    differing as f64 / compared as f64,
+                );
+            }
+            let differing_ranges = out_comparison.len() + differences.len() + pending.len();
+            self.progress
+                .write()
+                .differing_ranges
+                .insert(peer, differing_ranges);
+            if track_divergences || !differences.is_empty() {
+                self.track_divergences(peer, &matched, &differences);
+            }
+            if !pending.is_empty() {
+                debug!("deferring {} segments from {peer}", pending.len());
+                ServiceMetrics::add(&self.metrics.segments_deferred, pending.len() as u64);
+                deferred.insert((peer, reply_session_id), (fingerprint, pending));
+            } else if !resumed && out_comparison.is_empty() && differences.is_empty() {
+                // NOTE: when the segments of the datagram were deferred, the previous replies
+                // of the session already held differences
+                let hash = guard.hash(&..);
+                debug!("converged with {peer} at hash {hash}");
+                if opening && !partial_opening {
+                    // all the keys were compared
+                    if track_divergences {
+                        self.divergences.write().clear_peer(peer);
+                        self.update_divergence_age();
+                    }
+                    self.root_watch.write().matched(peer);
+                    let previous = self.confirmed.write().insert(peer, (hash, Instant::now()));
+                    if previous.map(|(previous_hash, _)| previous_hash) != Some(hash) {
+                        // open a session in return, so that the peer learns it too
+                        reply_opening = Some(guard.start_diff());
+                    }
+                }
+                if let Some(echo) = echo {
+                    out_comparison = echo
+                        .into_iter()
+                        .map(|segment| M::project_comparison(segment, fingerprint))
+                        .collect();
+                }
+                if priority_session {
+                    debug!("priority range matched with {peer}");
+                    self.priority.write().synced.insert(peer);
+                } else if !partial_opening {
+                    self.recent_writes.write().converged(peer);
+                }
+                self.convergence
+                    .send_replace(Some(Convergence { peer, hash }));
+            }
+        }
+        if let Some(segments) = reply_opening {
+            let session_id = self.sessions.write().start(peer);
+            self.send_opening(peer, session_id, &segments, send_buf)
+                .await;
+        }
+        let mut messages = Vec::new();
+        if out_comparison.is_empty() {
+            // the peer has nothing left to compare
+            let mut sessions = self.sessions.write();
+            sessions.complete(peer, reply_session_id);
+            self.metrics.set_longest_session(sessions.longest_active());
+        } else {
+            self.sessions
+                .write()
+                .sent(peer, reply_session_id, out_comparison.len());
+            debug!("returning {} segments", out_comparison.len());
+            trace!("segments: {out_comparison:?}");
+            messages.extend(fingerprints_message);
+            for segment in out_comparison {
+                messages.push(Message::ComparisonItem::<K, V, C>(
+                    reply_session_id,
+                    segment,
+                ))
+            }
+        }
+        if !differences.is_empty() {
+            // the differences found for overlapping segments may overlap
+            let differences = M::normalize_differences(differences);
+            debug!("returning {} diff_ranges", differences.len());
+            trace!("diff_ranges: {differences:?}");
+            let mut differences = VecDeque::from(differences);
+            let mut sent = 0;
+            while let Some(diff_range) = differences.pop_front() {
+                if sent >= MAX_ROUND_BYTES {
+                    debug!(
+                        "sent {sent} bytes to {peer}; {} diff_ranges left for next sessions",
+                        differences.len() + 1
+                    );
+                    break;
+                }
+                // only hold the read lock while enumerating a chunk of the updates
+                {
+                    let (updates, rest) = self
+                        .map
+                        .read()
+                        .enumerate_diff_range_chunk(diff_range, ENUMERATION_CHUNK);
+                    for update in updates {
+                        messages.push(self.update_message(update));
+                    }
+                    // resume after the last update at the next iteration
+                    if let Some(rest) = rest {
+                        differences.push_front(rest);
+                    }
+                }
+                if messages.len() >= ENUMERATION_CHUNK {
+                    sent += send_messages_to(
+                        &messages,
+                        socket,
+                        &peer,
+                        send_buf,
+                        &self.metrics,
+                        &self.limiter,
+                        &self.budgets,
+                        self.compression,
+                    )
+                    .await;
+                    messages.clear();
+                }
+            }
+        }
+        if !messages.is_empty() {
+            send_messages_to(
+                &messages,
+                socket,
+                &peer,
+                send_buf,
+                &self.metrics,
+                &self.limiter,
+                &self.budgets,
+                self.compression,
+            )
+            .await;
+        }
+    }
+}
+
+/// Hash identifying a specific version of a key-value pair in acknowledgements.
+pub(crate) fn version_hash<K: Hash, V: Hash>(key: &K, value: &V) -> u64 {
+    DefaultFingerprint::hash(key, value)
+}
+
+/// Hash of an element as the map computes it, to compare with its
+/// [`hash_of`](HashRangeQueryable::hash_of).
+pub(crate) fn element_hash<M, K, V>(map: &M, key: &K, value: &V) -> FingerprintOf<M>
+where
+    M: HashRangeQueryable,
+    K: Hash + Serialize,
+    V: Hash + Serialize,
+{
+    if map.canonical_hashing() {
+        canonical_hash::<M::Fingerprint, K, V>(key, value)
+    } else {
+        M::Fingerprint::hash(key, value)
+    }
+}
+
+/// Whether an element can be stored in a map hashing in the canonical mode or not: in that mode,
+/// an element that cannot be serialized cannot be hashed, and is dropped with a warning, as the
+/// messages that cannot be serialized.
+pub(crate) fn hashable<K: Serialize, V: Serialize>(canonical: bool, key: &K, value: &V) -> bool {
+    if !canonical {
+        return true;
+    }
+    let options = DefaultOptions::new();
+    match options
+        .serialized_size(key)
+        .and_then(|_| options.serialized_size(value))
+    {
+        Ok(_) => true,
+        Err(err) => {
+            warn!("dropping an element that cannot be serialized: {err}");
+            false
+        }
+    }
+}
+
+/// Append a message to the datagram, prefixed by its length.
+///
+/// The datagram is left unchanged when the message cannot be serialized.
+pub(crate) fn write_message<M: Serialize>(buf: &mut Vec<u8>, message: &M) -> Result<(), Error> {
+    let start = buf.len();
+    buf.extend_from_slice(&[0; 2]);
+    if let Err(err) = DefaultOptions::new().serialize_into(&mut *buf, message) {
+        buf.truncate(start);
+        return Err(Error::Serialize(err));
+    }
+    // NOTE: a message that does not fit in a datagram cannot be received anyway
+    let size = u16::try_from(buf.len() - start - 2).unwrap_or(u16::MAX);
+    buf[start..start + 2].copy_from_slice(&size.to_le_bytes());
+    Ok(())
+}
+
+/// Append a message to the datagram, or drop it with a warning if it cannot be serialized.
+///
+/// Return whether the message was written.
+fn write_or_drop<M: Serialize>(buf: &mut Vec<u8>, message: &M, metrics: &ServiceMetrics) -> bool {
+    match write_message(buf, message) {
+        Ok(()) => true,
+        Err(err) => {
+            warn!("dropping message: {err}");
+            ServiceMetrics::add(&metrics.serialize_errors, 1);
+            false
+        }
+    }
+}
+
+/// Number of bytes taken by a message in a datagram, including its length.
+pub(crate) fn message_size<M: Serialize>(message: &M) -> usize {
+    // NOTE: a message that cannot be serialized is dropped when written
+    let size = DefaultOptions::new().serialized_size(message).unwrap_or(0);
+    2 + size as usize
+}
+
+/// Read the next message of a datagram, or `None` if its type is unknown.
+///
+/// Trailing bytes in a message are ignored, so that fields can be added to existing messages.
+#[cfg(test)]
+pub(crate) fn read_message<M: DeserializeOwned>(reader: &mut &[u8]) -> bincode::Result<Option<M>> {
+    decode_message(read_framed(reader)?, BUFFER_SIZE)
+}
+
+/// Read the bytes of the next message of a datagram, without its length.
+///
+/// Fail if the datagram is truncated, in which case the following messages cannot be found.
+pub(crate) fn read_framed<'a>(reader: &mut &'a [u8]) -> bincode::Result<&'a [u8]> {
+    let eof = || {
+        Box::new(bincode::ErrorKind::Io(
+            std::io::ErrorKind::UnexpectedEof.into(),
+        ))
+    };
+    let (size, rest) = reader.split_first_chunk::<2>().ok_or_else(eof)?;
+    let size = u16::from_le_bytes(*size) as usize;
+    if rest.len() < size {
+        return Err(eof());
+    }
+    let (message, rest) = rest.split_at(size);
+    *reader = rest;
+    Ok(message)
+}
+
+/// Schema id of a [`Message::SchemaUpdate`], read even if its key-value pair cannot be.
+fn message_schema(message: &[u8]) -> Option<u64> {
+    match decode_message::<Message<(), (), ()>>(message, BUFFER_SIZE) {
+        Ok(Some(Message::SchemaUpdate(schema, _))) => Some(schema),
+        _ => None,
+    }
+}
+
+/// Why a datagram was discarded before reading its messages.
+pub(crate) enum DatagramError {
+    /// The datagram does not belong to the protocol
+    Foreign,
+    /// The messages of a compressed datagram could not be decompressed
+    Compression(std::io::Error),
+    /// The datagram was sent with an unsupported version of the protocol
+    Version(u8),
+}
+
+/// Message of a datagram that could not be deserialized, and was skipped.
+pub(crate) struct Undecodable {
+    pub error: bincode::Error,
+    /// Schema id the message was tagged with, if it is a [`Message::SchemaUpdate`]
+    pub schema: Option<u64>,
+    /// Bytes of the message, without its length
+    pub payload: Vec<u8>,
+}
+
+/// Messages of a datagram, up to the end of the datagram or until it is truncated.
+pub(crate) struct ParsedDatagram<M> {
+    pub messages: Vec<M>,
+    /// Messages that could not be deserialized; the following ones are read anyway
+    pub undecodable: Vec<Undecodable>,
+    /// Error reading the datagram where it is truncated, and the number of bytes dropped after it
+    pub malformed: Option<(bincode::Error, usize)>,
+}
+
+/// Read the messages of an unauthenticated datagram, decompressing them first if needed.
+///
+/// The messages of unknown types are skipped, as well as the ones that cannot be deserialized,
+/// which are returned. This does not depend on the state of the service, so that it can be fuzzed
+/// on its own.
+pub(crate) fn parse_messages<M: DeserializeOwned>(
+    datagram: &[u8],
+) -> Result<ParsedDatagram<M>, DatagramError> {
+    if !datagram.starts_with(&MAGIC) || datagram.len() < HEADER.len() {
+        return Err(DatagramError::Foreign);
+    }
+    let decompressed;
+    let mut reader = &datagram[HEADER.len()..];
+    let version = datagram[MAGIC.len()];
+    if version == PROTOCOL_VERSION | COMPRESSED {
+        decompressed = compression::decompress(reader).map_err(DatagramError::Compression)?;
+        reader = &decompressed;
+    } else if version != PROTOCOL_VERSION {
+        return Err(DatagramError::Version(version));
+    }
+    let mut parsed = ParsedDatagram {
+        messages: Vec::new(),
+        undecodable: Vec::new(),
+        malformed: None,
+    };
+    while !reader.is_empty() {
+        let payload = match read_framed(&mut reader) {
+            Ok(payload) => payload,
+            Err(err) => {
+                parsed.malformed = Some((err, reader.len()));
+                break;
+            }
+        };
+        match decode_message(payload, BUFFER_SIZE) {
+            Ok(Some(message)) => parsed.messages.push(message),
+            Ok(None) => trace!("skipping message of unknown type"),
+            Err(error) => parsed.undecodable.push(Undecodable {
+                error,
+                schema: message_schema(payload),
+                payload: payload.to_vec(),
+            }),
+        }
+    }
+    Ok(parsed)
+}
+
+/// Read the segments and the updates of a datagram, as the service does, and skip the other
+/// messages.
+pub(crate) fn parse_datagram<K, V, C>(datagram: &[u8]) -> (Vec<C>, Vec<(K, V)>)
+where
+    K: DeserializeOwned + Serialize,
+    V: DeserializeOwned + Serialize,
+    C: DeserializeOwned + Serialize,
+{
+    let mut segments = Vec::new();
+    let mut updates = Vec::new();
+    let Ok(parsed) = parse_messages::<Message<K, V, C>>(datagram) else {
+        return (segments, updates);
+    };
+    let mut namespace = 0;
+    for message in parsed.messages {
+        match message {
+            Message::Namespace(id) => namespace = id,
+            _ if namespace != 0 => {}
+            Message::ComparisonItem(_, segment) => segments.push(segment),
+            Message::Update(update) | Message::SchemaUpdate(_, update) => updates.push(update),
+            _ => {}
+        }
+    }
+    (segments, updates)
+}
+
+/// Deserialize a message of at most `limit` bytes, or return `None` if its type is unknown.
+pub(crate) fn decode_message<M: DeserializeOwned>(
+    message: &[u8],
+    limit: usize,
+) -> bincode::Result<Option<M>> {
+    // NOTE: tags below 251 are encoded on a single byte
+    if message.first().is_some_and(|&tag| tag >= MESSAGE_TAGS) {
+        return Ok(None);
+    }
+    DefaultOptions::new()
+        .with_limit(limit as u64)
+        .allow_trailing_bytes()
+        .deserialize(message)
+        .map(Some)
+}
+
+/// Split the messages of a datagram by namespace, into a datagram for each namespace.
+///
+/// Return `None` if the datagram does not belong to the protocol. The messages are not decoded,
+/// so that malformed ones are reported when handling the datagram of their namespace.
+pub(crate) fn split_namespaces(datagram: &[u8]) -> Option<Vec<(u16, Vec<u8>)>> {
+    if datagram.starts_with(&COMPRESSED_HEADER) {
+        let messages = compression::decompress(&datagram[HEADER.len()..]).ok()?;
+        return split_namespaces(&[&HEADER[..], &messages].concat());
+    }
+    if !datagram.starts_with(&HEADER) {
+        return None;
+    }
+    let mut datagrams: Vec<(u16, Vec<u8>)> = Vec::new();
+    let mut namespace = 0;
+    let mut reader = &datagram[HEADER.len()..];
+    while !reader.is_empty() {
+        let framed = next_framed(&mut reader);
+        if framed.get(2) == Some(&NAMESPACE_TAG) {
+            let message = decode_message::<Message<(), (), ()>>(&framed[2..], BUFFER_SIZE);
+            if let Ok(Some(Message::Namespace(id))) = message {
+                namespace = id;
+                continue;
+            }
+        }
+        match datagrams.iter_mut().find(|(id, _)| *id == namespace) {
+            Some((_, datagram)) => datagram.extend_from_slice(framed),
+            None => datagrams.push((namespace, [&HEADER[..], framed].concat())),
+        }
+    }
+    Some(datagrams)
+}
+
+/// Mark the messages of a datagram as belonging to the given namespace.
+///
+/// The datagram is split in two when the marker makes it too large.
+pub(crate) fn tag_namespace(datagram: &[u8], namespace: u16) -> Vec<Vec<u8>> {
+    let mut start = HEADER.to_vec();
+    write_message(&mut start, &Message::<(), (), ()>::Namespace(namespace))
+        .expect("namespace markers are serializable");
+    let mut datagrams = vec![start.clone()];
+    let mut reader = datagram.get(HEADER.len()..).unwrap_or_default();
+    while !reader.is_empty() {
+        let framed = next_framed(&mut reader);
+        let current = datagrams.last_mut().unwrap();
+        if current.len() + framed.len() > MAX_DATAGRAM_SIZE && current.len() > start.len() {
+            datagrams.push([&start[..], framed].concat());
+        } else {
+            current.extend_from_slice(framed);
+        }
+    }
+    datagrams
+}
+
+/// Take the next message of a datagram, with its length prefix, without decoding it.
+///
+/// A truncated message takes the rest of the datagram.
+fn next_framed<'a>(reader: &mut &'a [u8]) -> &'a [u8] {
+    let size = match reader.split_first_chunk::<2>() {
+        Some((size, rest)) => 2 + rest.len().min(u16::from_le_bytes(*size) as usize),
+        None => reader.len(),
+    };
+    let (framed, rest) = reader.split_at(size);
+    *reader = rest;
+    framed
+}
+
+/// Select the socket with the same address family as the given peer, along with the address of
+/// the peer.
+fn route(sockets: &[Box<dyn Transport>], addr: SocketAddr) -> Option<(&dyn Transport, SocketAddr)> {
+    sockets.iter().find_map(|socket| {
+        let socket = &**socket;
+        let local_addr = socket.local_addr().ok()?;
+        (local_addr.is_ipv4() == addr.is_ipv4()).then_some((socket, addr))
+    })
+}
+
+/// Receive a datagram from any of the sockets, in the buffer with the same index.
+///
+/// Return the index of the socket that received the datagram.
+async fn recv_from_any(
+    sockets: &[Box<dyn Transport>],
+    recv_bufs: &mut [Vec<u8>],
+) -> (usize, std::io::Result<(usize, SocketAddr)>) {
+    match (sockets, recv_bufs) {
+        ([socket], [recv_buf]) => (0, socket.recv_from(recv_buf).await),
+        ([socket0, socket1], [recv_buf0, recv_buf1]) => tokio::select! {
+            res = socket0.recv_from(recv_buf0) => (0, res),
+            res = socket1.recv_from(recv_buf1) => (1, res),
+        },
+        _ => unreachable!("the service needs one or two sockets"),
+    }
+}
+
+/// Send the datagram, retrying a few times on failure.
+///
+/// Return the number of bytes sent, or 0 if the datagram could not be sent.
+async fn send_to_retry(
+    socket: &dyn Transport,
+    buf: &[u8],
+    target: SocketAddr,
+    metrics: &ServiceMetrics,
+    limiter: &RateLimiter,
+) -> usize {
+    limiter.acquire(buf.len()).await;
+    for retry in 1..=MAX_SENDTO_RETRIES {
+        match socket.send_to(buf, target).await {
+            Ok(size) => {
+                ServiceMetrics::add(&metrics.datagrams_sent, 1);
+                ServiceMetrics::add(&metrics.bytes_sent, size as u64);
+                metrics.clear_last_error();
+                return size;
+            }
+            Err(err) if retry == MAX_SENDTO_RETRIES => {
+                // the service keeps running: the peers get the differences at the next sessions
+                warn!("failed to send {} bytes to {target}: {err}", buf.len());
+                ServiceMetrics::add(&metrics.send_errors, 1);
+                metrics.set_last_error(Error::Send(target, err));
+            }
+            Err(_) => tokio::time::sleep(Duration::from_millis(1)).await,
+        }
+    }
+    0
+}
+
+/// Send the datagram, compressing it if enabled.
+///
+/// A datagram too large to fit once compressed, or larger than the datagrams the target accepts,
+/// is split between its messages, into datagrams sent separately.
+///
+/// Return the number of bytes sent.
+async fn send_datagram(
+    socket: &dyn Transport,
+    buf: &[u8],
+    target: SocketAddr,
+    metrics: &ServiceMetrics,
+    limiter: &RateLimiter,
+    budgets: &DatagramBudgets,
+    compression: Compression,
+) -> usize {
+    let max_size = budgets.get(target);
+    if !compression.is_enabled() && buf.len() <= max_size {
+        return send_to_retry(socket, buf, target, metrics, limiter).await;
+    }
+    let mut sent = 0;
+    let mut parts = vec![&buf[HEADER.len()..]];
+    while let Some(messages) = parts.pop() {
+        let datagram = match compression.compress(messages) {
+            Some(compressed) if HEADER.len() + compressed.len() <= max_size => {
+                ServiceMetrics::add(&metrics.datagrams_compressed, 1);
+                [&COMPRESSED_HEADER[..], &compressed].concat()
+            }
+            _ if HEADER.len() + messages.len() <= max_size || is_single_message(messages) => {
+                [&HEADER[..], messages].concat()
+            }
+            _ => {
+                let (first, second) = split_messages(messages, max_size);
+                parts.push(second);
+                parts.push(first);
+                continue;
+            }
+        };
+        sent += send_to_retry(socket, &datagram, target, metrics, limiter).await;
+    }
+    sent
+}
+
+/// Split the messages of a datagram in two, the first part being the longest that fits in a
+/// datagram of `max_size` bytes uncompressed, so that the messages that must be sent together, at
+/// the start, stay together.
+///
+/// The first part holds at least the first message, even if it does not fit.
+fn split_messages(messages: &[u8], max_size: usize) -> (&[u8], &[u8]) {
+    let mut reader = messages;
+    let mut end = 0;
+    while !reader.is_empty() {
+        next_framed(&mut reader);
+        let boundary = messages.len() - reader.len();
+        if end > 0 && HEADER.len() + boundary > max_size {
+            break;
+        }
+        end = boundary;
+    }
+    messages.split_at(end)
+}
+
+/// Whether the messages of a datagram hold a single message.
+fn is_single_message(messages: &[u8]) -> bool {
+    let mut reader = messages;
+    next_framed(&mut reader);
+    reader.is_empty()
+}
+
+/// Serialize the messages into datagrams for peers accepting datagrams of `max_size` bytes, the
+/// messages too large for a datagram being split in fragments.
+///
+/// The datagrams are packed with more messages when compressed, and split if they do not fit once
+/// compressed, see [`send_datagram`].
+fn pack_messages<M: Serialize>(
+    messages: &[M],
+    max_size: usize,
+    send_buf: &mut Vec<u8>,
+    metrics: &ServiceMetrics,
+    compression: Compression,
+) -> Vec<Vec<u8>> {
+    let max_message_size = max_size - HEADER.len();
+    // NOTE: a fragment holds its id, index and count, and the length of its bytes
+    let fragment_size = FRAGMENT_SIZE.min(max_message_size - FRAGMENT_OVERHEAD);
+    let max_packed_size = if compression.is_enabled() {
+        MAX_DECOMPRESSED_SIZE
+    } else {
+        max_size
+    };
+    let mut datagrams = Vec::new();
+    // send the datagram up to `last_size` when the last message made it too large, and keep that
+    // message for the next datagram
+    let mut flush_full = |send_buf: &mut Vec<u8>, last_size: usize| {
+        if send_buf.len() > max_packed_size {
+            datagrams.push(send_buf[..last_size].to_vec());
+            send_buf.drain(HEADER.len()..last_size);
+        }
+    };
+    send_buf.clear();
+    send_buf.extend_from_slice(&HEADER);
     for message in messages {
         let last_size = send_buf.len();
-        message
-            .serialize(&mut Serializer::new(&mut *send_buf, DefaultOptions::new()))
-            .unwrap();
-        if send_buf.len() > BUFFER_SIZE {
-            trace!("sending {} bytes to {peer}", last_size);
-            send_to_retry(&socket, &send_buf[..last_size], &peer)
+        if !write_or_drop(send_buf, message, metrics) {
+            continue;
+        }
+        if send_buf.len() - last_size > max_message_size {
+            // too large for a datagram, send it in fragments
+            let bytes = send_buf.split_off(last_size + 2);
+            send_buf.truncate(last_size);
+            if bytes.len() > fragment_size * MAX_FRAGMENTS {
+                warn!("dropping message of {} bytes", bytes.len());
+                continue;
+            }
+            debug!("sending message of {} bytes in fragments", bytes.len());
+            let id = message_id(&bytes);
+            let total = bytes.len().div_ceil(fragment_size) as u16;
+            for (index, part) in bytes.chunks(fragment_size).enumerate() {
+                let fragment =
+                    Message::<(), (), ()>::Fragment(id, index as u16, total, part.to_vec());
+                let last_size = send_buf.len();
+                if write_or_drop(send_buf, &fragment, metrics) {
+                    flush_full(send_buf, last_size);
+                }
+            }
+        } else {
+            flush_full(send_buf, last_size);
+        }
+    }
+    datagrams.push(send_buf.clone());
+    datagrams
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send_messages_to<K: Serialize, V: Serialize, C: Serialize>(
+    messages: &[Message<K, V, C>],
+    socket: &dyn Transport,
+    peer: &SocketAddr,
+    send_buf: &mut Vec<u8>,
+    metrics: &ServiceMetrics,
+    limiter: &RateLimiter,
+    budgets: &DatagramBudgets,
+    compression: Compression,
+) -> usize {
+    debug!("sending {} messages to {peer}", messages.len());
+    let updates = messages
+        .iter()
+        .filter(|message| message.update().is_some())
+        .count();
+    ServiceMetrics::add(&metrics.updates_sent, updates as u64);
+    let datagrams = pack_messages(messages, budgets.get(*peer), send_buf, metrics, compression);
+    let mut sent = 0;
+    for datagram in &datagrams {
+        trace!("sending {} bytes to {peer}", datagram.len());
+        sent += send_datagram(
+            socket,
+            datagram,
+            *peer,
+            metrics,
+            limiter,
+            budgets,
+            compression,
+        )
+        .await;
+    }
+    sent
+}
+
+/// Send the messages to each of the peers, from the socket of the same address family.
+///
+/// The messages are serialized once for all the peers accepting datagrams of the same size.
+#[allow(clippy::too_many_arguments)]
+async fn broadcast_messages<M: Serialize>(
+    messages: &[M],
+    sockets: &[Box<dyn Transport>],
+    peers: &[SocketAddr],
+    send_buf: &mut Vec<u8>,
+    metrics: &ServiceMetrics,
+    limiter: &RateLimiter,
+    budgets: &DatagramBudgets,
+    compression: Compression,
+) {
+    let mut by_budget: BTreeMap<usize, Vec<_>> = BTreeMap::new();
+    for &addr in peers {
+        if let Some(route) = route(sockets, addr) {
+            by_budget.entry(budgets.get(addr)).or_default().push(route);
+        } else {
+            trace!("no socket to reach {addr}");
+        }
+    }
+    for (max_size, routes) in by_budget {
+        debug!(
+            "sending {} messages to {} peers",
+            messages.len(),
+            routes.len()
+        );
+        let datagrams = pack_messages(messages, max_size, send_buf, metrics, compression);
+        for (socket, peer) in routes {
+            for datagram in &datagrams {
+                send_datagram(
+                    socket,
+                    datagram,
+                    peer,
+                    metrics,
+                    limiter,
+                    budgets,
+                    compression,
+                )
+                .await;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{BTreeMap, HashMap};
+    use std::net::{IpAddr, SocketAddr};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    use chrono::Utc;
+    use serde::{Deserialize, Serialize, Serializer};
+    use tokio::net::UdpSocket;
+
+    use super::{
+        read_message, split_namespaces, tag_namespace, write_message, InternalService, Message,
+        UpdateRef, BUFFER_SIZE, HEADER,
+    };
+    use crate::diff::{DiffTuning, Diffable};
+    use crate::fingerprint::{DefaultFingerprint, FingerprintStrategy};
+    use crate::reconcilable::{Reconcilable, ReconciliationResult};
+    use crate::sim::SimNetwork;
+    use crate::transport::Transport;
+    use crate::{DatedMaybeTombstone, HRTree, HashRangeQueryable};
+
+    /// Counter that can only grow, incremented independently by each instance
+    #[derive(Clone, Debug, Default, Deserialize, Hash, PartialEq, Serialize)]
+    struct GCounter(BTreeMap<u8, u64>);
+
+    impl GCounter {
+        fn value(&self) -> u64 {
+            self.0.values().sum()
+        }
+    }
+
+    impl Reconcilable for GCounter {
+        fn reconcile(&self, _other: &Self) -> ReconciliationResult {
+            unreachable!("counters are always merged")
+        }
+
+        fn merge(&self, other: &Self) -> Option<Self> {
+            let mut merged = self.clone();
+            for (&node, &count) in &other.0 {
+                let local = merged.0.entry(node).or_default();
+                *local = count.max(*local);
+            }
+            Some(merged)
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn merge() {
+        let port = 8080;
+        let peer_net = "127.0.0.1/8".parse().unwrap();
+        let addrs: [IpAddr; 2] = ["127.0.0.97".parse().unwrap(), "127.0.0.98".parse().unwrap()];
+        let mut services = Vec::new();
+        for (i, &addr) in addrs.iter().enumerate() {
+            let service = InternalService::new(HRTree::<u8, GCounter>::new(), port, addr, peer_net)
+                .await
+                .unwrap();
+            service
+                .peers
+                .write()
+                .insert(SocketAddr::new(addrs[1 - i], port), Instant::now());
+            services.push(service);
+        }
+        let tasks: Vec<_> = services
+            .iter()
+            .map(|service| tokio::spawn(service.clone().run_until(std::future::pending())))
+            .collect();
+
+        // both instances increment the same counter concurrently
+        for round in 0..10 {
+            for (node, service) in services.iter().enumerate() {
+                service.update(0, |counter| {
+                    let mut counter = counter.cloned().unwrap_or_default();
+                    *counter.0.entry(node as u8).or_default() += 1;
+                    Some(counter)
+                });
+            }
+            if round % 3 == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }
+
+        let converged = || {
+            services
+                .iter()
+                .all(|service| service.map.read().get(&0).map(GCounter::value) == Some(20))
+                && services[0].map.read().hash(&..) == services[1].map.read().hash(&..)
+        };
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            if converged() {
+                break;
+            }
+        }
+        assert!(converged());
+
+        for task in tasks {
+            task.abort();
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn push_recent_writes() {
+        let port = 8080;
+        let peer_net = "127.0.0.1/8".parse().unwrap();
+        let addr: IpAddr = "127.0.0.68".parse().unwrap();
+        let peer: SocketAddr = "127.0.0.69:8080".parse().unwrap();
+        let service = InternalService::new(
+            HRTree::<u8, DatedMaybeTombstone<String>>::new(),
+            port,
+            addr,
+            peer_net,
+        )
+        .await
+        .unwrap();
+        service.peers.write().insert(peer, Instant::now());
+        service.recent_writes.write().converged(peer);
+
+        // the update is lost, since the peer is not listening yet
+        let value = (Utc::now(), Some("Hello".to_string()));
+        service.insert(0, value.clone());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let socket = UdpSocket::bind(peer).await.unwrap();
+
+        service.push_recent_writes(&mut Vec::new()).await;
+        let mut recv_buf = vec![0; 1024];
+        let (size, _) =
+            tokio::time::timeout(Duration::from_secs(1), socket.recv_from(&mut recv_buf))
+                .await
+                .unwrap()
+                .unwrap();
+        assert!(recv_buf.starts_with(&HEADER));
+        let mut reader = &recv_buf[HEADER.len()..size];
+        let message: Option<Message<u8, DatedMaybeTombstone<String>, ()>> =
+            read_message(&mut reader).unwrap();
+        assert!(matches!(message, Some(Message::Update((0, v))) if v == value));
+
+        // nothing is pushed once the peer converged
+        service.recent_writes.write().converged(peer);
+        service.push_recent_writes(&mut Vec::new()).await;
+        let recv =
+            tokio::time::timeout(Duration::from_millis(100), socket.recv_from(&mut recv_buf));
+        assert!(recv.await.is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn flush_recent_writes() {
+        let port = 8080;
+        let peer_net = "127.0.0.1/8".parse().unwrap();
+        let addr: IpAddr = "127.0.0.90".parse().unwrap();
+        let peer: SocketAddr = "127.0.0.91:8080".parse().unwrap();
+        let service = InternalService::new(
+            HRTree::<u8, DatedMaybeTombstone<String>>::new(),
+            port,
+            addr,
+            peer_net,
+        )
+        .await
+        .unwrap();
+        service.peers.write().insert(peer, Instant::now());
+
+        // the update is lost, since the peer is not listening yet
+        let since = Instant::now();
+        let value = (Utc::now(), Some("Hello".to_string()));
+        service.insert(0, value.clone());
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let socket = UdpSocket::bind(peer).await.unwrap();
+
+        // the write is pushed even though the peer never converged
+        service.flush_recent_writes(since, &mut Vec::new()).await;
+        let mut recv_buf = vec![0; 1024];
+        let (size, _) =
+            tokio::time::timeout(Duration::from_secs(1), socket.recv_from(&mut recv_buf))
                 .await
+                .unwrap()
                 .unwrap();
-            trace!("sent {} bytes to {peer}", last_size);
-            send_buf.drain(..last_size);
+        let mut reader = &recv_buf[HEADER.len()..size];
+        let message: Option<Message<u8, DatedMaybeTombstone<String>, ()>> =
+            read_message(&mut reader).unwrap();
+        assert!(matches!(message, Some(Message::Update((0, v))) if v == value));
+
+        // older writes are not
+        service
+            .flush_recent_writes(Instant::now(), &mut Vec::new())
+            .await;
+        let recv =
+            tokio::time::timeout(Duration::from_millis(100), socket.recv_from(&mut recv_buf));
+        assert!(recv.await.is_err());
+    }
+
+    #[test]
+    fn framing() {
+        type M = Message<u8, u8, ()>;
+        let mut buf = Vec::new();
+        write_message(&mut buf, &M::Update((1, 2))).unwrap();
+        // message of a future type
+        buf.extend_from_slice(&[3, 0, 200, 1, 2]);
+        // message with a future field
+        let start = buf.len();
+        write_message(&mut buf, &M::Ack(3, 4)).unwrap();
+        buf.push(5);
+        buf[start] += 1;
+        // truncated message
+        write_message(&mut buf, &M::Update((6, 7))).unwrap();
+        buf.pop();
+
+        let mut reader = &buf[..];
+        assert!(matches!(
+            read_message(&mut reader),
+            Ok(Some(M::Update((1, 2))))
+        ));
+        assert!(matches!(read_message::<M>(&mut reader), Ok(None)));
+        assert!(matches!(read_message(&mut reader), Ok(Some(M::Ack(3, 4)))));
+        assert!(read_message::<M>(&mut reader).is_err());
+    }
+
+    #[test]
+    fn update_ref() {
+        let key_value = (1u8, (Utc::now(), Some("Hello".to_string())));
+        let mut expected = Vec::new();
+        write_message(
+            &mut expected,
+            &Message::<_, _, ()>::Update(key_value.clone()),
+        )
+        .unwrap();
+        let mut buf = Vec::new();
+        write_message(&mut buf, &UpdateRef(&key_value, None)).unwrap();
+        assert_eq!(buf, expected);
+    }
+
+    #[tokio::test]
+    async fn broadcast_same_bytes() {
+        let network = SimNetwork::new(42);
+        let addr = |i: u8| SocketAddr::new(IpAddr::from([10, 0, 0, i]), 8080);
+        let socket: Box<dyn Transport> = Box::new(network.bind(addr(1)).unwrap());
+        let service = InternalService::with_sockets(
+            HRTree::<u16, DatedMaybeTombstone<String>>::new(),
+            vec![socket],
+            "10.0.0.0/24".parse().unwrap(),
+        );
+        let peers: Vec<_> = (2..7).map(|i| network.bind(addr(i)).unwrap()).collect();
+        for i in 2..7 {
+            service.peers.write().insert(addr(i), Instant::now());
+        }
+        // only the broadcast runs, so that no session messages are sent to the peers
+        let broadcast = service.clone();
+        let task = tokio::spawn(async move { broadcast.broadcast_queued().await });
+
+        // enough updates for several datagrams
+        let now = Utc::now();
+        let key_values: Vec<_> = (0..3000)
+            .map(|key| (key, (now, Some(format!("value {key}").repeat(10)))))
+            .collect();
+        service.insert_bulk(&key_values).sent().await;
+
+        let mut received = Vec::new();
+        for peer in &peers {
+            let mut datagrams = Vec::new();
+            let mut recv_buf = vec![0; BUFFER_SIZE];
+            while let Ok(Ok((size, _))) =
+                tokio::time::timeout(Duration::from_millis(100), peer.recv_from(&mut recv_buf))
+                    .await
+            {
+                datagrams.push(recv_buf[..size].to_vec());
+            }
+            received.push(datagrams);
+        }
+        assert!(received[0].len() > 1);
+        assert!(received.iter().all(|datagrams| *datagrams == received[0]));
+        task.abort();
+    }
+
+    /// Number of [`Counted`] values serialized
+    static SERIALIZED: AtomicUsize = AtomicUsize::new(0);
+
+    /// Value that counts its serializations
+    #[derive(Clone, Debug, Deserialize, Hash, PartialEq)]
+    struct Counted(Vec<u8>);
+
+    impl Serialize for Counted {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            SERIALIZED.fetch_add(1, Ordering::Relaxed);
+            self.0.serialize(serializer)
+        }
+    }
+
+    #[tokio::test]
+    async fn large_direct_segment() {
+        let network = SimNetwork::new(42);
+        let addr = |i: u8| SocketAddr::new(IpAddr::from([10, 0, 0, i]), 8080);
+        let socket: Box<dyn Transport> = Box::new(network.bind(addr(1)).unwrap());
+        let _peer = network.bind(addr(2)).unwrap();
+        // about 4 MiB of values, much more than a fresh peer holds
+        let now = Utc::now();
+        let tree: HRTree<u32, DatedMaybeTombstone<Counted>> = (0..4096)
+            .map(|key| (key, (now, Some(Counted(vec![0; 1024])))))
+            .collect();
+        let mut service =
+            InternalService::with_sockets(tree, vec![socket], "10.0.0.0/24".parse().unwrap());
+        service.diff_tuning = DiffTuning::adaptive();
+        let remote: HRTree<u32, DatedMaybeTombstone<Counted>> = (5000..5010)
+            .map(|key| (key, (now, Some(Counted(vec![0; 1024])))))
+            .collect();
+
+        // the whole map is answered directly, which exceeds the bytes sent in a round: the
+        // segment is split without listing all its updates
+        SERIALIZED.store(0, Ordering::Relaxed);
+        service
+            .reply_comparison(
+                service.sockets[0].as_ref(),
+                addr(2),
+                (1, true, DefaultFingerprint::ID),
+                remote.start_diff(),
+                None,
+                &mut HashMap::new(),
+                &mut Vec::new(),
+            )
+            .await;
+        let serialized = SERIALIZED.load(Ordering::Relaxed);
+        assert!(serialized < 100, "{serialized} values serialized");
+    }
+
+    /// Value that cannot be serialized when it is odd
+    #[derive(Clone, Debug, Deserialize, Hash, PartialEq)]
+    struct Fragile(u32);
+
+    impl Serialize for Fragile {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            if self.0 % 2 == 1 {
+                return Err(serde::ser::Error::custom("odd value"));
+            }
+            self.0.serialize(serializer)
+        }
+    }
+
+    #[tokio::test]
+    async fn canonical_unserializable() {
+        let network = SimNetwork::new(42);
+        let addr = |i: u8| SocketAddr::new(IpAddr::from([10, 0, 0, i]), 8080);
+        let socket: Box<dyn Transport> = Box::new(network.bind(addr(1)).unwrap());
+        let tree = HRTree::<u32, DatedMaybeTombstone<Fragile>>::new().with_canonical_hashing();
+        let service =
+            InternalService::with_sockets(tree, vec![socket], "10.0.0.0/24".parse().unwrap());
+        let now = Utc::now();
+
+        // the elements that cannot be hashed are dropped, instead of panicking
+        service.insert(1, (now, Some(Fragile(1))));
+        service.insert_bulk(&[(2, (now, Some(Fragile(2)))), (3, (now, Some(Fragile(3))))]);
+        service.just_merge_bulk([(4, (now, Some(Fragile(5)))), (6, (now, Some(Fragile(6))))]);
+        service.apply_updates(
+            addr(2),
+            vec![(7, (now, Some(Fragile(7)))), (8, (now, Some(Fragile(8))))],
+            None,
+        );
+        let keys: Vec<u32> = service.map.read().iter().map(|(key, _)| *key).collect();
+        assert_eq!(keys, [2, 6, 8]);
+        assert_eq!(service.metrics.snapshot().serialize_errors, 4);
+    }
+
+    #[test]
+    fn namespaces() {
+        type M = Message<u8, u8, ()>;
+        // messages of namespace 0, then 2, then 0 again, in a single datagram
+        let mut datagram = HEADER.to_vec();
+        write_message(&mut datagram, &M::Update((1, 2))).unwrap();
+        write_message(&mut datagram, &M::Namespace(2)).unwrap();
+        write_message(&mut datagram, &M::Ack(3, 4)).unwrap();
+        write_message(&mut datagram, &M::Namespace(0)).unwrap();
+        write_message(&mut datagram, &M::Update((5, 6))).unwrap();
+
+        let datagrams = split_namespaces(&datagram).unwrap();
+        assert_eq!(datagrams.len(), 2);
+        let (id, ref datagram0) = datagrams[0];
+        assert_eq!(id, 0);
+        let mut reader = &datagram0[HEADER.len()..];
+        assert!(matches!(
+            read_message(&mut reader),
+            Ok(Some(M::Update((1, 2))))
+        ));
+        assert!(matches!(
+            read_message(&mut reader),
+            Ok(Some(M::Update((5, 6))))
+        ));
+        assert!(reader.is_empty());
+
+        // the messages of namespace 2 are marked again when sent
+        let (id, ref datagram2) = datagrams[1];
+        assert_eq!(id, 2);
+        let tagged = tag_namespace(datagram2, 2);
+        assert_eq!(tagged.len(), 1);
+        assert_eq!(
+            split_namespaces(&tagged[0]).unwrap(),
+            vec![(2, datagram2.clone())]
+        );
+
+        // a full datagram is split in two
+        let mut datagram = HEADER.to_vec();
+        while datagram.len() + 5 <= BUFFER_SIZE {
+            write_message(&mut datagram, &M::Update((7, 8))).unwrap();
         }
+        // message of a future type, up to the size of the datagram
+        let size = BUFFER_SIZE - datagram.len() - 2;
+        datagram.extend_from_slice(&(size as u16).to_le_bytes());
+        datagram.resize(BUFFER_SIZE, 200);
+        let tagged = tag_namespace(&datagram, 1);
+        assert_eq!(tagged.len(), 2);
+        assert!(tagged.iter().all(|datagram| datagram.len() <= BUFFER_SIZE));
     }
-    trace!("sending last {} bytes to {peer}", send_buf.len());
-    send_to_retry(&socket, send_buf, &peer).await.unwrap();
-    trace!("sent last {} bytes to {peer}", send_buf.len());
 }
@@ -0,0 +1,129 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`DeletionJournal`], which remembers the recent deletions after their tombstones are
+//! removed from the map.
+//!
+//! Once a tombstone has expired and been removed, nothing prevents a peer that still holds an
+//! older value, for instance after a long partition, from inserting it back. The journal keeps
+//! the tombstones for a longer horizon: they are sent to each peer as soon as it is heard from,
+//! and the older values received for their keys are rejected.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+use std::net::SocketAddr;
+
+use chrono::{DateTime, Utc};
+
+use crate::reconcilable::{ConflictPolicy, Resolution};
+
+pub(crate) struct DeletionJournal<K, V> {
+    /// Tombstones by key, with the time of the deletion
+    entries: HashMap<K, (DateTime<Utc>, V)>,
+    /// Peers that were sent the tombstones since they were first heard from
+    informed: HashSet<SocketAddr>,
+}
+
+impl<K: Clone + Eq + Hash, V: Clone> DeletionJournal<K, V> {
+    pub fn new() -> Self {
+        DeletionJournal {
+            entries: HashMap::new(),
+            informed: HashSet::new(),
+        }
+    }
+
+    /// Remember the tombstone of a key deleted at the given time.
+    pub fn record(&mut self, key: K, timestamp: DateTime<Utc>, tombstone: V) {
+        self.entries.insert(key, (timestamp, tombstone));
+    }
+
+    /// Forget the deletion of a key that was written again.
+    pub fn forget(&mut self, key: &K) {
+        self.entries.remove(key);
+    }
+
+    /// Whether the value received for the key loses against its deletion, according to the
+    /// policy of the service.
+    ///
+    /// The tombstone itself is accepted, so that the peers holding it converge.
+    pub fn rejects(&self, key: &K, value: &V, policy: &dyn ConflictPolicy<V>) -> bool {
+        self.entries.get(key).is_some_and(|(_, tombstone)| {
+            matches!(policy.resolve(tombstone, value), Resolution::KeepLocal)
+                && matches!(policy.resolve(value, tombstone), Resolution::KeepIncoming)
+        })
+    }
+
+    /// Forget the deletions older than the given time, and the peers not in the list.
+    pub fn prune(&mut self, before: DateTime<Utc>, peers: &[SocketAddr]) {
+        self.entries
+            .retain(|_, (timestamp, _)| *timestamp >= before);
+        self.informed.retain(|peer| peers.contains(peer));
+    }
+
+    /// Forget that the peer was sent the tombstones, when it is heard from again after expiring.
+    pub fn reset_peer(&mut self, peer: SocketAddr) {
+        self.informed.remove(&peer);
+    }
+
+    /// Return the tombstones to send to the peer, unless it was already sent them.
+    pub fn inform(&mut self, peer: SocketAddr) -> Vec<(K, V)> {
+        if self.entries.is_empty() || !self.informed.insert(peer) {
+            return Vec::new();
+        }
+        self.tombstones()
+    }
+
+    /// List all the tombstones.
+    pub fn tombstones(&self) -> Vec<(K, V)> {
+        self.entries
+            .iter()
+            .map(|(key, (_, tombstone))| (key.clone(), tombstone.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, Utc};
+
+    use super::DeletionJournal;
+    use crate::reconcilable::LwwPolicy;
+
+    #[test]
+    fn journal() {
+        let peer = "10.0.0.1:8080".parse().unwrap();
+        let now = Utc::now();
+        let mut journal = DeletionJournal::new();
+        assert!(journal.inform(peer).is_empty());
+        journal.record(0, now, (now, None));
+        journal.record(
+            1,
+            now - Duration::hours(1),
+            (now - Duration::hours(1), None),
+        );
+
+        // older values are rejected, newer ones are not
+        assert!(journal.rejects(&0, &(now - Duration::seconds(1), Some("Hello")), &LwwPolicy));
+        assert!(!journal.rejects(&0, &(now + Duration::seconds(1), Some("Hello")), &LwwPolicy));
+        assert!(!journal.rejects(&2, &(now, Some("Hello")), &LwwPolicy));
+        assert!(!journal.rejects(&0, &(now, None), &LwwPolicy));
+
+        // the tombstones are sent once to each peer
+        assert_eq!(journal.inform(peer).len(), 2);
+        assert!(journal.inform(peer).is_empty());
+        journal.reset_peer(peer);
+        assert_eq!(journal.inform(peer).len(), 2);
+
+        // old deletions are forgotten, and so are expired peers
+        journal.prune(now - Duration::minutes(1), &[]);
+        assert_eq!(journal.tombstones(), vec![(0, (now, None))]);
+        assert_eq!(journal.inform(peer).len(), 1);
+        journal.forget(&0);
+        assert!(journal.tombstones().is_empty());
+    }
+}
@@ -17,15 +17,59 @@
 //! number of round-trips. It should also work well to populate an instance from
 //! scratch from other instances.
 
+pub(crate) mod auth;
+pub(crate) mod backlog;
+pub mod blocking;
+pub(crate) mod broadcast;
+pub mod chunk;
+pub mod clock;
+pub mod codec;
+pub(crate) mod compression;
+pub mod dated;
+pub mod debug;
 pub mod diff;
+pub mod discovery;
+pub(crate) mod divergence;
+pub mod engine;
+pub mod error;
+pub mod fingerprint;
+pub(crate) mod fragment;
+#[doc(hidden)]
+pub mod fuzzing;
 pub mod gen_ip;
+pub(crate) mod handshake;
 pub mod hrtree;
 pub(crate) mod internal_service;
+pub(crate) mod journal;
 pub mod map;
+pub mod metrics;
+pub mod multi_service;
+pub(crate) mod oversize;
+#[cfg(feature = "metrics-prometheus")]
+pub(crate) mod prometheus;
+pub(crate) mod rate_limit;
+pub(crate) mod recent_writes;
 pub mod reconcilable;
+pub(crate) mod replay;
+pub(crate) mod root_watch;
 pub mod service;
+pub(crate) mod session;
+pub mod sim;
+pub(crate) mod skew;
+pub(crate) mod snapshot;
+pub mod spill;
+pub mod tcp;
+pub mod testing;
 pub(crate) mod timeout_wheel;
+pub mod transport;
+pub(crate) mod wal;
 
-pub use diff::HashRangeQueryable;
-pub use hrtree::HRTree;
-pub use service::{DatedMaybeTombstone, Service};
+pub use clock::{Clock, MonotonicClock, SystemClock};
+pub use diff::{DiffTuning, HashRangeQueryable};
+pub use engine::{EngineOutput, ProtocolEngine};
+pub use error::Error;
+pub use fingerprint::{DefaultFingerprint, DualFingerprint, FingerprintStrategy};
+pub use hrtree::{HRTree, MergeStats, TreeStats};
+pub use metrics::{MetricsSnapshot, ServiceMetrics};
+pub use reconcilable::{ConflictPolicy, LwwPolicy, Resolution};
+pub use service::{DatedMaybeTombstone, Service, ServiceHandle};
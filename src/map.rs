@@ -9,40 +9,160 @@
 //! Provides the [`Map`] trait and the related implementation for [`HRTree`].
 
 use core::hash::Hash;
+use std::borrow::{Borrow, Cow};
+use std::ops::{Bound, RangeBounds};
+
+use serde::Serialize;
 
 use crate::diff::DiffRange;
-use crate::hrtree::HRTree;
+use crate::fingerprint::FingerprintStrategy;
+use crate::hrtree::{HRTree, MergeStats};
+
+/// Iterator over the key-value pairs of a [`Map`], see [`iter_entries`](Map::iter_entries).
+pub type Entries<'a, K, V> = Box<dyn Iterator<Item = (&'a K, Cow<'a, V>)> + 'a>;
 
 /// Provides the basic methods of a key-value map.
 /// In addition to [`get`](Map::get), [`insert`](Map::insert) and [`remove`](Map::remove),
 /// the method [`enumerate_diff_ranges`](Map::enumerate_diff_ranges) allows listing key-value pairs
 /// within the given [`DifferenceItem`](Map::DifferenceItem)s (typically, ranges).
+///
+/// The values are returned as [`Cow`]s, so that implementations may either store each
+/// [`Value`](Map::Value) as a whole and borrow it, or build it on the fly, for instance by reading
+/// it from disk as [`SpillMap`](crate::spill::SpillMap) does, or from a more compact form as
+/// [`DatedTree`](crate::dated::DatedTree) does.
 pub trait Map {
     type Key;
-    type Value;
+    type Value: Clone;
     type DifferenceItem;
 
     fn enumerate_diff_ranges(
         &self,
         diff_ranges: Vec<Self::DifferenceItem>,
     ) -> Vec<(Self::Key, Self::Value)>;
+    /// Lazily list the key-value pairs within the given
+    /// [`DifferenceItem`](Map::DifferenceItem)s, in order.
+    ///
+    /// The default implementation collects the result of
+    /// [`enumerate_diff_ranges`](Map::enumerate_diff_ranges).
+    fn enumerate_diff_ranges_iter<'a>(
+        &'a self,
+        diff_ranges: Vec<Self::DifferenceItem>,
+    ) -> Box<dyn Iterator<Item = (Self::Key, Self::Value)> + 'a>
+    where
+        Self::Key: 'a,
+        Self::Value: 'a,
+    {
+        Box::new(self.enumerate_diff_ranges(diff_ranges).into_iter())
+    }
+    /// List, in order, at most `limit` key-value pairs within the given
+    /// [`DifferenceItem`](Map::DifferenceItem), and the rest of the range when it holds more;
+    /// this allows enumerating a large range by chunks.
+    ///
+    /// The default implementation lists the whole range at once.
+    #[allow(clippy::type_complexity)]
+    fn enumerate_diff_range_chunk(
+        &self,
+        diff_range: Self::DifferenceItem,
+        limit: usize,
+    ) -> (Vec<(Self::Key, Self::Value)>, Option<Self::DifferenceItem>) {
+        let _ = limit;
+        (self.enumerate_diff_ranges(vec![diff_range]), None)
+    }
+    /// Whether the given key is within the given [`DifferenceItem`](Map::DifferenceItem).
+    fn diff_range_contains(diff_range: &Self::DifferenceItem, key: &Self::Key) -> bool;
+    /// List, in order, at most `limit` key-value pairs whose keys are within the given range.
+    fn enumerate_range(
+        &self,
+        range: &(Bound<Self::Key>, Bound<Self::Key>),
+        limit: usize,
+    ) -> Vec<(Self::Key, Self::Value)>;
+    /// List, in order, at most `count` key-value pairs starting with the one at position `start`.
+    fn enumerate_by_rank(&self, start: usize, count: usize) -> Vec<(Self::Key, Self::Value)>;
     /// Get the value associated with the given key, if it exists.
-    fn get<'a>(&'a self, key: &Self::Key) -> Option<&'a Self::Value>;
+    ///
+    /// The key may be any borrowed form of [`Key`](Map::Key), as long as its ordering matches.
+    fn get<'a, Q: Ord + ?Sized>(&'a self, key: &Q) -> Option<Cow<'a, Self::Value>>
+    where
+        Self::Key: Borrow<Q>;
+    /// Whether the key is in the map.
+    ///
+    /// The default implementation gets the value, which may be costly.
+    fn contains_key<Q: Ord + ?Sized>(&self, key: &Q) -> bool
+    where
+        Self::Key: Borrow<Q>,
+    {
+        self.get(key).is_some()
+    }
+    /// List all the key-value pairs, in order.
+    fn iter_entries(&self) -> Entries<'_, Self::Key, Self::Value>;
     /// Insert a value at the given key, return the current value if it exists.
     fn insert(&mut self, key: Self::Key, value: Self::Value) -> Option<Self::Value>;
     /// Remove and return the value at the given key if it exists.
     fn remove(&mut self, key: &Self::Key) -> Option<Self::Value>;
+    /// Remove all the key-value pairs for which the predicate returns `false`, and return them.
+    fn retain<P: FnMut(&Self::Key, &Self::Value) -> bool>(
+        &mut self,
+        predicate: P,
+    ) -> Vec<(Self::Key, Self::Value)>;
+    /// Merge key-value pairs sorted by key into the map, as with
+    /// [`HRTree::merge_from_sorted_with`]: the new keys are inserted, the existing values are
+    /// replaced when `decide` returns `true`, and `changed` is called before each insertion.
+    ///
+    /// The default implementation looks up and inserts each key-value pair in turn.
+    fn merge_from_sorted_with<I, D, C>(
+        &mut self,
+        iter: I,
+        mut decide: D,
+        mut changed: C,
+    ) -> MergeStats
+    where
+        Self::Key: Ord,
+        I: IntoIterator<Item = (Self::Key, Self::Value)>,
+        D: FnMut(&Self::Key, &Self::Value, &Self::Value) -> bool,
+        C: FnMut(&Self::Key, &Self::Value, Option<&Self::Value>),
+    {
+        let mut stats = MergeStats::default();
+        for (key, value) in iter {
+            let existing = self.get(&key).map(Cow::into_owned);
+            match &existing {
+                None => stats.inserted += 1,
+                Some(existing) if decide(&key, existing, &value) => stats.overwritten += 1,
+                Some(_) => {
+                    stats.skipped += 1;
+                    continue;
+                }
+            }
+            changed(&key, &value, existing.as_ref());
+            self.insert(key, value);
+        }
+        stats
+    }
+    /// Hash the elements in their serialized form from now on, as with
+    /// [`HRTree::with_canonical_hashing`], hashing the existing ones again; return whether the
+    /// map supports it.
+    ///
+    /// The default implementation returns `false`.
+    fn set_canonical_hashing(&mut self) -> bool
+    where
+        Self::Key: Serialize,
+        Self::Value: Serialize,
+    {
+        false
+    }
 }
 
 pub trait MutMap: Map {
     /// Get a mutable reference to the value associated with the given key, if it exists.
+    ///
+    /// Implementations must take the modification of the value into account (e.g. in hashes).
     fn get_mut<F: FnOnce(Option<&mut Self::Value>)>(&mut self, key: &Self::Key, callback: F);
 }
 
-impl<K, V> Map for HRTree<K, V>
+impl<K, V, F, const N: usize> Map for HRTree<K, V, F, N>
 where
     K: Clone + Hash + Ord,
     V: Clone + Hash,
+    F: FingerprintStrategy,
 {
     type Key = K;
     type Value = V;
@@ -52,17 +172,74 @@ where
         &self,
         diff_ranges: Vec<Self::DifferenceItem>,
     ) -> Vec<(Self::Key, Self::Value)> {
-        let mut ret = Vec::new();
-        for diff in diff_ranges {
-            for (k, v) in self.get_range(&diff) {
-                ret.push((k.clone(), v.clone()));
-            }
-        }
-        ret
+        self.enumerate_diff_ranges_iter(diff_ranges).collect()
+    }
+
+    fn enumerate_diff_ranges_iter<'a>(
+        &'a self,
+        diff_ranges: Vec<Self::DifferenceItem>,
+    ) -> Box<dyn Iterator<Item = (Self::Key, Self::Value)> + 'a>
+    where
+        K: 'a,
+        V: 'a,
+    {
+        Box::new(diff_ranges.into_iter().flat_map(|diff| {
+            self.get_range_owned(diff)
+                .map(|(k, v)| (k.clone(), v.clone()))
+        }))
     }
 
-    fn get<'a>(&'a self, key: &Self::Key) -> Option<&'a Self::Value> {
-        self.get(key)
+    fn enumerate_diff_range_chunk(
+        &self,
+        diff_range: Self::DifferenceItem,
+        limit: usize,
+    ) -> (Vec<(Self::Key, Self::Value)>, Option<Self::DifferenceItem>) {
+        let end = diff_range.1.clone();
+        let mut iter = self.enumerate_diff_ranges_iter(vec![diff_range]);
+        let items: Vec<_> = iter.by_ref().take(limit.max(1)).collect();
+        let rest = match (iter.next(), items.last()) {
+            (Some(_), Some((key, _))) => Some((Bound::Excluded(key.clone()), end)),
+            _ => None,
+        };
+        (items, rest)
+    }
+
+    fn diff_range_contains(diff_range: &Self::DifferenceItem, key: &Self::Key) -> bool {
+        diff_range.contains(key)
+    }
+
+    fn enumerate_range(
+        &self,
+        range: &(Bound<Self::Key>, Bound<Self::Key>),
+        limit: usize,
+    ) -> Vec<(Self::Key, Self::Value)> {
+        self.get_range(range)
+            .take(limit)
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+
+    fn enumerate_by_rank(&self, start: usize, count: usize) -> Vec<(Self::Key, Self::Value)> {
+        self.range_by_rank(start, start.saturating_add(count))
+            .map(|(k, v)| (k.clone(), v.clone()))
+            .collect()
+    }
+    fn get<'a, Q: Ord + ?Sized>(&'a self, key: &Q) -> Option<Cow<'a, Self::Value>>
+    where
+        K: Borrow<Q>,
+    {
+        self.get(key).map(Cow::Borrowed)
+    }
+
+    fn contains_key<Q: Ord + ?Sized>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        self.contains_key(key)
+    }
+
+    fn iter_entries(&self) -> Entries<'_, Self::Key, Self::Value> {
+        Box::new(self.iter().map(|(k, v)| (k, Cow::Borrowed(v))))
     }
 
     fn insert(&mut self, key: Self::Key, value: Self::Value) -> Option<Self::Value> {
@@ -72,14 +249,79 @@ where
     fn remove(&mut self, key: &Self::Key) -> Option<Self::Value> {
         self.remove(key)
     }
+
+    fn retain<P: FnMut(&Self::Key, &Self::Value) -> bool>(
+        &mut self,
+        predicate: P,
+    ) -> Vec<(Self::Key, Self::Value)> {
+        self.retain(predicate)
+    }
+
+    fn merge_from_sorted_with<I, D, C>(&mut self, iter: I, decide: D, changed: C) -> MergeStats
+    where
+        I: IntoIterator<Item = (Self::Key, Self::Value)>,
+        D: FnMut(&Self::Key, &Self::Value, &Self::Value) -> bool,
+        C: FnMut(&Self::Key, &Self::Value, Option<&Self::Value>),
+    {
+        self.merge_from_sorted_with(iter, decide, changed)
+    }
+
+    fn set_canonical_hashing(&mut self) -> bool
+    where
+        K: Serialize,
+        V: Serialize,
+    {
+        self.set_canonical_hashing();
+        true
+    }
 }
 
-impl<K, V> MutMap for HRTree<K, V>
+impl<K, V, F, const N: usize> MutMap for HRTree<K, V, F, N>
 where
     K: Clone + Hash + Ord,
     V: Clone + Hash,
+    F: FingerprintStrategy,
 {
-    fn get_mut<F: FnOnce(Option<&mut Self::Value>)>(&mut self, key: &Self::Key, callback: F) {
-        self.get_mut(key, callback);
+    fn get_mut<C: FnOnce(Option<&mut Self::Value>)>(&mut self, key: &Self::Key, callback: C) {
+        callback(self.get_mut(key).as_deref_mut());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ops::Bound;
+
+    use rand::{Rng, SeedableRng};
+
+    use super::Map;
+    use crate::hrtree::HRTree;
+
+    #[test]
+    fn resume_enumeration() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let tree: HRTree<u64, u64> = (0..1000).map(|_| (rng.gen(), rng.gen())).collect();
+        let diff_ranges = vec![
+            (Bound::Unbounded, Bound::Excluded(u64::MAX / 4)),
+            (Bound::Included(u64::MAX / 2), Bound::Unbounded),
+        ];
+        let expected = tree.enumerate_diff_ranges(diff_ranges.clone());
+        assert_eq!(
+            tree.enumerate_diff_ranges_iter(diff_ranges.clone())
+                .collect::<Vec<_>>(),
+            expected
+        );
+
+        // enumerate by chunks of 10 items
+        let mut items = Vec::new();
+        let mut diff_ranges = std::collections::VecDeque::from(diff_ranges);
+        while let Some(diff_range) = diff_ranges.pop_front() {
+            let (chunk, rest) = tree.enumerate_diff_range_chunk(diff_range, 10);
+            assert!(chunk.len() <= 10);
+            items.extend(chunk);
+            if let Some(rest) = rest {
+                diff_ranges.push_front(rest);
+            }
+        }
+        assert_eq!(items, expected);
     }
 }
@@ -0,0 +1,271 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`ServiceMetrics`], counters describing the activity of a
+//! [`Service`](crate::service::Service).
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// Counters updated by the service as it communicates with its peers.
+///
+/// All the counters only ever increase, except the age of the divergences and of the sessions. Use
+/// [`snapshot`](ServiceMetrics::snapshot) to read them.
+#[derive(Debug, Default)]
+pub struct ServiceMetrics {
+    pub(crate) datagrams_sent: AtomicU64,
+    pub(crate) datagrams_received: AtomicU64,
+    pub(crate) bytes_sent: AtomicU64,
+    pub(crate) bytes_received: AtomicU64,
+    pub(crate) segments_processed: AtomicU64,
+    pub(crate) segments_deferred: AtomicU64,
+    pub(crate) updates_sent: AtomicU64,
+    pub(crate) updates_applied: AtomicU64,
+    pub(crate) updates_rejected: AtomicU64,
+    pub(crate) timeout_reconciliations: AtomicU64,
+    pub(crate) malformed_datagrams: AtomicU64,
+    pub(crate) undecodable_messages: AtomicU64,
+    pub(crate) foreign_schema_updates: AtomicU64,
+    pub(crate) broadcasts_dropped: AtomicU64,
+    pub(crate) send_errors: AtomicU64,
+    pub(crate) auth_failures: AtomicU64,
+    pub(crate) upgraded_fingerprint_sessions: AtomicU64,
+    pub(crate) datagrams_compressed: AtomicU64,
+    pub(crate) future_timestamps_rejected: AtomicU64,
+    pub(crate) updates_throttled: AtomicU64,
+    pub(crate) datagrams_paused: AtomicU64,
+    pub(crate) serialize_errors: AtomicU64,
+    pub(crate) wal_errors: AtomicU64,
+    pub(crate) duplicate_datagrams: AtomicU64,
+    pub(crate) oversize_datagrams: AtomicU64,
+    pub(crate) sessions_restarted: AtomicU64,
+    pub(crate) restarted_session_rounds: AtomicU64,
+    pub(crate) restarted_session_ranges: AtomicU64,
+    /// When the oldest range still differing with a peer was first found, in milliseconds since
+    /// the Unix epoch, or 0
+    oldest_divergence: AtomicU64,
+    /// When the oldest active session initiated locally started, in milliseconds since the Unix
+    /// epoch, or 0
+    oldest_session: AtomicU64,
+    /// Number of key-value pairs received from each known peer and inserted in the local map
+    peer_updates_applied: Mutex<HashMap<SocketAddr, u64>>,
+    /// Number of datagrams received from each known peer and dropped as too large
+    peer_oversize_datagrams: Mutex<HashMap<SocketAddr, u64>>,
+    /// Error of the last datagram that could not be sent, until one is sent successfully
+    last_error: Mutex<Option<Arc<Error>>>,
+}
+
+/// Plain copy of the counters of a [`ServiceMetrics`] at a given time.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Eq, PartialEq, Serialize)]
+pub struct MetricsSnapshot {
+    /// Number of UDP datagrams sent to peers
+    pub datagrams_sent: u64,
+    /// Number of UDP datagrams received from peers
+    pub datagrams_received: u64,
+    /// Number of bytes sent to peers, as UDP payload
+    pub bytes_sent: u64,
+    /// Number of bytes received from peers, as UDP payload
+    pub bytes_received: u64,
+    /// Number of comparison segments received from peers
+    pub segments_processed: u64,
+    /// Number of times comparison segments received from peers were deferred to a later
+    /// datagram, because the reply would not have fit in a single one
+    pub segments_deferred: u64,
+    /// Number of key-value pairs sent to peers
+    pub updates_sent: u64,
+    /// Number of key-value pairs received from peers and inserted in the local map
+    pub updates_applied: u64,
+    /// Number of key-value pairs received from peers and discarded by
+    /// [`reconcile`](crate::reconcilable::Reconcilable::reconcile)
+    pub updates_rejected: u64,
+    /// Number of times the reconciliation protocol was started because of inactivity
+    pub timeout_reconciliations: u64,
+    /// Number of datagrams received with messages that could not be deserialized
+    pub malformed_datagrams: u64,
+    /// Number of messages received that could not be deserialized, and were skipped while the
+    /// rest of their datagram was kept; see
+    /// [`with_undecodable_hook`](crate::Service::with_undecodable_hook)
+    pub undecodable_messages: u64,
+    /// Number of key-value pairs received tagged with another schema id, and skipped; see
+    /// [`with_schema_tags`](crate::Service::with_schema_tags)
+    pub foreign_schema_updates: u64,
+    /// Number of local writes dropped from the broadcast queue because it was full; the peers
+    /// get them at the next reconciliation sessions
+    pub broadcasts_dropped: u64,
+    /// Number of datagrams that could not be sent
+    pub send_errors: u64,
+    /// Number of datagrams discarded because they were not authenticated with one of the keys
+    /// given to [`with_auth_keys`](crate::Service::with_auth_keys)
+    pub auth_failures: u64,
+    /// Number of sessions opened with a stronger fingerprint than the weakest one of the
+    /// [`FingerprintStrategy`](crate::FingerprintStrategy), because the peer supports it
+    pub upgraded_fingerprint_sessions: u64,
+    /// Number of datagrams sent compressed, see [`with_compression`](crate::Service::with_compression)
+    pub datagrams_compressed: u64,
+    /// Number of dated values received from peers and discarded because their timestamp was too
+    /// far in the future; see
+    /// [`with_max_future_timestamp_skew`](crate::Service::with_max_future_timestamp_skew)
+    pub future_timestamps_rejected: u64,
+    /// Number of key-value pairs received from peers beyond their budget, and dropped; see
+    /// [`with_peer_update_rate`](crate::Service::with_peer_update_rate)
+    pub updates_throttled: u64,
+    /// Number of datagrams received while the synchronization was paused, whose comparison
+    /// segments and updates were dropped; see [`pause_sync`](crate::Service::pause_sync)
+    pub datagrams_paused: u64,
+    /// Number of messages, and of elements of a map in the canonical hashing mode, dropped
+    /// because they could not be serialized; see
+    /// [`with_canonical_hashing`](crate::Service::with_canonical_hashing)
+    pub serialize_errors: u64,
+    /// Number of failures to write changes to the write-ahead log; see
+    /// [`sync_wal`](crate::Service::sync_wal)
+    pub wal_errors: u64,
+    /// Number of datagrams dropped because they were received twice from the same peer; see
+    /// [`with_dedup_window`](crate::Service::with_dedup_window)
+    pub duplicate_datagrams: u64,
+    /// Number of datagrams dropped because they did not fit in the receive buffer; see
+    /// [`health`](crate::Service::health)
+    pub oversize_datagrams: u64,
+    /// Time in milliseconds since the oldest range still differing with a peer was first found,
+    /// or 0 if none differs; it keeps growing while the instances cannot converge, see
+    /// [`divergences`](crate::Service::divergences)
+    pub max_divergence_age_ms: u64,
+    /// Number of sessions initiated locally that lasted longer than the deadline, and were
+    /// started over; see [`with_session_deadline`](crate::Service::with_session_deadline)
+    pub sessions_restarted: u64,
+    /// Number of replies received in the sessions started over because of the deadline
+    pub restarted_session_rounds: u64,
+    /// Number of segments still outstanding in the sessions started over because of the deadline
+    pub restarted_session_ranges: u64,
+    /// Time in milliseconds since the start of the oldest active session initiated locally, or 0
+    /// if none is active
+    pub longest_session_ms: u64,
+}
+
+impl ServiceMetrics {
+    pub(crate) fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Set the age of the oldest range still differing with a peer, if any.
+    pub(crate) fn set_max_divergence_age(&self, age: Option<Duration>) {
+        store_start(&self.oldest_divergence, age);
+    }
+
+    /// Set the age of the oldest active session initiated locally, if any.
+    pub(crate) fn set_longest_session(&self, age: Option<Duration>) {
+        store_start(&self.oldest_session, age);
+    }
+
+    /// Count the key-value pairs received from the peer and inserted in the local map.
+    pub(crate) fn add_peer_updates_applied(&self, peer: SocketAddr, value: u64) {
+        *self.peer_updates_applied.lock().entry(peer).or_default() += value;
+    }
+
+    /// Number of key-value pairs received from each of the given peers and inserted in the local
+    /// map, forgetting the other peers.
+    pub(crate) fn peer_updates_applied(&self, peers: &[SocketAddr]) -> HashMap<SocketAddr, u64> {
+        let mut guard = self.peer_updates_applied.lock();
+        guard.retain(|peer, _| peers.contains(peer));
+        guard.clone()
+    }
+
+    /// Count the datagrams received from the peer and dropped as too large.
+    pub(crate) fn add_peer_oversize_datagrams(&self, peer: SocketAddr, value: u64) {
+        *self.peer_oversize_datagrams.lock().entry(peer).or_default() += value;
+    }
+
+    /// Number of datagrams received from each of the given peers and dropped as too large,
+    /// forgetting the other peers.
+    pub(crate) fn peer_oversize_datagrams(&self, peers: &[SocketAddr]) -> HashMap<SocketAddr, u64> {
+        let mut guard = self.peer_oversize_datagrams.lock();
+        guard.retain(|peer, _| peers.contains(peer));
+        guard.clone()
+    }
+
+    /// Remember the error of a datagram that could not be sent.
+    pub(crate) fn set_last_error(&self, error: Error) {
+        *self.last_error.lock() = Some(Arc::new(error));
+    }
+
+    /// Forget the last error, once a datagram was sent successfully.
+    pub(crate) fn clear_last_error(&self) {
+        *self.last_error.lock() = None;
+    }
+
+    /// Error of the last datagram that could not be sent, unless one was sent successfully
+    /// since.
+    pub(crate) fn last_error(&self) -> Option<Arc<Error>> {
+        self.last_error.lock().clone()
+    }
+
+    /// Read the current value of all the counters.
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        MetricsSnapshot {
+            datagrams_sent: load(&self.datagrams_sent),
+            datagrams_received: load(&self.datagrams_received),
+            bytes_sent: load(&self.bytes_sent),
+            bytes_received: load(&self.bytes_received),
+            segments_processed: load(&self.segments_processed),
+            segments_deferred: load(&self.segments_deferred),
+            updates_sent: load(&self.updates_sent),
+            updates_applied: load(&self.updates_applied),
+            updates_rejected: load(&self.updates_rejected),
+            timeout_reconciliations: load(&self.timeout_reconciliations),
+            malformed_datagrams: load(&self.malformed_datagrams),
+            undecodable_messages: load(&self.undecodable_messages),
+            foreign_schema_updates: load(&self.foreign_schema_updates),
+            broadcasts_dropped: load(&self.broadcasts_dropped),
+            send_errors: load(&self.send_errors),
+            auth_failures: load(&self.auth_failures),
+            upgraded_fingerprint_sessions: load(&self.upgraded_fingerprint_sessions),
+            datagrams_compressed: load(&self.datagrams_compressed),
+            future_timestamps_rejected: load(&self.future_timestamps_rejected),
+            updates_throttled: load(&self.updates_throttled),
+            datagrams_paused: load(&self.datagrams_paused),
+            serialize_errors: load(&self.serialize_errors),
+            wal_errors: load(&self.wal_errors),
+            duplicate_datagrams: load(&self.duplicate_datagrams),
+            oversize_datagrams: load(&self.oversize_datagrams),
+            max_divergence_age_ms: age(load(&self.oldest_divergence)),
+            sessions_restarted: load(&self.sessions_restarted),
+            restarted_session_rounds: load(&self.restarted_session_rounds),
+            restarted_session_ranges: load(&self.restarted_session_ranges),
+            longest_session_ms: age(load(&self.oldest_session)),
+        }
+    }
+}
+
+/// Store when something of the given age started, or 0 if there is none.
+fn store_start(counter: &AtomicU64, age: Option<Duration>) {
+    let start = age
+        .and_then(|age| SystemTime::now().checked_sub(age))
+        .map_or(0, |start| unix_millis(start).max(1));
+    counter.store(start, Ordering::Relaxed);
+}
+
+/// Time in milliseconds since the start stored by [`store_start`], or 0.
+fn age(start: u64) -> u64 {
+    match start {
+        0 => 0,
+        start => unix_millis(SystemTime::now()).saturating_sub(start),
+    }
+}
+
+fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
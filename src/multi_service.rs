@@ -0,0 +1,299 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides the [`MultiService`], which reconciles several independent maps, or namespaces, over
+//! a single socket.
+//!
+//! Each namespace is identified on the wire by its index in a registry of names, which must be
+//! the same for all the instances. A datagram may carry messages of several namespaces, each
+//! group of messages being preceded by the id of its namespace. The messages of the namespaces an
+//! instance does not hold are ignored.
+//!
+//! ```
+//! # use reconcile::{multi_service::MultiService, sim::SimNetwork, HRTree, DatedMaybeTombstone};
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let network = SimNetwork::new(42);
+//! let socket = network.bind("10.0.0.1:8080".parse().unwrap()).unwrap();
+//! let maps: Vec<(&str, HRTree<u8, DatedMaybeTombstone<String>>)> =
+//!     vec![("users", HRTree::new()), ("configs", HRTree::new())];
+//! let registry = ["users", "sessions", "configs"];
+//! let service = MultiService::with_transport(&registry, maps, socket, "10.0.0.0/24".parse().unwrap());
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::net::UdpSocket;
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tracing::{debug, warn};
+
+use crate::diff::{Diffable, HashRangeQueryable};
+use crate::error::Error;
+use crate::internal_service::{split_namespaces, tag_namespace, BUFFER_SIZE};
+use crate::map::Map;
+use crate::service::{DatedMaybeTombstone, Service, ValueRef};
+use crate::transport::{Transport, TransportFuture};
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// Wraps several key-value maps, each in its own namespace, to reconcile them with other
+/// instances over a single socket.
+///
+/// Each namespace is reconciled by a [`Service`], which can be accessed with
+/// [`namespace`](MultiService::namespace); the services only share the socket, and run their own
+/// reconciliation sessions.
+pub struct MultiService<M: Map + HashRangeQueryable>
+where
+    <M as Map>::Key: Clone + Hash + std::cmp::Eq + Send + Sync,
+{
+    transport: Arc<dyn Transport>,
+    namespaces: HashMap<String, Service<M>>,
+    /// Channels to the sockets of the services, by namespace id
+    senders: HashMap<u16, mpsc::UnboundedSender<Datagram>>,
+}
+
+impl<M: Map + HashRangeQueryable> Clone for MultiService<M>
+where
+    <M as Map>::Key: Clone + Hash + std::cmp::Eq + Send + Sync,
+{
+    fn clone(&self) -> Self {
+        MultiService {
+            transport: self.transport.clone(),
+            namespaces: self.namespaces.clone(),
+            senders: self.senders.clone(),
+        }
+    }
+}
+
+impl<
+        K: Clone + Debug + DeserializeOwned + Hash + Ord + Send + Serialize + Sync + 'static,
+        V: Clone + DeserializeOwned + Hash + Send + Serialize + Sync + 'static,
+        C: Clone + Debug + DeserializeOwned + Send + Serialize + Sync + 'static,
+        D: Clone + Debug + Send + Sync + 'static,
+        M: Map<Key = K, Value = DatedMaybeTombstone<V>, DifferenceItem = D>
+            + Diffable<ComparisonItem = C, DifferenceItem = D>
+            + HashRangeQueryable<Key = K>
+            + Send
+            + Sync
+            + 'static,
+    > MultiService<M>
+{
+    pub async fn new<'a, I: IntoIterator<Item = (&'a str, M)>>(
+        registry: &[&str],
+        maps: I,
+        port: u16,
+        listen_addr: IpAddr,
+        peer_net: IpNet,
+    ) -> Result<Self, Error> {
+        let addr = SocketAddr::new(listen_addr, port);
+        let socket = UdpSocket::bind(addr)
+            .await
+            .map_err(|err| Error::Bind(addr, err))?;
+        Ok(MultiService::with_transport(
+            registry, maps, socket, peer_net,
+        ))
+    }
+
+    /// Create a service for the given maps over an already-bound [`Transport`].
+    ///
+    /// The id of each namespace on the wire is the index of its name in `registry`, which must be
+    /// the same for all the instances; an instance may hold only some of the namespaces.
+    ///
+    /// Panics if the name of a map is not in the registry.
+    pub fn with_transport<'a, T: Transport + 'static, I: IntoIterator<Item = (&'a str, M)>>(
+        registry: &[&str],
+        maps: I,
+        transport: T,
+        peer_net: IpNet,
+    ) -> Self {
+        assert!(
+            registry.len() <= u16::MAX as usize + 1,
+            "too many namespaces in the registry"
+        );
+        let transport: Arc<dyn Transport> = Arc::new(transport);
+        let mut namespaces = HashMap::new();
+        let mut senders = HashMap::new();
+        for (name, map) in maps {
+            let id = registry
+                .iter()
+                .position(|&registered| registered == name)
+                .unwrap_or_else(|| panic!("namespace {name} is not in the registry"))
+                as u16;
+            let (sender, receiver) = mpsc::unbounded_channel();
+            let socket = NamespaceSocket {
+                id,
+                transport: transport.clone(),
+                receiver: tokio::sync::Mutex::new(receiver),
+            };
+            senders.insert(id, sender);
+            namespaces.insert(
+                name.to_string(),
+                Service::with_transport(map, socket, peer_net),
+            );
+        }
+        MultiService {
+            transport,
+            namespaces,
+            senders,
+        }
+    }
+
+    /// Configure the service of each namespace, for instance to set a discovery strategy.
+    pub fn configure<F: FnMut(&str, Service<M>) -> Service<M>>(mut self, mut f: F) -> Self {
+        self.namespaces = self
+            .namespaces
+            .into_iter()
+            .map(|(name, service)| {
+                let service = f(&name, service);
+                (name, service)
+            })
+            .collect();
+        self
+    }
+
+    /// Provides the address of a known peer to the services of all the namespaces, like
+    /// [`Service::with_seed`].
+    pub fn with_seed(self, peer: IpAddr) -> Self {
+        self.configure(|_, service| service.with_seed(peer))
+    }
+
+    /// Provides the address and port of a known peer to the services of all the namespaces, like
+    /// [`Service::with_seed_addr`].
+    pub fn with_seed_addr(self, peer: SocketAddr) -> Self {
+        self.configure(|_, service| service.with_seed_addr(peer))
+    }
+
+    /// Service reconciling the given namespace, if it is held by this instance.
+    pub fn namespace(&self, namespace: &str) -> Option<&Service<M>> {
+        self.namespaces.get(namespace)
+    }
+
+    /// Names of the namespaces held by this instance.
+    pub fn namespaces(&self) -> impl Iterator<Item = &str> {
+        self.namespaces.keys().map(String::as_str)
+    }
+
+    fn service(&self, namespace: &str) -> &Service<M> {
+        self.namespace(namespace)
+            .unwrap_or_else(|| panic!("namespace {namespace} is not held by the service"))
+    }
+
+    /// Get the value at the given key in the given namespace.
+    ///
+    /// Return `None` if the namespace is not held by this instance.
+    pub fn get(&self, namespace: &str, k: &K) -> Option<ValueRef<'_, V>> {
+        self.namespace(namespace)?.get(k)
+    }
+
+    /// Insert a value at the given key in the given namespace, and send it to the peers.
+    ///
+    /// Panics if the namespace is not held by this instance.
+    pub fn insert(&self, namespace: &str, key: K, value: V, timestamp: DateTime<Utc>) -> Option<V> {
+        self.service(namespace).insert(key, value, timestamp)
+    }
+
+    /// Remove the value at the given key in the given namespace, and send the tombstone to the
+    /// peers.
+    ///
+    /// Panics if the namespace is not held by this instance.
+    pub fn remove(&self, namespace: &str, key: &K, timestamp: DateTime<Utc>) -> Option<V> {
+        self.service(namespace).remove(key, timestamp)
+    }
+
+    pub async fn run(self) {
+        let mut tasks = JoinSet::new();
+        for service in self.namespaces.into_values() {
+            tasks.spawn(service.run());
+        }
+        tokio::join!(dispatch(&*self.transport, &self.senders), async {
+            while tasks.join_next().await.is_some() {}
+        },);
+    }
+}
+
+/// Receive the datagrams of the shared socket, and pass the messages of each namespace to the
+/// socket of its service.
+async fn dispatch(
+    transport: &dyn Transport,
+    senders: &HashMap<u16, mpsc::UnboundedSender<Datagram>>,
+) {
+    // extra byte that easily detect when the buffer is too small
+    let mut recv_buf = vec![0; BUFFER_SIZE + 1];
+    loop {
+        let (size, peer) = match transport.recv_from(&mut recv_buf).await {
+            Ok(received) => received,
+            Err(err) => {
+                warn!("network error in recv_from: {err}");
+                continue;
+            }
+        };
+        if size == recv_buf.len() {
+            warn!("Buffer too small for message, discarded");
+            continue;
+        }
+        let datagram = &recv_buf[..size];
+        let Some(datagrams) = split_namespaces(datagram) else {
+            // let the services report it
+            for sender in senders.values() {
+                let _ = sender.send((datagram.to_vec(), peer));
+            }
+            continue;
+        };
+        for (id, datagram) in datagrams {
+            match senders.get(&id) {
+                Some(sender) => {
+                    let _ = sender.send((datagram, peer));
+                }
+                None => debug!("ignoring messages of namespace {id} from {peer}, not held locally"),
+            }
+        }
+    }
+}
+
+/// Socket of the service of a namespace, over the shared socket.
+struct NamespaceSocket {
+    id: u16,
+    transport: Arc<dyn Transport>,
+    receiver: tokio::sync::Mutex<mpsc::UnboundedReceiver<Datagram>>,
+}
+
+impl Transport for NamespaceSocket {
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> TransportFuture<'a, usize> {
+        Box::pin(async move {
+            let mut sent = 0;
+            for datagram in tag_namespace(buf, self.id) {
+                sent += self.transport.send_to(&datagram, target).await?;
+            }
+            Ok(sent)
+        })
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, SocketAddr)> {
+        Box::pin(async move {
+            let Some((data, from)) = self.receiver.lock().await.recv().await else {
+                return Err(std::io::ErrorKind::NotConnected.into());
+            };
+            let size = data.len().min(buf.len());
+            buf[..size].copy_from_slice(&data[..size]);
+            Ok((size, from))
+        })
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.transport.local_addr()
+    }
+}
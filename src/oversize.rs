@@ -0,0 +1,178 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`Oversize`], which tracks the peers whose datagrams do not fit in the receive
+//! buffer, and [`DatagramBudgets`], which holds the size of the datagrams sent to the peers that
+//! asked for smaller ones.
+//!
+//! A datagram that fills the whole receive buffer was truncated, and is dropped. Since the
+//! sender would send the same data again at the next sessions, it is told the size of the
+//! largest datagram accepted once several of its datagrams in a row were too large, and sends
+//! smaller datagrams from then on.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+
+/// Number of datagrams too large in a row from a peer before it is sent a nack
+pub(crate) const OVERSIZE_NACK_THRESHOLD: u32 = 3;
+/// Smallest size of the datagrams a peer may ask for
+pub(crate) const MIN_DATAGRAM_SIZE: usize = 1200;
+
+/// Why a peer refused the datagrams received.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub(crate) enum NackReason {
+    /// The datagrams did not fit in the receive buffer
+    TooLarge,
+}
+
+#[derive(Default)]
+struct PeerOversize {
+    /// Datagrams too large received since the last nack, or the last datagram handled
+    consecutive: u32,
+    /// Whether a nack was sent since the last datagram handled
+    nacked: bool,
+}
+
+pub(crate) struct Oversize {
+    peers: HashMap<SocketAddr, PeerOversize>,
+}
+
+impl Oversize {
+    pub fn new() -> Self {
+        Oversize {
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Record a datagram from the peer that did not fit in the receive buffer.
+    ///
+    /// Return whether the peer must be sent a nack.
+    pub fn record(&mut self, peer: SocketAddr) -> bool {
+        let state = self.peers.entry(peer).or_default();
+        state.consecutive += 1;
+        if state.consecutive < OVERSIZE_NACK_THRESHOLD {
+            return false;
+        }
+        state.consecutive = 0;
+        state.nacked = true;
+        true
+    }
+
+    /// Record a datagram from the peer that fit in the receive buffer.
+    pub fn clear(&mut self, peer: SocketAddr) {
+        self.peers.remove(&peer);
+    }
+
+    /// Peers whose last datagrams did not fit in the receive buffer, with whether they were sent
+    /// a nack.
+    pub fn peers(&self) -> Vec<(SocketAddr, bool)> {
+        let mut peers: Vec<_> = self
+            .peers
+            .iter()
+            .map(|(&peer, state)| (peer, state.nacked))
+            .collect();
+        peers.sort();
+        peers
+    }
+
+    /// Forget the peers not in the list.
+    pub fn retain_peers(&mut self, peers: &[SocketAddr]) {
+        self.peers.retain(|peer, _| peers.contains(peer));
+    }
+}
+
+/// Maximum size of the datagrams sent to each peer.
+pub(crate) struct DatagramBudgets {
+    default: usize,
+    /// Peers that asked for datagrams smaller than the default
+    peers: Mutex<HashMap<SocketAddr, usize>>,
+}
+
+impl DatagramBudgets {
+    pub fn new(default: usize) -> Self {
+        DatagramBudgets {
+            default,
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Maximum size of the datagrams sent to the peer.
+    pub fn get(&self, peer: SocketAddr) -> usize {
+        self.peers
+            .lock()
+            .get(&peer)
+            .copied()
+            .unwrap_or(self.default)
+    }
+
+    /// Send datagrams of at most `size` bytes to the peer, as it asked.
+    ///
+    /// The size is clamped to [`MIN_DATAGRAM_SIZE`], and never raised above the default.
+    pub fn lower(&self, peer: SocketAddr, size: usize) {
+        let size = size.max(MIN_DATAGRAM_SIZE);
+        if size < self.default {
+            self.peers.lock().insert(peer, size);
+        }
+    }
+
+    /// Peers that asked for datagrams smaller than the default, with their size.
+    pub fn lowered(&self) -> Vec<(SocketAddr, usize)> {
+        let mut peers: Vec<_> = self
+            .peers
+            .lock()
+            .iter()
+            .map(|(&peer, &size)| (peer, size))
+            .collect();
+        peers.sort();
+        peers
+    }
+
+    /// Forget the peers not in the list.
+    pub fn retain_peers(&self, peers: &[SocketAddr]) {
+        self.peers.lock().retain(|peer, _| peers.contains(peer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DatagramBudgets, Oversize, MIN_DATAGRAM_SIZE, OVERSIZE_NACK_THRESHOLD};
+
+    #[test]
+    fn oversize() {
+        let peer = "10.0.0.1:8080".parse().unwrap();
+        let mut oversize = Oversize::new();
+        for _ in 1..OVERSIZE_NACK_THRESHOLD {
+            assert!(!oversize.record(peer));
+        }
+        assert_eq!(oversize.peers(), [(peer, false)]);
+        assert!(oversize.record(peer));
+        assert_eq!(oversize.peers(), [(peer, true)]);
+        // the count starts over after a nack, or a datagram that fits
+        assert!(!oversize.record(peer));
+        oversize.clear(peer);
+        assert!(oversize.peers().is_empty());
+        for _ in 1..OVERSIZE_NACK_THRESHOLD {
+            assert!(!oversize.record(peer));
+        }
+        oversize.retain_peers(&[]);
+        assert!(oversize.peers().is_empty());
+
+        let budgets = DatagramBudgets::new(65000);
+        budgets.lower(peer, 100_000);
+        assert_eq!(budgets.get(peer), 65000);
+        budgets.lower(peer, 100);
+        assert_eq!(budgets.get(peer), MIN_DATAGRAM_SIZE);
+        budgets.lower(peer, 8000);
+        assert_eq!(budgets.lowered(), [(peer, 8000)]);
+        budgets.retain_peers(&[]);
+        assert_eq!(budgets.get(peer), 65000);
+    }
+}
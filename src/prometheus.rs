@@ -0,0 +1,341 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Renders the metrics of a service in the Prometheus text exposition format, see
+//! [`Service::render_prometheus`](crate::Service::render_prometheus).
+
+use std::fmt::{Display, Write};
+
+use chrono::{DateTime, Utc};
+
+use crate::internal_service::PeerInfo;
+use crate::metrics::MetricsSnapshot;
+
+/// State of the service reported besides the counters of the [`MetricsSnapshot`]
+pub(crate) struct ServiceState {
+    pub map_size: usize,
+    pub tombstones: usize,
+    pub pending_tombstones: usize,
+    /// Hash of the whole map, as displayed
+    pub root_hash: String,
+    pub last_change: DateTime<Utc>,
+    pub peers: Vec<PeerInfo>,
+}
+
+/// Text of the metric families, each with its HELP and TYPE lines
+#[derive(Default)]
+struct Exposition {
+    text: String,
+}
+
+impl Exposition {
+    fn family<T: Display>(
+        &mut self,
+        name: &str,
+        kind: &str,
+        help: &str,
+        samples: impl IntoIterator<Item = (Vec<(&'static str, String)>, T)>,
+    ) {
+        let help = help.replace('\\', "\\\\").replace('\n', "\\n");
+        // writing to a String cannot fail
+        let _ = writeln!(self.text, "# HELP reconcile_{name} {help}");
+        let _ = writeln!(self.text, "# TYPE reconcile_{name} {kind}");
+        for (labels, value) in samples {
+            let _ = write!(self.text, "reconcile_{name}");
+            if !labels.is_empty() {
+                let labels: Vec<_> = labels
+                    .iter()
+                    .map(|(label, value)| format!("{label}=\"{}\"", escape_label(value)))
+                    .collect();
+                let _ = write!(self.text, "{{{}}}", labels.join(","));
+            }
+            let _ = writeln!(self.text, " {value}");
+        }
+    }
+
+    fn counter(&mut self, name: &str, help: &str, value: u64) {
+        self.family(name, "counter", help, [(Vec::new(), value)]);
+    }
+
+    fn gauge<T: Display>(&mut self, name: &str, help: &str, value: T) {
+        self.family(name, "gauge", help, [(Vec::new(), value)]);
+    }
+}
+
+/// Escape a label value, which is quoted in the exposition format.
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render the metrics in the Prometheus text exposition format.
+pub(crate) fn render(metrics: &MetricsSnapshot, state: &ServiceState) -> String {
+    let mut exposition = Exposition::default();
+    let counters = [
+        (
+            "datagrams_sent_total",
+            "Datagrams sent to peers",
+            metrics.datagrams_sent,
+        ),
+        (
+            "datagrams_received_total",
+            "Datagrams received from peers",
+            metrics.datagrams_received,
+        ),
+        (
+            "bytes_sent_total",
+            "Bytes sent to peers",
+            metrics.bytes_sent,
+        ),
+        (
+            "bytes_received_total",
+            "Bytes received from peers",
+            metrics.bytes_received,
+        ),
+        (
+            "segments_processed_total",
+            "Comparison segments received from peers",
+            metrics.segments_processed,
+        ),
+        (
+            "segments_deferred_total",
+            "Comparison segments deferred to a later datagram",
+            metrics.segments_deferred,
+        ),
+        (
+            "updates_sent_total",
+            "Key-value pairs sent to peers",
+            metrics.updates_sent,
+        ),
+        (
+            "updates_applied_total",
+            "Key-value pairs received from peers and inserted",
+            metrics.updates_applied,
+        ),
+        (
+            "updates_rejected_total",
+            "Key-value pairs received from peers and discarded",
+            metrics.updates_rejected,
+        ),
+        (
+            "updates_throttled_total",
+            "Key-value pairs received from peers beyond their budget",
+            metrics.updates_throttled,
+        ),
+        (
+            "datagrams_paused_total",
+            "Datagrams whose segments and updates were dropped while paused",
+            metrics.datagrams_paused,
+        ),
+        (
+            "serialize_errors_total",
+            "Messages dropped because they could not be serialized",
+            metrics.serialize_errors,
+        ),
+        (
+            "wal_errors_total",
+            "Failures to write changes to the write-ahead log",
+            metrics.wal_errors,
+        ),
+        (
+            "duplicate_datagrams_total",
+            "Datagrams dropped because they were received twice from the same peer",
+            metrics.duplicate_datagrams,
+        ),
+        (
+            "oversize_datagrams_total",
+            "Datagrams dropped because they did not fit in the receive buffer",
+            metrics.oversize_datagrams,
+        ),
+        (
+            "timeout_reconciliations_total",
+            "Reconciliations started because of inactivity",
+            metrics.timeout_reconciliations,
+        ),
+        (
+            "malformed_datagrams_total",
+            "Datagrams received with messages that could not be deserialized",
+            metrics.malformed_datagrams,
+        ),
+        (
+            "undecodable_messages_total",
+            "Messages received that could not be deserialized, and were skipped",
+            metrics.undecodable_messages,
+        ),
+        (
+            "foreign_schema_updates_total",
+            "Updates received tagged with another schema id, and skipped",
+            metrics.foreign_schema_updates,
+        ),
+        (
+            "broadcasts_dropped_total",
+            "Local writes dropped from the broadcast queue",
+            metrics.broadcasts_dropped,
+        ),
+        (
+            "send_errors_total",
+            "Datagrams that could not be sent",
+            metrics.send_errors,
+        ),
+        (
+            "auth_failures_total",
+            "Datagrams discarded because they were not authenticated",
+            metrics.auth_failures,
+        ),
+        (
+            "upgraded_fingerprint_sessions_total",
+            "Sessions opened with a stronger fingerprint",
+            metrics.upgraded_fingerprint_sessions,
+        ),
+        (
+            "datagrams_compressed_total",
+            "Datagrams sent compressed",
+            metrics.datagrams_compressed,
+        ),
+        (
+            "future_timestamps_rejected_total",
+            "Values received from peers with a timestamp too far in the future",
+            metrics.future_timestamps_rejected,
+        ),
+        (
+            "sessions_restarted_total",
+            "Sessions started over because they exceeded the deadline",
+            metrics.sessions_restarted,
+        ),
+        (
+            "restarted_session_rounds_total",
+            "Replies received in the sessions started over",
+            metrics.restarted_session_rounds,
+        ),
+        (
+            "restarted_session_ranges_total",
+            "Segments still outstanding in the sessions started over",
+            metrics.restarted_session_ranges,
+        ),
+    ];
+    for (name, help, value) in counters {
+        exposition.counter(name, help, value);
+    }
+    exposition.gauge(
+        "max_divergence_age_seconds",
+        "Time since the oldest range still differing with a peer was found",
+        metrics.max_divergence_age_ms as f64 / 1000.,
+    );
+    exposition.gauge(
+        "longest_session_seconds",
+        "Time since the start of the oldest active session initiated locally",
+        metrics.longest_session_ms as f64 / 1000.,
+    );
+    exposition.gauge("peers", "Known peers", state.peers.len());
+    exposition.gauge("map_size", "Elements in the map", state.map_size);
+    exposition.gauge(
+        "tombstones",
+        "Tombstones that have not expired yet",
+        state.tombstones,
+    );
+    exposition.gauge(
+        "pending_tombstones",
+        "Expired tombstones waiting for the acknowledgement of the peers",
+        state.pending_tombstones,
+    );
+    exposition.family(
+        "info",
+        "gauge",
+        "Hash of the whole map",
+        [(vec![("root_hash", state.root_hash.clone())], 1)],
+    );
+    exposition.gauge(
+        "last_change_timestamp_seconds",
+        "Time of the last change to the map, local or received from a peer",
+        state.last_change.timestamp_millis() as f64 / 1000.,
+    );
+    exposition.family(
+        "peer_last_seen_seconds",
+        "gauge",
+        "Time since the last datagram received from the peer",
+        state.peers.iter().map(|peer| {
+            (
+                vec![("peer", peer.addr.to_string())],
+                peer.last_seen.as_secs_f64(),
+            )
+        }),
+    );
+    exposition.family(
+        "peer_updates_applied_total",
+        "counter",
+        "Key-value pairs received from the peer and inserted",
+        state
+            .peers
+            .iter()
+            .map(|peer| (vec![("peer", peer.addr.to_string())], peer.updates_applied)),
+    );
+    exposition.family(
+        "peer_oversize_datagrams_total",
+        "counter",
+        "Datagrams received from the peer and dropped as too large",
+        state.peers.iter().map(|peer| {
+            (
+                vec![("peer", peer.addr.to_string())],
+                peer.oversize_datagrams,
+            )
+        }),
+    );
+    exposition.text
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::{TimeZone, Utc};
+
+    use super::{escape_label, render, ServiceState};
+    use crate::internal_service::PeerInfo;
+    use crate::metrics::MetricsSnapshot;
+
+    #[test]
+    fn escaping() {
+        assert_eq!(escape_label("[::1]:8080"), "[::1]:8080");
+        assert_eq!(escape_label("a\"b\\c\nd"), "a\\\"b\\\\c\\nd");
+    }
+
+    #[test]
+    fn rendering() {
+        let metrics = MetricsSnapshot {
+            bytes_sent: 42,
+            max_divergence_age_ms: 1500,
+            ..MetricsSnapshot::default()
+        };
+        let state = ServiceState {
+            map_size: 3,
+            tombstones: 1,
+            pending_tombstones: 0,
+            root_hash: "123".to_string(),
+            last_change: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            peers: vec![PeerInfo {
+                addr: "[::1]:8080".parse().unwrap(),
+                last_seen: Duration::from_millis(250),
+                updates_applied: 7,
+                oversize_datagrams: 2,
+                incompatible: false,
+            }],
+        };
+        let text = render(&metrics, &state);
+        assert!(text.contains("# TYPE reconcile_bytes_sent_total counter\n"));
+        assert!(text.contains("\nreconcile_bytes_sent_total 42\n"));
+        assert!(text.contains("\nreconcile_max_divergence_age_seconds 1.5\n"));
+        assert!(text.contains("\nreconcile_map_size 3\n"));
+        assert!(text.contains("\nreconcile_info{root_hash=\"123\"} 1\n"));
+        assert!(text.contains("\nreconcile_last_change_timestamp_seconds 1700000000\n"));
+        assert!(text.contains("\nreconcile_peer_last_seen_seconds{peer=\"[::1]:8080\"} 0.25\n"));
+        assert!(text.contains("\nreconcile_peer_updates_applied_total{peer=\"[::1]:8080\"} 7\n"));
+        assert!(text.contains("\nreconcile_peer_oversize_datagrams_total{peer=\"[::1]:8080\"} 2\n"));
+    }
+}
@@ -0,0 +1,172 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`RateLimiter`], a token bucket that paces the datagrams sent by a service, and
+//! [`UpdateBudget`], which bounds the updates accepted from each peer.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// Time of sending at full rate that can be saved up while idle
+const BURST: Duration = Duration::from_millis(100);
+
+struct TokenBucket {
+    /// Bytes per second
+    rate: u64,
+    /// Maximum number of tokens saved up
+    capacity: f64,
+    /// Available bytes; negative when senders are waiting for tokens
+    tokens: f64,
+    last_refill: Instant,
+}
+
+/// Limit the outbound bandwidth; unlimited by default.
+#[derive(Default)]
+pub(crate) struct RateLimiter {
+    bucket: Mutex<Option<TokenBucket>>,
+}
+
+impl RateLimiter {
+    /// Limit the bandwidth to the given number of bytes per second.
+    pub fn new(rate: u64) -> Self {
+        assert!(rate > 0, "the bandwidth must be positive");
+        let capacity = rate as f64 * BURST.as_secs_f64();
+        RateLimiter {
+            bucket: Mutex::new(Some(TokenBucket {
+                rate,
+                capacity,
+                tokens: capacity,
+                last_refill: Instant::now(),
+            })),
+        }
+    }
+
+    /// Wait until `size` bytes can be sent.
+    ///
+    /// Tokens are taken immediately, so that concurrent senders wait in turn.
+    pub async fn acquire(&self, size: usize) {
+        let wait = {
+            let mut guard = self.bucket.lock();
+            let Some(bucket) = guard.as_mut() else {
+                return;
+            };
+            let now = Instant::now();
+            let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+            bucket.tokens = bucket
+                .capacity
+                .min(bucket.tokens + elapsed * bucket.rate as f64);
+            bucket.last_refill = now;
+            bucket.tokens -= size as f64;
+            if bucket.tokens >= 0. {
+                return;
+            }
+            Duration::from_secs_f64(-bucket.tokens / bucket.rate as f64)
+        };
+        tokio::time::sleep(wait).await;
+    }
+}
+
+struct PeerBuckets {
+    /// Updates per second
+    rate: u64,
+    /// Available updates of each peer, and their last refill
+    buckets: HashMap<SocketAddr, (f64, Instant)>,
+}
+
+/// Limit the number of updates accepted from each peer per second; unlimited by default.
+///
+/// Each peer can save up one second worth of updates while idle.
+#[derive(Default)]
+pub(crate) struct UpdateBudget {
+    peers: Mutex<Option<PeerBuckets>>,
+}
+
+impl UpdateBudget {
+    /// Limit the updates of each peer to the given number per second.
+    pub fn new(rate: u64) -> Self {
+        assert!(rate > 0, "the update rate must be positive");
+        UpdateBudget {
+            peers: Mutex::new(Some(PeerBuckets {
+                rate,
+                buckets: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Take up to `count` updates from the budget of the peer, and return how many are allowed.
+    pub fn take(&self, peer: SocketAddr, count: usize) -> usize {
+        let mut guard = self.peers.lock();
+        let Some(PeerBuckets { rate, buckets }) = guard.as_mut() else {
+            return count;
+        };
+        let capacity = *rate as f64;
+        let now = Instant::now();
+        if !buckets.contains_key(&peer) {
+            // the buckets untouched for a second are full, and need not be kept
+            buckets.retain(|_, (_, last_refill)| now.duration_since(*last_refill).as_secs() < 1);
+        }
+        let (tokens, last_refill) = buckets.entry(peer).or_insert((capacity, now));
+        let elapsed = now.duration_since(*last_refill).as_secs_f64();
+        *tokens = capacity.min(*tokens + elapsed * capacity);
+        *last_refill = now;
+        let allowed = count.min(*tokens as usize);
+        *tokens -= allowed as f64;
+        allowed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{RateLimiter, UpdateBudget};
+
+    #[tokio::test]
+    async fn pacing() {
+        let limiter = RateLimiter::new(100_000);
+        let start = Instant::now();
+        // the burst is sent at once
+        limiter.acquire(10_000).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+        // then, 10 kB every 100 ms
+        for _ in 0..5 {
+            limiter.acquire(10_000).await;
+        }
+        assert!(start.elapsed() >= Duration::from_millis(500));
+
+        // no limit
+        let limiter = RateLimiter::default();
+        let start = Instant::now();
+        limiter.acquire(usize::MAX).await;
+        assert!(start.elapsed() < Duration::from_millis(50));
+    }
+
+    #[test]
+    fn update_budget() {
+        let budget = UpdateBudget::new(1000);
+        let a = "10.0.0.1:8080".parse().unwrap();
+        let b = "10.0.0.2:8080".parse().unwrap();
+        // one second worth of updates is allowed at once
+        assert_eq!(budget.take(a, 600), 600);
+        assert_eq!(budget.take(a, 600), 400);
+        assert_eq!(budget.take(a, 600), 0);
+        // the peers have separate budgets
+        assert_eq!(budget.take(b, 600), 600);
+        // refilled over time
+        std::thread::sleep(Duration::from_millis(100));
+        let allowed = budget.take(a, 600);
+        assert!((100..=200).contains(&allowed), "{allowed}");
+
+        // no limit
+        let budget = UpdateBudget::default();
+        assert_eq!(budget.take(a, usize::MAX), usize::MAX);
+    }
+}
@@ -0,0 +1,135 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`RecentWrites`], which remembers the last local writes so that they can be pushed
+//! again to the peers that might have missed them.
+//!
+//! The datagram carrying an update can be lost, in which case the change would only propagate at
+//! the next reconciliation session. Instead, the writes made after the last convergence with a
+//! peer are pushed to it again, as long as they all fit in the buffer. Otherwise, the peer is left
+//! to the reconciliation sessions.
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::hash::Hash;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+/// Maximum number of writes remembered, and thus pushed again to a peer
+pub(crate) const RECENT_WRITES_CAPACITY: usize = 1000;
+
+pub(crate) struct RecentWrites<K> {
+    /// Keys of the last local writes, from the oldest to the newest
+    writes: VecDeque<(K, Instant)>,
+    /// Time of the newest write that was evicted from the buffer
+    evicted: Option<Instant>,
+    /// Time of the last convergence with each peer
+    converged: HashMap<SocketAddr, Instant>,
+}
+
+impl<K: Clone + Eq + Hash> RecentWrites<K> {
+    pub fn new() -> Self {
+        RecentWrites {
+            writes: VecDeque::new(),
+            evicted: None,
+            converged: HashMap::new(),
+        }
+    }
+
+    /// Remember a local write to the given key.
+    pub fn record(&mut self, key: K) {
+        if self.writes.len() == RECENT_WRITES_CAPACITY {
+            if let Some((_, instant)) = self.writes.pop_front() {
+                self.evicted = Some(instant);
+            }
+        }
+        self.writes.push_back((key, Instant::now()));
+    }
+
+    /// Remember that the peer held the same values as the local map.
+    pub fn converged(&mut self, peer: SocketAddr) {
+        self.converged.insert(peer, Instant::now());
+    }
+
+    /// List the keys written since the given instant, from the newest to the oldest.
+    pub fn since(&self, instant: Instant) -> Vec<K> {
+        let mut seen = HashSet::new();
+        self.writes
+            .iter()
+            .rev()
+            .take_while(|(_, write)| *write >= instant)
+            .filter(|(key, _)| seen.insert(key))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// List the keys written since the last convergence with each of the peers.
+    ///
+    /// The peers that never converged, or that missed more writes than the buffer holds, are not
+    /// listed. The state of the addresses that are not in `peers` is forgotten.
+    pub fn pending(&mut self, peers: &[SocketAddr]) -> Vec<(SocketAddr, Vec<K>)> {
+        self.converged.retain(|addr, _| peers.contains(addr));
+        let mut pending = Vec::new();
+        for (&peer, &converged) in &self.converged {
+            if self.evicted.is_some_and(|evicted| evicted >= converged) {
+                continue;
+            }
+            let mut seen = HashSet::new();
+            let keys: Vec<_> = self
+                .writes
+                .iter()
+                .rev()
+                .take_while(|(_, instant)| *instant >= converged)
+                .filter(|(key, _)| seen.insert(key))
+                .map(|(key, _)| key.clone())
+                .collect();
+            if !keys.is_empty() {
+                pending.push((peer, keys));
+            }
+        }
+        pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::{RecentWrites, RECENT_WRITES_CAPACITY};
+
+    #[test]
+    fn pending() {
+        let peer1: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let peer2: SocketAddr = "127.0.0.2:8080".parse().unwrap();
+        let mut writes = RecentWrites::new();
+
+        // peers that never converged are left to reconciliation sessions
+        writes.record(0);
+        assert!(writes.pending(&[peer1, peer2]).is_empty());
+
+        writes.converged(peer1);
+        writes.converged(peer2);
+        assert!(writes.pending(&[peer1, peer2]).is_empty());
+        writes.record(1);
+        writes.record(2);
+        writes.record(1);
+        writes.converged(peer2);
+        assert_eq!(writes.pending(&[peer1, peer2]), vec![(peer1, vec![1, 2])]);
+
+        // too many writes to push
+        for i in 0..RECENT_WRITES_CAPACITY {
+            writes.record(i);
+        }
+        let pending = writes.pending(&[peer1, peer2]);
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].0, peer2);
+        assert_eq!(pending[0].1.len(), RECENT_WRITES_CAPACITY);
+
+        // expired peers are forgotten
+        assert!(writes.pending(&[peer1]).is_empty());
+    }
+}
@@ -6,7 +6,12 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-//! Provides the [`Reconcilable`] trait.
+//! Provides the [`Reconcilable`] trait, and the [`ConflictPolicy`] of a
+//! [`Service`](crate::Service).
+
+use std::cmp::Ordering;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
 
 use chrono::{DateTime, Utc};
 
@@ -21,14 +26,205 @@ pub enum ReconciliationResult {
 /// have to be [`Reconcilable`] to ensure safe conflict handling.
 pub trait Reconcilable {
     fn reconcile(&self, other: &Self) -> ReconciliationResult;
-}
 
-impl<V> Reconcilable for (DateTime<Utc>, V) {
-    fn reconcile(&self, other: &Self) -> ReconciliationResult {
-        if other.0 > self.0 {
+    /// Combine two conflicting values into a new one, instead of choosing one of them.
+    ///
+    /// When it returns `Some`, the merged value replaces the local one, and is sent to the peers,
+    /// since none of them may hold it yet; [`reconcile`](Reconcilable::reconcile) is only used
+    /// when it returns `None`, which is the default. Merging must be commutative, associative and
+    /// idempotent, as for the state-based CRDTs, so that all the instances end up with the same
+    /// value whatever the order in which they receive the updates.
+    ///
+    /// The dated values of the [`Service`](crate::Service) are never merged: the most recent one
+    /// wins.
+    ///
+    /// ```
+    /// # use std::collections::BTreeMap;
+    /// # use reconcile::reconcilable::{Reconcilable, ReconciliationResult};
+    /// /// Counter that can only grow, incremented independently by each instance
+    /// #[derive(Clone, Default, Hash)]
+    /// struct GCounter(BTreeMap<u8, u64>);
+    ///
+    /// impl Reconcilable for GCounter {
+    ///     fn reconcile(&self, _other: &Self) -> ReconciliationResult {
+    ///         unreachable!("counters are always merged")
+    ///     }
+    ///
+    ///     fn merge(&self, other: &Self) -> Option<Self> {
+    ///         let mut merged = self.clone();
+    ///         for (&node, &count) in &other.0 {
+    ///             let local = merged.0.entry(node).or_default();
+    ///             *local = count.max(*local);
+    ///         }
+    ///         Some(merged)
+    ///     }
+    /// }
+    ///
+    /// let a = GCounter(BTreeMap::from([(0, 3), (1, 1)]));
+    /// let b = GCounter(BTreeMap::from([(1, 2)]));
+    /// let merged = a.merge(&b).unwrap();
+    /// assert_eq!(merged.0.values().sum::<u64>(), 5);
+    /// ```
+    fn merge(&self, _other: &Self) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        None
+    }
+
+    /// Choose between two conflicting values that are equally recent.
+    ///
+    /// Both instances must pick the same winner, whichever side calls it, or they would keep
+    /// exchanging the values. By default, the value with the largest hash wins. Override it to
+    /// use, for instance, the identifier of the instance that wrote the value.
+    fn break_tie(&self, other: &Self) -> ReconciliationResult
+    where
+        Self: Hash,
+    {
+        let hash = |value: &Self| {
+            let mut hasher = DefaultHasher::new();
+            value.hash(&mut hasher);
+            hasher.finish()
+        };
+        if hash(other) > hash(self) {
             ReconciliationResult::KeepOther
         } else {
             ReconciliationResult::KeepSelf
         }
     }
 }
+
+/// The most recent value wins.
+///
+/// When both values have the same timestamp, the conflict is settled by
+/// [`break_tie`](Reconcilable::break_tie), so that all the instances keep the same value.
+impl<V: Hash> Reconcilable for (DateTime<Utc>, V) {
+    fn reconcile(&self, other: &Self) -> ReconciliationResult {
+        match other.0.cmp(&self.0) {
+            Ordering::Greater => ReconciliationResult::KeepOther,
+            Ordering::Less => ReconciliationResult::KeepSelf,
+            Ordering::Equal => self.break_tie(other),
+        }
+    }
+}
+
+/// Outcome of [`ConflictPolicy::resolve`].
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Resolution<V> {
+    /// The local value is kept, and the incoming one discarded
+    KeepLocal,
+    /// The incoming value replaces the local one
+    KeepIncoming,
+    /// Both values are replaced by the given one, which is also sent to the peers
+    Merge(V),
+}
+
+/// Settles the conflicts between the local value of a key and the one received from a peer.
+///
+/// As with [`Reconcilable`], all the instances must settle a conflict the same way, whichever
+/// side resolves it, or they would keep exchanging the values; a merge must be commutative,
+/// associative and idempotent.
+///
+/// It lets a [`Service`](crate::Service) synchronize values that carry their own versioning,
+/// such as a version vector, rather than a timestamp; see
+/// [`Service::new_with_policy`](crate::Service::new_with_policy). Closures with the signature of
+/// [`resolve`](ConflictPolicy::resolve) are policies too.
+pub trait ConflictPolicy<V>: Send + Sync {
+    fn resolve(&self, local: &V, incoming: &V) -> Resolution<V>;
+}
+
+impl<V, F: Fn(&V, &V) -> Resolution<V> + Send + Sync> ConflictPolicy<V> for F {
+    fn resolve(&self, local: &V, incoming: &V) -> Resolution<V> {
+        self(local, incoming)
+    }
+}
+
+/// Policy of the values that are [`Reconcilable`]: they are merged if
+/// [`merge`](Reconcilable::merge) returns a value, and reconciled otherwise.
+///
+/// For the dated values of the [`Service`](crate::Service), the last write wins, which is the
+/// policy of [`Service::new`](crate::Service::new).
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LwwPolicy;
+
+impl<V: Reconcilable> ConflictPolicy<V> for LwwPolicy {
+    fn resolve(&self, local: &V, incoming: &V) -> Resolution<V> {
+        if let Some(merged) = local.merge(incoming) {
+            return Resolution::Merge(merged);
+        }
+        match local.reconcile(incoming) {
+            ReconciliationResult::KeepSelf => Resolution::KeepLocal,
+            ReconciliationResult::KeepOther => Resolution::KeepIncoming,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::Utc;
+    use rand::{Rng, SeedableRng};
+
+    use super::{Reconcilable, ReconciliationResult};
+
+    /// A write that remembers the instance it comes from
+    #[derive(Hash)]
+    struct Write {
+        timestamp: u64,
+        node: u8,
+    }
+
+    impl Reconcilable for Write {
+        fn reconcile(&self, other: &Self) -> ReconciliationResult {
+            if other.timestamp == self.timestamp {
+                self.break_tie(other)
+            } else if other.timestamp > self.timestamp {
+                ReconciliationResult::KeepOther
+            } else {
+                ReconciliationResult::KeepSelf
+            }
+        }
+
+        fn break_tie(&self, other: &Self) -> ReconciliationResult {
+            if other.node > self.node {
+                ReconciliationResult::KeepOther
+            } else {
+                ReconciliationResult::KeepSelf
+            }
+        }
+    }
+
+    #[test]
+    fn equal_timestamps() {
+        let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+        let timestamp = Utc::now();
+        for _ in 0..1000 {
+            let a = (timestamp, rng.gen::<u64>());
+            let b = (timestamp, rng.gen::<u64>());
+            // both sides agree on the winner
+            let winner = match a.reconcile(&b) {
+                ReconciliationResult::KeepSelf => a,
+                ReconciliationResult::KeepOther => b,
+            };
+            let other_winner = match b.reconcile(&a) {
+                ReconciliationResult::KeepSelf => b,
+                ReconciliationResult::KeepOther => a,
+            };
+            assert_eq!(winner, other_winner);
+            assert_eq!(a.reconcile(&a), ReconciliationResult::KeepSelf);
+        }
+    }
+
+    #[test]
+    fn custom_tie_breaker() {
+        let a = Write {
+            timestamp: 1,
+            node: 1,
+        };
+        let b = Write {
+            timestamp: 1,
+            node: 2,
+        };
+        assert_eq!(a.reconcile(&b), ReconciliationResult::KeepOther);
+        assert_eq!(b.reconcile(&a), ReconciliationResult::KeepSelf);
+    }
+}
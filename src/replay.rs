@@ -0,0 +1,92 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`Duplicates`], which detects the datagrams received twice from the same peer.
+//!
+//! The network may deliver a datagram twice. A duplicated update is harmless, since the same
+//! value is skipped, but duplicated comparison segments would be compared and answered again.
+//! The digests of the last datagrams of each peer are kept, and the datagrams with the same
+//! digest as one of them are dropped.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hasher;
+use std::net::SocketAddr;
+
+/// Number of datagrams of each peer remembered by default
+pub(crate) const DEFAULT_DEDUP_WINDOW: usize = 128;
+
+pub(crate) struct Duplicates {
+    /// Number of datagrams remembered for each peer; 0 disables the detection
+    window: usize,
+    /// Digests of the last datagrams of each peer, from the oldest
+    peers: HashMap<SocketAddr, VecDeque<u64>>,
+}
+
+impl Duplicates {
+    pub fn new(window: usize) -> Self {
+        Duplicates {
+            window,
+            peers: HashMap::new(),
+        }
+    }
+
+    /// Record a datagram received from the peer.
+    ///
+    /// Return whether the same datagram is among the last ones received from the peer.
+    pub fn is_duplicate(&mut self, peer: SocketAddr, datagram: &[u8]) -> bool {
+        if self.window == 0 {
+            return false;
+        }
+        let mut hasher = DefaultHasher::new();
+        hasher.write(datagram);
+        let digest = hasher.finish();
+        let digests = self.peers.entry(peer).or_default();
+        if digests.contains(&digest) {
+            return true;
+        }
+        if digests.len() == self.window {
+            digests.pop_front();
+        }
+        digests.push_back(digest);
+        false
+    }
+
+    /// Forget the peers not in the list.
+    pub fn retain_peers(&mut self, peers: &[SocketAddr]) {
+        self.peers.retain(|peer, _| peers.contains(peer));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Duplicates;
+
+    #[test]
+    fn duplicates() {
+        let peer1 = "10.0.0.1:8080".parse().unwrap();
+        let peer2 = "10.0.0.2:8080".parse().unwrap();
+        let mut duplicates = Duplicates::new(2);
+        assert!(!duplicates.is_duplicate(peer1, b"a"));
+        assert!(duplicates.is_duplicate(peer1, b"a"));
+        // the windows of the peers are separate
+        assert!(!duplicates.is_duplicate(peer2, b"a"));
+        // only the last datagrams are remembered
+        assert!(!duplicates.is_duplicate(peer1, b"b"));
+        assert!(!duplicates.is_duplicate(peer1, b"c"));
+        assert!(!duplicates.is_duplicate(peer1, b"a"));
+        assert!(duplicates.is_duplicate(peer1, b"c"));
+        duplicates.retain_peers(&[peer2]);
+        assert!(!duplicates.is_duplicate(peer1, b"a"));
+        assert!(duplicates.is_duplicate(peer2, b"a"));
+
+        let mut disabled = Duplicates::new(0);
+        assert!(!disabled.is_duplicate(peer1, b"a"));
+        assert!(!disabled.is_duplicate(peer1, b"a"));
+    }
+}
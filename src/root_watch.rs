@@ -0,0 +1,129 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`RootWatch`], which alerts when a peer in contact never agrees on the global hash.
+//!
+//! Unlike the [`Divergences`](crate::divergence::Divergences), which follow ranges of keys, it
+//! only remembers when the maps last matched as a whole with each peer. Instances exchanging
+//! datagrams normally match again shortly after each change; two instances in contact that never
+//! match for a long time reveal a bug in the fingerprints, the order of the keys or the
+//! serialization, rather than a lag.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Called with the peer, and the time since the global hashes last matched with it
+pub(crate) type DivergenceAlert = Arc<dyn Send + Sync + Fn(SocketAddr, Duration)>;
+
+struct PeerWatch {
+    /// When the global hashes last matched, or when the peer was first in contact
+    since: Instant,
+    /// Whether the alert fired since then
+    alerted: bool,
+}
+
+pub(crate) struct RootWatch {
+    /// Delay without matching after which the alert fires, and the alert
+    alert: Option<(Duration, DivergenceAlert)>,
+    peers: HashMap<SocketAddr, PeerWatch>,
+}
+
+impl RootWatch {
+    pub fn new() -> Self {
+        RootWatch {
+            alert: None,
+            peers: HashMap::new(),
+        }
+    }
+
+    pub fn set_alert(&mut self, threshold: Duration, alert: DivergenceAlert) {
+        self.alert = Some((threshold, alert));
+    }
+
+    /// Record that the global hashes matched with the peer.
+    pub fn matched(&mut self, peer: SocketAddr) {
+        if self.alert.is_some() {
+            self.peers.insert(
+                peer,
+                PeerWatch {
+                    since: Instant::now(),
+                    alerted: false,
+                },
+            );
+        }
+    }
+
+    /// List the peers in contact that did not match for longer than the threshold, once until
+    /// they match again, along with the alert to call; the other peers are forgotten.
+    pub fn check(
+        &mut self,
+        peers: &[SocketAddr],
+    ) -> Option<(DivergenceAlert, Vec<(SocketAddr, Duration)>)> {
+        let (threshold, alert) = self.alert.as_ref()?;
+        self.peers.retain(|peer, _| peers.contains(peer));
+        let now = Instant::now();
+        let mut diverging = Vec::new();
+        for &peer in peers {
+            let watch = self.peers.entry(peer).or_insert(PeerWatch {
+                since: now,
+                alerted: false,
+            });
+            let elapsed = now - watch.since;
+            if !watch.alerted && elapsed >= *threshold {
+                watch.alerted = true;
+                diverging.push((peer, elapsed));
+            }
+        }
+        Some((alert.clone(), diverging))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use super::RootWatch;
+
+    #[test]
+    fn alerts_once() {
+        let peer: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let other: SocketAddr = "127.0.0.2:8080".parse().unwrap();
+        let diverging = |watch: &mut RootWatch, peers: &[SocketAddr]| {
+            let (_, diverging) = watch.check(peers).unwrap();
+            diverging
+                .into_iter()
+                .map(|(peer, _)| peer)
+                .collect::<Vec<_>>()
+        };
+        let mut watch = RootWatch::new();
+        assert!(watch.check(&[peer]).is_none());
+        watch.set_alert(Duration::from_millis(50), Arc::new(|_, _| ()));
+
+        // the peers are watched from the first contact
+        assert!(diverging(&mut watch, &[peer, other]).is_empty());
+        std::thread::sleep(Duration::from_millis(60));
+        watch.matched(other);
+        assert_eq!(diverging(&mut watch, &[peer, other]), vec![peer]);
+        assert!(diverging(&mut watch, &[peer, other]).is_empty());
+
+        // the alert fires again once the peer matched, and diverged for long again
+        watch.matched(peer);
+        std::thread::sleep(Duration::from_millis(60));
+        let mut peers = diverging(&mut watch, &[peer, other]);
+        peers.sort();
+        assert_eq!(peers, vec![peer, other]);
+
+        // a peer out of contact is watched again from the next contact
+        assert!(diverging(&mut watch, &[other]).is_empty());
+        assert!(diverging(&mut watch, &[peer, other]).is_empty());
+    }
+}
@@ -9,25 +9,117 @@
 //! Provides the [`Service`], a wrapper to a key-value map
 //! to enable reconciliation between different instances over a network.
 
+use std::borrow::{Borrow, Cow};
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
+use std::future::Future;
 use std::hash::Hash;
-use std::net::IpAddr;
-use std::time::{Duration, Instant};
+use std::net::{IpAddr, SocketAddr};
+use std::ops::{Bound, Deref, RangeBounds};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
 
 use chrono::{DateTime, Utc};
 use ipnet::IpNet;
-use parking_lot::{MappedRwLockReadGuard, RwLockReadGuard};
+use parking_lot::{MappedRwLockReadGuard, Mutex, RwLock, RwLockReadGuard};
 use serde::{de::DeserializeOwned, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::UdpSocket;
+use tokio::sync::{broadcast, watch};
+use tokio::task::{JoinError, JoinHandle};
+use tracing::warn;
 
-use crate::diff::Diffable;
-use crate::internal_service::InternalService;
+use crate::chunk::{ChunkHash, Chunked, ChunkedValue};
+use crate::clock::Clock;
+use crate::codec::CodecMap;
+use crate::debug::{self, DiffReport};
+use crate::diff::{DiffTuning, Diffable, FingerprintOf, HashRangeQueryable};
+use crate::discovery::Discovery;
+use crate::error::Error;
+use crate::hrtree::MergeStats;
+use crate::internal_service::{version_hash, InternalService};
 use crate::map::{Map, MutMap};
+use crate::metrics::ServiceMetrics;
+use crate::reconcilable::ConflictPolicy;
+use crate::snapshot;
 use crate::timeout_wheel::TimeoutWheel;
+use crate::transport::Transport;
+use crate::wal::Wal;
+
+pub use crate::broadcast::{BroadcastOverflow, BroadcastTicket};
+pub use crate::internal_service::{
+    ChangeOrigin, Convergence, DivergenceInfo, Health, MapSummary, Origin, PeerInfo, SyncProgress,
+};
+pub use crate::wal::WalSync;
 
 pub type MaybeTombstone<V> = Option<V>;
 pub type DatedMaybeTombstone<V> = (DateTime<Utc>, MaybeTombstone<V>);
 
 const TOMBSTONE_CLEARING: Duration = Duration::from_secs(1);
+const WAL_COMPACTION_CHECK: Duration = Duration::from_secs(10);
+/// Number of entries copied under the read lock at a time when saving a snapshot
+const SNAPSHOT_CHUNK: usize = 1000;
+/// Number of events held for the subscribers of the change feed; slower subscribers miss the
+/// oldest ones
+const CHANGE_FEED_CAPACITY: usize = 1024;
+
+type SharedWal<K, V> = Arc<Mutex<Option<Wal<K, V>>>>;
+
+/// Change to a key of the map, as reported by [`Service::subscribe`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Change<K> {
+    /// Key whose value changed
+    pub key: K,
+    /// Timestamp of the new value
+    pub timestamp: DateTime<Utc>,
+    /// Whether the key was removed
+    pub tombstone: bool,
+}
+
+/// Changes made to the map by a local write, or by a datagram received from a peer.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ChangeEvent<K, H> {
+    /// Changed keys, in the order they were applied
+    pub changes: Vec<Change<K>>,
+    /// Whether the changes were written locally or received from a peer
+    pub origin: ChangeOrigin,
+    /// Global hash of the map right after the changes; when events were missed, it differs from
+    /// the hash computed by applying the received events
+    pub hash: H,
+}
+
+/// Value returned by [`Service::get`]: it borrows the map while the read lock is held, or owns a
+/// copy when the map builds its values on the fly, as a [`SpillMap`](crate::spill::SpillMap).
+pub enum ValueRef<'a, V> {
+    Borrowed(MappedRwLockReadGuard<'a, V>),
+    Owned(V),
+}
+
+impl<V> Deref for ValueRef<'_, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        match self {
+            ValueRef::Borrowed(guard) => guard,
+            ValueRef::Owned(value) => value,
+        }
+    }
+}
+
+/// Changes made to the map by a batch, as reported to the callback of
+/// [`with_post_batch`](Service::with_post_batch).
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BatchSummary<H> {
+    /// Number of keys whose value changed
+    pub changed: usize,
+    /// Number of those keys that were removed
+    pub tombstones: usize,
+    /// Whether the changes were written locally or received from a peer
+    pub origin: ChangeOrigin,
+    /// Global hash of the map right after the batch
+    pub hash: H,
+}
 
 /// Wraps a key-value map to enable reconciliation between different instances over a network.
 ///
@@ -39,23 +131,36 @@ const TOMBSTONE_CLEARING: Duration = Duration::from_secs(1);
 ///
 /// Known peers can optionally be provided using the [`with_seed`](Service::with_seed) method. In
 /// any case, the service will periodically look for new peers by sampling a random address from
-/// the given peer network.
-pub struct Service<M: Map>
+/// the given peer network, or with another strategy given to
+/// [`with_discovery`](Service::with_discovery).
+///
+/// The state of the map can optionally be persisted to a write-ahead log using
+/// [`with_wal`](Service::with_wal), and restored with [`recover_from_wal`](Service::recover_from_wal).
+pub struct Service<M: Map + HashRangeQueryable>
 where
-    M::Key: Clone + Hash + std::cmp::Eq + Send + Sync,
+    <M as Map>::Key: Clone + Hash + std::cmp::Eq + Send + Sync,
 {
     service: InternalService<M>,
-    tombstones: TimeoutWheel<M::Key>,
+    tombstones: TimeoutWheel<<M as Map>::Key>,
+    wal: SharedWal<<M as Map>::Key, M::Value>,
+    pending_tombstones: Arc<Mutex<HashSet<<M as Map>::Key>>>,
+    /// How long the deletions are remembered, if not twice the tombstone timeout
+    deletion_horizon: Option<Duration>,
+    changes: broadcast::Sender<ChangeEvent<<M as Map>::Key, FingerprintOf<M>>>,
 }
 
-impl<M: Map> Clone for Service<M>
+impl<M: Map + HashRangeQueryable> Clone for Service<M>
 where
-    M::Key: Clone + Hash + std::cmp::Eq + Send + Sync,
+    <M as Map>::Key: Clone + Hash + std::cmp::Eq + Send + Sync,
 {
     fn clone(&self) -> Self {
         Service {
             service: self.service.clone(),
             tombstones: self.tombstones.clone(),
+            wal: self.wal.clone(),
+            pending_tombstones: self.pending_tombstones.clone(),
+            deletion_horizon: self.deletion_horizon,
+            changes: self.changes.clone(),
         }
     }
 }
@@ -63,29 +168,1022 @@ where
 impl<
         K: Clone + Debug + DeserializeOwned + Hash + Ord + Send + Serialize + Sync + 'static,
         V: Clone + DeserializeOwned + Hash + Send + Serialize + Sync + 'static,
-        C: Debug + DeserializeOwned + Send + Serialize + Sync + 'static,
-        D: Debug + 'static,
-        M: Map<Key = K, Value = DatedMaybeTombstone<V>, DifferenceItem = D>
+        C: Clone + Debug + DeserializeOwned + Send + Serialize + Sync + 'static,
+        D: Clone + Debug + 'static,
+        M: Map<Key = K, Value = V, DifferenceItem = D>
             + Diffable<ComparisonItem = C, DifferenceItem = D>
+            + HashRangeQueryable<Key = K>
             + Send
             + Sync
             + 'static,
     > Service<M>
 {
-    pub async fn new(map: M, port: u16, listen_addr: IpAddr, peer_net: IpNet) -> Self {
+    /// Create a service listening on the given address and port, whose conflicts are settled by
+    /// the given policy.
+    ///
+    /// Unlike with [`new`](Service::new), the values need not be dated: they may carry their own
+    /// versioning, such as a version vector, which the policy compares. Such values are written
+    /// with [`insert_value`](Service::insert_value) and read with
+    /// [`get_value`](Service::get_value); the keys are never removed, so the values must represent
+    /// a removal themselves if needed.
+    ///
+    /// For [`Reconcilable`](crate::reconcilable::Reconcilable) values, such as a
+    /// `(DateTime<Utc>, V)` pair, the [`LwwPolicy`](crate::LwwPolicy) keeps the most recent one
+    /// without any tombstone: the values are stored as is, and
+    /// [`remove`](Service::remove) and the other removals are not available.
+    ///
+    /// Fails if the socket cannot be bound.
+    pub async fn new_with_policy<P: ConflictPolicy<V> + 'static>(
+        map: M,
+        policy: P,
+        port: u16,
+        listen_addr: IpAddr,
+        peer_net: IpNet,
+    ) -> Result<Self, Error> {
+        let service =
+            InternalService::new_with_policy(map, port, listen_addr, peer_net, Arc::new(policy))
+                .await?;
+        Ok(Service::wrap(service))
+    }
+
+    /// Same as [`with_transport`](Service::with_transport), but the conflicts are settled by the
+    /// given policy, as with [`new_with_policy`](Service::new_with_policy).
+    pub fn with_transport_and_policy<T: Transport + 'static, P: ConflictPolicy<V> + 'static>(
+        map: M,
+        policy: P,
+        transport: T,
+        peer_net: IpNet,
+    ) -> Self {
+        let sockets: Vec<Box<dyn Transport>> = vec![Box::new(transport)];
+        Service::wrap(InternalService::with_sockets_and_policy(
+            map,
+            sockets,
+            peer_net,
+            Arc::new(policy),
+        ))
+    }
+
+    fn wrap(service: InternalService<M>) -> Self {
         Service {
-            service: InternalService::new(map, port, listen_addr, peer_net).await,
+            service,
             tombstones: TimeoutWheel::new(),
+            wal: Arc::new(Mutex::new(None)),
+            pending_tombstones: Arc::new(Mutex::new(HashSet::new())),
+            deletion_horizon: None,
+            changes: broadcast::channel(CHANGE_FEED_CAPACITY).0,
         }
-        .with_pre_insert(|_, _| {})
     }
 
-    /// Provides the address of a known peer to the service
+    /// Save all the entries of the map, tombstones included, to the file at the given path.
+    ///
+    /// The read lock is only held for a chunk of entries at a time, so the service keeps running
+    /// meanwhile; the changes made during the save may or may not be in the snapshot. The file can
+    /// be loaded with [`load_snapshot`](Service::load_snapshot).
+    pub fn save_snapshot<P: AsRef<Path>>(&self, path: P) -> std::io::Result<()> {
+        snapshot::save(&self.service.map, path, SNAPSHOT_CHUNK)
+    }
+
+    /// Stream all the entries of the map, tombstones included, to the writer, for instance a pipe
+    /// to another host, where an instance can load them with
+    /// [`bootstrap_from`](Service::bootstrap_from).
+    ///
+    /// As with [`save_snapshot`](Service::save_snapshot), the read lock is only held for a chunk
+    /// of entries at a time. Return the number of entries written.
+    pub async fn serve_snapshot<W: AsyncWrite + Unpin>(
+        &self,
+        mut writer: W,
+    ) -> std::io::Result<u64> {
+        snapshot::write_stream(&self.service.map, &mut writer, SNAPSHOT_CHUNK).await
+    }
+
+    /// Merge the entries streamed by [`serve_snapshot`](Service::serve_snapshot) on another
+    /// instance into the map, before running the service.
+    ///
+    /// The entries are merged as they are received, as with [`merge_bulk`](Service::merge_bulk),
+    /// keeping their timestamps and tombstones, but without sending them to the peers. Once the
+    /// service runs, the reconciliation with the source only exchanges the changes made during
+    /// the transfer. Return the number of entries read.
+    pub async fn bootstrap_from<R: AsyncRead + Unpin>(
+        &self,
+        mut reader: R,
+    ) -> std::io::Result<u64> {
+        let mut count = 0;
+        while let Some(batch) = snapshot::read_batch(&mut reader).await? {
+            count += batch.len() as u64;
+            self.service.just_merge_bulk(batch);
+        }
+        Ok(count)
+    }
+
+    /// Provides the address of a known peer to the service, which listens on the same port as
+    /// this instance
     ///
     /// This is optional, but reduces the time to connect to existing peers
     pub fn with_seed(self, peer: IpAddr) -> Self {
-        let now = Instant::now();
-        self.service.peers.write().insert(peer, now);
+        self.add_peer(peer);
+        self
+    }
+
+    /// Provides the address and port of a known peer to the service, like
+    /// [`with_seed`](Service::with_seed)
+    pub fn with_seed_addr(self, peer: SocketAddr) -> Self {
+        self.add_peer_addr(peer);
+        self
+    }
+
+    /// Find other instances with the given strategy, such as a [`StaticList`] of addresses or a
+    /// [`DnsName`], instead of probing random addresses of the peer network.
+    ///
+    /// [`StaticList`]: crate::discovery::StaticList
+    /// [`DnsName`]: crate::discovery::DnsName
+    pub fn with_discovery<T: Discovery + 'static>(mut self, discovery: T) -> Self {
+        self.service = self.service.with_discovery(discovery);
+        self
+    }
+
+    /// Set the delay after which a peer that sent nothing is forgotten.
+    /// The default value is 60 seconds.
+    pub fn with_peer_expiration(mut self, peer_expiration: Duration) -> Self {
+        self.service.peer_expiration = peer_expiration;
+        self
+    }
+
+    /// Set the delay without any datagram received after which reconciliation sessions are
+    /// started; sessions are also started at least this often when busy.
+    /// The default value is 1 second.
+    pub fn with_activity_timeout(mut self, activity_timeout: Duration) -> Self {
+        self.service.activity_timeout = activity_timeout;
+        self
+    }
+
+    /// Set the delay after which a reconciliation session is started again with a peer whose
+    /// global hash was found equal to the local one. The default value is 60 seconds.
+    ///
+    /// Until then, no session is started with the peer while the local map is unchanged; changes
+    /// made by the peer are found by the sessions it starts.
+    pub fn with_paranoia_interval(mut self, paranoia_interval: Duration) -> Self {
+        self.service.paranoia_interval = paranoia_interval;
+        self
+    }
+
+    /// Set the maximum number of peers the service reconciles with at the same time.
+    /// The default value is 8.
+    ///
+    /// New reconciliation sessions are started with the peers in turn, as the previous ones
+    /// complete.
+    pub fn with_max_concurrent_sessions(mut self, max_concurrent_sessions: usize) -> Self {
+        self.service.max_concurrent_sessions = max_concurrent_sessions;
+        self
+    }
+
+    /// Set the maximum duration of a reconciliation session started by the service.
+    /// The default value is 30 seconds.
+    ///
+    /// A session still running after this delay, for instance against a slow peer, is abandoned
+    /// and counted in the [`metrics`](Service::metrics); the next one starts over from the whole
+    /// key space.
+    pub fn with_session_deadline(mut self, session_deadline: Duration) -> Self {
+        self.service.session_deadline = session_deadline;
+        self
+    }
+
+    /// Set how the differing segments are answered during the reconciliation sessions.
+    /// By default, they are always split into 16 segments.
+    ///
+    /// With [`DiffTuning::adaptive`], the sessions where most elements differ, as after a long
+    /// downtime, complete in fewer round trips, and a fresh instance is sent all the elements at
+    /// once.
+    pub fn with_diff_tuning(mut self, diff_tuning: DiffTuning) -> Self {
+        self.service.diff_tuning = diff_tuning;
+        self
+    }
+
+    /// Hash the elements of the map in their serialized form, as with
+    /// [`HRTree::with_canonical_hashing`](crate::HRTree::with_canonical_hashing), hashing the
+    /// existing ones again.
+    ///
+    /// The elements written or received afterwards that cannot be serialized are dropped with a
+    /// warning, and counted in the `serialize_errors` of the [`metrics`](Service::metrics).
+    ///
+    /// The mode is neither recorded in the write-ahead log nor in the snapshots: a service
+    /// restored with [`recover_from_wal`](Service::recover_from_wal) or
+    /// [`load_snapshot`](Service::load_snapshot) uses the default mode until this is called
+    /// again, before [`run`](Service::run). The entries merged later, for instance by
+    /// [`bootstrap_from`](Service::bootstrap_from), are hashed in the mode of the map.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the map does not support it, see [`Map::set_canonical_hashing`].
+    pub fn with_canonical_hashing(self) -> Self {
+        let supported = self.service.map.write().set_canonical_hashing();
+        assert!(supported, "the map does not support canonical hashing");
+        self
+    }
+
+    /// Authenticate the datagrams with a key shared by all the instances of the cluster.
+    ///
+    /// A truncated HMAC-SHA256 of each datagram is appended to it; the datagrams received without
+    /// a valid one are dropped, and counted in the metrics. Instances with and without a key
+    /// cannot talk to each other.
+    pub fn with_auth_key(self, key: [u8; 32]) -> Self {
+        self.with_auth_keys(vec![key])
+    }
+
+    /// Same as [`with_auth_key`](Service::with_auth_key), but the datagrams authenticated with any
+    /// of the given keys are accepted, while the first key is used to send.
+    ///
+    /// This allows rotating the key: first add the new key after the current one on all the
+    /// instances, then move it first, then remove the old key.
+    pub fn with_auth_keys(mut self, keys: Vec<[u8; 32]>) -> Self {
+        self.service = self.service.with_auth_keys(keys);
+        self
+    }
+
+    /// Compress with zstd at the given level the datagrams sent that are larger than 1024 bytes,
+    /// when it makes them smaller. The datagrams are not compressed by default.
+    ///
+    /// Compressed datagrams are always accepted, so compression can be enabled on some instances
+    /// only; instances of earlier versions drop them, though.
+    pub fn with_compression(mut self, level: i32) -> Self {
+        self.service = self.service.with_compression(level);
+        self
+    }
+
+    /// Only compress the datagrams larger than the given number of bytes, when compression is
+    /// enabled with [`with_compression`](Service::with_compression).
+    pub fn with_compression_threshold(mut self, threshold: usize) -> Self {
+        self.service = self.service.with_compression_threshold(threshold);
+        self
+    }
+
+    /// Limit the outbound bandwidth to the given number of bytes per second.
+    /// The bandwidth is not limited by default.
+    ///
+    /// Sending faster than the peers can receive only makes them drop datagrams.
+    pub fn with_max_bandwidth(mut self, bytes_per_sec: u64) -> Self {
+        self.service = self.service.with_max_bandwidth(bytes_per_sec);
+        self
+    }
+
+    /// Limit the number of key-value pairs accepted from each peer to the given number per
+    /// second, so that a faulty or malicious peer cannot flood the local map. The number of
+    /// updates is not limited by default.
+    ///
+    /// The updates beyond the budget of a peer are dropped, and counted in the metrics; the
+    /// differences are found again by the next reconciliation sessions. The updates outside of
+    /// the range synchronized with the peer are always dropped, see
+    /// [`with_sync_range`](Self::with_sync_range).
+    pub fn with_peer_update_rate(mut self, updates_per_sec: u64) -> Self {
+        self.service = self.service.with_peer_update_rate(updates_per_sec);
+        self
+    }
+
+    /// Set the maximum number of key-value pairs received from a peer that are applied under a
+    /// single write lock on the map. The default is 1024.
+    ///
+    /// The lock is released between the chunks, so that large datagrams of updates do not stall
+    /// the local reads and writes.
+    pub fn with_update_chunk(mut self, update_chunk: usize) -> Self {
+        self.service.update_chunk = update_chunk.max(1);
+        self
+    }
+
+    /// Set the number of datagrams remembered for each peer to detect the ones the network
+    /// delivers twice. The default is 128, and 0 disables the detection.
+    ///
+    /// A datagram identical to one of the last ones received from the same peer is dropped, and
+    /// counted in the metrics, so that duplicated segments are not compared and answered twice.
+    pub fn with_dedup_window(mut self, window: usize) -> Self {
+        self.service = self.service.with_dedup_window(window);
+        self
+    }
+
+    /// Set the size of the largest datagram received. The default is 65507 bytes, the largest UDP
+    /// payload over IPv4; a larger buffer only helps with transports that deliver larger
+    /// datagrams, such as loopback interfaces or jumbo frames.
+    ///
+    /// The datagrams that do not fit are dropped. After a few of them in a row, the peer is asked
+    /// to send datagrams of at most this size, and splits its messages accordingly.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the size is below 1200 bytes.
+    pub fn with_recv_buffer_size(mut self, size: usize) -> Self {
+        self.service = self.service.with_recv_buffer_size(size);
+        self
+    }
+
+    /// Track where the value of each key comes from, as returned by
+    /// [`origin_of`](Self::origin_of). The origins are not tracked by default.
+    ///
+    /// The origin of each key is kept in memory until the key is removed from the map, once its
+    /// tombstone expires.
+    pub fn with_origin_tracking(mut self) -> Self {
+        self.service = self.service.with_origin_tracking();
+        self
+    }
+
+    /// Set the id identifying the types of the keys and values. The default is 0.
+    ///
+    /// It is sent to the peers on first contact, along with the protocol version and the
+    /// fingerprints supported; the peers whose id differ are marked as incompatible in
+    /// [`peers`](Self::peers), and never reconciled with. Change it whenever the serialization of
+    /// the keys or values changes.
+    pub fn with_schema_id(mut self, schema_id: u64) -> Self {
+        self.service.schema_id = schema_id;
+        self
+    }
+
+    /// Tag the updates sent with the [schema id](Service::with_schema_id), and reconcile with the
+    /// peers of other schema ids instead of marking them as incompatible, for instance during a
+    /// rolling upgrade that changes the values.
+    ///
+    /// The updates tagged with another schema id are skipped, rather than deserialized into the
+    /// local type; the other messages and the updates of the peers sharing the schema are kept, so
+    /// that the instances of each version keep converging among themselves. All the instances
+    /// must enable it, since older versions skip the tagged updates.
+    pub fn with_schema_tags(mut self) -> Self {
+        self.service.schema_tags = true;
+        self
+    }
+
+    /// Call `hook` with the sender, the schema id it tagged the message with if any, and the
+    /// bytes of each message received that could not be deserialized or was tagged with another
+    /// schema id, for instance to log samples of what is dropped.
+    ///
+    /// Such messages are skipped, and the rest of their datagram is kept; they are counted in the
+    /// [`metrics`](Service::metrics).
+    pub fn with_undecodable_hook<F: Send + Sync + Fn(SocketAddr, Option<u64>, &[u8]) + 'static>(
+        self,
+        hook: F,
+    ) -> Self {
+        *self.service.undecodable_hook.write() = Some(Box::new(hook));
+        self
+    }
+
+    /// Set the maximum number of local writes waiting to be sent to the peers, and which ones to
+    /// drop when there are more. The default is 10000 writes, dropping the oldest ones.
+    ///
+    /// Writes never wait for the queue; the dropped ones reach the peers at the next
+    /// reconciliation sessions instead.
+    pub fn with_broadcast_queue(mut self, capacity: usize, overflow: BroadcastOverflow) -> Self {
+        self.service = self.service.with_broadcast_queue(capacity, overflow);
+        self
+    }
+
+    /// Only synchronize the keys within the given range with the peer.
+    ///
+    /// The comparison items and updates the peer sends outside of this range are ignored.
+    pub fn with_sync_range(mut self, peer: SocketAddr, range: D) -> Self {
+        self.service = self.service.with_sync_range(peer, range);
+        self
+    }
+
+    /// Only synchronize the keys within the given range with the peers that were not given a
+    /// specific range with [`with_sync_range`](Service::with_sync_range).
+    /// All the keys are synchronized by default.
+    pub fn with_default_sync_range(mut self, range: D) -> Self {
+        self.service = self.service.with_default_sync_range(range);
+        self
+    }
+
+    /// Reconcile the keys within the given range with each peer before the other keys, for
+    /// instance so that the recent keys are caught up first after a restart.
+    ///
+    /// The sessions with a peer only compare this range until it matches the peer; all the keys
+    /// are compared afterwards.
+    pub fn with_priority_range(mut self, range: D) -> Self {
+        self.service = self.service.with_priority_range(range);
+        self
+    }
+
+    /// Set the source of the current time, used to expire tombstones, by
+    /// [`get_mut`](Service::get_mut) and [`insert_now`](Service::insert_now), and to estimate the
+    /// skew with the clocks of the peers. The default is the
+    /// [`MonotonicClock`](crate::MonotonicClock).
+    pub fn with_clock<T: Clock + 'static>(mut self, clock: T) -> Self {
+        self.service.clock = Arc::new(clock);
+        self
+    }
+
+    /// Log a warning when the estimated skew between the clock of a peer and the local one goes
+    /// beyond the threshold. The default is 1 second.
+    ///
+    /// The most recent value wins the conflicts, so an instance whose clock is ahead overwrites
+    /// the values of the others, even when they were written after, and one whose clock is behind
+    /// cannot overwrite the values it received.
+    pub fn with_clock_skew_warning(mut self, threshold: Duration) -> Self {
+        self.service = self.service.with_clock_skew_warning(threshold);
+        self
+    }
+
+    /// Set a callback called after each change to the map (local or received from peers), with
+    /// the key, the new value and the previous value, if any.
+    ///
+    /// Unlike [`with_pre_insert`](Service::with_pre_insert), the callback is called once the write
+    /// lock on the map has been released, so it can do slow work without blocking the
+    /// reconciliation. The changes of a bulk insertion are all applied before it is called.
+    pub fn with_post_insert<F: Send + Sync + Fn(&K, &M::Value, Option<&M::Value>) + 'static>(
+        self,
+        post_insert: F,
+    ) -> Self {
+        *self.service.post_insert.write() = Some(Box::new(post_insert));
+        self
+    }
+
+    /// Subscribe to notifications of convergence with peers.
+    ///
+    /// The channel is updated each time a diff round initiated by a peer finds no difference
+    /// with the local map. It holds `None` until the first convergence.
+    pub fn subscribe_convergence(&self) -> watch::Receiver<Option<Convergence<FingerprintOf<M>>>> {
+        self.service.subscribe_convergence()
+    }
+
+    /// List the known peers, with the time since they were last heard from.
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.service.peer_infos()
+    }
+
+    /// Progress of the reconciliation with the known peers, for instance to follow the initial
+    /// synchronization of a new instance.
+    ///
+    /// The number of elements of the peers is only known once one of them opens a session over
+    /// all the keys; the local map has caught up when its size reaches it and no range differs.
+    pub fn sync_progress(&self) -> SyncProgress {
+        self.service.sync_progress()
+    }
+
+    /// Ranges of keys that kept differing with the known peers, with the time since they were
+    /// first found to differ, for instance to alert when the instances cannot converge.
+    ///
+    /// A range is listed from the diff round that finds it different, and until a later
+    /// comparison covering it matches; the age of the oldest one is also reported by the
+    /// [`metrics`](Service::metrics).
+    pub fn divergences(&self) -> Vec<DivergenceInfo<D>> {
+        self.service.divergences()
+    }
+
+    /// Call `alert` with the peer, and the time since the global hashes last matched, when a peer
+    /// in contact has not agreed on the global hash for longer than `threshold`.
+    ///
+    /// Instances exchanging datagrams normally match shortly after each change, so this reveals
+    /// a bug in the fingerprints, the order of the keys or their serialization, rather than a lag;
+    /// `threshold` should be well above the time to synchronize the whole map. The alert fires
+    /// once, until the peer matches again or is forgotten. Unlike [`divergences`](Service::divergences),
+    /// it only compares the whole maps, and costs nothing per range.
+    pub fn with_divergence_alert<F: Send + Sync + Fn(SocketAddr, Duration) + 'static>(
+        self,
+        threshold: Duration,
+        alert: F,
+    ) -> Self {
+        self.service
+            .root_watch
+            .write()
+            .set_alert(threshold, Arc::new(alert));
+        self
+    }
+
+    /// Estimated offset between the local clock and the clock of each peer, from the time the
+    /// sessions it opens are sent and received; positive when the clock of the peer is behind the
+    /// local one.
+    ///
+    /// The offset includes the network latency, and is averaged over the sessions.
+    pub fn peer_clock_offsets(&self) -> HashMap<SocketAddr, chrono::Duration> {
+        self.service.peer_clock_offsets()
+    }
+
+    /// Provides the address of a peer to the service, like [`with_seed`](Service::with_seed).
+    ///
+    /// Banned peers are not added.
+    pub fn add_peer(&self, peer: IpAddr) {
+        self.service.add_peer(self.service.default_peer_addr(peer));
+    }
+
+    /// Provides the address and port of a peer to the service, like
+    /// [`with_seed_addr`](Service::with_seed_addr).
+    ///
+    /// Banned peers are not added.
+    pub fn add_peer_addr(&self, peer: SocketAddr) {
+        self.service.add_peer(peer);
+    }
+
+    /// Forget a known peer, until it sends a datagram again or is advertised by another peer.
+    ///
+    /// Expired tombstones no longer wait for its acknowledgement; remove the peers that left for
+    /// good, since the ones that merely expired keep the tombstones in the map until they are back.
+    ///
+    /// Return whether the peer was known.
+    pub fn remove_peer(&self, peer: SocketAddr) -> bool {
+        self.service.remove_peer(peer)
+    }
+
+    /// Forget the known peers at the given IP address, whatever their port, and ignore the
+    /// datagrams they send for the given duration.
+    ///
+    /// In particular, the updates they send are not applied, and other peers advertising them are
+    /// ignored.
+    pub fn ban_peer(&self, peer: IpAddr, duration: Duration) {
+        self.service.ban_peer(peer, duration);
+    }
+
+    /// Stop synchronizing with the peers, for instance during a maintenance, until
+    /// [`resume_sync`](Service::resume_sync) is called.
+    ///
+    /// The service keeps serving local reads and writes, but does not start reconciliation
+    /// sessions, and drops the comparison segments and updates received from the peers. The local
+    /// writes are held in the broadcast queue, and sent as soon as the synchronization resumes;
+    /// when it overflows (see [`with_broadcast_queue`](Service::with_broadcast_queue)), the
+    /// dropped writes reach the peers through the reconciliation sessions instead.
+    pub fn pause_sync(&self) {
+        self.service.pause_sync();
+    }
+
+    /// Resume the synchronization with the peers after [`pause_sync`](Service::pause_sync).
+    pub fn resume_sync(&self) {
+        self.service.resume_sync();
+    }
+
+    /// Whether the synchronization with the peers is paused.
+    pub fn is_paused(&self) -> bool {
+        self.service.is_paused()
+    }
+
+    /// Error of the last datagram the service could not send, unless it sent one successfully
+    /// since.
+    ///
+    /// The service keeps running when sending fails, and the peers get the differences at the
+    /// next reconciliation sessions; an error that persists means the service cannot reach its
+    /// peers anymore, for instance when the network is unreachable.
+    pub fn last_error(&self) -> Option<Arc<Error>> {
+        self.service.metrics.last_error()
+    }
+
+    /// Conditions that keep the service from converging with its peers.
+    ///
+    /// A peer whose datagrams keep being too large for the receive buffer is listed until one of
+    /// its datagrams fits; it is asked for smaller datagrams after a few of them in a row, see
+    /// [`with_recv_buffer_size`](Self::with_recv_buffer_size).
+    pub fn health(&self) -> Health {
+        self.service.health()
+    }
+
+    /// Counters describing the network activity of the service.
+    pub fn metrics(&self) -> &ServiceMetrics {
+        &self.service.metrics
+    }
+
+    /// Render the metrics of the service in the Prometheus text exposition format, to be served
+    /// by an HTTP handler of the application.
+    ///
+    /// Besides the counters of the [`metrics`](Service::metrics), this reports the number of
+    /// peers, elements and tombstones, the hash of the whole map as the `root_hash` label of
+    /// `reconcile_info`, and series labeled by the address of each peer. All the metric names are
+    /// prefixed by `reconcile_`.
+    #[cfg(feature = "metrics-prometheus")]
+    pub fn render_prometheus(&self) -> String {
+        let summary = self.summary();
+        let state = crate::prometheus::ServiceState {
+            map_size: summary.len,
+            tombstones: self.tombstones.len(),
+            pending_tombstones: self.pending_tombstones.lock().len(),
+            root_hash: summary.root_hash.to_string(),
+            last_change: summary.last_change,
+            peers: self.peers(),
+        };
+        crate::prometheus::render(&self.service.metrics.snapshot(), &state)
+    }
+
+    /// Hash and size of the map, and time of its last change, read at the same instant.
+    ///
+    /// Unlike reading the hash and the size with two calls to [`read`](Self::read), this never
+    /// observes a pair torn by a concurrent write, so it can be compared with the summaries of the
+    /// other instances to check their consistency.
+    pub fn summary(&self) -> MapSummary<FingerprintOf<M>> {
+        self.service.summary()
+    }
+
+    /// Whether the current value of the key was written locally or received from a peer, and
+    /// when it was stored.
+    ///
+    /// Return `None` unless [`with_origin_tracking`](Self::with_origin_tracking) was set, or if
+    /// the key is not in the map. The values loaded with the map, when the service is created,
+    /// have no origin.
+    pub fn origin_of(&self, key: &K) -> Option<Origin> {
+        self.service.origin_of(key)
+    }
+
+    /// Direct read access to the underlying map.
+    pub fn read(&self) -> RwLockReadGuard<'_, M> {
+        self.service.map.read()
+    }
+
+    /// Hash of the entry with the given key, tombstones included, as stored in the map.
+    ///
+    /// The hash changes exactly when the value does, so comparing it to a previous one tells
+    /// whether the entry changed, without getting nor hashing the value.
+    pub fn entry_hash<Q: Ord + ?Sized>(&self, k: &Q) -> Option<FingerprintOf<M>>
+    where
+        K: Borrow<Q>,
+    {
+        self.service.map.read().hash_of(k)
+    }
+
+    pub async fn start_reconciliation(&self) {
+        let mut buf = Vec::new();
+        self.service.start_reconciliation(&mut buf).await;
+    }
+
+    /// Number of tombstones in the map that have not expired yet.
+    pub fn tombstone_count(&self) -> usize {
+        self.tombstones.len()
+    }
+
+    /// Expired tombstones that are waiting for the acknowledgement of all the peers heard from
+    /// before being removed from the map, including the expired ones, unless they were
+    /// [removed](Service::remove_peer).
+    pub fn pending_tombstones(&self) -> Vec<K> {
+        self.pending_tombstones.lock().iter().cloned().collect()
+    }
+
+    /// Remove the expired tombstones from the map, even if some peers did not acknowledge them.
+    ///
+    /// A peer that still holds an older value for one of these keys will resurrect it.
+    pub fn force_clear_tombstones(&self) {
+        let mut guard = self.service.map.write();
+        for key in self.pending_tombstones.lock().drain() {
+            if let Some(value) = guard.remove(&key) {
+                self.service.record_change();
+                self.service.forget_origin(&key);
+                self.service.collect(&key, version_hash(&key, &value));
+            }
+        }
+    }
+
+    /// Acknowledge the expired tombstones to the peers, and remove the ones that all the peers
+    /// have acknowledged.
+    ///
+    /// Otherwise, a peer that was partitioned during the removal would send back its older value
+    /// once the tombstone is gone.
+    async fn clear_acknowledged_tombstones(&self) {
+        let acks: Vec<_> = {
+            let guard = self.service.map.read();
+            let pending = self.pending_tombstones.lock();
+            pending
+                .iter()
+                .filter_map(|key| {
+                    guard
+                        .get(key)
+                        .map(|value| (key.clone(), version_hash(key, &value)))
+                })
+                .collect()
+        };
+        if acks.is_empty() {
+            return;
+        }
+        self.service.send_acks(&acks).await;
+        let mut guard = self.service.map.write();
+        let mut pending = self.pending_tombstones.lock();
+        for (key, hash) in acks {
+            if pending.contains(&key) && self.service.is_acknowledged(&key, hash) {
+                guard.remove(&key);
+                self.service.record_change();
+                self.service.forget_origin(&key);
+                pending.remove(&key);
+                self.service.collect(&key, hash);
+            }
+        }
+    }
+
+    async fn clear_expired_tombstones(&self, mut shutdown: watch::Receiver<bool>) {
+        loop {
+            {
+                let mut pending = self.pending_tombstones.lock();
+                while let Some(key) = self.tombstones.pop_expired(self.service.clock.now()) {
+                    pending.insert(key);
+                }
+            }
+            let horizon = self
+                .deletion_horizon
+                .unwrap_or(2 * self.tombstones.timeout());
+            self.service.deletions.write().prune(
+                self.service.clock.now() - horizon,
+                &self.service.get_peers(),
+            );
+            self.clear_acknowledged_tombstones().await;
+            tokio::select! {
+                () = tokio::time::sleep(TOMBSTONE_CLEARING) => {}
+                _ = shutdown.wait_for(|&stop| stop) => break,
+            }
+        }
+    }
+
+    async fn compact_wal(&self, mut shutdown: watch::Receiver<bool>) {
+        loop {
+            tokio::select! {
+                () = tokio::time::sleep(WAL_COMPACTION_CHECK) => {}
+                _ = shutdown.wait_for(|&stop| stop) => break,
+            }
+            self.compact_wal_if_needed();
+        }
+    }
+
+    /// Rewrite the write-ahead log with the current entries of the map, if it grew enough.
+    fn compact_wal_if_needed(&self) {
+        let guard = self.service.map.read();
+        let mut wal = self.wal.lock();
+        if let Some(wal) = wal.as_mut().filter(|wal| wal.needs_compaction()) {
+            // keep the recent deletions whose tombstones were removed from the map
+            let mut deletions = self.service.deletions.read().tombstones();
+            deletions.retain(|(key, _)| guard.get(key).is_none());
+            let deletions = deletions
+                .iter()
+                .map(|(key, tombstone)| (key, Cow::Borrowed(tombstone)));
+            if let Err(err) = wal.compact(guard.iter_entries().chain(deletions)) {
+                warn!("failed to compact the write-ahead log: {err}");
+            }
+        }
+    }
+
+    /// Run the service until it is dropped.
+    ///
+    /// The datagrams received since the service was created, when it was created within a tokio
+    /// runtime, are handled first: the map can be filled before running the service without
+    /// missing the updates of the peers meanwhile.
+    pub async fn run(self) {
+        self.run_with_shutdown(std::future::pending()).await;
+    }
+
+    /// Run the service until `signal` completes, then shut it down gracefully.
+    ///
+    /// No new diff round is started, but the datagram being handled is processed completely.
+    /// The recent local writes are then pushed one last time to all the peers, before returning.
+    pub async fn run_with_shutdown<F: Future<Output = ()>>(self, signal: F) {
+        let (sender, receiver) = watch::channel(false);
+        let mut stopped = receiver.clone();
+        let clone1 = self.clone();
+        let clone2 = self.clone();
+        tokio::join!(
+            async move {
+                signal.await;
+                sender.send_replace(true);
+            },
+            self.service.run_until(async move {
+                let _ = stopped.wait_for(|&stop| stop).await;
+            }),
+            clone1.clear_expired_tombstones(receiver.clone()),
+            clone2.compact_wal(receiver),
+        );
+    }
+
+    /// Run the service in a new task, and return a handle to shut it down gracefully.
+    ///
+    /// Dropping the handle detaches the task, which then keeps running.
+    pub fn spawn(self) -> ServiceHandle
+    where
+        D: Send + Sync,
+    {
+        let (sender, mut receiver) = watch::channel(false);
+        let task = tokio::spawn(self.run_with_shutdown(async move {
+            if receiver.wait_for(|&stop| stop).await.is_err() {
+                // the handle was dropped
+                std::future::pending::<()>().await;
+            }
+        }));
+        ServiceHandle { sender, task }
+    }
+    /// Get the value stored at the given key, as is.
+    ///
+    /// The key may be any borrowed form of `K`, as with [`Map::get`].
+    pub fn get_value<Q: Ord + ?Sized>(&self, k: &Q) -> Option<ValueRef<'_, V>>
+    where
+        K: Borrow<Q>,
+    {
+        let guard = self.service.map.read();
+        let mut owned = None;
+        let borrowed = RwLockReadGuard::try_map(guard, |map: &M| match map.get(k)? {
+            Cow::Borrowed(value) => Some(value),
+            Cow::Owned(value) => {
+                owned = Some(value);
+                None
+            }
+        });
+        match borrowed {
+            Ok(guard) => Some(ValueRef::Borrowed(guard)),
+            Err(_) => owned.map(ValueRef::Owned),
+        }
+    }
+
+    /// Store the value at the given key as is, and send it to the peers.
+    ///
+    /// The local value is replaced whatever the policy, which only settles the conflicts with the
+    /// values of the peers; see [`update_value`](Service::update_value) to derive the new value
+    /// from the current one. Return the previous value.
+    pub fn insert_value(&self, key: K, value: V) -> Option<V> {
+        self.service.insert(key, value)
+    }
+
+    /// Store the values as is like [`insert_value`](Service::insert_value), and send them to the
+    /// peers in shared datagrams.
+    ///
+    /// Return a ticket completing once the values were sent, as with
+    /// [`insert_bulk`](Service::insert_bulk).
+    pub fn insert_bulk_values(&self, key_values: &[(K, V)]) -> BroadcastTicket {
+        self.service.insert_bulk(key_values)
+    }
+
+    /// Replace the value at the given key with the result of the closure, atomically, and send it
+    /// to the peers.
+    ///
+    /// The closure is given the current value, if any, and returns the new value; `None` leaves
+    /// the map unchanged.
+    pub fn update_value<F: FnOnce(Option<&V>) -> Option<V>>(&self, key: K, f: F) {
+        self.service.update(key, f);
+    }
+}
+
+impl<
+        K: Clone + Debug + DeserializeOwned + Hash + Ord + Send + Serialize + Sync + 'static,
+        V: Clone + DeserializeOwned + Hash + Send + Serialize + Sync + 'static,
+        C: Clone + Debug + DeserializeOwned + Send + Serialize + Sync + 'static,
+        D: Clone + Debug + 'static,
+        M: Map<Key = K, Value = DatedMaybeTombstone<V>, DifferenceItem = D>
+            + Diffable<ComparisonItem = C, DifferenceItem = D>
+            + HashRangeQueryable<Key = K>
+            + Send
+            + Sync
+            + 'static,
+    > Service<M>
+{
+    /// Create a service listening on the given address and port.
+    ///
+    /// The most recent value wins the conflicts, as with
+    /// [`new_with_policy`](Service::new_with_policy) and the [`LwwPolicy`](crate::LwwPolicy); removals are
+    /// synchronized with tombstones.
+    ///
+    /// Fails if the socket cannot be bound.
+    pub async fn new(
+        map: M,
+        port: u16,
+        listen_addr: IpAddr,
+        peer_net: IpNet,
+    ) -> Result<Self, Error> {
+        let service = InternalService::new(map, port, listen_addr, peer_net).await?;
+        Ok(Service::from_internal(service))
+    }
+
+    /// Create a service over an already-bound socket (e.g. inherited from socket activation, or
+    /// bound with specific options).
+    ///
+    /// Peers found by probing the peer network, or given without a port, are expected to listen
+    /// on the same port as the socket.
+    pub fn with_socket(map: M, socket: UdpSocket, peer_net: IpNet) -> Self {
+        Service::with_transport(map, socket, peer_net)
+    }
+
+    /// Create a service over another [`Transport`] than a UDP socket, such as a
+    /// [`SimSocket`](crate::sim::SimSocket) to simulate a network in tests.
+    ///
+    /// Peers found by probing the peer network, or given without a port, are expected to listen
+    /// on the same port as the transport.
+    pub fn with_transport<T: Transport + 'static>(map: M, transport: T, peer_net: IpNet) -> Self {
+        let sockets: Vec<Box<dyn Transport>> = vec![Box::new(transport)];
+        Service::from_internal(InternalService::with_sockets(map, sockets, peer_net))
+    }
+
+    /// Create a service over two already-bound sockets of different address families, typically
+    /// to listen on both an IPv4 and an IPv6 address.
+    ///
+    /// Each peer is contacted using the socket of its address family.
+    pub fn with_sockets(map: M, socket1: UdpSocket, socket2: UdpSocket, peer_net: IpNet) -> Self {
+        let sockets: Vec<Box<dyn Transport>> = vec![Box::new(socket1), Box::new(socket2)];
+        Service::from_internal(InternalService::with_sockets(map, sockets, peer_net))
+    }
+
+    fn from_internal(service: InternalService<M>) -> Self {
+        // the pre-insertion callback tracks the tombstones, and appends to the write-ahead log
+        Service::wrap(service).with_pre_insert(|_, _, _| {})
+    }
+
+    /// Create a service whose map is restored from the write-ahead log at the given path.
+    ///
+    /// The log is replayed in an empty map, then kept open to record new changes, as with
+    /// [`with_wal`](Service::with_wal). If the last record of the log is incomplete, it is
+    /// truncated.
+    ///
+    /// The map uses the default hashing mode, see
+    /// [`with_canonical_hashing`](Service::with_canonical_hashing).
+    pub async fn recover_from_wal<P: AsRef<Path>>(
+        path: P,
+        port: u16,
+        listen_addr: IpAddr,
+        peer_net: IpNet,
+    ) -> Result<Self, Error>
+    where
+        M: Default,
+    {
+        let records = Wal::replay(path.as_ref())?;
+        let service = Service::new(M::default(), port, listen_addr, peer_net).await?;
+        service.service.just_insert_bulk(&records);
+        Ok(service.with_wal(path)?)
+    }
+
+    /// Create a service whose map is loaded from a snapshot written by
+    /// [`save_snapshot`](Service::save_snapshot).
+    ///
+    /// The map is deserialized directly, without inserting the entries one by one.
+    /// As with [`recover_from_wal`](Service::recover_from_wal), the map uses the default hashing
+    /// mode.
+    pub async fn load_snapshot<P: AsRef<Path>>(
+        path: P,
+        port: u16,
+        listen_addr: IpAddr,
+        peer_net: IpNet,
+    ) -> Result<Self, Error>
+    where
+        M: DeserializeOwned,
+    {
+        let map: M = snapshot::load(path)?;
+        let tombstones: Vec<_> = map
+            .iter_entries()
+            .filter(|(_, value)| value.1.is_none())
+            .map(|(key, value)| (key.clone(), value.0))
+            .collect();
+        let service = Service::new(map, port, listen_addr, peer_net).await?;
+        for (key, timestamp) in tombstones {
+            service.tombstones.insert(key.clone(), timestamp);
+            let tombstone = (timestamp, None);
+            service
+                .service
+                .deletions
+                .write()
+                .record(key, timestamp, tombstone);
+        }
+        Ok(service)
+    }
+
+    /// Record every change to the map (local or received from peers)
+    /// in the write-ahead log at the given path.
+    ///
+    /// New records are appended to the file if it already exists. Use
+    /// [`recover_from_wal`](Service::recover_from_wal) to restore the state from the log.
+    ///
+    /// The records of each batch of changes are written once the write lock on the map is
+    /// released, so a write returns before it is durable; see
+    /// [`with_wal_sync`](Service::with_wal_sync) and [`sync_wal`](Service::sync_wal).
+    pub fn with_wal<P: AsRef<Path>>(self, path: P) -> std::io::Result<Self> {
+        *self.wal.lock() = Some(Wal::open(path)?);
+        let wal = self.wal.clone();
+        let metrics = self.service.metrics.clone();
+        *self.service.flush_batch.write() = Some(Box::new(move || {
+            if let Some(wal) = wal.lock().as_mut() {
+                if let Err(err) = wal.flush() {
+                    ServiceMetrics::add(&metrics.wal_errors, 1);
+                    wal.record_error(err);
+                }
+            }
+        }));
+        Ok(self)
+    }
+
+    /// Set when the records of the write-ahead log are flushed to the disk. By default, they are
+    /// written to the file after each batch of changes, and left to the operating system.
+    ///
+    /// Has no effect if [`with_wal`](Service::with_wal) was not called before.
+    pub fn with_wal_sync(self, sync: WalSync) -> Self {
+        {
+            let mut guard = self.wal.lock();
+            if let Some(wal) = guard.take() {
+                *guard = Some(wal.with_sync(sync));
+            }
+        }
+        self
+    }
+
+    /// Write the pending records of the write-ahead log and flush it to the disk, so that the
+    /// changes made so far survive a crash.
+    ///
+    /// Fails with the first error met by the log since the last call, if any: the changes made
+    /// since then may be missing from it. The errors are also counted in
+    /// [`wal_errors`](crate::MetricsSnapshot::wal_errors). Does nothing without
+    /// [`with_wal`](Service::with_wal).
+    pub fn sync_wal(&self) -> Result<(), Error> {
+        match self.wal.lock().as_mut() {
+            Some(wal) => wal.sync().map_err(Error::Io),
+            None => Ok(()),
+        }
+    }
+
+    /// Set the size in bytes above which the write-ahead log is rewritten from the current
+    /// content of the map. The default value is 64 MiB.
+    ///
+    /// Has no effect if [`with_wal`](Service::with_wal) was not called before.
+    pub fn with_wal_compaction_threshold(self, compaction_threshold: u64) -> Self {
+        {
+            let mut guard = self.wal.lock();
+            if let Some(wal) = guard.take() {
+                *guard = Some(wal.with_compaction_threshold(compaction_threshold));
+            }
+        }
         self
     }
 
@@ -96,31 +1194,274 @@ impl<
         self
     }
 
-    pub fn with_pre_insert<F: Send + Sync + Fn(&M::Key, &M::Value) + 'static>(
-        self,
-        pre_insert: F,
-    ) -> Self {
-        let tombstones = self.tombstones.clone();
-        let wrapped_pre_insert = move |k: &K, v: &(DateTime<Utc>, Option<V>)| {
-            pre_insert(k, v);
-            if v.1.is_some() {
-                tombstones.remove(k);
-            } else {
-                tombstones.insert(k.clone(), v.0);
-            }
-        };
-        *self.service.pre_insert.write() = Box::new(wrapped_pre_insert);
-        self
+    /// Set how long the deletions are remembered after their tombstones are removed from the map;
+    /// the default is twice the [tombstone timeout](Service::with_tombstone_timeout).
+    ///
+    /// The recent deletions are sent to each peer as soon as it is heard from, and the older
+    /// values received for the same keys are rejected, so that a peer that was partitioned during
+    /// the deletion does not insert its values back. With [`with_wal`](Service::with_wal), they
+    /// are written as tombstones when the log is compacted, and thus loaded back as such.
+    pub fn with_deletion_horizon(mut self, horizon: Duration) -> Self {
+        self.deletion_horizon = Some(horizon);
+        self
+    }
+
+    /// Change the expiry timeout of the tombstones while the service runs, for instance to keep
+    /// them while a peer is down for maintenance.
+    ///
+    /// The timeout applies to the tombstones already in the map, counted from their timestamps,
+    /// except the ones removed with their own timeout by [`remove_with_ttl`](Service::remove_with_ttl).
+    pub fn set_tombstone_timeout(&self, tombstone_timeout: Duration) {
+        self.tombstones.set_timeout(tombstone_timeout);
+    }
+
+    /// Discard the values received from peers whose timestamp is more than `max_skew` ahead of
+    /// the local clock, so that an instance whose clock is far ahead cannot win all the conflicts.
+    /// Values are accepted whatever their timestamp by default.
+    ///
+    /// The discarded values are counted in
+    /// [`future_timestamps_rejected`](crate::MetricsSnapshot::future_timestamps_rejected). They
+    /// are not clamped to the local time, which would make the instances disagree on their
+    /// hash; the peers keep sending them until they are no longer too far in the future.
+    pub fn with_max_future_timestamp_skew(self, max_skew: Duration) -> Self {
+        let clock = self.service.clock.clone();
+        let metrics = self.service.metrics.clone();
+        let max_skew = chrono::Duration::from_std(max_skew).unwrap_or(chrono::TimeDelta::MAX);
+        let filter = move |_: &K, (timestamp, _): &M::Value| {
+            let accepted = clock
+                .now()
+                .checked_add_signed(max_skew)
+                .is_none_or(|limit| *timestamp <= limit);
+            if !accepted {
+                ServiceMetrics::add(&metrics.future_timestamps_rejected, 1);
+            }
+            accepted
+        };
+        *self.service.update_filter.write() = Some(Box::new(filter));
+        self
+    }
+
+    /// Set a callback called before each change to the map (local or received from peers), with
+    /// the key, the new value and the previous value, if any.
+    ///
+    /// The callback is called while holding the write lock on the map, so it should be quick; see
+    /// [`with_post_insert`](Service::with_post_insert) otherwise.
+    pub fn with_pre_insert<F: Send + Sync + Fn(&K, &M::Value, Option<&M::Value>) + 'static>(
+        self,
+        pre_insert: F,
+    ) -> Self {
+        self.with_pre_insert_origin(move |k, v, old_v, _| pre_insert(k, v, old_v))
+    }
+
+    /// Set a callback called before each change to the map like
+    /// [`with_pre_insert`](Service::with_pre_insert), with the origin of the change as well.
+    pub fn with_pre_insert_origin<
+        F: Send + Sync + Fn(&K, &M::Value, Option<&M::Value>, ChangeOrigin) + 'static,
+    >(
+        self,
+        pre_insert: F,
+    ) -> Self {
+        let tombstones = self.tombstones.clone();
+        let wal = self.wal.clone();
+        let metrics = self.service.metrics.clone();
+        let pending_tombstones = self.pending_tombstones.clone();
+        let deletions = self.service.deletions.clone();
+        let wrapped_pre_insert =
+            move |k: &K, v: &M::Value, old_v: Option<&M::Value>, origin: ChangeOrigin| {
+                pre_insert(k, v, old_v, origin);
+                pending_tombstones.lock().remove(k);
+                if v.1.is_some() {
+                    tombstones.remove(k);
+                    deletions.write().forget(k);
+                } else {
+                    tombstones.insert(k.clone(), v.0);
+                    deletions.write().record(k.clone(), v.0, v.clone());
+                }
+                // NOTE: the record is written once the write lock is released
+                if let Some(wal) = wal.lock().as_mut() {
+                    if let Err(err) = wal.stage(k, v) {
+                        ServiceMetrics::add(&metrics.wal_errors, 1);
+                        wal.record_error(err);
+                    }
+                }
+            };
+        *self.service.pre_insert.write() = Box::new(wrapped_pre_insert);
+        self
+    }
+
+    /// Set a callback called once after each batch of changes to the map, with the service and a
+    /// summary of the batch.
+    ///
+    /// A batch is the updates of a datagram received from a peer, a bulk operation such as
+    /// [`insert_bulk`](Service::insert_bulk) or [`remove_bulk`](Service::remove_bulk), or a single
+    /// local write. The callback is called once the write lock on the map has been released, so it
+    /// can read the map, for instance to persist a checkpoint or recompute aggregates once per
+    /// batch rather than once per key as with [`with_post_insert`](Service::with_post_insert).
+    pub fn with_post_batch<
+        F: Send + Sync + Fn(&Service<M>, BatchSummary<FingerprintOf<M>>) + 'static,
+    >(
+        self,
+        post_batch: F,
+    ) -> Self {
+        // the callback is stored in the internal service, so it rebuilds the service around the
+        // one it is given rather than holding a clone of it
+        let tombstones = self.tombstones.clone();
+        let wal = self.wal.clone();
+        let pending_tombstones = self.pending_tombstones.clone();
+        let deletion_horizon = self.deletion_horizon;
+        let changes = self.changes.clone();
+        *self.service.post_batch.write() =
+            Some(Box::new(move |service, inserted, origin, hash| {
+                let service = Service {
+                    service: service.clone(),
+                    tombstones: tombstones.clone(),
+                    wal: wal.clone(),
+                    pending_tombstones: pending_tombstones.clone(),
+                    deletion_horizon,
+                    changes: changes.clone(),
+                };
+                let summary = BatchSummary {
+                    changed: inserted.len(),
+                    tombstones: inserted
+                        .iter()
+                        .filter(|(_, (_, value), _)| value.is_none())
+                        .count(),
+                    origin,
+                    hash,
+                };
+                post_batch(&service, summary);
+            }));
+        self
+    }
+
+    /// Subscribe to the changes of the map, whether written locally or received from peers.
+    ///
+    /// An event is sent after each local write, or each datagram from a peer that changed the map,
+    /// once the write lock has been released. A subscriber that falls behind misses the oldest
+    /// events, and gets [`RecvError::Lagged`](broadcast::error::RecvError::Lagged); the hash
+    /// carried by each event also reveals missed events, after which the subscriber can resync
+    /// using [`snapshot_range`](Service::snapshot_range).
+    pub fn subscribe(&self) -> broadcast::Receiver<ChangeEvent<K, FingerprintOf<M>>> {
+        let mut on_changes = self.service.on_changes.write();
+        if on_changes.is_none() {
+            let sender = self.changes.clone();
+            *on_changes = Some(Box::new(move |inserted, origin, hash| {
+                let changes = inserted
+                    .iter()
+                    .map(|(key, (timestamp, value), _)| Change {
+                        key: key.clone(),
+                        timestamp: *timestamp,
+                        tombstone: value.is_none(),
+                    })
+                    .collect();
+                // fails only when there is no subscriber left
+                let _ = sender.send(ChangeEvent {
+                    changes,
+                    origin,
+                    hash,
+                });
+            }));
+        }
+        self.changes.subscribe()
+    }
+
+    /// Iterate over the values whose keys are within the given range, without holding the read
+    /// lock on the map during the whole iteration.
+    ///
+    /// The entries are copied by chunks of at most `chunk` entries; the lock is only held while
+    /// copying a chunk. Changes made between chunks may or may not be visible, but the entries that
+    /// exist during the whole iteration are always listed.
+    pub fn snapshot_range<R: RangeBounds<K>>(&self, range: &R, chunk: usize) -> SnapshotIter<M> {
+        SnapshotIter {
+            map: self.service.map.clone(),
+            range: (range.start_bound().cloned(), range.end_bound().cloned()),
+            chunk: chunk.max(1),
+            exhausted: false,
+        }
+    }
+
+    /// List the entries at the positions `start_index..start_index + count` of the map, in order,
+    /// holding the read lock only once, for instance to paginate the map.
+    ///
+    /// The positions count the tombstones, which are skipped; fewer than `count` entries may thus
+    /// be returned before the end of the map.
+    pub fn page(&self, start_index: usize, count: usize) -> Vec<(K, V)> {
+        self.service
+            .map
+            .read()
+            .enumerate_by_rank(start_index, count)
+            .into_iter()
+            .filter_map(|(key, (_, value))| value.map(|value| (key, value)))
+            .collect()
+    }
+
+    /// Report the differences between the map and another one, typically loaded from the snapshot
+    /// of another instance with [`read_snapshot`](crate::debug::read_snapshot).
+    ///
+    /// See [`explain_diff`](crate::debug::explain_diff); the read lock is held during the whole
+    /// comparison.
+    pub fn explain_diff_with(
+        &self,
+        peer_snapshot: &M,
+        max_items: usize,
+    ) -> DiffReport<K, FingerprintOf<M>> {
+        debug::explain_diff(&*self.read(), peer_snapshot, max_items)
+    }
+
+    /// Get the value associated with the given key, if it exists and is not deleted.
+    ///
+    /// The key may be any borrowed form of `K`, as with [`Map::get`].
+    ///
+    /// The returned value may hold the read lock on the map until it is dropped, which blocks the
+    /// writes, including the ones received from the peers. Holding it across an `.await` may
+    /// deadlock; prefer [`get_cloned`](Service::get_cloned) then, which releases the lock before
+    /// returning.
+    pub fn get<Q: Ord + ?Sized>(&self, k: &Q) -> Option<ValueRef<'_, V>>
+    where
+        K: Borrow<Q>,
+    {
+        let guard = self.service.map.read();
+        let mut owned = None;
+        let borrowed = RwLockReadGuard::try_map(guard, |map: &M| match map.get(k)? {
+            Cow::Borrowed((_, value)) => value.as_ref(),
+            Cow::Owned((_, value)) => {
+                owned = value;
+                None
+            }
+        });
+        match borrowed {
+            Ok(guard) => Some(ValueRef::Borrowed(guard)),
+            Err(_) => owned.map(ValueRef::Owned),
+        }
+    }
+
+    /// Get a copy of the value associated with the given key, if it exists and is not deleted.
+    ///
+    /// Unlike with [`get`](Service::get), the read lock is only held while copying the value.
+    pub fn get_cloned<Q: Ord + ?Sized>(&self, k: &Q) -> Option<V>
+    where
+        K: Borrow<Q>,
+    {
+        let map = self.service.map.read();
+        map.get(k)?.into_owned().1
     }
 
-    /// Direct read access to the underlying map.
-    pub fn read(&self) -> RwLockReadGuard<'_, M> {
-        self.service.map.read()
+    /// Get a copy of the entry stored at the given key, with its timestamp, tombstones included.
+    pub fn get_dated<Q: Ord + ?Sized>(&self, k: &Q) -> Option<DatedMaybeTombstone<V>>
+    where
+        K: Borrow<Q>,
+    {
+        let map = self.service.map.read();
+        map.get(k).map(Cow::into_owned)
     }
 
-    pub fn get(&self, k: &K) -> Option<MappedRwLockReadGuard<'_, V>> {
-        let guard = self.service.map.read();
-        RwLockReadGuard::try_map(guard, |map: &M| map.get(k).and_then(|(_, v)| v.as_ref())).ok()
+    /// Get copies of the values associated with the given keys, in order, holding the read lock
+    /// only once.
+    pub fn multi_get(&self, keys: &[K]) -> Vec<Option<V>> {
+        let map = self.service.map.read();
+        keys.iter()
+            .map(|key| map.get(key)?.into_owned().1)
+            .collect()
     }
 
     pub fn just_insert(&self, key: K, value: V, timestamp: DateTime<Utc>) -> Option<V> {
@@ -142,13 +1483,98 @@ impl<
         );
     }
 
-    pub fn insert_bulk(&self, key_values: &[(K, V, DateTime<Utc>)]) {
+    /// Insert the values, and send them to the peers in shared datagrams.
+    ///
+    /// Return a ticket completing once the values were sent, for bulk loaders to pace themselves;
+    /// the writes are sent anyway when it is dropped. It only completes while the service runs,
+    /// and the [synchronization](Service::pause_sync) is not paused.
+    pub fn insert_bulk(&self, key_values: &[(K, V, DateTime<Utc>)]) -> BroadcastTicket {
         self.service.insert_bulk(
             &key_values
                 .iter()
                 .map(|(k, v, t)| (k.clone(), (*t, Some(v.clone()))))
                 .collect::<Vec<_>>(),
-        );
+        )
+    }
+
+    /// Insert the value like [`insert`](Service::insert), dated with the [clock](Service::with_clock)
+    /// of the service.
+    ///
+    /// The default [`MonotonicClock`](crate::MonotonicClock) never returns the same time twice,
+    /// so the values inserted in a tight loop, here or on the peers in the same process, do not
+    /// conflict with equal timestamps. Mixing this with explicit timestamps is fine: the most
+    /// recent value wins either way.
+    pub fn insert_now(&self, key: K, value: V) -> Option<V> {
+        let timestamp = self.service.clock.now();
+        self.insert(key, value, timestamp)
+    }
+
+    /// Insert the values like [`insert_bulk`](Service::insert_bulk), each dated with the
+    /// [clock](Service::with_clock) of the service, as with [`insert_now`](Service::insert_now).
+    pub fn insert_bulk_now(&self, key_values: &[(K, V)]) -> BroadcastTicket {
+        self.service.insert_bulk(
+            &key_values
+                .iter()
+                .map(|(k, v)| (k.clone(), (self.service.clock.now(), Some(v.clone()))))
+                .collect::<Vec<_>>(),
+        )
+    }
+
+    /// Merge key-value pairs sorted by key into the map, for instance a full export of another
+    /// system, under a single write lock: the new keys are inserted, and the existing values
+    /// replaced when the imported ones are more recent.
+    ///
+    /// The changes are sent to the peers like the ones of [`insert_bulk`](Service::insert_bulk).
+    /// The order is not checked: unsorted items corrupt the map; see
+    /// [`HRTree::merge_from_sorted`](crate::HRTree::merge_from_sorted).
+    pub fn merge_bulk<I: IntoIterator<Item = (K, V, DateTime<Utc>)>>(
+        &self,
+        key_values: I,
+    ) -> MergeStats {
+        self.service
+            .merge_bulk(key_values.into_iter().map(|(k, v, t)| (k, (t, Some(v)))))
+    }
+
+    /// Read the value at the given key after asking the known peers for their version of it.
+    ///
+    /// The versions received from the peers are applied locally, like updates, until `quorum`
+    /// peers responded or the timeout elapsed; only the peers holding the key respond. The
+    /// returned value is the local one afterwards, tombstones included.
+    pub async fn fetch_latest(
+        &self,
+        key: K,
+        quorum: usize,
+        timeout: Duration,
+    ) -> Option<(DateTime<Utc>, Option<V>)> {
+        self.service.fetch_latest(key, quorum, timeout).await
+    }
+
+    /// Replace the value at the given key with the result of the closure, atomically, and send it
+    /// to the peers.
+    ///
+    /// The closure is given the current value, if any, and returns the new value; `None` removes
+    /// the key. If the current value is newer than `timestamp`, it is kept and the closure is not
+    /// called, as when reconciling with a peer.
+    ///
+    /// Return the value at the key after the call.
+    pub fn update<F: FnOnce(Option<&V>) -> Option<V>>(
+        &self,
+        key: K,
+        timestamp: DateTime<Utc>,
+        f: F,
+    ) -> Option<V> {
+        let mut ret = None;
+        self.service.update(key, |current| {
+            if let Some((current_timestamp, value)) = current {
+                if *current_timestamp > timestamp {
+                    ret = value.clone();
+                    return None;
+                }
+            }
+            ret = f(current.and_then(|(_, value)| value.as_ref()));
+            Some((timestamp, ret.clone()))
+        });
+        ret
     }
 
     pub fn just_remove(&self, key: &K, timestamp: DateTime<Utc>) -> Option<V> {
@@ -161,6 +1587,24 @@ impl<
         ret.and_then(|t| t.1)
     }
 
+    /// Remove the value for the key like [`remove`](Service::remove), dated with the
+    /// [clock](Service::with_clock) of the service, as with [`insert_now`](Service::insert_now).
+    pub fn remove_now(&self, key: &K) -> Option<V> {
+        let timestamp = self.service.clock.now();
+        self.remove(key, timestamp)
+    }
+
+    /// Remove the value for the key like [`remove`](Service::remove), but expire the tombstone
+    /// after the given time instead of the [tombstone timeout](Service::set_tombstone_timeout).
+    ///
+    /// The specific timeout is lost when the tombstone is replaced, or loaded back from a
+    /// snapshot or the write-ahead log.
+    pub fn remove_with_ttl(&self, key: &K, timestamp: DateTime<Utc>, ttl: Duration) -> Option<V> {
+        let ret = self.remove(key, timestamp);
+        self.tombstones.set_element_timeout(key, ttl);
+        ret
+    }
+
     pub fn just_remove_bulk(&self, keys: &[(K, DateTime<Utc>)]) {
         self.service.just_insert_bulk(
             &keys
@@ -170,65 +1614,357 @@ impl<
         );
     }
 
-    pub fn remove_bulk(&self, keys: &[(K, DateTime<Utc>)]) {
+    /// Remove the values, and send the tombstones to the peers like
+    /// [`insert_bulk`](Service::insert_bulk), returning a ticket completing once they were sent.
+    pub fn remove_bulk(&self, keys: &[(K, DateTime<Utc>)]) -> BroadcastTicket {
         self.service.insert_bulk(
             &keys
                 .iter()
                 .map(|(k, t)| (k.clone(), (*t, None)))
                 .collect::<Vec<_>>(),
+        )
+    }
+
+    /// Remove all the values for which the predicate returns `false`, so that peers remove them as
+    /// well.
+    ///
+    /// The removed values are replaced by tombstones with the given timestamp, which are inserted
+    /// and sent to the peers at once, as with [`remove_bulk`](Service::remove_bulk).
+    pub fn retain<P: FnMut(&K, &V) -> bool>(&self, mut predicate: P, timestamp: DateTime<Utc>) {
+        let removed = self
+            .service
+            .map
+            .write()
+            .retain(|key, (_, value)| value.as_ref().is_none_or(|value| predicate(key, value)));
+        self.service.insert_bulk(
+            &removed
+                .into_iter()
+                .map(|(key, _)| (key, (timestamp, None)))
+                .collect::<Vec<_>>(),
         );
     }
+}
 
-    pub async fn start_reconciliation(&self) {
-        let mut buf = Vec::new();
-        self.service.start_reconciliation(&mut buf).await;
+impl<
+        K: Clone + Debug + DeserializeOwned + Hash + Ord + Send + Serialize + Sync + 'static,
+        T: Clone + DeserializeOwned + Hash + Send + Serialize + Sync + 'static,
+        C: Clone + Debug + DeserializeOwned + Send + Serialize + Sync + 'static,
+        D: Clone + Debug + 'static,
+        M: Map<Key = K, Value = DatedMaybeTombstone<Chunked<T>>, DifferenceItem = D>
+            + Diffable<ComparisonItem = C, DifferenceItem = D>
+            + HashRangeQueryable<Key = K>
+            + Send
+            + Sync
+            + 'static,
+    > Service<M>
+{
+    /// Store the content of the values in chunks outside of the map, see [`chunk`](crate::chunk).
+    ///
+    /// The chunks of the values received from a peer are requested from it; the values are
+    /// [`Pending`](ChunkedValue::Pending) until all their chunks arrived.
+    pub fn with_chunks(self) -> Self {
+        {
+            let guard = self.service.map.read();
+            let mut chunks = self.service.chunks.write();
+            for (_, value) in guard.iter_entries() {
+                if let (_, Some(value)) = &*value {
+                    chunks.add_refs(&value.chunks);
+                }
+            }
+        }
+        *self.service.chunk_refs.write() = Some(Box::new(|(_, value)| {
+            value
+                .as_ref()
+                .map(|value| value.chunks.clone())
+                .unwrap_or_default()
+        }));
+        self
     }
 
-    async fn clear_expired_tombstones(&self) {
-        loop {
-            while let Some(value) = self.tombstones.pop_expired() {
-                self.service.map.write().remove(&value);
+    /// Split the content in chunks, and insert its manifest at the given key, along with the
+    /// metadata.
+    ///
+    /// The service must have been built [`with_chunks`](Service::with_chunks).
+    pub fn insert_chunked(
+        &self,
+        key: K,
+        metadata: T,
+        data: &[u8],
+        timestamp: DateTime<Utc>,
+    ) -> Option<Chunked<T>> {
+        let chunks = self.service.chunks.write().put(data);
+        self.insert(key, Chunked { metadata, chunks }, timestamp)
+    }
+
+    /// Get the metadata and the content of the value at the given key.
+    pub fn get_chunked(&self, k: &K) -> Option<ChunkedValue<T>> {
+        let value = self.get(k)?;
+        match self.service.chunks.read().assemble(&value.chunks) {
+            Some(data) => Some(ChunkedValue::Complete(value.metadata.clone(), data)),
+            None => Some(ChunkedValue::Pending),
+        }
+    }
+
+    /// Hashes of the chunks held by the service, in no particular order.
+    pub fn chunk_hashes(&self) -> Vec<ChunkHash> {
+        self.service.chunks.read().hashes()
+    }
+}
+
+impl<
+        K: Clone + Debug + DeserializeOwned + Hash + Ord + Send + Serialize + Sync + 'static,
+        T: 'static,
+        E: 'static,
+        C: Clone + Debug + DeserializeOwned + Send + Serialize + Sync + 'static,
+        D: Clone + Debug + 'static,
+        M: Map<Key = K, Value = DatedMaybeTombstone<Vec<u8>>, DifferenceItem = D>
+            + HashRangeQueryable<Key = K>
+            + Send
+            + Sync
+            + 'static,
+    > Service<CodecMap<M, T, E>>
+where
+    CodecMap<M, T, E>: Diffable<ComparisonItem = C, DifferenceItem = D>,
+{
+    /// Encode the value with the codec of the map, and insert it at the given key.
+    ///
+    /// Return the previous value, decoded, if any.
+    pub fn insert_encoded(
+        &self,
+        key: K,
+        value: &T,
+        timestamp: DateTime<Utc>,
+    ) -> Option<Result<T, E>> {
+        let codec = self.service.map.read().codec().clone();
+        let old = self.insert(key, codec.encode(value), timestamp)?;
+        Some(codec.decode(&old))
+    }
+
+    /// Get and decode the value at the given key, see [`CodecMap::try_get`].
+    pub fn try_get<Q: Ord + ?Sized>(&self, key: &Q) -> Option<Result<T, E>>
+    where
+        K: Borrow<Q>,
+    {
+        self.service.map.read().try_get(key)
+    }
+
+    /// Replace the value at the given key with the result of the closure like
+    /// [`update`](Service::update), decoding the current value and encoding the new one.
+    ///
+    /// A current value that cannot be decoded is left unchanged, and the closure is not called.
+    /// Return the value at the key after the call, unless it could not be decoded.
+    pub fn update_decoded<F: FnOnce(Option<&T>) -> Option<T>>(
+        &self,
+        key: K,
+        timestamp: DateTime<Utc>,
+        f: F,
+    ) -> Option<T> {
+        let codec = self.service.map.read().codec().clone();
+        let mut ret = None;
+        self.service.update(key, |current| {
+            let mut decoded = None;
+            if let Some((current_timestamp, bytes)) = current {
+                decoded = bytes
+                    .as_ref()
+                    .map(|bytes| codec.decode(bytes))
+                    .transpose()
+                    .ok()?;
+                if *current_timestamp > timestamp {
+                    ret = decoded;
+                    return None;
+                }
             }
-            tokio::time::sleep(TOMBSTONE_CLEARING).await;
+            ret = f(decoded.as_ref());
+            Some((timestamp, ret.as_ref().map(|value| codec.encode(value))))
+        });
+        ret
+    }
+
+    /// Set a callback called before each change to the map like
+    /// [`with_pre_insert`](Service::with_pre_insert), with the new value and the previous one
+    /// decoded; tombstones are given as `None`.
+    pub fn with_pre_insert_decoded<
+        F: Send + Sync + Fn(&K, Option<Result<T, E>>, Option<Result<T, E>>) + 'static,
+    >(
+        self,
+        pre_insert: F,
+    ) -> Self {
+        let codec = self.service.map.read().codec().clone();
+        self.with_pre_insert(move |k, v, old_v| {
+            let decode = |value: &DatedMaybeTombstone<Vec<u8>>| {
+                value.1.as_ref().map(|bytes| codec.decode(bytes))
+            };
+            pre_insert(k, decode(v), old_v.and_then(decode))
+        })
+    }
+}
+
+/// Iterator over a range of the map of a [`Service`], returned by
+/// [`snapshot_range`](Service::snapshot_range).
+pub struct SnapshotIter<M: Map> {
+    map: Arc<RwLock<M>>,
+    /// Remaining range, starting after the last copied key
+    range: (Bound<M::Key>, Bound<M::Key>),
+    chunk: usize,
+    exhausted: bool,
+}
+
+impl<K: Clone, V, M: Map<Key = K, Value = DatedMaybeTombstone<V>>> SnapshotIter<M> {
+    /// Copy the next chunk of entries, holding the read lock on the map only during the copy.
+    ///
+    /// Tombstones are skipped, so the returned chunk might be empty even if the iteration is not
+    /// over. Return `None` once the end of the range is reached.
+    pub fn next_chunk(&mut self) -> Option<Vec<(K, V)>> {
+        if self.exhausted {
+            return None;
+        }
+        let entries = self.map.read().enumerate_range(&self.range, self.chunk);
+        if entries.len() < self.chunk {
+            self.exhausted = true;
         }
+        if let Some((key, _)) = entries.last() {
+            self.range.0 = Bound::Excluded(key.clone());
+        }
+        Some(
+            entries
+                .into_iter()
+                .filter_map(|(key, (_, value))| value.map(|value| (key, value)))
+                .collect(),
+        )
     }
 
-    pub async fn run(self) {
-        let clone = self.clone();
-        tokio::join!(self.service.run(), clone.clear_expired_tombstones());
+    /// Iterate over the remaining entries one by one, copying them by chunks.
+    pub fn entries(self) -> impl Iterator<Item = (K, V)> {
+        let mut iter = self;
+        std::iter::from_fn(move || iter.next_chunk()).flatten()
     }
 }
 
 impl<
         K: Clone + Debug + DeserializeOwned + Hash + Ord + Send + Serialize + Sync + 'static,
         V: Clone + DeserializeOwned + Hash + Send + Serialize + Sync + 'static,
-        C: Debug + DeserializeOwned + Send + Serialize + Sync + 'static,
-        D: Debug + 'static,
+        C: Clone + Debug + DeserializeOwned + Send + Serialize + Sync + 'static,
+        D: Clone + Debug + 'static,
         M: MutMap<Key = K, Value = DatedMaybeTombstone<V>, DifferenceItem = D>
+            + HashRangeQueryable<Key = K>
             + Diffable<ComparisonItem = C, DifferenceItem = D>
             + Send
             + Sync
             + 'static,
     > Service<M>
 {
+    /// Modify in place the value associated with the given key, if it exists.
+    ///
+    /// The timestamp of the value is set to the current time, so that the modification wins over
+    /// the older value of the peers at the next diff round.
     pub fn get_mut<F: FnOnce(Option<&mut V>)>(&self, k: &K, callback: F) {
         let mut guard = self.service.map.write();
-        guard.get_mut(k, |maybe_tv| {
-            if let Some((_, v)) = maybe_tv {
-                callback(v.as_mut());
-            } else {
-                callback(None);
+        let mut old_value = None;
+        guard.get_mut(k, |maybe_tv| match maybe_tv {
+            Some((timestamp, Some(v))) => {
+                old_value = Some((*timestamp, Some(v.clone())));
+                callback(Some(v));
+                *timestamp = self.service.clock.now();
             }
+            _ => callback(None),
         });
+        if let Some(old_value) = old_value {
+            if let Some(value) = guard.get(k).map(Cow::into_owned) {
+                self.service.record_change();
+                self.service.record_origin(k, ChangeOrigin::Local);
+                (self.service.pre_insert.read())(k, &value, Some(&old_value), ChangeOrigin::Local);
+                let hash = self.service.batch_hash(&guard);
+                drop(guard);
+                self.service.post_insert(
+                    &[(k.clone(), value, Some(old_value))],
+                    ChangeOrigin::Local,
+                    hash,
+                );
+            }
+        }
+    }
+}
+
+/// Handle to a [`Service`] running in its own task, returned by [`spawn`](Service::spawn).
+pub struct ServiceHandle {
+    sender: watch::Sender<bool>,
+    task: JoinHandle<()>,
+}
+
+impl ServiceHandle {
+    /// Ask the service to shut down gracefully; see
+    /// [`run_with_shutdown`](Service::run_with_shutdown).
+    pub fn shutdown(&self) {
+        self.sender.send_replace(true);
+    }
+
+    /// Wait for the service to return.
+    pub async fn join(self) -> Result<(), JoinError> {
+        self.task.await
     }
 }
 
 #[cfg(test)]
 mod service_tests {
-    use chrono::Utc;
+    use chrono::{DateTime, Utc};
+    use parking_lot::Mutex;
+    use std::net::SocketAddr;
+    use std::sync::Arc;
     use std::time::Duration;
 
-    use crate::{DatedMaybeTombstone, HRTree, Service};
+    use super::{ChangeOrigin, WalSync, TOMBSTONE_CLEARING};
+    use crate::{Clock, DatedMaybeTombstone, HRTree, HashRangeQueryable, Service};
+
+    #[tokio::test]
+    async fn entry_hash() {
+        let service = Service::new(
+            HRTree::<u8, DatedMaybeTombstone<String>>::new(),
+            8080,
+            "127.0.0.109".parse().unwrap(),
+            "127.0.0.1/8".parse().unwrap(),
+        )
+        .await
+        .unwrap();
+        assert_eq!(service.entry_hash(&0), None);
+        let timestamp = Utc::now();
+        service.insert(0, "Hello".to_string(), timestamp);
+        let hash = service.entry_hash(&0).unwrap();
+        service.insert(0, "Hello".to_string(), timestamp);
+        assert_eq!(service.entry_hash(&0), Some(hash));
+        service.insert(0, "World".to_string(), Utc::now());
+        let hash = service.entry_hash(&0).unwrap();
+
+        // the tombstone is an entry, until it is removed from the map
+        service.remove(&0, Utc::now());
+        assert!(service
+            .entry_hash(&0)
+            .is_some_and(|tombstone| tombstone != hash));
+        service.pending_tombstones.lock().insert(0);
+        service.force_clear_tombstones();
+        assert_eq!(service.entry_hash(&0), None);
+    }
+
+    #[tokio::test]
+    async fn origin_removal() {
+        let service = Service::new(
+            HRTree::<u8, DatedMaybeTombstone<String>>::new(),
+            8080,
+            "127.0.0.120".parse().unwrap(),
+            "127.0.0.1/8".parse().unwrap(),
+        )
+        .await
+        .unwrap()
+        .with_origin_tracking();
+        service.insert(0, "Hello".to_string(), Utc::now());
+        let origin = service.origin_of(&0).unwrap();
+        assert_eq!(origin.source, ChangeOrigin::Local);
+        // the tombstone keeps an origin, until it is removed from the map
+        service.remove(&0, Utc::now());
+        assert!(service.origin_of(&0).is_some());
+        service.pending_tombstones.lock().insert(0);
+        service.force_clear_tombstones();
+        assert_eq!(service.origin_of(&0), None);
+    }
 
     #[tokio::test]
     async fn tombstones_expiration() {
@@ -239,6 +1975,7 @@ mod service_tests {
             "127.0.0.1/8".parse().unwrap(),
         )
         .await
+        .unwrap()
         .with_tombstone_timeout(Duration::from_millis(1));
 
         let task = tokio::spawn(service.clone().run());
@@ -246,10 +1983,547 @@ mod service_tests {
         // insert an already-expired tombstone
         service.remove(&0, Utc::now() - Duration::from_millis(2));
         // check that pop_expired() does yield the tombstone
-        assert_eq!(service.tombstones.pop_expired(), Some(0));
+        assert_eq!(service.tombstones.pop_expired(Utc::now()), Some(0));
         // check that it was indeed removed
         assert_eq!(service.tombstones.remove(&0), None);
 
         task.abort();
     }
+
+    #[tokio::test]
+    async fn tombstone_timeouts() {
+        let service = Service::new(
+            HRTree::<u8, DatedMaybeTombstone<String>>::new(),
+            8080,
+            "127.0.0.45".parse().unwrap(),
+            "127.255.255.254/32".parse().unwrap(),
+        )
+        .await
+        .unwrap()
+        .with_tombstone_timeout(Duration::from_millis(100));
+        let now = Utc::now();
+        let soon = now + Duration::from_millis(200);
+
+        // raising the timeout applies to the tombstones already scheduled
+        service.remove(&0, now);
+        service.set_tombstone_timeout(Duration::from_secs(3600));
+        assert_eq!(service.tombstones.pop_expired(soon), None);
+        service.set_tombstone_timeout(Duration::from_millis(100));
+        assert_eq!(service.tombstones.pop_expired(soon), Some(0));
+
+        // a shorter specific timeout expires before the global one
+        service.set_tombstone_timeout(Duration::from_secs(3600));
+        service.remove(&1, now);
+        service.remove_with_ttl(&2, now, Duration::from_millis(100));
+        assert_eq!(service.tombstones.pop_expired(soon), Some(2));
+        assert_eq!(service.tombstones.pop_expired(soon), None);
+
+        // a longer specific timeout outlives the global one
+        service.set_tombstone_timeout(Duration::from_millis(100));
+        service.remove_with_ttl(&3, now, Duration::from_secs(3600));
+        assert_eq!(service.tombstones.pop_expired(soon), Some(1));
+        assert_eq!(service.tombstones.pop_expired(soon), None);
+        assert_eq!(service.tombstone_count(), 1);
+        let later = now + Duration::from_secs(3601);
+        assert_eq!(service.tombstones.pop_expired(later), Some(3));
+    }
+
+    #[tokio::test]
+    async fn tombstones_bounded() {
+        let service = Service::new(
+            HRTree::<u32, DatedMaybeTombstone<u32>>::new(),
+            8080,
+            "127.0.0.99".parse().unwrap(),
+            "127.255.255.254/32".parse().unwrap(),
+        )
+        .await
+        .unwrap()
+        .with_tombstone_timeout(Duration::from_millis(1));
+
+        // tombstones with equal timestamps are all tracked
+        let timestamp = Utc::now();
+        let keys: Vec<_> = (0..100_000).map(|key| (key, timestamp)).collect();
+        let values: Vec<_> = (0..100_000).map(|key| (key, key, timestamp)).collect();
+        service.just_insert_bulk(&values);
+        service.just_remove_bulk(&keys);
+        assert_eq!(service.tombstone_count(), 100_000);
+
+        // a tombstone re-armed by a newer removal keeps only its new deadline
+        let later = timestamp + Duration::from_secs(3600);
+        service.remove(&0, later);
+        assert_eq!(service.tombstone_count(), 100_000);
+
+        // an insertion clears the tombstone
+        service.insert(1, 1, later);
+        assert_eq!(service.tombstone_count(), 99_999);
+
+        // the expired tombstones are removed from the map
+        let task = tokio::spawn(service.clone().run());
+        tokio::time::sleep(TOMBSTONE_CLEARING * 2).await;
+        assert_eq!(service.tombstone_count(), 1);
+        assert!(service.pending_tombstones().is_empty());
+        assert_eq!(service.read().len(), 2);
+
+        task.abort();
+    }
+
+    #[derive(Clone)]
+    struct ManualClock(Arc<Mutex<DateTime<Utc>>>);
+
+    impl Clock for ManualClock {
+        fn now(&self) -> DateTime<Utc> {
+            *self.0.lock()
+        }
+    }
+
+    #[tokio::test]
+    async fn injected_clock() {
+        let start = Utc::now();
+        let clock = ManualClock(Arc::new(Mutex::new(start)));
+        let service = Service::new(
+            HRTree::<u8, DatedMaybeTombstone<String>>::new(),
+            8080,
+            "127.0.0.85".parse().unwrap(),
+            "127.255.255.254/32".parse().unwrap(),
+        )
+        .await
+        .unwrap()
+        .with_tombstone_timeout(Duration::from_secs(60))
+        .with_clock(clock.clone());
+
+        // local modifications are dated by the clock
+        service.insert(0, "Hello".to_string(), start - Duration::from_secs(1));
+        service.get_mut(&0, |value| value.unwrap().push('!'));
+        assert_eq!(service.read().get(&0).unwrap().0, start);
+
+        let task = tokio::spawn(service.clone().run());
+
+        // the tombstone is kept while the clock does not move
+        service.remove(&0, start);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(service.read().get(&0).is_some());
+
+        // and cleared once the clock passes the timeout
+        *clock.0.lock() = start + Duration::from_secs(61);
+        tokio::time::sleep(TOMBSTONE_CLEARING + Duration::from_millis(200)).await;
+        assert!(service.read().get(&0).is_none());
+
+        task.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn peer_gossip() {
+        // no random peer discovery
+        let peer_net = "127.255.255.254/32".parse().unwrap();
+        // the instances share the same IP address, on different ports
+        let addr_a: SocketAddr = "127.0.0.50:8080".parse().unwrap();
+        let addr_b: SocketAddr = "127.0.0.50:8081".parse().unwrap();
+        let addr_c: SocketAddr = "127.0.0.50:8082".parse().unwrap();
+
+        let tree_a = HRTree::from_iter([(0u8, (Utc::now(), Some("Hello".to_string())))]);
+        let hash = tree_a.hash(&..);
+        let service_a = Service::new(tree_a, addr_a.port(), addr_a.ip(), peer_net)
+            .await
+            .unwrap()
+            .with_seed_addr(addr_b);
+        let service_b = Service::new(HRTree::new(), addr_b.port(), addr_b.ip(), peer_net)
+            .await
+            .unwrap()
+            .with_seed_addr(addr_c);
+        let service_c = Service::new(HRTree::new(), addr_c.port(), addr_c.ip(), peer_net)
+            .await
+            .unwrap();
+        let task_a = tokio::spawn(service_a.clone().run());
+        let task_b = tokio::spawn(service_b.clone().run());
+        let task_c = tokio::spawn(service_c.clone().run());
+
+        // all instances should end up knowing each other
+        let knows = |service: &Service<_>, addr| service.service.peers.read().contains_key(&addr);
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            if knows(&service_a, addr_c)
+                && knows(&service_c, addr_a)
+                && service_c.read().hash(&..) == hash
+            {
+                break;
+            }
+        }
+        assert!(knows(&service_a, addr_c));
+        assert!(knows(&service_c, addr_a));
+        assert!(knows(&service_b, addr_a));
+        assert!(knows(&service_c, addr_b));
+        assert_eq!(service_b.read().hash(&..), hash);
+        assert_eq!(service_c.read().hash(&..), hash);
+
+        task_a.abort();
+        task_b.abort();
+        task_c.abort();
+    }
+
+    #[tokio::test]
+    async fn wal_recovery() {
+        let path =
+            std::env::temp_dir().join(format!("reconcile-{}-service.wal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let port = 8080;
+        let peer_net = "127.0.0.1/8".parse().unwrap();
+
+        let service = Service::new(
+            HRTree::<u8, DatedMaybeTombstone<String>>::new(),
+            port,
+            "127.0.0.46".parse().unwrap(),
+            peer_net,
+        )
+        .await
+        .unwrap()
+        .with_wal(&path)
+        .unwrap()
+        .with_wal_sync(WalSync::EveryBatch);
+        service.just_insert(0, "Hello".to_string(), Utc::now());
+        // the record is written once the write lock is released
+        let size = std::fs::metadata(&path).unwrap().len();
+        assert!(size > 0);
+        service.insert_bulk(&[(1, "World!".to_string(), Utc::now())]);
+        assert!(std::fs::metadata(&path).unwrap().len() > size);
+        service.just_insert(0, "Goodbye".to_string(), Utc::now());
+        service.just_remove(&1, Utc::now());
+        service.sync_wal().unwrap();
+        assert_eq!(service.metrics().snapshot().wal_errors, 0);
+        let hash = service.read().hash(&..);
+        drop(service);
+
+        let recovered = Service::<HRTree<u8, DatedMaybeTombstone<String>>>::recover_from_wal(
+            &path,
+            port,
+            "127.0.0.47".parse().unwrap(),
+            peer_net,
+        )
+        .await
+        .unwrap();
+        assert_eq!(recovered.read().hash(&..), hash);
+        assert_eq!(recovered.get(&0).as_deref(), Some(&"Goodbye".to_string()));
+        assert_eq!(recovered.get(&1).as_deref(), None);
+        // the tombstone should be tracked again
+        assert_eq!(recovered.tombstones.remove(&1), Some(1));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn wal_deletions() {
+        let path =
+            std::env::temp_dir().join(format!("reconcile-{}-deletions.wal", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let port = 8080;
+        let peer_net = "127.0.0.1/8".parse().unwrap();
+
+        let service = Service::new(
+            HRTree::<u8, DatedMaybeTombstone<String>>::new(),
+            port,
+            "127.0.0.107".parse().unwrap(),
+            peer_net,
+        )
+        .await
+        .unwrap()
+        .with_wal(&path)
+        .unwrap()
+        .with_wal_compaction_threshold(1);
+        service.just_insert(0, "Hello".to_string(), Utc::now());
+        let deleted = Utc::now();
+        service.just_remove(&0, deleted);
+        // the tombstone is removed from the map, but kept in the compacted log
+        service.service.map.write().remove(&0);
+        service.compact_wal_if_needed();
+        drop(service);
+
+        let recovered = Service::<HRTree<u8, DatedMaybeTombstone<String>>>::recover_from_wal(
+            &path,
+            port,
+            "127.0.0.108".parse().unwrap(),
+            peer_net,
+        )
+        .await
+        .unwrap();
+        assert_eq!(recovered.read().get(&0), Some(&(deleted, None)));
+        let stale = (
+            deleted - chrono::Duration::seconds(1),
+            Some("Hello".to_string()),
+        );
+        assert!(recovered
+            .service
+            .deletions
+            .read()
+            .rejects(&0, &stale, &*recovered.service.policy));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn insertion_hooks() {
+        type Change = (u8, Option<String>, Option<Option<String>>);
+        let port = 8080;
+        // no random peer discovery
+        let peer_net = "127.255.255.254/32".parse().unwrap();
+        let addr_a = "127.0.0.95".parse().unwrap();
+        let addr_b = "127.0.0.96".parse().unwrap();
+
+        let pre: Arc<Mutex<Vec<Change>>> = Arc::default();
+        let post: Arc<Mutex<Vec<Change>>> = Arc::default();
+        let tree_a = HRTree::<u8, DatedMaybeTombstone<String>>::new();
+        let service_a = Service::new(tree_a, port, addr_a, peer_net).await.unwrap();
+        let map = service_a.service.map.clone();
+        let service_a = service_a
+            .with_pre_insert({
+                let pre = pre.clone();
+                move |k, v, old_v| {
+                    let old_v = old_v.map(|old_v| old_v.1.clone());
+                    pre.lock().push((*k, v.1.clone(), old_v));
+                }
+            })
+            .with_post_insert({
+                let post = post.clone();
+                move |k, v, old_v| {
+                    // the write lock is released
+                    assert!(map.try_write().is_some());
+                    let old_v = old_v.map(|old_v| old_v.1.clone());
+                    post.lock().push((*k, v.1.clone(), old_v));
+                }
+            });
+        let tree_b = HRTree::<u8, DatedMaybeTombstone<String>>::new();
+        let service_b = Service::new(tree_b, port, addr_b, peer_net)
+            .await
+            .unwrap()
+            .with_seed(addr_a);
+        let task_a = tokio::spawn(service_a.clone().run());
+        let task_b = tokio::spawn(service_b.clone().run());
+
+        let hello = Some("Hello".to_string());
+        let world = Some("World".to_string());
+        service_a.insert(0, "Hello".to_string(), Utc::now());
+        service_a.insert(0, "World".to_string(), Utc::now());
+        service_a.remove(&0, Utc::now());
+        service_b.insert(1, "Hello".to_string(), Utc::now());
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            if service_a.get(&1).is_some() {
+                break;
+            }
+        }
+        let expected = vec![
+            (0, hello.clone(), None),
+            (0, world.clone(), Some(hello.clone())),
+            (0, None, Some(world)),
+            (1, hello, None),
+        ];
+        assert_eq!(*pre.lock(), expected);
+        assert_eq!(*post.lock(), expected);
+
+        task_a.abort();
+        task_b.abort();
+    }
+
+    #[tokio::test]
+    async fn snapshot() {
+        let path =
+            std::env::temp_dir().join(format!("reconcile-{}-service.snapshot", std::process::id()));
+        let port = 8080;
+        let peer_net = "127.0.0.1/8".parse().unwrap();
+
+        // several chunks, and a tombstone
+        let timestamp = Utc::now();
+        let tree = HRTree::from_iter((0..2500u16).map(|i| (i, (timestamp, Some(i.to_string())))));
+        let service = Service::new(tree, port, "127.0.0.93".parse().unwrap(), peer_net)
+            .await
+            .unwrap();
+        service.just_remove(&1, timestamp);
+        service.save_snapshot(&path).unwrap();
+        let hash = service.read().hash(&..);
+        drop(service);
+
+        let loaded = Service::<HRTree<u16, DatedMaybeTombstone<String>>>::load_snapshot(
+            &path,
+            port,
+            "127.0.0.94".parse().unwrap(),
+            peer_net,
+        )
+        .await
+        .unwrap();
+        loaded.read().check_invariants();
+        assert_eq!(loaded.read().hash(&..), hash);
+        assert_eq!(loaded.read().len(), 2500);
+        assert_eq!(loaded.get(&2499).as_deref(), Some(&"2499".to_string()));
+        assert_eq!(loaded.get(&1).as_deref(), None);
+        // the tombstone should be tracked again
+        assert_eq!(loaded.tombstones.remove(&1), Some(1));
+
+        // compare with the snapshot after some changes
+        let snapshot: HRTree<u16, DatedMaybeTombstone<String>> =
+            crate::debug::read_snapshot(&path).unwrap();
+        assert!(loaded.explain_diff_with(&snapshot, 10).is_empty());
+        loaded.insert(2500, "2500".to_string(), Utc::now());
+        loaded.just_remove(&0, Utc::now());
+        let report = loaded.explain_diff_with(&snapshot, 10);
+        assert_eq!(
+            (report.only_a, report.only_b, report.conflicting),
+            (1, 0, 1)
+        );
+        let item = report
+            .ranges
+            .iter()
+            .flat_map(|range| &range.items)
+            .find(|item| item.key == 0)
+            .unwrap();
+        assert!(item.a.as_ref().unwrap().tombstone);
+        assert!(!item.b.as_ref().unwrap().tombstone);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tombstone_acknowledgement_after_peer_expiration() {
+        let port = 8080;
+        // no random peer discovery
+        let peer_net = "127.255.255.254/32".parse().unwrap();
+        let addr_a = "127.0.0.59".parse().unwrap();
+        let addr_b = "127.0.0.60".parse().unwrap();
+        let timeout = Duration::from_millis(1);
+        let expiration = Duration::from_secs(2);
+
+        let tree = HRTree::from_iter([(0u8, (Utc::now(), Some("Hello".to_string())))]);
+        let service_a = Service::new(tree, port, addr_a, peer_net)
+            .await
+            .unwrap()
+            .with_tombstone_timeout(timeout)
+            .with_peer_expiration(expiration);
+        let service_b = Service::new(HRTree::new(), port, addr_b, peer_net)
+            .await
+            .unwrap()
+            .with_tombstone_timeout(timeout)
+            .with_peer_expiration(expiration)
+            .with_seed(addr_a);
+        let task_a = tokio::spawn(service_a.clone().run());
+        let task_b = tokio::spawn(service_b.clone().run());
+        type S = Service<HRTree<u8, DatedMaybeTombstone<String>>>;
+        let has_entry = |service: &S| service.read().get(&0).is_some();
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            if service_b.get(&0).is_some() {
+                break;
+            }
+        }
+        assert!(service_b.get(&0).is_some());
+
+        // partition service_b for longer than the peer expiration, and remove the key meanwhile
+        task_b.abort();
+        service_a.remove(&0, Utc::now());
+        tokio::time::sleep(3 * expiration).await;
+        assert!(service_a.peers().is_empty());
+        assert!(has_entry(&service_a));
+        assert_eq!(service_a.pending_tombstones(), vec![0]);
+
+        // heal the partition: the key should not be resurrected
+        let task_b = tokio::spawn(service_b.clone().run());
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            if !has_entry(&service_a) && !has_entry(&service_b) {
+                break;
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        for service in [&service_a, &service_b] {
+            assert!(!has_entry(service));
+            assert!(service.pending_tombstones().is_empty());
+        }
+        task_a.abort();
+        task_b.abort();
+
+        // a peer removed explicitly no longer holds the tombstones back
+        service_a.insert(1, "World".to_string(), Utc::now());
+        service_a.remove(&1, Utc::now());
+        service_a.remove_peer(std::net::SocketAddr::new(addr_b, port));
+        let clearing = tokio::spawn(service_a.clone().run());
+        for _ in 0..50 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            if service_a.read().get(&1).is_none() {
+                break;
+            }
+        }
+        assert!(service_a.read().get(&1).is_none());
+        clearing.abort();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn tombstone_acknowledgement() {
+        let port = 8080;
+        // no random peer discovery
+        let peer_net = "127.255.255.254/32".parse().unwrap();
+        let addr_a = "127.0.0.56".parse().unwrap();
+        let addr_b = "127.0.0.57".parse().unwrap();
+        let addr_c = "127.0.0.58".parse().unwrap();
+        let timeout = Duration::from_millis(1);
+
+        let tree = HRTree::from_iter([(0u8, (Utc::now(), Some("Hello".to_string())))]);
+        let service_a = Service::new(tree, port, addr_a, peer_net)
+            .await
+            .unwrap()
+            .with_tombstone_timeout(timeout)
+            .with_seed(addr_b)
+            .with_seed(addr_c);
+        let service_b = Service::new(HRTree::new(), port, addr_b, peer_net)
+            .await
+            .unwrap()
+            .with_tombstone_timeout(timeout)
+            .with_seed(addr_a)
+            .with_seed(addr_c);
+        let service_c = Service::new(HRTree::new(), port, addr_c, peer_net)
+            .await
+            .unwrap()
+            .with_tombstone_timeout(timeout)
+            .with_seed(addr_a)
+            .with_seed(addr_b);
+        let task_a = tokio::spawn(service_a.clone().run());
+        let task_b = tokio::spawn(service_b.clone().run());
+        let task_c = tokio::spawn(service_c.clone().run());
+        type S = Service<HRTree<u8, DatedMaybeTombstone<String>>>;
+        let has_value = |service: &S| service.get(&0).is_some();
+        let has_entry = |service: &S| service.read().get(&0).is_some();
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            if has_value(&service_b) && has_value(&service_c) {
+                break;
+            }
+        }
+        assert!(has_value(&service_b));
+        assert!(has_value(&service_c));
+
+        // partition service_c, and remove the key in the meantime
+        task_c.abort();
+        service_a.remove(&0, Utc::now());
+        tokio::time::sleep(Duration::from_secs(3)).await;
+        // the tombstones expired long ago, but service_c did not acknowledge them
+        for service in [&service_a, &service_b] {
+            assert!(!has_value(service));
+            assert!(has_entry(service));
+            assert_eq!(service.pending_tombstones(), vec![0]);
+        }
+
+        // heal the partition
+        let task_c = tokio::spawn(service_c.clone().run());
+        for _ in 0..100 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            if !has_entry(&service_a) && !has_entry(&service_b) && !has_entry(&service_c) {
+                break;
+            }
+        }
+        // the key should not be resurrected
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        for service in [&service_a, &service_b, &service_c] {
+            assert!(!has_entry(service));
+            assert!(service.pending_tombstones().is_empty());
+        }
+
+        task_a.abort();
+        task_b.abort();
+        task_c.abort();
+    }
 }
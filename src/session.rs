@@ -0,0 +1,442 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`Sessions`], which tracks the reconciliation sessions with each peer.
+//!
+//! A session is the sequence of diff rounds started by an instance (the initiator) when it sends
+//! the segments covering the whole key space to a peer (the responder). Comparison messages carry
+//! the id of their session. The lowest bit of the id is set in the messages sent by the responder,
+//! so that an instance can tell apart the sessions it initiated from the ones initiated by a peer.
+//!
+//! A session initiated locally that lasts longer than a deadline is abandoned, so that the next
+//! one starts over from the whole key space; its later replies are dropped as stale.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime};
+
+/// Set in the session id of the messages sent by the responder
+const RESPONSE_BIT: u64 = 1;
+/// A session without any activity for this long is considered over
+const SESSION_TIMEOUT: Duration = Duration::from_secs(1);
+
+struct LocalSession {
+    id: u64,
+    started: Instant,
+    last_activity: Instant,
+    completed: bool,
+    /// Whether the peer replied in the session
+    replied: bool,
+    /// Whether the session was reported without reply
+    reported: bool,
+    /// Number of replies received from the peer
+    rounds: u64,
+    /// Number of segments sent in the last round
+    outstanding: usize,
+    /// Fraction of the segments received in the last round that differed
+    density: Option<f64>,
+}
+
+impl LocalSession {
+    fn is_active(&self) -> bool {
+        !self.completed && self.last_activity.elapsed() < SESSION_TIMEOUT
+    }
+}
+
+/// Session initiated locally that exceeded the deadline, and was abandoned
+#[derive(Debug, Eq, PartialEq)]
+pub(crate) struct OverdueSession {
+    pub peer: SocketAddr,
+    pub elapsed: Duration,
+    /// Number of replies received from the peer
+    pub rounds: u64,
+    /// Number of segments sent in the last round, whose differences were not found yet
+    pub outstanding: usize,
+}
+
+#[derive(Default)]
+struct PeerSessions {
+    /// Last session initiated locally with the peer
+    local: Option<LocalSession>,
+    /// Id of the last session initiated by the peer
+    remote: u64,
+    /// Fraction of the segments received in the last round of that session that differed
+    remote_density: Option<f64>,
+}
+
+pub(crate) struct Sessions {
+    peers: HashMap<SocketAddr, PeerSessions>,
+    next_id: u64,
+}
+
+impl Sessions {
+    pub fn new() -> Self {
+        // ids must keep increasing when the instance restarts, so that peers do not consider the
+        // new sessions as stale
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default();
+        Sessions {
+            peers: HashMap::new(),
+            next_id: now.as_nanos() as u64 & !RESPONSE_BIT,
+        }
+    }
+
+    /// Start a new session with the given address, replacing the current one if any.
+    ///
+    /// Return the id of the new session.
+    pub fn start(&mut self, addr: SocketAddr) -> u64 {
+        let id = self.next_id;
+        self.next_id += 2;
+        let now = Instant::now();
+        self.peers.entry(addr).or_default().local = Some(LocalSession {
+            id,
+            started: now,
+            last_activity: now,
+            completed: false,
+            replied: false,
+            reported: false,
+            rounds: 0,
+            outstanding: 0,
+            density: None,
+        });
+        id
+    }
+
+    /// Start sessions with the given peers, so that at most `max_concurrent` of them are active.
+    ///
+    /// The peers are selected round-robin: the ones whose last session is the oldest go first.
+    /// The peers for which `is_idle` returns true are skipped. The state of the addresses that
+    /// are not in `peers` is forgotten.
+    pub fn schedule<F: Fn(SocketAddr) -> bool>(
+        &mut self,
+        peers: &[SocketAddr],
+        max_concurrent: usize,
+        is_idle: F,
+    ) -> Vec<(SocketAddr, u64)> {
+        self.peers.retain(|addr, _| peers.contains(addr));
+        let is_active = |addr: &SocketAddr| {
+            self.peers
+                .get(addr)
+                .and_then(|state| state.local.as_ref())
+                .is_some_and(|session| session.is_active())
+        };
+        let active = peers.iter().filter(|addr| is_active(addr)).count();
+        let mut candidates: Vec<_> = peers
+            .iter()
+            .filter(|&&addr| !is_active(&addr) && !is_idle(addr))
+            .collect();
+        candidates.sort_by_key(|addr| {
+            self.peers
+                .get(addr)
+                .and_then(|state| state.local.as_ref())
+                .map(|session| session.started)
+        });
+        candidates.truncate(max_concurrent.saturating_sub(active));
+        candidates
+            .into_iter()
+            .map(|&addr| (addr, self.start(addr)))
+            .collect()
+    }
+
+    /// Whether comparison messages with the given session id received from the peer open a new
+    /// session, and thus cover all the keys.
+    pub fn is_opening(&self, peer: SocketAddr, session_id: u64) -> bool {
+        session_id & RESPONSE_BIT == 0
+            && self
+                .peers
+                .get(&peer)
+                .is_none_or(|state| session_id > state.remote)
+    }
+
+    /// Check whether comparison messages with the given session id received from the peer
+    /// belong to a current session.
+    ///
+    /// If so, return the session id to use in the reply. Otherwise, they should be dropped.
+    pub fn accept(&mut self, peer: SocketAddr, session_id: u64) -> Option<u64> {
+        let state = self.peers.entry(peer).or_default();
+        if session_id & RESPONSE_BIT != 0 {
+            // reply from the peer in a session we initiated
+            let id = session_id & !RESPONSE_BIT;
+            let session = state
+                .local
+                .as_mut()
+                .filter(|session| session.id == id && !session.completed)?;
+            session.last_activity = Instant::now();
+            session.replied = true;
+            session.rounds += 1;
+            Some(id)
+        } else if session_id >= state.remote {
+            // session initiated by the peer
+            if session_id > state.remote {
+                state.remote_density = None;
+            }
+            state.remote = session_id;
+            Some(session_id | RESPONSE_BIT)
+        } else {
+            None
+        }
+    }
+
+    /// List the peers whose last session initiated locally timed out without any reply, once per
+    /// session: the peer found all the keys equal.
+    pub fn take_unanswered(&mut self) -> Vec<SocketAddr> {
+        let mut unanswered = Vec::new();
+        for (&addr, state) in &mut self.peers {
+            if let Some(session) = state.local.as_mut().filter(|session| {
+                !session.completed && !session.replied && !session.reported && !session.is_active()
+            }) {
+                session.reported = true;
+                unanswered.push(addr);
+            }
+        }
+        unanswered
+    }
+
+    /// Record the number of segments sent to the peer in the session, if it was initiated
+    /// locally.
+    pub fn sent(&mut self, peer: SocketAddr, session_id: u64, segments: usize) {
+        if let Some(session) = self.local_session(peer, session_id) {
+            session.outstanding = segments;
+        }
+    }
+
+    /// Whether the segments deferred in the session with the given reply id should be dropped:
+    /// the session was replaced or abandoned, or the peer expired.
+    pub fn is_stale(&self, peer: SocketAddr, reply_session_id: u64) -> bool {
+        let Some(state) = self.peers.get(&peer) else {
+            return true;
+        };
+        if reply_session_id & RESPONSE_BIT != 0 {
+            reply_session_id & !RESPONSE_BIT < state.remote
+        } else {
+            state
+                .local
+                .as_ref()
+                .is_none_or(|session| session.id != reply_session_id || session.completed)
+        }
+    }
+
+    /// Abandon the active sessions initiated locally that started at least `deadline` ago, so
+    /// that the next sessions start over, and list them.
+    pub fn take_overdue(&mut self, deadline: Duration) -> Vec<OverdueSession> {
+        let mut overdue = Vec::new();
+        for (&peer, state) in &mut self.peers {
+            if let Some(session) = state
+                .local
+                .as_mut()
+                .filter(|session| session.is_active() && session.started.elapsed() >= deadline)
+            {
+                session.completed = true;
+                overdue.push(OverdueSession {
+                    peer,
+                    elapsed: session.started.elapsed(),
+                    rounds: session.rounds,
+                    outstanding: session.outstanding,
+                });
+            }
+        }
+        overdue
+    }
+
+    /// Time since the start of the oldest active session initiated locally, if any.
+    pub fn longest_active(&self) -> Option<Duration> {
+        self.peers
+            .values()
+            .filter_map(|state| state.local.as_ref())
+            .filter(|session| session.is_active())
+            .map(|session| session.started.elapsed())
+            .max()
+    }
+
+    fn local_session(&mut self, peer: SocketAddr, session_id: u64) -> Option<&mut LocalSession> {
+        self.peers
+            .get_mut(&peer)
+            .and_then(|state| state.local.as_mut())
+            .filter(|session| session.id == session_id)
+    }
+
+    /// Mark the session as completed, if it was initiated locally.
+    pub fn complete(&mut self, peer: SocketAddr, session_id: u64) {
+        if let Some(session) = self.local_session(peer, session_id) {
+            session.completed = true;
+        }
+    }
+
+    /// Fraction of the segments that differed in the last round of the session with the given
+    /// reply id, if recorded.
+    pub fn density(&self, peer: SocketAddr, reply_session_id: u64) -> Option<f64> {
+        let state = self.peers.get(&peer)?;
+        if reply_session_id & RESPONSE_BIT != 0 {
+            state
+                .remote_density
+                .filter(|_| reply_session_id & !RESPONSE_BIT == state.remote)
+        } else {
+            state
+                .local
+                .as_ref()
+                .filter(|session| session.id == reply_session_id)
+                .and_then(|session| session.density)
+        }
+    }
+
+    /// Record the fraction of the segments that differed in a round of the session with the
+    /// given reply id, to choose how to answer the next round.
+    pub fn record_density(&mut self, peer: SocketAddr, reply_session_id: u64, density: f64) {
+        if reply_session_id & RESPONSE_BIT != 0 {
+            if let Some(state) = self
+                .peers
+                .get_mut(&peer)
+                .filter(|state| reply_session_id & !RESPONSE_BIT == state.remote)
+            {
+                state.remote_density = Some(density);
+            }
+        } else if let Some(session) = self.local_session(peer, reply_session_id) {
+            session.density = Some(density);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use super::{OverdueSession, Sessions, RESPONSE_BIT, SESSION_TIMEOUT};
+
+    #[test]
+    fn stale_sessions() {
+        let peer: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let mut sessions = Sessions::new();
+
+        // sessions initiated locally
+        let old = sessions.start(peer);
+        let new = sessions.start(peer);
+        assert_eq!(sessions.accept(peer, old | RESPONSE_BIT), None);
+        assert_eq!(sessions.accept(peer, new | RESPONSE_BIT), Some(new));
+        sessions.complete(peer, new);
+        assert_eq!(sessions.accept(peer, new | RESPONSE_BIT), None);
+
+        // sessions initiated by the peer
+        assert_eq!(sessions.accept(peer, 42), Some(42 | RESPONSE_BIT));
+        assert_eq!(sessions.accept(peer, 44), Some(44 | RESPONSE_BIT));
+        assert_eq!(sessions.accept(peer, 42), None);
+        assert_eq!(sessions.accept(peer, 44), Some(44 | RESPONSE_BIT));
+    }
+
+    #[test]
+    fn densities() {
+        let peer: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let mut sessions = Sessions::new();
+
+        // session initiated locally
+        let id = sessions.start(peer);
+        assert_eq!(sessions.density(peer, id), None);
+        sessions.record_density(peer, id, 0.5);
+        assert_eq!(sessions.density(peer, id), Some(0.5));
+        // a new session starts over
+        let id = sessions.start(peer);
+        assert_eq!(sessions.density(peer, id), None);
+
+        // session initiated by the peer
+        let reply_id = sessions.accept(peer, 4).unwrap();
+        sessions.record_density(peer, reply_id, 0.9);
+        assert_eq!(sessions.density(peer, reply_id), Some(0.9));
+        assert_eq!(sessions.accept(peer, 4), Some(reply_id));
+        assert_eq!(sessions.density(peer, reply_id), Some(0.9));
+        let reply_id = sessions.accept(peer, 6).unwrap();
+        assert_eq!(sessions.density(peer, reply_id), None);
+        assert_eq!(sessions.density(peer, 4 | RESPONSE_BIT), None);
+    }
+
+    #[test]
+    fn unanswered() {
+        let peer: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let other: SocketAddr = "127.0.0.2:8080".parse().unwrap();
+        let mut sessions = Sessions::new();
+        sessions.start(peer);
+        let id = sessions.start(other);
+        sessions.accept(other, id | RESPONSE_BIT);
+        assert!(sessions.take_unanswered().is_empty());
+
+        // only the session without reply is reported, once it timed out
+        std::thread::sleep(SESSION_TIMEOUT);
+        assert_eq!(sessions.take_unanswered(), vec![peer]);
+        assert!(sessions.take_unanswered().is_empty());
+    }
+
+    #[test]
+    fn overdue() {
+        let peer: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let other: SocketAddr = "127.0.0.2:8080".parse().unwrap();
+        let deadline = Duration::from_millis(50);
+        let mut sessions = Sessions::new();
+        let id = sessions.start(peer);
+        let other_id = sessions.start(other);
+        sessions.sent(peer, id, 16);
+        assert_eq!(sessions.accept(peer, id | RESPONSE_BIT), Some(id));
+        sessions.sent(peer, id, 4);
+        sessions.complete(other, other_id);
+        assert!(sessions.take_overdue(deadline).is_empty());
+        assert!(sessions.longest_active().unwrap() < deadline);
+
+        // only the active session is abandoned, once
+        std::thread::sleep(deadline);
+        let overdue = sessions.take_overdue(deadline);
+        assert_eq!(overdue.len(), 1);
+        let OverdueSession {
+            peer: overdue_peer,
+            elapsed,
+            rounds,
+            outstanding,
+        } = overdue[0];
+        assert_eq!(overdue_peer, peer);
+        assert!(elapsed >= deadline);
+        assert_eq!((rounds, outstanding), (1, 4));
+        assert!(sessions.take_overdue(deadline).is_empty());
+        assert_eq!(sessions.longest_active(), None);
+
+        // the late replies and the deferred segments of the abandoned session are dropped
+        assert!(sessions.is_stale(peer, id));
+        assert_eq!(sessions.accept(peer, id | RESPONSE_BIT), None);
+        let new = sessions.start(peer);
+        assert!(!sessions.is_stale(peer, new));
+
+        // as well as the ones of the expired peers
+        assert_eq!(sessions.accept(other, 42), Some(42 | RESPONSE_BIT));
+        assert!(!sessions.is_stale(other, 42 | RESPONSE_BIT));
+        sessions.schedule(&[peer], 1, |_| false);
+        assert!(sessions.is_stale(other, 42 | RESPONSE_BIT));
+    }
+
+    #[test]
+    fn round_robin() {
+        let peers: Vec<SocketAddr> = ["127.0.0.1:8080", "127.0.0.1:8081", "127.0.0.2:8080"]
+            .iter()
+            .map(|addr| addr.parse().unwrap())
+            .collect();
+        let mut sessions = Sessions::new();
+        let mut seen = Vec::new();
+        for _ in 0..3 {
+            let started = sessions.schedule(&peers, 1, |_| false);
+            assert_eq!(started.len(), 1);
+            let (addr, id) = started[0];
+            // no other session while this one is active
+            assert!(sessions.schedule(&peers, 1, |_| false).is_empty());
+            sessions.complete(addr, id);
+            seen.push(addr);
+        }
+        seen.sort();
+        assert_eq!(seen, peers);
+
+        // idle peers are skipped
+        let started = sessions.schedule(&peers, 3, |addr| addr != peers[1]);
+        assert_eq!(started.len(), 1);
+        assert_eq!(started[0].0, peers[1]);
+    }
+}
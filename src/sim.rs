@@ -0,0 +1,289 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`SimNetwork`], an in-memory network to test services without real sockets.
+//!
+//! Each [`SimSocket`] bound on the network implements [`Transport`], and can be given to
+//! [`Service::with_transport`](crate::Service::with_transport). The links between the addresses
+//! can lose, duplicate, delay and reorder datagrams, and the network can be partitioned.
+//!
+//! ```
+//! # use reconcile::{sim::{LinkConfig, SimNetwork}, HRTree, DatedMaybeTombstone, Service};
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let network = SimNetwork::new(42);
+//! network.set_default_link(LinkConfig {
+//!     drop_probability: 0.1,
+//!     ..Default::default()
+//! });
+//! let socket = network.bind("10.0.0.1:8080".parse().unwrap()).unwrap();
+//! let tree: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+//! let service = Service::with_transport(tree, socket, "10.0.0.0/24".parse().unwrap());
+//! # }
+//! ```
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::sync::mpsc;
+
+use crate::transport::{Transport, TransportFuture};
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// Maximum payload of a UDP datagram over IPv4; larger datagrams are rejected, like by a real socket
+const MAX_DATAGRAM_SIZE: usize = 65507;
+
+/// Behavior of the link from one address to another.
+///
+/// The default link delivers every datagram once, immediately.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LinkConfig {
+    /// Probability that a datagram is lost
+    pub drop_probability: f64,
+    /// Probability that a datagram is delivered twice
+    pub duplicate_probability: f64,
+    /// Delay before a datagram is delivered
+    pub latency: Duration,
+    /// Maximum additional random delay of each datagram; datagrams get reordered when it is
+    /// larger than the interval between them
+    pub jitter: Duration,
+}
+
+struct Network {
+    sockets: HashMap<SocketAddr, mpsc::UnboundedSender<Datagram>>,
+    default_link: LinkConfig,
+    links: HashMap<(IpAddr, IpAddr), LinkConfig>,
+    /// Pairs of addresses that cannot reach each other
+    cuts: HashSet<(IpAddr, IpAddr)>,
+    rng: StdRng,
+}
+
+/// In-memory network, shared by all the [`SimSocket`]s bound on it.
+///
+/// Cloning the network gives another handle to the same network.
+#[derive(Clone)]
+pub struct SimNetwork {
+    network: Arc<Mutex<Network>>,
+}
+
+impl SimNetwork {
+    /// Create an empty network, whose random behaviors are drawn from the given seed.
+    pub fn new(seed: u64) -> Self {
+        SimNetwork {
+            network: Arc::new(Mutex::new(Network {
+                sockets: HashMap::new(),
+                default_link: LinkConfig::default(),
+                links: HashMap::new(),
+                cuts: HashSet::new(),
+                rng: StdRng::seed_from_u64(seed),
+            })),
+        }
+    }
+
+    /// Bind a socket to the given address, until it is dropped.
+    pub fn bind(&self, addr: SocketAddr) -> std::io::Result<SimSocket> {
+        let mut network = self.network.lock();
+        if network.sockets.contains_key(&addr) {
+            return Err(std::io::ErrorKind::AddrInUse.into());
+        }
+        let (sender, receiver) = mpsc::unbounded_channel();
+        network.sockets.insert(addr, sender);
+        Ok(SimSocket {
+            addr,
+            network: self.network.clone(),
+            receiver: tokio::sync::Mutex::new(receiver),
+        })
+    }
+
+    /// Set the behavior of the links that were not configured with
+    /// [`set_link`](SimNetwork::set_link).
+    pub fn set_default_link(&self, link: LinkConfig) {
+        check_link(&link);
+        self.network.lock().default_link = link;
+    }
+
+    /// Set the behavior of the link from one address to another; the reverse link is unchanged.
+    pub fn set_link(&self, from: IpAddr, to: IpAddr, link: LinkConfig) {
+        check_link(&link);
+        self.network.lock().links.insert((from, to), link);
+    }
+
+    /// Drop all the datagrams between the addresses of each side, in both directions.
+    pub fn partition(&self, side1: &[IpAddr], side2: &[IpAddr]) {
+        let mut network = self.network.lock();
+        for &addr1 in side1 {
+            for &addr2 in side2 {
+                network.cuts.insert((addr1, addr2));
+                network.cuts.insert((addr2, addr1));
+            }
+        }
+    }
+
+    /// Remove all the partitions.
+    pub fn heal(&self) {
+        self.network.lock().cuts.clear();
+    }
+}
+
+fn check_link(link: &LinkConfig) {
+    assert!(
+        (0. ..=1.).contains(&link.drop_probability)
+            && (0. ..=1.).contains(&link.duplicate_probability),
+        "probabilities must be between 0 and 1"
+    );
+}
+
+/// Socket bound on a [`SimNetwork`].
+pub struct SimSocket {
+    addr: SocketAddr,
+    network: Arc<Mutex<Network>>,
+    receiver: tokio::sync::Mutex<mpsc::UnboundedReceiver<Datagram>>,
+}
+
+impl Transport for SimSocket {
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> TransportFuture<'a, usize> {
+        if buf.len() > MAX_DATAGRAM_SIZE {
+            return Box::pin(async { Err(std::io::ErrorKind::InvalidInput.into()) });
+        }
+        let deliveries = {
+            let mut network = self.network.lock();
+            let network = &mut *network;
+            let from = self.addr.ip();
+            let link = network
+                .links
+                .get(&(from, target.ip()))
+                .unwrap_or(&network.default_link);
+            match network.sockets.get(&target) {
+                Some(sender)
+                    if !network.cuts.contains(&(from, target.ip()))
+                        && !network.rng.gen_bool(link.drop_probability) =>
+                {
+                    let copies = 1 + network.rng.gen_bool(link.duplicate_probability) as usize;
+                    (0..copies)
+                        .map(|_| {
+                            let jitter = link.jitter.mul_f64(network.rng.gen());
+                            (sender.clone(), link.latency + jitter)
+                        })
+                        .collect()
+                }
+                // unreachable, or lost
+                _ => Vec::new(),
+            }
+        };
+        for (sender, delay) in deliveries {
+            let datagram = (buf.to_vec(), self.addr);
+            if delay.is_zero() {
+                let _ = sender.send(datagram);
+            } else {
+                tokio::spawn(async move {
+                    tokio::time::sleep(delay).await;
+                    let _ = sender.send(datagram);
+                });
+            }
+        }
+        Box::pin(async move { Ok(buf.len()) })
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, SocketAddr)> {
+        Box::pin(async move {
+            let Some((data, from)) = self.receiver.lock().await.recv().await else {
+                return Err(std::io::ErrorKind::NotConnected.into());
+            };
+            let size = data.len().min(buf.len());
+            buf[..size].copy_from_slice(&data[..size]);
+            Ok((size, from))
+        })
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+impl Drop for SimSocket {
+    fn drop(&mut self) {
+        self.network.lock().sockets.remove(&self.addr);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use std::time::Duration;
+
+    use super::{LinkConfig, SimNetwork, SimSocket};
+    use crate::transport::Transport;
+
+    #[tokio::test]
+    async fn links() {
+        let network = SimNetwork::new(42);
+        let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+        let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+        let socket1 = network.bind(addr1).unwrap();
+        let socket2 = network.bind(addr2).unwrap();
+        assert!(network.bind(addr1).is_err());
+        let mut buf = [0; 16];
+        async fn recv(
+            socket: &SimSocket,
+            buf: &mut [u8],
+        ) -> Result<std::io::Result<(usize, SocketAddr)>, tokio::time::error::Elapsed> {
+            tokio::time::timeout(Duration::from_millis(50), socket.recv_from(buf)).await
+        }
+
+        // perfect link
+        socket1.send_to(b"Hello", addr2).await.unwrap();
+        let (size, from) = recv(&socket2, &mut buf).await.unwrap().unwrap();
+        assert_eq!((&buf[..size], from), (&b"Hello"[..], addr1));
+
+        // truncation
+        socket1.send_to(&[1; 32], addr2).await.unwrap();
+        let (size, _) = recv(&socket2, &mut buf).await.unwrap().unwrap();
+        assert_eq!(size, 16);
+        assert!(socket1.send_to(&[1; 65508], addr2).await.is_err());
+
+        // lossy, duplicating link, in one direction only
+        let lossy = LinkConfig {
+            drop_probability: 1.,
+            ..Default::default()
+        };
+        network.set_link(addr1.ip(), addr2.ip(), lossy);
+        socket1.send_to(b"Hello", addr2).await.unwrap();
+        assert!(recv(&socket2, &mut buf).await.is_err());
+        socket2.send_to(b"Hello", addr1).await.unwrap();
+        assert!(recv(&socket1, &mut buf).await.is_ok());
+        let duplicating = LinkConfig {
+            duplicate_probability: 1.,
+            latency: Duration::from_millis(10),
+            ..Default::default()
+        };
+        network.set_link(addr1.ip(), addr2.ip(), duplicating);
+        socket1.send_to(b"Hello", addr2).await.unwrap();
+        assert!(recv(&socket2, &mut buf).await.is_ok());
+        assert!(recv(&socket2, &mut buf).await.is_ok());
+        assert!(recv(&socket2, &mut buf).await.is_err());
+
+        // partition
+        network.partition(&[addr1.ip()], &[addr2.ip()]);
+        socket2.send_to(b"Hello", addr1).await.unwrap();
+        assert!(recv(&socket1, &mut buf).await.is_err());
+        network.heal();
+        socket2.send_to(b"Hello", addr1).await.unwrap();
+        assert!(recv(&socket1, &mut buf).await.is_ok());
+
+        // unbound address
+        drop(socket1);
+        socket2.send_to(b"Hello", addr1).await.unwrap();
+        assert!(network.bind(addr1).is_ok());
+    }
+}
@@ -0,0 +1,116 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`ClockOffsets`], which estimates the skew between the clock of each peer and the
+//! local one.
+//!
+//! The conflicts between dated values are settled by their timestamps, so an instance whose clock
+//! is ahead wins all of them, and one whose clock is behind cannot overwrite the values it just
+//! received. Each session opening carries the time of the sender, and the difference with the
+//! time it is received is averaged over the sessions, the network latency included.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+/// Skew beyond which a warning is logged, by default
+pub(crate) const DEFAULT_WARNING_THRESHOLD: Duration = Duration::from_secs(1);
+/// Weight of each new sample in the average
+const SMOOTHING: f64 = 0.2;
+
+pub(crate) struct ClockOffsets {
+    /// Average of the time of reception minus the time of sending, in microseconds, for each peer
+    offsets: HashMap<SocketAddr, f64>,
+    pub warning_threshold: Duration,
+}
+
+impl ClockOffsets {
+    pub fn new() -> Self {
+        ClockOffsets {
+            offsets: HashMap::new(),
+            warning_threshold: DEFAULT_WARNING_THRESHOLD,
+        }
+    }
+
+    /// Record a datagram sent by the peer at `sent`, according to its clock, and received at
+    /// `received`, according to the local one.
+    ///
+    /// Return the new offset of the peer if it just went beyond the warning threshold.
+    pub fn record(
+        &mut self,
+        peer: SocketAddr,
+        sent: DateTime<Utc>,
+        received: DateTime<Utc>,
+    ) -> Option<chrono::Duration> {
+        let sample = (received - sent).num_microseconds().unwrap_or(i64::MAX) as f64;
+        let threshold = self.warning_threshold.as_micros() as f64;
+        let (previous, offset) = match self.offsets.get_mut(&peer) {
+            Some(offset) => {
+                let previous = *offset;
+                *offset += SMOOTHING * (sample - *offset);
+                (Some(previous), *offset)
+            }
+            None => (None, *self.offsets.entry(peer).or_insert(sample)),
+        };
+        let was_beyond = previous.is_some_and(|previous| previous.abs() > threshold);
+        (offset.abs() > threshold && !was_beyond)
+            .then(|| chrono::Duration::microseconds(offset as i64))
+    }
+
+    /// Estimated offset of each peer: the local time minus the time of the peer, so positive when
+    /// the clock of the peer is behind.
+    pub fn get(&self) -> HashMap<SocketAddr, chrono::Duration> {
+        self.offsets
+            .iter()
+            .map(|(&peer, &offset)| (peer, chrono::Duration::microseconds(offset as i64)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use chrono::{Duration, Utc};
+
+    use super::ClockOffsets;
+
+    #[test]
+    fn offsets() {
+        let mut offsets = ClockOffsets::new();
+        let peer: SocketAddr = "127.0.0.1:8080".parse().unwrap();
+        let now = Utc::now();
+
+        // a small skew does not warn
+        assert_eq!(
+            offsets.record(peer, now, now + Duration::milliseconds(10)),
+            None
+        );
+        assert_eq!(offsets.get()[&peer], Duration::milliseconds(10));
+
+        // the peer's clock jumps 5 minutes ahead: the average moves towards it and warns once
+        let ahead = now + Duration::minutes(5);
+        let warning = offsets.record(peer, ahead, now).unwrap();
+        assert!(warning < Duration::seconds(-1));
+        assert_eq!(offsets.record(peer, ahead, now), None);
+        for _ in 0..100 {
+            offsets.record(peer, ahead, now);
+        }
+        let offset = offsets.get()[&peer];
+        assert!((offset + Duration::minutes(5)).num_milliseconds().abs() < 10);
+
+        // then is fixed
+        for _ in 0..100 {
+            offsets.record(peer, now, now);
+        }
+        assert!(offsets.get()[&peer].num_milliseconds().abs() < 10);
+        assert!(offsets.record(peer, ahead, now).is_some());
+    }
+}
@@ -0,0 +1,155 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Saves and loads snapshots of a whole map, used by
+//! [`Service::save_snapshot`](crate::service::Service::save_snapshot) and
+//! [`Service::load_snapshot`](crate::service::Service::load_snapshot).
+//!
+//! A snapshot is the serialization of the map as a sequence of key-value pairs in key order, as
+//! for an [`HRTree`](crate::HRTree), encoded by `bincode` with fixed-size integers. This way, the
+//! number of entries at the start of the file can be written once all the entries are known.
+//!
+//! A snapshot can also be streamed to another host with
+//! [`Service::serve_snapshot`](crate::service::Service::serve_snapshot), and loaded there with
+//! [`Service::bootstrap_from`](crate::service::Service::bootstrap_from). Since a stream cannot be
+//! rewritten, it is made of batches of key-value pairs encoded the same way, each prefixed by its
+//! size in bytes, and ends with an empty batch.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+
+use bincode::{DefaultOptions, Options};
+use parking_lot::RwLock;
+use serde::{de::DeserializeOwned, Serialize};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::debug;
+
+use crate::map::Map;
+
+fn options() -> impl Options {
+    DefaultOptions::new().with_fixint_encoding()
+}
+
+/// Write the entries of the map to the file at the given path, taking the read lock for `chunk`
+/// entries at a time.
+///
+/// The snapshot is written to a temporary file, then atomically renamed.
+pub(crate) fn save<M, P>(map: &RwLock<M>, path: P, chunk: usize) -> std::io::Result<()>
+where
+    M: Map,
+    M::Key: Clone + Serialize,
+    M::Value: Serialize,
+    P: AsRef<Path>,
+{
+    let mut tmp_path = path.as_ref().to_path_buf().into_os_string();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    let mut tmp = BufWriter::new(File::create(&tmp_path)?);
+    // placeholder for the number of entries
+    let mut count: u64 = 0;
+    options()
+        .serialize_into(&mut tmp, &count)
+        .map_err(std::io::Error::other)?;
+    let mut range = (Bound::Unbounded, Bound::Unbounded);
+    loop {
+        let entries = map.read().enumerate_range(&range, chunk);
+        for entry in &entries {
+            options()
+                .serialize_into(&mut tmp, entry)
+                .map_err(std::io::Error::other)?;
+        }
+        count += entries.len() as u64;
+        let exhausted = entries.len() < chunk;
+        match entries.into_iter().last() {
+            Some((key, _)) if !exhausted => range.0 = Bound::Excluded(key),
+            _ => break,
+        }
+    }
+    let mut tmp = tmp.into_inner().map_err(|err| err.into_error())?;
+    tmp.seek(SeekFrom::Start(0))?;
+    options()
+        .serialize_into(&mut tmp, &count)
+        .map_err(std::io::Error::other)?;
+    tmp.flush()?;
+    tmp.sync_all()?;
+    std::fs::rename(&tmp_path, path.as_ref())?;
+    debug!("saved {count} entries to {}", path.as_ref().display());
+    Ok(())
+}
+
+/// Write the entries of the map to the writer as a stream of batches of `chunk` entries, each
+/// read under the read lock.
+///
+/// Return the number of entries written.
+pub(crate) async fn write_stream<M, W>(
+    map: &RwLock<M>,
+    writer: &mut W,
+    chunk: usize,
+) -> std::io::Result<u64>
+where
+    M: Map,
+    M::Key: Clone + Serialize,
+    M::Value: Serialize,
+    W: AsyncWrite + Unpin,
+{
+    let mut count = 0;
+    let mut range = (Bound::Unbounded, Bound::Unbounded);
+    loop {
+        let entries = map.read().enumerate_range(&range, chunk);
+        if !entries.is_empty() {
+            let batch = options()
+                .serialize(&entries)
+                .map_err(std::io::Error::other)?;
+            writer.write_u64_le(batch.len() as u64).await?;
+            writer.write_all(&batch).await?;
+        }
+        count += entries.len() as u64;
+        let exhausted = entries.len() < chunk;
+        match entries.into_iter().last() {
+            Some((key, _)) if !exhausted => range.0 = Bound::Excluded(key),
+            _ => break,
+        }
+    }
+    writer.write_u64_le(0).await?;
+    writer.flush().await?;
+    debug!("streamed {count} entries");
+    Ok(count)
+}
+
+/// Read the next batch of a stream written by [`write_stream`], or `None` at its end.
+pub(crate) async fn read_batch<K, V, R>(reader: &mut R) -> std::io::Result<Option<Vec<(K, V)>>>
+where
+    K: DeserializeOwned,
+    V: DeserializeOwned,
+    R: AsyncRead + Unpin,
+{
+    let size = reader.read_u64_le().await?;
+    if size == 0 {
+        return Ok(None);
+    }
+    // the buffer grows with the bytes actually read, whatever the size announced
+    let mut batch = Vec::new();
+    (&mut *reader).take(size).read_to_end(&mut batch).await?;
+    if batch.len() as u64 != size {
+        return Err(std::io::ErrorKind::UnexpectedEof.into());
+    }
+    options()
+        .deserialize(&batch)
+        .map(Some)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+}
+
+/// Read the map saved in the file at the given path.
+pub(crate) fn load<M: DeserializeOwned, P: AsRef<Path>>(path: P) -> std::io::Result<M> {
+    let file = BufReader::new(File::open(path)?);
+    options()
+        .deserialize_from(file)
+        .map_err(|err| std::io::Error::new(std::io::ErrorKind::InvalidData, err))
+}
@@ -0,0 +1,607 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides the [`SpillMap`], a [`Map`] that keeps its keys in memory but its values in a log on
+//! disk, for collections whose values do not fit in memory.
+//!
+//! The in-memory [`HRTree`] only stores a [`ValueHandle`] per key: the length and a content hash
+//! of the value. Since the handle is what gets hashed, a [`SpillMap`] has the same hashes as an
+//! `HRTree<K, DatedMaybeTombstone<Blob>>` holding the same values, and both can be reconciled
+//! together.
+//!
+//! The value log is content-addressed: identical values are stored once, and records are
+//! reference-counted so that the log can be compacted once overwritten values make up most of
+//! it. The log is a scratch file: it is truncated when the map is created, and the map cannot be
+//! reopened from it after a restart (use the write-ahead log or the snapshots of the
+//! [`Service`](crate::service::Service) for that).
+//!
+//! Reading a value goes to disk, so operations that go through all the values, such as
+//! iterating over the map or the scan of the tombstones when loading a snapshot, are much slower
+//! than with an [`HRTree`].
+
+use core::hash::{Hash, Hasher};
+use std::borrow::{Borrow, Cow};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::ops::{Bound, RangeBounds};
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use parking_lot::Mutex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+use crate::diff::{DiffRange, HashRangeQueryable};
+use crate::fingerprint::{DefaultFingerprint, FingerprintStrategy};
+use crate::hrtree::HRTree;
+use crate::map::{Entries, Map, MutMap};
+use crate::service::DatedMaybeTombstone;
+
+/// By default, compact the value log once it holds more than 64 MiB of overwritten values.
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// First 16 bytes of the SHA-256 of a value.
+pub type ContentHash = [u8; 16];
+
+/// Identifies a value stored in the log of a [`SpillMap`].
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct ValueHandle {
+    len: u64,
+    hash: ContentHash,
+}
+
+impl ValueHandle {
+    /// Handle of the given value.
+    pub fn of(bytes: &[u8]) -> Self {
+        let digest = Sha256::digest(bytes);
+        let mut hash = ContentHash::default();
+        hash.copy_from_slice(&digest[..16]);
+        ValueHandle {
+            len: bytes.len() as u64,
+            hash,
+        }
+    }
+
+    /// Length of the value, in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Content hash of the value.
+    pub fn content_hash(&self) -> &ContentHash {
+        &self.hash
+    }
+}
+
+/// Opaque value of a [`SpillMap`].
+///
+/// A blob is hashed through its [`ValueHandle`], so that maps holding blobs have the same hashes
+/// as a [`SpillMap`] holding the same values.
+#[derive(Clone, Debug, Deserialize, Eq, PartialEq, Serialize)]
+pub struct Blob(pub Vec<u8>);
+
+impl Hash for Blob {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        ValueHandle::of(&self.0).hash(state);
+    }
+}
+
+impl From<Vec<u8>> for Blob {
+    fn from(bytes: Vec<u8>) -> Self {
+        Blob(bytes)
+    }
+}
+
+impl AsRef<[u8]> for Blob {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// Location of a value in the log, and number of keys referencing it
+struct Record {
+    offset: u64,
+    len: u64,
+    refs: usize,
+}
+
+/// Append-only log of the values of a [`SpillMap`], with an in-memory index.
+struct ValueLog {
+    path: PathBuf,
+    /// The file is only accessed through the mutex, so that values can be read from a shared
+    /// reference to the map
+    file: Mutex<File>,
+    size: u64,
+    live_size: u64,
+    compaction_threshold: u64,
+    index: HashMap<ContentHash, Record>,
+}
+
+impl ValueLog {
+    fn create(path: PathBuf) -> std::io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&path)?;
+        Ok(ValueLog {
+            path,
+            file: Mutex::new(file),
+            size: 0,
+            live_size: 0,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            index: HashMap::new(),
+        })
+    }
+
+    /// Store a value, or add a reference to it if it is already stored.
+    fn put(&mut self, bytes: &[u8]) -> std::io::Result<ValueHandle> {
+        let handle = ValueHandle::of(bytes);
+        if let Some(record) = self.index.get_mut(&handle.hash) {
+            record.refs += 1;
+            return Ok(handle);
+        }
+        let file = self.file.get_mut();
+        file.seek(SeekFrom::Start(self.size))?;
+        file.write_all(bytes)?;
+        self.index.insert(
+            handle.hash,
+            Record {
+                offset: self.size,
+                len: handle.len,
+                refs: 1,
+            },
+        );
+        self.size += handle.len;
+        self.live_size += handle.len;
+        Ok(handle)
+    }
+
+    /// Drop a reference to a value; its space is reclaimed at the next compaction.
+    fn release(&mut self, handle: &ValueHandle) {
+        if let Some(record) = self.index.get_mut(&handle.hash) {
+            record.refs -= 1;
+            if record.refs == 0 {
+                self.live_size -= record.len;
+                self.index.remove(&handle.hash);
+            }
+        }
+    }
+
+    fn read(&self, handle: &ValueHandle) -> std::io::Result<Vec<u8>> {
+        let Some(record) = self.index.get(&handle.hash) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                "value missing from the log",
+            ));
+        };
+        let mut bytes = vec![0; record.len as usize];
+        let mut file = self.file.lock();
+        file.seek(SeekFrom::Start(record.offset))?;
+        file.read_exact(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Whether the overwritten values exceed the threshold, and make up most of the log.
+    fn needs_compaction(&self) -> bool {
+        let garbage = self.size - self.live_size;
+        garbage > self.compaction_threshold && garbage > self.live_size
+    }
+
+    /// Rewrite the log with only the referenced values.
+    ///
+    /// The new log is written to a temporary file, then atomically renamed over the current one.
+    fn compact(&mut self) -> std::io::Result<()> {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut records: Vec<&mut Record> = self.index.values_mut().collect();
+        records.sort_unstable_by_key(|record| record.offset);
+        let file = self.file.get_mut();
+        let mut tmp = std::io::BufWriter::new(File::create(&tmp_path)?);
+        let mut offsets = Vec::with_capacity(records.len());
+        let mut size = 0;
+        for record in &records {
+            let mut bytes = vec![0; record.len as usize];
+            file.seek(SeekFrom::Start(record.offset))?;
+            file.read_exact(&mut bytes)?;
+            tmp.write_all(&bytes)?;
+            offsets.push(size);
+            size += record.len;
+        }
+        tmp.into_inner().map_err(|err| err.into_error())?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        *file = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        // only update the index once the new log is in place
+        for (record, offset) in records.into_iter().zip(offsets) {
+            record.offset = offset;
+        }
+        debug!(
+            "compacted {} from {} to {size} bytes",
+            self.path.display(),
+            self.size
+        );
+        self.size = size;
+        Ok(())
+    }
+}
+
+/// Key-value map holding its keys in memory and its values in a log on disk.
+///
+/// See the [module documentation](crate::spill) for the trade-offs.
+pub struct SpillMap<K, F: FingerprintStrategy = DefaultFingerprint> {
+    tree: HRTree<K, (DateTime<Utc>, Option<ValueHandle>), F>,
+    log: ValueLog,
+}
+
+impl<K: Hash + Ord, F: FingerprintStrategy> SpillMap<K, F> {
+    /// Create an empty map, storing its values in a log at the given path.
+    ///
+    /// The file is created, or truncated if it exists.
+    pub fn new<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Ok(SpillMap {
+            tree: HRTree::default(),
+            log: ValueLog::create(path.as_ref().to_path_buf())?,
+        })
+    }
+
+    /// Set the size in bytes of overwritten values above which the log should be compacted.
+    pub fn with_compaction_threshold(mut self, compaction_threshold: u64) -> Self {
+        self.log.compaction_threshold = compaction_threshold;
+        self
+    }
+
+    /// Handle of the value at the given key, without reading it from disk.
+    pub fn get_handle<Q: Ord + ?Sized>(
+        &self,
+        key: &Q,
+    ) -> Option<(DateTime<Utc>, Option<ValueHandle>)>
+    where
+        K: Borrow<Q>,
+    {
+        self.tree.get(key).copied()
+    }
+
+    /// Size in bytes of the value log.
+    pub fn log_size(&self) -> u64 {
+        self.log.size
+    }
+
+    fn maybe_compact(&mut self) {
+        if self.log.needs_compaction() {
+            if let Err(err) = self.log.compact() {
+                warn!("failed to compact {}: {err}", self.log.path.display());
+            }
+        }
+    }
+}
+
+/// Read the value of the given handle from the log.
+///
+/// Read errors are logged, and the value is then considered missing.
+fn load(
+    log: &ValueLog,
+    &(timestamp, handle): &(DateTime<Utc>, Option<ValueHandle>),
+) -> Option<DatedMaybeTombstone<Blob>> {
+    let Some(handle) = handle else {
+        return Some((timestamp, None));
+    };
+    match log.read(&handle) {
+        Ok(bytes) => Some((timestamp, Some(Blob(bytes)))),
+        Err(err) => {
+            warn!("failed to read value from {}: {err}", log.path.display());
+            None
+        }
+    }
+}
+
+impl<K, F> Map for SpillMap<K, F>
+where
+    K: Clone + Hash + Ord,
+    F: FingerprintStrategy,
+{
+    type Key = K;
+    type Value = DatedMaybeTombstone<Blob>;
+    type DifferenceItem = DiffRange<K>;
+
+    fn enumerate_diff_ranges(
+        &self,
+        diff_ranges: Vec<Self::DifferenceItem>,
+    ) -> Vec<(Self::Key, Self::Value)> {
+        self.enumerate_diff_ranges_iter(diff_ranges).collect()
+    }
+
+    fn enumerate_diff_ranges_iter<'a>(
+        &'a self,
+        diff_ranges: Vec<Self::DifferenceItem>,
+    ) -> Box<dyn Iterator<Item = (Self::Key, Self::Value)> + 'a>
+    where
+        K: 'a,
+    {
+        Box::new(diff_ranges.into_iter().flat_map(|diff| {
+            self.tree
+                .get_range_owned(diff)
+                .filter_map(|(k, v)| Some((k.clone(), load(&self.log, v)?)))
+        }))
+    }
+
+    fn enumerate_diff_range_chunk(
+        &self,
+        diff_range: Self::DifferenceItem,
+        limit: usize,
+    ) -> (Vec<(Self::Key, Self::Value)>, Option<Self::DifferenceItem>) {
+        let end = diff_range.1.clone();
+        let mut iter = self.enumerate_diff_ranges_iter(vec![diff_range]);
+        let items: Vec<_> = iter.by_ref().take(limit.max(1)).collect();
+        let rest = match (iter.next(), items.last()) {
+            (Some(_), Some((key, _))) => Some((Bound::Excluded(key.clone()), end)),
+            _ => None,
+        };
+        (items, rest)
+    }
+
+    fn diff_range_contains(diff_range: &Self::DifferenceItem, key: &Self::Key) -> bool {
+        diff_range.contains(key)
+    }
+
+    fn enumerate_range(
+        &self,
+        range: &(Bound<Self::Key>, Bound<Self::Key>),
+        limit: usize,
+    ) -> Vec<(Self::Key, Self::Value)> {
+        self.tree
+            .get_range(range)
+            .take(limit)
+            .filter_map(|(k, v)| Some((k.clone(), load(&self.log, v)?)))
+            .collect()
+    }
+
+    fn enumerate_by_rank(&self, start: usize, count: usize) -> Vec<(Self::Key, Self::Value)> {
+        self.tree
+            .range_by_rank(start, start.saturating_add(count))
+            .filter_map(|(k, v)| Some((k.clone(), load(&self.log, v)?)))
+            .collect()
+    }
+
+    fn get<'a, Q: Ord + ?Sized>(&'a self, key: &Q) -> Option<Cow<'a, Self::Value>>
+    where
+        K: Borrow<Q>,
+    {
+        load(&self.log, self.tree.get(key)?).map(Cow::Owned)
+    }
+
+    fn contains_key<Q: Ord + ?Sized>(&self, key: &Q) -> bool
+    where
+        K: Borrow<Q>,
+    {
+        self.tree.contains_key(key)
+    }
+
+    fn iter_entries(&self) -> Entries<'_, Self::Key, Self::Value> {
+        Box::new(
+            self.tree
+                .iter()
+                .filter_map(|(k, v)| Some((k, Cow::Owned(load(&self.log, v)?)))),
+        )
+    }
+
+    /// Insert a value at the given key, return the current value if it exists.
+    ///
+    /// If the value cannot be written to the log, the error is logged and the map is left
+    /// unchanged.
+    fn insert(&mut self, key: Self::Key, (timestamp, value): Self::Value) -> Option<Self::Value> {
+        let handle = match value {
+            None => None,
+            Some(blob) => match self.log.put(&blob.0) {
+                Ok(handle) => Some(handle),
+                Err(err) => {
+                    warn!(
+                        "failed to write value to {}: {err}",
+                        self.log.path.display()
+                    );
+                    return None;
+                }
+            },
+        };
+        let old = self.tree.insert(key, (timestamp, handle))?;
+        let old_value = load(&self.log, &old);
+        if let Some(handle) = old.1 {
+            self.log.release(&handle);
+        }
+        self.maybe_compact();
+        old_value
+    }
+
+    fn remove(&mut self, key: &Self::Key) -> Option<Self::Value> {
+        let old = self.tree.remove(key)?;
+        let old_value = load(&self.log, &old);
+        if let Some(handle) = old.1 {
+            self.log.release(&handle);
+        }
+        self.maybe_compact();
+        old_value
+    }
+
+    /// Remove all the key-value pairs for which the predicate returns `false`, and return them.
+    ///
+    /// Values that cannot be read from the log are kept.
+    fn retain<P: FnMut(&Self::Key, &Self::Value) -> bool>(
+        &mut self,
+        mut predicate: P,
+    ) -> Vec<(Self::Key, Self::Value)> {
+        let log = &self.log;
+        let removed = self.tree.retain(|k, v| match load(log, v) {
+            Some(value) => predicate(k, &value),
+            None => true,
+        });
+        let removed = removed
+            .into_iter()
+            .filter_map(|(k, v)| {
+                let value = load(&self.log, &v);
+                if let Some(handle) = v.1 {
+                    self.log.release(&handle);
+                }
+                Some((k, value?))
+            })
+            .collect();
+        self.maybe_compact();
+        removed
+    }
+}
+
+impl<K, F> MutMap for SpillMap<K, F>
+where
+    K: Clone + Hash + Ord,
+    F: FingerprintStrategy,
+{
+    fn get_mut<C: FnOnce(Option<&mut Self::Value>)>(&mut self, key: &Self::Key, callback: C) {
+        let Some(mut value) = self.tree.get(key).and_then(|v| load(&self.log, v)) else {
+            callback(None);
+            return;
+        };
+        callback(Some(&mut value));
+        Map::insert(self, key.clone(), value);
+    }
+}
+
+impl<K: Hash + Ord, F: FingerprintStrategy> HashRangeQueryable for SpillMap<K, F> {
+    type Key = K;
+    type Fingerprint = F;
+
+    fn hash<R: RangeBounds<K>>(&self, range: &R) -> F::Output {
+        self.tree.hash(range)
+    }
+
+    fn insertion_position<Q: Ord + ?Sized>(&self, key: &Q) -> usize
+    where
+        K: Borrow<Q>,
+    {
+        self.tree.insertion_position(key)
+    }
+
+    fn key_at(&self, index: usize) -> &K {
+        self.tree.key_at(index)
+    }
+
+    fn len(&self) -> usize {
+        self.tree.len()
+    }
+
+    fn hash_of<Q: Ord + ?Sized>(&self, key: &Q) -> Option<F::Output>
+    where
+        K: Borrow<Q>,
+    {
+        self.tree.hash_of(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Utc};
+
+    use super::{Blob, SpillMap};
+    use crate::diff::HashRangeQueryable;
+    use crate::hrtree::HRTree;
+    use crate::map::Map;
+    use crate::service::DatedMaybeTombstone;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("reconcile-{}-{name}", std::process::id()))
+    }
+
+    fn value(timestamp: i64, bytes: &[u8]) -> DatedMaybeTombstone<Blob> {
+        (
+            DateTime::from_timestamp(timestamp, 0).unwrap(),
+            Some(Blob(bytes.to_vec())),
+        )
+    }
+
+    #[test]
+    fn roundtrip() {
+        let path = temp_path("roundtrip.values");
+        let mut map: SpillMap<u32> = SpillMap::new(&path).unwrap();
+        assert_eq!(Map::insert(&mut map, 1, value(0, b"Hello")), None);
+        assert_eq!(Map::insert(&mut map, 2, value(0, b"World!")), None);
+        let tombstone = (Utc::now(), None);
+        assert_eq!(Map::insert(&mut map, 3, tombstone.clone()), None);
+        assert_eq!(map.get(&1).unwrap().into_owned(), value(0, b"Hello"));
+        assert_eq!(map.get(&3).unwrap().into_owned(), tombstone);
+        assert_eq!(map.get(&4), None);
+        assert_eq!(
+            map.enumerate_range(&(std::ops::Bound::Unbounded, std::ops::Bound::Unbounded), 2),
+            vec![(1, value(0, b"Hello")), (2, value(0, b"World!"))]
+        );
+        assert_eq!(map.log_size(), 11);
+        drop(map);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn overwrite_and_remove() {
+        let path = temp_path("overwrite_and_remove.values");
+        let mut map: SpillMap<u32> = SpillMap::new(&path).unwrap();
+        Map::insert(&mut map, 1, value(0, b"Hello"));
+        // identical values are stored once
+        Map::insert(&mut map, 2, value(0, b"Hello"));
+        assert_eq!(map.log_size(), 5);
+        assert_eq!(
+            Map::insert(&mut map, 1, value(1, b"Goodbye")),
+            Some(value(0, b"Hello"))
+        );
+        assert_eq!(Map::remove(&mut map, &2), Some(value(0, b"Hello")));
+        assert_eq!(Map::remove(&mut map, &2), None);
+        let removed = Map::retain(&mut map, |_, (timestamp, _)| timestamp.timestamp() == 0);
+        assert_eq!(removed, vec![(1, value(1, b"Goodbye"))]);
+        assert!(map.is_empty());
+        drop(map);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn same_hash_as_tree() {
+        let path = temp_path("same_hash_as_tree.values");
+        let mut map: SpillMap<u32> = SpillMap::new(&path).unwrap();
+        let mut tree: HRTree<u32, DatedMaybeTombstone<Blob>> = HRTree::new();
+        for i in 0..100u32 {
+            let value = value(i.into(), &i.to_be_bytes());
+            Map::insert(&mut map, i, value.clone());
+            tree.insert(i, value);
+        }
+        let tombstone = (Utc::now(), None);
+        Map::insert(&mut map, 100, tombstone.clone());
+        tree.insert(100, tombstone);
+        assert_eq!(map.hash(&..), tree.hash(&..));
+        assert_eq!(map.hash(&(10..20)), tree.hash(&(10..20)));
+        drop(map);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compaction() {
+        let path = temp_path("compaction.values");
+        let mut map: SpillMap<u32> = SpillMap::new(&path).unwrap().with_compaction_threshold(100);
+        for i in 0..100u32 {
+            Map::insert(&mut map, i % 3, value(0, &[i as u8; 10]));
+        }
+        // the log is compacted once the garbage exceeds both the threshold and the live values
+        assert!(map.log_size() <= 30 + 2 * 100);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), map.log_size());
+        assert_eq!(map.get(&0).unwrap().into_owned(), value(0, &[99; 10]));
+        assert_eq!(map.get(&1).unwrap().into_owned(), value(0, &[97; 10]));
+        assert_eq!(map.get(&2).unwrap().into_owned(), value(0, &[98; 10]));
+        drop(map);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
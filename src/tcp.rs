@@ -0,0 +1,362 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides [`TcpTransport`], a [`Transport`] sending the datagrams of the protocol as frames over
+//! persistent TCP connections, for networks where UDP is unreliable or blocked.
+//!
+//! Each datagram is sent as a frame prefixed by its length, so the service still receives whole
+//! datagrams. Each instance opens one outbound connection per peer it sends to, and accepts the
+//! connections of the peers on the port it listens on. A connection starts with the port the
+//! sender listens on, so that the datagrams received are attributed to the address the peer is
+//! reached at, as with UDP.
+//!
+//! The transport keeps the semantics of datagrams: a frame that cannot be sent, because the
+//! connection is broken or too many frames are queued, is lost. A broken connection is reopened
+//! when the next frame is sent to the peer, after a delay that doubles with each failed attempt.
+//!
+//! [`MixedTransport`] combines a UDP socket with a [`TcpTransport`] on the same port, to reach
+//! only some of the peers over TCP.
+//!
+//! ```no_run
+//! # use reconcile::{tcp::TcpTransport, HRTree, DatedMaybeTombstone, Service};
+//! # #[tokio::main(flavor = "current_thread")]
+//! # async fn main() {
+//! let transport = TcpTransport::bind("10.0.0.1:8080".parse().unwrap()).await.unwrap();
+//! let tree: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+//! let service = Service::with_transport(tree, transport, "10.0.0.0/24".parse().unwrap());
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
+
+use ipnet::IpNet;
+use parking_lot::Mutex;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpSocket, TcpStream, UdpSocket};
+use tokio::sync::mpsc;
+use tokio::task::JoinSet;
+use tokio::time::{timeout, Instant};
+use tracing::{debug, trace, warn};
+
+use crate::transport::{Transport, TransportFuture};
+
+type Datagram = (Vec<u8>, SocketAddr);
+
+/// Maximum payload of a frame, as for a UDP datagram over IPv4
+const MAX_FRAME_SIZE: usize = 65507;
+/// Sent at the start of each connection, followed by the port the sender listens on
+const HELLO_MAGIC: &[u8; 4] = b"RCT1";
+/// Number of frames queued for each peer before new ones are dropped
+const OUTGOING_QUEUE: usize = 256;
+/// Number of frames received and not read yet before new ones are dropped
+const INCOMING_QUEUE: usize = 1024;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+const INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+/// Close the outbound connection to a peer after this time without sending to it
+const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// [`Transport`] over persistent TCP connections.
+///
+/// The connections are accepted while receiving, and closed when the transport is dropped.
+pub struct TcpTransport {
+    listener: TcpListener,
+    local_addr: SocketAddr,
+    incoming: tokio::sync::Mutex<Incoming>,
+    /// Queues of the frames to send to each peer, emptied by the task holding the connection;
+    /// the task ends once the queue is dropped
+    outgoing: Mutex<HashMap<SocketAddr, mpsc::Sender<Vec<u8>>>>,
+}
+
+/// Frames received by the tasks reading the incoming connections
+struct Incoming {
+    sender: mpsc::Sender<Datagram>,
+    receiver: mpsc::Receiver<Datagram>,
+    readers: JoinSet<()>,
+}
+
+impl TcpTransport {
+    /// Listen for connections on the given address.
+    pub async fn bind(addr: SocketAddr) -> std::io::Result<Self> {
+        let listener = TcpListener::bind(addr).await?;
+        let local_addr = listener.local_addr()?;
+        let (sender, receiver) = mpsc::channel(INCOMING_QUEUE);
+        Ok(TcpTransport {
+            listener,
+            local_addr,
+            incoming: tokio::sync::Mutex::new(Incoming {
+                sender,
+                receiver,
+                readers: JoinSet::new(),
+            }),
+            outgoing: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Receive the next frame, along with the address of its sender, accepting the incoming
+    /// connections in the meantime.
+    async fn recv_frame(&self) -> std::io::Result<Datagram> {
+        let mut incoming = self.incoming.lock().await;
+        let Incoming {
+            sender,
+            receiver,
+            readers,
+        } = &mut *incoming;
+        loop {
+            tokio::select! {
+                Some(frame) = receiver.recv() => return Ok(frame),
+                accepted = self.listener.accept() => match accepted {
+                    Ok((stream, peer)) => {
+                        trace!("accepted connection from {peer}");
+                        let sender = sender.clone();
+                        readers.spawn(async move {
+                            if let Err(err) = recv_frames(stream, peer.ip(), sender).await {
+                                debug!("connection from {peer} closed: {err}");
+                            }
+                        });
+                    }
+                    Err(err) => {
+                        // e.g. too many open files; wait for some connections to close
+                        warn!("failed to accept connection: {err}");
+                        tokio::time::sleep(INITIAL_BACKOFF).await;
+                    }
+                },
+                // reap the closed connections
+                Some(_) = readers.join_next() => (),
+            }
+        }
+    }
+
+    /// Queue the frame for the task connected to the target, starting it if needed.
+    fn queue_frame(&self, frame: Vec<u8>, target: SocketAddr) {
+        let mut outgoing = self.outgoing.lock();
+        let sender = match outgoing.get(&target) {
+            Some(sender) if !sender.is_closed() => sender,
+            _ => {
+                // forget the peers whose connection was closed for inactivity
+                outgoing.retain(|_, sender| !sender.is_closed());
+                let (sender, receiver) = mpsc::channel(OUTGOING_QUEUE);
+                tokio::spawn(send_frames(self.local_addr, target, receiver));
+                outgoing.insert(target, sender);
+                &outgoing[&target]
+            }
+        };
+        if sender.try_send(frame).is_err() {
+            trace!("dropping frame to {target}: queue full");
+        }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> TransportFuture<'a, usize> {
+        Box::pin(async move {
+            if buf.len() > MAX_FRAME_SIZE {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "frame too large",
+                ));
+            }
+            self.queue_frame(buf.to_vec(), target);
+            Ok(buf.len())
+        })
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, SocketAddr)> {
+        Box::pin(async move {
+            let (frame, from) = self.recv_frame().await?;
+            let size = frame.len().min(buf.len());
+            buf[..size].copy_from_slice(&frame[..size]);
+            Ok((size, from))
+        })
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// Read the frames of an incoming connection until it is closed.
+async fn recv_frames(
+    mut stream: TcpStream,
+    peer_ip: IpAddr,
+    sender: mpsc::Sender<Datagram>,
+) -> std::io::Result<()> {
+    let mut hello = [0; 6];
+    stream.read_exact(&mut hello).await?;
+    if &hello[..4] != HELLO_MAGIC {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "unexpected hello",
+        ));
+    }
+    let from = SocketAddr::new(peer_ip, u16::from_be_bytes([hello[4], hello[5]]));
+    loop {
+        let len = match stream.read_u32().await {
+            Ok(len) => len as usize,
+            Err(err) if err.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        if len > MAX_FRAME_SIZE {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("frame of {len} bytes"),
+            ));
+        }
+        let mut frame = vec![0; len];
+        stream.read_exact(&mut frame).await?;
+        match sender.try_send((frame, from)) {
+            Ok(()) => (),
+            Err(mpsc::error::TrySendError::Full(_)) => {
+                trace!("dropping frame from {from}: queue full");
+            }
+            Err(mpsc::error::TrySendError::Closed(_)) => return Ok(()),
+        }
+    }
+}
+
+/// Send the queued frames to the target, connecting when needed, until no frame is queued for
+/// [`IDLE_TIMEOUT`].
+///
+/// The frames queued while the target cannot be reached are dropped.
+async fn send_frames(
+    local_addr: SocketAddr,
+    target: SocketAddr,
+    mut receiver: mpsc::Receiver<Vec<u8>>,
+) {
+    let mut stream = None;
+    let mut backoff = INITIAL_BACKOFF;
+    let mut retry_at = Instant::now();
+    while let Ok(Some(frame)) = timeout(IDLE_TIMEOUT, receiver.recv()).await {
+        let connected = match &mut stream {
+            Some(stream) => stream,
+            None if Instant::now() < retry_at => continue,
+            None => match connect(local_addr, target).await {
+                Ok(connected) => {
+                    debug!("connected to {target}");
+                    backoff = INITIAL_BACKOFF;
+                    stream.insert(connected)
+                }
+                Err(err) => {
+                    debug!("failed to connect to {target}: {err}");
+                    retry_at = Instant::now() + backoff;
+                    backoff = (2 * backoff).min(MAX_BACKOFF);
+                    continue;
+                }
+            },
+        };
+        let written = async {
+            connected.write_u32(frame.len() as u32).await?;
+            connected.write_all(&frame).await
+        };
+        if let Err(err) = written.await {
+            debug!("connection to {target} closed: {err}");
+            stream = None;
+        }
+    }
+}
+
+/// Open a connection to the target, from the address of the listener if it is specified, and
+/// announce the port of the listener.
+async fn connect(local_addr: SocketAddr, target: SocketAddr) -> std::io::Result<TcpStream> {
+    let socket = match target {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if !local_addr.ip().is_unspecified() && local_addr.is_ipv4() == target.is_ipv4() {
+        socket.bind(SocketAddr::new(local_addr.ip(), 0))?;
+    }
+    let mut stream = timeout(CONNECT_TIMEOUT, socket.connect(target))
+        .await
+        .map_err(|_| std::io::Error::from(std::io::ErrorKind::TimedOut))??;
+    stream.set_nodelay(true)?;
+    let mut hello = [0; 6];
+    hello[..4].copy_from_slice(HELLO_MAGIC);
+    hello[4..].copy_from_slice(&local_addr.port().to_be_bytes());
+    stream.write_all(&hello).await?;
+    Ok(stream)
+}
+
+/// [`Transport`] reaching the peers of a network over TCP, and the other ones over UDP.
+///
+/// The UDP socket and the TCP listener should be bound to the same address, so that the peers are
+/// known by the same address over both.
+pub struct MixedTransport {
+    udp: UdpSocket,
+    tcp: TcpTransport,
+    tcp_net: IpNet,
+}
+
+impl MixedTransport {
+    /// Combine the transports, sending to the peers in `tcp_net` over TCP.
+    pub fn new(udp: UdpSocket, tcp: TcpTransport, tcp_net: IpNet) -> Self {
+        MixedTransport { udp, tcp, tcp_net }
+    }
+}
+
+impl Transport for MixedTransport {
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> TransportFuture<'a, usize> {
+        if self.tcp_net.contains(&target.ip()) {
+            self.tcp.send_to(buf, target)
+        } else {
+            Transport::send_to(&self.udp, buf, target)
+        }
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, SocketAddr)> {
+        Box::pin(async move {
+            let (frame, from) = tokio::select! {
+                received = self.udp.recv_from(buf) => return received,
+                received = self.tcp.recv_frame() => received?,
+            };
+            let size = frame.len().min(buf.len());
+            buf[..size].copy_from_slice(&frame[..size]);
+            Ok((size, from))
+        })
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.udp.local_addr()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use super::TcpTransport;
+    use crate::transport::Transport;
+
+    #[tokio::test]
+    async fn frames() {
+        let addr1: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let transport1 = TcpTransport::bind(addr1).await.unwrap();
+        let transport2 = TcpTransport::bind(addr1).await.unwrap();
+        let addr1 = transport1.local_addr().unwrap();
+        let addr2 = transport2.local_addr().unwrap();
+
+        // the boundaries of the frames are preserved
+        let mut buf = vec![0; 100];
+        transport1.send_to(b"Hello", addr2).await.unwrap();
+        transport1.send_to(b"World!", addr2).await.unwrap();
+        assert_eq!(transport2.recv_from(&mut buf).await.unwrap(), (5, addr1));
+        assert_eq!(&buf[..5], b"Hello");
+        assert_eq!(transport2.recv_from(&mut buf).await.unwrap(), (6, addr1));
+        assert_eq!(&buf[..6], b"World!");
+
+        // replies go over another connection, and are attributed to the listening address
+        transport2.send_to(b"Goodbye", addr1).await.unwrap();
+        assert_eq!(transport1.recv_from(&mut buf).await.unwrap(), (7, addr2));
+
+        // larger frames are truncated to the buffer
+        transport1.send_to(&[1; 200], addr2).await.unwrap();
+        assert_eq!(transport2.recv_from(&mut buf).await.unwrap(), (100, addr1));
+        assert!(transport1.send_to(&[1; 70_000], addr2).await.is_err());
+    }
+}
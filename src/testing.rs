@@ -0,0 +1,76 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides helpers to check, in tests, the types used with an [`HRTree`](crate::HRTree).
+
+use std::hash::Hash;
+
+use crate::fingerprint::{DefaultFingerprint, FingerprintStrategy, Sum128Fingerprint};
+
+/// Check that two keys give the same element hashes with the fingerprint strategies, as needed
+/// when one computes the hashes expected from a tree holding the other, or when peers use both
+/// types.
+///
+/// Panics with both hashes otherwise.
+/// ```
+/// use reconcile::testing::assert_key_hash_compat;
+///
+/// // a `String` hashes as the `str` it holds
+/// assert_key_hash_compat(&"a", &String::from("a"));
+/// ```
+pub fn assert_key_hash_compat<A: Hash + ?Sized, B: Hash + ?Sized>(a: &A, b: &B) {
+    let default = (
+        DefaultFingerprint::hash(&a, &()),
+        DefaultFingerprint::hash(&b, &()),
+    );
+    assert!(
+        default.0 == default.1,
+        "keys hash differently: {} != {}; see `HRTree::with_canonical_hashing`",
+        default.0,
+        default.1
+    );
+    let sum128 = (
+        Sum128Fingerprint::hash(&a, &()),
+        Sum128Fingerprint::hash(&b, &()),
+    );
+    assert!(
+        sum128.0 == sum128.1,
+        "keys hash differently: {} != {}; see `HRTree::with_canonical_hashing`",
+        sum128.0,
+        sum128.1
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use std::hash::{Hash, Hasher};
+
+    use super::assert_key_hash_compat;
+
+    /// Wraps a string, but does not hash it as `str` does
+    struct Name(String);
+
+    impl Hash for Name {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            state.write(self.0.as_bytes());
+        }
+    }
+
+    #[test]
+    fn compatible_keys() {
+        assert_key_hash_compat(&"a", &String::from("a"));
+        assert_key_hash_compat("a", &String::from("a"));
+        assert_key_hash_compat(&(1u32, "a"), &(1u32, String::from("a")));
+    }
+
+    #[test]
+    #[should_panic(expected = "keys hash differently")]
+    fn incompatible_keys() {
+        assert_key_hash_compat(&String::from("a"), &Name(String::from("a")));
+    }
+}
@@ -7,19 +7,52 @@ use chrono::{DateTime, Utc};
 
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(60);
 
+/// Position of an element in the wheel; the sequence number tells apart the elements inserted
+/// with the same instant
+type Slot = (DateTime<Utc>, u64);
+
+/// Where an element is tracked
+#[derive(Clone, Copy)]
+enum Position {
+    /// By instant of insertion, expiring after the timeout of the wheel
+    Default(Slot),
+    /// By deadline, for the elements with their own timeout, along with the instant of insertion
+    Custom(Slot, DateTime<Utc>),
+}
+
 #[derive(Default)]
-pub(crate) struct TimeoutWheel<T: Clone + Hash + std::cmp::Eq> {
-    wheel: Arc<RwLock<BTreeMap<DateTime<Utc>, T>>>,
-    map: Arc<RwLock<HashMap<T, DateTime<Utc>>>>,
+struct Inner<T> {
+    wheel: BTreeMap<Slot, T>,
+    custom: BTreeMap<Slot, T>,
+    map: HashMap<T, Position>,
+    next_seq: u64,
     timeout: Duration,
 }
 
+impl<T: Clone + Hash + std::cmp::Eq> Inner<T> {
+    fn remove(&mut self, value: &T) -> Option<T> {
+        match self.map.remove(value)? {
+            Position::Default(slot) => self.wheel.remove(&slot),
+            Position::Custom(slot, _) => self.custom.remove(&slot),
+        }
+    }
+
+    fn next_slot(&mut self, instant: DateTime<Utc>) -> Slot {
+        let slot = (instant, self.next_seq);
+        self.next_seq += 1;
+        slot
+    }
+}
+
+#[derive(Default)]
+pub(crate) struct TimeoutWheel<T: Clone + Hash + std::cmp::Eq> {
+    inner: Arc<RwLock<Inner<T>>>,
+}
+
 impl<T: Clone + Hash + std::cmp::Eq> Clone for TimeoutWheel<T> {
     fn clone(&self) -> Self {
         TimeoutWheel {
-            wheel: self.wheel.clone(),
-            map: self.map.clone(),
-            timeout: self.timeout,
+            inner: self.inner.clone(),
         }
     }
 }
@@ -27,40 +60,129 @@ impl<T: Clone + Hash + std::cmp::Eq> Clone for TimeoutWheel<T> {
 impl<T: Clone + Hash + std::cmp::Eq> TimeoutWheel<T> {
     pub fn new() -> Self {
         TimeoutWheel {
-            wheel: Arc::new(RwLock::new(BTreeMap::new())),
-            map: Arc::new(RwLock::new(HashMap::new())),
-            timeout: DEFAULT_TIMEOUT,
+            inner: Arc::new(RwLock::new(Inner {
+                wheel: BTreeMap::new(),
+                custom: BTreeMap::new(),
+                map: HashMap::new(),
+                next_seq: 0,
+                timeout: DEFAULT_TIMEOUT,
+            })),
         }
     }
 
-    pub fn with_timeout(mut self, timeout: Duration) -> Self {
-        self.timeout = timeout;
+    pub fn with_timeout(self, timeout: Duration) -> Self {
+        self.set_timeout(timeout);
         self
     }
 
+    /// Change the timeout, including for the elements already tracked, except the ones with their
+    /// own timeout.
+    pub fn set_timeout(&self, timeout: Duration) {
+        self.inner.write().unwrap().timeout = timeout;
+    }
+
+    /// Timeout of the elements without their own timeout.
+    pub fn timeout(&self) -> Duration {
+        self.inner.read().unwrap().timeout
+    }
+
+    /// Track the element from the given instant; an element already tracked is re-armed.
     pub fn insert(&self, e: T, instant: DateTime<Utc>) {
-        self.wheel.write().unwrap().insert(instant, e.clone());
-        self.map.write().unwrap().insert(e, instant);
+        let mut inner = self.inner.write().unwrap();
+        inner.remove(&e);
+        let slot = inner.next_slot(instant);
+        inner.map.insert(e.clone(), Position::Default(slot));
+        inner.wheel.insert(slot, e);
+    }
+
+    /// Give its own timeout to a tracked element, counted from the instant it was inserted with;
+    /// return whether the element is tracked.
+    pub fn set_element_timeout(&self, e: &T, timeout: Duration) -> bool {
+        let mut inner = self.inner.write().unwrap();
+        let instant = match inner.map.get(e) {
+            Some(Position::Default((instant, _)) | Position::Custom(_, instant)) => *instant,
+            None => return false,
+        };
+        let Some(value) = inner.remove(e) else {
+            return false;
+        };
+        let slot = inner.next_slot(instant + timeout);
+        inner
+            .map
+            .insert(value.clone(), Position::Custom(slot, instant));
+        inner.custom.insert(slot, value);
+        true
     }
 
-    pub fn pop_expired(&self) -> Option<T> {
-        self.wheel
-            .write()
-            .unwrap()
+    pub fn pop_expired(&self, now: DateTime<Utc>) -> Option<T> {
+        let mut inner = self.inner.write().unwrap();
+        let timeout = inner.timeout;
+        let value = match inner
+            .wheel
             .first_entry()
-            .filter(|entry| *entry.key() + self.timeout < Utc::now())
-            .map(|entry| {
-                let value = entry.remove();
-                self.map.write().unwrap().remove(&value);
-                value
-            })
+            .filter(|entry| entry.key().0 + timeout < now)
+        {
+            Some(entry) => entry.remove(),
+            None => inner
+                .custom
+                .first_entry()
+                .filter(|entry| entry.key().0 < now)
+                .map(|entry| entry.remove())?,
+        };
+        inner.map.remove(&value);
+        Some(value)
     }
 
     pub fn remove(&self, value: &T) -> Option<T> {
-        self.map
-            .write()
-            .unwrap()
-            .remove(value)
-            .and_then(|instant| self.wheel.write().unwrap().remove(&instant))
+        self.inner.write().unwrap().remove(value)
+    }
+
+    /// Number of elements tracked.
+    pub fn len(&self) -> usize {
+        self.inner.read().unwrap().map.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Utc;
+
+    use super::TimeoutWheel;
+
+    #[test]
+    fn same_instant() {
+        let wheel = TimeoutWheel::new().with_timeout(Duration::from_secs(1));
+        let instant = Utc::now();
+        for i in 0..1000 {
+            wheel.insert(i, instant);
+        }
+        assert_eq!(wheel.len(), 1000);
+
+        // the elements sharing an instant are removed and re-armed independently
+        assert_eq!(wheel.remove(&500), Some(500));
+        assert_eq!(wheel.remove(&500), None);
+        wheel.insert(0, instant + Duration::from_secs(10));
+        assert!(wheel.set_element_timeout(&1, Duration::from_secs(20)));
+        assert_eq!(wheel.len(), 999);
+
+        let mut expired = Vec::new();
+        while let Some(i) = wheel.pop_expired(instant + Duration::from_secs(2)) {
+            expired.push(i);
+        }
+        assert_eq!(expired.len(), 997);
+        assert!(expired.iter().all(|&i| i >= 2 && i != 500));
+        assert_eq!(wheel.len(), 2);
+        assert_eq!(
+            wheel.pop_expired(instant + Duration::from_secs(12)),
+            Some(0)
+        );
+        assert_eq!(wheel.pop_expired(instant + Duration::from_secs(12)), None);
+        assert_eq!(
+            wheel.pop_expired(instant + Duration::from_secs(21)),
+            Some(1)
+        );
+        assert_eq!(wheel.len(), 0);
     }
 }
@@ -0,0 +1,49 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides the [`Transport`] trait, the datagram socket used by a [`Service`](crate::Service).
+//!
+//! It is implemented by [`UdpSocket`], by [`TcpTransport`](crate::tcp::TcpTransport) which sends
+//! the datagrams over TCP connections, and by the in-memory [`SimSocket`](crate::sim::SimSocket)
+//! of the simulator.
+
+use std::future::Future;
+use std::net::SocketAddr;
+use std::pin::Pin;
+
+use tokio::net::UdpSocket;
+
+/// Future returned by the methods of [`Transport`]
+pub type TransportFuture<'a, T> = Pin<Box<dyn Future<Output = std::io::Result<T>> + Send + 'a>>;
+
+/// Unreliable datagram socket, with the semantics of UDP: datagrams can be lost, duplicated or
+/// reordered, but are never corrupted nor merged.
+pub trait Transport: Send + Sync {
+    /// Send a datagram to the given address; return the number of bytes sent.
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> TransportFuture<'a, usize>;
+    /// Receive a datagram in the buffer; return its size and the address of the sender.
+    ///
+    /// A datagram larger than the buffer is truncated.
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, SocketAddr)>;
+    /// Address the socket is bound to.
+    fn local_addr(&self) -> std::io::Result<SocketAddr>;
+}
+
+impl Transport for UdpSocket {
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> TransportFuture<'a, usize> {
+        Box::pin(UdpSocket::send_to(self, buf, target))
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, SocketAddr)> {
+        Box::pin(UdpSocket::recv_from(self, buf))
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+}
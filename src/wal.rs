@@ -0,0 +1,306 @@
+// Copyright 2023 Developers of the reconcile project.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// https://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Provides the [`Wal`], an append-only log of the changes applied to a map,
+//! used by the [`Service`](crate::service::Service) to recover its state after a restart.
+//!
+//! Each record is a key-value pair encoded with the same `bincode` options as the network
+//! protocol. Records are simply concatenated in the file.
+//!
+//! The records of a batch of changes are staged in memory while the map is locked, and written
+//! once it is released, as set by the [`WalSync`] policy.
+
+use std::borrow::Borrow;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use bincode::{DefaultOptions, Options};
+use serde::{de::DeserializeOwned, Serialize};
+use tracing::{debug, warn};
+
+/// By default, compact the log once it reaches 64 MiB.
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// When the records of the write-ahead log are made durable, see
+/// [`with_wal_sync`](crate::Service::with_wal_sync).
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum WalSync {
+    /// Write the records of each batch of changes to the file, and leave them to the operating
+    /// system; they survive a crash of the process, but not of the machine
+    #[default]
+    Write,
+    /// Also flush the file to the disk after each batch of changes
+    EveryBatch,
+}
+
+pub(crate) struct Wal<K, V> {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    compacted_size: u64,
+    compaction_threshold: u64,
+    sync: WalSync,
+    /// Records staged but not written yet
+    pending: Vec<u8>,
+    /// First error since the last call to [`sync`](Wal::sync)
+    error: Option<std::io::Error>,
+    phantom: PhantomData<(K, V)>,
+}
+
+impl<K: DeserializeOwned + Serialize, V: DeserializeOwned + Serialize> Wal<K, V> {
+    /// Open the log at the given path for appending, creating it if it does not exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Wal {
+            path,
+            file,
+            size,
+            compacted_size: 0,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            sync: WalSync::default(),
+            pending: Vec::new(),
+            error: None,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Read all the complete records of the log at the given path, in order.
+    ///
+    /// A torn final record (e.g. after a crash during a write) is truncated from the file.
+    pub fn replay<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<(K, V)>> {
+        let mut file = match OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path.as_ref())
+        {
+            Ok(file) => file,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        let mut data = Vec::new();
+        file.read_to_end(&mut data)?;
+        let mut records = Vec::new();
+        let mut remaining = data.as_slice();
+        while !remaining.is_empty() {
+            let valid = data.len() - remaining.len();
+            match DefaultOptions::new().deserialize_from(&mut remaining) {
+                Ok(record) => records.push(record),
+                Err(err) => {
+                    warn!(
+                        "truncating invalid record at offset {valid} of {}: {err}",
+                        path.as_ref().display()
+                    );
+                    file.set_len(valid as u64)?;
+                    break;
+                }
+            }
+        }
+        debug!("replayed {} records", records.len());
+        Ok(records)
+    }
+
+    /// Set the size in bytes above which the log should be compacted.
+    pub fn with_compaction_threshold(mut self, compaction_threshold: u64) -> Self {
+        self.compaction_threshold = compaction_threshold;
+        self
+    }
+
+    /// Set when the records are flushed to the disk.
+    pub fn with_sync(mut self, sync: WalSync) -> Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Stage one record, to be written by the next [`flush`](Wal::flush).
+    pub fn stage(&mut self, key: &K, value: &V) -> std::io::Result<()> {
+        let start = self.pending.len();
+        DefaultOptions::new()
+            .serialize_into(&mut self.pending, &(key, value))
+            .map_err(|err| {
+                self.pending.truncate(start);
+                std::io::Error::other(err)
+            })
+    }
+
+    /// Write the staged records at the end of the log, and flush them to the disk if the policy
+    /// says so.
+    ///
+    /// On failure, the file is cut back to its last complete record, and the records stay staged
+    /// for the next attempt.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        if !self.pending.is_empty() {
+            if let Err(err) = self.file.write_all(&self.pending) {
+                // a torn record would hide the next ones from the replay
+                let _ = self.file.set_len(self.size);
+                return Err(err);
+            }
+            self.size += self.pending.len() as u64;
+            self.pending.clear();
+        }
+        if self.sync == WalSync::EveryBatch {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+
+    /// Write the staged records and flush the file to the disk, whatever the policy.
+    ///
+    /// Fails with the first error recorded since the last call, if any.
+    pub fn sync(&mut self) -> std::io::Result<()> {
+        let result = self.flush().and_then(|()| self.file.sync_data());
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => result,
+        }
+    }
+
+    /// Keep the error for the next [`sync`](Wal::sync), unless one is kept already.
+    pub fn record_error(&mut self, err: std::io::Error) {
+        warn!("failed to write to the write-ahead log: {err}");
+        self.error.get_or_insert(err);
+    }
+
+    /// Whether the log has grown enough since the last compaction to be worth compacting.
+    ///
+    /// Since the compacted log contains the whole map, we also wait for the log to have doubled
+    /// in size to avoid compacting continuously a map that is larger than the threshold.
+    pub fn needs_compaction(&self) -> bool {
+        self.size > self.compaction_threshold && self.size > 2 * self.compacted_size
+    }
+
+    /// Rewrite the log to contain exactly the given key-value pairs.
+    ///
+    /// The new log is written to a temporary file, then atomically renamed over the current one.
+    pub fn compact<'a, I, B>(&mut self, items: I) -> std::io::Result<()>
+    where
+        K: 'a,
+        B: Borrow<V>,
+        I: IntoIterator<Item = (&'a K, B)>,
+    {
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        let mut tmp = std::io::BufWriter::new(File::create(&tmp_path)?);
+        for (key, value) in items {
+            DefaultOptions::new()
+                .serialize_into(&mut tmp, &(key, value.borrow()))
+                .map_err(std::io::Error::other)?;
+        }
+        let tmp = tmp.into_inner().map_err(|err| err.into_error())?;
+        tmp.sync_all()?;
+        std::fs::rename(&tmp_path, &self.path)?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.size = self.file.seek(SeekFrom::End(0))?;
+        self.compacted_size = self.size;
+        debug!("compacted {} to {} bytes", self.path.display(), self.size);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::{Wal, WalSync};
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let path = std::env::temp_dir().join(format!("reconcile-{}-{name}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn append_and_replay() {
+        let path = temp_path("append_and_replay.wal");
+        let mut wal = Wal::<u32, String>::open(&path).unwrap();
+        wal.stage(&1, &"Hello".to_string()).unwrap();
+        wal.stage(&2, &"World!".to_string()).unwrap();
+        // the staged records are only written once flushed
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+        wal.flush().unwrap();
+        wal.stage(&1, &"Goodbye".to_string()).unwrap();
+        wal.sync().unwrap();
+        drop(wal);
+        assert_eq!(
+            Wal::<u32, String>::replay(&path).unwrap(),
+            vec![
+                (1, "Hello".to_string()),
+                (2, "World!".to_string()),
+                (1, "Goodbye".to_string())
+            ]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn torn_record() {
+        let path = temp_path("torn_record.wal");
+        let mut wal = Wal::<u32, String>::open(&path).unwrap();
+        wal.stage(&1, &"Hello".to_string()).unwrap();
+        wal.flush().unwrap();
+        drop(wal);
+        let valid_size = std::fs::metadata(&path).unwrap().len();
+        // simulate a crash in the middle of writing a record: key 2, string of length 6, "Wor"
+        let mut file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(&[2, 6, b'W', b'o', b'r']).unwrap();
+        drop(file);
+        assert_eq!(
+            Wal::<u32, String>::replay(&path).unwrap(),
+            vec![(1, "Hello".to_string())]
+        );
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), valid_size);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compaction() {
+        let path = temp_path("compaction.wal");
+        let mut wal = Wal::<u32, u32>::open(&path)
+            .unwrap()
+            .with_compaction_threshold(16);
+        for i in 0..100 {
+            wal.stage(&(i % 3), &i).unwrap();
+        }
+        wal.flush().unwrap();
+        assert!(wal.needs_compaction());
+        wal.compact([(&0, &99), (&1, &97), (&2, &98)]).unwrap();
+        assert!(!wal.needs_compaction());
+        wal.stage(&3, &100).unwrap();
+        wal.flush().unwrap();
+        drop(wal);
+        assert_eq!(
+            Wal::<u32, u32>::replay(&path).unwrap(),
+            vec![(0, 99), (1, 97), (2, 98), (3, 100)]
+        );
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn sync_errors() {
+        let path = temp_path("sync_errors.wal");
+        let mut wal = Wal::<u32, u32>::open(&path)
+            .unwrap()
+            .with_sync(WalSync::EveryBatch);
+        wal.stage(&1, &1).unwrap();
+        wal.flush().unwrap();
+        // an error recorded by a batch is reported by the next sync, once
+        wal.record_error(std::io::Error::other("disk full"));
+        assert_eq!(wal.sync().unwrap_err().to_string(), "disk full");
+        wal.sync().unwrap();
+        drop(wal);
+        assert_eq!(Wal::<u32, u32>::replay(&path).unwrap(), vec![(1, 1)]);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
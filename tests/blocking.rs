@@ -0,0 +1,67 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use reconcile::{blocking, DatedMaybeTombstone, HRTree, HashRangeQueryable, Service};
+
+type Map = HRTree<u32, DatedMaybeTombstone<String>>;
+
+/// Wait for a while until the provided predicate becomes true, blocking the thread
+fn wait_until<F: FnMut() -> bool>(mut f: F) -> bool {
+    for _ in 0..500 {
+        std::thread::sleep(Duration::from_millis(10));
+        if f() {
+            return true;
+        }
+    }
+    false
+}
+
+#[test]
+fn blocking_service() {
+    let port = 8080;
+    // no random peer discovery
+    let peer_net = "127.255.255.254/32".parse().unwrap();
+    let addr1: IpAddr = "127.0.1.1".parse().unwrap();
+    let addr2: IpAddr = "127.0.1.2".parse().unwrap();
+
+    // no runtime is needed for the blocking service
+    let service1 = blocking::Service::new(Map::new(), port, addr1, peer_net)
+        .unwrap()
+        .configure(|service| service.with_seed(addr2));
+    let handle = service1.clone().spawn_background();
+
+    // its peer is a usual service, in its own runtime
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let service2 = runtime
+        .block_on(Service::new(Map::new(), port, addr2, peer_net))
+        .unwrap();
+    let task2 = runtime.spawn(service2.clone().run());
+
+    // writes from several threads
+    std::thread::scope(|scope| {
+        for thread in 0..4 {
+            let service1 = service1.clone();
+            scope.spawn(move || {
+                for i in 0..100 {
+                    let key = thread * 100 + i;
+                    service1.insert(key, format!("value {key}"), Utc::now());
+                }
+                service1.remove(&(thread * 100), Utc::now());
+            });
+        }
+    });
+    assert_eq!(service1.read().len(), 400);
+    assert!(service1.get(&0).is_none());
+    assert!(wait_until(|| service2.read().len() == 400));
+    assert_eq!(*service2.get(&1).unwrap(), "value 1");
+    assert!(service2.get(&100).is_none());
+
+    // and the other way around
+    service2.insert_bulk(&[(1000, "Hello".to_string(), Utc::now())]);
+    assert!(wait_until(|| service1.get(&1000).is_some()));
+
+    handle.shutdown().unwrap();
+    task2.abort();
+}
@@ -1,10 +1,17 @@
 use std::hash::Hash;
-use std::ops::Bound;
+use std::ops::{Bound, RangeBounds};
 
-use reconcile::diff::{DiffRange, Diffable, HashRangeQueryable, HashSegment};
+use rand::{Rng, SeedableRng};
+
+use reconcile::diff::{DiffRange, Diffable, HashRangeQueryable, HashSegment, MaybeParallel};
+use reconcile::fingerprint::Sum128Fingerprint;
 use reconcile::hrtree::HRTree;
 
-pub fn diff<K, D: Diffable<ComparisonItem = HashSegment<K>, DifferenceItem = DiffRange<K>>>(
+pub fn diff<
+    K,
+    H,
+    D: Diffable<ComparisonItem = HashSegment<K, H>, DifferenceItem = DiffRange<K>>,
+>(
     local: &D,
     remote: &D,
 ) -> (Vec<DiffRange<K>>, Vec<DiffRange<K>>) {
@@ -14,12 +21,12 @@ pub fn diff<K, D: Diffable<ComparisonItem = HashSegment<K>, DifferenceItem = Dif
     let mut remote_segments = Vec::new();
     while !local_segments.is_empty() {
         remote.diff_round(
-            local_segments.drain(..).collect(),
+            std::mem::take(&mut local_segments),
             &mut remote_segments,
             &mut remote_diff_ranges,
         );
         local.diff_round(
-            remote_segments.drain(..).collect(),
+            std::mem::take(&mut remote_segments),
             &mut local_segments,
             &mut local_diff_ranges,
         );
@@ -29,8 +36,8 @@ pub fn diff<K, D: Diffable<ComparisonItem = HashSegment<K>, DifferenceItem = Dif
 
 pub fn reconcile<K, V>(local: &mut HRTree<K, V>, remote: &mut HRTree<K, V>)
 where
-    K: Clone + Hash + Ord,
-    V: Clone + Hash,
+    K: Clone + Hash + MaybeParallel + Ord,
+    V: Clone + Hash + MaybeParallel,
 {
     let (diff_ranges1, diff_ranges2) = diff(local, remote);
     for diff in diff_ranges1 {
@@ -71,15 +78,15 @@ fn test_compare() {
     assert_eq!(
         diff(&tree1, &tree4),
         (
-            vec![(Bound::Included(40), Bound::Excluded(75))],
-            vec![(Bound::Included(40), Bound::Excluded(75))],
+            vec![(Bound::Excluded(40), Bound::Excluded(75))],
+            vec![(Bound::Included(40), Bound::Included(40))],
         ),
     );
     assert_eq!(
         diff(&tree1, &tree5),
         (
-            vec![(Bound::Included(75), Bound::Unbounded)],
-            vec![(Bound::Included(75), Bound::Unbounded)],
+            vec![(Bound::Included(75), Bound::Included(75))],
+            vec![(Bound::Included(75), Bound::Included(75))],
         ),
     );
 
@@ -108,3 +115,67 @@ fn test_compare() {
         ]
     )
 }
+
+#[test]
+fn test_compare_sum128() {
+    let mut tree1: HRTree<_, _, Sum128Fingerprint> = HRTree::default();
+    tree1.extend([(25, "World!"), (50, "Hello"), (75, "Everyone!")]);
+    let mut tree2: HRTree<_, _, Sum128Fingerprint> = HRTree::default();
+    tree2.extend([(75, "Everyone!"), (25, "World!"), (50, "Hello")]);
+    let mut tree3: HRTree<_, _, Sum128Fingerprint> = HRTree::default();
+    tree3.extend([(75, "Everyone!"), (25, "World!"), (40, "Hello")]);
+
+    assert_eq!(tree1.hash(&..), tree2.hash(&..));
+    assert_ne!(tree1.hash(&..), tree3.hash(&..));
+
+    assert_eq!(diff(&tree1, &tree2), (vec![], vec![]));
+    assert_eq!(
+        diff(&tree1, &tree3),
+        (
+            vec![(Bound::Excluded(40), Bound::Excluded(75))],
+            vec![(Bound::Included(40), Bound::Included(40))],
+        ),
+    );
+}
+
+#[test]
+fn test_export_segments() {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    let tree1: HRTree<u32, u32> = HRTree::from_iter((0..1000).map(|key| (key * 2, key)));
+    let mut tree2 = HRTree::from_iter((0..1000).map(|key| (key * 2, key)));
+
+    // identical trees
+    let segments = tree1.export_segments(50);
+    assert_eq!(segments.len(), 20);
+    assert!(tree2.compare_segments(&segments).is_empty());
+    assert_eq!(HRTree::<u32, u32>::new().export_segments(50).len(), 1);
+
+    // changed, added and removed keys
+    for _ in 0..10 {
+        let key = rng.gen_range(0..1000) * 2;
+        tree2.insert(key, key + 1);
+        tree2.insert(rng.gen_range(0..1000) * 2 + 1, 0);
+        tree2.remove(&(rng.gen_range(0..1000) * 2));
+    }
+    let differing: Vec<u32> = (0..2000)
+        .filter(|key| tree1.get(key) != tree2.get(key))
+        .collect();
+    assert!(!differing.is_empty());
+
+    // the offline comparison finds the same keys as the interactive protocol
+    let (ranges1, ranges2) = diff(&tree1, &tree2);
+    for max_leaf in [1, 7, 50, 5000] {
+        let ranges = tree2.compare_segments(&tree1.export_segments(max_leaf));
+        for key in &differing {
+            assert!(ranges.iter().any(|range| range.contains(key)));
+            assert!(ranges1
+                .iter()
+                .chain(&ranges2)
+                .any(|range| range.contains(key)));
+        }
+        // each range holds a difference
+        for range in &ranges {
+            assert!(differing.iter().any(|key| range.contains(key)));
+        }
+    }
+}
@@ -0,0 +1,237 @@
+//! Property-based tests of the diff protocol, checked against a naive model of the merge.
+//!
+//! The extended run, with more cases and larger trees, is enabled by the `extended-tests`
+//! feature.
+
+use std::collections::{BTreeMap, HashMap};
+
+use bincode::{DefaultOptions, Options};
+use chrono::{DateTime, Utc};
+use proptest::prelude::*;
+
+use reconcile::diff::{DiffRange, Diffable, HashRangeQueryable, HashSegment};
+use reconcile::hrtree::HRTree;
+use reconcile::reconcilable::{Reconcilable, ReconciliationResult};
+
+type Value = (DateTime<Utc>, u32);
+type Tree = HRTree<u16, Value>;
+
+const CASES: u32 = if cfg!(feature = "extended-tests") {
+    10_000
+} else {
+    16
+};
+
+/// Exclusive bound on the number of keys of the trees with a single difference
+const MAX_KEYS: usize = if cfg!(feature = "extended-tests") {
+    20_000
+} else {
+    2_000
+};
+
+/// Statistics of a run of the protocol between two trees
+struct Exchange {
+    /// Number of diff rounds
+    rounds: usize,
+    /// Bytes of the segments and of the updates sent by both sides
+    bytes: usize,
+}
+
+fn value(timestamp: i64, value: u32) -> Value {
+    (DateTime::from_timestamp(timestamp, 0).unwrap(), value)
+}
+
+fn serialized_size<T: serde::Serialize>(value: &T) -> usize {
+    DefaultOptions::new().serialized_size(value).unwrap() as usize
+}
+
+/// Apply the value received from a peer, as the service does.
+fn apply(tree: &mut Tree, key: u16, value: Value) {
+    let keep_other = tree
+        .get(&key)
+        .is_none_or(|local| local.reconcile(&value) == ReconciliationResult::KeepOther);
+    if keep_other {
+        tree.insert(key, value);
+    }
+}
+
+/// Run the protocol from `a` to `b` until it completes, then send the entries in the differing
+/// ranges found by each side.
+fn exchange(a: &mut Tree, b: &mut Tree) -> Exchange {
+    let mut rounds = 0;
+    let mut bytes = 0;
+    let mut differences: [Vec<DiffRange<u16>>; 2] = [Vec::new(), Vec::new()];
+    let mut segments: Vec<HashSegment<u16>> = a.start_diff();
+    let mut turn = 1;
+    while !segments.is_empty() {
+        rounds += 1;
+        bytes += serialized_size(&segments);
+        let mut out_comparison = Vec::new();
+        let tree = if turn == 0 { &*a } else { &*b };
+        tree.diff_round(segments, &mut out_comparison, &mut differences[turn]);
+        segments = out_comparison;
+        turn = 1 - turn;
+    }
+    // the updates are computed from both states before any is applied, as they cross on the wire
+    let updates = |tree: &Tree, ranges: &[DiffRange<u16>]| -> Vec<(u16, Value)> {
+        ranges
+            .iter()
+            .flat_map(|range| tree.get_range(range).map(|(k, v)| (*k, *v)))
+            .collect()
+    };
+    let updates_a = updates(a, &differences[0]);
+    let updates_b = updates(b, &differences[1]);
+    bytes += serialized_size(&updates_a) + serialized_size(&updates_b);
+    for (key, value) in updates_a {
+        apply(b, key, value);
+    }
+    for (key, value) in updates_b {
+        apply(a, key, value);
+    }
+    Exchange { rounds, bytes }
+}
+
+/// Last-write-wins merge of the two maps, computed naively.
+fn reference_merge(a: &Tree, b: &Tree) -> HashMap<u16, Value> {
+    let mut merged: HashMap<u16, Value> = a.iter().map(|(k, v)| (*k, *v)).collect();
+    for (&key, &value) in b.iter() {
+        let keep_other = merged
+            .get(&key)
+            .is_none_or(|local| local.reconcile(&value) == ReconciliationResult::KeepOther);
+        if keep_other {
+            merged.insert(key, value);
+        }
+    }
+    merged
+}
+
+/// How a key is held by the two trees
+#[derive(Clone, Debug)]
+enum Entry {
+    Shared(Value),
+    Conflicting(Value, Value),
+    OnlyA(Value),
+    OnlyB(Value),
+}
+
+fn arb_value() -> impl Strategy<Value = Value> {
+    // few timestamps and values, so that ties happen
+    (0..8i64, 0..4u32).prop_map(|(timestamp, v)| value(timestamp, v))
+}
+
+fn arb_entry() -> impl Strategy<Value = Entry> {
+    prop_oneof![
+        4 => arb_value().prop_map(Entry::Shared),
+        1 => (arb_value(), arb_value()).prop_map(|(a, b)| Entry::Conflicting(a, b)),
+        1 => arb_value().prop_map(Entry::OnlyA),
+        1 => arb_value().prop_map(Entry::OnlyB),
+    ]
+}
+
+/// Two trees with controlled overlap
+fn arb_trees() -> impl Strategy<Value = (Tree, Tree)> {
+    prop::collection::btree_map(any::<u16>(), arb_entry(), 0..500).prop_map(
+        |entries: BTreeMap<u16, Entry>| {
+            let mut a = Tree::new();
+            let mut b = Tree::new();
+            for (key, entry) in entries {
+                match entry {
+                    Entry::Shared(value) => {
+                        a.insert(key, value);
+                        b.insert(key, value);
+                    }
+                    Entry::Conflicting(value_a, value_b) => {
+                        a.insert(key, value_a);
+                        b.insert(key, value_b);
+                    }
+                    Entry::OnlyA(value) => {
+                        a.insert(key, value);
+                    }
+                    Entry::OnlyB(value) => {
+                        b.insert(key, value);
+                    }
+                }
+            }
+            (a, b)
+        },
+    )
+}
+
+/// Upper bound on the number of rounds to find a single difference among `n` elements: the
+/// segments are split in 16 at each round, and the small ones are listed item by item.
+fn max_rounds(n: usize) -> usize {
+    let mut levels = 0;
+    let mut size = n;
+    while size > 8 {
+        size = size.div_ceil(16);
+        levels += 1;
+    }
+    // the opening, the splits, the listing of the items, and the request of the conflicting one
+    levels + 3
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(CASES))]
+
+    #[test]
+    fn converges_to_reference_merge((mut a, mut b) in arb_trees()) {
+        let expected = reference_merge(&a, &b);
+        exchange(&mut a, &mut b);
+        prop_assert_eq!(a.hash(&..), b.hash(&..));
+        let merged: HashMap<u16, Value> = a.iter().map(|(k, v)| (*k, *v)).collect();
+        prop_assert_eq!(merged, expected);
+        prop_assert_eq!(&a, &b);
+    }
+
+    #[test]
+    fn single_difference(
+        keys in prop::collection::btree_set(any::<u16>(), 1..MAX_KEYS),
+        index in any::<prop::sample::Index>(),
+        kind in 0..3u8,
+    ) {
+        let keys: Vec<u16> = keys.into_iter().collect();
+        let mut a: Tree = keys.iter().map(|&key| (key, value(0, 0))).collect();
+        let mut b = a.clone();
+        let key = *index.get(&keys);
+        match kind {
+            // conflicting value
+            0 => {
+                b.insert(key, value(1, 0));
+            }
+            // missing on one side
+            1 => {
+                b.remove(&key);
+            }
+            _ => {
+                a.remove(&key);
+            }
+        }
+        let n = keys.len();
+        let Exchange { rounds, bytes } = exchange(&mut a, &mut b);
+        prop_assert_eq!(&a, &b);
+        prop_assert!(rounds <= max_rounds(n), "{} rounds for {} keys", rounds, n);
+        // the optimal exchange only sends the differing entry
+        let optimal = serialized_size(&vec![(key, value(1, 0))]);
+        let levels = max_rounds(n);
+        prop_assert!(
+            bytes <= 16 * optimal * levels,
+            "{} bytes for {} keys (optimal {})", bytes, n, optimal
+        );
+    }
+
+    #[test]
+    fn bytes_bounded_by_divergence((mut a, mut b) in arb_trees()) {
+        let differing = reference_merge(&a, &b)
+            .into_iter()
+            .filter(|(key, value)| a.get(key) != Some(value) || b.get(key) != Some(value))
+            .count();
+        let n = a.len().max(b.len());
+        let Exchange { bytes, .. } = exchange(&mut a, &mut b);
+        // each difference costs at most a few segments per level of the splits
+        let optimal = differing * serialized_size(&(0u16, value(0, 0)));
+        prop_assert!(
+            bytes <= 16 * (optimal + 16) * max_rounds(n),
+            "{} bytes for {} differences among {} keys", bytes, differing, n
+        );
+    }
+}
@@ -0,0 +1,86 @@
+//! Short runs of the fuzz targets under `fuzz/`, on random inputs shaped like the ones of the
+//! protocol, for when cargo-fuzz is not available.
+//!
+//! Enabled by the `fuzz-smoke` feature.
+#![cfg(feature = "fuzz-smoke")]
+
+use std::ops::Bound;
+use std::time::{Duration, Instant};
+
+use bincode::{DefaultOptions, Options};
+use rand::{Rng, SeedableRng};
+use serde::Serialize;
+
+use reconcile::fuzzing;
+
+/// How long each target runs
+const RUN_TIME: Duration = Duration::from_secs(3);
+
+/// Same layout as the segments of the protocol
+#[derive(Serialize)]
+struct Segment {
+    range: (Bound<u64>, Bound<u64>),
+    hash: u64,
+    size: usize,
+    items: Option<Vec<(u64, u64)>>,
+}
+
+fn random_bound<R: Rng>(rng: &mut R) -> Bound<u64> {
+    match rng.gen_range(0..3) {
+        0 => Bound::Included(rng.gen_range(0..2100)),
+        1 => Bound::Excluded(rng.gen_range(0..2100)),
+        _ => Bound::Unbounded,
+    }
+}
+
+/// Flip a few random bytes, so that the input is mostly, but not entirely, well-formed.
+fn mutate<R: Rng>(rng: &mut R, bytes: &mut [u8]) {
+    if bytes.is_empty() {
+        return;
+    }
+    for _ in 0..rng.gen_range(0..4) {
+        let index = rng.gen_range(0..bytes.len());
+        bytes[index] = rng.gen();
+    }
+}
+
+#[test]
+fn datagram() {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    let start = Instant::now();
+    while start.elapsed() < RUN_TIME {
+        // a header of the protocol, followed by framed messages of known types
+        let mut bytes = vec![b'R', b'C', 2];
+        for _ in 0..rng.gen_range(0..8) {
+            let size = rng.gen_range(1..64);
+            bytes.extend_from_slice(&(size as u16).to_le_bytes());
+            bytes.push(rng.gen_range(0..16));
+            bytes.extend((1..size).map(|_| rng.gen::<u8>()));
+        }
+        mutate(&mut rng, &mut bytes);
+        fuzzing::datagram(&bytes);
+    }
+}
+
+#[test]
+fn diff_round() {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    let start = Instant::now();
+    while start.elapsed() < RUN_TIME {
+        let segments: Vec<_> = (0..rng.gen_range(0..8))
+            .map(|_| Segment {
+                range: (random_bound(&mut rng), random_bound(&mut rng)),
+                hash: if rng.gen() { 0 } else { rng.gen() },
+                size: rng.gen_range(0..2000),
+                items: rng.gen::<bool>().then(|| {
+                    (0..rng.gen_range(0..4))
+                        .map(|_| (rng.gen_range(0..2100), rng.gen()))
+                        .collect()
+                }),
+            })
+            .collect();
+        let mut bytes = DefaultOptions::new().serialize(&segments).unwrap();
+        mutate(&mut rng, &mut bytes);
+        fuzzing::diff_round(&bytes);
+    }
+}
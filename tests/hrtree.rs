@@ -0,0 +1,39 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use reconcile::HRTree;
+
+/// Allocator that keeps track of the number of bytes currently allocated
+struct CountingAllocator;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED.fetch_add(layout.size(), Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+#[test]
+fn stats_bytes() {
+    let before = ALLOCATED.load(Ordering::Relaxed);
+    let mut tree: HRTree<u64, u64> = HRTree::new();
+    for i in 0..100_000u64 {
+        tree.insert(i.wrapping_mul(0x9E37_79B9_7F4A_7C15), i);
+    }
+    let measured = ALLOCATED.load(Ordering::Relaxed) - before;
+    let estimated = tree.stats().bytes;
+    assert!(
+        estimated <= 2 * measured && measured <= 2 * estimated,
+        "estimated {estimated} bytes, measured {measured} bytes"
+    );
+}
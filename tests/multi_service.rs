@@ -0,0 +1,113 @@
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use reconcile::multi_service::MultiService;
+use reconcile::sim::SimNetwork;
+use reconcile::{DatedMaybeTombstone, HRTree, HashRangeQueryable};
+
+type Map = HRTree<u16, DatedMaybeTombstone<String>>;
+
+/// Names of all the namespaces, with the same ids on all the instances
+const REGISTRY: [&str; 3] = ["users", "sessions", "configs"];
+
+/// Wait for a while until the provided predicate becomes true
+///
+/// If the predicate become true in the delay, return true, otherwise return false. This functions
+/// minimizes the wait time by checking regularly if the predicate is true.
+async fn wait_until<F: FnMut() -> bool>(mut f: F) -> bool {
+    for _ in 0..100 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        if f() {
+            return true;
+        }
+    }
+    false
+}
+
+macro_rules! assert_until {
+    ( $x:expr ) => {
+        assert!(wait_until(|| $x).await, stringify!($x))
+    };
+}
+
+fn hash(service: &MultiService<Map>, namespace: &str) -> u64 {
+    service.namespace(namespace).unwrap().read().hash(&..)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn independent_namespaces() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    let maps = || [("users", Map::new()), ("configs", Map::new())];
+    let service1 =
+        MultiService::with_transport(&REGISTRY, maps(), network.bind(addr1).unwrap(), peer_net)
+            .with_seed(addr2.ip());
+    let service2 =
+        MultiService::with_transport(&REGISTRY, maps(), network.bind(addr2).unwrap(), peer_net)
+            .with_seed(addr1.ip());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // the same key holds different values in each namespace
+    service1.insert("users", 0, "Alice".to_string(), Utc::now());
+    service2.insert("configs", 0, "verbose".to_string(), Utc::now());
+    service1.insert("configs", 1, "quiet".to_string(), Utc::now());
+    assert_until!(service2.get("users", &0).as_deref() == Some(&"Alice".to_string()));
+    assert_until!(service1.get("configs", &0).as_deref() == Some(&"verbose".to_string()));
+    assert_until!(service2.get("configs", &1).as_deref() == Some(&"quiet".to_string()));
+    assert!(service1.get("users", &1).is_none());
+    assert!(service2.get("sessions", &0).is_none());
+    assert_until!(
+        hash(&service1, "users") == hash(&service2, "users")
+            && hash(&service1, "configs") == hash(&service2, "configs")
+    );
+    assert_ne!(hash(&service1, "users"), hash(&service1, "configs"));
+
+    // removals stay in their namespace too
+    service2.remove("users", &0, Utc::now());
+    assert_until!(service1.get("users", &0).is_none());
+    assert!(service1.get("configs", &0).is_some());
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn missing_namespace() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    let maps1 = [("users", Map::new()), ("sessions", Map::new())];
+    let maps2 = [("users", Map::new())];
+    let service1 =
+        MultiService::with_transport(&REGISTRY, maps1, network.bind(addr1).unwrap(), peer_net)
+            .with_seed(addr2.ip());
+    let service2 =
+        MultiService::with_transport(&REGISTRY, maps2, network.bind(addr2).unwrap(), peer_net)
+            .with_seed(addr1.ip());
+    assert!(service2.namespace("sessions").is_none());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // the messages of the namespace the peer lacks are ignored, but do not disturb the other one
+    for i in 0..100 {
+        service1.insert("sessions", i, format!("session {i}"), Utc::now());
+    }
+    service1.insert("users", 0, "Alice".to_string(), Utc::now());
+    assert_until!(service2.get("users", &0).as_deref() == Some(&"Alice".to_string()));
+    service2.insert("users", 1, "Bob".to_string(), Utc::now());
+    assert_until!(service1.get("users", &1).as_deref() == Some(&"Bob".to_string()));
+    assert_until!(hash(&service1, "users") == hash(&service2, "users"));
+    assert!(service2.get("sessions", &0).is_none());
+    assert_eq!(service1.namespace("sessions").unwrap().read().len(), 100);
+
+    task1.abort();
+    task2.abort();
+}
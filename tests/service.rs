@@ -1,12 +1,34 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::hash::Hash;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::{Bound, RangeBounds};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
 
-use chrono::Utc;
+use chrono::{DateTime, Utc};
 use rand::{
     distributions::{Alphanumeric, DistString},
     Rng, SeedableRng,
 };
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use tokio::net::UdpSocket;
 
-use reconcile::{DatedMaybeTombstone, HRTree, HashRangeQueryable, Service};
+use reconcile::chunk::{Chunked, ChunkedValue};
+use reconcile::codec::CodecMap;
+use reconcile::dated::DatedTree;
+use reconcile::discovery::{DiscoveryFuture, DnsName, Resolver, StaticList};
+use reconcile::fingerprint::{FingerprintStrategy, Sum128Fingerprint};
+use reconcile::map::Map;
+use reconcile::service::{BroadcastOverflow, ChangeOrigin};
+use reconcile::sim::{LinkConfig, SimNetwork, SimSocket};
+use reconcile::spill::{Blob, SpillMap};
+use reconcile::tcp::{MixedTransport, TcpTransport};
+use reconcile::transport::{Transport, TransportFuture};
+use reconcile::{
+    Clock, DatedMaybeTombstone, DefaultFingerprint, DiffTuning, DualFingerprint, HRTree,
+    HashRangeQueryable, LwwPolicy, Resolution, Service,
+};
 
 /// Wait for a while until the provided predicate becomes true
 ///
@@ -28,12 +50,36 @@ macro_rules! assert_until {
     };
 }
 
+/// Same layout as the messages of the protocol, for the variants used in the tests
+#[derive(Serialize)]
+enum Message {
+    #[allow(dead_code)]
+    ComparisonItem(u64, ()),
+    Update((u8, DatedMaybeTombstone<String>)),
+}
+
+/// Version of the wire format spoken by the services
+const PROTOCOL_VERSION: u8 = 2;
+
+/// Build a datagram of the given protocol version, framing each message with its length
+fn datagram<M: Serialize>(version: u8, messages: &[M]) -> Vec<u8> {
+    use bincode::{DefaultOptions, Options};
+
+    let mut buf = vec![b'R', b'C', version];
+    for message in messages {
+        let message = DefaultOptions::new().serialize(message).unwrap();
+        buf.extend_from_slice(&(message.len() as u16).to_le_bytes());
+        buf.extend_from_slice(&message);
+    }
+    buf
+}
+
 #[tokio::test(flavor = "multi_thread")]
 async fn test() {
-    let port = 8080;
-    let peer_net = "127.0.0.1/8".parse().unwrap();
-    let addr1 = "127.0.0.44".parse().unwrap();
-    let addr2 = "127.0.0.45".parse().unwrap();
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
 
     // create tree1 with many values
     let mut rng = rand::rngs::StdRng::seed_from_u64(42);
@@ -51,12 +97,10 @@ async fn test() {
     let tree2: HRTree<String, DatedMaybeTombstone<String>> = HRTree::new();
 
     // start reconciliation services for tree1 and tree2
-    let service1 = Service::new(tree1, port, addr1, peer_net)
-        .await
-        .with_seed(addr2);
-    let service2 = Service::new(tree2, port, addr2, peer_net)
-        .await
-        .with_seed(addr1);
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_seed(addr2.ip());
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip());
     let task2 = tokio::spawn(service2.clone().run());
     assert_eq!(service2.read().hash(&..), 0);
     let task1 = tokio::spawn(service1.clone().run());
@@ -130,3 +174,3661 @@ async fn test() {
     task2.abort();
     task1.abort();
 }
+
+#[tokio::test(flavor = "multi_thread")]
+async fn convergence() {
+    let port = 8080;
+    let peer_net = "127.0.0.1/8".parse().unwrap();
+    let addr1 = "127.0.0.48".parse().unwrap();
+    let addr2 = "127.0.0.49".parse().unwrap();
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    let mut key_values = Vec::new();
+    for _ in 0..1000 {
+        let key: String = Alphanumeric.sample_string(&mut rng, 100);
+        let value: DatedMaybeTombstone<String> =
+            (Utc::now(), Some(Alphanumeric.sample_string(&mut rng, 100)));
+        key_values.push((key, value));
+    }
+    let tree1 = HRTree::from_iter(key_values.into_iter());
+    let start_hash = tree1.hash(&..);
+    let tree2: HRTree<String, DatedMaybeTombstone<String>> = HRTree::new();
+
+    let service1 = Service::new(tree1, port, addr1, peer_net)
+        .await
+        .unwrap()
+        .with_seed(addr2);
+    let service2 = Service::new(tree2, port, addr2, peer_net)
+        .await
+        .unwrap()
+        .with_seed(addr1);
+    let mut convergence = service2.subscribe_convergence();
+    assert_eq!(*convergence.borrow(), None);
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // wait until service2 notices it has converged with service1
+    let converged = tokio::time::timeout(
+        Duration::from_secs(5),
+        convergence.wait_for(|c| c.map(|c| c.hash) == Some(start_hash)),
+    )
+    .await
+    .expect("no convergence")
+    .unwrap()
+    .unwrap();
+    assert_eq!(converged.peer.ip(), addr1);
+    assert_eq!(service2.read().hash(&..), start_hash);
+
+    task2.abort();
+    task1.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn metrics() {
+    let port = 8080;
+    let peer_net = "127.0.0.1/8".parse().unwrap();
+    let addr1 = "127.0.0.50".parse().unwrap();
+    let addr2 = "127.0.0.51".parse().unwrap();
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    let mut key_values = Vec::new();
+    for _ in 0..1000 {
+        let key: String = Alphanumeric.sample_string(&mut rng, 100);
+        let value: DatedMaybeTombstone<String> =
+            (Utc::now(), Some(Alphanumeric.sample_string(&mut rng, 100)));
+        key_values.push((key, value));
+    }
+    let tree1 = HRTree::from_iter(key_values.into_iter());
+    let start_hash = tree1.hash(&..);
+    let tree2: HRTree<String, DatedMaybeTombstone<String>> = HRTree::new();
+
+    let service1 = Service::new(tree1, port, addr1, peer_net)
+        .await
+        .unwrap()
+        .with_seed(addr2);
+    let service2 = Service::new(tree2, port, addr2, peer_net)
+        .await
+        .unwrap()
+        .with_seed(addr1);
+    assert_eq!(service2.metrics().snapshot(), Default::default());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    assert_until!(service2.read().hash(&..) == start_hash);
+    let metrics1 = service1.metrics().snapshot();
+    let metrics2 = service2.metrics().snapshot();
+    assert_eq!(metrics2.updates_applied, 1000);
+    assert!(metrics1.updates_sent >= 1000);
+    assert!(metrics1.datagrams_sent > 0);
+    assert!(metrics2.datagrams_received > 0);
+    assert!(metrics2.bytes_received > 0);
+    assert!(metrics1.segments_processed > 0);
+
+    task2.abort();
+    task1.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn superset() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    // the first instance holds 100 more keys, interleaved with the shared ones
+    let timestamp = Utc::now();
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    let mut tree1: HRTree<u32, DatedMaybeTombstone<u32>> = HRTree::new();
+    let mut tree2: HRTree<u32, DatedMaybeTombstone<u32>> = HRTree::new();
+    while tree1.len() < 1100 {
+        let key = rng.gen();
+        tree1.insert(key, (timestamp, Some(key)));
+        if !tree1.len().is_multiple_of(11) {
+            tree2.insert(key, (timestamp, Some(key)));
+        }
+    }
+    assert_eq!(tree2.len(), 1000);
+    let hash = tree1.hash(&..);
+    let pre_inserts = Arc::new(AtomicUsize::new(0));
+    let pre_inserts2 = pre_inserts.clone();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_seed(addr2.ip());
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip())
+        .with_pre_insert(move |_, _, _| {
+            pre_inserts2.fetch_add(1, Ordering::Relaxed);
+        });
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    assert_until!(service2.read().hash(&..) == hash);
+    // only the missing pairs are sent and written
+    let metrics1 = service1.metrics().snapshot();
+    let metrics2 = service2.metrics().snapshot();
+    assert_eq!(metrics2.updates_applied, 100);
+    assert_eq!(pre_inserts.load(Ordering::Relaxed), 100);
+    assert_eq!(metrics1.updates_applied, 0);
+    assert_eq!(metrics2.updates_sent, 0);
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn get_mut() {
+    let port = 8080;
+    let peer_net = "127.0.0.1/8".parse().unwrap();
+    let addr1 = "127.0.0.52".parse().unwrap();
+    let addr2 = "127.0.0.53".parse().unwrap();
+
+    let tree1: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let tree2: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let service1 = Service::new(tree1, port, addr1, peer_net)
+        .await
+        .unwrap()
+        .with_seed(addr2);
+    let service2 = Service::new(tree2, port, addr2, peer_net)
+        .await
+        .unwrap()
+        .with_seed(addr1);
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    service1.insert(0, "Hello".to_string(), Utc::now());
+    assert_until!(service2.get(&0).as_deref() == Some(&"Hello".to_string()));
+
+    // in-place modifications are propagated by the diff protocol
+    service1.get_mut(&0, |v| v.unwrap().push_str(", World!"));
+    assert_eq!(
+        service1.get(&0).as_deref(),
+        Some(&"Hello, World!".to_string())
+    );
+    assert_until!(service2.get(&0).as_deref() == Some(&"Hello, World!".to_string()));
+    assert_eq!(service1.read().hash(&..), service2.read().hash(&..));
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dual_stack() {
+    // the loopback interface only has one IPv6 address, so we use IPv4-mapped IPv6 addresses
+    // to run several IPv6 instances on the same port
+    let port = 8080;
+    let addr_a4: IpAddr = "127.0.0.60".parse().unwrap();
+    let addr_a6: IpAddr = "::ffff:127.0.0.61".parse().unwrap();
+    let addr_b: IpAddr = "127.0.0.62".parse().unwrap();
+    let addr_c: IpAddr = "::ffff:127.0.0.63".parse().unwrap();
+    // no random peer discovery
+    let peer_net4 = "127.255.255.254/32".parse().unwrap();
+    let peer_net6 = "::ffff:127.255.255.254/128".parse().unwrap();
+    let bind = |addr| UdpSocket::bind(SocketAddr::new(addr, port));
+
+    // service_a listens on both families, service_b only on IPv4, service_c only on IPv6
+    let tree_a = HRTree::from_iter([(0u8, (Utc::now(), Some("Hello".to_string())))]);
+    let hash = tree_a.hash(&..);
+    let tree_b: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let tree_c: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let service_a = Service::with_sockets(
+        tree_a,
+        bind(addr_a4).await.unwrap(),
+        bind(addr_a6).await.unwrap(),
+        peer_net4,
+    )
+    .with_seed(addr_b)
+    .with_seed(addr_c);
+    let service_b =
+        Service::with_socket(tree_b, bind(addr_b).await.unwrap(), peer_net4).with_seed(addr_a4);
+    let service_c =
+        Service::with_socket(tree_c, bind(addr_c).await.unwrap(), peer_net6).with_seed(addr_a6);
+    let task_a = tokio::spawn(service_a.clone().run());
+    let task_b = tokio::spawn(service_b.clone().run());
+    let task_c = tokio::spawn(service_c.clone().run());
+
+    assert_until!(service_b.read().hash(&..) == hash);
+    assert_until!(service_c.read().hash(&..) == hash);
+
+    // service_b and service_c can only communicate through service_a
+    service_c.insert(1, "World!".to_string(), Utc::now());
+    assert_until!(service_b.get(&1).as_deref() == Some(&"World!".to_string()));
+    service_b.insert(2, "Goodbye".to_string(), Utc::now());
+    assert_until!(service_c.get(&2).as_deref() == Some(&"Goodbye".to_string()));
+
+    task_a.abort();
+    task_b.abort();
+    task_c.abort();
+}
+
+/// Key-value pairs with random keys, and values of 100 bytes
+fn random_tree(seed: u64, len: usize) -> HRTree<String, DatedMaybeTombstone<String>> {
+    let mut rng = rand::rngs::StdRng::seed_from_u64(seed);
+    (0..len)
+        .map(|_| {
+            let key = Alphanumeric.sample_string(&mut rng, 20);
+            let value = Alphanumeric.sample_string(&mut rng, 100);
+            (key, (Utc::now(), Some(value)))
+        })
+        .collect()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tcp() {
+    let peer_net = "127.0.0.1/8".parse().unwrap();
+    let addr1: SocketAddr = "127.0.0.100:8080".parse().unwrap();
+    let addr2: SocketAddr = "127.0.0.101:8080".parse().unwrap();
+
+    let tree1 = random_tree(42, 1000);
+    let tree2 = random_tree(43, 1000);
+    let transport1 = TcpTransport::bind(addr1).await.unwrap();
+    let transport2 = TcpTransport::bind(addr2).await.unwrap();
+    let service1 = Service::with_transport(tree1, transport1, peer_net).with_seed(addr2.ip());
+    let service2 = Service::with_transport(tree2, transport2, peer_net).with_seed(addr1.ip());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    assert_until!(service1.read().hash(&..) == service2.read().hash(&..));
+    assert_eq!(service1.read().len(), 2000);
+    // the replies are attributed to the listening addresses
+    assert!(service1.peers().iter().all(|peer| peer.addr == addr2));
+
+    service2.insert("Hello".to_string(), "World!".to_string(), Utc::now());
+    assert_until!(service1.get("Hello").as_deref() == Some(&"World!".to_string()));
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn tcp_connection_drop() {
+    let peer_net = "127.0.0.1/8".parse().unwrap();
+    let addr1: SocketAddr = "127.0.0.102:8080".parse().unwrap();
+    let addr2: SocketAddr = "127.0.0.103:8080".parse().unwrap();
+
+    let tree1 = random_tree(42, 20_000);
+    let hash = tree1.hash(&..);
+    let tree2: HRTree<String, DatedMaybeTombstone<String>> = HRTree::new();
+    let transport1 = TcpTransport::bind(addr1).await.unwrap();
+    let transport2 = TcpTransport::bind(addr2).await.unwrap();
+    let service1 = Service::with_transport(tree1, transport1, peer_net).with_seed(addr2.ip());
+    let service2 = Service::with_transport(tree2, transport2, peer_net).with_seed(addr1.ip());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // restart the second instance in the middle of the synchronization, closing its connections
+    assert_until!(!service2.read().is_empty());
+    task2.abort();
+    let _ = task2.await;
+    let tree2 = service2.read().clone();
+    drop(service2);
+    let transport2 = TcpTransport::bind(addr2).await.unwrap();
+    let service2 = Service::with_transport(tree2, transport2, peer_net).with_seed(addr1.ip());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // the first instance reconnects
+    assert!(wait_long_until(|| service2.read().hash(&..) == hash).await);
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn mixed_transports() {
+    let peer_net = "127.0.0.1/8".parse().unwrap();
+    let addr1: SocketAddr = "127.0.0.104:8080".parse().unwrap();
+    let addr2: SocketAddr = "127.0.0.105:8080".parse().unwrap();
+    let addr3: SocketAddr = "127.0.0.106:8080".parse().unwrap();
+
+    // the first two instances talk over UDP, and over TCP to the third one
+    let mixed = |addr| async move {
+        let udp = UdpSocket::bind(addr).await.unwrap();
+        let tcp = TcpTransport::bind(addr).await.unwrap();
+        MixedTransport::new(udp, tcp, "127.0.0.106/32".parse().unwrap())
+    };
+    let service1 = Service::with_transport(random_tree(42, 1000), mixed(addr1).await, peer_net)
+        .with_seed(addr2.ip())
+        .with_seed(addr3.ip());
+    let service2 = Service::with_transport(random_tree(43, 1000), mixed(addr2).await, peer_net)
+        .with_seed(addr1.ip());
+    let transport3 = TcpTransport::bind(addr3).await.unwrap();
+    let service3 =
+        Service::with_transport(random_tree(44, 1000), transport3, peer_net).with_seed(addr2.ip());
+    let tasks = [
+        tokio::spawn(service1.clone().run()),
+        tokio::spawn(service2.clone().run()),
+        tokio::spawn(service3.clone().run()),
+    ];
+
+    assert_until!(
+        service1.read().hash(&..) == service2.read().hash(&..)
+            && service2.read().hash(&..) == service3.read().hash(&..)
+    );
+    assert_eq!(service3.read().len(), 3000);
+
+    for task in tasks {
+        task.abort();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn max_concurrent_sessions() {
+    let port = 8080;
+    // no random peer discovery
+    let peer_net = "127.255.255.254/32".parse().unwrap();
+    let addrs: Vec<IpAddr> = ["127.0.0.64", "127.0.0.65", "127.0.0.66"]
+        .iter()
+        .map(|addr| addr.parse().unwrap())
+        .collect();
+
+    let mut services = Vec::new();
+    for (i, &addr) in addrs.iter().enumerate() {
+        let tree: HRTree<u8, DatedMaybeTombstone<String>> =
+            HRTree::from_iter([(i as u8, (Utc::now(), Some(format!("Hello from {i}"))))]);
+        let mut service = Service::new(tree, port, addr, peer_net)
+            .await
+            .unwrap()
+            .with_max_concurrent_sessions(1);
+        for &other in &addrs {
+            if other != addr {
+                service = service.with_seed(other);
+            }
+        }
+        services.push(service);
+    }
+    let tasks: Vec<_> = services
+        .iter()
+        .map(|service| tokio::spawn(service.clone().run()))
+        .collect();
+
+    // each instance reconciles with a single peer at a time, but all of them end up with all the
+    // values
+    let converged = || {
+        services
+            .iter()
+            .all(|service| (0..3).all(|i| service.get(&i).is_some()))
+    };
+    for _ in 0..100 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if converged() {
+            break;
+        }
+    }
+    assert!(converged());
+    let hash = services[0].read().hash(&..);
+    for service in &services {
+        assert_eq!(service.read().hash(&..), hash);
+    }
+
+    for task in tasks {
+        task.abort();
+    }
+}
+
+/// Trees of the given size that differ on one key every `step`, so that the sessions between them
+/// take several rounds
+fn scattered_trees(len: u32, step: u32) -> [HRTree<u32, DatedMaybeTombstone<u32>>; 2] {
+    let timestamp = Utc::now();
+    let tree1: HRTree<u32, DatedMaybeTombstone<u32>> =
+        HRTree::from_iter((0..len).map(|i| (i, (timestamp, Some(i)))));
+    let mut tree2 = tree1.clone();
+    for i in (0..len).step_by(step as usize) {
+        tree2.insert(i, (timestamp + chrono::Duration::seconds(1), Some(0)));
+    }
+    [tree1, tree2]
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn session_deadline() {
+    let network = SimNetwork::new(42);
+    let slow = LinkConfig {
+        latency: Duration::from_millis(100),
+        ..LinkConfig::default()
+    };
+    network.set_default_link(slow);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    let [tree1, tree2] = scattered_trees(10_000, 97);
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_millis(200))
+        .with_session_deadline(Duration::from_millis(300))
+        .with_seed(addr2.ip());
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_millis(200))
+        .with_seed(addr1.ip());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // the rounds over the slow link exceed the deadline, so the sessions start over
+    assert!(wait_long_until(|| service1.metrics().snapshot().sessions_restarted > 0).await);
+    let metrics = service1.metrics().snapshot();
+    assert!(metrics.restarted_session_ranges > 0);
+
+    // they complete once the link is fast again
+    network.set_default_link(LinkConfig::default());
+    assert!(wait_long_until(|| service1.read().hash(&..) == service2.read().hash(&..)).await);
+    assert!(wait_long_until(|| service1.metrics().snapshot().longest_session_ms < 300).await);
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn peer_dies_mid_session() {
+    let network = SimNetwork::new(42);
+    network.set_default_link(LinkConfig {
+        latency: Duration::from_millis(20),
+        ..LinkConfig::default()
+    });
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    let [tree1, tree2] = scattered_trees(10_000, 97);
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_peer_expiration(Duration::from_millis(500))
+        .with_discovery(StaticList::new([]));
+    let task1 = tokio::spawn(service1.clone().run());
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_discovery(StaticList::new([addr1.ip()]));
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // the peer dies once the instances found differences
+    assert_until!(service1.sync_progress().differing_ranges > 0);
+    task2.abort();
+    let _ = task2.await;
+    let tree2 = service2.read().clone();
+    drop(service2);
+
+    // the state of its sessions is dropped with it
+    assert!(
+        wait_long_until(|| {
+            service1.peers().is_empty()
+                && service1.sync_progress().differing_ranges == 0
+                && service1.metrics().snapshot().longest_session_ms == 0
+        })
+        .await
+    );
+
+    // once revived, it converges
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_discovery(StaticList::new([addr1.ip()]));
+    let task2 = tokio::spawn(service2.clone().run());
+    assert!(wait_long_until(|| service1.read().hash(&..) == service2.read().hash(&..)).await);
+
+    task1.abort();
+    task2.abort();
+}
+
+/// Run two instances with the given tuning until they converge, and return the number of
+/// segments they compared.
+async fn segments_until_converged(
+    [tree1, tree2]: [HRTree<u32, DatedMaybeTombstone<u32>>; 2],
+    tuning: DiffTuning,
+) -> u64 {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_diff_tuning(tuning)
+        .with_seed(addr2.ip());
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_diff_tuning(tuning)
+        .with_seed(addr1.ip());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    assert!(wait_long_until(|| service1.read().hash(&..) == service2.read().hash(&..)).await);
+    let segments = service1.metrics().snapshot().segments_processed
+        + service2.metrics().snapshot().segments_processed;
+
+    task1.abort();
+    task2.abort();
+    segments
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn adaptive_diff_tuning() {
+    // a fresh instance that received a few writes
+    let timestamp = Utc::now();
+    let fresh = || {
+        [
+            HRTree::from_iter((0..10_000).map(|i| (i, (timestamp, Some(i))))),
+            HRTree::from_iter((0..10_000).step_by(200).map(|i| (i, (timestamp, Some(0))))),
+        ]
+    };
+    let default = segments_until_converged(fresh(), DiffTuning::default()).await;
+    let adaptive = segments_until_converged(fresh(), DiffTuning::adaptive()).await;
+    assert!(
+        adaptive * 10 <= default,
+        "{adaptive} segments compared, {default} without the adaptive tuning"
+    );
+
+    // all the values differ, as after a long downtime
+    let default = segments_until_converged(scattered_trees(10_000, 1), DiffTuning::default()).await;
+    let adaptive =
+        segments_until_converged(scattered_trees(10_000, 1), DiffTuning::adaptive()).await;
+    assert!(
+        adaptive * 2 <= default,
+        "{adaptive} segments compared, {default} without the adaptive tuning"
+    );
+}
+
+/// Key serialized as a `String`, but not hashed as one
+#[derive(Clone, Debug, Deserialize, Eq, Ord, PartialEq, PartialOrd, Serialize)]
+#[serde(transparent)]
+struct Name(String);
+
+impl Hash for Name {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        state.write(self.0.as_bytes());
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn canonical_hashing() {
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    let timestamp = Utc::now();
+    let names = |range: std::ops::Range<u32>| range.map(|i| format!("key{i:05}"));
+
+    // the instances converge in both modes
+    for canonical in [false, true] {
+        let network = SimNetwork::new(42);
+        let mut tree1: HRTree<String, DatedMaybeTombstone<u32>> = names(0..1000)
+            .map(|key| (key, (timestamp, Some(1))))
+            .collect();
+        let mut tree2: HRTree<String, DatedMaybeTombstone<u32>> = names(500..1500)
+            .map(|key| (key, (timestamp, Some(2))))
+            .collect();
+        if canonical {
+            tree1 = tree1.with_canonical_hashing();
+            tree2 = tree2.with_canonical_hashing();
+        }
+        let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+            .with_seed(addr2.ip());
+        let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+            .with_seed(addr1.ip());
+        let task1 = tokio::spawn(service1.clone().run());
+        let task2 = tokio::spawn(service2.clone().run());
+        assert!(wait_long_until(|| service1.read().hash(&..) == service2.read().hash(&..)).await);
+        assert_eq!(service2.read().len(), 1500);
+        task1.abort();
+        task2.abort();
+    }
+
+    // keys of different types with the same serialization only converge in the canonical mode
+    let network = SimNetwork::new(42);
+    let tree1: HRTree<String, DatedMaybeTombstone<u32>> = names(0..1000)
+        .map(|key| (key, (timestamp, Some(1))))
+        .collect();
+    let tree2: HRTree<Name, DatedMaybeTombstone<u32>> = names(500..1500)
+        .map(|key| (Name(key), (timestamp, Some(2))))
+        .collect();
+    let service1 = Service::with_transport(
+        tree1.with_canonical_hashing(),
+        network.bind(addr1).unwrap(),
+        peer_net,
+    )
+    .with_seed(addr2.ip());
+    let service2 = Service::with_transport(
+        tree2.with_canonical_hashing(),
+        network.bind(addr2).unwrap(),
+        peer_net,
+    )
+    .with_seed(addr1.ip());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+    assert!(wait_long_until(|| service1.read().hash(&..) == service2.read().hash(&..)).await);
+    assert_eq!(service1.read().len(), 1500);
+    assert_eq!(service2.read().len(), 1500);
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn canonical_hashing_restart() {
+    let port = 8080;
+    let peer_net = "127.0.0.1/8".parse().unwrap();
+    let addr1: IpAddr = "127.0.0.110".parse().unwrap();
+    let addr2: IpAddr = "127.0.0.111".parse().unwrap();
+    let wal_path =
+        std::env::temp_dir().join(format!("reconcile-{}-canonical.wal", std::process::id()));
+    let snapshot_path = std::env::temp_dir().join(format!(
+        "reconcile-{}-canonical.snapshot",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&wal_path);
+    let timestamp = Utc::now();
+
+    // the instance with `String` keys writes its changes to the log, then saves a snapshot
+    let tree: HRTree<String, DatedMaybeTombstone<u32>> = HRTree::new();
+    let service1 = Service::new(tree, port, addr1, peer_net)
+        .await
+        .unwrap()
+        .with_canonical_hashing()
+        .with_wal(&wal_path)
+        .unwrap();
+    for i in 0..1000 {
+        service1.insert(format!("key{i:05}"), 1, timestamp);
+    }
+    service1.sync_wal().unwrap();
+    service1.save_snapshot(&snapshot_path).unwrap();
+    let hash = service1.read().hash(&..);
+    drop(service1);
+
+    // the other instance has `Name` keys
+    let tree2: HRTree<Name, DatedMaybeTombstone<u32>> = (500..1500)
+        .map(|i| (Name(format!("key{i:05}")), (timestamp, Some(2))))
+        .collect();
+    let service2 = Service::new(tree2.with_canonical_hashing(), port, addr2, peer_net)
+        .await
+        .unwrap()
+        .with_seed(addr1);
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // the restored instance hashes as before once switched again, and converges
+    for recover_from_wal in [true, false] {
+        let service1 = if recover_from_wal {
+            Service::<HRTree<String, DatedMaybeTombstone<u32>>>::recover_from_wal(
+                &wal_path, port, addr1, peer_net,
+            )
+            .await
+            .unwrap()
+        } else {
+            Service::<HRTree<String, DatedMaybeTombstone<u32>>>::load_snapshot(
+                &snapshot_path,
+                port,
+                addr1,
+                peer_net,
+            )
+            .await
+            .unwrap()
+        };
+        let service1 = service1.with_canonical_hashing().with_seed(addr2);
+        assert!(service1.read().canonical_hashing());
+        assert_eq!(service1.read().hash(&..), hash);
+        let task1 = tokio::spawn(service1.clone().run());
+        assert!(wait_long_until(|| service1.read().hash(&..) == service2.read().hash(&..)).await);
+        assert_eq!(service1.read().len(), 1500);
+        task1.abort();
+        let _ = task1.await;
+    }
+
+    // a fresh instance bootstrapped in the canonical mode matches directly
+    let tree: HRTree<String, DatedMaybeTombstone<u32>> = HRTree::new();
+    let service1 = Service::new(tree, port, addr1, peer_net)
+        .await
+        .unwrap()
+        .with_canonical_hashing();
+    let (reader, writer) = tokio::io::duplex(64 * 1024);
+    let serving = tokio::spawn({
+        let service2 = service2.clone();
+        async move { service2.serve_snapshot(writer).await.unwrap() }
+    });
+    assert_eq!(service1.bootstrap_from(reader).await.unwrap(), 1500);
+    serving.await.unwrap();
+    assert_eq!(service1.read().hash(&..), service2.read().hash(&..));
+    task2.abort();
+    let _ = std::fs::remove_file(&wal_path);
+    let _ = std::fs::remove_file(&snapshot_path);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn snapshot_range() {
+    let port = 8080;
+    let peer_net = "127.0.0.1/8".parse().unwrap();
+    let addr = "127.0.0.67".parse().unwrap();
+
+    let tree: HRTree<u32, DatedMaybeTombstone<u32>> =
+        HRTree::from_iter((0..1000).map(|i| (2 * i, (Utc::now(), Some(i)))));
+    let service = Service::new(tree, port, addr, peer_net).await.unwrap();
+
+    // the map can be written to between chunks, since the lock is not held
+    let mut removed = Vec::new();
+    let mut listed = Vec::new();
+    let mut snapshot = service.snapshot_range(&(100..1900), 100);
+    while let Some(chunk) = snapshot.next_chunk() {
+        let cursor = chunk.last().map_or(0, |&(key, _)| key);
+        service.insert(cursor + 1, 0, Utc::now());
+        service.remove(&(cursor + 200), Utc::now());
+        removed.push(cursor + 200);
+        listed.extend(chunk.into_iter().map(|(key, _)| key));
+    }
+
+    // the keys are listed in order, once
+    assert!(listed.windows(2).all(|pair| pair[0] < pair[1]));
+    // all the keys present during the whole iteration are listed
+    for key in (100..1900).step_by(2) {
+        assert_eq!(listed.contains(&key), !removed.contains(&key), "{key}");
+    }
+    assert!(listed.iter().all(|key| (100..1900).contains(key)));
+
+    let entries: Vec<_> = service.snapshot_range(&(..10), 3).entries().collect();
+    assert_eq!(entries, vec![(0, 0), (2, 1), (4, 2), (6, 3), (8, 4)]);
+}
+
+#[tokio::test]
+async fn page() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+
+    let tree: HRTree<u32, DatedMaybeTombstone<u32>> =
+        HRTree::from_iter((0..1000).map(|i| (2 * i, (Utc::now(), Some(i)))));
+    let service = Service::with_transport(tree, network.bind(addr).unwrap(), peer_net);
+    let page = service.page(100, 5);
+    assert_eq!(
+        page,
+        vec![(200, 100), (202, 101), (204, 102), (206, 103), (208, 104)]
+    );
+    assert_eq!(service.page(998, 5), vec![(1996, 998), (1998, 999)]);
+    assert!(service.page(1000, 5).is_empty());
+
+    // tombstones keep their position
+    service.remove(&202, Utc::now());
+    assert_eq!(service.page(100, 3), vec![(200, 100), (204, 102)]);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn owned_reads() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+
+    let timestamp = Utc::now();
+    let tree: HRTree<u32, DatedMaybeTombstone<u32>> =
+        HRTree::from_iter((0..100).map(|i| (i, (timestamp, Some(i)))));
+    let service = Service::with_transport(tree, network.bind(addr).unwrap(), peer_net);
+    assert_eq!(service.get_cloned(&5), Some(5));
+    assert_eq!(service.get_dated(&5), Some((timestamp, Some(5))));
+    assert_eq!(service.get_cloned(&100), None);
+    assert_eq!(service.get_dated(&100), None);
+
+    // tombstones are only visible with their timestamp
+    let removal = Utc::now();
+    service.remove(&5, removal);
+    assert_eq!(service.get_cloned(&5), None);
+    assert_eq!(service.get_dated(&5), Some((removal, None)));
+    assert_eq!(
+        service.multi_get(&[4, 5, 6, 100]),
+        vec![Some(4), None, Some(6), None]
+    );
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+async fn owned_reads_under_load() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    let tree1: HRTree<u32, DatedMaybeTombstone<u32>> = HRTree::new();
+    let tree2: HRTree<u32, DatedMaybeTombstone<u32>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net);
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // readers keep the values across awaits, while the peer floods updates
+    let keys: Vec<u32> = (0..50).collect();
+    let readers: Vec<_> = (0..8)
+        .map(|_| {
+            let service1 = service1.clone();
+            let keys = keys.clone();
+            tokio::spawn(async move {
+                for _ in 0..200 {
+                    let values = service1.multi_get(&keys);
+                    let value = service1.get_cloned(&0);
+                    tokio::task::yield_now().await;
+                    assert_eq!(values.len(), keys.len());
+                    assert_eq!(value, values[0]);
+                }
+            })
+        })
+        .collect();
+    for round in 0..20u32 {
+        let key_values: Vec<_> = (0..1000).map(|i| (i, round)).collect();
+        service2.insert_bulk_now(&key_values);
+        tokio::task::yield_now().await;
+    }
+    let readers = async {
+        for reader in readers {
+            reader.await.unwrap();
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(30), readers)
+        .await
+        .expect("the readers are stalled");
+    assert!(wait_long_until(|| service1.multi_get(&keys) == vec![Some(19); 50]).await);
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn retain() {
+    let port = 8080;
+    let peer_net = "127.0.0.1/8".parse().unwrap();
+    let addr1 = "127.0.0.70".parse().unwrap();
+    let addr2 = "127.0.0.71".parse().unwrap();
+
+    let tree1: HRTree<u8, DatedMaybeTombstone<u8>> =
+        HRTree::from_iter((0..100).map(|i| (i, (Utc::now(), Some(i)))));
+    let service1 = Service::new(tree1, port, addr1, peer_net).await.unwrap();
+    let tree2: HRTree<u8, DatedMaybeTombstone<u8>> = HRTree::new();
+    let service2 = Service::new(tree2, port, addr2, peer_net)
+        .await
+        .unwrap()
+        .with_seed(addr1);
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+    for _ in 0..100 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if service2.read().len() == 100 {
+            break;
+        }
+    }
+    assert_eq!(service2.read().len(), 100);
+
+    // the removed values become tombstones, which are sent to the peer
+    service1.retain(|_, &value| value % 2 == 0, Utc::now());
+    assert_eq!(service1.read().len(), 100);
+    assert!((0..100).all(|i| service1.get(&i).is_some() == (i % 2 == 0)));
+    assert_until!((0..100).all(|i| service2.get(&i).is_some() == (i % 2 == 0)));
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn max_bandwidth() {
+    let port = 8080;
+    let peer_net = "127.0.0.1/8".parse().unwrap();
+    let addr1 = "127.0.0.72".parse().unwrap();
+    let addr2 = "127.0.0.73".parse().unwrap();
+
+    // about 5 MB of data
+    let value = "x".repeat(1000);
+    let tree1: HRTree<u16, DatedMaybeTombstone<String>> =
+        HRTree::from_iter((0..5000).map(|i| (i, (Utc::now(), Some(value.clone())))));
+    let tree2: HRTree<u16, DatedMaybeTombstone<String>> = HRTree::new();
+    let service1 = Service::new(tree1, port, addr1, peer_net)
+        .await
+        .unwrap()
+        .with_max_bandwidth(1_000_000);
+    let service2 = Service::new(tree2, port, addr2, peer_net)
+        .await
+        .unwrap()
+        .with_max_bandwidth(1_000_000)
+        .with_seed(addr1);
+
+    let start = std::time::Instant::now();
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+    for _ in 0..300 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if service2.read().len() == 5000 {
+            break;
+        }
+    }
+    let elapsed = start.elapsed();
+    assert_eq!(service2.read().len(), 5000);
+    assert!(elapsed >= Duration::from_secs(4), "{elapsed:?}");
+    assert_eq!(service1.read().hash(&..), service2.read().hash(&..));
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn malformed_datagrams() {
+    let port = 8080;
+    let peer_net = "127.0.0.1/8".parse().unwrap();
+    let addr1 = "127.0.0.74".parse().unwrap();
+    let addr2 = "127.0.0.75".parse().unwrap();
+    let addr3: IpAddr = "127.0.0.76".parse().unwrap();
+
+    let tree1: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let tree2: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let service1 = Service::new(tree1, port, addr1, peer_net).await.unwrap();
+    let service2 = Service::new(tree2, port, addr2, peer_net)
+        .await
+        .unwrap()
+        .with_seed(addr1);
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    let socket = UdpSocket::bind(SocketAddr::new(addr3, port)).await.unwrap();
+    let target = SocketAddr::new(addr1, port);
+    // garbage
+    socket.send_to(&[0xff; 100], target).await.unwrap();
+    // bogus length, which must not be allocated
+    let mut buf = datagram::<Message>(PROTOCOL_VERSION, &[]);
+    buf.extend_from_slice(&[
+        10, 0, 2, 253, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x7f,
+    ]);
+    socket.send_to(&buf, target).await.unwrap();
+    // a valid update followed by a truncated one
+    let value = (Utc::now(), Some("Hello".to_string()));
+    let buf = datagram(
+        PROTOCOL_VERSION,
+        &[
+            Message::Update((42, value.clone())),
+            Message::Update((43, value.clone())),
+        ],
+    );
+    socket.send_to(&buf[..buf.len() - 4], target).await.unwrap();
+
+    // the valid update is kept
+    assert_until!(service1.get(&42).is_some());
+    assert_until!(service1.metrics().snapshot().malformed_datagrams == 3);
+    assert!(service1.get(&43).is_none());
+
+    // the service keeps reconciling
+    service2.insert(0, "World".to_string(), Utc::now());
+    for _ in 0..100 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if service1.get(&0).is_some() && service2.get(&42).is_some() {
+            break;
+        }
+    }
+    assert!(service1.get(&0).is_some());
+    assert!(service2.get(&42).is_some());
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sync_range() {
+    use std::ops::Bound;
+
+    let port = 8080;
+    // no random peer discovery
+    let peer_net = "127.255.255.254/32".parse().unwrap();
+    let addr_a = "127.0.0.77".parse().unwrap();
+    let addr_b = "127.0.0.78".parse().unwrap();
+    let addr_c = "127.0.0.79".parse().unwrap();
+    let range = (Bound::Included(100), Bound::Excluded(150));
+
+    // A synchronizes everything with C, but only a range with B
+    let tree_a: HRTree<u8, DatedMaybeTombstone<u8>> =
+        HRTree::from_iter((0..200).map(|i| (i, (Utc::now(), Some(i)))));
+    let tree_b: HRTree<u8, DatedMaybeTombstone<u8>> = HRTree::new();
+    let tree_c: HRTree<u8, DatedMaybeTombstone<u8>> = HRTree::new();
+    let service_a = Service::new(tree_a, port, addr_a, peer_net)
+        .await
+        .unwrap()
+        .with_sync_range(SocketAddr::new(addr_b, port), range);
+    let service_b = Service::new(tree_b, port, addr_b, peer_net)
+        .await
+        .unwrap()
+        .with_default_sync_range(range)
+        .with_seed(addr_a);
+    let service_c = Service::new(tree_c, port, addr_c, peer_net)
+        .await
+        .unwrap()
+        .with_seed(addr_a);
+    let tasks = [
+        tokio::spawn(service_a.clone().run()),
+        tokio::spawn(service_b.clone().run()),
+        tokio::spawn(service_c.clone().run()),
+    ];
+
+    for _ in 0..100 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if service_b.read().len() == 50 && service_c.read().len() == 200 {
+            break;
+        }
+    }
+    assert_eq!(service_c.read().len(), 200);
+    assert_eq!(service_b.read().len(), 50);
+
+    // writes outside of the range are not sent to B, even by C which knows about B by gossip
+    service_a.insert(10, 0, Utc::now());
+    service_c.insert(11, 0, Utc::now());
+    service_a.insert(120, 0, Utc::now());
+    assert_until!(service_b.get(&120).is_some_and(|value| *value == 0));
+    assert_until!(service_c.get(&10).is_some_and(|value| *value == 0));
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(service_b.read().len(), 50);
+    assert!((100..150).all(|i| service_b.get(&i).is_some()));
+
+    for task in tasks {
+        task.abort();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn update() {
+    let port = 8080;
+    let peer_net = "127.0.0.1/8".parse().unwrap();
+    let addr1 = "127.0.0.80".parse().unwrap();
+    let addr2 = "127.0.0.81".parse().unwrap();
+
+    let tree1: HRTree<u8, DatedMaybeTombstone<u32>> = HRTree::new();
+    let tree2: HRTree<u8, DatedMaybeTombstone<u32>> = HRTree::new();
+    let service1 = Service::new(tree1, port, addr1, peer_net).await.unwrap();
+    let service2 = Service::new(tree2, port, addr2, peer_net)
+        .await
+        .unwrap()
+        .with_seed(addr1);
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // the current value is newer
+    let now = Utc::now();
+    assert_eq!(service1.update(1, now, |_| Some(1)), Some(1));
+    let older = now - chrono::Duration::seconds(1);
+    assert_eq!(service1.update(1, older, |_| unreachable!()), Some(1));
+    assert_eq!(
+        service1.update(1, now, |value| value.map(|v| v + 1)),
+        Some(2)
+    );
+    assert_eq!(service1.update(1, now, |_| None), None);
+    assert!(service1.get(&1).is_none());
+
+    // concurrent increments, while the peer keeps sending older values for the same key; each
+    // write has its own timestamp, since equal timestamps are settled by the value
+    let timestamp = Utc::now();
+    service1.insert(0, 0, timestamp);
+    let increments = Arc::new(AtomicU32::new(0));
+    let writers: Vec<_> = (0..8)
+        .map(|_| {
+            let service1 = service1.clone();
+            let increments = increments.clone();
+            tokio::spawn(async move {
+                for _ in 0..100 {
+                    service1.update(0, Utc::now(), |value| {
+                        increments.fetch_add(1, Ordering::Relaxed);
+                        value.map(|v| v + 1)
+                    });
+                    tokio::task::yield_now().await;
+                }
+            })
+        })
+        .collect();
+    let stale = {
+        let service2 = service2.clone();
+        tokio::spawn(async move {
+            for i in 0..100 {
+                service2.insert(0, 1000 + i, older);
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+    };
+    for writer in writers {
+        writer.await.unwrap();
+    }
+    stale.await.unwrap();
+    let increments = increments.load(Ordering::Relaxed);
+    assert!(increments > 0);
+    assert_eq!(*service1.get(&0).unwrap(), increments);
+    for _ in 0..100 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if service2.get(&0).is_some_and(|value| *value == increments) {
+            break;
+        }
+    }
+    assert_eq!(*service2.get(&0).unwrap(), increments);
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn protocol_version() {
+    let port = 8080;
+    let peer_net = "127.0.0.1/8".parse().unwrap();
+    let addr1 = "127.0.0.82".parse().unwrap();
+    let addr2 = "127.0.0.83".parse().unwrap();
+    let addr3: IpAddr = "127.0.0.84".parse().unwrap();
+
+    let tree1: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let tree2: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let service1 = Service::new(tree1, port, addr1, peer_net).await.unwrap();
+    let service2 = Service::new(tree2, port, addr2, peer_net)
+        .await
+        .unwrap()
+        .with_seed(addr1);
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // a datagram from a future version of the protocol is ignored
+    let socket = UdpSocket::bind(SocketAddr::new(addr3, port)).await.unwrap();
+    let target = SocketAddr::new(addr1, port);
+    let value = (Utc::now(), Some("Hello".to_string()));
+    let future = datagram(
+        PROTOCOL_VERSION + 1,
+        &[Message::Update((42, value.clone()))],
+    );
+    socket.send_to(&future, target).await.unwrap();
+    let current = datagram(PROTOCOL_VERSION, &[Message::Update((43, value.clone()))]);
+    socket.send_to(&current, target).await.unwrap();
+    assert_until!(service1.get(&43).is_some());
+    assert!(service1.get(&42).is_none());
+    assert_eq!(service1.metrics().snapshot().malformed_datagrams, 0);
+
+    // the service keeps reconciling on the current version
+    service2.insert(0, "World".to_string(), Utc::now());
+    for _ in 0..100 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if service1.get(&0).is_some() && service2.get(&43).is_some() {
+            break;
+        }
+    }
+    assert!(service1.get(&0).is_some());
+    assert!(service2.get(&43).is_some());
+    assert!(service2.get(&42).is_none());
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn equal_timestamps() {
+    let port = 8080;
+    let peer_net = "127.0.0.1/8".parse().unwrap();
+    let addr1 = "127.0.0.86".parse().unwrap();
+    let addr2 = "127.0.0.87".parse().unwrap();
+
+    // the same keys, with the same timestamp but different values on both sides
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    let timestamp = Utc::now();
+    let tree1: HRTree<u16, DatedMaybeTombstone<u64>> =
+        HRTree::from_iter((0..1000).map(|key| (key, (timestamp, Some(rng.gen())))));
+    let tree2: HRTree<u16, DatedMaybeTombstone<u64>> =
+        HRTree::from_iter((0..1000).map(|key| (key, (timestamp, Some(rng.gen())))));
+    let service1 = Service::new(tree1, port, addr1, peer_net).await.unwrap();
+    let service2 = Service::new(tree2, port, addr2, peer_net)
+        .await
+        .unwrap()
+        .with_seed(addr1);
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    for _ in 0..100 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if service1.read().hash(&..) == service2.read().hash(&..) {
+            break;
+        }
+    }
+    assert_eq!(service1.read().hash(&..), service2.read().hash(&..));
+    for key in 0..1000 {
+        assert_eq!(*service1.get(&key).unwrap(), *service2.get(&key).unwrap());
+    }
+
+    // the values are no longer exchanged
+    let updates_sent = |service: &Service<_>| service.metrics().snapshot().updates_sent;
+    let sent1 = updates_sent(&service1);
+    let sent2 = updates_sent(&service2);
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    assert_eq!(updates_sent(&service1), sent1);
+    assert_eq!(updates_sent(&service2), sent2);
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn graceful_shutdown() {
+    let port = 8080;
+    let peer_net = "127.0.0.1/8".parse().unwrap();
+    let addr1 = "127.0.0.88".parse().unwrap();
+    let addr2 = "127.0.0.89".parse().unwrap();
+
+    let tree1: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let tree2: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let service1 = Service::new(tree1, port, addr1, peer_net).await.unwrap();
+    let service2 = Service::new(tree2, port, addr2, peer_net)
+        .await
+        .unwrap()
+        .with_seed(addr1);
+    let task1 = tokio::spawn(service1.clone().run());
+    let handle2 = service2.clone().spawn();
+    service2.insert(1, "World".to_string(), Utc::now());
+    assert_until!(service1.get(&1).is_some());
+
+    // a write made just before the shutdown reaches the peer
+    service2.insert(0, "Hello".to_string(), Utc::now());
+    handle2.shutdown();
+    tokio::time::timeout(Duration::from_secs(1), handle2.join())
+        .await
+        .expect("the service did not shut down")
+        .unwrap();
+    assert_until!(service1.get(&0).is_some_and(|value| *value == "Hello"));
+
+    // shutting down is also possible with a future
+    let tree3: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let addr3 = "127.0.0.92".parse().unwrap();
+    let service3 = Service::new(tree3, port, addr3, peer_net).await.unwrap();
+    let run = service3.run_with_shutdown(tokio::time::sleep(Duration::from_millis(100)));
+    tokio::time::timeout(Duration::from_secs(1), run)
+        .await
+        .expect("the service did not shut down");
+
+    task1.abort();
+}
+
+/// Wait for at most 2 minutes until the provided predicate becomes true
+async fn wait_long_until<F: FnMut() -> bool>(mut f: F) -> bool {
+    for _ in 0..1200 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        if f() {
+            return true;
+        }
+    }
+    false
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn packet_loss() {
+    let network = SimNetwork::new(42);
+    network.set_default_link(LinkConfig {
+        drop_probability: 0.5,
+        duplicate_probability: 0.1,
+        latency: Duration::from_millis(1),
+        jitter: Duration::from_millis(5),
+    });
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addrs: Vec<SocketAddr> = (1..=3)
+        .map(|i| format!("10.0.0.{i}:8080").parse().unwrap())
+        .collect();
+
+    // each service starts with its own values
+    let timestamp = Utc::now();
+    let services: Vec<_> = addrs
+        .iter()
+        .enumerate()
+        .map(|(i, &addr)| {
+            let tree: HRTree<u16, DatedMaybeTombstone<u16>> =
+                HRTree::from_iter((0..100).map(|key| (key * 3 + i as u16, (timestamp, Some(key)))));
+            let mut service = Service::with_transport(tree, network.bind(addr).unwrap(), peer_net);
+            for other in &addrs {
+                if *other != addr {
+                    service = service.with_seed(other.ip());
+                }
+            }
+            service
+        })
+        .collect();
+    let tasks: Vec<_> = services
+        .iter()
+        .map(|service| tokio::spawn(service.clone().run()))
+        .collect();
+
+    // most sessions are cut short by a lost datagram, but each of them makes some progress
+    let converged = || {
+        services.iter().all(|service| service.read().len() == 300)
+            && services
+                .windows(2)
+                .all(|pair| pair[0].read().hash(&..) == pair[1].read().hash(&..))
+    };
+    assert!(wait_long_until(converged).await);
+
+    for task in tasks {
+        task.abort();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn partition() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    let tree1: HRTree<u16, DatedMaybeTombstone<String>> = HRTree::new();
+    let tree2: HRTree<u16, DatedMaybeTombstone<String>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_seed(addr2.ip());
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+    service1.insert(0, "Hello".to_string(), Utc::now());
+    assert_until!(service2.get(&0).is_some());
+
+    // the changes made during the partition do not cross it
+    network.partition(&[addr1.ip()], &[addr2.ip()]);
+    service1.insert(1, "World".to_string(), Utc::now());
+    service2.insert(0, "Goodbye".to_string(), Utc::now());
+    service2.remove(&1, Utc::now() - chrono::Duration::seconds(1));
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert_eq!(service1.get(&0).as_deref(), Some(&"Hello".to_string()));
+    assert!(service2.get(&1).is_none());
+
+    // once healed, the most recent values win on both sides
+    network.heal();
+    let converged = || {
+        service1.read().hash(&..) == service2.read().hash(&..)
+            && service1.get(&0).as_deref() == Some(&"Goodbye".to_string())
+            && service2.get(&1).as_deref() == Some(&"World".to_string())
+    };
+    assert!(wait_long_until(converged).await);
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn large_values() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    // values larger than a datagram are sent in fragments
+    let value = |seed: u8| -> Vec<u8> { (0..200_000).map(|i| (i % 251) as u8 ^ seed).collect() };
+    let tree1 = HRTree::from_iter([(0u8, (Utc::now(), Some(value(0))))]);
+    let tree2: HRTree<u8, DatedMaybeTombstone<Vec<u8>>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_seed(addr2.ip());
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // through reconciliation
+    assert_until!(service2.get(&0).as_deref() == Some(&value(0)));
+    // through broadcast
+    service2.insert(1, value(1), Utc::now());
+    assert_until!(service1.get(&1).as_deref() == Some(&value(1)));
+    assert_eq!(service1.read().hash(&..), service2.read().hash(&..));
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn spill_map() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    let path = std::env::temp_dir().join(format!("reconcile-{}-spill_map", std::process::id()));
+
+    // values on disk on one side, in memory on the other
+    let value =
+        |seed: u16| -> Blob { Blob((0..5_000).map(|i| (i % 251) as u8 ^ seed as u8).collect()) };
+    let mut map1: SpillMap<u16> = SpillMap::new(&path).unwrap();
+    let mut tree2: HRTree<u16, DatedMaybeTombstone<Blob>> = HRTree::new();
+    let timestamp = Utc::now();
+    for i in 0..100 {
+        Map::insert(&mut map1, i, (timestamp, Some(value(i))));
+        tree2.insert(i + 50, (timestamp, Some(value(i + 50))));
+    }
+    let service1 =
+        Service::with_transport(map1, network.bind(addr1).unwrap(), peer_net).with_seed(addr2.ip());
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    assert_until!(service1.read().hash(&..) == service2.read().hash(&..));
+    assert_eq!(service1.read().len(), 150);
+    assert_eq!(service1.get(&149).as_deref(), Some(&value(149)));
+    assert_eq!(service2.get(&0).as_deref(), Some(&value(0)));
+
+    // overwrites and removals, on both sides
+    service1.insert(0, value(1000), Utc::now());
+    service2.remove(&1, Utc::now());
+    assert_until!(service2.get(&0).as_deref() == Some(&value(1000)));
+    assert_until!(service1.get(&1).is_none());
+    assert_until!(service1.read().hash(&..) == service2.read().hash(&..));
+
+    task1.abort();
+    task2.abort();
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dated_tree() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    // compact slots on one side, an ordinary tree on the other
+    let timestamp = Utc::now();
+    let map1: DatedTree<u16, u64> = (0..100)
+        .map(|i| (i, (timestamp, (i % 10 != 0).then_some(i.into()))))
+        .collect();
+    let tree2: HRTree<u16, DatedMaybeTombstone<u64>> = (50..150)
+        .map(|i| (i, (timestamp, (i % 10 != 0).then_some(i.into()))))
+        .collect();
+    let service1 =
+        Service::with_transport(map1, network.bind(addr1).unwrap(), peer_net).with_seed(addr2.ip());
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    assert_until!(service1.read().hash(&..) == service2.read().hash(&..));
+    assert_eq!(service1.read().len(), 150);
+    assert_eq!(service1.get(&149).as_deref(), Some(&149));
+    assert!(service1.get(&140).is_none());
+    assert_eq!(service2.get(&1).as_deref(), Some(&1));
+
+    // overwrites and removals, on both sides
+    service1.insert(1, 1000, Utc::now());
+    service2.remove(&2, Utc::now());
+    assert_until!(service2.get(&1).as_deref() == Some(&1000));
+    assert_until!(service1.get(&2).is_none());
+    assert_until!(service1.read().hash(&..) == service2.read().hash(&..));
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn heterogeneous_ports() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    // three instances on the same host, and the third one learned through gossip
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.1:8081".parse().unwrap();
+    let addr3: SocketAddr = "10.0.0.1:8082".parse().unwrap();
+
+    let tree1 = HRTree::from_iter([(0u8, (Utc::now(), Some("Hello".to_string())))]);
+    let tree2: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let tree3: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let hash = tree1.hash(&..);
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_seed_addr(addr2);
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed_addr(addr3);
+    let service3 = Service::with_transport(tree3, network.bind(addr3).unwrap(), peer_net);
+    let tasks = [
+        tokio::spawn(service1.clone().run()),
+        tokio::spawn(service2.clone().run()),
+        tokio::spawn(service3.clone().run()),
+    ];
+
+    let knows = |service: &Service<_>, addr| service.peers().iter().any(|peer| peer.addr == addr);
+    assert_until!(knows(&service3, addr1) && knows(&service1, addr3));
+    assert_until!(service3.read().hash(&..) == hash);
+    assert!(service1.peers().iter().all(|peer| peer.addr != addr1));
+
+    // banning the host forgets the peers on every port
+    service1.ban_peer(addr2.ip(), Duration::from_secs(10));
+    assert!(service1.peers().is_empty());
+
+    for task in tasks {
+        task.abort();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn ban_peer() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    let tree1: HRTree<u16, DatedMaybeTombstone<String>> = HRTree::new();
+    let tree2: HRTree<u16, DatedMaybeTombstone<String>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_seed(addr2.ip());
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_millis(200));
+    assert!(service2.peers().is_empty());
+    service2.add_peer(addr1.ip());
+    assert!(service2.remove_peer(addr1));
+    assert!(!service2.remove_peer(addr1));
+
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+    // the peer is learned when it sends a datagram
+    assert_until!(service2.peers().iter().any(|peer| peer.addr == addr1));
+    let last_seen = service2.peers()[0].last_seen;
+    assert!(last_seen < Duration::from_secs(2), "{last_seen:?}");
+
+    // the updates from a banned peer are not applied
+    service2.ban_peer(addr1.ip(), Duration::from_secs(2));
+    assert!(service2.peers().is_empty());
+    service1.insert(0, "Hello".to_string(), Utc::now());
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    assert!(service2.get(&0).is_none());
+    assert!(service2.peers().is_empty());
+    assert_eq!(service2.metrics().snapshot().updates_applied, 0);
+
+    // until the ban expires
+    assert!(wait_long_until(|| service2.get(&0).is_some()).await);
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn subscribe() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    let tree1: HRTree<u16, DatedMaybeTombstone<String>> = HRTree::new();
+    let tree2: HRTree<u16, DatedMaybeTombstone<String>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_seed(addr2.ip());
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip());
+    let mut changes1 = service1.subscribe();
+    let mut changes2 = service2.subscribe();
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // local writes
+    let timestamp = Utc::now();
+    service1.insert_bulk(&[
+        (0, "Hello".to_string(), timestamp),
+        (1, "World".to_string(), timestamp),
+    ]);
+    let event = changes1.recv().await.unwrap();
+    assert_eq!(event.origin, ChangeOrigin::Local);
+    let keys: Vec<_> = event.changes.iter().map(|change| change.key).collect();
+    assert_eq!(keys, vec![0, 1]);
+    assert!(event
+        .changes
+        .iter()
+        .all(|change| change.timestamp == timestamp));
+    assert!(event.changes.iter().all(|change| !change.tombstone));
+    assert_eq!(event.hash, service1.read().hash(&..));
+
+    // updates applied from the peer
+    let mut keys = Vec::new();
+    while keys.len() < 2 {
+        let event = tokio::time::timeout(Duration::from_secs(5), changes2.recv())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(event.origin, ChangeOrigin::Peer(addr1));
+        keys.extend(event.changes.iter().map(|change| change.key));
+    }
+    keys.sort();
+    assert_eq!(keys, vec![0, 1]);
+
+    // removals are reported as tombstones on both sides
+    let timestamp = Utc::now();
+    service1.remove(&0, timestamp);
+    let event = changes1.recv().await.unwrap();
+    assert_eq!(event.changes.len(), 1);
+    assert!(event.changes[0].tombstone);
+    assert_eq!(event.changes[0].timestamp, timestamp);
+    let event = tokio::time::timeout(Duration::from_secs(5), changes2.recv())
+        .await
+        .unwrap()
+        .unwrap();
+    assert_eq!(event.origin, ChangeOrigin::Peer(addr1));
+    assert_eq!(event.changes[0].key, 0);
+    assert!(event.changes[0].tombstone);
+    // the maps are identical once the last event is received
+    assert_until!(service1.read().hash(&..) == service2.read().hash(&..));
+    assert_eq!(event.hash, service2.read().hash(&..));
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn static_list_discovery() {
+    let network = SimNetwork::new(42);
+    // random addresses of this network would never be the peer
+    let peer_net = "10.0.0.0/8".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    let discovery = || StaticList::new([addr1.ip(), addr2.ip()]);
+
+    let tree1: HRTree<u16, DatedMaybeTombstone<String>> = HRTree::new();
+    let tree2: HRTree<u16, DatedMaybeTombstone<String>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_discovery(discovery());
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_discovery(discovery());
+    service1.insert(0, "Hello".to_string(), Utc::now());
+    service2.insert(1, "World".to_string(), Utc::now());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    assert!(wait_long_until(|| service1.get(&1).is_some() && service2.get(&0).is_some()).await);
+    assert_eq!(service1.peers()[0].addr, addr2);
+    assert_eq!(service2.peers()[0].addr, addr1);
+
+    task1.abort();
+    task2.abort();
+}
+
+/// Resolves any hostname to the same addresses
+struct FixedResolver(Vec<IpAddr>);
+
+impl Resolver for FixedResolver {
+    fn resolve<'a>(&'a self, _: &'a str) -> DiscoveryFuture<'a, std::io::Result<Vec<IpAddr>>> {
+        Box::pin(async move { Ok(self.0.clone()) })
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn dns_discovery() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/8".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    let tree1: HRTree<u16, DatedMaybeTombstone<String>> = HRTree::new();
+    let tree2: HRTree<u16, DatedMaybeTombstone<String>> = HRTree::new();
+    let resolver = FixedResolver(vec![addr2.ip()]);
+    let service1 =
+        Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net).with_discovery(
+            DnsName::with_resolver("peers.local", Duration::from_secs(30), resolver),
+        );
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net);
+    for i in 0..100 {
+        service1.insert(i, format!("value {i}"), Utc::now());
+    }
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    assert!(wait_long_until(|| service1.read().hash(&..) == service2.read().hash(&..)).await);
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn quiet_after_convergence() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    let tree1: HRTree<u16, DatedMaybeTombstone<String>> = HRTree::new();
+    let tree2: HRTree<u16, DatedMaybeTombstone<String>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_discovery(StaticList::new([]))
+        .with_activity_timeout(Duration::from_millis(100))
+        .with_seed(addr2.ip());
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_discovery(StaticList::new([]))
+        .with_activity_timeout(Duration::from_millis(100))
+        .with_seed(addr1.ip());
+    service1.just_insert(0, "Hello".to_string(), Utc::now());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+    assert_until!(service1.read().hash(&..) == service2.read().hash(&..));
+
+    // no diff round is started while the maps are unchanged
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let sent =
+        service1.metrics().snapshot().datagrams_sent + service2.metrics().snapshot().datagrams_sent;
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let quiet_sent = service1.metrics().snapshot().datagrams_sent
+        + service2.metrics().snapshot().datagrams_sent
+        - sent;
+    assert_eq!(quiet_sent, 0);
+
+    // changes that are not broadcast still propagate promptly, from both sides
+    service1.just_insert(1, "World".to_string(), Utc::now());
+    assert_until!(service2.get(&1).is_some());
+    service2.just_insert(2, "Again".to_string(), Utc::now());
+    assert_until!(service1.get(&2).is_some());
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn insert_burst() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    let tree1: HRTree<u32, DatedMaybeTombstone<u32>> = HRTree::new();
+    let tree2: HRTree<u32, DatedMaybeTombstone<u32>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_seed(addr2.ip())
+        .with_broadcast_queue(1000, BroadcastOverflow::DropOldest);
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip());
+
+    // the writes do not wait for the updates to be sent, and only the last ones are kept
+    for i in 0..100_000 {
+        service1.insert(i, i, Utc::now());
+    }
+    assert_eq!(service1.metrics().snapshot().broadcasts_dropped, 99_000);
+
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+    let converged = || {
+        service2.read().len() == 100_000 && service1.read().hash(&..) == service2.read().hash(&..)
+    };
+    assert!(wait_long_until(converged).await);
+    assert_eq!(service1.metrics().snapshot().send_errors, 0);
+
+    task1.abort();
+    task2.abort();
+}
+
+/// Socket that records the size of the largest datagram sent
+struct MaxSizeSocket {
+    socket: SimSocket,
+    max_size: Arc<AtomicUsize>,
+}
+
+impl Transport for MaxSizeSocket {
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> TransportFuture<'a, usize> {
+        self.max_size.fetch_max(buf.len(), Ordering::Relaxed);
+        self.socket.send_to(buf, target)
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, SocketAddr)> {
+        self.socket.recv_from(buf)
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+/// Transport whose sends fail while the network is down
+struct FlakySocket {
+    socket: SimSocket,
+    down: Arc<AtomicBool>,
+}
+
+impl Transport for FlakySocket {
+    fn send_to<'a>(&'a self, buf: &'a [u8], target: SocketAddr) -> TransportFuture<'a, usize> {
+        if self.down.load(Ordering::Relaxed) {
+            return Box::pin(async { Err(std::io::ErrorKind::NetworkUnreachable.into()) });
+        }
+        self.socket.send_to(buf, target)
+    }
+
+    fn recv_from<'a>(&'a self, buf: &'a mut [u8]) -> TransportFuture<'a, (usize, SocketAddr)> {
+        self.socket.recv_from(buf)
+    }
+
+    fn local_addr(&self) -> std::io::Result<SocketAddr> {
+        self.socket.local_addr()
+    }
+}
+
+#[tokio::test]
+async fn send_errors() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    let down = Arc::new(AtomicBool::new(true));
+    let socket = FlakySocket {
+        socket: network.bind(addr1).unwrap(),
+        down: down.clone(),
+    };
+
+    let tree1: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, socket, peer_net)
+        .with_activity_timeout(Duration::from_millis(100))
+        .with_seed(addr2.ip());
+    let tree2: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_millis(100));
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // the service keeps running while it cannot send
+    service1.insert(0, "Hello".to_string(), Utc::now());
+    assert_until!(service1.last_error().is_some());
+    // NOTE: the error may be about another address of the peer network, probed by the discovery
+    assert!(matches!(
+        *service1.last_error().unwrap(),
+        reconcile::Error::Send(addr, _) if peer_net.contains(&addr.ip())
+    ));
+    assert!(service1.metrics().snapshot().send_errors > 0);
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(!task1.is_finished());
+    assert!(service2.get(&0).is_none());
+
+    // and recovers once the network is back
+    down.store(false, Ordering::Relaxed);
+    assert_until!(service2.get(&0).is_some());
+    assert_until!(service1.last_error().is_none());
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn large_keys() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    // with keys of 1000 bytes, replies with all the refined segments would not fit in a datagram
+    let key = |i: u32| format!("{i:01000}");
+    let timestamp = Utc::now();
+    let tree1 = HRTree::from_iter((0..1000).map(|i| (key(2 * i), (timestamp, Some(i)))));
+    let tree2 = HRTree::from_iter((0..1000).map(|i| (key(2 * i + 1), (timestamp, Some(i)))));
+    let max_size = Arc::new(AtomicUsize::new(0));
+    let socket = |addr| MaxSizeSocket {
+        socket: network.bind(addr).unwrap(),
+        max_size: max_size.clone(),
+    };
+    let service1 = Service::with_transport(tree1, socket(addr1), peer_net).with_seed(addr2.ip());
+    let service2 = Service::with_transport(tree2, socket(addr2), peer_net).with_seed(addr1.ip());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    let converged =
+        || service1.read().len() == 2000 && service1.read().hash(&..) == service2.read().hash(&..);
+    assert!(wait_long_until(converged).await);
+    assert!(max_size.load(Ordering::Relaxed) <= 65507);
+    let metrics1 = service1.metrics().snapshot();
+    let metrics2 = service2.metrics().snapshot();
+    assert!(metrics1.segments_deferred + metrics2.segments_deferred > 0);
+    assert_eq!(metrics1.send_errors + metrics2.send_errors, 0);
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn sync_progress() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    let timestamp = Utc::now();
+    let tree1 = HRTree::from_iter((0..10_000u32).map(|i| (i, (timestamp, Some(i)))));
+    let tree2: HRTree<u32, DatedMaybeTombstone<u32>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_seed(addr2.ip());
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip());
+    let progress = service2.sync_progress();
+    assert_eq!(progress.known_remote_size, None);
+    assert_eq!(progress.local_size, 0);
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // the new instance only ever gets closer to its peer
+    let mut last = progress;
+    let mut done = false;
+    for _ in 0..1000 {
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let progress = service2.sync_progress();
+        assert!(
+            progress.local_size >= last.local_size,
+            "{progress:?} after {last:?}"
+        );
+        assert!(progress.updates_applied >= last.updates_applied);
+        last = progress;
+        if progress.known_remote_size == Some(10_000)
+            && progress.local_size == 10_000
+            && progress.differing_ranges == 0
+        {
+            done = true;
+            break;
+        }
+    }
+    assert!(done, "{last:?}");
+    assert_eq!(last.updates_applied, 10_000);
+    assert_eq!(service1.read().hash(&..), service2.read().hash(&..));
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn auth_key() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    let addr3: SocketAddr = "10.0.0.3:8080".parse().unwrap();
+
+    // the second instance already accepts the next key
+    let key = [42; 32];
+    let tree1: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let tree2: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_seed(addr2.ip())
+        .with_auth_key(key);
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip())
+        .with_auth_keys(vec![key, [43; 32]]);
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+    service1.insert(0, "Hello".to_string(), Utc::now());
+    service2.insert(1, "World".to_string(), Utc::now());
+    assert_until!(service2.get(&0).is_some());
+
+    // forged updates, with a newer timestamp, are not applied
+    let socket = network.bind(addr3).unwrap();
+    let value = (
+        Utc::now() + chrono::Duration::hours(1),
+        Some("Forged".to_string()),
+    );
+    let mut buf = datagram(PROTOCOL_VERSION, &[Message::Update((0, value))]);
+    socket.send_to(&buf, addr1).await.unwrap();
+    socket.send_to(&buf, addr2).await.unwrap();
+    // with the magic bytes of authenticated datagrams, and a bogus tag
+    buf[1] = b'A';
+    buf.extend_from_slice(&[0; 16]);
+    socket.send_to(&buf, addr1).await.unwrap();
+    socket.send_to(&buf, addr2).await.unwrap();
+    assert_until!(
+        service1.metrics().snapshot().auth_failures == 2
+            && service2.metrics().snapshot().auth_failures == 2
+    );
+    assert_eq!(service1.get(&0).as_deref(), Some(&"Hello".to_string()));
+    assert_eq!(service2.get(&0).as_deref(), Some(&"Hello".to_string()));
+
+    // the instances keep reconciling
+    assert_until!(service1.get(&1).is_some());
+    assert_eq!(service1.read().hash(&..), service2.read().hash(&..));
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test]
+async fn chunked_values() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    let tree1: HRTree<u8, DatedMaybeTombstone<Chunked<String>>> = HRTree::new();
+    let tree2: HRTree<u8, DatedMaybeTombstone<Chunked<String>>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_seed(addr2.ip())
+        .with_chunks();
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip())
+        .with_chunks();
+
+    // a manifest whose chunks are missing is pending
+    let manifest = Chunked {
+        metadata: "missing".to_string(),
+        chunks: vec![[0; 32]],
+    };
+    service2.insert(1, manifest, Utc::now());
+    assert_eq!(service2.get_chunked(&1), Some(ChunkedValue::Pending));
+    service2.remove(&1, Utc::now());
+
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    let data: Vec<u8> = (0..300_000).map(|_| rng.gen()).collect();
+    service1.insert_chunked(0, "blob".to_string(), &data, Utc::now());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    let expected = Some(ChunkedValue::Complete("blob".to_string(), data.clone()));
+    assert_until!(service2.get_chunked(&0) == expected);
+    assert_until!(service1.read().hash(&..) == service2.read().hash(&..));
+    let mut chunks1 = service1.chunk_hashes();
+    let mut chunks2 = service2.chunk_hashes();
+    chunks1.sort();
+    chunks2.sort();
+    assert_eq!(chunks1.len(), 10);
+    assert_eq!(chunks1, chunks2);
+
+    // the chunks of the previous value are dropped on both instances
+    service1.insert_chunked(0, "small".to_string(), &data[..1000], Utc::now());
+    let expected = Some(ChunkedValue::Complete(
+        "small".to_string(),
+        data[..1000].to_vec(),
+    ));
+    assert_until!(service2.get_chunked(&0) == expected);
+    assert_eq!(service1.chunk_hashes().len(), 1);
+    assert_until!(service2.chunk_hashes() == service1.chunk_hashes());
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test]
+async fn priority_range() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    const HOT: u32 = 1_000_000;
+
+    let timestamp = Utc::now();
+    let cold = (0..100_000u32).map(|i| (i, (timestamp, Some(i))));
+    let hot = (HOT..HOT + 1000).map(|i| (i, (timestamp, Some(i))));
+    let tree1 = HRTree::from_iter(cold.chain(hot));
+    let tree2: HRTree<u32, DatedMaybeTombstone<u32>> = HRTree::new();
+    let priority = (Bound::Included(HOT), Bound::Unbounded);
+
+    // number of cold keys received when the last hot key arrived
+    let cold_received = Arc::new(AtomicUsize::new(0));
+    let hot_received = Arc::new(AtomicUsize::new(0));
+    let cold_before_hot = Arc::new(AtomicUsize::new(0));
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_seed(addr2.ip())
+        .with_priority_range(priority);
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip())
+        .with_priority_range(priority)
+        .with_pre_insert({
+            let cold_received = cold_received.clone();
+            let hot_received = hot_received.clone();
+            let cold_before_hot = cold_before_hot.clone();
+            move |&key, _, _| {
+                if key < HOT {
+                    cold_received.fetch_add(1, Ordering::Relaxed);
+                } else if hot_received.fetch_add(1, Ordering::Relaxed) + 1 == 1000 {
+                    cold_before_hot.store(cold_received.load(Ordering::Relaxed), Ordering::Relaxed);
+                }
+            }
+        });
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    assert!(wait_long_until(|| service2.read().len() == 101_000).await);
+    assert_eq!(hot_received.load(Ordering::Relaxed), 1000);
+    let cold_before_hot = cold_before_hot.load(Ordering::Relaxed);
+    assert!(
+        cold_before_hot < 10_000,
+        "{cold_before_hot} cold keys first"
+    );
+    assert_eq!(service1.read().hash(&..), service2.read().hash(&..));
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test]
+async fn fetch_latest() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    // no periodic diff during the test
+    let tree1: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let tree2: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_seed(addr2.ip())
+        .with_activity_timeout(Duration::from_secs(60));
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip())
+        .with_activity_timeout(Duration::from_secs(60));
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    // the write is not sent to the peer
+    let timestamp = Utc::now();
+    service1.just_insert(0, "Hello".to_string(), timestamp);
+    assert!(service2.get(&0).is_none());
+    let latest = service2.fetch_latest(0, 1, Duration::from_secs(1)).await;
+    assert_eq!(latest, Some((timestamp, Some("Hello".to_string()))));
+    assert_eq!(*service2.get(&0).unwrap(), "Hello");
+    assert_eq!(service1.read().hash(&..), service2.read().hash(&..));
+
+    // the peers without the key do not respond
+    let latest = service2
+        .fetch_latest(1, 1, Duration::from_millis(100))
+        .await;
+    assert_eq!(latest, None);
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn fingerprint_migration() {
+    type Dual = DualFingerprint<reconcile::DefaultFingerprint, Sum128Fingerprint>;
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    let addr3: SocketAddr = "10.0.0.3:8080".parse().unwrap();
+
+    let timestamp = Utc::now();
+    let items = |range: std::ops::Range<u32>| range.map(move |i| (i, (timestamp, Some(i))));
+    // an instance not migrated yet, and two instances restarted with both fingerprints
+    let tree1: HRTree<u32, DatedMaybeTombstone<u32>> = HRTree::from_iter(items(0..1000));
+    let tree2: HRTree<u32, DatedMaybeTombstone<u32>> = HRTree::from_iter(items(500..1500));
+    let tree3: HRTree<u32, DatedMaybeTombstone<u32>> = HRTree::from_iter(items(1000..2000));
+    let tree2: HRTree<_, _, Dual> = tree2.enable_secondary_fingerprint();
+    let tree3: HRTree<_, _, Dual> = tree3.enable_secondary_fingerprint();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_seed(addr2.ip());
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // the old instance and the migrated one compare with the old fingerprint
+    assert_until!(service1.read().len() == 1500 && service2.read().len() == 1500);
+    assert_eq!(
+        service2.metrics().snapshot().upgraded_fingerprint_sessions,
+        0
+    );
+    task1.abort();
+
+    // two migrated instances compare with the new fingerprint
+    let service3 = Service::with_transport(tree3, network.bind(addr3).unwrap(), peer_net)
+        .with_seed(addr2.ip());
+    let task3 = tokio::spawn(service3.clone().run());
+    assert_until!(service3.read().len() == 2000 && service2.read().len() == 2000);
+    assert_until!(
+        service2.metrics().snapshot().upgraded_fingerprint_sessions > 0
+            && service3.metrics().snapshot().upgraded_fingerprint_sessions > 0
+    );
+    assert_eq!(service2.read().hash(&..), service3.read().hash(&..));
+
+    // once all the instances are migrated, the old fingerprint can be dropped
+    let tree: HRTree<_, _, Sum128Fingerprint> =
+        service3.read().clone().disable_primary_fingerprint();
+    assert_eq!(tree.len(), 2000);
+
+    task2.abort();
+    task3.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn compression() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    let addr3: SocketAddr = "10.0.0.3:8080".parse().unwrap();
+
+    // verbose values, which compress well
+    let timestamp = Utc::now();
+    let json = |i: u32| format!(r#"{{"id": {i}, "name": "item {i}", "tags": ["a", "b", "c"]}}"#);
+    let tree1 = HRTree::from_iter((0..5000).map(|i| (i, (timestamp, Some(json(i))))));
+    let tree2: HRTree<u32, DatedMaybeTombstone<String>> = HRTree::new();
+    let tree3: HRTree<u32, DatedMaybeTombstone<String>> = HRTree::new();
+    // compression on both sides between 1 and 2, on one side only between 1 and 3
+    let service1 =
+        Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net).with_compression(3);
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip())
+        .with_compression(3);
+    let service3 = Service::with_transport(tree3, network.bind(addr3).unwrap(), peer_net)
+        .with_seed(addr1.ip());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+    let task3 = tokio::spawn(service3.clone().run());
+
+    let hash = service1.read().hash(&..);
+    assert_until!(service2.read().hash(&..) == hash && service3.read().hash(&..) == hash);
+    let metrics1 = service1.metrics().snapshot();
+    assert!(metrics1.datagrams_compressed > 0);
+    assert_eq!(service3.metrics().snapshot().datagrams_compressed, 0);
+    // the values were sent in fewer bytes than their size
+    let size: usize = (0..5000).map(|i| json(i).len()).sum();
+    assert!(metrics1.bytes_sent < size as u64, "{metrics1:?}");
+
+    // a corrupted compressed datagram is dropped
+    let socket = network.bind("10.0.0.4:8080".parse().unwrap()).unwrap();
+    let mut buf = vec![b'R', b'C', PROTOCOL_VERSION | 0x80];
+    buf.extend_from_slice(&[0x28, 0xb5, 0x2f, 0xfd, 0xff, 0xff, 0xff]);
+    socket.send_to(&buf, addr2).await.unwrap();
+    assert_until!(service2.metrics().snapshot().malformed_datagrams == 1);
+
+    // and the services keep reconciling
+    service3.insert(5000, json(5000), Utc::now());
+    assert_until!(service2.get(&5000).is_some());
+
+    task1.abort();
+    task2.abort();
+    task3.abort();
+}
+
+/// Clock that is off by a fixed offset
+struct SkewedClock(chrono::Duration);
+
+impl Clock for SkewedClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now() + self.0
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn clock_skew() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    let addr3: SocketAddr = "10.0.0.3:8080".parse().unwrap();
+
+    // the clock of the first service is 5 minutes ahead; only the second one guards against it
+    let ahead = chrono::Duration::minutes(5);
+    let map = || HRTree::<u32, DatedMaybeTombstone<String>>::new();
+    let service1 = Service::with_transport(map(), network.bind(addr1).unwrap(), peer_net)
+        .with_clock(SkewedClock(ahead));
+    let service2 = Service::with_transport(map(), network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip())
+        .with_max_future_timestamp_skew(Duration::from_secs(10));
+    let service3 = Service::with_transport(map(), network.bind(addr3).unwrap(), peer_net)
+        .with_seed(addr1.ip());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+    let task3 = tokio::spawn(service3.clone().run());
+
+    // the skew is estimated on both sides
+    let offset =
+        |service: &Service<_>, addr: SocketAddr| service.peer_clock_offsets().get(&addr).copied();
+    let close = |offset: Option<chrono::Duration>, expected: chrono::Duration| {
+        offset.is_some_and(|offset| (offset - expected).num_seconds().abs() < 1)
+    };
+    assert_until!(close(offset(&service2, addr1), -ahead));
+    assert_until!(close(offset(&service1, addr2), ahead));
+    assert_until!(close(offset(&service3, addr1), -ahead));
+
+    // the values dated by the clock ahead only spread where they are not guarded against
+    service1.insert(0, "from the future".to_string(), SkewedClock(ahead).now());
+    service1.insert(1, "from the present".to_string(), Utc::now());
+    assert_until!(service3.get(&0).is_some() && service3.get(&1).is_some());
+    assert_until!(service2.get(&1).is_some());
+    assert!(service2.get(&0).is_none());
+    assert!(service2.metrics().snapshot().future_timestamps_rejected > 0);
+    assert_eq!(service3.metrics().snapshot().future_timestamps_rejected, 0);
+
+    task1.abort();
+    task2.abort();
+    task3.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn merge_bulk() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    let old = Utc::now() - chrono::Duration::hours(1);
+    let now = Utc::now();
+    let tree1 = HRTree::from_iter((0..1000).map(|i| (i, (now, Some(format!("local {i}"))))));
+    let tree2 = tree1.clone();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net);
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip());
+    let changes = Arc::new(AtomicUsize::new(0));
+    let service1 = service1.with_post_insert({
+        let changes = changes.clone();
+        move |_, _, _| {
+            changes.fetch_add(1, Ordering::Relaxed);
+        }
+    });
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // older values for the even keys, newer ones for the odd keys, and new keys
+    let import = (0..2000).map(|i| {
+        let timestamp = if i % 2 == 0 { old } else { Utc::now() };
+        (i, format!("imported {i}"), timestamp)
+    });
+    let stats = service1.merge_bulk(import);
+    assert_eq!(stats.inserted, 1000);
+    assert_eq!(stats.overwritten, 500);
+    assert_eq!(stats.skipped, 500);
+    assert_eq!(changes.load(Ordering::Relaxed), 1500);
+    assert_eq!(*service1.get(&0).unwrap(), "local 0");
+    assert_eq!(*service1.get(&1).unwrap(), "imported 1");
+    assert_eq!(*service1.get(&1500).unwrap(), "imported 1500");
+
+    // the changes reach the peers
+    let hash = service1.read().hash(&..);
+    assert_until!(service2.read().hash(&..) == hash);
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn startup_backlog() {
+    let port = 8080;
+    // no random peer discovery
+    let peer_net = "127.255.255.254/32".parse().unwrap();
+    let addr1: IpAddr = "127.0.1.3".parse().unwrap();
+    let addr2: IpAddr = "127.0.1.4".parse().unwrap();
+    let activity_timeout = Duration::from_secs(1);
+
+    let map = || HRTree::<u32, DatedMaybeTombstone<String>>::new();
+    let service1 = Service::new(map(), port, addr1, peer_net)
+        .await
+        .unwrap()
+        .with_seed(addr2)
+        .with_activity_timeout(activity_timeout);
+    let service2 = Service::new(map(), port, addr2, peer_net)
+        .await
+        .unwrap()
+        .with_seed(addr1)
+        .with_activity_timeout(activity_timeout);
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // both are written to while the first one is not running yet: its peer sends it updates,
+    // and opens sessions with it
+    let key_values = |range: std::ops::Range<u32>| {
+        range
+            .map(|i| (i, format!("value {i}"), Utc::now()))
+            .collect::<Vec<_>>()
+    };
+    service1.insert_bulk(&key_values(0..10_000));
+    service2.insert_bulk(&key_values(10_000..11_000));
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(service1.metrics().snapshot().datagrams_received, 0);
+
+    // which it handles as soon as it runs
+    let task1 = tokio::spawn(service1.clone().run());
+    let start = std::time::Instant::now();
+    assert_until!(service1.read().len() == 11_000);
+    // and the peer converges within a timeout, even if some of the burst of updates is lost
+    assert!(wait_long_until(|| service2.read().len() == 11_000).await);
+    assert!(start.elapsed() < 2 * activity_timeout);
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn composite_keys() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    type Key = (String, u128);
+    let tenant_range = |tenant: &str| -> (Bound<Key>, Bound<Key>) {
+        (
+            Bound::Included((tenant.to_string(), 0)),
+            Bound::Included((tenant.to_string(), u128::MAX)),
+        )
+    };
+
+    // service2 only synchronizes the keys of one tenant
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    let now = Utc::now();
+    let tree1: HRTree<Key, DatedMaybeTombstone<u32>> = ["alpha", "beta", "gamma"]
+        .iter()
+        .flat_map(|tenant| (0..100).map(|i| ((tenant.to_string(), i), (now, Some(i as u32)))))
+        .collect();
+    let tree2: HRTree<Key, DatedMaybeTombstone<u32>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_sync_range(addr2, tenant_range("beta"));
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_default_sync_range(tenant_range("beta"))
+        .with_seed(addr1.ip());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+    assert_until!(service2.read().len() == 100);
+    assert!(service2
+        .read()
+        .iter()
+        .all(|((tenant, _), _)| tenant == "beta"));
+    assert_eq!(
+        service2.read().hash(&tenant_range("beta")),
+        service1.read().hash(&tenant_range("beta"))
+    );
+
+    // the writes of the tenant are sent, the other ones are not
+    let id: u128 = rng.gen();
+    service1.insert(("beta".to_string(), id), 1, Utc::now());
+    service1.insert(("alpha".to_string(), id), 2, Utc::now());
+    assert_until!(service2.get(&("beta".to_string(), id)).is_some());
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert!(service2.get(&("alpha".to_string(), id)).is_none());
+    assert_eq!(service2.read().range_len(&tenant_range("beta")), 101);
+    assert_eq!(service2.read().len(), 101);
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn post_batch() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    let tree1: HRTree<u16, DatedMaybeTombstone<u16>> = HRTree::new();
+    let tree2: HRTree<u16, DatedMaybeTombstone<u16>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_seed(addr2.ip());
+    let batches = Arc::new(std::sync::Mutex::new(Vec::new()));
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip())
+        .with_post_batch({
+            let batches = batches.clone();
+            move |service, summary| {
+                // the write lock is released
+                let len = service.read().len();
+                batches.lock().unwrap().push((summary, len));
+            }
+        });
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // the updates from the peer are reported once per datagram
+    let timestamp = Utc::now();
+    let key_values: Vec<_> = (0..1000).map(|i| (i, i, timestamp)).collect();
+    service1.insert_bulk(&key_values);
+    let received = || {
+        batches
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(summary, _)| summary.changed)
+            .sum::<usize>()
+    };
+    assert!(wait_long_until(|| received() == 1000).await);
+    {
+        let batches = batches.lock().unwrap();
+        assert!(!batches.is_empty() && batches.len() < 100);
+        for (summary, _) in batches.iter() {
+            assert_eq!(summary.origin, ChangeOrigin::Peer(addr1));
+            assert_eq!(summary.tombstones, 0);
+        }
+        let (summary, len) = batches.last().unwrap();
+        assert_eq!(*len, 1000);
+        assert_eq!(summary.hash, service2.read().hash(&..));
+    }
+
+    // a bulk insertion or removal is a single batch
+    batches.lock().unwrap().clear();
+    let timestamp = Utc::now();
+    let key_values: Vec<_> = (1000..1010).map(|i| (i, i, timestamp)).collect();
+    service2.insert_bulk(&key_values);
+    let keys: Vec<_> = (0..5).map(|i| (i, timestamp)).collect();
+    service2.remove_bulk(&keys);
+    {
+        let batches = batches.lock().unwrap();
+        assert_eq!(batches.len(), 2);
+        assert_eq!(batches[0].0.changed, 10);
+        assert_eq!(batches[0].0.tombstones, 0);
+        assert_eq!(batches[0].0.origin, ChangeOrigin::Local);
+        assert_eq!(batches[1].0.changed, 5);
+        assert_eq!(batches[1].0.tombstones, 5);
+        assert_eq!(batches[1].0.hash, service2.read().hash(&..));
+    }
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn divergences() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    // the second service rejects the value of the first one, which keeps it since it is newer
+    let timestamp = Utc::now();
+    let map = || -> HRTree<u32, DatedMaybeTombstone<u32>> {
+        (0..100).map(|i| (i, (timestamp, Some(i)))).collect()
+    };
+    let service1 = Service::with_transport(map(), network.bind(addr1).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_millis(100));
+    let service2 = Service::with_transport(map(), network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip())
+        .with_activity_timeout(Duration::from_millis(100))
+        .with_max_future_timestamp_skew(Duration::from_secs(10));
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+    service1.insert(42, 0, Utc::now() + chrono::Duration::hours(1));
+
+    // the range of the key is reported as divergent, for longer and longer
+    let divergences = || {
+        let mut divergences = service1.divergences();
+        divergences.extend(service2.divergences());
+        divergences
+    };
+    assert_until!(!divergences().is_empty());
+    for divergence in divergences() {
+        assert!([addr1, addr2].contains(&divergence.peer));
+        assert!(divergence.range.contains(&42));
+    }
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let divergences_now = divergences();
+    assert!(!divergences_now.is_empty());
+    assert!(divergences_now
+        .iter()
+        .all(|divergence| divergence.range.contains(&42)));
+    let oldest = divergences_now
+        .iter()
+        .map(|divergence| divergence.first_seen)
+        .max()
+        .unwrap();
+    assert!(oldest >= Duration::from_millis(1500));
+    let max_age = |service: &Service<_>| service.metrics().snapshot().max_divergence_age_ms;
+    assert!(max_age(&service1).max(max_age(&service2)) >= 1500);
+
+    // once resolved, the list drains
+    service1.insert(42, 1, Utc::now());
+    assert!(wait_long_until(|| divergences().is_empty()).await);
+    assert_eq!(service2.get(&42).as_deref(), Some(&1));
+    assert_eq!(max_age(&service1), 0);
+    assert_eq!(max_age(&service2), 0);
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn update_flood() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    let addr3: SocketAddr = "10.0.0.3:8080".parse().unwrap();
+    let tree1: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let tree2: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_peer_update_rate(10_000)
+        .with_update_chunk(256);
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+    service1.insert(0, "Hello".to_string(), Utc::now());
+
+    // a faulty peer sends a million updates, newer at each one
+    let socket = network.bind(addr3).unwrap();
+    let start = Utc::now();
+    for i in 0..1000 {
+        let messages: Vec<_> = (0..1000)
+            .map(|j| {
+                let timestamp = start + chrono::Duration::microseconds(i * 1000 + j);
+                Message::Update(((j % 256) as u8, (timestamp, Some(String::new()))))
+            })
+            .collect();
+        socket
+            .send_to(&datagram(PROTOCOL_VERSION, &messages), addr1)
+            .await
+            .unwrap();
+    }
+
+    // meanwhile, the local reads are not stalled
+    let done = Arc::new(std::sync::atomic::AtomicBool::new(false));
+    let reader = std::thread::spawn({
+        let service1 = service1.clone();
+        let done = done.clone();
+        move || {
+            let mut max_latency = Duration::ZERO;
+            while !done.load(Ordering::Relaxed) {
+                let start = std::time::Instant::now();
+                let _ = service1.get(&0);
+                max_latency = max_latency.max(start.elapsed());
+                std::thread::sleep(Duration::from_millis(1));
+            }
+            max_latency
+        }
+    });
+    assert!(
+        wait_long_until(|| {
+            let metrics = service1.metrics().snapshot();
+            metrics.updates_applied + metrics.updates_rejected + metrics.updates_throttled
+                >= 1_000_000
+        })
+        .await
+    );
+    done.store(true, Ordering::Relaxed);
+    let max_latency = reader.join().unwrap();
+    assert!(max_latency < Duration::from_millis(200), "{max_latency:?}");
+    let metrics = service1.metrics().snapshot();
+    assert!(metrics.updates_throttled > 0);
+    assert!(metrics.updates_throttled < 1_000_000);
+
+    // the service keeps reconciling with the legitimate peer
+    service2.insert(
+        255,
+        "World".to_string(),
+        Utc::now() + chrono::Duration::hours(1),
+    );
+    assert!(wait_long_until(|| service1.get(&255).as_deref() == Some(&"World".to_string())).await);
+
+    task1.abort();
+    task2.abort();
+}
+
+#[cfg(feature = "metrics-prometheus")]
+#[tokio::test(flavor = "multi_thread")]
+async fn prometheus() {
+    use prometheus_parse::{Scrape, Value};
+
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    let tree1: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let tree2: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net);
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip());
+
+    let scrape = |service: &Service<_>| {
+        let text = service.render_prometheus();
+        let scrape = Scrape::parse(text.lines().map(|line| Ok(line.to_string()))).unwrap();
+        move |name: &str, peer: Option<SocketAddr>| {
+            let sample = scrape
+                .samples
+                .iter()
+                .find(|sample| {
+                    sample.metric == name
+                        && peer.is_none_or(|peer| {
+                            sample.labels.get("peer") == Some(peer.to_string().as_str())
+                        })
+                })
+                .unwrap_or_else(|| panic!("missing {name}"));
+            match sample.value {
+                Value::Counter(value) | Value::Gauge(value) | Value::Untyped(value) => value,
+                _ => panic!("unexpected type for {name}"),
+            }
+        }
+    };
+    let before = scrape(&service1);
+    assert_eq!(before("reconcile_updates_applied_total", None), 0.);
+    assert_eq!(before("reconcile_peers", None), 0.);
+
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+    for key in 0..10 {
+        service2.insert(key, "Hello".to_string(), Utc::now());
+    }
+    assert_until!(service1.read().hash(&..) == service2.read().hash(&..));
+
+    let after = scrape(&service1);
+    assert!(after("reconcile_bytes_received_total", None) > 0.);
+    assert!(after("reconcile_datagrams_sent_total", None) > 0.);
+    assert_eq!(after("reconcile_updates_applied_total", None), 10.);
+    assert_eq!(after("reconcile_map_size", None), 10.);
+    assert_eq!(after("reconcile_peers", None), 1.);
+    assert_eq!(
+        after("reconcile_peer_updates_applied_total", Some(addr2)),
+        10.
+    );
+    assert!(after("reconcile_peer_last_seen_seconds", Some(addr2)) < 1.);
+    let text = service1.render_prometheus();
+    let root_hash = service1.read().hash(&..).to_string();
+    assert!(text.contains(&format!("reconcile_info{{root_hash=\"{root_hash}\"}} 1")));
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn deletion_journal() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    let written = Utc::now() - chrono::Duration::seconds(10);
+
+    // the key is deleted, and the tombstone expires
+    let tree1: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_tombstone_timeout(Duration::from_millis(100))
+        .with_deletion_horizon(Duration::from_secs(60));
+    let task1 = tokio::spawn(service1.clone().run());
+    service1.insert(0, "Hello".to_string(), written);
+    service1.insert(1, "World".to_string(), written);
+    service1.remove(&0, Utc::now());
+    assert!(wait_long_until(|| service1.read().get(&0).is_none()).await);
+
+    // a stale peer, which missed the deletion, does not bring the value back
+    let mut tree2 = HRTree::new();
+    tree2.insert(0, (written, Some("Hello".to_string())));
+    tree2.insert(1, (written, Some("World".to_string())));
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip());
+    let task2 = tokio::spawn(service2.clone().run());
+    assert_until!(service2.get(&0).is_none());
+    // the instances keep reconciling, since the first one dropped the tombstone
+    for _ in 0..20 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(service1.get(&0).is_none());
+        assert!(service2.get(&0).is_none());
+    }
+    assert!(service1.get(&1).is_some());
+    assert!(service2.get(&1).is_some());
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test]
+async fn pause_sync() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    let now = Utc::now();
+
+    let tree1: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_millis(100));
+    let tree2: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_millis(100))
+        .with_seed(addr1.ip());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+    service1.insert(0, "Hello".to_string(), now);
+    assert_until!(service2.get(&0).is_some());
+
+    // the instances do not converge while the second one is paused
+    service2.pause_sync();
+    assert!(service2.is_paused());
+    service1.insert(1, "World".to_string(), now);
+    service1.insert(3, "Old".to_string(), now);
+    service2.insert(2, "Local".to_string(), now);
+    service2.insert(3, "New".to_string(), now + chrono::Duration::seconds(1));
+    for _ in 0..10 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert!(service1.get(&2).is_none());
+        assert!(service2.get(&1).is_none());
+        assert_eq!(service1.get(&3).unwrap().as_str(), "Old");
+        assert_eq!(service2.get(&3).unwrap().as_str(), "New");
+    }
+    assert!(service2.metrics().snapshot().datagrams_paused > 0);
+
+    // they converge in both directions once it resumes
+    service2.resume_sync();
+    assert!(!service2.is_paused());
+    assert_until!(service1.get(&2).is_some() && service2.get(&1).is_some());
+    assert_until!(service1
+        .get(&3)
+        .is_some_and(|value| value.as_str() == "New"));
+    assert_eq!(service2.get(&3).unwrap().as_str(), "New");
+
+    task1.abort();
+    task2.abort();
+}
+
+/// Set of tags versioned by a version vector, whose concurrent writes are merged
+#[derive(Clone, Debug, Default, Deserialize, Hash, PartialEq, Serialize)]
+struct Tags {
+    version: BTreeMap<u8, u64>,
+    tags: BTreeSet<String>,
+}
+
+impl Tags {
+    fn with_tag(&self, node: u8, tag: &str) -> Tags {
+        let mut tags = self.clone();
+        *tags.version.entry(node).or_default() += 1;
+        tags.tags.insert(tag.to_string());
+        tags
+    }
+
+    fn dominates(&self, other: &Tags) -> bool {
+        other
+            .version
+            .iter()
+            .all(|(node, count)| self.version.get(node).unwrap_or(&0) >= count)
+    }
+}
+
+fn resolve_tags(local: &Tags, incoming: &Tags) -> Resolution<Tags> {
+    if local.dominates(incoming) {
+        Resolution::KeepLocal
+    } else if incoming.dominates(local) {
+        Resolution::KeepIncoming
+    } else {
+        let mut merged = local.clone();
+        for (&node, &count) in &incoming.version {
+            let local = merged.version.entry(node).or_default();
+            *local = count.max(*local);
+        }
+        merged.tags.extend(incoming.tags.iter().cloned());
+        Resolution::Merge(merged)
+    }
+}
+
+#[tokio::test]
+async fn conflict_policy() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    // no reconciliation session during the test: the values only travel as local writes, and as
+    // merged values sent back
+    let tree1: HRTree<u8, Tags> = HRTree::new();
+    let service1 = Service::with_transport_and_policy(
+        tree1,
+        resolve_tags,
+        network.bind(addr1).unwrap(),
+        peer_net,
+    )
+    .with_activity_timeout(Duration::from_secs(60))
+    .with_seed_addr(addr2);
+    let tree2: HRTree<u8, Tags> = HRTree::new();
+    let service2 = Service::with_transport_and_policy(
+        tree2,
+        resolve_tags,
+        network.bind(addr2).unwrap(),
+        peer_net,
+    )
+    .with_activity_timeout(Duration::from_secs(60))
+    .with_seed_addr(addr1);
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // a newer version replaces the older one
+    let first = Tags::default().with_tag(1, "a");
+    service1.insert_value(0, first.clone());
+    assert_until!(service2.get_value(&0).as_deref() == Some(&first));
+    service2.update_value(0, |tags| Some(tags.unwrap().with_tag(2, "b")));
+    assert_until!(service1
+        .get_value(&0)
+        .is_some_and(|tags| tags.tags.contains("b")));
+
+    // concurrent versions are merged, and the merged value is sent back
+    service2.pause_sync();
+    service1.update_value(0, |tags| Some(tags.unwrap().with_tag(1, "c")));
+    service2.update_value(0, |tags| Some(tags.unwrap().with_tag(2, "d")));
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(!service2.get_value(&0).unwrap().tags.contains("c"));
+    service2.resume_sync();
+    let expected: BTreeSet<String> = ["a", "b", "c", "d"].map(String::from).into();
+    assert_until!(service1
+        .get_value(&0)
+        .is_some_and(|tags| tags.tags == expected));
+    assert_until!(service2
+        .get_value(&0)
+        .is_some_and(|tags| tags.tags == expected));
+    assert_eq!(service1.read().hash(&..), service2.read().hash(&..));
+    assert_eq!(service2.get_value(&0).unwrap().version[&2], 2);
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn values_without_tombstones() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    // dated values, but never removed
+    let timestamp = Utc::now();
+    let tree1: HRTree<u32, (DateTime<Utc>, String)> =
+        HRTree::from_iter((0..100).map(|i| (i, (timestamp, format!("{i}")))));
+    let tree2: HRTree<u32, (DateTime<Utc>, String)> = HRTree::new();
+    let service1 = Service::with_transport_and_policy(
+        tree1,
+        LwwPolicy,
+        network.bind(addr1).unwrap(),
+        peer_net,
+    )
+    .with_seed(addr2.ip());
+    let service2 = Service::with_transport_and_policy(
+        tree2,
+        LwwPolicy,
+        network.bind(addr2).unwrap(),
+        peer_net,
+    )
+    .with_seed(addr1.ip());
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+    assert_until!(service2.read().len() == 100);
+
+    // the most recent value wins
+    let later = timestamp + chrono::Duration::seconds(1);
+    service2.insert_value(0, (later, "new".to_string()));
+    service1.insert_value(0, (timestamp, "old".to_string()));
+    let key_values: Vec<_> = (100..200).map(|i| (i, (later, format!("{i}")))).collect();
+    service1.insert_bulk_values(&key_values).sent().await;
+    assert_until!(service2.read().len() == 200);
+    assert_until!(service1.get_value(&0).is_some_and(|value| value.1 == "new"));
+    assert_eq!(service1.read().hash(&..), service2.read().hash(&..));
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test]
+async fn schema_mismatch() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    let mut tree1: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    tree1.insert(0, (Utc::now(), Some("Hello".to_string())));
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_millis(100))
+        .with_schema_id(1)
+        .with_seed_addr(addr2);
+    let mut tree2: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    tree2.insert(1, (Utc::now(), Some("World".to_string())));
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_millis(100))
+        .with_schema_id(2);
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // each instance marks the other as incompatible
+    let incompatible = |service: &Service<_>, addr| {
+        service
+            .peers()
+            .iter()
+            .any(|peer| peer.addr == addr && peer.incompatible)
+    };
+    assert_until!(incompatible(&service1, addr2));
+    assert_until!(incompatible(&service2, addr1));
+
+    // and never sends it updates, neither from the sessions nor from the local writes
+    service1.insert(2, "!".to_string(), Utc::now());
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    assert_eq!(service1.read().len(), 2);
+    assert_eq!(service2.read().len(), 1);
+    assert_eq!(service1.metrics().snapshot().updates_applied, 0);
+    assert_eq!(service2.metrics().snapshot().updates_applied, 0);
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test]
+async fn schema_match() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    let mut tree1: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    tree1.insert(0, (Utc::now(), Some("Hello".to_string())));
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_millis(100))
+        .with_schema_id(7)
+        .with_seed_addr(addr2);
+    let mut tree2: HRTree<u8, DatedMaybeTombstone<String>> = HRTree::new();
+    tree2.insert(1, (Utc::now(), Some("World".to_string())));
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_millis(100))
+        .with_schema_id(7);
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    assert_until!(service1.read().len() == 2 && service2.read().len() == 2);
+    assert_eq!(service1.read().hash(&..), service2.read().hash(&..));
+    assert!(service1.peers().iter().all(|peer| !peer.incompatible));
+    assert!(service2.peers().iter().all(|peer| !peer.incompatible));
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn summary_is_consistent() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let tree: HRTree<u16, DatedMaybeTombstone<u32>> = HRTree::new();
+    let service = Service::with_transport(tree, network.bind(addr).unwrap(), peer_net);
+    let start = service.summary();
+    assert_eq!(start.len, 0);
+
+    // sample the summary while a single thread writes, recording each state of the map
+    let done = Arc::new(AtomicBool::new(false));
+    let sampler = std::thread::spawn({
+        let service = service.clone();
+        let done = done.clone();
+        move || {
+            let mut samples = BTreeSet::new();
+            while !done.load(Ordering::Relaxed) {
+                let summary = service.summary();
+                samples.insert((summary.root_hash, summary.len));
+            }
+            samples
+        }
+    });
+    let mut states = BTreeSet::from([(start.root_hash, start.len)]);
+    let mut rng = rand::rngs::StdRng::seed_from_u64(42);
+    for i in 0..20000u32 {
+        let key = rng.gen_range(0..2000);
+        if i % 5 == 0 {
+            service.remove(&key, Utc::now());
+        } else {
+            service.insert(key, i, Utc::now());
+        }
+        let guard = service.read();
+        states.insert((guard.hash(&..), guard.len()));
+    }
+    done.store(true, Ordering::Relaxed);
+    let samples = sampler.join().unwrap();
+    assert!(samples.len() > 1);
+    assert!(samples.is_subset(&states));
+
+    let summary = service.summary();
+    assert_eq!(summary.root_hash, service.read().hash(&..));
+    assert!(summary.last_change >= start.last_change);
+}
+
+/// Synchronize two instances over a link that duplicates every datagram, and return the number of
+/// calls to the post-insertion callback of the receiver, and its metrics.
+async fn duplicated_sync(dedup_window: usize) -> (usize, reconcile::MetricsSnapshot) {
+    let network = SimNetwork::new(42);
+    let duplicating = LinkConfig {
+        drop_probability: 0.,
+        duplicate_probability: 1.,
+        latency: Duration::from_millis(1),
+        jitter: Duration::ZERO,
+    };
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    network.set_link(addr1.ip(), addr2.ip(), duplicating);
+    network.set_link(addr2.ip(), addr1.ip(), duplicating);
+
+    let timestamp = Utc::now();
+    let tree1: HRTree<u16, DatedMaybeTombstone<u16>> =
+        HRTree::from_iter((0..100).map(|key| (key, (timestamp, Some(key)))));
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_secs(60))
+        .with_dedup_window(dedup_window)
+        .with_seed_addr(addr2);
+    let inserted = Arc::new(AtomicUsize::new(0));
+    let tree2: HRTree<u16, DatedMaybeTombstone<u16>> = HRTree::new();
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_secs(60))
+        .with_dedup_window(dedup_window)
+        .with_post_insert({
+            let inserted = inserted.clone();
+            move |_, _, _| {
+                inserted.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    assert_until!(service2.read().len() == 100);
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    task1.abort();
+    task2.abort();
+    (
+        inserted.load(Ordering::Relaxed),
+        service2.metrics().snapshot(),
+    )
+}
+
+#[tokio::test]
+async fn duplicated_datagrams() {
+    let (inserted, metrics) = duplicated_sync(128).await;
+    // each value is inserted once, and each segment is answered once
+    assert_eq!(inserted, 100);
+    assert!(metrics.duplicate_datagrams > 0);
+    assert_eq!(metrics.duplicate_datagrams * 2, metrics.datagrams_received);
+
+    // without the detection, the duplicated updates are skipped, but the segments are answered
+    // twice
+    let (inserted, undetected) = duplicated_sync(0).await;
+    assert_eq!(inserted, 100);
+    assert_eq!(undetected.duplicate_datagrams, 0);
+    assert!(undetected.segments_processed > metrics.segments_processed);
+    assert!(undetected.datagrams_sent > metrics.datagrams_sent);
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn bootstrap_from_snapshot() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    let timestamp = Utc::now() - chrono::Duration::seconds(10);
+    let tree1: HRTree<u32, DatedMaybeTombstone<u32>> =
+        HRTree::from_iter((0..100_000).map(|key| (key, (timestamp, Some(key)))));
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_millis(100));
+    let tombstone_time = timestamp + chrono::Duration::seconds(1);
+    service1.remove(&5, tombstone_time);
+    let task1 = tokio::spawn(service1.clone().run());
+
+    // the source keeps writing while its snapshot is streamed
+    let (reader, writer) = tokio::io::duplex(64 * 1024);
+    let serving = tokio::spawn({
+        let service1 = service1.clone();
+        async move { service1.serve_snapshot(writer).await.unwrap() }
+    });
+    let done = Arc::new(AtomicBool::new(false));
+    let writing = tokio::spawn({
+        let service1 = service1.clone();
+        let done = done.clone();
+        async move {
+            let mut writes = 0;
+            while !done.load(Ordering::Relaxed) && writes < 2000 {
+                if writes % 4 == 0 {
+                    service1.remove(&(writes * 13), Utc::now());
+                } else {
+                    service1.insert(100_000 + writes, writes, Utc::now());
+                }
+                writes += 1;
+                tokio::time::sleep(Duration::from_micros(100)).await;
+            }
+            writes
+        }
+    });
+    let tree2: HRTree<u32, DatedMaybeTombstone<u32>> = HRTree::new();
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_millis(100))
+        .with_seed_addr(addr1);
+    let read = service2.bootstrap_from(reader).await.unwrap();
+    done.store(true, Ordering::Relaxed);
+    assert_eq!(read, serving.await.unwrap());
+    let writes = writing.await.unwrap();
+    assert!(read >= 100_000);
+    // the tombstones keep their timestamps
+    assert_eq!(
+        service2.get_value(&5).as_deref(),
+        Some(&(tombstone_time, None))
+    );
+    assert_eq!(
+        service2.get_value(&6).as_deref(),
+        Some(&(timestamp, Some(6)))
+    );
+
+    // only the changes made during the transfer are reconciled
+    let task2 = tokio::spawn(service2.clone().run());
+    let hash = service1.read().hash(&..);
+    assert!(wait_long_until(|| service2.read().hash(&..) == hash).await);
+    let updates = service1.metrics().snapshot().updates_applied
+        + service2.metrics().snapshot().updates_applied;
+    assert!(
+        updates <= 2 * u64::from(writes) + 100,
+        "{updates} updates for {writes} writes"
+    );
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test]
+async fn oversize_datagrams() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    // the sender packs its updates in datagrams of up to 64 KB, which the receiver cannot hold
+    let timestamp = Utc::now();
+    let tree1: HRTree<u16, DatedMaybeTombstone<String>> =
+        HRTree::from_iter((0..2000).map(|key| (key, (timestamp, Some("x".repeat(100))))));
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_millis(200))
+        .with_seed_addr(addr2);
+    let tree2: HRTree<u16, DatedMaybeTombstone<String>> = HRTree::new();
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_millis(200))
+        .with_recv_buffer_size(8000);
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // the sender is asked for smaller datagrams, and the pair converges
+    assert!(wait_long_until(|| service2.read().len() == 2000).await);
+    assert_eq!(service1.read().hash(&..), service2.read().hash(&..));
+    assert!(service2.metrics().snapshot().oversize_datagrams > 0);
+    let peer = service2
+        .peers()
+        .into_iter()
+        .find(|peer| peer.addr == addr1)
+        .unwrap();
+    assert!(peer.oversize_datagrams > 0);
+    let health = service1.health();
+    assert!(matches!(health.narrowed_peers[..], [(addr, size)] if addr == addr2 && size <= 8000));
+    assert!(health.is_healthy());
+    // the datagrams received since fit in the buffer
+    assert!(service2.health().oversize_peers.is_empty());
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test]
+async fn origin_tracking() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    let tree1: HRTree<u16, DatedMaybeTombstone<u16>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_origin_tracking()
+        .with_seed_addr(addr2);
+    let peer_origins = Arc::new(AtomicUsize::new(0));
+    let tree2: HRTree<u16, DatedMaybeTombstone<u16>> = HRTree::new();
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_origin_tracking()
+        .with_pre_insert_origin({
+            let peer_origins = peer_origins.clone();
+            move |_, _, _, origin| {
+                if origin == ChangeOrigin::Peer(addr1) {
+                    peer_origins.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+        });
+    for key in 0..10 {
+        service1.insert(key, key, Utc::now());
+    }
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    assert_until!(service2.read().len() == 10);
+    assert_eq!(peer_origins.load(Ordering::Relaxed), 10);
+    for key in 0..10 {
+        let origin = service1.origin_of(&key).unwrap();
+        assert_eq!(origin.source, ChangeOrigin::Local);
+        let origin = service2.origin_of(&key).unwrap();
+        assert_eq!(origin.source, ChangeOrigin::Peer(addr1));
+    }
+    assert_eq!(service2.origin_of(&10), None);
+
+    // an overwrite flips the origin on both sides
+    service2.insert(0, 100, Utc::now());
+    assert_eq!(
+        service2.origin_of(&0).map(|origin| origin.source),
+        Some(ChangeOrigin::Local)
+    );
+    assert_until!(service1.get(&0).is_some_and(|value| *value == 100));
+    assert_eq!(
+        service1.origin_of(&0).map(|origin| origin.source),
+        Some(ChangeOrigin::Peer(addr2))
+    );
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn insert_now() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    let tree1: HRTree<u16, DatedMaybeTombstone<u32>> = HRTree::new();
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_seed_addr(addr2);
+    let tree2: HRTree<u16, DatedMaybeTombstone<u32>> = HRTree::new();
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net);
+
+    // both instances write the same keys in a tight loop at the same time
+    let writers: Vec<_> = [service1.clone(), service2.clone()]
+        .into_iter()
+        .enumerate()
+        .map(|(writer, service)| {
+            std::thread::spawn(move || {
+                let mut last = DateTime::<Utc>::MIN_UTC;
+                for i in 0..100_000u32 {
+                    let key = (i % 1000) as u16;
+                    service.insert_now(key, writer as u32);
+                    let timestamp = service.read().get(&key).unwrap().0;
+                    assert!(timestamp > last);
+                    last = timestamp;
+                }
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap();
+    }
+    service1.insert_bulk_now(&[(1000, 0), (1001, 0)]);
+    service2.remove_now(&1001);
+
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+    assert_until!(service1.read().hash(&..) == service2.read().hash(&..));
+
+    // no two writes share a timestamp, so no conflict was left to the tie breaker
+    let timestamps: BTreeSet<_> = service1.read().iter().map(|(_, (t, _))| *t).collect();
+    assert_eq!(timestamps.len(), 1002);
+    assert!(service1.get(&1001).is_none());
+
+    task1.abort();
+    task2.abort();
+}
+
+type EncryptedMap = CodecMap<HRTree<u16, DatedMaybeTombstone<Vec<u8>>>, String, String>;
+
+/// Map encrypted with a toy cipher: the id of the key, followed by the bytes xored with it
+fn encrypted_map(key: u8) -> EncryptedMap {
+    let encode = move |value: &String| {
+        let mut bytes = vec![key];
+        bytes.extend(value.bytes().map(|byte| byte ^ key));
+        bytes
+    };
+    let decode = move |bytes: &[u8]| match bytes.split_first() {
+        Some((&id, rest)) if id == key => {
+            String::from_utf8(rest.iter().map(|byte| byte ^ key).collect())
+                .map_err(|err| err.to_string())
+        }
+        _ => Err("encrypted with another key".to_string()),
+    };
+    CodecMap::new(HRTree::new(), encode, decode)
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn value_codec() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+    let addr3: SocketAddr = "10.0.0.3:8080".parse().unwrap();
+
+    let service1 =
+        Service::with_transport(encrypted_map(1), network.bind(addr1).unwrap(), peer_net)
+            .with_seed_addr(addr2)
+            .with_seed_addr(addr3);
+    let decoded = Arc::new(AtomicUsize::new(0));
+    let service2 =
+        Service::with_transport(encrypted_map(1), network.bind(addr2).unwrap(), peer_net)
+            .with_pre_insert_decoded({
+                let decoded = decoded.clone();
+                move |_, value, _| {
+                    if matches!(value, Some(Ok(_))) {
+                        decoded.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+            });
+    // a peer with another key
+    let service3 =
+        Service::with_transport(encrypted_map(2), network.bind(addr3).unwrap(), peer_net);
+    for key in 0..10 {
+        service1.insert_encoded(key, &format!("value {key}"), Utc::now());
+    }
+    let tasks = [
+        tokio::spawn(service1.clone().run()),
+        tokio::spawn(service2.clone().run()),
+        tokio::spawn(service3.clone().run()),
+    ];
+
+    // the instances sharing the key read the values
+    assert_until!(service2.read().len() == 10);
+    assert_eq!(service2.try_get(&3), Some(Ok("value 3".to_string())));
+    assert_eq!(decoded.load(Ordering::Relaxed), 10);
+    let value =
+        service2.update_decoded(3, Utc::now(), |value| Some(format!("{}!", value.unwrap())));
+    assert_eq!(value.as_deref(), Some("value 3!"));
+    assert_until!(service1.try_get(&3) == Some(Ok("value 3!".to_string())));
+
+    // the other one holds the same bytes, but cannot decode them
+    assert_until!(service3.read().hash(&..) == service1.read().hash(&..));
+    assert_eq!(service1.read().hash(&..), service2.read().hash(&..));
+    assert_eq!(
+        service3.try_get(&3),
+        Some(Err("encrypted with another key".to_string()))
+    );
+    assert_eq!(service3.update_decoded(3, Utc::now(), |_| None), None);
+    assert_eq!(
+        service3.read().inner().get(&3),
+        service1.read().inner().get(&3)
+    );
+
+    for task in tasks {
+        task.abort();
+    }
+}
+
+/// Same id as the [`DefaultFingerprint`], but other hashes, like an instance with a bug
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct SkewedFingerprint;
+
+impl FingerprintStrategy for SkewedFingerprint {
+    type Output = u64;
+
+    fn hash<K: Hash, V: Hash>(key: &K, value: &V) -> u64 {
+        DefaultFingerprint::hash(key, value).rotate_left(1)
+    }
+
+    fn identity() -> u64 {
+        0
+    }
+
+    fn combine(a: u64, b: u64) -> u64 {
+        a ^ b
+    }
+
+    fn invert(a: u64) -> u64 {
+        a
+    }
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn divergence_alert() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    // the instances hold the same items, but never agree on the global hash
+    let timestamp = Utc::now();
+    let items = || (0..100).map(|i| (i, (timestamp, Some(i))));
+    let tree1: HRTree<u32, DatedMaybeTombstone<u32>> = HRTree::from_iter(items());
+    let mut tree2: HRTree<u32, DatedMaybeTombstone<u32>, SkewedFingerprint> = HRTree::default();
+    tree2.extend(items());
+    let alerts = Arc::new(Mutex::new(Vec::new()));
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_millis(100))
+        .with_divergence_alert(Duration::from_millis(500), {
+            let alerts = alerts.clone();
+            move |peer, elapsed| alerts.lock().unwrap().push((peer, elapsed))
+        });
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip())
+        .with_activity_timeout(Duration::from_millis(100));
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    // the alert fires once the threshold is crossed, and only once
+    assert!(wait_long_until(|| !alerts.lock().unwrap().is_empty()).await);
+    tokio::time::sleep(Duration::from_secs(1)).await;
+    let alerts = alerts.lock().unwrap().clone();
+    assert_eq!(alerts.len(), 1);
+    assert_eq!(alerts[0].0, addr2);
+    assert!(alerts[0].1 >= Duration::from_millis(500));
+
+    task1.abort();
+    task2.abort();
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn no_divergence_alert() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    // a large initial synchronization, shorter than the threshold
+    let timestamp = Utc::now();
+    let tree1: HRTree<u32, DatedMaybeTombstone<u32>> =
+        (0..10_000).map(|i| (i, (timestamp, Some(i)))).collect();
+    let tree2: HRTree<u32, DatedMaybeTombstone<u32>> = HRTree::new();
+    let alerts = Arc::new(AtomicUsize::new(0));
+    let alert = {
+        let alerts = alerts.clone();
+        move |_, _| {
+            alerts.fetch_add(1, Ordering::Relaxed);
+        }
+    };
+    let threshold = Duration::from_secs(3);
+    let service1 = Service::with_transport(tree1, network.bind(addr1).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_millis(100))
+        .with_divergence_alert(threshold, alert.clone());
+    let service2 = Service::with_transport(tree2, network.bind(addr2).unwrap(), peer_net)
+        .with_seed(addr1.ip())
+        .with_activity_timeout(Duration::from_millis(100))
+        .with_divergence_alert(threshold, alert);
+    let task1 = tokio::spawn(service1.clone().run());
+    let task2 = tokio::spawn(service2.clone().run());
+
+    assert!(wait_long_until(|| service2.read().len() == 10_000).await);
+    tokio::time::sleep(threshold + Duration::from_secs(1)).await;
+    assert_eq!(alerts.load(Ordering::Relaxed), 0);
+
+    task1.abort();
+    task2.abort();
+}
+
+/// Value of the instances running the first version
+#[derive(Clone, Debug, Deserialize, Hash, PartialEq, Serialize)]
+struct ValueV1 {
+    count: u32,
+}
+
+/// Value of the instances upgraded to the second version, with a new field
+#[derive(Clone, Debug, Deserialize, Hash, PartialEq, Serialize)]
+struct ValueV2 {
+    count: u32,
+    #[serde(default)]
+    label: Option<String>,
+}
+
+/// Same layout as the updates of the protocol, for any type of values
+#[derive(Serialize)]
+enum VersionedMessage<V> {
+    #[allow(dead_code)]
+    ComparisonItem(u64, ()),
+    Update((u32, DatedMaybeTombstone<V>)),
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn undecodable_messages() {
+    let network = SimNetwork::new(42);
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let addr1: SocketAddr = "10.0.0.1:8080".parse().unwrap();
+    let addr2: SocketAddr = "10.0.0.2:8080".parse().unwrap();
+
+    let tree: HRTree<u32, DatedMaybeTombstone<ValueV2>> = HRTree::new();
+    let skipped = Arc::new(Mutex::new(Vec::new()));
+    let service = Service::with_transport(tree, network.bind(addr1).unwrap(), peer_net)
+        .with_undecodable_hook({
+            let skipped = skipped.clone();
+            move |peer, schema, payload: &[u8]| {
+                skipped
+                    .lock()
+                    .unwrap()
+                    .push((peer, schema, payload.to_vec()))
+            }
+        });
+    let task = tokio::spawn(service.clone().run());
+
+    // an update of the first version cannot be read, but the one after it is kept
+    let now = Utc::now();
+    let old = VersionedMessage::Update((0, (now, Some(ValueV1 { count: 1 }))));
+    let new = ValueV2 {
+        count: 2,
+        label: Some("two".to_string()),
+    };
+    let new = VersionedMessage::Update((1, (now, Some(new))));
+    let socket = network.bind(addr2).unwrap();
+    let mut buf = datagram(PROTOCOL_VERSION, &[old]);
+    buf.extend_from_slice(&datagram(PROTOCOL_VERSION, &[new])[3..]);
+    socket.send_to(&buf, addr1).await.unwrap();
+
+    assert_until!(service.get(&1).is_some());
+    assert!(service.get(&0).is_none());
+    let metrics = service.metrics().snapshot();
+    assert_eq!(metrics.undecodable_messages, 1);
+    assert_eq!(metrics.malformed_datagrams, 1);
+    let skipped = skipped.lock().unwrap().clone();
+    assert_eq!(skipped.len(), 1);
+    assert_eq!(skipped[0].0, addr2);
+    assert_eq!(skipped[0].1, None);
+    // the payload starts with the tag of the update
+    assert_eq!(skipped[0].2[0], 1);
+
+    task.abort();
+}
+
+/// Start an instance of the given schema in contact with all the others
+fn schema_service<V: Clone + DeserializeOwned + Hash + Send + Serialize + Sync + 'static>(
+    network: &SimNetwork,
+    addrs: &[SocketAddr],
+    tree: HRTree<u32, DatedMaybeTombstone<V>>,
+    i: usize,
+    schema_id: u64,
+) -> Service<HRTree<u32, DatedMaybeTombstone<V>>> {
+    let peer_net = "10.0.0.0/24".parse().unwrap();
+    let mut service = Service::with_transport(tree, network.bind(addrs[i]).unwrap(), peer_net)
+        .with_activity_timeout(Duration::from_millis(100))
+        .with_schema_id(schema_id)
+        .with_schema_tags();
+    for (j, &addr) in addrs.iter().enumerate() {
+        if j != i {
+            service = service.with_seed_addr(addr);
+        }
+    }
+    service
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn mixed_schemas() {
+    let network = SimNetwork::new(42);
+    let addrs: Vec<SocketAddr> = (1..=4)
+        .map(|i| format!("10.0.0.{i}:8080").parse().unwrap())
+        .collect();
+    let timestamp = Utc::now();
+
+    let tree1: HRTree<u32, DatedMaybeTombstone<ValueV1>> = (0..50)
+        .map(|i| (i, (timestamp, Some(ValueV1 { count: i }))))
+        .collect();
+    let tree2: HRTree<u32, DatedMaybeTombstone<ValueV2>> = (100..150)
+        .map(|i| {
+            let value = ValueV2 {
+                count: i,
+                label: Some(i.to_string()),
+            };
+            (i, (timestamp, Some(value)))
+        })
+        .collect();
+    let foreign = Arc::new(AtomicUsize::new(0));
+    let v1 = [
+        schema_service(&network, &addrs, tree1, 0, 1).with_undecodable_hook({
+            let foreign = foreign.clone();
+            move |_, schema, _| {
+                assert_eq!(schema, Some(2));
+                foreign.fetch_add(1, Ordering::Relaxed);
+            }
+        }),
+        schema_service(&network, &addrs, HRTree::new(), 1, 1),
+    ];
+    let v2 = [
+        schema_service(&network, &addrs, tree2, 2, 2),
+        schema_service(&network, &addrs, HRTree::new(), 3, 2),
+    ];
+    let mut tasks = Vec::new();
+    for service in &v1 {
+        tasks.push(tokio::spawn(service.clone().run()));
+    }
+    for service in &v2 {
+        tasks.push(tokio::spawn(service.clone().run()));
+    }
+
+    // the instances of each version converge among themselves, and skip the other updates
+    assert!(wait_long_until(|| v1[1].read().len() == 50 && v2[1].read().len() == 50).await);
+    assert_eq!(v1[0].read().hash(&..), v1[1].read().hash(&..));
+    assert_eq!(v2[0].read().hash(&..), v2[1].read().hash(&..));
+    v1[0].insert(50, ValueV1 { count: 50 }, Utc::now());
+    let value = ValueV2 {
+        count: 150,
+        label: None,
+    };
+    v2[0].insert(150, value.clone(), Utc::now());
+    assert_until!(v1[1].get(&50).is_some() && v2[1].get(&150).as_deref() == Some(&value));
+    for service in &v1 {
+        assert!(service.read().len() == 51);
+        assert!(service.metrics().snapshot().foreign_schema_updates > 0);
+    }
+    for service in &v2 {
+        assert!(service.read().len() == 51);
+        assert!(service.metrics().snapshot().foreign_schema_updates > 0);
+    }
+    assert!(foreign.load(Ordering::Relaxed) > 0);
+
+    for task in tasks {
+        task.abort();
+    }
+}